@@ -16,7 +16,9 @@ import uniffi.musicopy.TransferJobProgressModel
 import uniffi.musicopy.logDebug
 
 const val NOTIFICATION_CHANNEL_ID_FOREGROUND = "foreground"
+const val NOTIFICATION_CHANNEL_ID_EVENTS = "events"
 const val NOTIFICATION_ID_TRANSFER = 100
+const val NOTIFICATION_ID_EVENT = 101
 
 class AppApplication : Application() {
     lateinit var platformAppContext: PlatformAppContext
@@ -57,8 +59,16 @@ class AppApplication : Application() {
                     NotificationManager.IMPORTANCE_DEFAULT
                 )
 
+            val eventsNotificationChannel =
+                NotificationChannel(
+                    NOTIFICATION_CHANNEL_ID_EVENTS,
+                    "Transfer results",
+                    NotificationManager.IMPORTANCE_DEFAULT
+                )
+
             val notificationManager = getSystemService(NOTIFICATION_SERVICE) as NotificationManager
             notificationManager.createNotificationChannel(foregroundNotificationChannel)
+            notificationManager.createNotificationChannel(eventsNotificationChannel)
         }
     }
 
@@ -85,6 +95,7 @@ class AppApplication : Application() {
                                     is TransferJobProgressModel.Requested -> countWaiting += 1
                                     is TransferJobProgressModel.Transcoding -> countWaiting += 1
                                     is TransferJobProgressModel.Ready -> countWaiting += 1
+                                    is TransferJobProgressModel.Paused -> countWaiting += 1
                                     is TransferJobProgressModel.InProgress -> countWaiting += 1
 
                                     is TransferJobProgressModel.Finished -> countFinished += 1
@@ -100,6 +111,7 @@ class AppApplication : Application() {
                                     is TransferJobProgressModel.Requested -> countFailed += 1
                                     is TransferJobProgressModel.Transcoding -> countFailed += 1
                                     is TransferJobProgressModel.Ready -> countFailed += 1
+                                    is TransferJobProgressModel.Paused -> countFailed += 1
                                     is TransferJobProgressModel.InProgress -> countFailed += 1
 
                                     is TransferJobProgressModel.Finished -> countFinished += 1
@@ -1,6 +1,7 @@
 package app.musicopy
 
 import android.app.ForegroundServiceStartNotAllowedException
+import android.app.Notification
 import android.app.NotificationManager
 import android.app.Service
 import android.content.Intent
@@ -25,6 +26,11 @@ class AppForegroundService : Service() {
         val countFailed = intent?.getIntExtra("count_failed", 0) ?: 0
         val countFinished = intent?.getIntExtra("count_finished", 0) ?: 0
 
+        // sent by the core while the app is in the background
+        val title = intent?.getStringExtra("title") ?: ""
+        val percent = intent?.getIntExtra("percent", -1) ?: -1
+        val currentFile = intent?.getStringExtra("current_file")
+
         try {
             when (action) {
                 "MUSICOPY_UPDATE" -> {
@@ -33,6 +39,12 @@ class AppForegroundService : Service() {
                     )
                 }
 
+                "MUSICOPY_BACKGROUND_PROGRESS" -> {
+                    updateForegroundProgress(
+                        title, percent, currentFile, countTotal, countFinished
+                    )
+                }
+
                 "MUSICOPY_STOP" -> {
                     stopForeground(
                         countTotal, countWaiting, countFailed, countFinished
@@ -83,11 +95,40 @@ class AppForegroundService : Service() {
             .setPriority(NotificationManager.IMPORTANCE_LOW)
             .build()
 
+        showForeground(notification)
+    }
+
+    fun updateForegroundProgress(
+        title: String,
+        percent: Int,
+        currentFile: String?,
+        countTotal: Int,
+        countFinished: Int,
+    ) {
+        // build notification
+        val contentText = currentFile ?: "$countFinished of $countTotal files"
+
+        val notification = NotificationCompat.Builder(this, NOTIFICATION_CHANNEL_ID_FOREGROUND)
+            .setSmallIcon(R.drawable.icon_mask)
+            .setColor(0xff4c662b.toInt())
+            .setContentTitle(title)
+            .setContentText(contentText)
+            .setOngoing(true)
+            // percent is -1 if no file sizes are known yet
+            .setProgress(100, percent.coerceAtLeast(0), percent < 0)
+            .setOnlyAlertOnce(true)
+            .setPriority(NotificationManager.IMPORTANCE_LOW)
+            .build()
+
+        showForeground(notification)
+    }
+
+    private fun showForeground(notification: Notification) {
         if (!started) {
             // start foreground service using notification
             ServiceCompat.startForeground(
                 this,
-                100,
+                NOTIFICATION_ID_TRANSFER,
                 notification,
                 if (Build.VERSION.SDK_INT >= Build.VERSION_CODES.R) {
                     ServiceInfo.FOREGROUND_SERVICE_TYPE_DATA_SYNC
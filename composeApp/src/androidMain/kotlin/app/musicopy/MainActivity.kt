@@ -88,4 +88,24 @@ class MainActivity : ComponentActivity() {
             }
         }
     }
+
+    override fun onStart() {
+        super.onStart()
+
+        // send model changes to the UI again
+        val app = application as AppApplication
+        if (app.coreInstanceReady.value) {
+            app.coreInstance.instance.enterForeground()
+        }
+    }
+
+    override fun onStop() {
+        super.onStop()
+
+        // send compact progress to the foreground service notification instead of model changes
+        val app = application as AppApplication
+        if (app.coreInstanceReady.value) {
+            app.coreInstance.instance.enterBackground()
+        }
+    }
 }
@@ -1,6 +1,7 @@
 package app.musicopy
 
 import android.Manifest
+import android.app.PendingIntent
 import android.content.ClipData
 import android.content.ComponentName
 import android.content.Intent
@@ -17,11 +18,14 @@ import androidx.compose.runtime.remember
 import androidx.compose.ui.platform.ClipEntry
 import androidx.compose.ui.platform.LocalContext
 import androidx.compose.ui.platform.toClipEntry
+import androidx.core.app.NotificationCompat
+import androidx.core.app.NotificationManagerCompat
 import androidx.core.content.ContextCompat
 import androidx.core.content.FileProvider
 import androidx.core.net.toUri
 import com.russhwolf.settings.Settings
 import com.russhwolf.settings.SharedPreferencesSettings
+import uniffi.musicopy.BackgroundProgressEvent
 import uniffi.musicopy.CoreOptions
 import uniffi.musicopy.ProjectDirsOptions
 import java.io.File
@@ -178,6 +182,58 @@ actual fun rememberNotificationsPermission(): MutableState<PermissionState> {
     return permissionState
 }
 
+actual fun PlatformAppContext.showNotification(tag: String, title: String, text: String) {
+    if (Build.VERSION.SDK_INT >= Build.VERSION_CODES.TIRAMISU &&
+        ContextCompat.checkSelfPermission(application, Manifest.permission.POST_NOTIFICATIONS)
+        != PackageManager.PERMISSION_GRANTED
+    ) {
+        return
+    }
+
+    // open the app when the notification is tapped
+    val intent = Intent(application, MainActivity::class.java)
+        .addFlags(Intent.FLAG_ACTIVITY_NEW_TASK or Intent.FLAG_ACTIVITY_CLEAR_TOP)
+    val pendingIntent =
+        PendingIntent.getActivity(application, 0, intent, PendingIntent.FLAG_IMMUTABLE)
+
+    val notification = NotificationCompat.Builder(application, NOTIFICATION_CHANNEL_ID_EVENTS)
+        .setSmallIcon(R.drawable.icon_mask)
+        .setColor(0xff4c662b.toInt())
+        .setContentTitle(title)
+        .setContentText(text)
+        .setContentIntent(pendingIntent)
+        .setAutoCancel(true)
+        .build()
+
+    NotificationManagerCompat.from(application).notify(tag, NOTIFICATION_ID_EVENT, notification)
+}
+
+actual fun PlatformAppContext.showBackgroundProgress(event: BackgroundProgressEvent) {
+    val serviceIntent = Intent(application, AppForegroundService::class.java)
+
+    // send progress data in extras
+    serviceIntent.putExtra("title", event.title)
+    serviceIntent.putExtra("percent", event.percent?.toInt() ?: -1)
+    serviceIntent.putExtra("current_file", event.currentFile)
+    serviceIntent.putExtra("count_total", event.totalFiles.toInt())
+    serviceIntent.putExtra("count_finished", event.completedFiles.toInt())
+
+    if (event.active) {
+        serviceIntent.action = "MUSICOPY_BACKGROUND_PROGRESS"
+
+        // start the service or update the notification
+        if (Build.VERSION.SDK_INT >= Build.VERSION_CODES.O) {
+            application.startForegroundService(serviceIntent)
+        } else {
+            application.startService(serviceIntent)
+        }
+    } else {
+        serviceIntent.action = "MUSICOPY_STOP"
+
+        application.startService(serviceIntent)
+    }
+}
+
 @Composable
 actual fun BackHandler(enabled: Boolean, onBack: () -> Unit) {
     androidx.activity.compose.BackHandler(enabled = enabled, onBack = onBack)
@@ -5,9 +5,12 @@ package app.musicopy
 import androidx.compose.animation.AnimatedContentTransitionScope
 import androidx.compose.animation.core.tween
 import androidx.compose.foundation.layout.imePadding
+import androidx.compose.material3.AlertDialog
 import androidx.compose.material3.SnackbarDuration
 import androidx.compose.material3.SnackbarHost
 import androidx.compose.material3.SnackbarHostState
+import androidx.compose.material3.Text
+import androidx.compose.material3.TextButton
 import androidx.compose.runtime.Composable
 import androidx.compose.runtime.LaunchedEffect
 import androidx.compose.runtime.collectAsState
@@ -72,6 +75,7 @@ fun App(
     // collect core state flows as compose state
     val libraryModel by coreInstance.libraryState.collectAsState()
     val nodeModel by coreInstance.nodeState.collectAsState()
+    val databaseRecovered by coreInstance.databaseRecovered.collectAsState()
 
     val directoryPicker = rememberDirectoryPicker(platformActivityContext, appSettings)
 
@@ -172,6 +176,29 @@ fun App(
     val onShowNodeStatus = { nodeStatusSheetState.peek() }
 
     Theme {
+        databaseRecovered?.let { event ->
+            AlertDialog(
+                onDismissRequest = { coreInstance.dismissDatabaseRecovered() },
+                confirmButton = {
+                    TextButton(onClick = { coreInstance.dismissDatabaseRecovered() }) {
+                        Text("OK")
+                    }
+                },
+                title = { Text("Database recovered") },
+                text = {
+                    val lost = if (event.lostTables.isEmpty()) {
+                        "All of your data was recovered."
+                    } else {
+                        "Some data couldn't be recovered: ${event.lostTables.joinToString()}."
+                    }
+                    Text(
+                        "The database was damaged and has been repaired. $lost\n\n" +
+                            "The damaged copy was saved to ${event.backupPath}."
+                    )
+                },
+            )
+        }
+
         NavHost(
             navController = navController,
             startDestination = Home,
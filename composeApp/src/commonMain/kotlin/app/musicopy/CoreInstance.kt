@@ -2,26 +2,43 @@ package app.musicopy
 
 import kotlinx.coroutines.flow.MutableStateFlow
 import kotlinx.coroutines.flow.StateFlow
+import uniffi.musicopy.BackgroundProgressEvent
+import uniffi.musicopy.ConnectionLostEvent
 import uniffi.musicopy.Core
+import uniffi.musicopy.DatabaseRecoveredEvent
 import uniffi.musicopy.EventHandler
 import uniffi.musicopy.LibraryModel
+import uniffi.musicopy.LibraryModelDiff
 import uniffi.musicopy.NodeModel
+import uniffi.musicopy.NodeModelDiff
+import uniffi.musicopy.SettingsModel
+import uniffi.musicopy.ShutdownProgressEvent
 import uniffi.musicopy.StatsModel
+import uniffi.musicopy.TransferJobFailedEvent
+import uniffi.musicopy.TransferSessionCompletedEvent
 
-class CoreInstance private constructor() : EventHandler {
+class CoreInstance private constructor(
+    private val platformAppContext: PlatformAppContext,
+) : EventHandler {
     companion object {
         suspend fun start(
             platformAppContext: PlatformAppContext,
             appSettings: AppSettings,
         ): CoreInstance {
-            val instance = CoreInstance()
+            val instance = CoreInstance(platformAppContext)
             instance._instance = Core.start(
                 eventHandler = instance,
                 options = CoreProvider.getOptions(platformAppContext, appSettings)
             )
-            instance._libraryState = MutableStateFlow(instance._instance.getLibraryModel())
-            instance._nodeState = MutableStateFlow(instance._instance.getNodeModel())
+            // versioned, so diffs that are already included can be skipped
+            val library = instance._instance.getLibraryModelVersioned()
+            instance.libraryVersion = library.version
+            instance._libraryState = MutableStateFlow(library.model)
+            val node = instance._instance.getNodeModelVersioned()
+            instance.nodeVersion = node.version
+            instance._nodeState = MutableStateFlow(node.model)
             instance._statsState = MutableStateFlow(instance._instance.getStatsModel())
+            instance._settingsState = MutableStateFlow(instance._instance.getSettingsModel())
             return instance
         }
     }
@@ -35,6 +52,10 @@ class CoreInstance private constructor() : EventHandler {
     val libraryState: StateFlow<LibraryModel>
         get() = _libraryState
 
+    // version of the last change applied to each model, when model diffs are enabled
+    private var libraryVersion: ULong = 0u
+    private var nodeVersion: ULong = 0u
+
     private lateinit var _nodeState: MutableStateFlow<NodeModel>
     val nodeState: StateFlow<NodeModel>
         get() = _nodeState
@@ -43,6 +64,24 @@ class CoreInstance private constructor() : EventHandler {
     val statsState: StateFlow<StatsModel>
         get() = _statsState
 
+    private lateinit var _settingsState: MutableStateFlow<SettingsModel>
+    val settingsState: StateFlow<SettingsModel>
+        get() = _settingsState
+
+    // set during Core.start, so this can't be lateinit like the models
+    private val _databaseRecovered = MutableStateFlow<DatabaseRecoveredEvent?>(null)
+    val databaseRecovered: StateFlow<DatabaseRecoveredEvent?>
+        get() = _databaseRecovered
+
+    fun dismissDatabaseRecovered() {
+        _databaseRecovered.value = null
+    }
+
+    // progress of a shutdown started with shutdownWithDeadline, or null if none was started
+    private val _shutdownProgress = MutableStateFlow<ShutdownProgressEvent?>(null)
+    val shutdownProgress: StateFlow<ShutdownProgressEvent?>
+        get() = _shutdownProgress
+
     override fun onLibraryModelSnapshot(model: LibraryModel) {
         // TODO: this is a hack because Core.start calls the callback before CoreInstance finishes initializing
         if (::_libraryState.isInitialized) {
@@ -56,9 +95,95 @@ class CoreInstance private constructor() : EventHandler {
         }
     }
 
+    override fun onLibraryModelDiff(diff: LibraryModelDiff) {
+        if (!::_libraryState.isInitialized || diff.version <= libraryVersion) {
+            return
+        }
+
+        if (diff.version == libraryVersion + 1u) {
+            _libraryState.value = _libraryState.value.applyPatch(diff.patch)
+            libraryVersion = diff.version
+        } else {
+            // missed a change, so resync from a new snapshot
+            val library = _instance.getLibraryModelVersioned()
+            _libraryState.value = library.model
+            libraryVersion = library.version
+        }
+    }
+
+    override fun onNodeModelDiff(diff: NodeModelDiff) {
+        if (!::_nodeState.isInitialized || diff.version <= nodeVersion) {
+            return
+        }
+
+        if (diff.version == nodeVersion + 1u) {
+            _nodeState.value = _nodeState.value.applyPatch(diff.patch)
+            nodeVersion = diff.version
+        } else {
+            // missed a change, so resync from a new snapshot
+            val node = _instance.getNodeModelVersioned()
+            _nodeState.value = node.model
+            nodeVersion = node.version
+        }
+    }
+
     override fun onStatsModelSnapshot(model: StatsModel) {
         if (::_statsState.isInitialized) {
             _statsState.value = model
         }
     }
-}
\ No newline at end of file
+
+    override fun onSettingsModelSnapshot(model: SettingsModel) {
+        if (::_settingsState.isInitialized) {
+            _settingsState.value = model
+        }
+    }
+
+    override fun onTransferSessionCompleted(event: TransferSessionCompletedEvent) {
+        var text = "${event.completedFiles} files, ${formatSize(event.transferredBytes)}"
+        if (event.failedFiles > 0u) {
+            text += ", ${event.failedFiles} failed"
+        }
+
+        platformAppContext.showNotification(
+            tag = "session:${event.endpointId}",
+            title = "Downloaded from ${event.name}",
+            text = text,
+        )
+    }
+
+    override fun onTransferJobFailed(event: TransferJobFailedEvent) {
+        // failures from the same server replace each other, since the session notification has
+        // the total
+        platformAppContext.showNotification(
+            tag = "failed:${event.endpointId}",
+            title = "Failed to download ${event.filePath.substringAfterLast('/')}",
+            text = event.error,
+        )
+    }
+
+    override fun onConnectionLost(event: ConnectionLostEvent) {
+        var text = event.error
+        if (event.unfinishedFiles > 0u) {
+            text = "${event.unfinishedFiles} unfinished files. $text"
+        }
+
+        platformAppContext.showNotification(
+            tag = "connection:${event.endpointId}",
+            title = "Lost connection to ${event.name}",
+            text = text,
+        )
+    }
+
+    override fun onDatabaseRecovered(event: DatabaseRecoveredEvent) {
+        _databaseRecovered.value = event
+    }
+
+    override fun onShutdownProgress(event: ShutdownProgressEvent) {
+        _shutdownProgress.value = event
+    }
+
+    override fun onBackgroundProgress(event: BackgroundProgressEvent) {
+        platformAppContext.showBackgroundProgress(event)
+    }
+}
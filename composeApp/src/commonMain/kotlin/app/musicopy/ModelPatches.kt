@@ -0,0 +1,74 @@
+package app.musicopy
+
+import uniffi.musicopy.LibraryModel
+import uniffi.musicopy.LibraryModelPatch
+import uniffi.musicopy.NodeModel
+import uniffi.musicopy.NodeModelPatch
+
+/**
+ * Returns the model with a patch from a diff applied. Mirrors `LibraryModel::apply_patch` in the
+ * core.
+ */
+internal fun LibraryModel.applyPatch(patch: LibraryModelPatch): LibraryModel {
+    return when (patch) {
+        is LibraryModelPatch.LocalRoots -> copy(localRoots = patch.localRoots)
+        is LibraryModelPatch.TranscodesDirSize -> copy(transcodesDirSize = patch.transcodesDirSize)
+        is LibraryModelPatch.Scanning -> copy(isScanning = patch.isScanning)
+    }
+}
+
+/**
+ * Returns the model with a patch from a diff applied. Mirrors `NodeModel::apply_patch` in the
+ * core.
+ */
+internal fun NodeModel.applyPatch(patch: NodeModelPatch): NodeModel {
+    return when (patch) {
+        // servers and clients are patched separately
+        is NodeModelPatch.Node -> patch.model.copy(servers = servers, clients = clients)
+
+        is NodeModelPatch.Snapshot -> patch.model
+
+        is NodeModelPatch.ServerUpdated -> copy(
+            servers = servers + (patch.server.endpointId to patch.server)
+        )
+
+        is NodeModelPatch.ServerRemoved -> copy(servers = servers - patch.endpointId)
+
+        is NodeModelPatch.ClientUpdated -> {
+            // the index is left out of the patch
+            val index = clients[patch.client.endpointId]?.index
+            copy(clients = clients + (patch.client.endpointId to patch.client.copy(index = index)))
+        }
+
+        is NodeModelPatch.ClientTransferJobsUpdated -> {
+            // the index and unchanged jobs are left out of the patch
+            val existing = clients[patch.client.endpointId]
+            val changedJobs = patch.changedJobs.associateBy { it.jobId }.toMutableMap()
+            val removedJobIds = patch.removedJobIds.toSet()
+
+            val jobs = (existing?.transferJobs ?: emptyList())
+                .filter { it.jobId !in removedJobIds }
+                .map { changedJobs.remove(it.jobId) ?: it }
+                .plus(changedJobs.values)
+
+            copy(
+                clients = clients + (patch.client.endpointId to patch.client.copy(
+                    index = existing?.index,
+                    transferJobs = jobs,
+                ))
+            )
+        }
+
+        is NodeModelPatch.ClientIndexUpdated -> {
+            val client = clients[patch.endpointId] ?: return this
+            copy(
+                clients = clients + (patch.endpointId to client.copy(
+                    index = patch.index,
+                    indexComplete = patch.indexComplete,
+                ))
+            )
+        }
+
+        is NodeModelPatch.ClientRemoved -> copy(clients = clients - patch.endpointId)
+    }
+}
@@ -6,6 +6,7 @@ import androidx.compose.runtime.mutableStateOf
 import androidx.compose.runtime.remember
 import androidx.compose.ui.platform.ClipEntry
 import com.russhwolf.settings.Settings
+import uniffi.musicopy.BackgroundProgressEvent
 import uniffi.musicopy.CoreOptions
 
 expect val isAndroid: Boolean
@@ -59,6 +60,16 @@ class PermissionState(
 @Composable
 expect fun rememberNotificationsPermission(): MutableState<PermissionState>
 
+// Shows a system notification about something that happened in the core, like a finished
+// download session.
+//
+// Notifications with the same tag replace each other, so repeated events don't pile up.
+expect fun PlatformAppContext.showNotification(tag: String, title: String, text: String)
+
+// Shows the progress of the transfers while the app is in the background, e.g. in a foreground
+// service notification that keeps them running.
+expect fun PlatformAppContext.showBackgroundProgress(event: BackgroundProgressEvent)
+
 @Composable
 fun stubRememberNotificationsPermission() =
     remember { mutableStateOf(PermissionState(isGranted = true, requestPermission = {})) }
@@ -99,7 +99,9 @@ fun TranscodeFormatSheet(appSettings: AppSettings, state: TranscodeFormatSheetSt
                         state.hide()
                     }
 
+                    TranscodeFormatButton(TranscodeFormat.Opus192, onSetFormat)
                     TranscodeFormatButton(TranscodeFormat.Opus128, onSetFormat)
+                    TranscodeFormatButton(TranscodeFormat.Opus96, onSetFormat)
                     TranscodeFormatButton(TranscodeFormat.Opus64, onSetFormat)
                     TranscodeFormatButton(TranscodeFormat.Mp3v0, onSetFormat)
                     TranscodeFormatButton(TranscodeFormat.Mp3v5, onSetFormat)
@@ -151,12 +153,24 @@ enum class TranscodeFormat(
     val formatLabel: String?,
     val description: String,
 ) {
+    Opus192(
+        "opus192",
+        "Headphones",
+        "Opus 192kb/s",
+        "For good headphones and speakers, ~200 songs per GB."
+    ),
     Opus128(
         "opus128",
         "Best Quality",
         "Opus 128kb/s",
         "Optimized for quality, ~300 songs per GB."
     ),
+    Opus96(
+        "opus96",
+        "Balanced",
+        "Opus 96kb/s",
+        "Balanced for quality and size, ~400 songs per GB."
+    ),
     Opus64(
         "opus64",
         "Best Size",
@@ -187,7 +201,9 @@ enum class TranscodeFormat(
 
 fun TranscodeFormat.Companion.fromId(id: String): TranscodeFormat? {
     return when (id) {
+        TranscodeFormat.Opus192.id -> TranscodeFormat.Opus192
         TranscodeFormat.Opus128.id -> TranscodeFormat.Opus128
+        TranscodeFormat.Opus96.id -> TranscodeFormat.Opus96
         TranscodeFormat.Opus64.id -> TranscodeFormat.Opus64
         TranscodeFormat.Mp3v0.id -> TranscodeFormat.Mp3v0
         TranscodeFormat.Mp3v5.id -> TranscodeFormat.Mp3v5
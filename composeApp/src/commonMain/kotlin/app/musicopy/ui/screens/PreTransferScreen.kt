@@ -457,8 +457,10 @@ internal fun FileRow(
 
     val isSelectable = if (node.leaf != null) {
         when (node.leaf.downloadStatus) {
-            // always selectable
-            null -> {
+            // always selectable, changed files can be downloaded again
+            null,
+            IndexItemDownloadStatusModel.CHANGED,
+                -> {
                 true
             }
 
@@ -952,10 +954,32 @@ internal class SelectionManager {
 
     /**
      * Sets the selected state of an item and its descendants.
+     *
+     * When selecting, descendants that are filtered out are skipped, see [childrenToSelect].
      */
     fun setSelectedRecursive(node: TreeNode, selected: Boolean, paused: Boolean) {
         node.leaf?.let { setSelected(it, selected, paused) }
-        node.children.forEach { setSelectedRecursive(it, selected, paused) }
+        val children = if (selected) childrenToSelect(node) else node.children
+        children.forEach { setSelectedRecursive(it, selected, paused) }
+    }
+
+    /**
+     * Gets the children of a node that selecting it selects.
+     *
+     * Children that are filtered out are skipped, like when downloading everything, unless all
+     * of them are, so they can still be selected together.
+     */
+    private fun childrenToSelect(node: TreeNode): List<TreeNode> {
+        return node.children.filterNot { isFilteredOut(it) }.ifEmpty { node.children }
+    }
+
+    /**
+     * Gets whether a node is filtered out, i.e. it only has files excluded by the server's
+     * download filter that aren't selected.
+     */
+    private fun isFilteredOut(node: TreeNode): Boolean {
+        return node.leaf?.let { it.filtered && !isSelected(it) }
+            ?: (node.children.isNotEmpty() && node.children.all { isFilteredOut(it) })
     }
 
     /**
@@ -1007,6 +1031,7 @@ internal class SelectionManager {
      *  - If all children are DisabledOrNone, Disabled, or None, it is DisabledOrNone
      *  - If all children are DisabledOrSelected, Disabled, or Selected, it is DisabledOrSelected
      *  - Otherwise, it is Indeterminate
+     * Children that selecting the branch skips are left out, see [childrenToSelect].
      *
      *  We also need to know the [RowDisabledState] to determine which checkbox to render for
      *  disabled folders.
@@ -1051,7 +1076,8 @@ internal class SelectionManager {
             var countDisabledDownloaded = 0
             var countDisabledFailed = 0
 
-            node.children.forEach { child ->
+            val children = childrenToSelect(node)
+            children.forEach { child ->
                 val childState = getNodeState(child, paused)
 
                 when (childState.first) {
@@ -1097,7 +1123,7 @@ internal class SelectionManager {
                 }
             }
 
-            val total = node.children.size
+            val total = children.size
 
             val toggleState = if (countToggleNone == total) {
                 RowToggleState.None
@@ -70,6 +70,7 @@ import musicopy_root.musicopy.generated.resources.pause_24px
 import musicopy_root.musicopy.generated.resources.pending_24px
 import org.jetbrains.compose.resources.painterResource
 import uniffi.musicopy.ClientModel
+import uniffi.musicopy.TransferErrorReasonModel
 import uniffi.musicopy.TransferJobModel
 import uniffi.musicopy.TransferJobProgressModel
 
@@ -88,12 +89,16 @@ fun TransferScreen(
 
     val inProgressExpanded = remember { mutableStateOf(true) }
     val finishedExpanded = remember { mutableStateOf(false) }
-    val failedExpanded = remember { mutableStateOf(true) }
+    val failedExpanded = remember {
+        TransferErrorReasonModel.entries.associateWith { mutableStateOf(true) }
+    }
     val waitingExpanded = remember { mutableStateOf(false) }
 
     val inProgressJobs = jobs.filter { job -> job.progress is TransferJobProgressModel.InProgress }
     val finishedJobs = jobs.filter { job -> job.progress is TransferJobProgressModel.Finished }
     val failedJobs = jobs.filter { job -> job.progress is TransferJobProgressModel.Failed }
+    val failedJobsByReason =
+        failedJobs.groupBy { job -> (job.progress as TransferJobProgressModel.Failed).reason }
 
     val waitingJobs = jobs.filter { job ->
         job.progress !is TransferJobProgressModel.InProgress &&
@@ -194,11 +199,14 @@ fun TransferScreen(
             }
 
             LazyColumn {
-                collapsibleSection(
-                    title = "FAILED",
-                    expanded = failedExpanded,
-                    jobs = failedJobs
-                )
+                // one section for each reason, so failures with the same cause are together
+                TransferErrorReasonModel.entries.forEach { reason ->
+                    collapsibleSection(
+                        title = "FAILED: ${formatErrorReason(reason).uppercase()}",
+                        expanded = failedExpanded.getValue(reason),
+                        jobs = failedJobsByReason[reason].orEmpty()
+                    )
+                }
 
                 collapsibleSection(
                     title = "IN PROGRESS",
@@ -251,7 +259,7 @@ fun TransferJob(job: TransferJobModel) {
             ) {
                 val progress = job.progress
                 when (progress) {
-                    is TransferJobProgressModel.Requested, is TransferJobProgressModel.Transcoding, is TransferJobProgressModel.Ready -> {
+                    is TransferJobProgressModel.Requested, is TransferJobProgressModel.Transcoding, is TransferJobProgressModel.Ready, is TransferJobProgressModel.Paused -> {
                         Icon(
                             painter = painterResource(Res.drawable.pending_24px),
                             contentDescription = null,
@@ -393,6 +401,10 @@ internal fun formatJobSubtitle(job: TransferJobModel): String {
             "Waiting..."
         }
 
+        is TransferJobProgressModel.Paused -> {
+            "Paused"
+        }
+
         is TransferJobProgressModel.InProgress -> {
             job.fileSize?.let {
                 val totalMB = it.toFloat() / 1_000_000f
@@ -412,6 +424,19 @@ internal fun formatJobSubtitle(job: TransferJobModel): String {
     }
 }
 
+internal fun formatErrorReason(reason: TransferErrorReasonModel): String {
+    return when (reason) {
+        TransferErrorReasonModel.NETWORK -> "Connection problem"
+        TransferErrorReasonModel.REMOTE_FILE_MISSING -> "Missing on server"
+        TransferErrorReasonModel.DECODE_FAILED -> "Couldn't read file"
+        TransferErrorReasonModel.DISK_FULL -> "Out of space"
+        TransferErrorReasonModel.CHECKSUM_MISMATCH -> "Corrupted in transfer"
+        TransferErrorReasonModel.PERMISSION_DENIED -> "Permission denied"
+        TransferErrorReasonModel.CANCELLED -> "Cancelled"
+        TransferErrorReasonModel.OTHER -> "Other errors"
+    }
+}
+
 @Composable
 internal fun NotificationPermissionPrompt() {
     val notificationPermission by rememberNotificationsPermission()
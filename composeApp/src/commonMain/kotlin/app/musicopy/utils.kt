@@ -15,12 +15,17 @@ import uniffi.musicopy.IndexItemDownloadStatusModel
 import uniffi.musicopy.IndexItemModel
 import uniffi.musicopy.LibraryModel
 import uniffi.musicopy.LibraryRootModel
+import uniffi.musicopy.NetworkState
 import uniffi.musicopy.NodeModel
+import uniffi.musicopy.RelayConfig
 import uniffi.musicopy.ServerModel
 import uniffi.musicopy.ServerStateModel
+import uniffi.musicopy.SessionProgressModel
 import uniffi.musicopy.StatsModel
+import uniffi.musicopy.TransferErrorReasonModel
 import uniffi.musicopy.TransferJobModel
 import uniffi.musicopy.TransferJobProgressModel
+import uniffi.musicopy.TransferSchedule
 import kotlin.time.Clock
 import kotlin.time.ExperimentalTime
 
@@ -103,6 +108,10 @@ fun mockNodeModel(
     return NodeModel(
         endpointId = endpointId,
         homeRelay = homeRelay,
+        relayConfig = RelayConfig.Default,
+        lanOnly = false,
+        acceptIncoming = true,
+        boundSockets = listOf("0.0.0.0:41234", "[::]:41235"),
         sendIpv4 = 12345u,
         sendIpv6 = 12345u,
         sendRelay = 12345u,
@@ -115,6 +124,23 @@ fun mockNodeModel(
         clients = clients.associateBy { it.endpointId },
         trustedNodes = emptyList(),
         recentServers = emptyList(),
+        peerTraffic = emptyList(),
+        downloadUsage = emptyList(),
+        shareServerAddr = null,
+        shareLinks = emptyList(),
+        syncGroups = emptyList(),
+        syncGroupInvitations = emptyList(),
+        transferSchedule = TransferSchedule(
+            requireCharging = false,
+            requireUnmetered = false,
+            hours = null,
+        ),
+        transferHoldReasons = emptyList(),
+        networkState = NetworkState(metered = false, wifi = true, offline = false),
+        downloadRateLimit = 0u,
+        interruptedDownloads = emptyList(),
+        partialCleanup = null,
+        lastPlaylistWrite = null,
     )
 }
 
@@ -129,7 +155,8 @@ fun mockServerModel(
         state = ServerStateModel.Accepted,
         connectionType = "direct",
         latencyMs = 42u,
-        transferJobs = transferJobs
+        transferJobs = transferJobs,
+        session = mockSessionProgressModel(),
     )
 }
 
@@ -143,6 +170,16 @@ fun mockClientModel(
             add(mockTransferJobModel(progress = mockTransferJobProgressModelFinished()))
             add(mockTransferJobModel(progress = mockTransferJobProgressModelFailed()))
         }
+        repeat(10) {
+            add(
+                mockTransferJobModel(
+                    progress = mockTransferJobProgressModelFailed(
+                        reason = TransferErrorReasonModel.REMOTE_FILE_MISSING,
+                        retryable = false,
+                    )
+                )
+            )
+        }
     },
     paused: Boolean = false,
 ): ClientModel {
@@ -412,6 +449,7 @@ fun mockClientModel(
             ),
         ),
         transferJobs = transferJobs,
+        session = mockSessionProgressModel(),
         paused = paused,
     )
 }
@@ -450,6 +488,19 @@ fun mockIndexItemModel(
 
 var nextMockJobId: ULong = 0u
 
+fun mockSessionProgressModel(): SessionProgressModel {
+    return SessionProgressModel(
+        queuedFiles = 300u,
+        activeFiles = 100u,
+        pausedFiles = 0u,
+        completedFiles = 100u,
+        failedFiles = 100u,
+        expectedBytes = 6172839000u,
+        transferredBytes = 1234567800u,
+        etaSecs = 600u,
+    )
+}
+
 fun mockTransferJobModel(
     fileRoot: String = "root",
     filePath: String = "a/b/c.mp3",
@@ -482,8 +533,13 @@ fun mockTransferJobProgressModelFinished() = TransferJobProgressModel.Finished(
     finishedAt = now() - 1u
 )
 
-fun mockTransferJobProgressModelFailed() = TransferJobProgressModel.Failed(
-    error = "something went wrong"
+fun mockTransferJobProgressModelFailed(
+    reason: TransferErrorReasonModel = TransferErrorReasonModel.NETWORK,
+    retryable: Boolean = true,
+) = TransferJobProgressModel.Failed(
+    error = "something went wrong",
+    reason = reason,
+    retryable = retryable,
 )
 
 fun mockLibraryModel(
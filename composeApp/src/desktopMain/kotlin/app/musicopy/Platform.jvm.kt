@@ -6,6 +6,7 @@ import androidx.compose.ui.ExperimentalComposeUiApi
 import androidx.compose.ui.platform.ClipEntry
 import com.russhwolf.settings.PreferencesSettings
 import com.russhwolf.settings.Settings
+import uniffi.musicopy.BackgroundProgressEvent
 import uniffi.musicopy.CoreOptions
 import java.awt.Window
 import java.awt.datatransfer.StringSelection
@@ -71,6 +72,14 @@ actual fun rememberNotificationsPermission(): MutableState<PermissionState> {
     return stubRememberNotificationsPermission()
 }
 
+actual fun PlatformAppContext.showNotification(tag: String, title: String, text: String) {
+    // not implemented on desktop
+}
+
+actual fun PlatformAppContext.showBackgroundProgress(event: BackgroundProgressEvent) {
+    // not implemented on desktop
+}
+
 @Composable
 actual fun BackHandler(enabled: Boolean, onBack: () -> Unit) {
     // not implemented on desktop
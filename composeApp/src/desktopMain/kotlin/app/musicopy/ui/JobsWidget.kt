@@ -173,6 +173,10 @@ private fun ActiveConnectionJob(connection: ServerModel) {
                     "Latency: ${connection.latencyMs}ms",
                     style = MaterialTheme.typography.bodyMedium
                 )
+                Text(
+                    "Address: ${connection.remoteAddr}",
+                    style = MaterialTheme.typography.bodyMedium
+                )
             }
         }
     )
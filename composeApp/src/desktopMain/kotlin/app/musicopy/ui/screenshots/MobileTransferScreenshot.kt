@@ -1,6 +1,7 @@
 package app.musicopy.ui.screenshots
 
 import androidx.compose.runtime.Composable
+import app.musicopy.mockSessionProgressModel
 import app.musicopy.now
 import app.musicopy.ui.screens.TransferScreen
 import uniffi.musicopy.ClientModel
@@ -17,6 +18,7 @@ fun MobileTransferScreenshot() {
         latencyMs = 42u,
         index = emptyList(),
         transferJobs = screenshotTransferJobs,
+        session = mockSessionProgressModel(),
         paused = false,
     )
 
@@ -0,0 +1,47 @@
+package app.musicopy
+
+import io.kotest.core.spec.style.FunSpec
+import io.kotest.matchers.shouldBe
+import uniffi.musicopy.NodeModelPatch
+
+class ModelPatchesTest : FunSpec({
+    context("NodeModel.applyPatch") {
+        test("keeps the client index when the client is updated") {
+            val client = mockClientModel()
+            val model = mockNodeModel(clients = listOf(client))
+
+            val patched = model.applyPatch(
+                NodeModelPatch.ClientUpdated(client.copy(index = null, paused = true))
+            )
+
+            patched.clients[client.endpointId]?.paused shouldBe true
+            patched.clients[client.endpointId]?.index shouldBe client.index
+        }
+
+        test("merges changed and removed transfer jobs") {
+            val jobs = List(3) { mockTransferJobModel() }
+            val client = mockClientModel(transferJobs = jobs)
+            val model = mockNodeModel(clients = listOf(client))
+
+            val changed = jobs[1].copy(fileSize = 1u)
+            val added = mockTransferJobModel()
+            val patched = model.applyPatch(
+                NodeModelPatch.ClientTransferJobsUpdated(
+                    client = client.copy(index = null, transferJobs = emptyList()),
+                    changedJobs = listOf(changed, added),
+                    removedJobIds = listOf(jobs[0].jobId),
+                )
+            )
+
+            patched.clients[client.endpointId]?.transferJobs shouldBe listOf(changed, jobs[2], added)
+            patched.clients[client.endpointId]?.index shouldBe client.index
+        }
+
+        test("replaces the whole model with a snapshot") {
+            val model = mockNodeModel(clients = listOf(mockClientModel()))
+            val snapshot = mockNodeModel()
+
+            model.applyPatch(NodeModelPatch.Snapshot(snapshot)) shouldBe snapshot
+        }
+    }
+})
@@ -261,6 +261,46 @@ class PreTransferScreenTest : FunSpec({
             // A should not be selected
             manager.selectedKeys shouldBe emptySet()
         }
+
+        test("selecting a folder skips filtered items") {
+            val paused = false
+            val manager = SelectionManager()
+            val tree = buildTree(
+                listOf(
+                    makeIndexItem("library", "album1/song1.mp3", null),
+                    makeIndexItem("library", "album1/dj-set.mp3", null, filtered = true),
+                    makeIndexItem("library", "album2/dj-set.mp3", null, filtered = true),
+                )
+            )
+
+            manager.handleSelectNode(tree, paused)
+
+            manager.selectedKeys shouldBe setOf("library" to "album1/song1.mp3")
+            manager.getNodeState(tree, paused).first shouldBe RowToggleState.Selected
+
+            manager.handleSelectNode(tree, paused)
+
+            manager.selectedKeys shouldBe emptySet()
+        }
+
+        test("selecting a folder of only filtered items selects them") {
+            val paused = false
+            val manager = SelectionManager()
+            val tree = buildTree(
+                listOf(
+                    makeIndexItem("library", "album/dj-set1.mp3", null, filtered = true),
+                    makeIndexItem("library", "album/dj-set2.mp3", null, filtered = true),
+                )
+            )
+
+            manager.handleSelectNode(tree, paused)
+
+            manager.selectedKeys shouldBe setOf(
+                "library" to "album/dj-set1.mp3",
+                "library" to "album/dj-set2.mp3",
+            )
+            manager.getNodeState(tree, paused).first shouldBe RowToggleState.Selected
+        }
     }
 })
 
@@ -303,6 +343,7 @@ private fun makeIndexItem(
     root: String,
     path: String,
     downloadStatus: IndexItemDownloadStatusModel?,
+    filtered: Boolean = false,
 ): IndexItemModel {
     return IndexItemModel(
         endpointId = endpointId,
@@ -310,5 +351,6 @@ private fun makeIndexItem(
         path = path,
         downloadStatus = downloadStatus,
         fileSize = FileSizeModel.Unknown,
+        filtered = filtered,
     )
 }
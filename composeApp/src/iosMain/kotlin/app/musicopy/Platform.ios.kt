@@ -21,6 +21,7 @@ import platform.MessageUI.MFMailComposeViewControllerDelegateProtocol
 import platform.UIKit.UIApplication
 import platform.UIKit.UIDevice
 import platform.darwin.NSObject
+import uniffi.musicopy.BackgroundProgressEvent
 
 actual val isAndroid = false
 
@@ -109,6 +110,14 @@ actual fun rememberNotificationsPermission(): MutableState<PermissionState> {
     return stubRememberNotificationsPermission()
 }
 
+actual fun PlatformAppContext.showNotification(tag: String, title: String, text: String) {
+    // not implemented on iOS
+}
+
+actual fun PlatformAppContext.showBackgroundProgress(event: BackgroundProgressEvent) {
+    // not implemented on iOS
+}
+
 @Composable
 actual fun BackHandler(enabled: Boolean, onBack: () -> Unit) {
     // not implemented on iOS
@@ -0,0 +1,860 @@
+use crate::{daemon::StatusFile, metrics, parse_root, parse_transcode_format, rpc};
+use anyhow::Context;
+use musicopy::{
+    Core, ProjectDirsOptions,
+    downloads::DownloadedFileSelectionModel,
+    library::transcode::TranscodeFormat,
+    node::{
+        ClientModel, ClientStateModel, DownloadRequestModel, IndexItemDownloadStatusModel,
+        IndexItemModel, ServerStateModel, TransferSessionCompletedEvent,
+    },
+    operation::{OperationProgress, OperationProgressHandler},
+    profile::list_profiles,
+    settings::TranscodePolicy,
+};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+/// How often commands poll the models while waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Options of the serve command.
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// Library root to add before serving, as `<name>=<path>`. Can be repeated.
+    #[arg(long = "root", value_parser = parse_root)]
+    roots: Vec<(String, String)>,
+
+    /// Endpoint id of a node to trust before serving. Can be repeated.
+    #[arg(long = "trust")]
+    trusted: Vec<String>,
+
+    /// Whether to accept connections from nodes that aren't trusted.
+    #[arg(long, default_value_t = false)]
+    accept_all: bool,
+
+    /// Whether to print a one-time pairing ticket for a new client.
+    #[arg(long, default_value_t = false)]
+    pairing_ticket: bool,
+
+    /// TCP address to serve the JSON-RPC control API on, like `127.0.0.1:41642`. Requires
+    /// --rpc-token-file, since any local process could connect.
+    #[arg(long, requires = "rpc_token_file")]
+    rpc_listen: Option<SocketAddr>,
+
+    /// File with the token that connections to --rpc-listen authenticate with. On unix, the file
+    /// can't be readable by other users.
+    #[arg(long)]
+    rpc_token_file: Option<PathBuf>,
+
+    /// Unix socket to serve the JSON-RPC control API on.
+    #[arg(long)]
+    rpc_socket: Option<PathBuf>,
+
+    /// TCP address to serve Prometheus metrics on at `/metrics`, like `0.0.0.0:9464`.
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Transcode format to keep the library transcoded in ahead of time, e.g. `opus128`. Stored
+    /// in the settings like the transcode command does.
+    #[arg(long, value_parser = parse_transcode_format)]
+    transcode_format: Option<TranscodeFormat>,
+
+    /// Seconds between rescans of the library, so files added while serving are indexed and
+    /// transcoded. The library is only scanned at startup if not set.
+    #[arg(long)]
+    rescan_interval: Option<u64>,
+
+    /// Whether to run in the background. The command exits once the daemon is started, and
+    /// prints its pid.
+    #[arg(long, default_value_t = false)]
+    pub(crate) daemon: bool,
+
+    /// File to append the daemon's logs to. Logs are discarded if not set. Only used with
+    /// --daemon.
+    #[arg(long)]
+    pub(crate) log_file: Option<PathBuf>,
+
+    /// File to write the pid to while serving, e.g. for a service unit. Serving fails if another
+    /// process is serving with the same file.
+    #[arg(long)]
+    pub(crate) pid_file: Option<PathBuf>,
+
+    /// File to periodically write a JSON summary of the status to while serving, like the control
+    /// API's `status` method.
+    #[arg(long)]
+    status_file: Option<PathBuf>,
+}
+
+/// Serves the library until interrupted.
+pub async fn serve(core: &Arc<Core>, args: ServeArgs) -> anyhow::Result<()> {
+    let ServeArgs {
+        roots,
+        trusted,
+        accept_all,
+        pairing_ticket,
+        rpc_listen,
+        rpc_token_file,
+        rpc_socket,
+        metrics_listen,
+        transcode_format,
+        rescan_interval,
+        daemon: _,
+        log_file: _,
+        pid_file: _,
+        status_file,
+    } = args;
+
+    // read the control API token before changing anything, in case it's missing
+    let rpc_token = match (rpc_listen, rpc_token_file) {
+        (Some(_), Some(path)) => Some(rpc::read_token_file(&path)?),
+        (Some(_), None) => anyhow::bail!("--rpc-listen requires --rpc-token-file"),
+        (None, _) => None,
+    };
+
+    if let Some(format) = transcode_format {
+        info!("keeping the library transcoded to {format:?}");
+        set_ahead_of_time_transcoding(core, format)?;
+    }
+
+    // roots are stored, so skip the ones added by an earlier run
+    let library_model = core.get_library_model()?;
+    for (name, path) in roots {
+        if library_model
+            .local_roots
+            .iter()
+            .any(|root| root.name == name)
+        {
+            info!("library root {name} already exists");
+            continue;
+        }
+
+        info!("adding library root {name} at {path}");
+        core.add_library_root(name, path)?;
+    }
+    core.rescan_library()?;
+
+    for endpoint_id in trusted {
+        info!("trusting node {endpoint_id}");
+        core.trust_node(&endpoint_id)?;
+    }
+
+    if accept_all {
+        info!("ran with --accept-all, will automatically accept incoming connections");
+        let core = core.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+
+                let node_model = match core.get_node_model() {
+                    Ok(model) => model,
+                    Err(e) => {
+                        error!("auto accept: error getting node model: {e:#}");
+                        continue;
+                    }
+                };
+
+                for server in node_model.servers.values() {
+                    if matches!(server.state, ServerStateModel::Pending) {
+                        info!("auto accepting server: {}", server.endpoint_id);
+                        if let Err(e) = core.accept_connection(&server.endpoint_id) {
+                            error!("error auto accepting server {}: {e:#}", server.endpoint_id);
+                        }
+                    }
+                }
+            }
+        });
+    } else {
+        // nobody is around to accept connections, so only trusted nodes can connect
+        core.set_accept_incoming(false)?;
+    }
+
+    // the control API and metrics tasks stop when the runtime shuts down
+    if let Some((addr, token)) = rpc_listen.zip(rpc_token) {
+        let core = core.clone();
+        tokio::spawn(async move {
+            if let Err(e) = rpc::serve_tcp(core, addr, token).await {
+                error!("control API failed: {e:#}");
+            }
+        });
+    }
+    if let Some(addr) = metrics_listen {
+        let core = core.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(core, addr).await {
+                error!("metrics endpoint failed: {e:#}");
+            }
+        });
+    }
+    if let Some(path) = rpc_socket {
+        #[cfg(unix)]
+        {
+            let core = core.clone();
+            tokio::spawn(async move {
+                if let Err(e) = rpc::serve_unix(core, path).await {
+                    error!("control API failed: {e:#}");
+                }
+            });
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!(
+            "unix sockets aren't supported on this platform, can't serve control API on {}",
+            path.display()
+        );
+    }
+
+    if let Some(secs) = rescan_interval {
+        let core = core.clone();
+        tokio::spawn(async move {
+            let period = Duration::from_secs(secs.max(1));
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                if let Err(e) = core.rescan_library() {
+                    error!("periodic rescan failed: {e:#}");
+                }
+            }
+        });
+    }
+
+    let _status_file = status_file.map(|path| StatusFile::start(core.clone(), path));
+
+    println!("endpoint id: {}", core.get_node_model()?.endpoint_id);
+    if pairing_ticket {
+        println!("pairing ticket: {}", core.create_pairing_ticket(true)?);
+    }
+
+    wait_for_shutdown_signal().await?;
+    info!("interrupted, shutting down");
+
+    Ok(())
+}
+
+/// Waits for ctrl-c, or on unix for SIGTERM from a service manager.
+async fn wait_for_shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut sigterm =
+            signal(SignalKind::terminate()).context("failed to listen for SIGTERM")?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.context("failed to wait for ctrl-c")?,
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .context("failed to wait for ctrl-c")?;
+
+    Ok(())
+}
+
+/// Connects to a node and prints its index.
+pub async fn connect(
+    core: &Core,
+    endpoint_id: &str,
+    format: Option<TranscodeFormat>,
+) -> anyhow::Result<()> {
+    let index = connect_and_wait_for_index(core, endpoint_id, format).await?;
+
+    for item in &index {
+        println!("{}/{}", item.root, item.path);
+    }
+    println!("{} files", index.len());
+
+    core.close_client(endpoint_id)?;
+
+    Ok(())
+}
+
+/// Downloads everything a node has that isn't downloaded yet, and waits for the downloads.
+pub async fn sync(
+    core: &Core,
+    endpoint_id: &str,
+    download_dir: Option<String>,
+    format: Option<TranscodeFormat>,
+    mut sessions_rx: mpsc::UnboundedReceiver<TransferSessionCompletedEvent>,
+) -> anyhow::Result<()> {
+    if let Some(download_dir) = download_dir {
+        core.set_download_directory(&download_dir)?;
+    }
+
+    let requested = start_sync(core, endpoint_id, format).await?;
+    if requested == 0 {
+        println!("nothing to download");
+        core.close_client(endpoint_id)?;
+        return Ok(());
+    }
+
+    let event = wait_for_session(core, endpoint_id, &mut sessions_rx).await?;
+
+    println!(
+        "{} completed, {} failed, {} bytes transferred",
+        event.completed_files, event.failed_files, event.transferred_bytes
+    );
+
+    core.close_client(endpoint_id)?;
+
+    if event.failed_files > 0 {
+        anyhow::bail!("{} downloads failed", event.failed_files);
+    }
+
+    Ok(())
+}
+
+/// Checks the files downloaded from a node against its index, and optionally downloads the broken
+/// ones again.
+pub async fn verify(
+    core: &Core,
+    endpoint_id: &str,
+    format: Option<TranscodeFormat>,
+    requeue: bool,
+    mut sessions_rx: mpsc::UnboundedReceiver<TransferSessionCompletedEvent>,
+) -> anyhow::Result<()> {
+    connect_and_wait_for_index(core, endpoint_id, format).await?;
+
+    let issues = core.verify_downloads(endpoint_id, requeue).await?;
+    for issue in &issues {
+        println!("{}: {:?}", issue.local_path, issue.issue);
+    }
+    println!("{} files with issues", issues.len());
+
+    if issues.iter().any(|issue| issue.requeued) {
+        let event = wait_for_session(core, endpoint_id, &mut sessions_rx).await?;
+        println!(
+            "downloaded again: {} completed, {} failed",
+            event.completed_files, event.failed_files
+        );
+    }
+
+    core.close_client(endpoint_id)?;
+
+    Ok(())
+}
+
+/// Lists the downloaded files that changed on a node since they were downloaded, and optionally
+/// downloads them again.
+pub async fn changed(
+    core: &Core,
+    endpoint_id: &str,
+    format: Option<TranscodeFormat>,
+    requeue: bool,
+    mut sessions_rx: mpsc::UnboundedReceiver<TransferSessionCompletedEvent>,
+) -> anyhow::Result<()> {
+    connect_and_wait_for_index(core, endpoint_id, format).await?;
+
+    let changed = core.changed_downloads(endpoint_id, requeue).await?;
+    for file in &changed {
+        println!("{}/{}: {}", file.root, file.path, file.local_path);
+    }
+    println!("{} changed files", changed.len());
+
+    if changed.iter().any(|file| file.requeued) {
+        let event = wait_for_session(core, endpoint_id, &mut sessions_rx).await?;
+        println!(
+            "downloaded again: {} completed, {} failed",
+            event.completed_files, event.failed_files
+        );
+    }
+
+    core.close_client(endpoint_id)?;
+
+    Ok(())
+}
+
+/// Connects to a node and tracks the untracked files in the download directory that match files in
+/// its library as downloaded.
+pub async fn import(
+    core: &Core,
+    endpoint_id: &str,
+    folder: Option<String>,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    // without transcoding, so the index has the exact sizes of the files
+    connect_and_wait_for_index(core, endpoint_id, None).await?;
+
+    let result = core.import_downloads(endpoint_id, folder, dry_run).await?;
+    for file in &result.imported {
+        println!(
+            "{} -> {}/{} ({:?})",
+            file.local_path, file.root, file.path, file.matched_by
+        );
+    }
+    let verb = if dry_run { "would import" } else { "imported" };
+    println!(
+        "{verb} {} files, {} didn't match",
+        result.imported.len(),
+        result.unmatched_files
+    );
+
+    core.close_client(endpoint_id)?;
+
+    Ok(())
+}
+
+/// Prints the node's identity, library, connections, transfers, trusted nodes, and settings.
+pub fn status(core: &Core) -> anyhow::Result<()> {
+    let status = core.get_status()?;
+    let node_model = core.get_node_model()?;
+    let library_model = core.get_library_model()?;
+    let settings = core.get_settings_model()?;
+
+    println!(
+        "profile: {}",
+        core.get_profile().as_deref().unwrap_or("(default)")
+    );
+    println!("endpoint id: {}", node_model.endpoint_id);
+    println!("bound sockets: {}", node_model.bound_sockets.join(", "));
+
+    println!("library roots:");
+    for root in &library_model.local_roots {
+        println!("  {}: {} ({} files)", root.name, root.path, root.num_files);
+    }
+    println!(
+        "transcodes: {} ready, {} failed, in {}",
+        library_model.transcode_count_ready.get(),
+        library_model.transcode_count_failed.get(),
+        library_model.transcodes_dir
+    );
+    println!(
+        "connections: {} open, {} pending",
+        status.open_connections, status.pending_connections
+    );
+    println!(
+        "transfers: {} downloading, {} queued, {} uploading",
+        status.active_downloads, status.queued_downloads, status.active_uploads
+    );
+    if !node_model.interrupted_downloads.is_empty() {
+        println!(
+            "interrupted downloads: {}",
+            node_model.interrupted_downloads.len()
+        );
+    }
+    if let Some(cleanup) = &node_model.partial_cleanup
+        && (cleanup.deleted_files > 0 || cleanup.kept_files > 0)
+    {
+        println!(
+            "orphaned partial files: {} deleted ({} bytes reclaimed), {} kept ({} bytes)",
+            cleanup.deleted_files, cleanup.reclaimed_bytes, cleanup.kept_files, cleanup.kept_bytes
+        );
+    }
+    if !status.recent_errors.is_empty() {
+        println!("recent errors:");
+        for error in &status.recent_errors {
+            println!("  {:?}: {}", error.source, error.message);
+        }
+    }
+
+    println!("trusted nodes:");
+    for node in &node_model.trusted_nodes {
+        println!("  {}: {}", node.endpoint_id, node.name);
+    }
+
+    println!("settings: {settings:#?}");
+
+    Ok(())
+}
+
+/// Writes a diagnostic bundle.
+pub fn diagnostics(core: &Core, path: String) -> anyhow::Result<()> {
+    core.export_diagnostics(path.clone())?;
+    println!("wrote diagnostics to {path}");
+
+    Ok(())
+}
+
+/// Prints the named profiles.
+pub fn profiles(project_dirs: Option<ProjectDirsOptions>) -> anyhow::Result<()> {
+    for name in list_profiles(project_dirs)? {
+        println!("{name}");
+    }
+
+    Ok(())
+}
+
+/// Transcodes the library ahead of time and waits for the transcodes.
+pub async fn transcode(core: &Core, format: TranscodeFormat) -> anyhow::Result<()> {
+    set_ahead_of_time_transcoding(core, format)?;
+    core.wait_for_transcodes_queued().await?;
+
+    loop {
+        let model = core.get_library_model()?;
+        let queued = model.transcode_count_queued.get();
+        let inprogress = model.transcode_count_inprogress.get();
+
+        if !model.is_scanning && queued == 0 && inprogress == 0 {
+            println!(
+                "{} transcodes ready, {} failed",
+                model.transcode_count_ready.get(),
+                model.transcode_count_failed.get()
+            );
+            return Ok(());
+        }
+
+        info!("transcoding: {queued} queued, {inprogress} in progress");
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Stores settings that transcode the library ahead of time, including files found by later
+/// scans.
+fn set_ahead_of_time_transcoding(core: &Core, format: TranscodeFormat) -> anyhow::Result<()> {
+    let mut settings = core.get_settings_model()?;
+    settings.transcode_policy = TranscodePolicy::AheadOfTime;
+    settings.transcode_format = Some(format);
+    core.update_settings(settings)?;
+    Ok(())
+}
+
+/// Lists the nodes that files were downloaded from, or the files downloaded from a node, and
+/// optionally deletes them.
+pub async fn downloads(
+    core: &Core,
+    endpoint_id: Option<String>,
+    measure: bool,
+    delete_all: bool,
+) -> anyhow::Result<()> {
+    let Some(endpoint_id) = endpoint_id else {
+        let servers = if measure {
+            core.measure_downloaded_files().await?
+        } else {
+            core.list_downloaded_servers()?
+        };
+        for server in &servers {
+            print!(
+                "{}: {} files, {} bytes",
+                server.endpoint_id, server.files, server.bytes
+            );
+            match server.missing_files {
+                Some(missing_files) if missing_files > 0 => println!(", {missing_files} missing"),
+                _ => println!(),
+            }
+        }
+        return Ok(());
+    };
+
+    if delete_all {
+        let deleted = core
+            .delete_downloaded_files(&endpoint_id, vec![DownloadedFileSelectionModel::All])
+            .await?;
+        println!(
+            "deleted {} files ({} bytes), {} failed",
+            deleted.deleted_files, deleted.deleted_bytes, deleted.failed_files
+        );
+        return Ok(());
+    }
+
+    let files = core.list_downloaded_files(&endpoint_id)?;
+    for file in &files {
+        println!("{}/{}", file.local_tree, file.local_path);
+    }
+    println!("{} files", files.len());
+
+    Ok(())
+}
+
+/// Moves the downloaded files to the paths the download path template gives them, optionally
+/// storing a new template first.
+pub async fn reorganize(
+    core: &Core,
+    template: Option<String>,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    if let Some(template) = template {
+        core.set_download_path_template(Some(template))?;
+    }
+
+    let result = core.reorganize_downloads(dry_run).await?;
+    for moved in &result.moved {
+        println!("{} -> {}", moved.from, moved.to);
+    }
+    for conflict in &result.conflicts {
+        println!(
+            "{} -> {}: {:?}",
+            conflict.local_path, conflict.target, conflict.reason
+        );
+    }
+    let verb = if dry_run { "would move" } else { "moved" };
+    println!(
+        "{verb} {} files, {} conflicts",
+        result.moved.len(),
+        result.conflicts.len()
+    );
+
+    Ok(())
+}
+
+/// Connects to a node and requests everything it has that isn't downloaded yet or excluded by its
+/// download filter, returning the number of files requested.
+pub async fn start_sync(
+    core: &Core,
+    endpoint_id: &str,
+    format: Option<TranscodeFormat>,
+) -> anyhow::Result<usize> {
+    let index = connect_and_wait_for_index(core, endpoint_id, format).await?;
+
+    let (filtered, index): (Vec<_>, Vec<_>) = index.into_iter().partition(|item| item.filtered);
+    for item in &filtered {
+        info!(
+            "skipping {}/{}: excluded by download filter",
+            item.root, item.path
+        );
+    }
+
+    let download_requests = index
+        .into_iter()
+        .filter(|item| {
+            !matches!(
+                item.download_status,
+                Some(IndexItemDownloadStatusModel::Downloaded)
+            )
+        })
+        .map(|item| DownloadRequestModel {
+            endpoint_id: item.endpoint_id,
+            root: item.root,
+            path: item.path,
+        })
+        .collect::<Vec<_>>();
+
+    let requested = download_requests.len();
+    if requested > 0 {
+        info!("downloading {requested} files from {endpoint_id}");
+        core.set_downloads(endpoint_id, download_requests)?;
+    }
+
+    Ok(requested)
+}
+
+/// Connects to a node and waits for the connection to be accepted and the index to arrive.
+async fn connect_and_wait_for_index(
+    core: &Core,
+    endpoint_id: &str,
+    format: Option<TranscodeFormat>,
+) -> anyhow::Result<Vec<IndexItemModel>> {
+    info!("connecting to node: {endpoint_id}");
+    let progress: Arc<dyn OperationProgressHandler> = Arc::new(ConnectProgressLogger {
+        endpoint_id: endpoint_id.to_string(),
+    });
+    core.connect_and_wait(format, endpoint_id, Some(progress))
+        .await?;
+
+    // the index is sent again from the start if the server's library changes meanwhile
+    loop {
+        let client = open_client(core, endpoint_id)?;
+        if client.index_complete
+            && let Some(index) = client.index
+        {
+            return Ok(index.into());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Waits for the downloads from a node to finish, failing if the connection closes first.
+async fn wait_for_session(
+    core: &Core,
+    endpoint_id: &str,
+    sessions_rx: &mut mpsc::UnboundedReceiver<TransferSessionCompletedEvent>,
+) -> anyhow::Result<TransferSessionCompletedEvent> {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            // a session that finished just before the connection closed still counts
+            biased;
+
+            event = sessions_rx.recv() => {
+                let event = event.context("event handler was dropped")?;
+                if event.endpoint_id == endpoint_id {
+                    return Ok(event);
+                }
+            }
+            _ = interval.tick() => {
+                open_client(core, endpoint_id)?;
+            }
+        }
+    }
+}
+
+/// Gets the connection to a node, failing if it was closed or removed.
+fn open_client(core: &Core, endpoint_id: &str) -> anyhow::Result<ClientModel> {
+    let client = core
+        .get_node_model()?
+        .clients
+        .remove(endpoint_id)
+        .with_context(|| format!("connection to {endpoint_id} was removed"))?;
+    if let ClientStateModel::Closed { error } = &client.state {
+        anyhow::bail!(
+            "connection to {endpoint_id} closed: {}",
+            error.as_deref().unwrap_or("no error")
+        );
+    }
+    Ok(client)
+}
+
+/// Logs when a connection is waiting to be accepted.
+struct ConnectProgressLogger {
+    endpoint_id: String,
+}
+
+impl OperationProgressHandler for ConnectProgressLogger {
+    fn on_progress(&self, progress: OperationProgress) {
+        if let OperationProgress::ConnectionPending = progress {
+            info!("waiting for {} to accept the connection", self.endpoint_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::CliEventHandler;
+    use musicopy::CoreOptions;
+    use std::path::Path;
+
+    type SessionsRx = mpsc::UnboundedReceiver<TransferSessionCompletedEvent>;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("musicopy-cli-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn start_core(dir: &Path, label: &str) -> (Arc<Core>, SessionsRx) {
+        let data_dir = dir.join(label);
+        let (event_handler, sessions_rx) = CliEventHandler::new();
+        let core = Core::start(
+            Arc::new(event_handler),
+            CoreOptions {
+                init_logging: false,
+                in_memory: false,
+                project_dirs: Some(ProjectDirsOptions {
+                    cache_dir: data_dir.join("cache").to_string_lossy().into_owned(),
+                    data_dir: data_dir.to_string_lossy().into_owned(),
+                }),
+                relay_config: None,
+                lan_only: false,
+                bind_addrs: None,
+                model_diffs: false,
+                profile: None,
+            },
+        )
+        .await
+        .expect("should start core");
+        (core, sessions_rx)
+    }
+
+    /// Starts a server with two files that trusts the client, and a client that can reach it.
+    async fn start_pair(dir: &Path) -> (Arc<Core>, Arc<Core>, SessionsRx) {
+        let (server, _) = start_core(dir, "server").await;
+        let (client, sessions_rx) = start_core(dir, "client").await;
+
+        add_fixture_root(&server).await;
+        server.trust_node(&endpoint_id(&client)).unwrap();
+
+        server.add_endpoint_addr(client.get_endpoint_addr());
+        client.add_endpoint_addr(server.get_endpoint_addr());
+
+        (server, client, sessions_rx)
+    }
+
+    /// Adds a library root with two files and waits for it to be scanned.
+    async fn add_fixture_root(core: &Core) {
+        let fixture =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../musicopy/tests/fixtures/multiple");
+        core.add_library_root("music".into(), fixture.to_string_lossy().into_owned())
+            .unwrap();
+        core.rescan_library_and_wait(None).await.unwrap();
+    }
+
+    fn endpoint_id(core: &Core) -> String {
+        core.get_node_model().unwrap().endpoint_id
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connect_and_wait_for_index() {
+        let dir = test_dir("connect");
+        let (server, client, _) = start_pair(&dir).await;
+
+        let index = connect_and_wait_for_index(&client, &endpoint_id(&server), None)
+            .await
+            .unwrap();
+        assert_eq!(index.len(), 2);
+
+        client.shutdown().unwrap();
+        server.shutdown().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync() {
+        let dir = test_dir("sync");
+        let (server, client, sessions_rx) = start_pair(&dir).await;
+        let download_dir = dir.join("downloads");
+
+        sync(
+            &client,
+            &endpoint_id(&server),
+            Some(download_dir.to_string_lossy().into_owned()),
+            None,
+            sessions_rx,
+        )
+        .await
+        .unwrap();
+        let downloaded = client.list_downloaded_files(&endpoint_id(&server)).unwrap();
+        assert_eq!(downloaded.len(), 2);
+
+        client.shutdown().unwrap();
+        server.shutdown().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Waiting for downloads fails instead of hanging when the server closes the connection.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wait_for_session_closed() {
+        let dir = test_dir("closed");
+        let (server, client, mut sessions_rx) = start_pair(&dir).await;
+        let server_id = endpoint_id(&server);
+
+        connect_and_wait_for_index(&client, &server_id, None)
+            .await
+            .unwrap();
+        server.close_server(&endpoint_id(&client)).unwrap();
+
+        let err = tokio::time::timeout(
+            Duration::from_secs(10),
+            wait_for_session(&client, &server_id, &mut sessions_rx),
+        )
+        .await
+        .expect("should fail before the timeout")
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("closed"),
+            "unexpected error: {err:#}"
+        );
+
+        client.shutdown().unwrap();
+        server.shutdown().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_transcode() {
+        let dir = test_dir("transcode");
+        let (server, _) = start_core(&dir, "server").await;
+        add_fixture_root(&server).await;
+
+        transcode(&server, TranscodeFormat::Opus128).await.unwrap();
+        let model = server.get_library_model().unwrap();
+        assert_eq!(model.transcode_count_ready.get(), 2);
+        assert_eq!(model.transcode_count_queued.get(), 0);
+
+        server.shutdown().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
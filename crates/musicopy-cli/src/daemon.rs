@@ -0,0 +1,229 @@
+//! Daemon mode for `serve`, e.g. to run a family music server from a service unit without
+//! anyone logged in.
+//!
+//! `serve --daemon` re-runs the CLI in the background with the same arguments and exits once the
+//! child is spawned. The child writes its pid to the pid file, and periodically writes the same
+//! summary as the control API's `status` method to the status file, so scripts and service
+//! managers can check on it without connecting to the control socket. Both files are removed
+//! when it shuts down.
+
+use crate::rpc;
+use anyhow::Context;
+use musicopy::Core;
+use serde_json::json;
+use std::{
+    fs::{self, File, TryLockError},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, warn};
+
+/// Flag that runs `serve` in the background.
+const DAEMON_FLAG: &str = "--daemon";
+
+/// How often the status file is rewritten.
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Runs the CLI again in the background with the same arguments minus `--daemon`, and returns
+/// the child's pid.
+///
+/// The child's output goes to the log file if set, and is discarded otherwise. On unix, it runs
+/// in its own process group so it isn't interrupted along with the terminal that started it.
+pub fn spawn(log_file: Option<&Path>) -> anyhow::Result<u32> {
+    let exe = std::env::current_exe().context("failed to get current executable")?;
+    let args = std::env::args_os().skip(1).filter(|arg| arg != DAEMON_FLAG);
+
+    let (stdout, stderr) = match log_file {
+        Some(path) => {
+            let file = File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open log file {}", path.display()))?;
+            let file_clone = file.try_clone().context("failed to clone log file")?;
+            (Stdio::from(file), Stdio::from(file_clone))
+        }
+        None => (Stdio::null(), Stdio::null()),
+    };
+
+    let mut command = Command::new(exe);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .stderr(stderr);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    let child = command.spawn().context("failed to spawn daemon")?;
+    Ok(child.id())
+}
+
+/// A file with the pid of the running daemon, locked while it runs and removed when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    /// Holds the lock on the file, which is released when the process exits, even if it crashes.
+    _file: File,
+}
+
+impl PidFile {
+    /// Locks the file and writes the pid of this process to it.
+    ///
+    /// Fails if another process holds the lock. A file left behind by a daemon that crashed isn't
+    /// locked anymore, and is overwritten.
+    pub fn create(path: PathBuf) -> anyhow::Result<Self> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("failed to open pid file {}", path.display()))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut contents = String::new();
+                let _ = file.read_to_string(&mut contents);
+                match contents.trim().parse::<u32>() {
+                    Ok(pid) => anyhow::bail!(
+                        "daemon is already running with pid {pid}, according to {}",
+                        path.display()
+                    ),
+                    Err(_) => {
+                        anyhow::bail!("daemon is already running, according to {}", path.display())
+                    }
+                }
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e)
+                    .with_context(|| format!("failed to lock pid file {}", path.display()));
+            }
+        }
+
+        // the lock is held, so any contents are from a daemon that's no longer running
+        file.set_len(0)
+            .and_then(|()| file.write_all(format!("{}\n", std::process::id()).as_bytes()))
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("failed to write pid file {}", path.display()))?;
+
+        Ok(Self { path, _file: file })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // removed before the lock is released, so no other daemon has written to it yet
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("failed to remove pid file {}: {e:#}", self.path.display());
+        }
+    }
+}
+
+/// Periodically writes the daemon's status to a file, and removes it when dropped.
+#[derive(Debug)]
+pub struct StatusFile {
+    path: PathBuf,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl StatusFile {
+    /// Writes the status now, and starts rewriting it every `STATUS_INTERVAL`.
+    pub fn start(core: Arc<Core>, path: PathBuf) -> Self {
+        let task = tokio::spawn({
+            let path = path.clone();
+            async move {
+                let mut interval = tokio::time::interval(STATUS_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = write_status(&core, &path) {
+                        error!("failed to write status file {}: {e:#}", path.display());
+                    }
+                }
+            }
+        });
+
+        Self { path, task }
+    }
+}
+
+impl Drop for StatusFile {
+    fn drop(&mut self) {
+        self.task.abort();
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(
+                "failed to remove status file {}: {e:#}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+fn write_status(core: &Core, path: &Path) -> anyhow::Result<()> {
+    let status = rpc::status_json(core)?;
+    let updated_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let contents = json!({
+        "pid": std::process::id(),
+        "updated_at": updated_at,
+        "status": status,
+    });
+    write_atomic(path, format!("{contents:#}\n").as_bytes())
+}
+
+/// Writes a file through a temporary file next to it, so readers never see a partial write.
+fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut tmp_name = path
+        .file_name()
+        .context("path has no file name")?
+        .to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    fs::write(&tmp_path, contents).context("failed to write temporary file")?;
+    fs::rename(&tmp_path, path).context("failed to rename temporary file")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let dir = std::env::temp_dir().join(format!("musicopy-pid-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("musicopy.pid");
+
+        // a pid file left behind by a daemon that crashed is overwritten
+        fs::write(&path, "4294967295\n").unwrap();
+        let pid_file = PidFile::create(path.clone()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap().trim(),
+            std::process::id().to_string()
+        );
+
+        // a locked pid file can't be claimed again
+        let err = PidFile::create(path.clone()).unwrap_err();
+        assert!(
+            err.to_string().contains(&std::process::id().to_string()),
+            "unexpected error: {err:#}"
+        );
+
+        drop(pid_file);
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+use musicopy::{
+    DatabaseRecoveredEvent, EventHandler, ShutdownProgressEvent, StatsModel,
+    library::{LibraryModel, LibraryModelDiff},
+    node::{
+        BackgroundProgressEvent, ConnectionLostEvent, NodeModel, NodeModelDiff,
+        TransferJobFailedEvent, TransferSessionCompletedEvent,
+    },
+    settings::SettingsModel,
+};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Event handler that logs events and forwards finished download sessions to the running command.
+pub struct CliEventHandler {
+    sessions_tx: mpsc::UnboundedSender<TransferSessionCompletedEvent>,
+}
+
+impl CliEventHandler {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<TransferSessionCompletedEvent>) {
+        let (sessions_tx, sessions_rx) = mpsc::unbounded_channel();
+        (Self { sessions_tx }, sessions_rx)
+    }
+}
+
+impl EventHandler for CliEventHandler {
+    // commands poll the models when they need them
+    fn on_library_model_snapshot(&self, _model: LibraryModel) {}
+
+    fn on_node_model_snapshot(&self, _model: NodeModel) {}
+
+    fn on_stats_model_snapshot(&self, _model: StatsModel) {}
+
+    fn on_settings_model_snapshot(&self, _model: SettingsModel) {}
+
+    // model diffs aren't enabled
+    fn on_library_model_diff(&self, _diff: LibraryModelDiff) {}
+
+    fn on_node_model_diff(&self, _diff: NodeModelDiff) {}
+
+    fn on_transfer_session_completed(&self, event: TransferSessionCompletedEvent) {
+        info!(
+            "downloads from {} finished: {} completed, {} failed",
+            event.name, event.completed_files, event.failed_files
+        );
+
+        // the receiver is dropped if no command is waiting for downloads
+        let _ = self.sessions_tx.send(event);
+    }
+
+    fn on_transfer_job_failed(&self, event: TransferJobFailedEvent) {
+        warn!(
+            "download of {}/{} failed: {}",
+            event.file_root, event.file_path, event.error
+        );
+    }
+
+    fn on_connection_lost(&self, event: ConnectionLostEvent) {
+        warn!("connection to {} lost: {}", event.name, event.error);
+    }
+
+    fn on_database_recovered(&self, event: DatabaseRecoveredEvent) {
+        warn!(
+            "database was corrupted and moved to {}, lost tables: {:?}",
+            event.backup_path, event.lost_tables
+        );
+    }
+
+    fn on_shutdown_progress(&self, event: ShutdownProgressEvent) {
+        info!(
+            "shutdown {:?}: {} connections and {} transcodes active, {}s left",
+            event.phase, event.open_connections, event.active_transcodes, event.remaining_secs
+        );
+    }
+
+    // the CLI never enters the background
+    fn on_background_progress(&self, _event: BackgroundProgressEvent) {}
+}
@@ -0,0 +1,286 @@
+mod commands;
+mod daemon;
+mod handler;
+mod metrics;
+mod rpc;
+
+use crate::handler::CliEventHandler;
+use clap::{Parser, Subcommand};
+use musicopy::{Core, CoreOptions, ProjectDirsOptions, library::transcode::TranscodeFormat};
+use std::{path::PathBuf, sync::Arc};
+use tracing_subscriber::{EnvFilter, Registry, prelude::*};
+
+/// How long to wait for active transfers and transcodes when shutting down.
+const SHUTDOWN_DEADLINE_SECS: u64 = 30;
+
+/// Headless Musicopy node, e.g. for hosting a library on a NAS.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Whether to store state in memory only, without persisting to disk.
+    #[arg(long, short = 'm', global = true, default_value_t = false)]
+    in_memory: bool,
+
+    /// Directory to store the database and identity in, instead of the platform's data directory.
+    /// Transcodes are cached in its `cache` subdirectory.
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+
+    /// Whether to only connect to peers on the local network.
+    #[arg(long, global = true, default_value_t = false)]
+    lan_only: bool,
+
+    /// Local socket address to bind to, like `0.0.0.0:41641`. Can be repeated.
+    #[arg(long = "bind", global = true)]
+    bind_addrs: Vec<String>,
+
+    /// Profile to run, with its own database, identity, library, and trusted nodes. The default
+    /// profile is used if not set.
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve the library until interrupted.
+    ///
+    /// Connections from trusted nodes are accepted. Other connections are closed, unless
+    /// --accept-all is set. With --daemon, serves in the background, e.g. from a service unit.
+    Serve(commands::ServeArgs),
+
+    /// Connect to a node and list its library.
+    Connect {
+        /// Endpoint id of the node.
+        endpoint_id: String,
+
+        /// Transcode format to request, e.g. `opus128`. Original files are listed if not set.
+        #[arg(long, value_parser = parse_transcode_format)]
+        format: Option<TranscodeFormat>,
+    },
+
+    /// Download a node's library and exit when the downloads finish.
+    Sync {
+        /// Endpoint id of the node.
+        endpoint_id: String,
+
+        /// Directory to download to. Defaults to the stored download directory.
+        #[arg(long)]
+        download_dir: Option<String>,
+
+        /// Transcode format to download in, e.g. `opus128`. Original files are downloaded if not
+        /// set.
+        #[arg(long, value_parser = parse_transcode_format)]
+        format: Option<TranscodeFormat>,
+    },
+
+    /// Rehash the files downloaded from a node and check them against its index.
+    Verify {
+        /// Endpoint id of the node.
+        endpoint_id: String,
+
+        /// Transcode format the files were downloaded in, e.g. `opus128`.
+        #[arg(long, value_parser = parse_transcode_format)]
+        format: Option<TranscodeFormat>,
+
+        /// Whether to delete missing, corrupted, and outdated files and download them again.
+        #[arg(long, default_value_t = false)]
+        requeue: bool,
+    },
+
+    /// List the downloaded files that changed on a node since they were downloaded.
+    Changed {
+        /// Endpoint id of the node.
+        endpoint_id: String,
+
+        /// Transcode format the files were downloaded in, e.g. `opus128`.
+        #[arg(long, value_parser = parse_transcode_format)]
+        format: Option<TranscodeFormat>,
+
+        /// Whether to download the changed files again.
+        #[arg(long, default_value_t = false)]
+        requeue: bool,
+    },
+
+    /// Track the files in the download directory that weren't downloaded by musicopy, e.g. ones
+    /// copied by hand, as downloaded from a node if they match files in its library.
+    Import {
+        /// Endpoint id of the node.
+        endpoint_id: String,
+
+        /// Folder in the download directory to look in. The whole directory is used if not set.
+        #[arg(long)]
+        folder: Option<String>,
+
+        /// Whether to only print what would be imported.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// Print this node's identity, library, trusted nodes, and settings.
+    Status,
+
+    /// List the named profiles.
+    Profiles,
+
+    /// Transcode the library ahead of time and exit when the transcodes finish.
+    ///
+    /// The format is stored in the settings, so `serve` keeps transcoding new files after scans.
+    Transcode {
+        /// Transcode format, e.g. `opus128`.
+        #[arg(value_parser = parse_transcode_format)]
+        format: TranscodeFormat,
+    },
+
+    /// List the nodes that files were downloaded from, or the files downloaded from a node.
+    Downloads {
+        /// Endpoint id of a node to list the downloaded files of. The nodes are listed if not set.
+        endpoint_id: Option<String>,
+
+        /// Whether to measure the files on disk instead of using the sizes recorded when they
+        /// were downloaded.
+        #[arg(long, default_value_t = false)]
+        measure: bool,
+
+        /// Whether to delete everything downloaded from the node.
+        #[arg(long, default_value_t = false, requires = "endpoint_id")]
+        delete_all: bool,
+    },
+
+    /// Move the downloaded files to the paths the download path template gives them.
+    Reorganize {
+        /// Template to store before reorganizing, e.g. `{artist}/{album}/{track:02} - {title}`.
+        /// The stored template is used if not set.
+        #[arg(long)]
+        template: Option<String>,
+
+        /// Whether to only print what would be moved.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// Write a zip file with logs, settings, and database statistics to attach to a bug report.
+    Diagnostics {
+        /// Path of the zip file to write.
+        path: String,
+    },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    // Forward `log` records to `tracing`
+    let _ = tracing_log::LogTracer::init();
+
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("warn,musicopy_cli=info,musicopy=info"));
+
+    tracing::subscriber::set_global_default(
+        Registry::default()
+            .with(filter)
+            .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr)),
+    )?;
+
+    // spawn the daemon before starting core, so only the daemon opens the database
+    if let Command::Serve(serve_args) = &args.command
+        && serve_args.daemon
+    {
+        let pid = daemon::spawn(serve_args.log_file.as_deref())?;
+        println!("started daemon with pid {pid}");
+        return Ok(());
+    }
+
+    // claim the pid file before starting core, so a second instance fails before it opens the
+    // database. It's held until core has shut down.
+    let _pid_file = match &args.command {
+        Command::Serve(serve_args) => serve_args
+            .pid_file
+            .clone()
+            .map(daemon::PidFile::create)
+            .transpose()?,
+        _ => None,
+    };
+
+    let project_dirs = args.data_dir.map(|data_dir| ProjectDirsOptions {
+        cache_dir: data_dir.join("cache").to_string_lossy().into_owned(),
+        data_dir: data_dir.to_string_lossy().into_owned(),
+    });
+
+    let list_project_dirs = project_dirs.clone();
+
+    let (event_handler, sessions_rx) = CliEventHandler::new();
+    let core = Core::start(
+        Arc::new(event_handler),
+        CoreOptions {
+            init_logging: false,
+            in_memory: args.in_memory,
+            project_dirs,
+            relay_config: None,
+            lan_only: args.lan_only,
+            bind_addrs: (!args.bind_addrs.is_empty()).then_some(args.bind_addrs),
+            model_diffs: false,
+            profile: args.profile,
+        },
+    )
+    .await?;
+
+    let result = match args.command {
+        Command::Serve(serve_args) => commands::serve(&core, serve_args).await,
+        Command::Connect {
+            endpoint_id,
+            format,
+        } => commands::connect(&core, &endpoint_id, format).await,
+        Command::Sync {
+            endpoint_id,
+            download_dir,
+            format,
+        } => commands::sync(&core, &endpoint_id, download_dir, format, sessions_rx).await,
+        Command::Verify {
+            endpoint_id,
+            format,
+            requeue,
+        } => commands::verify(&core, &endpoint_id, format, requeue, sessions_rx).await,
+        Command::Changed {
+            endpoint_id,
+            format,
+            requeue,
+        } => commands::changed(&core, &endpoint_id, format, requeue, sessions_rx).await,
+        Command::Import {
+            endpoint_id,
+            folder,
+            dry_run,
+        } => commands::import(&core, &endpoint_id, folder, dry_run).await,
+        Command::Status => commands::status(&core),
+        Command::Profiles => commands::profiles(list_project_dirs),
+        Command::Transcode { format } => commands::transcode(&core, format).await,
+        Command::Downloads {
+            endpoint_id,
+            measure,
+            delete_all,
+        } => commands::downloads(&core, endpoint_id, measure, delete_all).await,
+        Command::Reorganize { template, dry_run } => {
+            commands::reorganize(&core, template, dry_run).await
+        }
+        Command::Diagnostics { path } => commands::diagnostics(&core, path),
+    };
+
+    // shut down core, letting active uploads and transcodes finish
+    core.shutdown_with_deadline(SHUTDOWN_DEADLINE_SECS).await?;
+
+    result
+}
+
+pub(crate) fn parse_root(s: &str) -> Result<(String, String), String> {
+    let (name, path) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <name>=<path>, got {s}"))?;
+    Ok((name.to_string(), path.to_string()))
+}
+
+pub(crate) fn parse_transcode_format(s: &str) -> Result<TranscodeFormat, String> {
+    s.parse().map_err(|e: anyhow::Error| {
+        format!("{e:#}, expected one of opus192, opus128, opus96, opus64, mp3v0, mp3v5")
+    })
+}
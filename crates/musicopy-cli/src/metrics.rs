@@ -0,0 +1,111 @@
+//! HTTP endpoint for Prometheus to scrape metrics from.
+//!
+//! Serves the core's metrics at `/metrics` in the OpenMetrics text format. Like the share server,
+//! this is a minimal HTTP/1.1 server that only answers simple GET requests, one per connection.
+
+use anyhow::Context;
+use musicopy::{Core, metrics::OPENMETRICS_CONTENT_TYPE};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+/// Maximum size of a request line and headers.
+const MAX_REQUEST_HEAD_SIZE: u64 = 8 * 1024;
+
+/// How long a scrape can take to send its request before the connection is closed.
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves metrics on a TCP address until the task is dropped.
+pub async fn serve(core: Arc<Core>, addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind metrics endpoint to {addr}"))?;
+    info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    loop {
+        let (stream, remote_addr) = listener
+            .accept()
+            .await
+            .context("failed to accept metrics connection")?;
+        debug!("metrics request from {remote_addr}");
+
+        let core = core.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&core, stream).await {
+                warn!("metrics request from {remote_addr} failed: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(core: &Core, mut stream: TcpStream) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_HEAD_SIZE));
+
+    let request_line = tokio::time::timeout(REQUEST_HEAD_TIMEOUT, async {
+        let mut request_line = String::new();
+        reader
+            .read_line(&mut request_line)
+            .await
+            .context("failed to read request line")?;
+
+        // skip the headers
+        loop {
+            let mut line = String::new();
+            let n = reader
+                .read_line(&mut line)
+                .await
+                .context("failed to read header")?;
+            if n == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+        anyhow::Ok(request_line)
+    })
+    .await
+    .context("timed out reading request")??;
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => match core.export_metrics() {
+            Ok(metrics) => ("200 OK", OPENMETRICS_CONTENT_TYPE, metrics),
+            Err(e) => (
+                "500 Internal Server Error",
+                "text/plain; charset=utf-8",
+                format!("failed to export metrics: {e}\n"),
+            ),
+        },
+        (Some("GET"), Some(_)) => (
+            "404 Not Found",
+            "text/plain; charset=utf-8",
+            "not found\n".to_string(),
+        ),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain; charset=utf-8",
+            "method not allowed\n".to_string(),
+        ),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    writer
+        .write_all(head.as_bytes())
+        .await
+        .context("failed to write response head")?;
+    writer
+        .write_all(body.as_bytes())
+        .await
+        .context("failed to write response body")?;
+    writer.shutdown().await.context("failed to shut down")?;
+
+    Ok(())
+}
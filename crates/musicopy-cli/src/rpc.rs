@@ -0,0 +1,653 @@
+//! Local control API for scripts and other tools.
+//!
+//! A small JSON-RPC 2.0 server that exposes core commands and model snapshots, e.g. to trigger
+//! rescans, start syncs, and query transfer progress on a headless install. Requests and
+//! responses are single lines of JSON, up to [`MAX_REQUEST_LEN`] bytes.
+//!
+//! On a unix socket, the socket's permissions decide who can use the API. On a TCP address, any
+//! local process or a web page could connect, so the first request of each connection has to be
+//! `auth` with the token from the token file, and the connection is closed otherwise.
+//!
+//! Methods:
+//! - `auth` `{token}`: authenticates a TCP connection
+//! - `status`: the endpoint id, library roots, and a summary of scans, transfers, connections,
+//!   and recent errors
+//! - `library.get`: the library model
+//! - `library.rescan`: rescans the library
+//! - `connections.list`: incoming and outgoing connections with their transfer progress
+//! - `connections.accept` `{endpoint_id, trust?}`: accepts a pending incoming connection
+//! - `connections.close` `{endpoint_id}`: closes an outgoing connection
+//! - `transfers.progress`: the combined progress of all downloads
+//! - `transfers.pause` `{endpoint_id}`: pauses the downloads from a node
+//! - `sync.start` `{endpoint_id, format?}`: connects to a node and downloads everything it has
+//!   that isn't downloaded yet, returning once the downloads are requested
+
+use crate::commands;
+use anyhow::Context;
+use musicopy::{
+    Core,
+    error::CoreError,
+    library::{LibraryModel, transcode::TranscodeFormat},
+    node::{ClientStateModel, ServerStateModel, SessionProgressModel},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use std::{net::SocketAddr, path::Path, sync::Arc};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::TcpListener,
+};
+use tracing::{debug, info, warn};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Error code for a core command that failed.
+const CORE_ERROR: i64 = -32000;
+/// Error code for a request on a TCP connection that isn't authenticated.
+const UNAUTHORIZED: i64 = -32001;
+
+/// Maximum length of a request line. Longer requests close the connection.
+pub const MAX_REQUEST_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    /// None for notifications, which don't get a response.
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+impl From<CoreError> for RpcError {
+    fn from(e: CoreError) -> Self {
+        Self {
+            code: CORE_ERROR,
+            message: e.to_string(),
+            data: Some(json!({ "kind": format!("{:?}", e.kind()) })),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(CORE_ERROR, format!("{e:#}"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointParams {
+    endpoint_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AcceptParams {
+    endpoint_id: String,
+    #[serde(default)]
+    trust: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthParams {
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncParams {
+    endpoint_id: String,
+    #[serde(default)]
+    format: Option<String>,
+}
+
+/// Reads the token that TCP connections authenticate with from a file. On unix, the file can't be
+/// readable by other users.
+pub fn read_token_file(path: &Path) -> anyhow::Result<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("failed to read control API token file {}", path.display()))?;
+        anyhow::ensure!(
+            metadata.permissions().mode() & 0o077 == 0,
+            "control API token file {} can be read by other users, set its mode to 0600",
+            path.display()
+        );
+    }
+
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read control API token file {}", path.display()))?;
+    let token = token.trim().to_string();
+    anyhow::ensure!(
+        token.len() >= 16,
+        "control API token in {} must be at least 16 characters",
+        path.display()
+    );
+    Ok(token)
+}
+
+/// Serves the API on a TCP address until the task is dropped. Connections have to authenticate
+/// with the token before anything else.
+pub async fn serve_tcp(core: Arc<Core>, addr: SocketAddr, token: String) -> anyhow::Result<()> {
+    let token = Arc::new(token);
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind control API to {addr}"))?;
+    info!("control API listening on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, remote_addr) = listener
+            .accept()
+            .await
+            .context("failed to accept control API connection")?;
+        debug!("control API connection from {remote_addr}");
+
+        let core = core.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&core, stream, Some(&token)).await {
+                warn!("control API connection from {remote_addr} failed: {e:#}");
+            }
+        });
+    }
+}
+
+/// Serves the API on a unix socket until the task is dropped. A stale socket file left by an
+/// earlier run is replaced, but any other file at the path is left alone and serving fails.
+#[cfg(unix)]
+pub async fn serve_unix(core: Arc<Core>, path: std::path::PathBuf) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        anyhow::ensure!(
+            metadata.file_type().is_socket(),
+            "{} exists and isn't a socket, not replacing it",
+            path.display()
+        );
+        std::fs::remove_file(&path).context("failed to remove stale control API socket")?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(&path)
+        .with_context(|| format!("failed to bind control API to {}", path.display()))?;
+    info!("control API listening on {}", path.display());
+
+    loop {
+        let (stream, _) = listener
+            .accept()
+            .await
+            .context("failed to accept control API connection")?;
+
+        let core = core.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&core, stream, None).await {
+                warn!("control API connection failed: {e:#}");
+            }
+        });
+    }
+}
+
+/// Handles requests from a connection, one per line, until it closes.
+///
+/// If a token is given, the first request has to be `auth` with the token, and the connection is
+/// closed after responding to anything else.
+async fn handle_connection(
+    core: &Core,
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    token: Option<&str>,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut authenticated = token.is_none();
+
+    while let Some(line) = read_request_line(&mut reader).await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = if authenticated {
+            handle_line(core, &line).await
+        } else {
+            let (response, ok) = authenticate(&line, token.unwrap_or_default());
+            if !ok {
+                write_response(&mut writer, &response).await?;
+                anyhow::bail!("connection didn't authenticate");
+            }
+            authenticated = true;
+            Some(response)
+        };
+
+        if let Some(response) = response {
+            write_response(&mut writer, &response).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a request line without its line ending, or None at the end of the stream. Fails if the
+/// line is longer than [`MAX_REQUEST_LEN`] or isn't UTF-8.
+async fn read_request_line(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> anyhow::Result<Option<String>> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_REQUEST_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await
+        .context("failed to read request")?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    } else if line.len() > MAX_REQUEST_LEN {
+        anyhow::bail!("request is longer than {MAX_REQUEST_LEN} bytes");
+    }
+
+    let line = String::from_utf8(line).context("request isn't UTF-8")?;
+    Ok(Some(line))
+}
+
+async fn write_response(
+    writer: &mut (impl AsyncWrite + Unpin),
+    response: &Response,
+) -> anyhow::Result<()> {
+    let mut response = serde_json::to_vec(response).context("failed to serialize response")?;
+    response.push(b'\n');
+    writer
+        .write_all(&response)
+        .await
+        .context("failed to write response")
+}
+
+/// Checks that a request is `auth` with the token, returning its response and whether it was.
+fn authenticate(line: &str, token: &str) -> (Response, bool) {
+    let request = serde_json::from_str::<Request>(line).ok();
+    let id = request
+        .as_ref()
+        .and_then(|request| request.id.clone())
+        .unwrap_or(Value::Null);
+    let params = request
+        .filter(|request| request.jsonrpc == "2.0" && request.method == "auth")
+        .and_then(|request| parse_params::<AuthParams>(request.params).ok());
+
+    match params {
+        Some(params) if constant_time_eq(params.token.as_bytes(), token.as_bytes()) => (
+            Response {
+                jsonrpc: "2.0",
+                id,
+                result: Some(Value::Null),
+                error: None,
+            },
+            true,
+        ),
+        _ => (
+            error_response(id, RpcError::new(UNAUTHORIZED, "not authenticated")),
+            false,
+        ),
+    }
+}
+
+/// Compares two byte strings in time that only depends on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Handles a request, returning its response, or None if it was a notification.
+async fn handle_line(core: &Core, line: &str) -> Option<Response> {
+    let request = match serde_json::from_str::<Value>(line) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, e.to_string()),
+            ));
+        }
+    };
+
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let request = match serde_json::from_value::<Request>(request) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        Ok(_) => {
+            return Some(error_response(
+                id,
+                RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""),
+            ));
+        }
+        Err(e) => {
+            return Some(error_response(
+                id,
+                RpcError::new(INVALID_REQUEST, e.to_string()),
+            ));
+        }
+    };
+
+    let result = call(core, &request.method, request.params).await;
+    if let Err(e) = &result {
+        debug!("control API {} failed: {}", request.method, e.message);
+    }
+
+    let id = request.id?;
+    Some(match result {
+        Ok(result) => Response {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        },
+        Err(e) => error_response(id, e),
+    })
+}
+
+fn error_response(id: Value, error: RpcError) -> Response {
+    Response {
+        jsonrpc: "2.0",
+        id,
+        result: None,
+        error: Some(error),
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+/// Runs a method.
+async fn call(core: &Core, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "status" => Ok(status_json(core)?),
+        // only meaningful as the first request on a TCP connection
+        "auth" => Ok(Value::Null),
+
+        "library.get" => Ok(library_json(&core.get_library_model()?)),
+        "library.rescan" => {
+            core.rescan_library()?;
+            Ok(Value::Null)
+        }
+
+        "connections.list" => {
+            let node_model = core.get_node_model()?;
+
+            let servers = node_model
+                .servers
+                .values()
+                .map(|server| {
+                    let (state, error) = match &server.state {
+                        ServerStateModel::Pending => ("pending", None),
+                        ServerStateModel::Accepted => ("accepted", None),
+                        ServerStateModel::Closed { error } => ("closed", error.clone()),
+                    };
+                    json!({
+                        "endpoint_id": server.endpoint_id,
+                        "name": server.name,
+                        "state": state,
+                        "error": error,
+                        "draining": server.draining,
+                        "session": session_json(&server.session),
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let clients = node_model
+                .clients
+                .values()
+                .map(|client| {
+                    let (state, error) = match &client.state {
+                        ClientStateModel::Pending => ("pending", None),
+                        ClientStateModel::Accepted => ("accepted", None),
+                        ClientStateModel::Closed { error } => ("closed", error.clone()),
+                    };
+                    json!({
+                        "endpoint_id": client.endpoint_id,
+                        "name": client.name,
+                        "state": state,
+                        "error": error,
+                        "draining": client.draining,
+                        "index_files": client.index.as_ref().map(|index| index.len()),
+                        "session": session_json(&client.session),
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            Ok(json!({ "servers": servers, "clients": clients }))
+        }
+        "connections.accept" => {
+            let params: AcceptParams = parse_params(params)?;
+            if params.trust {
+                core.accept_connection_and_trust(&params.endpoint_id)?;
+            } else {
+                core.accept_connection(&params.endpoint_id)?;
+            }
+            Ok(Value::Null)
+        }
+        "connections.close" => {
+            let params: EndpointParams = parse_params(params)?;
+            core.close_client(&params.endpoint_id)?;
+            Ok(Value::Null)
+        }
+
+        "transfers.progress" => {
+            let progress = core.get_download_progress()?;
+            Ok(json!({
+                "servers": progress.servers,
+                "queued_jobs": progress.queued_jobs,
+                "active_jobs": progress.active_jobs,
+                "finished_jobs": progress.finished_jobs,
+                "failed_jobs": progress.failed_jobs,
+                "total_bytes": progress.total_bytes,
+                "downloaded_bytes": progress.downloaded_bytes,
+            }))
+        }
+        "transfers.pause" => {
+            let params: EndpointParams = parse_params(params)?;
+            core.pause_downloads(&params.endpoint_id)?;
+            Ok(Value::Null)
+        }
+
+        "sync.start" => {
+            let params: SyncParams = parse_params(params)?;
+            let format = params
+                .format
+                .map(|format| format.parse::<TranscodeFormat>())
+                .transpose()
+                .map_err(|e| RpcError::new(INVALID_PARAMS, format!("{e:#}")))?;
+
+            let requested = commands::start_sync(core, &params.endpoint_id, format).await?;
+            Ok(json!({ "requested_files": requested }))
+        }
+
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method: {method}"),
+        )),
+    }
+}
+
+/// Summarizes the scans, transfers, connections, and recent errors, for the `status` method and
+/// the daemon's status file.
+pub(crate) fn status_json(core: &Core) -> Result<Value, CoreError> {
+    let status = core.get_status()?;
+    let library_model = core.get_library_model()?;
+
+    let recent_errors = status
+        .recent_errors
+        .iter()
+        .map(|error| {
+            json!({
+                "at": error.at,
+                "source": format!("{:?}", error.source).to_lowercase(),
+                "message": error.message,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "endpoint_id": status.endpoint_id,
+        "scanning": status.is_scanning,
+        "roots": library_roots_json(&library_model),
+        "transcodes": {
+            "queued": status.transcodes_queued,
+            "in_progress": status.transcodes_in_progress,
+            "failed": status.transcodes_failed,
+        },
+        "connections": {
+            "open": status.open_connections,
+            "pending": status.pending_connections,
+        },
+        "transfers": {
+            "active_downloads": status.active_downloads,
+            "queued_downloads": status.queued_downloads,
+            "active_uploads": status.active_uploads,
+            "held_by": status
+                .transfer_hold_reasons
+                .iter()
+                .map(|reason| format!("{reason:?}"))
+                .collect::<Vec<_>>(),
+        },
+        "recent_errors": recent_errors,
+    }))
+}
+
+fn library_roots_json(model: &LibraryModel) -> Vec<Value> {
+    model
+        .local_roots
+        .iter()
+        .map(|root| {
+            json!({
+                "name": root.name,
+                "path": root.path,
+                "num_files": root.num_files,
+            })
+        })
+        .collect()
+}
+
+fn library_json(model: &LibraryModel) -> Value {
+    json!({
+        "scanning": model.is_scanning,
+        "roots": library_roots_json(model),
+        "transcodes_dir": model.transcodes_dir,
+        "transcodes": {
+            "queued": model.transcode_count_queued.get(),
+            "in_progress": model.transcode_count_inprogress.get(),
+            "ready": model.transcode_count_ready.get(),
+            "failed": model.transcode_count_failed.get(),
+        },
+    })
+}
+
+fn session_json(session: &SessionProgressModel) -> Value {
+    json!({
+        "queued_files": session.queued_files,
+        "active_files": session.active_files,
+        "paused_files": session.paused_files,
+        "completed_files": session.completed_files,
+        "failed_files": session.failed_files,
+        "expected_bytes": session.expected_bytes,
+        "transferred_bytes": session.transferred_bytes,
+        "eta_secs": session.eta_secs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request: Request =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","id":1,"method":"status"}"#).unwrap();
+        assert_eq!(request.id, Some(json!(1)));
+        assert_eq!(request.method, "status");
+        assert_eq!(request.params, Value::Null);
+
+        // notifications have no id
+        let request: Request =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","method":"library.rescan","params":{}}"#)
+                .unwrap();
+        assert_eq!(request.id, None);
+
+        let params: AcceptParams = parse_params(json!({ "endpoint_id": "abc" })).unwrap();
+        assert!(!params.trust);
+        assert!(parse_params::<EndpointParams>(json!({})).is_err());
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let token = "0123456789abcdef";
+
+        let (_, ok) = authenticate(
+            r#"{"jsonrpc":"2.0","id":1,"method":"auth","params":{"token":"0123456789abcdef"}}"#,
+            token,
+        );
+        assert!(ok);
+
+        let (response, ok) = authenticate(
+            r#"{"jsonrpc":"2.0","id":1,"method":"auth","params":{"token":"wrong"}}"#,
+            token,
+        );
+        assert!(!ok);
+        assert_eq!(response.error.unwrap().code, UNAUTHORIZED);
+
+        // other methods and requests from other protocols don't authenticate
+        let (_, ok) = authenticate(
+            r#"{"jsonrpc":"2.0","id":1,"method":"connections.accept","params":{}}"#,
+            token,
+        );
+        assert!(!ok);
+        let (_, ok) = authenticate("POST / HTTP/1.1", token);
+        assert!(!ok);
+    }
+
+    #[tokio::test]
+    async fn test_read_request_line() {
+        let mut reader = BufReader::new(&b"first\nsecond"[..]);
+        assert_eq!(
+            read_request_line(&mut reader).await.unwrap().unwrap(),
+            "first"
+        );
+        assert_eq!(
+            read_request_line(&mut reader).await.unwrap().unwrap(),
+            "second"
+        );
+        assert!(read_request_line(&mut reader).await.unwrap().is_none());
+
+        let long = vec![b'a'; MAX_REQUEST_LEN + 10];
+        let mut reader = BufReader::new(&long[..]);
+        assert!(read_request_line(&mut reader).await.is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use musicopy_transcode::hash::{get_file_duration, get_file_hash};
-use std::{path::Path, process};
+use std::{path::Path, process, time::Instant};
 use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
 
@@ -22,11 +22,18 @@ fn main() {
 
     let mut failed = false;
 
+    let start = Instant::now();
     match get_file_hash(path) {
-        Ok((kind, hash)) => info!(
-            "get_file_hash: {kind} {}",
-            hash.iter().map(|b| format!("{b:02x}")).collect::<String>()
-        ),
+        Ok((kind, hash)) => {
+            // report throughput to compare read strategies on big files
+            let elapsed = start.elapsed().as_secs_f64();
+            let file_size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            info!(
+                "get_file_hash: {kind} {} in {elapsed:.3}s ({:.1} MB/s)",
+                hash.iter().map(|b| format!("{b:02x}")).collect::<String>(),
+                file_size as f64 / 1_000_000.0 / elapsed,
+            );
+        }
         Err(e) => {
             error!("error getting hash: {e:#}");
             failed = true;
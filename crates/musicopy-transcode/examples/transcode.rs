@@ -15,7 +15,7 @@ fn main() {
 
     if args.len() < 3 || args.len() > 4 {
         error!("usage: transcode <input> <output> [format]");
-        error!("  format: opus128 (default), opus64, mp3v0, mp3v5");
+        error!("  format: opus128 (default), opus192, opus96, opus64, mp3v0, mp3v5");
         process::exit(1);
     }
 
@@ -46,11 +46,13 @@ fn main() {
 fn parse_format(s: &str) -> Result<TranscodePreset, String> {
     match s {
         "opus128" => Ok(TranscodePreset::Opus(OpusPreset::Opus128)),
+        "opus192" => Ok(TranscodePreset::Opus(OpusPreset::Opus192)),
+        "opus96" => Ok(TranscodePreset::Opus(OpusPreset::Opus96)),
         "opus64" => Ok(TranscodePreset::Opus(OpusPreset::Opus64)),
         "mp3v0" => Ok(TranscodePreset::Mp3(Mp3Preset::Mp3V0)),
         "mp3v5" => Ok(TranscodePreset::Mp3(Mp3Preset::Mp3V5)),
         other => Err(format!(
-            "unknown format '{other}' (expected: opus128, opus64, mp3v0, mp3v5)"
+            "unknown format '{other}' (expected: opus128, opus192, opus96, opus64, mp3v0, mp3v5)"
         )),
     }
 }
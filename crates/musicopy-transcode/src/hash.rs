@@ -5,7 +5,8 @@ use std::{hash::Hasher, path::Path};
 use symphonia::core::{
     codecs::audio::VerificationCheck,
     formats::{Track, TrackType, probe::Hint},
-    io::MediaSourceStream,
+    io::{MediaSourceStream, MediaSourceStreamOptions},
+    meta::StandardTag,
     units::Timestamp,
 };
 #[cfg(feature = "transcode")]
@@ -13,6 +14,14 @@ use tracing::{trace, warn};
 #[cfg(feature = "transcode")]
 use twox_hash::XxHash3_64;
 
+/// Size of the read buffer used when hashing a file.
+///
+/// Hashing reads the whole file, so this is larger than symphonia's default of 64 KiB to read big
+/// files like WAVs in fewer, longer reads, e.g. for spinning disks or when many files are hashed
+/// in parallel. Must be a power of two.
+#[cfg(feature = "transcode")]
+const HASH_BUFFER_LEN: usize = 1024 * 1024;
+
 /// Get the hash of a file.
 ///
 /// If the file contains an MD5 checksum (many flacs do), then it will be used.
@@ -22,7 +31,12 @@ use twox_hash::XxHash3_64;
 pub fn get_file_hash(path: &Path) -> anyhow::Result<(&'static str, [u8; 16])> {
     let src = std::fs::File::open(path).context("failed to open file")?;
 
-    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+    let mss = MediaSourceStream::new(
+        Box::new(src),
+        MediaSourceStreamOptions {
+            buffer_len: HASH_BUFFER_LEN,
+        },
+    );
 
     let mut hint = Hint::new();
     if let Some(extension) = path.extension() {
@@ -192,6 +206,66 @@ fn get_audio_track_duration(audio_track: &Track) -> Option<f64> {
     }
 }
 
+/// Tags read from a file's metadata.
+#[derive(Debug, Clone, Default)]
+pub struct FileTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    /// Duration in seconds, if it can be read without decoding the file.
+    pub duration: Option<f64>,
+    /// Short name of the file's format, e.g. `flac`.
+    pub codec: Option<String>,
+    pub sample_rate: Option<u32>,
+}
+
+/// Gets the tags and stream info of a file by reading its metadata, without decoding it.
+#[cfg(feature = "transcode")]
+pub fn get_file_tags(path: &Path) -> anyhow::Result<FileTags> {
+    let src = std::fs::File::open(path).context("failed to open file")?;
+
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension() {
+        hint.with_extension(extension.to_str().context("invalid file extension")?);
+    }
+
+    let mut format = symphonia::default::get_probe()
+        .probe(&hint, mss, Default::default(), Default::default())
+        .context("failed to probe file")?;
+
+    let mut tags = FileTags::default();
+    if let Some(metadata) = format.metadata().skip_to_latest() {
+        for tag in metadata.media.tags.iter().flat_map(|t| &t.std) {
+            match tag {
+                StandardTag::TrackTitle(tag) => tags.title = Some(tag.to_string()),
+                StandardTag::Artist(tag) => tags.artist = Some(tag.to_string()),
+                StandardTag::Album(tag) => tags.album = Some(tag.to_string()),
+                StandardTag::AlbumArtist(tag) => tags.album_artist = Some(tag.to_string()),
+                StandardTag::TrackNumber(tag) => tags.track_number = (*tag).try_into().ok(),
+                StandardTag::DiscNumber(tag) => tags.disc_number = (*tag).try_into().ok(),
+                _ => {}
+            }
+        }
+    }
+
+    tags.codec = Some(format.format_info().short_name.to_string());
+    if let Some(audio_track) = format.default_track(TrackType::Audio) {
+        tags.duration = get_audio_track_duration(audio_track);
+        tags.sample_rate = audio_track
+            .codec_params
+            .as_ref()
+            .and_then(|codec_params| codec_params.audio())
+            .and_then(|audio_codec_params| audio_codec_params.sample_rate);
+    }
+
+    Ok(tags)
+}
+
 /// Stub implementation when compiled without the `transcode` feature.
 #[cfg(not(feature = "transcode"))]
 pub fn get_file_hash(_path: &Path) -> anyhow::Result<(&'static str, [u8; 16])> {
@@ -203,3 +277,9 @@ pub fn get_file_hash(_path: &Path) -> anyhow::Result<(&'static str, [u8; 16])> {
 pub fn get_file_duration(_path: &Path) -> anyhow::Result<f64> {
     anyhow::bail!("get_file_duration is not supported without the transcode feature")
 }
+
+/// Stub implementation when compiled without the `transcode` feature.
+#[cfg(not(feature = "transcode"))]
+pub fn get_file_tags(_path: &Path) -> anyhow::Result<FileTags> {
+    anyhow::bail!("get_file_tags is not supported without the transcode feature")
+}
@@ -32,7 +32,9 @@ pub enum TranscodePreset {
 }
 
 pub enum OpusPreset {
+    Opus192,
     Opus128,
+    Opus96,
     Opus64,
 }
 
@@ -170,7 +172,9 @@ fn transcode_opus(
     .context("failed to create opus encoder")?;
     encoder
         .set_bitrate(match preset {
+            OpusPreset::Opus192 => opus::Bitrate::Bits(192000),
             OpusPreset::Opus128 => opus::Bitrate::Bits(128000),
+            OpusPreset::Opus96 => opus::Bitrate::Bits(96000),
             OpusPreset::Opus64 => opus::Bitrate::Bits(64000),
         })
         .context("failed to set opus bitrate")?;
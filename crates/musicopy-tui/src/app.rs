@@ -4,16 +4,22 @@ use crate::{
 };
 use anyhow::Context;
 use musicopy::{
-    Core, CoreOptions, StatsModel,
-    library::{LibraryModel, transcode::TranscodeFormat},
-    node::{ClientStateModel, DownloadRequestModel, NodeModel, ServerStateModel},
+    Core, CoreOptions, DatabaseRecoveredEvent, ShutdownProgressEvent, StatsModel,
+    deep_link::DeepLinkModel,
+    library::{LibraryModel, LibraryModelDiff, transcode::TranscodeFormat},
+    node::{
+        BackgroundProgressEvent, ClientStateModel, ConnectionLostEvent, DownloadRequestModel,
+        NodeModel, NodeModelDiff, ServerStateModel, TransferJobFailedEvent,
+        TransferSessionCompletedEvent,
+    },
+    settings::SettingsModel,
 };
 use ratatui::{
     DefaultTerminal,
     crossterm::event::{KeyCode, KeyEvent, KeyModifiers},
 };
 use std::{sync::Arc, time::SystemTime};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tui_widgets::prompts::{State, Status, TextState};
 
 /// Application.
@@ -90,6 +96,11 @@ impl<'a> App<'a> {
                 init_logging: false,
                 in_memory,
                 project_dirs: None,
+                relay_config: None,
+                lan_only: false,
+                bind_addrs: None,
+                model_diffs: false,
+                profile: None,
             },
         )
         .await?;
@@ -358,6 +369,28 @@ impl<'a> App<'a> {
                 });
             }
 
+            "open" => {
+                if parts.len() < 2 {
+                    anyhow::bail!("usage: open <link or ticket>");
+                }
+
+                let url = parts[1].to_string();
+
+                let core = self.core.clone();
+                let transcode_format = self.transcode_format;
+                tokio::spawn(async move {
+                    match core.handle_url(url, transcode_format).await {
+                        Ok(DeepLinkModel::Connecting { endpoint_id }) => {
+                            info!("connecting to node: {endpoint_id}");
+                        }
+                        Ok(DeepLinkModel::ShareLink { url }) => {
+                            info!("share link, open it in a browser: {url}");
+                        }
+                        Err(e) => error!("error opening link: {e:#}"),
+                    }
+                });
+            }
+
             "dc" | "disconnect" => {
                 info!("disconnecting everything");
 
@@ -397,6 +430,7 @@ impl<'a> App<'a> {
                     .as_ref()
                     .ok_or(anyhow::anyhow!("client index not available"))?
                     .iter()
+                    .filter(|item| !item.filtered)
                     .map(|item| DownloadRequestModel {
                         endpoint_id: endpoint_id.clone(),
                         root: item.root.clone(),
@@ -508,11 +542,13 @@ impl<'a> App<'a> {
 
             "f" | "format" => {
                 if parts.len() < 2 {
-                    anyhow::bail!("usage: format <opus128|opus64|mp3v0|mp3v5|none>");
+                    anyhow::bail!("usage: format <opus192|opus128|opus96|opus64|mp3v0|mp3v5|none>");
                 }
 
                 let format = match parts[1] {
                     "opus128" => Some(TranscodeFormat::Opus128),
+                    "opus192" => Some(TranscodeFormat::Opus192),
+                    "opus96" => Some(TranscodeFormat::Opus96),
                     "opus64" => Some(TranscodeFormat::Opus64),
                     "mp3v0" => Some(TranscodeFormat::Mp3V0),
                     "mp3v5" => Some(TranscodeFormat::Mp3V5),
@@ -525,15 +561,23 @@ impl<'a> App<'a> {
             "connectinfo" => {
                 for server in self.node_model.servers.values() {
                     info!(
-                        "server {}: status={:?} remote_addr={} latency_ms={:?}",
-                        server.endpoint_id, server.state, server.connection_type, server.latency_ms,
+                        "server {}: status={:?} connection_type={} remote_addr={} latency_ms={:?}",
+                        server.endpoint_id,
+                        server.state,
+                        server.connection_type,
+                        server.remote_addr,
+                        server.latency_ms,
                     );
                 }
 
                 for client in self.node_model.clients.values() {
                     info!(
-                        "client {}: status={:?} remote_addr={} latency_ms={:?}",
-                        client.endpoint_id, client.state, client.connection_type, client.latency_ms
+                        "client {}: status={:?} connection_type={} remote_addr={} latency_ms={:?}",
+                        client.endpoint_id,
+                        client.state,
+                        client.connection_type,
+                        client.remote_addr,
+                        client.latency_ms
                     );
                 }
             }
@@ -587,4 +631,46 @@ impl musicopy::EventHandler for AppEventHandler {
     fn on_stats_model_snapshot(&self, model: StatsModel) {
         app_send!(AppEvent::StatsModel(Box::new(model)));
     }
+
+    fn on_settings_model_snapshot(&self, _model: SettingsModel) {}
+
+    // model diffs aren't enabled
+    fn on_library_model_diff(&self, _diff: LibraryModelDiff) {}
+
+    fn on_node_model_diff(&self, _diff: NodeModelDiff) {}
+
+    fn on_transfer_session_completed(&self, event: TransferSessionCompletedEvent) {
+        info!(
+            "downloads from {} finished: {} completed, {} failed",
+            event.name, event.completed_files, event.failed_files
+        );
+    }
+
+    fn on_transfer_job_failed(&self, event: TransferJobFailedEvent) {
+        warn!(
+            "download of {}/{} failed: {}",
+            event.file_root, event.file_path, event.error
+        );
+    }
+
+    fn on_connection_lost(&self, event: ConnectionLostEvent) {
+        warn!("connection to {} lost: {}", event.name, event.error);
+    }
+
+    fn on_database_recovered(&self, event: DatabaseRecoveredEvent) {
+        warn!(
+            "database was corrupted and moved to {}, lost tables: {:?}",
+            event.backup_path, event.lost_tables
+        );
+    }
+
+    fn on_shutdown_progress(&self, event: ShutdownProgressEvent) {
+        info!(
+            "shutdown {:?}: {} connections and {} transcodes active, {}s left",
+            event.phase, event.open_connections, event.active_transcodes, event.remaining_secs
+        );
+    }
+
+    // the TUI never enters the background
+    fn on_background_progress(&self, _event: BackgroundProgressEvent) {}
 }
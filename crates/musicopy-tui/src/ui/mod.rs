@@ -678,6 +678,10 @@ impl<'a> App<'a> {
                 ],
                 &["connect to a device".into()],
             ),
+            format_command(
+                &[cmd("open"), " <link or ticket>".into()],
+                &["open a musicopy:// link or pairing ticket".into()],
+            ),
             format_command(
                 &[cmd("dc"), ", ".into(), cmd("disconnect")],
                 &["close all connections".into()],
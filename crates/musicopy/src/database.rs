@@ -4,12 +4,27 @@
 //! - Rename the `file_sizes` table to `file_durations` and the `FileSize` struct to `FileDuration`.
 //! - Rename the `node_id` columns to `endpoint_id` for consistency with Iroh v1.
 
+use crate::{
+    node_settings::{NodeSetting, NodeSettingsHook, NodeSettingsHooks},
+    sync_group::{GroupId, GroupRecord},
+};
 use anyhow::Context;
 use iroh::EndpointId;
 use itertools::Itertools;
 use rusqlite::OptionalExtension;
-use std::{borrow::Cow, collections::HashMap, path::Path};
-use tracing::warn;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::runtime::RuntimeFlavor;
+use tracing::{error, info, warn};
 
 pub struct Root {
     pub id: u64,
@@ -27,6 +42,37 @@ pub struct File {
     pub local_path: String,
 }
 
+/// A file downloaded from a remote node.
+pub struct DownloadedFile {
+    pub root: String,
+    pub path: String,
+    pub local_tree: String,
+    pub local_path: String,
+    /// Kind and hash of the original file on the remote node, if the remote node sent it.
+    pub source_hash: Option<(String, [u8; 16])>,
+    /// What the remote node sent about the downloaded file, if it sent a checksum.
+    pub manifest: Option<FileManifest>,
+}
+
+/// What a remote node sent about a downloaded file, used to verify the local copy later without
+/// contacting the node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileManifest {
+    pub file_size: u64,
+    /// CRC-64 of the downloaded file.
+    pub checksum: u64,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+/// A file in a local root with its cached hash, if it has been hashed.
+pub struct LocalFileHash {
+    pub root: String,
+    pub path: String,
+    pub hash: Option<(String, [u8; 16])>,
+}
+
 pub struct InsertFile<'a> {
     pub root: &'a str,
     pub path: &'a str,
@@ -71,28 +117,656 @@ pub struct InsertFileSize<'a> {
     pub duration: f64,
 }
 
+/// Cached tags of a file.
+pub struct FileTags {
+    pub id: u64,
+    pub path: String,
+    pub last_file_size: u64,
+    pub last_modified_at: u64,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub duration: Option<f64>,
+    pub codec: Option<String>,
+    pub sample_rate: Option<u32>,
+}
+
+pub struct InsertFileTags<'a> {
+    pub path: Cow<'a, str>,
+    pub last_file_size: u64,
+    pub last_modified_at: u64,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub duration: Option<f64>,
+    pub codec: Option<String>,
+    pub sample_rate: Option<u32>,
+}
+
+/// The metadata of a local file, built from its cached tags after a scan.
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub file_id: u64,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    /// Short name of the file's format, e.g. `flac`.
+    pub codec: Option<String>,
+    pub sample_rate: Option<u32>,
+}
+
+/// An album in the local library, grouped by album artist (or artist) and album.
+#[derive(Debug, Clone)]
+pub struct Album {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_count: u64,
+    /// Total duration of the album's tracks in seconds.
+    pub duration: f64,
+}
+
 pub struct TrustedNode {
     pub node_id: EndpointId,
     pub name: Option<String>,
+    /// Local label set by the user, if any.
+    pub label: Option<String>,
     pub connected_at: Option<u64>,
 }
 
+/// A sync group that this node is a member of.
+pub struct SyncGroup {
+    pub group_id: GroupId,
+    pub name: String,
+    pub founder: EndpointId,
+}
+
+/// Settings for automatically downloading new files from a trusted server.
+#[derive(Debug, Clone, Default)]
+pub struct AutoDownload {
+    /// Artists and albums to download, or empty to download everything.
+    pub selections: Vec<AutoDownloadSelection>,
+    /// Maximum total size of the files queued on each connection, or None for no limit.
+    pub max_bytes: Option<u64>,
+}
+
+/// An artist, or an album optionally by an artist, to download automatically.
+#[derive(Debug, Clone)]
+pub struct AutoDownloadSelection {
+    pub artist: Option<String>,
+    /// The album, or None to select everything by the artist.
+    pub album: Option<String>,
+}
+
+/// Limits on the files shared with a node, e.g. to keep huge WAV stems off a phone.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShareFilter {
+    /// Maximum size of an original file in bytes.
+    pub max_file_size: Option<u64>,
+    /// Maximum duration in seconds.
+    pub max_duration: Option<f64>,
+    /// Lowercase file extensions that aren't shared, e.g. `wav`.
+    pub excluded_formats: Vec<String>,
+}
+
+impl ShareFilter {
+    /// Whether a file passes the filter. Limits are only checked if the file's size or duration
+    /// is known.
+    pub fn allows(&self, path: &str, file_size: Option<u64>, duration: Option<f64>) -> bool {
+        let extension = path
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_lowercase())
+            .unwrap_or_default();
+        if self.excluded_formats.contains(&extension) {
+            return false;
+        }
+        if let (Some(max_file_size), Some(file_size)) = (self.max_file_size, file_size)
+            && file_size > max_file_size
+        {
+            return false;
+        }
+        if let (Some(max_duration), Some(duration)) = (self.max_duration, duration)
+            && duration > max_duration
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// Limits on the files downloaded from a server by auto-download or when downloading
+/// everything, e.g. to skip hour-long DJ sets.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DownloadFilter {
+    /// Maximum size of a downloaded file in bytes.
+    pub max_file_size: Option<u64>,
+    /// Maximum duration in seconds.
+    pub max_duration: Option<f64>,
+}
+
+impl DownloadFilter {
+    /// Whether a file passes the filter. Limits are only checked if the file's size or duration
+    /// is known.
+    pub fn allows(&self, file_size: Option<u64>, duration: Option<f64>) -> bool {
+        if let (Some(max_file_size), Some(file_size)) = (self.max_file_size, file_size)
+            && file_size > max_file_size
+        {
+            return false;
+        }
+        if let (Some(max_duration), Some(duration)) = (self.max_duration, duration)
+            && duration > max_duration
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// A root, or a directory in a root, that is shared with a node.
+///
+/// Nodes without any shares can see the whole library.
+#[derive(Debug, Clone)]
+pub struct NodeShare {
+    pub root: String,
+    /// Slash path of a directory or file in the root, or empty to share the whole root.
+    pub path_prefix: String,
+}
+
+impl NodeShare {
+    /// Whether the file at the given root and path is in this share.
+    pub fn contains(&self, root: &str, path: &str) -> bool {
+        if self.root != root {
+            return false;
+        }
+
+        let prefix = self.path_prefix.trim_matches('/');
+        prefix.is_empty()
+            || path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+/// A finished download session from a server, for the transfer history.
+pub struct TransferSession {
+    pub id: u64,
+    pub node_id: EndpointId,
+    pub name: String,
+    pub finished_at: u64,
+    pub completed_files: u32,
+    pub failed_files: u32,
+    pub transferred_bytes: u64,
+}
+
+pub struct InsertTransferSession<'a> {
+    pub node_id: EndpointId,
+    pub name: &'a str,
+    pub finished_at: u64,
+    pub completed_files: u32,
+    pub failed_files: u32,
+    pub transferred_bytes: u64,
+}
+
+/// The outcome of a file in a transfer session.
+pub struct TransferSessionFile {
+    pub root: String,
+    pub path: String,
+    pub file_size: Option<u64>,
+    /// The error the download failed with, or None if it finished.
+    pub error: Option<String>,
+}
+
+/// A download whose partial file is in the download tree, journaled so it can be cleaned up or
+/// resumed if the app stops before it finishes.
+pub struct DownloadJournalEntry {
+    /// The server the file is downloaded from.
+    pub node_id: EndpointId,
+    /// The node the file belongs to, which is the server unless it shares files of other nodes.
+    pub file_node_id: EndpointId,
+    pub root: String,
+    pub path: String,
+    pub local_tree: String,
+    pub local_path: String,
+    pub expected_size: Option<u64>,
+    pub expected_checksum: Option<u64>,
+    /// Length of the partial file at the last checkpoint.
+    pub bytes_written: u64,
+    pub started_at: u64,
+    pub updated_at: u64,
+    /// Whether the download was found unfinished at startup.
+    pub interrupted: bool,
+}
+
+/// Bytes of files transferred with a node, across all sessions.
+pub struct PeerTraffic {
+    pub node_id: EndpointId,
+    /// Bytes uploaded to the node while it was a client.
+    pub sent_bytes: u64,
+    /// Bytes downloaded from the node while it was a server.
+    pub received_bytes: u64,
+}
+
 pub struct RecentServer {
     pub node_id: EndpointId,
     pub name: String,
+    /// Local label set by the user, if any.
+    pub label: Option<String>,
     pub connected_at: u64,
 }
 
+/// Number and size of the files downloaded from a node, across all local trees.
+pub struct DownloadUsage {
+    pub node_id: EndpointId,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Maximum number of connections open to a database file at once.
+const MAX_CONNECTIONS: usize = 4;
+
+/// How long a connection waits for another connection's write to finish before failing with
+/// "database is locked". Batches of hashes from a large scan can take a while to write.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of prepared statements cached on each connection, enough for the hot queries of a scan
+/// and a transfer to stay cached.
+const STATEMENT_CACHE_CAPACITY: usize = 32;
+
+/// A pool of connections to the database.
+///
+/// The scan, hashing workers, and connection tasks each take their own connection, so a long
+/// batch of writes doesn't stall everything else behind one lock. Connections are opened as
+/// they're needed, up to [`MAX_CONNECTIONS`], and only wait for each other inside SQLite.
+///
+/// An in-memory database is opened with a shared cache, so its connections all see the same
+/// database.
+///
+/// Waiting for a connection blocks the thread, so guards shouldn't be held across awaits. Clippy
+/// is configured to deny that.
+#[derive(Debug)]
+pub struct DatabasePool {
+    target: PoolTarget,
+    state: Mutex<PoolState>,
+    /// Notified when a connection is returned to the pool.
+    returned: Condvar,
+    /// Hooks called when node settings change, shared with every connection.
+    settings_hooks: Arc<NodeSettingsHooks>,
+    /// Incremented when files change through any connection.
+    files_version: Arc<AtomicU64>,
+}
+
+/// What the connections of a pool are opened to.
+#[derive(Debug)]
+enum PoolTarget {
+    File(PathBuf),
+    /// URI of a named in-memory database with a shared cache.
+    Memory(String),
+}
+
+impl PoolTarget {
+    fn connect(&self) -> anyhow::Result<Database> {
+        match self {
+            PoolTarget::File(path) => Database::connect(path),
+            PoolTarget::Memory(uri) => Database::connect_shared_memory(uri),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PoolState {
+    idle: Vec<Database>,
+    /// Number of connections open, including ones in use.
+    open: usize,
+}
+
+impl DatabasePool {
+    /// Open the database from a file.
+    ///
+    /// The database is checked for corruption first. If it's corrupted, it's moved aside and as
+    /// much of it as possible is copied into a new database, and the returned recovery describes
+    /// what happened.
+    pub fn open_file(path: &Path) -> anyhow::Result<(Self, Option<DatabaseRecovery>)> {
+        let problems = match Database::connect(path).and_then(|db| db.quick_check()) {
+            Ok(problems) => problems,
+            Err(e) if is_corruption(&e) => vec![format!("{e:#}")],
+            Err(e) => return Err(e),
+        };
+
+        let recovery = if problems.is_empty() {
+            None
+        } else {
+            error!("database is corrupted: {}", problems.join("; "));
+            Some(Database::recover(path, problems).context("failed to recover database")?)
+        };
+
+        let db = Database::open_file(path)?;
+        Ok((Self::new(PoolTarget::File(path.to_owned()), db), recovery))
+    }
+
+    /// Open the database in memory.
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        warn!("using in-memory database");
+        let uri = Database::shared_memory_uri();
+        let db = Database::connect_shared_memory(&uri)?;
+        db.create_tables()?;
+        Ok(Self::new(PoolTarget::Memory(uri), db))
+    }
+
+    fn new(target: PoolTarget, db: Database) -> Self {
+        Self {
+            target,
+            settings_hooks: db.settings_hooks.clone(),
+            files_version: db.files_version.clone(),
+            state: Mutex::new(PoolState {
+                idle: vec![db],
+                open: 1,
+            }),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Registers a hook called after a node's setting is set or removed through any connection,
+    /// e.g. to invalidate a cache of the setting.
+    pub fn on_node_settings_changed(&self, hook: NodeSettingsHook) {
+        self.settings_hooks.add(hook);
+    }
+
+    /// Returns a version that changes whenever files, their cached hashes, sizes or tags, or
+    /// tracks are changed through any connection, e.g. to invalidate a cache of the index.
+    pub fn files_version(&self) -> u64 {
+        self.files_version.load(Ordering::Acquire)
+    }
+
+    /// Takes a connection from the pool, opening a new one if they're all in use. Only waits for
+    /// a connection to be returned if the pool is full. The connection goes back to the pool when
+    /// the returned guard is dropped.
+    ///
+    /// This blocks while waiting, so async tasks that run for every request, like the transfer
+    /// handlers, should take connections in `spawn_blocking`.
+    pub fn get(&self) -> PooledDatabase<'_> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(db) = state.idle.pop() {
+                return PooledDatabase {
+                    pool: self,
+                    db: Some(db),
+                };
+            }
+
+            if state.open < MAX_CONNECTIONS {
+                // open the connection without holding the lock
+                state.open += 1;
+                drop(state);
+                match self.target.connect() {
+                    Ok(mut db) => {
+                        db.settings_hooks = self.settings_hooks.clone();
+                        db.files_version = self.files_version.clone();
+                        return PooledDatabase {
+                            pool: self,
+                            db: Some(db),
+                        };
+                    }
+                    Err(e) => {
+                        warn!("failed to open database connection: {e:#}");
+                        state = self.state.lock().unwrap();
+                        state.open -= 1;
+                    }
+                }
+            }
+
+            state = self.wait_for_returned(state);
+        }
+    }
+
+    /// Waits for a connection to be returned to the pool.
+    ///
+    /// On a multi-threaded runtime, the runtime is told that the thread blocks, so its other
+    /// tasks can run elsewhere meanwhile, including the ones that would return a connection.
+    fn wait_for_returned<'a>(&self, state: MutexGuard<'a, PoolState>) -> MutexGuard<'a, PoolState> {
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(|| self.returned.wait(state).unwrap())
+            }
+            _ => self.returned.wait(state).unwrap(),
+        }
+    }
+}
+
+/// A connection taken from a [`DatabasePool`], returned to it when dropped.
+#[derive(Debug)]
+pub struct PooledDatabase<'a> {
+    pool: &'a DatabasePool,
+    db: Option<Database>,
+}
+
+impl Deref for PooledDatabase<'_> {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        self.db.as_ref().expect("connection is only taken on drop")
+    }
+}
+
+impl DerefMut for PooledDatabase<'_> {
+    fn deref_mut(&mut self) -> &mut Database {
+        self.db.as_mut().expect("connection is only taken on drop")
+    }
+}
+
+impl Drop for PooledDatabase<'_> {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            let mut state = self.pool.state.lock().unwrap();
+            state.idle.push(db);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+/// What was done at startup because the database file was corrupted.
+#[derive(Debug)]
+pub struct DatabaseRecovery {
+    /// The problems found by the integrity check, or the error that opening the database failed
+    /// with.
+    pub problems: Vec<String>,
+    /// Where the corrupted database was moved to.
+    pub backup_path: PathBuf,
+    /// Tables whose rows couldn't be copied from the corrupted database.
+    pub lost_tables: Vec<String>,
+}
+
+/// A connection to the database.
 #[derive(Debug)]
 pub struct Database {
     conn: rusqlite::Connection,
+    settings_hooks: Arc<NodeSettingsHooks>,
+    files_version: Arc<AtomicU64>,
 }
 
 impl Database {
     /// Open the database from a file.
     pub fn open_file(path: &Path) -> anyhow::Result<Self> {
+        let db = Self::connect(path)?;
+
+        db.create_tables()?;
+
+        Ok(db)
+    }
+
+    /// Opens a connection to a database file without creating tables.
+    ///
+    /// The database uses write-ahead logging, so reads don't wait for writes and writes don't wait
+    /// for reads. With WAL, `synchronous = NORMAL` can't corrupt the database, and only risks
+    /// losing the last transactions if the device loses power, which is fine for a cache of what's
+    /// on disk.
+    fn connect(path: &Path) -> anyhow::Result<Self> {
         let conn = rusqlite::Connection::open(path)?;
-        Self::new_from_connection(conn)
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        let journal_mode: String =
+            conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            warn!("database is using journal mode {journal_mode} instead of WAL");
+        }
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        Ok(Self {
+            conn,
+            settings_hooks: Arc::default(),
+            files_version: Arc::default(),
+        })
+    }
+
+    /// Checks the database for corruption with `PRAGMA quick_check`, returning the problems found,
+    /// or an empty list if there are none.
+    fn quick_check(&self) -> anyhow::Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("PRAGMA quick_check")
+            .context("failed to prepare integrity check")?;
+        let results = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()
+            .context("failed to run integrity check")?;
+
+        if results.iter().all(|result| result == "ok") {
+            Ok(Vec::new())
+        } else {
+            Ok(results)
+        }
+    }
+
+    /// Moves a corrupted database file aside and copies what can still be read from it into a
+    /// new database at the same path.
+    fn recover(path: &Path, problems: Vec<String>) -> anyhow::Result<DatabaseRecovery> {
+        let file_name = path
+            .file_name()
+            .context("database path has no file name")?
+            .to_string_lossy();
+        let backup_path = path.with_file_name(format!(
+            "{file_name}.corrupt-{}",
+            crate::node::unix_epoch_now_secs()
+        ));
+
+        // the WAL and shared memory files belong to the corrupted database
+        for suffix in ["", "-wal", "-shm"] {
+            let from = PathBuf::from(format!("{}{suffix}", path.display()));
+            if from.exists() {
+                let to = PathBuf::from(format!("{}{suffix}", backup_path.display()));
+                std::fs::rename(&from, &to).with_context(|| {
+                    format!("failed to move {} to {}", from.display(), to.display())
+                })?;
+            }
+        }
+        warn!(
+            "moved corrupted database to {}, recovering into a new database",
+            backup_path.display()
+        );
+
+        let db = Database::open_file(path).context("failed to create new database")?;
+        let lost_tables = db.copy_tables_from(&backup_path)?;
+        if lost_tables.is_empty() {
+            info!("recovered all tables from the corrupted database");
+        } else {
+            warn!(
+                "failed to recover tables from the corrupted database: {}",
+                lost_tables.join(", ")
+            );
+        }
+
+        Ok(DatabaseRecovery {
+            problems,
+            backup_path,
+            lost_tables,
+        })
+    }
+
+    /// Copies the rows of every table from another database, returning the tables that couldn't
+    /// be copied.
+    ///
+    /// Only columns that exist in both databases are copied, so an older database can be copied
+    /// into the current schema. Rows replace the defaults inserted by `create_tables`. The
+    /// full-text index and download usage are filled by the triggers on the tracks and files
+    /// tables, so they aren't copied.
+    fn copy_tables_from(&self, path: &Path) -> anyhow::Result<Vec<String>> {
+        let tables = {
+            let mut stmt = self
+                .conn
+                .prepare(
+                    "SELECT name FROM main.sqlite_master
+                    WHERE type = 'table' AND name NOT LIKE 'sqlite_%' AND name NOT LIKE 'tracks_fts%'
+                        AND name != 'download_usage'
+                    ORDER BY name",
+                )
+                .expect("should prepare statement");
+            stmt.query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        if let Err(e) = self.conn.execute(
+            "ATTACH DATABASE ? AS old",
+            [path.to_string_lossy().as_ref()],
+        ) {
+            warn!("failed to attach corrupted database: {e:#}");
+            return Ok(tables);
+        }
+
+        let mut lost_tables = Vec::new();
+        for table in tables {
+            if let Err(e) = self.copy_table_from_old(&table) {
+                warn!("failed to copy table {table} from corrupted database: {e:#}");
+                lost_tables.push(table);
+            }
+        }
+
+        self.conn
+            .execute("DETACH DATABASE old", [])
+            .context("failed to detach corrupted database")?;
+
+        Ok(lost_tables)
+    }
+
+    /// Copies the rows of a table from the attached `old` database.
+    fn copy_table_from_old(&self, table: &str) -> anyhow::Result<()> {
+        let columns = |schema: &str| -> anyhow::Result<HashSet<String>> {
+            let mut stmt = self
+                .conn
+                .prepare(&format!("PRAGMA {schema}.table_info(\"{table}\")"))?;
+            let columns = stmt
+                .query_map([], |row| row.get::<_, String>(1))?
+                .collect::<Result<HashSet<_>, _>>()?;
+            Ok(columns)
+        };
+
+        let new_columns = columns("main")?;
+        let old_columns = columns("old")?;
+        let columns = new_columns
+            .intersection(&old_columns)
+            .map(|column| format!("\"{column}\""))
+            .join(", ");
+
+        // the table didn't exist in the old database
+        if columns.is_empty() {
+            return Ok(());
+        }
+
+        self.conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO main.\"{table}\" ({columns}) SELECT {columns} FROM old.\"{table}\""
+            ),
+            [],
+        )?;
+
+        Ok(())
     }
 
     /// Open the database in memory.
@@ -102,8 +776,41 @@ impl Database {
         Self::new_from_connection(conn)
     }
 
+    /// Returns the URI of a new named in-memory database with a shared cache, unique within the
+    /// process.
+    fn shared_memory_uri() -> String {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        format!(
+            "file:musicopy-{}-{id}?mode=memory&cache=shared",
+            std::process::id()
+        )
+    }
+
+    /// Opens a connection to a named in-memory database with a shared cache, without creating
+    /// tables. The database lives as long as any connection to it is open.
+    ///
+    /// Connections read uncommitted data, so reads don't fail on tables locked by another
+    /// connection's write.
+    fn connect_shared_memory(uri: &str) -> anyhow::Result<Self> {
+        let conn = rusqlite::Connection::open(uri)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "read_uncommitted", true)?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        Ok(Self {
+            conn,
+            settings_hooks: Arc::default(),
+            files_version: Arc::default(),
+        })
+    }
+
     fn new_from_connection(conn: rusqlite::Connection) -> anyhow::Result<Self> {
-        let db = Self { conn };
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        let db = Self {
+            conn,
+            settings_hooks: Arc::default(),
+            files_version: Arc::default(),
+        };
 
         db.create_tables()?;
 
@@ -133,6 +840,27 @@ impl Database {
             )",
             [],
         )?;
+        let _ = self
+            .conn
+            .execute("ALTER TABLE files ADD COLUMN source_hash_kind TEXT", []);
+        let _ = self
+            .conn
+            .execute("ALTER TABLE files ADD COLUMN source_hash BLOB", []);
+        let _ = self
+            .conn
+            .execute("ALTER TABLE files ADD COLUMN file_size INTEGER", []);
+        let _ = self
+            .conn
+            .execute("ALTER TABLE files ADD COLUMN checksum BLOB", []);
+        let _ = self
+            .conn
+            .execute("ALTER TABLE files ADD COLUMN title TEXT", []);
+        let _ = self
+            .conn
+            .execute("ALTER TABLE files ADD COLUMN artist TEXT", []);
+        let _ = self
+            .conn
+            .execute("ALTER TABLE files ADD COLUMN album TEXT", []);
         self.conn.execute(
             "CREATE TABLE IF NOT EXISTS file_hashes (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -159,6 +887,37 @@ impl Database {
         let _ = self
             .conn
             .execute("ALTER TABLE file_sizes DROP COLUMN estimated_size", []);
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS file_tags (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                path TEXT NOT NULL,
+                last_file_size INTEGER NOT NULL,
+                last_modified_at INTEGER NOT NULL,
+                title TEXT,
+                artist TEXT,
+                album TEXT,
+                track_number INTEGER,
+                UNIQUE (path)
+            )",
+            [],
+        )?;
+        let _ = self
+            .conn
+            .execute("ALTER TABLE file_tags ADD COLUMN title TEXT", []);
+        let _ = self
+            .conn
+            .execute("ALTER TABLE file_tags ADD COLUMN track_number INTEGER", []);
+        for column in [
+            "album_artist TEXT",
+            "disc_number INTEGER",
+            "duration REAL",
+            "codec TEXT",
+            "sample_rate INTEGER",
+        ] {
+            let _ = self
+                .conn
+                .execute(&format!("ALTER TABLE file_tags ADD COLUMN {column}"), []);
+        }
         self.conn.execute(
             "CREATE TABLE IF NOT EXISTS trusted_nodes (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -175,6 +934,32 @@ impl Database {
             "ALTER TABLE trusted_nodes ADD COLUMN connected_at INTEGER",
             [],
         );
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS node_shares (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                node_id TEXT NOT NULL,
+                root TEXT NOT NULL,
+                path_prefix TEXT NOT NULL,
+                UNIQUE(node_id, root, path_prefix)
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS auto_downloads (
+                node_id TEXT PRIMARY KEY,
+                max_bytes INTEGER
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS auto_download_selections (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                node_id TEXT NOT NULL,
+                artist TEXT,
+                album TEXT
+            )",
+            [],
+        )?;
         self.conn.execute(
             "CREATE TABLE IF NOT EXISTS recent_servers (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -188,6 +973,13 @@ impl Database {
             "ALTER TABLE recent_servers ADD COLUMN name TEXT NOT NULL DEFAULT 'unknown'",
             [],
         );
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS node_labels (
+                node_id TEXT PRIMARY KEY,
+                label TEXT NOT NULL
+            )",
+            [],
+        )?;
         self.conn.execute(
             "CREATE TABLE IF NOT EXISTS stats (
                 id INTEGER PRIMARY KEY CHECK (id = 1),
@@ -203,26 +995,373 @@ impl Database {
         )?;
         self.conn
             .execute("INSERT OR IGNORE INTO stats (id) VALUES (1)", [])?;
-        Ok(())
-    }
-
-    pub fn reset(&self) -> anyhow::Result<()> {
-        self.conn.execute("DROP TABLE IF EXISTS roots", [])?;
-        self.conn.execute("DROP TABLE IF EXISTS files", [])?;
-        self.conn.execute("DROP TABLE IF EXISTS file_hashes", [])?;
-        self.conn.execute("DROP TABLE IF EXISTS file_sizes", [])?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS peer_traffic (
+                node_id TEXT PRIMARY KEY,
+                sent_bytes INTEGER NOT NULL DEFAULT 0,
+                received_bytes INTEGER NOT NULL DEFAULT 0
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS node_settings (
+                node_id TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value BLOB NOT NULL,
+                PRIMARY KEY (node_id, key)
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value BLOB NOT NULL
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS transfer_sessions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                node_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                finished_at INTEGER NOT NULL,
+                completed_files INTEGER NOT NULL,
+                failed_files INTEGER NOT NULL,
+                transferred_bytes INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS transfer_sessions_node_id ON transfer_sessions (node_id, finished_at)",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS transfer_sessions_finished_at ON transfer_sessions (finished_at)",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS transfer_session_files (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                session_id INTEGER NOT NULL,
+                root TEXT NOT NULL,
+                path TEXT NOT NULL,
+                file_size INTEGER,
+                error TEXT
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS transfer_session_files_session_id ON transfer_session_files (session_id)",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS delivered_hashes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                node_id TEXT NOT NULL,
+                hash_kind TEXT NOT NULL,
+                hash BLOB NOT NULL,
+                delivered_at INTEGER NOT NULL,
+                UNIQUE (node_id, hash_kind, hash)
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS sync_groups (
+                group_id BLOB PRIMARY KEY,
+                name TEXT NOT NULL,
+                founder TEXT NOT NULL
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS sync_group_records (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id BLOB NOT NULL,
+                group_name TEXT NOT NULL,
+                founder TEXT NOT NULL,
+                member TEXT NOT NULL,
+                issuer TEXT NOT NULL,
+                issued_at INTEGER NOT NULL,
+                signature BLOB NOT NULL,
+                UNIQUE (group_id, member)
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS untrusted_group_members (
+                node_id TEXT PRIMARY KEY
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS share_filters (
+                node_id TEXT PRIMARY KEY,
+                max_file_size INTEGER,
+                max_duration REAL,
+                excluded_formats TEXT NOT NULL
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS storage_quotas (
+                node_id TEXT PRIMARY KEY,
+                max_bytes INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS download_filters (
+                node_id TEXT PRIMARY KEY,
+                max_file_size INTEGER,
+                max_duration REAL
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS scanned_files (
+                root TEXT NOT NULL,
+                path TEXT NOT NULL,
+                local_tree TEXT NOT NULL,
+                UNIQUE (root, path, local_tree)
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS tracks (
+                file_id INTEGER PRIMARY KEY,
+                title TEXT,
+                artist TEXT,
+                album TEXT,
+                album_artist TEXT,
+                track_number INTEGER,
+                disc_number INTEGER,
+                duration REAL,
+                codec TEXT,
+                sample_rate INTEGER
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS download_journal (
+                node_id TEXT NOT NULL,
+                file_node_id TEXT NOT NULL,
+                root TEXT NOT NULL,
+                path TEXT NOT NULL,
+                local_tree TEXT NOT NULL,
+                local_path TEXT NOT NULL,
+                expected_size INTEGER,
+                expected_checksum BLOB,
+                bytes_written INTEGER NOT NULL,
+                started_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                interrupted INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (local_tree, local_path)
+            )",
+            [],
+        )?;
+
+        // full-text index over the tracks, kept in sync by triggers. tracks created before the
+        // index existed need to be indexed once when it's created.
+        let tracks_fts_exists: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'tracks_fts')",
+            [],
+            |row| row.get(0),
+        )?;
+        self.conn.execute(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS tracks_fts USING fts5 (
+                title,
+                artist,
+                album,
+                album_artist,
+                content = 'tracks',
+                content_rowid = 'file_id',
+                tokenize = 'unicode61 remove_diacritics 2'
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TRIGGER IF NOT EXISTS tracks_fts_insert AFTER INSERT ON tracks BEGIN
+                INSERT INTO tracks_fts (rowid, title, artist, album, album_artist)
+                VALUES (new.file_id, new.title, new.artist, new.album, new.album_artist);
+            END",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TRIGGER IF NOT EXISTS tracks_fts_delete AFTER DELETE ON tracks BEGIN
+                INSERT INTO tracks_fts (tracks_fts, rowid, title, artist, album, album_artist)
+                VALUES ('delete', old.file_id, old.title, old.artist, old.album, old.album_artist);
+            END",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TRIGGER IF NOT EXISTS tracks_fts_update AFTER UPDATE ON tracks BEGIN
+                INSERT INTO tracks_fts (tracks_fts, rowid, title, artist, album, album_artist)
+                VALUES ('delete', old.file_id, old.title, old.artist, old.album, old.album_artist);
+                INSERT INTO tracks_fts (rowid, title, artist, album, album_artist)
+                VALUES (new.file_id, new.title, new.artist, new.album, new.album_artist);
+            END",
+            [],
+        )?;
+        if !tracks_fts_exists {
+            self.conn
+                .execute("INSERT INTO tracks_fts (tracks_fts) VALUES ('rebuild')", [])?;
+        }
+
+        // number and size of the files from each node, kept in sync by triggers so it doesn't need
+        // to sum the files table. files tracked before the totals existed need to be counted once.
+        let download_usage_exists: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'download_usage')",
+            [],
+            |row| row.get(0),
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS download_usage (
+                node_id TEXT PRIMARY KEY,
+                files INTEGER NOT NULL DEFAULT 0,
+                bytes INTEGER NOT NULL DEFAULT 0
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TRIGGER IF NOT EXISTS download_usage_insert AFTER INSERT ON files BEGIN
+                INSERT OR IGNORE INTO download_usage (node_id) VALUES (new.node_id);
+                UPDATE download_usage SET files = files + 1, bytes = bytes + COALESCE(new.file_size, 0)
+                WHERE node_id = new.node_id;
+            END",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TRIGGER IF NOT EXISTS download_usage_delete AFTER DELETE ON files BEGIN
+                UPDATE download_usage SET files = files - 1, bytes = bytes - COALESCE(old.file_size, 0)
+                WHERE node_id = old.node_id;
+            END",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TRIGGER IF NOT EXISTS download_usage_update AFTER UPDATE OF node_id, file_size ON files BEGIN
+                UPDATE download_usage SET files = files - 1, bytes = bytes - COALESCE(old.file_size, 0)
+                WHERE node_id = old.node_id;
+                INSERT OR IGNORE INTO download_usage (node_id) VALUES (new.node_id);
+                UPDATE download_usage SET files = files + 1, bytes = bytes + COALESCE(new.file_size, 0)
+                WHERE node_id = new.node_id;
+            END",
+            [],
+        )?;
+        if !download_usage_exists {
+            self.conn.execute(
+                "INSERT INTO download_usage (node_id, files, bytes)
+                SELECT node_id, COUNT(*), COALESCE(SUM(file_size), 0) FROM files GROUP BY node_id",
+                [],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Marks the files as changed, invalidating caches built from an older
+    /// [`DatabasePool::files_version`].
+    fn files_changed(&self) {
+        self.files_version.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn reset(&self) -> anyhow::Result<()> {
+        self.conn.execute("DROP TABLE IF EXISTS roots", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS files", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS file_hashes", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS file_sizes", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS file_tags", [])?;
         self.conn
             .execute("DROP TABLE IF EXISTS trusted_nodes", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS node_shares", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS auto_downloads", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS auto_download_selections", [])?;
         self.conn
             .execute("DROP TABLE IF EXISTS recent_servers", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS node_labels", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS peer_traffic", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS node_settings", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS settings", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS transfer_sessions", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS transfer_session_files", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS delivered_hashes", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS sync_groups", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS sync_group_records", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS untrusted_group_members", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS share_filters", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS storage_quotas", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS download_filters", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS scanned_files", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS tracks", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS download_journal", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS tracks_fts", [])?;
+        self.conn
+            .execute("DROP TABLE IF EXISTS download_usage", [])?;
         self.create_tables()?;
+        self.files_changed();
         Ok(())
     }
 
+    /// Get the number of rows in each table, sorted by table name, for diagnostics.
+    pub fn get_table_counts(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        let tables: Vec<String> = {
+            let mut stmt = self
+                .conn
+                .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name ASC")
+                .expect("should prepare statement");
+            stmt.query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?
+        };
+
+        tables
+            .into_iter()
+            .map(|table| {
+                let count: u64 = self.conn.query_row(
+                    &format!("SELECT COUNT(*) FROM \"{table}\""),
+                    [],
+                    |row| row.get(0),
+                )?;
+                Ok((table, count))
+            })
+            .collect()
+    }
+
+    /// Get the schema version, which SQLite increments each time the schema changes.
+    pub fn get_schema_version(&self) -> anyhow::Result<u64> {
+        let version = self
+            .conn
+            .query_row("PRAGMA schema_version", [], |row| row.get(0))?;
+        Ok(version)
+    }
+
+    /// Writes the write-ahead log into the database file and truncates it, so nothing is lost if
+    /// the process is killed after shutting down.
+    pub fn checkpoint(&self) -> anyhow::Result<()> {
+        self.conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .context("failed to checkpoint database")
+    }
+
     pub fn reset_caches(&self) -> anyhow::Result<()> {
         self.conn.execute("DROP TABLE IF EXISTS file_hashes", [])?;
         self.conn.execute("DROP TABLE IF EXISTS file_sizes", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS file_tags", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS tracks", [])?;
+        self.conn.execute("DROP TABLE IF EXISTS tracks_fts", [])?;
         self.create_tables()?;
+        self.files_changed();
         Ok(())
     }
 
@@ -282,8 +1421,20 @@ impl Database {
         Ok(count)
     }
 
-    /// Delete all local files and insert new ones.
-    pub fn replace_local_files<'a>(
+    /// Delete the files staged by an earlier scan, before a new scan starts.
+    pub fn clear_scanned_files(&self) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM scanned_files", [])
+            .context("failed to clear scanned files")?;
+        Ok(())
+    }
+
+    /// Insert a chunk of files found by a scan into the local files in its own transaction, so
+    /// other work can use the database between chunks.
+    ///
+    /// Files that are already in the database keep their ids. The files are also staged, so the
+    /// files that the scan didn't find can be removed once it's done.
+    pub fn insert_scanned_files<'a>(
         &mut self,
         local_node_id: EndpointId,
         iter: impl Iterator<Item = InsertFile<'a>>,
@@ -293,16 +1444,19 @@ impl Database {
             .transaction()
             .context("failed to begin transaction")?;
 
-        tx.execute(
-            "DELETE FROM files WHERE node_id = ?",
-            [endpoint_id_to_string(&local_node_id)],
-        )?;
-
         {
-            let mut stmt = tx.prepare("INSERT INTO files (node_id, root, path, local_tree, local_path) VALUES (?, ?, ?, ?, ?)")?;
+            let mut stage_stmt = tx.prepare_cached(
+                "INSERT OR IGNORE INTO scanned_files (root, path, local_tree) VALUES (?, ?, ?)",
+            )?;
+            let mut insert_stmt = tx.prepare_cached(
+                "INSERT INTO files (node_id, root, path, local_tree, local_path) VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT(node_id, root, path, local_tree) DO UPDATE SET local_path = excluded.local_path",
+            )?;
+            let node_id = endpoint_id_to_string(&local_node_id);
             for file in iter {
-                stmt.execute((
-                    endpoint_id_to_string(&local_node_id),
+                stage_stmt.execute((file.root, file.path, file.local_tree))?;
+                insert_stmt.execute((
+                    &node_id,
                     file.root,
                     file.path,
                     file.local_tree,
@@ -313,31 +1467,189 @@ impl Database {
 
         tx.commit().context("failed to commit transaction")?;
 
+        self.files_changed();
+        Ok(())
+    }
+
+    /// Delete the local files that the scan didn't find, finishing the scan.
+    pub fn remove_unscanned_files(&mut self, local_node_id: EndpointId) -> anyhow::Result<()> {
+        let tx = self
+            .conn
+            .transaction()
+            .context("failed to begin transaction")?;
+
+        let node_id = endpoint_id_to_string(&local_node_id);
+        tx.execute(
+            "DELETE FROM tracks WHERE file_id IN (
+                SELECT id FROM files WHERE node_id = ? AND NOT EXISTS (
+                    SELECT 1 FROM scanned_files
+                    WHERE scanned_files.root = files.root AND scanned_files.path = files.path
+                        AND scanned_files.local_tree = files.local_tree
+                )
+            )",
+            [&node_id],
+        )?;
+        tx.execute(
+            "DELETE FROM files WHERE node_id = ? AND NOT EXISTS (
+                SELECT 1 FROM scanned_files
+                WHERE scanned_files.root = files.root AND scanned_files.path = files.path
+                    AND scanned_files.local_tree = files.local_tree
+            )",
+            [&node_id],
+        )?;
+        tx.execute("DELETE FROM scanned_files", [])?;
+
+        // new files don't have tracks yet, so rebuild them from the cached tags right away
+        rebuild_tracks(&tx, local_node_id)?;
+
+        tx.commit().context("failed to commit transaction")?;
+
+        self.files_changed();
         Ok(())
     }
 
     /// Insert a file from a remote node, updating the existing entry if it exists.
+    ///
+    /// The source hash is the kind and hash of the original file on the remote node, used to
+    /// skip unchanged files when syncing. The manifest is used to verify the downloaded file.
     pub fn insert_remote_file<'a>(
         &mut self,
         remote_node_id: EndpointId,
         file: InsertFile<'a>,
+        source_hash: Option<(&str, [u8; 16])>,
+        manifest: Option<&FileManifest>,
     ) -> anyhow::Result<()> {
-        let mut stmt = self.conn.prepare(
-            "INSERT INTO files (node_id, root, path, local_tree, local_path) VALUES (?, ?, ?, ?, ?)
-            ON CONFLICT(node_id, root, path, local_tree) DO UPDATE SET local_path = excluded.local_path"
+        let mut stmt = self.conn.prepare_cached(
+            "INSERT INTO files (node_id, root, path, local_tree, local_path, source_hash_kind, source_hash, file_size, checksum, title, artist, album) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT(node_id, root, path, local_tree) DO UPDATE SET local_path = excluded.local_path, source_hash_kind = excluded.source_hash_kind, source_hash = excluded.source_hash,
+                file_size = excluded.file_size, checksum = excluded.checksum, title = excluded.title, artist = excluded.artist, album = excluded.album"
         )?;
 
+        let (source_hash_kind, source_hash) = source_hash.unzip();
         stmt.execute((
             endpoint_id_to_string(&remote_node_id),
             file.root,
             file.path,
             file.local_tree,
             file.local_path,
+            source_hash_kind,
+            source_hash,
+            manifest.map(|manifest| manifest.file_size),
+            manifest.map(|manifest| manifest.checksum.to_be_bytes()),
+            manifest.and_then(|manifest| manifest.title.as_deref()),
+            manifest.and_then(|manifest| manifest.artist.as_deref()),
+            manifest.and_then(|manifest| manifest.album.as_deref()),
         ))?;
 
+        self.files_changed();
         Ok(())
     }
 
+    /// Get the downloaded copies of a remote file in all local trees.
+    pub fn get_downloaded_files_by_node_root_path(
+        &self,
+        node_id: EndpointId,
+        root: &str,
+        path: &str,
+    ) -> anyhow::Result<Vec<DownloadedFile>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT root, path, local_tree, local_path, source_hash_kind, source_hash, file_size, checksum, title, artist, album FROM files WHERE node_id = ? AND root = ? AND path = ?")
+            .expect("should prepare statement");
+
+        let node_id = endpoint_id_to_string(&node_id);
+        stmt.query_and_then([&node_id, root, path], downloaded_file_from_row)
+            .expect("should bind parameters")
+            .collect()
+    }
+
+    /// Get the files of a node with their cached hashes, without validating the hashes.
+    pub fn get_file_hashes_by_node_id(
+        &self,
+        node_id: EndpointId,
+    ) -> anyhow::Result<Vec<LocalFileHash>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT files.root, files.path, file_hashes.hash_kind, file_hashes.hash FROM files LEFT JOIN file_hashes ON file_hashes.path = files.local_path WHERE files.node_id = ?")
+            .expect("should prepare statement");
+
+        stmt.query_and_then([endpoint_id_to_string(&node_id)], |row| {
+            let hash_kind: Option<String> = row.get(2)?;
+            let hash: Option<[u8; 16]> = row.get(3)?;
+
+            Ok(LocalFileHash {
+                root: row.get(0)?,
+                path: row.get(1)?,
+                hash: hash_kind.zip(hash),
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Get the downloaded files from a remote node in a local tree.
+    pub fn get_downloaded_files_by_node_localtree(
+        &self,
+        node_id: EndpointId,
+        local_tree: &str,
+    ) -> anyhow::Result<Vec<DownloadedFile>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT root, path, local_tree, local_path, source_hash_kind, source_hash, file_size, checksum, title, artist, album FROM files WHERE node_id = ? AND local_tree = ?")
+            .expect("should prepare statement");
+
+        let node_id = endpoint_id_to_string(&node_id);
+        stmt.query_and_then([node_id.as_str(), local_tree], downloaded_file_from_row)
+            .expect("should bind parameters")
+            .collect()
+    }
+
+    /// Get the downloaded files from a remote node in all local trees.
+    ///
+    /// The local node's files are its library, not downloads, so none are returned for it.
+    pub fn get_downloaded_files_by_node(
+        &self,
+        local_node_id: EndpointId,
+        node_id: EndpointId,
+    ) -> anyhow::Result<Vec<DownloadedFile>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT root, path, local_tree, local_path, source_hash_kind, source_hash, file_size, checksum, title, artist, album FROM files WHERE node_id = ? AND node_id != ?")
+            .expect("should prepare statement");
+
+        stmt.query_and_then(
+            [
+                endpoint_id_to_string(&node_id),
+                endpoint_id_to_string(&local_node_id),
+            ],
+            downloaded_file_from_row,
+        )
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Get the files downloaded from all remote nodes, with the node each was downloaded from.
+    pub fn get_downloaded_files(
+        &self,
+        local_node_id: EndpointId,
+    ) -> anyhow::Result<Vec<(EndpointId, DownloadedFile)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT root, path, local_tree, local_path, source_hash_kind, source_hash, file_size, checksum, title, artist, album, node_id FROM files WHERE node_id != ?")
+            .expect("should prepare statement");
+
+        stmt.query_and_then([endpoint_id_to_string(&local_node_id)], |row| {
+            let node_id =
+                hex::decode(row.get::<_, String>(11)?).context("failed to parse node id")?;
+            let node_id =
+                EndpointId::try_from(node_id.as_slice()).context("failed to parse node id")?;
+
+            Ok((node_id, downloaded_file_from_row(row)?))
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
     pub fn get_files(&self) -> anyhow::Result<Vec<File>> {
         let mut stmt = self
             .conn
@@ -428,7 +1740,7 @@ impl Database {
     ) -> anyhow::Result<bool> {
         let mut stmt = self
             .conn
-            .prepare("SELECT 1 FROM files WHERE node_id = ? AND root = ? AND path = ? AND local_tree = ? LIMIT 1")
+            .prepare_cached("SELECT 1 FROM files WHERE node_id = ? AND root = ? AND path = ? AND local_tree = ? LIMIT 1")
             .expect("should prepare statement");
 
         let node_id = endpoint_id_to_string(&node_id);
@@ -440,16 +1752,41 @@ impl Database {
         Ok(exists.is_some())
     }
 
-    pub fn get_file_by_node_root_path(
+    /// Check if a different file is already stored at the given local path.
+    pub fn exists_other_file_by_local_treepath(
         &self,
         node_id: EndpointId,
         root: &str,
         path: &str,
-    ) -> anyhow::Result<Option<File>> {
+        local_tree: &str,
+        local_path: &str,
+    ) -> anyhow::Result<bool> {
         let mut stmt = self
-        .conn
-        .prepare("SELECT id, node_id, root, path, local_tree, local_path FROM files WHERE node_id = ? AND root = ? AND path = ?")
-        .expect("should prepare statement");
+            .conn
+            .prepare_cached("SELECT 1 FROM files WHERE local_tree = ? AND local_path = ? AND NOT (node_id = ? AND root = ? AND path = ?) LIMIT 1")
+            .expect("should prepare statement");
+
+        let node_id = endpoint_id_to_string(&node_id);
+        let exists: Option<u8> = stmt
+            .query_row([local_tree, local_path, &node_id, root, path], |row| {
+                row.get(0)
+            })
+            .optional()
+            .context("failed to query row")?;
+
+        Ok(exists.is_some())
+    }
+
+    pub fn get_file_by_node_root_path(
+        &self,
+        node_id: EndpointId,
+        root: &str,
+        path: &str,
+    ) -> anyhow::Result<Option<File>> {
+        let mut stmt = self
+        .conn
+        .prepare_cached("SELECT id, node_id, root, path, local_tree, local_path FROM files WHERE node_id = ? AND root = ? AND path = ?")
+        .expect("should prepare statement");
 
         let node_id = endpoint_id_to_string(&node_id);
         stmt.query_and_then([&node_id, root, path], |row| {
@@ -530,6 +1867,32 @@ impl Database {
 
         stmt.execute(params_flat)?;
 
+        self.files_changed();
+        Ok(())
+    }
+
+    /// Move a file to a new path in its local tree, e.g. when downloads are reorganized.
+    pub fn set_file_local_path(
+        &self,
+        local_tree: &str,
+        old_local_path: &str,
+        new_local_path: &str,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE files SET local_path = ? WHERE local_tree = ? AND local_path = ?",
+            [new_local_path, local_tree, old_local_path],
+        )?;
+        self.files_changed();
+        Ok(())
+    }
+
+    /// Replace the local tree of all files in a tree, e.g. when an iOS bookmark is refreshed.
+    pub fn replace_local_tree(&self, old_tree: &str, new_tree: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE OR REPLACE files SET local_tree = ? WHERE local_tree = ?",
+            [new_tree, old_tree],
+        )?;
+        self.files_changed();
         Ok(())
     }
 
@@ -537,7 +1900,7 @@ impl Database {
     pub fn get_file_hash_by_path(&self, path: &Path) -> anyhow::Result<Option<FileHash>> {
         let mut stmt = self
             .conn
-            .prepare("SELECT id, path, last_file_size, last_modified_at, hash_kind, hash FROM file_hashes WHERE path = ?")
+            .prepare_cached("SELECT id, path, last_file_size, last_modified_at, hash_kind, hash FROM file_hashes WHERE path = ?")
             .expect("should prepare statement");
 
         stmt.query_and_then([path.to_string_lossy().as_ref()], |row| {
@@ -592,7 +1955,7 @@ impl Database {
 
     /// Insert a file hash, updating the existing entry if it exists.
     pub fn insert_file_hash(&self, file_hash: InsertFileHash) -> anyhow::Result<()> {
-        let mut stmt = self.conn.prepare(
+        let mut stmt = self.conn.prepare_cached(
             "INSERT INTO file_hashes (path, last_file_size, last_modified_at, hash_kind, hash) VALUES (?, ?, ?, ?, ?)
             ON CONFLICT(path) DO UPDATE SET last_file_size = excluded.last_file_size, last_modified_at = excluded.last_modified_at, hash_kind = excluded.hash_kind, hash = excluded.hash",
         )?;
@@ -605,6 +1968,7 @@ impl Database {
             file_hash.hash,
         ))?;
 
+        self.files_changed();
         Ok(())
     }
 
@@ -619,7 +1983,7 @@ impl Database {
             .context("failed to begin transaction")?;
 
         {
-            let mut stmt = tx.prepare(
+            let mut stmt = tx.prepare_cached(
                 "INSERT INTO file_hashes (path, last_file_size, last_modified_at, hash_kind, hash) VALUES (?, ?, ?, ?, ?)
                 ON CONFLICT(path) DO UPDATE SET last_file_size = excluded.last_file_size, last_modified_at = excluded.last_modified_at, hash_kind = excluded.hash_kind, hash = excluded.hash",
             )?;
@@ -637,6 +2001,7 @@ impl Database {
 
         tx.commit().context("failed to commit transaction")?;
 
+        self.files_changed();
         Ok(())
     }
 
@@ -644,7 +2009,7 @@ impl Database {
     pub fn get_file_size_by_path(&self, path: &Path) -> anyhow::Result<Option<FileSize>> {
         let mut stmt = self
             .conn
-            .prepare("SELECT id, path, last_file_size, last_modified_at, duration FROM file_sizes WHERE path = ?")
+            .prepare_cached("SELECT id, path, last_file_size, last_modified_at, duration FROM file_sizes WHERE path = ?")
             .expect("should prepare statement");
 
         stmt.query_and_then([path.to_string_lossy().as_ref()], |row| {
@@ -704,7 +2069,7 @@ impl Database {
             .context("failed to begin transaction")?;
 
         {
-            let mut stmt = tx.prepare(
+            let mut stmt = tx.prepare_cached(
                 "INSERT INTO file_sizes (path, last_file_size, last_modified_at, duration) VALUES (?, ?, ?, ?)
                 ON CONFLICT(path) DO UPDATE SET last_file_size = excluded.last_file_size, last_modified_at = excluded.last_modified_at, duration = excluded.duration",
             )?;
@@ -721,98 +2086,276 @@ impl Database {
 
         tx.commit().context("failed to commit transaction")?;
 
+        self.files_changed();
         Ok(())
     }
 
-    pub fn get_trusted_nodes(&self) -> anyhow::Result<Vec<TrustedNode>> {
+    /// Get cached file tags by path.
+    pub fn get_file_tags_by_path(&self, path: &Path) -> anyhow::Result<Option<FileTags>> {
         let mut stmt = self
             .conn
-            .prepare("SELECT node_id, name, connected_at FROM trusted_nodes ORDER BY id ASC")
+            .prepare_cached("SELECT id, path, last_file_size, last_modified_at, title, artist, album, album_artist, track_number, disc_number, duration, codec, sample_rate FROM file_tags WHERE path = ?")
             .expect("should prepare statement");
 
-        stmt.query_and_then([], |row| {
-            let node_id =
-                hex::decode(row.get::<_, String>(0)?).context("failed to parse node id")?;
-            let node_id =
-                EndpointId::try_from(node_id.as_slice()).context("failed to parse node id")?;
-
-            Ok(TrustedNode {
-                node_id,
-                name: row.get(1)?,
-                connected_at: row.get(2)?,
+        stmt.query_and_then([path.to_string_lossy().as_ref()], |row| {
+            Ok(FileTags {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                last_file_size: row.get(2)?,
+                last_modified_at: row.get(3)?,
+                title: row.get(4)?,
+                artist: row.get(5)?,
+                album: row.get(6)?,
+                album_artist: row.get(7)?,
+                track_number: row.get(8)?,
+                disc_number: row.get(9)?,
+                duration: row.get(10)?,
+                codec: row.get(11)?,
+                sample_rate: row.get(12)?,
             })
         })
         .expect("should bind parameters")
+        .next()
+        .transpose()
+    }
+
+    /// Get multiple cached file tags by paths.
+    pub fn get_file_tags_by_paths<'a>(
+        &self,
+        paths: impl ExactSizeIterator<Item = Cow<'a, str>>,
+    ) -> anyhow::Result<HashMap<String, FileTags>> {
+        if paths.len() == 0 {
+            return Ok(HashMap::new());
+        }
+
+        let placeholders = std::iter::repeat_n("?", paths.len()).join(", ");
+        let sql = format!(
+            "SELECT id, path, last_file_size, last_modified_at, title, artist, album, album_artist, track_number, disc_number, duration, codec, sample_rate FROM file_tags WHERE path IN ({placeholders})"
+        );
+
+        let mut stmt = self.conn.prepare(&sql).expect("should prepare statement");
+
+        let params_flat = rusqlite::params_from_iter(paths);
+
+        stmt.query_and_then(params_flat, |row| {
+            let file_tags = FileTags {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                last_file_size: row.get(2)?,
+                last_modified_at: row.get(3)?,
+                title: row.get(4)?,
+                artist: row.get(5)?,
+                album: row.get(6)?,
+                album_artist: row.get(7)?,
+                track_number: row.get(8)?,
+                disc_number: row.get(9)?,
+                duration: row.get(10)?,
+                codec: row.get(11)?,
+                sample_rate: row.get(12)?,
+            };
+            Ok((file_tags.path.clone(), file_tags))
+        })
+        .expect("should bind parameters")
         .collect()
     }
 
-    pub fn add_trusted_node(&self, node_id: EndpointId) -> anyhow::Result<()> {
-        let node_id = endpoint_id_to_string(&node_id);
-        self.conn.execute(
-            "INSERT INTO trusted_nodes (node_id) VALUES (?) ON CONFLICT(node_id) DO NOTHING",
-            [&node_id],
-        )?;
+    /// Insert multiple file tags, updating existing entries if they exist.
+    pub fn insert_file_tags<'a>(
+        &mut self,
+        file_tags: impl Iterator<Item = InsertFileTags<'a>>,
+    ) -> anyhow::Result<()> {
+        let tx = self
+            .conn
+            .transaction()
+            .context("failed to begin transaction")?;
+
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO file_tags (path, last_file_size, last_modified_at, title, artist, album, album_artist, track_number, disc_number, duration, codec, sample_rate) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT(path) DO UPDATE SET last_file_size = excluded.last_file_size, last_modified_at = excluded.last_modified_at, title = excluded.title, artist = excluded.artist, album = excluded.album, album_artist = excluded.album_artist, track_number = excluded.track_number, disc_number = excluded.disc_number, duration = excluded.duration, codec = excluded.codec, sample_rate = excluded.sample_rate",
+            )?;
+
+            for file_tags in file_tags {
+                stmt.execute((
+                    file_tags.path,
+                    file_tags.last_file_size,
+                    file_tags.last_modified_at,
+                    file_tags.title,
+                    file_tags.artist,
+                    file_tags.album,
+                    file_tags.album_artist,
+                    file_tags.track_number,
+                    file_tags.disc_number,
+                    file_tags.duration,
+                    file_tags.codec,
+                    file_tags.sample_rate,
+                ))?;
+            }
+        }
+
+        tx.commit().context("failed to commit transaction")?;
+
+        self.files_changed();
         Ok(())
     }
 
-    pub fn remove_trusted_node(&self, node_id: EndpointId) -> anyhow::Result<()> {
-        let node_id = endpoint_id_to_string(&node_id);
-        self.conn
-            .execute("DELETE FROM trusted_nodes WHERE node_id = ?", [&node_id])?;
+    /// Delete the cached tags of multiple files, so stale tags aren't used for their tracks.
+    pub fn remove_file_tags<'a>(
+        &mut self,
+        paths: impl Iterator<Item = Cow<'a, str>>,
+    ) -> anyhow::Result<()> {
+        let tx = self
+            .conn
+            .transaction()
+            .context("failed to begin transaction")?;
+
+        {
+            let mut stmt = tx.prepare_cached("DELETE FROM file_tags WHERE path = ?")?;
+            for path in paths {
+                stmt.execute([path])?;
+            }
+        }
+
+        tx.commit().context("failed to commit transaction")?;
+
+        self.files_changed();
         Ok(())
     }
 
-    pub fn is_node_trusted(&self, node_id: EndpointId) -> anyhow::Result<bool> {
+    /// Rebuild the tracks of a node's files from their cached tags and durations.
+    ///
+    /// Tracks are only built for files that have cached tags, which aren't read for files in
+    /// document trees.
+    pub fn refresh_tracks(&mut self, node_id: EndpointId) -> anyhow::Result<()> {
+        let tx = self
+            .conn
+            .transaction()
+            .context("failed to begin transaction")?;
+
+        rebuild_tracks(&tx, node_id)?;
+
+        tx.commit().context("failed to commit transaction")?;
+
+        self.files_changed();
+        Ok(())
+    }
+
+    /// Get the track of a file by its id.
+    pub fn get_track_by_file_id(&self, file_id: u64) -> anyhow::Result<Option<Track>> {
         let mut stmt = self
             .conn
-            .prepare("SELECT 1 FROM trusted_nodes WHERE node_id = ? LIMIT 1")
+            .prepare_cached(&format!(
+                "SELECT {TRACK_COLUMNS} FROM tracks WHERE file_id = ?"
+            ))
             .expect("should prepare statement");
 
-        let node_id = endpoint_id_to_string(&node_id);
-        let exists: Option<u8> = stmt
-            .query_row([&node_id], |row| row.get(0))
-            .optional()
-            .context("failed to query trusted node")?;
-
-        Ok(exists.is_some())
+        stmt.query_and_then([file_id], |row| track_from_row(row, 0))
+            .expect("should bind parameters")
+            .next()
+            .transpose()
     }
 
-    /// Update a node ID's stored name and connected_at if it is trusted.
-    pub fn update_trusted_node(
+    /// Get the tracks of a node's files, keyed by file id.
+    pub fn get_tracks_by_node_id(
         &self,
         node_id: EndpointId,
-        name: &str,
-        connected_at: u64,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<HashMap<u64, Track>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {TRACK_COLUMNS} FROM tracks
+                JOIN files ON files.id = tracks.file_id
+                WHERE files.node_id = ?"
+            ))
+            .expect("should prepare statement");
+
         let node_id = endpoint_id_to_string(&node_id);
-        self.conn.execute(
-            "UPDATE trusted_nodes SET name = ?, connected_at = ? WHERE node_id = ?",
-            [name, &connected_at.to_string(), &node_id],
-        )?;
-        Ok(())
+        stmt.query_and_then([&node_id], |row| {
+            let track = track_from_row(row, 0)?;
+            Ok((track.file_id, track))
+        })
+        .expect("should bind parameters")
+        .collect()
     }
 
-    /// Insert or update a recent server entry.
-    pub fn update_recent_server(
+    /// Search a node's tracks for a query in their title, artist, album or album artist using the
+    /// full-text index, ordered by relevance.
+    ///
+    /// Each word of the query matches words that start with it, so results update as the user
+    /// types. Returns nothing if the query has no words.
+    pub fn search_tracks(
         &self,
         node_id: EndpointId,
-        name: &str,
-        connected_at: u64,
-    ) -> anyhow::Result<()> {
+        query: &str,
+        limit: u64,
+    ) -> anyhow::Result<Vec<(File, Track)>> {
+        let Some(fts_query) = fts_prefix_query(query) else {
+            return Ok(Vec::new());
+        };
+
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT files.id, files.node_id, files.root, files.path, files.local_tree, files.local_path, {TRACK_COLUMNS}
+                FROM tracks_fts
+                JOIN tracks ON tracks.file_id = tracks_fts.rowid
+                JOIN files ON files.id = tracks.file_id
+                WHERE tracks_fts MATCH ?2 AND files.node_id = ?1
+                ORDER BY bm25(tracks_fts, 10.0, 5.0, 3.0, 3.0), files.path
+                LIMIT ?3"
+            ))
+            .expect("should prepare statement");
+
+        stmt.query_and_then((endpoint_id_to_string(&node_id), fts_query, limit), |row| {
+            let file = File {
+                id: row.get(0)?,
+                node_id: endpoint_id_from_string(&row.get::<_, String>(1)?)?,
+                root: row.get(2)?,
+                path: row.get(3)?,
+                local_tree: row.get(4)?,
+                local_path: row.get(5)?,
+            };
+            Ok((file, track_from_row(row, 6)?))
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Get the albums of a node's tracks, grouped by album artist (falling back to artist) and
+    /// album, and sorted by them.
+    pub fn get_albums_by_node_id(&self, node_id: EndpointId) -> anyhow::Result<Vec<Album>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT COALESCE(tracks.album_artist, tracks.artist), tracks.album, COUNT(*), COALESCE(SUM(tracks.duration), 0)
+                FROM tracks
+                JOIN files ON files.id = tracks.file_id
+                WHERE files.node_id = ?
+                GROUP BY 1, 2
+                ORDER BY 1, 2",
+            )
+            .expect("should prepare statement");
+
         let node_id = endpoint_id_to_string(&node_id);
-        self.conn.execute(
-            "INSERT INTO recent_servers (node_id, name, connected_at) VALUES (?, ?, ?)
-            ON CONFLICT(node_id) DO UPDATE SET name = excluded.name, connected_at = excluded.connected_at",
-            [&node_id, name, &connected_at.to_string()],
-        )?;
-        Ok(())
+        stmt.query_and_then([&node_id], |row| {
+            Ok(Album {
+                artist: row.get(0)?,
+                album: row.get(1)?,
+                track_count: row.get(2)?,
+                duration: row.get(3)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
     }
 
-    pub fn get_recent_servers(&self) -> anyhow::Result<Vec<RecentServer>> {
+    pub fn get_trusted_nodes(&self) -> anyhow::Result<Vec<TrustedNode>> {
         let mut stmt = self
             .conn
             .prepare(
-                "SELECT node_id, name, connected_at FROM recent_servers ORDER BY connected_at DESC",
+                "SELECT trusted_nodes.node_id, name, label, connected_at FROM trusted_nodes
+                LEFT JOIN node_labels ON node_labels.node_id = trusted_nodes.node_id
+                ORDER BY id ASC",
             )
             .expect("should prepare statement");
 
@@ -822,75 +2365,1426 @@ impl Database {
             let node_id =
                 EndpointId::try_from(node_id.as_slice()).context("failed to parse node id")?;
 
-            Ok(RecentServer {
+            Ok(TrustedNode {
                 node_id,
                 name: row.get(1)?,
-                connected_at: row.get(2)?,
+                label: row.get(2)?,
+                connected_at: row.get(3)?,
             })
         })
         .expect("should bind parameters")
         .collect()
     }
 
-    pub fn get_stats(&self) -> anyhow::Result<crate::StatsModel> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT launches, server_sessions, client_sessions, server_files, client_files, server_bytes, client_bytes FROM stats WHERE id = 1")
-            .expect("should prepare statement");
-
-        stmt.query_row([], |row| {
-            Ok(crate::StatsModel {
-                launches: row.get(0)?,
-                server_sessions: row.get(1)?,
-                client_sessions: row.get(2)?,
-                server_files: row.get(3)?,
-                client_files: row.get(4)?,
-                server_bytes: row.get(5)?,
-                client_bytes: row.get(6)?,
-            })
-        })
-        .context("failed to get stats")
-    }
-
-    pub fn track_launch(&self) -> anyhow::Result<()> {
-        self.conn
-            .execute("UPDATE stats SET launches = launches + 1 WHERE id = 1", [])?;
-        Ok(())
-    }
+    pub fn add_trusted_node(&self, node_id: EndpointId) -> anyhow::Result<()> {
+        self.retrust_group_member(node_id)?;
 
-    pub fn track_server_session(&self) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
         self.conn.execute(
-            "UPDATE stats SET server_sessions = server_sessions + 1 WHERE id = 1",
-            [],
+            "INSERT INTO trusted_nodes (node_id) VALUES (?) ON CONFLICT(node_id) DO NOTHING",
+            [&node_id],
         )?;
         Ok(())
     }
 
-    pub fn track_client_session(&self) -> anyhow::Result<()> {
+    /// Stops trusting a node through the sync groups it's a member of, until it's trusted again.
+    pub fn untrust_group_member(&self, node_id: EndpointId) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
         self.conn.execute(
-            "UPDATE stats SET client_sessions = client_sessions + 1 WHERE id = 1",
-            [],
+            "INSERT INTO untrusted_group_members (node_id) VALUES (?)
+            ON CONFLICT(node_id) DO NOTHING",
+            [&node_id],
         )?;
         Ok(())
     }
 
-    pub fn track_server_transfer(&self, files: u64, bytes: u64) -> anyhow::Result<()> {
+    /// Allows trusting a node through the sync groups it's a member of again.
+    pub fn retrust_group_member(&self, node_id: EndpointId) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
         self.conn.execute(
-            "UPDATE stats SET server_files = server_files + ?, server_bytes = server_bytes + ? WHERE id = 1",
-            [files, bytes],
+            "DELETE FROM untrusted_group_members WHERE node_id = ?",
+            [&node_id],
         )?;
         Ok(())
     }
 
-    pub fn track_client_transfer(&self, files: u64, bytes: u64) -> anyhow::Result<()> {
+    pub fn remove_trusted_node(&self, node_id: EndpointId) -> anyhow::Result<()> {
+        self.remove_node_settings(node_id)?;
+        self.untrust_group_member(node_id)?;
+
+        let node_id = endpoint_id_to_string(&node_id);
+        self.conn
+            .execute("DELETE FROM trusted_nodes WHERE node_id = ?", [&node_id])?;
+        self.conn
+            .execute("DELETE FROM node_shares WHERE node_id = ?", [&node_id])?;
+        self.conn
+            .execute("DELETE FROM auto_downloads WHERE node_id = ?", [&node_id])?;
         self.conn.execute(
-            "UPDATE stats SET client_files = client_files + ?, client_bytes = client_bytes + ? WHERE id = 1",
-            [files, bytes],
+            "DELETE FROM auto_download_selections WHERE node_id = ?",
+            [&node_id],
         )?;
+        self.conn
+            .execute("DELETE FROM delivered_hashes WHERE node_id = ?", [&node_id])?;
+        self.conn
+            .execute("DELETE FROM share_filters WHERE node_id = ?", [&node_id])?;
         Ok(())
     }
-}
 
-fn endpoint_id_to_string(node_id: &EndpointId) -> String {
-    hex::encode(node_id)
+    /// Get a setting of a node, or None if it isn't set.
+    ///
+    /// A value that can't be deserialized, e.g. one written by a newer version, is treated as
+    /// unset.
+    pub fn get_node_setting<S: NodeSetting>(
+        &self,
+        node_id: EndpointId,
+    ) -> anyhow::Result<Option<S::Value>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT value FROM node_settings WHERE node_id = ? AND key = ?")
+            .expect("should prepare statement");
+
+        let node_id = endpoint_id_to_string(&node_id);
+        let value: Option<Vec<u8>> = stmt
+            .query_row([node_id.as_str(), S::KEY], |row| row.get(0))
+            .optional()
+            .context("failed to get node setting")?;
+
+        Ok(value.and_then(|value| match postcard::from_bytes(&value) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("failed to deserialize node setting {}: {e:#}", S::KEY);
+                None
+            }
+        }))
+    }
+
+    /// Set a setting of a node, replacing its previous value.
+    pub fn set_node_setting<S: NodeSetting>(
+        &self,
+        node_id: EndpointId,
+        value: &S::Value,
+    ) -> anyhow::Result<()> {
+        let value = postcard::to_stdvec(value).context("failed to serialize node setting")?;
+        self.conn.execute(
+            "INSERT INTO node_settings (node_id, key, value) VALUES (?, ?, ?)
+            ON CONFLICT(node_id, key) DO UPDATE SET value = excluded.value",
+            rusqlite::params![endpoint_id_to_string(&node_id), S::KEY, value],
+        )?;
+        self.settings_hooks.notify(node_id, S::KEY);
+        Ok(())
+    }
+
+    /// Remove a setting of a node, so it falls back to its default.
+    pub fn remove_node_setting<S: NodeSetting>(&self, node_id: EndpointId) -> anyhow::Result<()> {
+        let removed = self.conn.execute(
+            "DELETE FROM node_settings WHERE node_id = ? AND key = ?",
+            [endpoint_id_to_string(&node_id).as_str(), S::KEY],
+        )?;
+        if removed > 0 {
+            self.settings_hooks.notify(node_id, S::KEY);
+        }
+        Ok(())
+    }
+
+    /// Remove all settings of a node.
+    pub fn remove_node_settings(&self, node_id: EndpointId) -> anyhow::Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare("DELETE FROM node_settings WHERE node_id = ? RETURNING key")
+            .expect("should prepare statement");
+
+        let keys = stmt
+            .query_map([endpoint_id_to_string(&node_id)], |row| {
+                row.get::<_, String>(0)
+            })
+            .expect("should bind parameters")
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for key in keys {
+            self.settings_hooks.notify(node_id, &key);
+        }
+        Ok(())
+    }
+
+    /// Get the serialized values of the app's settings by key.
+    pub fn get_settings(&self) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT key, value FROM settings")
+            .expect("should prepare statement");
+
+        let settings = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("should bind parameters")
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(settings)
+    }
+
+    /// Set the serialized values of some of the app's settings, replacing their previous values.
+    pub fn set_settings<'a>(
+        &mut self,
+        values: impl IntoIterator<Item = (&'a str, Vec<u8>)>,
+    ) -> anyhow::Result<()> {
+        let tx = self
+            .conn
+            .transaction()
+            .context("failed to begin transaction")?;
+
+        {
+            let mut stmt = tx
+                .prepare(
+                    "INSERT INTO settings (key, value) VALUES (?, ?)
+                    ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                )
+                .expect("should prepare statement");
+            for (key, value) in values {
+                stmt.execute(rusqlite::params![key, value])?;
+            }
+        }
+
+        tx.commit().context("failed to commit transaction")?;
+        Ok(())
+    }
+
+    /// Get the roots and directories shared with a node, or an empty list if it can see the whole
+    /// library.
+    pub fn get_node_shares(&self, node_id: EndpointId) -> anyhow::Result<Vec<NodeShare>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT root, path_prefix FROM node_shares WHERE node_id = ? ORDER BY id ASC")
+            .expect("should prepare statement");
+
+        let node_id = endpoint_id_to_string(&node_id);
+        stmt.query_map([&node_id], |row| {
+            Ok(NodeShare {
+                root: row.get(0)?,
+                path_prefix: row.get(1)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to query node shares")
+    }
+
+    /// Replace the roots and directories shared with a node. An empty list shares the whole
+    /// library.
+    pub fn set_node_shares(
+        &mut self,
+        node_id: EndpointId,
+        shares: &[NodeShare],
+    ) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
+
+        let tx = self
+            .conn
+            .transaction()
+            .context("failed to begin transaction")?;
+
+        tx.execute("DELETE FROM node_shares WHERE node_id = ?", [&node_id])?;
+        {
+            let mut stmt = tx
+                .prepare(
+                    "INSERT INTO node_shares (node_id, root, path_prefix) VALUES (?, ?, ?)
+                    ON CONFLICT(node_id, root, path_prefix) DO NOTHING",
+                )
+                .context("failed to prepare statement")?;
+            for share in shares {
+                stmt.execute([
+                    node_id.as_str(),
+                    share.root.as_str(),
+                    share.path_prefix.trim_matches('/'),
+                ])?;
+            }
+        }
+
+        tx.commit().context("failed to commit transaction")?;
+
+        Ok(())
+    }
+
+    /// Get the limits on the files shared with a node, or None if it isn't filtered.
+    pub fn get_share_filter(&self, node_id: EndpointId) -> anyhow::Result<Option<ShareFilter>> {
+        self.conn
+            .query_row(
+                "SELECT max_file_size, max_duration, excluded_formats FROM share_filters WHERE node_id = ?",
+                [endpoint_id_to_string(&node_id)],
+                |row| {
+                    let max_file_size: Option<i64> = row.get(0)?;
+                    let excluded_formats: String = row.get(2)?;
+                    Ok(ShareFilter {
+                        max_file_size: max_file_size.map(|max_file_size| max_file_size as u64),
+                        max_duration: row.get(1)?,
+                        excluded_formats: excluded_formats
+                            .split(',')
+                            .filter(|format| !format.is_empty())
+                            .map(str::to_string)
+                            .collect(),
+                    })
+                },
+            )
+            .optional()
+            .context("failed to query share filter")
+    }
+
+    /// Replace the limits on the files shared with a node, or remove them with None.
+    pub fn set_share_filter(
+        &self,
+        node_id: EndpointId,
+        share_filter: Option<&ShareFilter>,
+    ) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
+        match share_filter {
+            Some(share_filter) => {
+                self.conn.execute(
+                    "INSERT INTO share_filters (node_id, max_file_size, max_duration, excluded_formats)
+                    VALUES (?, ?, ?, ?)
+                    ON CONFLICT(node_id) DO UPDATE SET
+                        max_file_size = excluded.max_file_size,
+                        max_duration = excluded.max_duration,
+                        excluded_formats = excluded.excluded_formats",
+                    rusqlite::params![
+                        node_id,
+                        share_filter.max_file_size.map(|max_file_size| max_file_size as i64),
+                        share_filter.max_duration,
+                        share_filter.excluded_formats.join(","),
+                    ],
+                )?;
+            }
+            None => {
+                self.conn
+                    .execute("DELETE FROM share_filters WHERE node_id = ?", [&node_id])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the auto-download settings for a server, or None if auto-download is disabled.
+    pub fn get_auto_download(&self, node_id: EndpointId) -> anyhow::Result<Option<AutoDownload>> {
+        let node_id = endpoint_id_to_string(&node_id);
+
+        let max_bytes: Option<Option<i64>> = self
+            .conn
+            .query_row(
+                "SELECT max_bytes FROM auto_downloads WHERE node_id = ?",
+                [&node_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("failed to query auto download")?;
+        let Some(max_bytes) = max_bytes else {
+            return Ok(None);
+        };
+
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT artist, album FROM auto_download_selections WHERE node_id = ? ORDER BY id ASC",
+            )
+            .expect("should prepare statement");
+        let selections = stmt
+            .query_map([&node_id], |row| {
+                Ok(AutoDownloadSelection {
+                    artist: row.get(0)?,
+                    album: row.get(1)?,
+                })
+            })
+            .expect("should bind parameters")
+            .collect::<Result<Vec<_>, _>>()
+            .context("failed to query auto download selections")?;
+
+        Ok(Some(AutoDownload {
+            selections,
+            max_bytes: max_bytes.map(|max_bytes| max_bytes as u64),
+        }))
+    }
+
+    /// Replace the auto-download settings for a server, or disable auto-download with None.
+    pub fn set_auto_download(
+        &mut self,
+        node_id: EndpointId,
+        auto_download: Option<&AutoDownload>,
+    ) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
+
+        let tx = self
+            .conn
+            .transaction()
+            .context("failed to begin transaction")?;
+
+        tx.execute("DELETE FROM auto_downloads WHERE node_id = ?", [&node_id])?;
+        tx.execute(
+            "DELETE FROM auto_download_selections WHERE node_id = ?",
+            [&node_id],
+        )?;
+        if let Some(auto_download) = auto_download {
+            tx.execute(
+                "INSERT INTO auto_downloads (node_id, max_bytes) VALUES (?, ?)",
+                rusqlite::params![
+                    node_id,
+                    auto_download.max_bytes.map(|max_bytes| max_bytes as i64)
+                ],
+            )?;
+
+            let mut stmt = tx
+                .prepare(
+                    "INSERT INTO auto_download_selections (node_id, artist, album) VALUES (?, ?, ?)",
+                )
+                .context("failed to prepare statement")?;
+            for selection in &auto_download.selections {
+                stmt.execute(rusqlite::params![
+                    node_id,
+                    selection.artist,
+                    selection.album
+                ])?;
+            }
+        }
+
+        tx.commit().context("failed to commit transaction")?;
+
+        Ok(())
+    }
+
+    /// Get the maximum total size of the files downloaded from a server, or None for no limit.
+    pub fn get_storage_quota(&self, node_id: EndpointId) -> anyhow::Result<Option<u64>> {
+        let max_bytes: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT max_bytes FROM storage_quotas WHERE node_id = ?",
+                [endpoint_id_to_string(&node_id)],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("failed to query storage quota")?;
+
+        Ok(max_bytes.map(|max_bytes| max_bytes as u64))
+    }
+
+    /// Set the maximum total size of the files downloaded from a server, or remove the limit with
+    /// None.
+    pub fn set_storage_quota(
+        &self,
+        node_id: EndpointId,
+        max_bytes: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
+        match max_bytes {
+            Some(max_bytes) => {
+                self.conn.execute(
+                    "INSERT INTO storage_quotas (node_id, max_bytes) VALUES (?, ?)
+                    ON CONFLICT(node_id) DO UPDATE SET max_bytes = excluded.max_bytes",
+                    rusqlite::params![node_id, max_bytes as i64],
+                )?;
+            }
+            None => {
+                self.conn
+                    .execute("DELETE FROM storage_quotas WHERE node_id = ?", [&node_id])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the limits on the files downloaded from a server, or None if they aren't filtered.
+    pub fn get_download_filter(
+        &self,
+        node_id: EndpointId,
+    ) -> anyhow::Result<Option<DownloadFilter>> {
+        self.conn
+            .query_row(
+                "SELECT max_file_size, max_duration FROM download_filters WHERE node_id = ?",
+                [endpoint_id_to_string(&node_id)],
+                |row| {
+                    let max_file_size: Option<i64> = row.get(0)?;
+                    Ok(DownloadFilter {
+                        max_file_size: max_file_size.map(|max_file_size| max_file_size as u64),
+                        max_duration: row.get(1)?,
+                    })
+                },
+            )
+            .optional()
+            .context("failed to query download filter")
+    }
+
+    /// Set the limits on the files downloaded from a server, or remove them with None.
+    pub fn set_download_filter(
+        &self,
+        node_id: EndpointId,
+        download_filter: Option<&DownloadFilter>,
+    ) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
+        match download_filter {
+            Some(download_filter) => {
+                self.conn.execute(
+                    "INSERT INTO download_filters (node_id, max_file_size, max_duration)
+                    VALUES (?, ?, ?)
+                    ON CONFLICT(node_id) DO UPDATE SET
+                        max_file_size = excluded.max_file_size,
+                        max_duration = excluded.max_duration",
+                    rusqlite::params![
+                        node_id,
+                        download_filter
+                            .max_file_size
+                            .map(|max_file_size| max_file_size as i64),
+                        download_filter.max_duration,
+                    ],
+                )?;
+            }
+            None => {
+                self.conn
+                    .execute("DELETE FROM download_filters WHERE node_id = ?", [&node_id])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the total size of the files downloaded from a remote node in all local trees. Files
+    /// downloaded before sizes were recorded count as empty.
+    pub fn get_downloaded_bytes(&self, node_id: EndpointId) -> anyhow::Result<u64> {
+        let bytes: i64 = self
+            .conn
+            .query_row(
+                "SELECT COALESCE(SUM(file_size), 0) FROM files WHERE node_id = ?",
+                [endpoint_id_to_string(&node_id)],
+                |row| row.get(0),
+            )
+            .context("failed to query downloaded bytes")?;
+
+        Ok(bytes as u64)
+    }
+
+    /// Get the number and total size of the files downloaded from each remote node, most bytes
+    /// first. Files downloaded before sizes were recorded count as empty.
+    pub fn get_download_usage(
+        &self,
+        local_node_id: EndpointId,
+    ) -> anyhow::Result<Vec<DownloadUsage>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT node_id, files, bytes FROM download_usage
+                WHERE node_id != ? AND files > 0
+                ORDER BY bytes DESC, node_id ASC",
+            )
+            .expect("should prepare statement");
+
+        stmt.query_and_then([endpoint_id_to_string(&local_node_id)], |row| {
+            let node_id =
+                hex::decode(row.get::<_, String>(0)?).context("failed to parse node id")?;
+            let node_id =
+                EndpointId::try_from(node_id.as_slice()).context("failed to parse node id")?;
+
+            Ok(DownloadUsage {
+                node_id,
+                files: row.get(1)?,
+                bytes: row.get(2)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Journal a download when its partial file is opened, replacing any entry for the same
+    /// destination. Returns whether the replaced entry was interrupted.
+    pub fn upsert_download_journal_entry(
+        &self,
+        entry: &DownloadJournalEntry,
+    ) -> anyhow::Result<bool> {
+        let was_interrupted: Option<bool> = self
+            .conn
+            .query_row(
+                "SELECT interrupted FROM download_journal WHERE local_tree = ? AND local_path = ?",
+                rusqlite::params![entry.local_tree, entry.local_path],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        self.conn.execute(
+            "INSERT INTO download_journal
+            (node_id, file_node_id, root, path, local_tree, local_path, expected_size, expected_checksum, bytes_written, started_at, updated_at, interrupted)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT(local_tree, local_path) DO UPDATE SET
+                node_id = excluded.node_id, file_node_id = excluded.file_node_id, root = excluded.root, path = excluded.path,
+                expected_size = excluded.expected_size, expected_checksum = excluded.expected_checksum,
+                bytes_written = excluded.bytes_written, started_at = excluded.started_at, updated_at = excluded.updated_at,
+                interrupted = excluded.interrupted",
+            rusqlite::params![
+                endpoint_id_to_string(&entry.node_id),
+                endpoint_id_to_string(&entry.file_node_id),
+                entry.root,
+                entry.path,
+                entry.local_tree,
+                entry.local_path,
+                entry.expected_size,
+                entry.expected_checksum.map(u64::to_be_bytes),
+                entry.bytes_written,
+                entry.started_at,
+                entry.updated_at,
+                entry.interrupted,
+            ],
+        )?;
+        Ok(was_interrupted.unwrap_or(false))
+    }
+
+    /// Checkpoint the length of a journaled download's partial file.
+    pub fn update_download_journal_progress(
+        &self,
+        local_tree: &str,
+        local_path: &str,
+        bytes_written: u64,
+        updated_at: u64,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE download_journal SET bytes_written = ?, updated_at = ? WHERE local_tree = ? AND local_path = ?",
+            rusqlite::params![bytes_written, updated_at, local_tree, local_path],
+        )?;
+        Ok(())
+    }
+
+    /// Remove a download from the journal once its partial file is moved into place or removed.
+    pub fn delete_download_journal_entry(
+        &self,
+        local_tree: &str,
+        local_path: &str,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "DELETE FROM download_journal WHERE local_tree = ? AND local_path = ?",
+            rusqlite::params![local_tree, local_path],
+        )?;
+        Ok(())
+    }
+
+    /// Get the journaled downloads, optionally only the interrupted ones, oldest first.
+    pub fn get_download_journal(
+        &self,
+        interrupted_only: bool,
+    ) -> anyhow::Result<Vec<DownloadJournalEntry>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT node_id, file_node_id, root, path, local_tree, local_path, expected_size, expected_checksum, bytes_written, started_at, updated_at, interrupted
+                FROM download_journal
+                WHERE ?1 = 0 OR interrupted = 1
+                ORDER BY started_at ASC",
+            )
+            .expect("should prepare statement");
+
+        stmt.query_and_then([interrupted_only], |row| {
+            Ok(DownloadJournalEntry {
+                node_id: endpoint_id_from_string(&row.get::<_, String>(0)?)?,
+                file_node_id: endpoint_id_from_string(&row.get::<_, String>(1)?)?,
+                root: row.get(2)?,
+                path: row.get(3)?,
+                local_tree: row.get(4)?,
+                local_path: row.get(5)?,
+                expected_size: row.get(6)?,
+                expected_checksum: row.get::<_, Option<[u8; 8]>>(7)?.map(u64::from_be_bytes),
+                bytes_written: row.get(8)?,
+                started_at: row.get(9)?,
+                updated_at: row.get(10)?,
+                interrupted: row.get(11)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Mark the journaled downloads as interrupted, or clear the mark of the ones from a server
+    /// when they're requested again.
+    pub fn set_downloads_interrupted(
+        &self,
+        node_id: Option<EndpointId>,
+        interrupted: bool,
+    ) -> anyhow::Result<()> {
+        let node_id = node_id.map(|node_id| endpoint_id_to_string(&node_id));
+        self.conn.execute(
+            "UPDATE download_journal SET interrupted = ?1 WHERE ?2 IS NULL OR node_id = ?2",
+            rusqlite::params![interrupted, node_id],
+        )?;
+        Ok(())
+    }
+
+    /// Whether a node is trusted, either directly or as a member of a sync group this node is in.
+    ///
+    /// Untrusting a node also stops trusting it through its sync groups, until it's trusted again.
+    pub fn is_node_trusted(&self, node_id: EndpointId) -> anyhow::Result<bool> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT 1 FROM trusted_nodes WHERE node_id = ?1
+                UNION ALL SELECT 1 FROM sync_group_records WHERE member = ?1
+                    AND NOT EXISTS (SELECT 1 FROM untrusted_group_members WHERE node_id = ?1)
+                LIMIT 1",
+            )
+            .expect("should prepare statement");
+
+        let node_id = endpoint_id_to_string(&node_id);
+        let exists: Option<u8> = stmt
+            .query_row([&node_id], |row| row.get(0))
+            .optional()
+            .context("failed to query trusted node")?;
+
+        Ok(exists.is_some())
+    }
+
+    /// Update a node ID's stored name and connected_at if it is trusted.
+    pub fn update_trusted_node(
+        &self,
+        node_id: EndpointId,
+        name: &str,
+        connected_at: u64,
+    ) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
+        self.conn.execute(
+            "UPDATE trusted_nodes SET name = ?, connected_at = ? WHERE node_id = ?",
+            [name, &connected_at.to_string(), &node_id],
+        )?;
+        Ok(())
+    }
+
+    /// Insert or update a recent server entry.
+    pub fn update_recent_server(
+        &self,
+        node_id: EndpointId,
+        name: &str,
+        connected_at: u64,
+    ) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
+        self.conn.execute(
+            "INSERT INTO recent_servers (node_id, name, connected_at) VALUES (?, ?, ?)
+            ON CONFLICT(node_id) DO UPDATE SET name = excluded.name, connected_at = excluded.connected_at",
+            [&node_id, name, &connected_at.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Set a node's local label, or remove it with None.
+    ///
+    /// Labels are kept separately from trusted nodes and recent servers, so they
+    /// aren't lost when a node is untrusted and trusted again.
+    pub fn set_node_label(&self, node_id: EndpointId, label: Option<&str>) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
+        match label {
+            Some(label) => {
+                self.conn.execute(
+                    "INSERT INTO node_labels (node_id, label) VALUES (?, ?)
+                    ON CONFLICT(node_id) DO UPDATE SET label = excluded.label",
+                    [&node_id, label],
+                )?;
+            }
+            None => {
+                self.conn
+                    .execute("DELETE FROM node_labels WHERE node_id = ?", [&node_id])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_recent_servers(&self) -> anyhow::Result<Vec<RecentServer>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT recent_servers.node_id, name, label, connected_at FROM recent_servers
+                LEFT JOIN node_labels ON node_labels.node_id = recent_servers.node_id
+                ORDER BY connected_at DESC",
+            )
+            .expect("should prepare statement");
+
+        stmt.query_and_then([], |row| {
+            let node_id =
+                hex::decode(row.get::<_, String>(0)?).context("failed to parse node id")?;
+            let node_id =
+                EndpointId::try_from(node_id.as_slice()).context("failed to parse node id")?;
+
+            Ok(RecentServer {
+                node_id,
+                name: row.get(1)?,
+                label: row.get(2)?,
+                connected_at: row.get(3)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    pub fn get_stats(&self) -> anyhow::Result<crate::StatsModel> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT launches, server_sessions, client_sessions, server_files, client_files, server_bytes, client_bytes FROM stats WHERE id = 1")
+            .expect("should prepare statement");
+
+        stmt.query_row([], |row| {
+            Ok(crate::StatsModel {
+                launches: row.get(0)?,
+                server_sessions: row.get(1)?,
+                client_sessions: row.get(2)?,
+                server_files: row.get(3)?,
+                client_files: row.get(4)?,
+                server_bytes: row.get(5)?,
+                client_bytes: row.get(6)?,
+            })
+        })
+        .context("failed to get stats")
+    }
+
+    pub fn track_launch(&self) -> anyhow::Result<()> {
+        self.conn
+            .execute("UPDATE stats SET launches = launches + 1 WHERE id = 1", [])?;
+        Ok(())
+    }
+
+    pub fn track_server_session(&self) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE stats SET server_sessions = server_sessions + 1 WHERE id = 1",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn track_client_session(&self) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE stats SET client_sessions = client_sessions + 1 WHERE id = 1",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn track_server_transfer(&self, files: u64, bytes: u64) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE stats SET server_files = server_files + ?, server_bytes = server_bytes + ? WHERE id = 1",
+            [files, bytes],
+        )?;
+        Ok(())
+    }
+
+    pub fn track_client_transfer(&self, files: u64, bytes: u64) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE stats SET client_files = client_files + ?, client_bytes = client_bytes + ? WHERE id = 1",
+            [files, bytes],
+        )?;
+        Ok(())
+    }
+
+    /// Add to the bytes transferred with a node.
+    pub fn track_peer_traffic(
+        &self,
+        node_id: EndpointId,
+        sent_bytes: u64,
+        received_bytes: u64,
+    ) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
+        self.conn.execute(
+            "INSERT INTO peer_traffic (node_id, sent_bytes, received_bytes) VALUES (?, ?, ?)
+            ON CONFLICT(node_id) DO UPDATE SET
+                sent_bytes = sent_bytes + excluded.sent_bytes,
+                received_bytes = received_bytes + excluded.received_bytes",
+            rusqlite::params![node_id, sent_bytes, received_bytes],
+        )?;
+        Ok(())
+    }
+
+    /// Get the bytes transferred with each node, most traffic first.
+    pub fn get_peer_traffic(&self) -> anyhow::Result<Vec<PeerTraffic>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT node_id, sent_bytes, received_bytes FROM peer_traffic
+                ORDER BY sent_bytes + received_bytes DESC",
+            )
+            .expect("should prepare statement");
+
+        stmt.query_and_then([], |row| {
+            let node_id =
+                hex::decode(row.get::<_, String>(0)?).context("failed to parse node id")?;
+            let node_id =
+                EndpointId::try_from(node_id.as_slice()).context("failed to parse node id")?;
+
+            Ok(PeerTraffic {
+                node_id,
+                sent_bytes: row.get(1)?,
+                received_bytes: row.get(2)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Insert a finished transfer session and the outcomes of its files, returning its id.
+    pub fn insert_transfer_session<'a>(
+        &mut self,
+        session: InsertTransferSession<'_>,
+        files: impl Iterator<Item = &'a TransferSessionFile>,
+    ) -> anyhow::Result<u64> {
+        let tx = self
+            .conn
+            .transaction()
+            .context("failed to begin transaction")?;
+
+        tx.execute(
+            "INSERT INTO transfer_sessions (node_id, name, finished_at, completed_files, failed_files, transferred_bytes)
+            VALUES (?, ?, ?, ?, ?, ?)",
+            rusqlite::params![
+                endpoint_id_to_string(&session.node_id),
+                session.name,
+                session.finished_at,
+                session.completed_files,
+                session.failed_files,
+                session.transferred_bytes,
+            ],
+        )?;
+        let session_id = tx.last_insert_rowid() as u64;
+
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO transfer_session_files (session_id, root, path, file_size, error) VALUES (?, ?, ?, ?, ?)",
+            )?;
+            for file in files {
+                stmt.execute(rusqlite::params![
+                    session_id,
+                    file.root,
+                    file.path,
+                    file.file_size,
+                    file.error,
+                ])?;
+            }
+        }
+
+        tx.commit().context("failed to commit transaction")?;
+
+        Ok(session_id)
+    }
+
+    /// Get the most recent transfer sessions, optionally only with the given node, most recent
+    /// first.
+    pub fn get_transfer_sessions(
+        &self,
+        node_id: Option<EndpointId>,
+        limit: u64,
+    ) -> anyhow::Result<Vec<TransferSession>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT id, node_id, name, finished_at, completed_files, failed_files, transferred_bytes
+                FROM transfer_sessions
+                WHERE ?1 IS NULL OR node_id = ?1
+                ORDER BY finished_at DESC, id DESC
+                LIMIT ?2",
+            )
+            .expect("should prepare statement");
+
+        let node_id = node_id.map(|node_id| endpoint_id_to_string(&node_id));
+        stmt.query_and_then(rusqlite::params![node_id, limit], |row| {
+            Ok(TransferSession {
+                id: row.get(0)?,
+                node_id: endpoint_id_from_string(&row.get::<_, String>(1)?)?,
+                name: row.get(2)?,
+                finished_at: row.get(3)?,
+                completed_files: row.get(4)?,
+                failed_files: row.get(5)?,
+                transferred_bytes: row.get(6)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Get the outcomes of the files in a transfer session.
+    pub fn get_transfer_session_files(
+        &self,
+        session_id: u64,
+    ) -> anyhow::Result<Vec<TransferSessionFile>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT root, path, file_size, error FROM transfer_session_files
+                WHERE session_id = ?
+                ORDER BY id ASC",
+            )
+            .expect("should prepare statement");
+
+        stmt.query_and_then([session_id], |row| {
+            Ok(TransferSessionFile {
+                root: row.get(0)?,
+                path: row.get(1)?,
+                file_size: row.get(2)?,
+                error: row.get(3)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Delete transfer sessions that finished before the given time, and their files. Returns
+    /// the number of sessions deleted.
+    pub fn prune_transfer_sessions(&mut self, before: u64) -> anyhow::Result<usize> {
+        let tx = self
+            .conn
+            .transaction()
+            .context("failed to begin transaction")?;
+
+        tx.execute(
+            "DELETE FROM transfer_session_files WHERE session_id IN
+                (SELECT id FROM transfer_sessions WHERE finished_at < ?)",
+            [before],
+        )?;
+        let deleted = tx.execute(
+            "DELETE FROM transfer_sessions WHERE finished_at < ?",
+            [before],
+        )?;
+
+        tx.commit().context("failed to commit transaction")?;
+
+        Ok(deleted)
+    }
+
+    /// Record that a file with the given content hash was sent to a node.
+    pub fn insert_delivered_hash(
+        &self,
+        node_id: EndpointId,
+        hash_kind: &str,
+        hash: &[u8; 16],
+        delivered_at: u64,
+    ) -> anyhow::Result<()> {
+        let node_id = endpoint_id_to_string(&node_id);
+        self.conn.execute(
+            "INSERT INTO delivered_hashes (node_id, hash_kind, hash, delivered_at) VALUES (?, ?, ?, ?)
+            ON CONFLICT(node_id, hash_kind, hash) DO UPDATE SET delivered_at = excluded.delivered_at",
+            rusqlite::params![node_id, hash_kind, hash, delivered_at],
+        )?;
+        Ok(())
+    }
+
+    /// Get the local node's files with their content hashes, and whether a file with the same
+    /// hash was sent to the given node.
+    pub fn get_delivered_files(
+        &self,
+        local_node_id: EndpointId,
+        node_id: EndpointId,
+    ) -> anyhow::Result<Vec<(LocalFileHash, bool)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT files.root, files.path, file_hashes.hash_kind, file_hashes.hash, delivered_hashes.id IS NOT NULL FROM files LEFT JOIN file_hashes ON file_hashes.path = files.local_path LEFT JOIN delivered_hashes ON delivered_hashes.node_id = ?2 AND delivered_hashes.hash_kind = file_hashes.hash_kind AND delivered_hashes.hash = file_hashes.hash WHERE files.node_id = ?1")
+            .expect("should prepare statement");
+
+        stmt.query_and_then(
+            [
+                endpoint_id_to_string(&local_node_id),
+                endpoint_id_to_string(&node_id),
+            ],
+            |row| {
+                let hash_kind: Option<String> = row.get(2)?;
+                let hash: Option<[u8; 16]> = row.get(3)?;
+
+                Ok((
+                    LocalFileHash {
+                        root: row.get(0)?,
+                        path: row.get(1)?,
+                        hash: hash_kind.zip(hash),
+                    },
+                    row.get(4)?,
+                ))
+            },
+        )
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Get the sync groups that this node is a member of.
+    pub fn get_sync_groups(&self) -> anyhow::Result<Vec<SyncGroup>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT group_id, name, founder FROM sync_groups ORDER BY rowid ASC")
+            .expect("should prepare statement");
+
+        stmt.query_and_then([], |row| {
+            Ok(SyncGroup {
+                group_id: row.get(0)?,
+                name: row.get(1)?,
+                founder: endpoint_id_from_string(&row.get::<_, String>(2)?)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    pub fn insert_sync_group(
+        &self,
+        group_id: GroupId,
+        name: &str,
+        founder: EndpointId,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO sync_groups (group_id, name, founder) VALUES (?, ?, ?)
+            ON CONFLICT(group_id) DO NOTHING",
+            rusqlite::params![group_id, name, endpoint_id_to_string(&founder)],
+        )?;
+        Ok(())
+    }
+
+    /// Remove a sync group and its records, so its members are no longer trusted through it.
+    pub fn delete_sync_group(&self, group_id: GroupId) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM sync_groups WHERE group_id = ?", [group_id])?;
+        self.conn.execute(
+            "DELETE FROM sync_group_records WHERE group_id = ?",
+            [group_id],
+        )?;
+        Ok(())
+    }
+
+    /// Get the membership records of a sync group, oldest first.
+    pub fn get_group_records(&self, group_id: GroupId) -> anyhow::Result<Vec<GroupRecord>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT group_id, group_name, founder, member, issuer, issued_at, signature
+                FROM sync_group_records WHERE group_id = ? ORDER BY id ASC",
+            )
+            .expect("should prepare statement");
+
+        stmt.query_and_then([group_id], |row| {
+            Ok(GroupRecord {
+                group_id: row.get(0)?,
+                group_name: row.get(1)?,
+                founder: endpoint_id_from_string(&row.get::<_, String>(2)?)?,
+                member: endpoint_id_from_string(&row.get::<_, String>(3)?)?,
+                issuer: endpoint_id_from_string(&row.get::<_, String>(4)?)?,
+                issued_at: row.get(5)?,
+                signature: row.get(6)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Store a verified membership record, unless the member already has one.
+    pub fn insert_group_record(&self, record: &GroupRecord) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO sync_group_records
+            (group_id, group_name, founder, member, issuer, issued_at, signature)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT(group_id, member) DO NOTHING",
+            rusqlite::params![
+                record.group_id,
+                record.group_name,
+                endpoint_id_to_string(&record.founder),
+                endpoint_id_to_string(&record.member),
+                endpoint_id_to_string(&record.issuer),
+                record.issued_at,
+                record.signature,
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// Whether an error is caused by the database file being corrupted or not being a database.
+fn is_corruption(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::SqliteFailure(error, _))
+                if matches!(
+                    error.code,
+                    rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase
+                )
+        )
+    })
+}
+
+fn endpoint_id_to_string(node_id: &EndpointId) -> String {
+    hex::encode(node_id)
+}
+
+fn endpoint_id_from_string(node_id: &str) -> anyhow::Result<EndpointId> {
+    let node_id = hex::decode(node_id).context("failed to parse node id")?;
+    EndpointId::try_from(node_id.as_slice()).context("failed to parse node id")
+}
+
+fn downloaded_file_from_row(row: &rusqlite::Row) -> anyhow::Result<DownloadedFile> {
+    let source_hash_kind: Option<String> = row.get(4)?;
+    let source_hash: Option<[u8; 16]> = row.get(5)?;
+    let file_size: Option<u64> = row.get(6)?;
+    let checksum: Option<[u8; 8]> = row.get(7)?;
+
+    let manifest = match (file_size, checksum) {
+        (Some(file_size), Some(checksum)) => Some(FileManifest {
+            file_size,
+            checksum: u64::from_be_bytes(checksum),
+            title: row.get(8)?,
+            artist: row.get(9)?,
+            album: row.get(10)?,
+        }),
+        _ => None,
+    };
+
+    Ok(DownloadedFile {
+        root: row.get(0)?,
+        path: row.get(1)?,
+        local_tree: row.get(2)?,
+        local_path: row.get(3)?,
+        source_hash: source_hash_kind.zip(source_hash),
+        manifest,
+    })
+}
+
+/// The columns of the tracks table, in the order read by `track_from_row`.
+const TRACK_COLUMNS: &str = "tracks.file_id, tracks.title, tracks.artist, tracks.album, tracks.album_artist, tracks.track_number, tracks.disc_number, tracks.duration, tracks.codec, tracks.sample_rate";
+
+/// Reads a track from the `TRACK_COLUMNS` of a row, starting at the given column.
+fn track_from_row(row: &rusqlite::Row, start: usize) -> anyhow::Result<Track> {
+    Ok(Track {
+        file_id: row.get(start)?,
+        title: row.get(start + 1)?,
+        artist: row.get(start + 2)?,
+        album: row.get(start + 3)?,
+        album_artist: row.get(start + 4)?,
+        track_number: row.get(start + 5)?,
+        disc_number: row.get(start + 6)?,
+        duration: row.get(start + 7)?,
+        codec: row.get(start + 8)?,
+        sample_rate: row.get(start + 9)?,
+    })
+}
+
+/// Rebuilds the tracks of a node's files from the cached tags, falling back to the cached
+/// durations for files whose duration couldn't be read from their metadata.
+fn rebuild_tracks(conn: &rusqlite::Connection, node_id: EndpointId) -> anyhow::Result<()> {
+    let node_id = endpoint_id_to_string(&node_id);
+    conn.execute(
+        "DELETE FROM tracks WHERE file_id IN (SELECT id FROM files WHERE node_id = ?)",
+        [&node_id],
+    )?;
+    conn.execute(
+        "INSERT INTO tracks (file_id, title, artist, album, album_artist, track_number, disc_number, duration, codec, sample_rate)
+        SELECT files.id, file_tags.title, file_tags.artist, file_tags.album, file_tags.album_artist, file_tags.track_number,
+            file_tags.disc_number, COALESCE(file_tags.duration, file_sizes.duration), file_tags.codec, file_tags.sample_rate
+        FROM files
+        JOIN file_tags ON file_tags.path = files.local_path
+        LEFT JOIN file_sizes ON file_sizes.path = files.local_path
+        WHERE files.node_id = ? AND files.local_tree = ''",
+        [&node_id],
+    )?;
+    Ok(())
+}
+
+/// Builds an FTS5 query that matches rows containing words starting with each word of the query,
+/// or None if the query has no words.
+///
+/// Words are split on the same characters as the tokenizer and quoted, so the query can't use
+/// FTS5 syntax.
+fn fts_prefix_query(query: &str) -> Option<String> {
+    let terms = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{word}\"*"))
+        .join(" ");
+    (!terms.is_empty()).then_some(terms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    #[test]
+    fn test_open_healthy_database() {
+        let path = testdir::testdir!().join("musicopy.db");
+
+        let (pool, recovery) = DatabasePool::open_file(&path).unwrap();
+        assert!(recovery.is_none());
+        drop(pool);
+
+        let (_pool, recovery) = DatabasePool::open_file(&path).unwrap();
+        assert!(recovery.is_none());
+    }
+
+    #[test]
+    fn test_recover_not_a_database() {
+        let dir = testdir::testdir!();
+        let path = dir.join("musicopy.db");
+        std::fs::write(&path, vec![0xAB; 64 * 1024]).unwrap();
+
+        let (pool, recovery) = DatabasePool::open_file(&path).unwrap();
+        let recovery = recovery.expect("should recover corrupted database");
+        assert!(!recovery.problems.is_empty());
+        assert!(recovery.backup_path.exists());
+        assert!(!recovery.lost_tables.is_empty());
+
+        // the new database works
+        let node_id = SecretKey::generate().public();
+        let db = pool.get();
+        db.add_root(node_id, "music", "/music").unwrap();
+        assert_eq!(db.get_roots_by_node_id(node_id).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_recover_copies_tables() {
+        let path = testdir::testdir!().join("musicopy.db");
+        let node_id = SecretKey::generate().public();
+
+        {
+            let (pool, _) = DatabasePool::open_file(&path).unwrap();
+            let db = pool.get();
+            db.add_root(node_id, "music", "/music").unwrap();
+            db.track_launch().unwrap();
+        }
+
+        let recovery = Database::recover(&path, vec!["test".into()]).unwrap();
+        assert!(recovery.lost_tables.is_empty());
+        assert!(recovery.backup_path.exists());
+
+        let (pool, recovery) = DatabasePool::open_file(&path).unwrap();
+        assert!(recovery.is_none());
+        let db = pool.get();
+        let roots = db.get_roots_by_node_id(node_id).unwrap();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].path, "/music");
+        assert_eq!(db.get_stats().unwrap().launches, 1);
+    }
+
+    #[test]
+    fn test_scanned_files() {
+        let path = testdir::testdir!().join("musicopy.db");
+        let local_node_id = SecretKey::generate().public();
+
+        let (pool, _) = DatabasePool::open_file(&path).unwrap();
+        let mut db = pool.get();
+        let scan = |db: &mut Database, paths: &[&str]| {
+            db.clear_scanned_files().unwrap();
+            // insert in chunks of one file, like a scan inserting batches
+            for path in paths {
+                let file = InsertFile {
+                    root: "music",
+                    path,
+                    local_tree: "",
+                    local_path: path,
+                };
+                db.insert_scanned_files(local_node_id, std::iter::once(file))
+                    .unwrap();
+            }
+            db.remove_unscanned_files(local_node_id).unwrap();
+            let mut files = db.get_files_by_node_id(local_node_id).unwrap();
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            files
+        };
+
+        let files = scan(&mut *db, &["a.ogg", "b.ogg"]);
+        assert_eq!(files.len(), 2);
+        let a_id = files[0].id;
+
+        // files that are still there keep their ids, and missing files are removed
+        let files = scan(&mut *db, &["a.ogg", "c.ogg"]);
+        let paths = files
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["a.ogg", "c.ogg"]);
+        assert_eq!(files[0].id, a_id);
+    }
+
+    #[test]
+    fn test_files_version() {
+        let path = testdir::testdir!().join("musicopy.db");
+        let local_node_id = SecretKey::generate().public();
+
+        let (pool, _) = DatabasePool::open_file(&path).unwrap();
+        let version = pool.files_version();
+
+        // hold one connection so the write goes through a newly opened one
+        let _db = pool.get();
+        let mut db = pool.get();
+        let file = InsertFile {
+            root: "music",
+            path: "a.ogg",
+            local_tree: "",
+            local_path: "a.ogg",
+        };
+        db.insert_scanned_files(local_node_id, std::iter::once(file))
+            .unwrap();
+        assert_ne!(pool.files_version(), version);
+
+        // reads don't change the version
+        let version = pool.files_version();
+        db.get_files().unwrap();
+        assert_eq!(pool.files_version(), version);
+    }
+
+    #[test]
+    fn test_in_memory_pool_shares_database() {
+        let local_node_id = SecretKey::generate().public();
+        let pool = DatabasePool::open_in_memory().unwrap();
+
+        // a nested connection sees writes made through the first one
+        let mut db = pool.get();
+        let file = InsertFile {
+            root: "music",
+            path: "a.ogg",
+            local_tree: "",
+            local_path: "a.ogg",
+        };
+        db.insert_scanned_files(local_node_id, std::iter::once(file))
+            .unwrap();
+        let nested = pool.get();
+        assert_eq!(nested.get_files().unwrap().len(), 1);
+
+        // other pools have their own database
+        let other = DatabasePool::open_in_memory().unwrap();
+        assert!(other.get().get_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_download_usage() {
+        let path = testdir::testdir!().join("musicopy.db");
+        let local_node_id = SecretKey::generate().public();
+        let server_id = SecretKey::generate().public();
+
+        let (pool, _) = DatabasePool::open_file(&path).unwrap();
+        let mut db = pool.get();
+        let manifest = |file_size| FileManifest {
+            file_size,
+            checksum: 0,
+            title: None,
+            artist: None,
+            album: None,
+        };
+        for (path, file_size) in [("a.ogg", 100), ("b.ogg", 200)] {
+            let file = InsertFile {
+                root: "music",
+                path,
+                local_tree: "/downloads",
+                local_path: path,
+            };
+            db.insert_remote_file(server_id, file, None, Some(&manifest(file_size)))
+                .unwrap();
+        }
+
+        let usage = db.get_download_usage(local_node_id).unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].node_id, server_id);
+        assert_eq!((usage[0].files, usage[0].bytes), (2, 300));
+
+        // downloading a file again replaces its size
+        let file = InsertFile {
+            root: "music",
+            path: "a.ogg",
+            local_tree: "/downloads",
+            local_path: "a.ogg",
+        };
+        db.insert_remote_file(server_id, file, None, Some(&manifest(150)))
+            .unwrap();
+        let usage = db.get_download_usage(local_node_id).unwrap();
+        assert_eq!((usage[0].files, usage[0].bytes), (2, 350));
+
+        db.remove_files_by_local_treepath(
+            [("/downloads".to_string(), "b.ogg".to_string())].into_iter(),
+        )
+        .unwrap();
+        let usage = db.get_download_usage(local_node_id).unwrap();
+        assert_eq!((usage[0].files, usage[0].bytes), (1, 150));
+
+        db.remove_files_by_local_treepath(
+            [("/downloads".to_string(), "a.ogg".to_string())].into_iter(),
+        )
+        .unwrap();
+        assert!(db.get_download_usage(local_node_id).unwrap().is_empty());
+    }
 }
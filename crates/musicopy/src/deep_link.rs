@@ -0,0 +1,124 @@
+//! Deep links that open the app at an action, e.g. from a tapped link or a scanned QR code.
+//!
+//! `musicopy://connect/<ticket>` connects to the node in a pairing ticket, and also takes a bare
+//! endpoint ID instead of a ticket. Share links are plain HTTP URLs for browsers, so they're
+//! recognized to be opened in one rather than rejected. Tickets without a link around them are
+//! accepted too, since that's what QR codes made from `create_pairing_ticket` contain.
+
+use crate::pairing::PairingTicket;
+use anyhow::Context;
+use iroh::EndpointId;
+
+/// Scheme of deep links into the app.
+const SCHEME: &str = "musicopy";
+
+/// What `Core::handle_url` did with a link.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Enum)]
+pub enum DeepLinkModel {
+    /// Started connecting to a node. The connection shows up in the node model like one started
+    /// by `connect`, so the pairing flow continues from there.
+    Connecting { endpoint_id: String },
+    /// A share link, which the UI should open in a browser.
+    ShareLink { url: String },
+}
+
+/// What a deep link asks the app to do.
+#[derive(Debug)]
+pub(crate) enum DeepLink {
+    Connect(PairingTicket),
+    ConnectEndpoint(EndpointId),
+    ShareLink { url: String },
+}
+
+impl DeepLink {
+    /// Parses a deep link, share link, or bare pairing ticket.
+    pub(crate) fn parse(link: &str) -> anyhow::Result<Self> {
+        let link = link.trim();
+        if let Ok(ticket) = PairingTicket::decode(link) {
+            return Ok(DeepLink::Connect(ticket));
+        }
+
+        let url = url::Url::parse(link).context("failed to parse link")?;
+        match url.scheme() {
+            SCHEME => match url.host_str() {
+                Some("connect") => {
+                    let target = url.path().trim_matches('/');
+                    anyhow::ensure!(!target.is_empty(), "connect link has no ticket");
+                    if let Ok(endpoint_id) = target.parse::<EndpointId>() {
+                        return Ok(DeepLink::ConnectEndpoint(endpoint_id));
+                    }
+                    let ticket = PairingTicket::decode(target)?;
+                    Ok(DeepLink::Connect(ticket))
+                }
+                host => anyhow::bail!("unsupported link action {host:?}"),
+            },
+            "http" | "https" => {
+                // share links end with /s/<token>, after the server's public url
+                let segments = url
+                    .path_segments()
+                    .map(|segments| segments.collect::<Vec<_>>())
+                    .unwrap_or_default();
+                let is_share_link = matches!(
+                    segments.as_slice(),
+                    [.., "s", token] if !token.is_empty()
+                );
+                anyhow::ensure!(is_share_link, "not a share link");
+                Ok(DeepLink::ShareLink {
+                    url: url.to_string(),
+                })
+            }
+            scheme => anyhow::bail!("unsupported link scheme {scheme:?}"),
+        }
+    }
+}
+
+/// Builds a deep link that connects to the node in a pairing ticket.
+pub(crate) fn connect_link(ticket: &str) -> String {
+    format!("{SCHEME}://connect/{ticket}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::{EndpointAddr, SecretKey};
+
+    #[test]
+    fn test_parse() {
+        let endpoint_id = SecretKey::generate().public();
+        let ticket = PairingTicket::new(&EndpointAddr::new(endpoint_id), None)
+            .encode()
+            .unwrap();
+
+        for link in [
+            connect_link(&ticket),
+            ticket.clone(),
+            format!(" {ticket}\n"),
+        ] {
+            let DeepLink::Connect(parsed) = DeepLink::parse(&link).unwrap() else {
+                panic!("should parse {link:?} as a ticket");
+            };
+            assert_eq!(parsed.endpoint_id, endpoint_id);
+        }
+
+        assert!(matches!(
+            DeepLink::parse(&connect_link(&endpoint_id.to_string())).unwrap(),
+            DeepLink::ConnectEndpoint(parsed) if parsed == endpoint_id
+        ));
+
+        assert!(matches!(
+            DeepLink::parse("https://music.example.com/share/s/abc123").unwrap(),
+            DeepLink::ShareLink { url } if url == "https://music.example.com/share/s/abc123"
+        ));
+
+        for link in [
+            "musicopy://connect/",
+            "musicopy://connect/not-a-ticket",
+            "musicopy://settings",
+            "https://example.com/",
+            "ftp://example.com/s/abc123",
+            "not a link",
+        ] {
+            assert!(DeepLink::parse(link).is_err(), "should reject {link:?}");
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Diagnostic bundles for bug reports.
+//!
+//! A bundle is a zip file with the recent logs, the settings, counts of the rows in the database,
+//! and information about the platform, so a user can attach one file to a bug report. The
+//! database is only summarized by counts, so the bundle doesn't include the names of files or
+//! nodes, but logs and settings can still include paths.
+
+use crate::database::Database;
+use anyhow::Context;
+use flate2::{Compression, write::DeflateEncoder};
+use std::{fmt::Write as _, io::Write as _, path::Path};
+
+static ZIP_CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Describes the database without its contents.
+pub(crate) fn database_summary(db: &Database) -> anyhow::Result<String> {
+    let mut summary = String::new();
+    writeln!(summary, "sqlite version: {}", rusqlite::version())?;
+    writeln!(summary, "schema version: {}", db.get_schema_version()?)?;
+    writeln!(summary)?;
+    for (table, count) in db.get_table_counts()? {
+        writeln!(summary, "{table}: {count} rows")?;
+    }
+    Ok(summary)
+}
+
+/// Describes the platform and build.
+pub(crate) fn platform_summary() -> String {
+    format!(
+        "version: {}\nos: {}\nfamily: {}\narch: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::FAMILY,
+        std::env::consts::ARCH,
+    )
+}
+
+/// Adds the files in the log directory to a bundle. Rotated logs are already compressed, so
+/// they're added as they are.
+pub(crate) fn add_logs(zip: &mut ZipWriter, log_dir: &Path) -> anyhow::Result<()> {
+    if !log_dir.exists() {
+        return Ok(());
+    }
+
+    let mut log_files: Vec<_> = std::fs::read_dir(log_dir)
+        .context("failed to read log dir")?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    log_files.sort();
+
+    for path in log_files {
+        let Some(file_name) = path.file_name() else {
+            continue;
+        };
+        let content = std::fs::read(&path)
+            .with_context(|| format!("failed to read log file {}", path.display()))?;
+        zip.add_file(&format!("logs/{}", file_name.to_string_lossy()), &content)?;
+    }
+
+    Ok(())
+}
+
+/// Writes a zip file with deflated entries in memory.
+///
+/// Only supports what bundles need: no directories, no timestamps, and no ZIP64, so entries and
+/// the whole file must be smaller than 4 GiB.
+pub(crate) struct ZipWriter {
+    buf: Vec<u8>,
+    central_directory: Vec<u8>,
+    num_entries: u16,
+}
+
+impl ZipWriter {
+    pub(crate) fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            central_directory: Vec::new(),
+            num_entries: 0,
+        }
+    }
+
+    pub(crate) fn add_file(&mut self, name: &str, content: &[u8]) -> anyhow::Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content)?;
+        let compressed = encoder.finish()?;
+
+        let crc = ZIP_CRC.checksum(content);
+        let compressed_size = u32::try_from(compressed.len()).context("file too large for zip")?;
+        let size = u32::try_from(content.len()).context("file too large for zip")?;
+        let offset = u32::try_from(self.buf.len()).context("zip too large")?;
+        let name_len = u16::try_from(name.len()).context("file name too long for zip")?;
+
+        // fields shared by the local header and the central directory: version needed to extract
+        // 2.0, flags with the UTF-8 name bit, deflate, no timestamp, crc, sizes, and name length
+        let mut common = Vec::with_capacity(24);
+        common.extend_from_slice(&20u16.to_le_bytes());
+        common.extend_from_slice(&(1u16 << 11).to_le_bytes());
+        common.extend_from_slice(&8u16.to_le_bytes());
+        common.extend_from_slice(&0u32.to_le_bytes());
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&compressed_size.to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&name_len.to_le_bytes());
+
+        // local file header, with no extra field
+        self.buf.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        self.buf.extend_from_slice(&common);
+        self.buf.extend_from_slice(&0u16.to_le_bytes());
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.extend_from_slice(&compressed);
+
+        // central directory header, made by version 2.0, with no extra field, comment, or
+        // attributes
+        let cd = &mut self.central_directory;
+        cd.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        cd.extend_from_slice(&20u16.to_le_bytes());
+        cd.extend_from_slice(&common);
+        cd.extend_from_slice(&[0; 12]);
+        cd.extend_from_slice(&offset.to_le_bytes());
+        cd.extend_from_slice(name.as_bytes());
+
+        self.num_entries = self
+            .num_entries
+            .checked_add(1)
+            .context("too many files for zip")?;
+        Ok(())
+    }
+
+    /// Appends the central directory and returns the zip file.
+    pub(crate) fn finish(mut self) -> anyhow::Result<Vec<u8>> {
+        let cd_offset = u32::try_from(self.buf.len()).context("zip too large")?;
+        let cd_size = u32::try_from(self.central_directory.len()).context("zip too large")?;
+        self.buf.extend_from_slice(&self.central_directory);
+
+        // end of central directory record, for a single disk and with no comment
+        self.buf.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        self.buf.extend_from_slice(&[0; 4]);
+        self.buf.extend_from_slice(&self.num_entries.to_le_bytes());
+        self.buf.extend_from_slice(&self.num_entries.to_le_bytes());
+        self.buf.extend_from_slice(&cd_size.to_le_bytes());
+        self.buf.extend_from_slice(&cd_offset.to_le_bytes());
+        self.buf.extend_from_slice(&0u16.to_le_bytes());
+
+        Ok(self.buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_zip_writer() {
+        let mut zip = ZipWriter::new();
+        zip.add_file("a.txt", b"hello hello hello").unwrap();
+        zip.add_file("logs/b.log", b"").unwrap();
+        let buf = zip.finish().unwrap();
+
+        // the end of central directory record counts both files
+        let eocd = &buf[buf.len() - 22..];
+        assert_eq!(&eocd[..4], &0x06054b50u32.to_le_bytes());
+        assert_eq!(u16::from_le_bytes([eocd[10], eocd[11]]), 2);
+        let cd_offset = u32::from_le_bytes(eocd[16..20].try_into().unwrap()) as usize;
+        assert_eq!(&buf[cd_offset..cd_offset + 4], &0x02014b50u32.to_le_bytes());
+
+        // the first file can be read back from its local header
+        assert_eq!(&buf[..4], &0x04034b50u32.to_le_bytes());
+        let crc = u32::from_le_bytes(buf[14..18].try_into().unwrap());
+        let compressed_size = u32::from_le_bytes(buf[18..22].try_into().unwrap()) as usize;
+        let name_len = u16::from_le_bytes([buf[26], buf[27]]) as usize;
+        assert_eq!(&buf[30..30 + name_len], b"a.txt");
+        let data_start = 30 + name_len;
+        let mut content = Vec::new();
+        flate2::read::DeflateDecoder::new(&buf[data_start..data_start + compressed_size])
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"hello hello hello");
+        assert_eq!(crc, ZIP_CRC.checksum(&content));
+    }
+}
@@ -0,0 +1,673 @@
+//! Manager for the files downloaded from servers, so they can be listed and cleaned up from the
+//! app instead of the filesystem.
+//!
+//! Downloaded files are tracked in the files table with the server they came from. Sizes come from
+//! the manifests servers send with files, so listing is fast but doesn't notice files that were
+//! changed or deleted outside the app. `measure` checks the files on disk instead.
+//!
+//! When the download path template changes, `reorganize` moves the files that were already
+//! downloaded to the paths new downloads would get, so the download directory doesn't end up with
+//! a mix of layouts.
+//!
+//! Files copied to the download directory outside the app, e.g. by hand before using it, can be
+//! matched to a server's index with `import`, so they're tracked as downloaded instead of being
+//! transferred again.
+
+use crate::{
+    database::{DatabasePool, DownloadedFile, FileManifest, InsertFile},
+    fs::{
+        TreePath,
+        template::{PathTemplate, TemplateValues},
+    },
+    library::hash::HashCache,
+    node::{build_download_path, file_checksum},
+    playlist::{self, PlaylistSettings},
+    protocol::{ContentHash, ItemExtraMetadata, ItemMetadata},
+};
+use anyhow::Context;
+use iroh::EndpointId;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use tracing::{debug, info, warn};
+
+/// Model of the files downloaded from a server.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct DownloadedServerModel {
+    pub endpoint_id: String,
+    pub files: u64,
+    /// Total size of the files. Files downloaded before sizes were recorded count as empty, unless
+    /// measured.
+    pub bytes: u64,
+    /// Number of tracked files that no longer exist, or None if the files weren't measured.
+    pub missing_files: Option<u64>,
+}
+
+/// Model of a file downloaded from a server.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct DownloadedFileModel {
+    /// The root and path of the file on the server.
+    pub root: String,
+    pub path: String,
+    pub local_tree: String,
+    pub local_path: String,
+    /// Size of the file when it was downloaded, if recorded.
+    pub file_size: Option<u64>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+impl From<DownloadedFile> for DownloadedFileModel {
+    fn from(file: DownloadedFile) -> Self {
+        let manifest = file.manifest;
+        Self {
+            root: file.root,
+            path: file.path,
+            local_tree: file.local_tree,
+            local_path: file.local_path,
+            file_size: manifest.as_ref().map(|manifest| manifest.file_size),
+            title: manifest
+                .as_ref()
+                .and_then(|manifest| manifest.title.clone()),
+            artist: manifest
+                .as_ref()
+                .and_then(|manifest| manifest.artist.clone()),
+            album: manifest.and_then(|manifest| manifest.album),
+        }
+    }
+}
+
+/// Selects downloaded files from a server to delete.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Enum)]
+pub enum DownloadedFileSelectionModel {
+    /// A file, by its root and path on the server.
+    File { root: String, path: String },
+    /// The files of an album, by their album tag and optionally their artist tag. Files downloaded
+    /// before tags were recorded don't match.
+    Album {
+        artist: Option<String>,
+        album: String,
+    },
+    /// Every file downloaded from the server.
+    All,
+}
+
+impl DownloadedFileSelectionModel {
+    fn matches(&self, file: &DownloadedFile) -> bool {
+        match self {
+            Self::File { root, path } => file.root == *root && file.path == *path,
+            Self::Album { artist, album } => file.manifest.as_ref().is_some_and(|manifest| {
+                manifest.album.as_ref() == Some(album)
+                    && artist
+                        .as_ref()
+                        .is_none_or(|artist| manifest.artist.as_ref() == Some(artist))
+            }),
+            Self::All => true,
+        }
+    }
+}
+
+/// Result of deleting downloaded files.
+#[derive(Debug, Clone, Default, PartialEq, Eq, uniffi::Record)]
+pub struct DeletedDownloadsModel {
+    /// Number of files deleted and untracked, including ones that were already missing.
+    pub deleted_files: u32,
+    /// Recorded size of the deleted files.
+    pub deleted_bytes: u64,
+    /// Number of files that couldn't be deleted, which are still tracked.
+    pub failed_files: u32,
+}
+
+/// Model of a downloaded file that reorganizing moved, or would move in a dry run.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct MovedDownloadModel {
+    pub endpoint_id: String,
+    pub root: String,
+    pub path: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// Model of a downloaded file that reorganizing couldn't move, which was left where it is.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct ReorganizeConflictModel {
+    pub endpoint_id: String,
+    pub root: String,
+    pub path: String,
+    pub local_path: String,
+    /// The path the file would have been moved to.
+    pub target: String,
+    pub reason: ReorganizeConflictReasonModel,
+}
+
+/// Model of why a downloaded file couldn't be moved.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Enum)]
+pub enum ReorganizeConflictReasonModel {
+    /// A file that wasn't moved there by reorganizing already exists at the new path.
+    Exists,
+    /// Another downloaded file is moved to the same path.
+    Duplicate,
+    /// Moving the file failed.
+    Failed { error: String },
+}
+
+/// Result of reorganizing downloaded files.
+#[derive(Debug, Clone, Default, PartialEq, Eq, uniffi::Record)]
+pub struct ReorganizedDownloadsModel {
+    pub moved: Vec<MovedDownloadModel>,
+    pub conflicts: Vec<ReorganizeConflictModel>,
+}
+
+/// How an existing local file was matched to a file in a server's index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum ImportMatchModel {
+    /// The audio content hashes to the same hash as the original on the server.
+    ContentHash,
+    /// The file has the same extension and size as the original on the server, and no other file
+    /// in the index does. Used where files can't be hashed, like on mobile.
+    FileSize,
+}
+
+/// Model of an existing local file that was matched to a file in a server's index.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct ImportedDownloadModel {
+    /// The root and path of the file on the server.
+    pub root: String,
+    pub path: String,
+    /// Path of the local file in the download directory.
+    pub local_path: String,
+    pub matched_by: ImportMatchModel,
+}
+
+/// Result of importing existing local files as downloads.
+#[derive(Debug, Clone, Default, PartialEq, Eq, uniffi::Record)]
+pub struct ImportedDownloadsModel {
+    pub imported: Vec<ImportedDownloadModel>,
+    /// Number of untracked local files that didn't match any file in the index.
+    pub unmatched_files: u32,
+}
+
+/// A file in a server's index that isn't downloaded yet, which a local file can be matched to.
+pub(crate) struct ImportCandidate {
+    pub root: String,
+    pub path: String,
+    /// Exact size of the original file, if the server knows it.
+    pub file_size: Option<u64>,
+    pub content_hash: Option<ContentHash>,
+    pub metadata: Option<ItemMetadata>,
+}
+
+/// Lists the servers that files were downloaded from, with their recorded sizes.
+pub(crate) fn list_servers(
+    db: &DatabasePool,
+    local_node_id: EndpointId,
+) -> anyhow::Result<Vec<DownloadedServerModel>> {
+    let files = {
+        let db = db.get();
+        db.get_downloaded_files(local_node_id)
+            .context("failed to get downloaded files")?
+    };
+
+    let mut servers = BTreeMap::new();
+    for (node_id, file) in files {
+        let server = servers
+            .entry(node_id.to_string())
+            .or_insert_with_key(|endpoint_id| DownloadedServerModel {
+                endpoint_id: endpoint_id.clone(),
+                files: 0,
+                bytes: 0,
+                missing_files: None,
+            });
+        server.files += 1;
+        server.bytes += file
+            .manifest
+            .as_ref()
+            .map_or(0, |manifest| manifest.file_size);
+    }
+
+    Ok(servers.into_values().collect())
+}
+
+/// Lists the files downloaded from a server, sorted by their local path.
+pub(crate) fn list_files(
+    db: &DatabasePool,
+    local_node_id: EndpointId,
+    node_id: EndpointId,
+) -> anyhow::Result<Vec<DownloadedFileModel>> {
+    let mut files = {
+        let db = db.get();
+        db.get_downloaded_files_by_node(local_node_id, node_id)
+            .context("failed to get downloaded files")?
+    };
+    files.sort_by(|a, b| (&a.local_tree, &a.local_path).cmp(&(&b.local_tree, &b.local_path)));
+
+    Ok(files.into_iter().map(DownloadedFileModel::from).collect())
+}
+
+/// Deletes the selected files downloaded from a server and untracks them, so they're downloaded
+/// again if requested. Files that fail to delete are skipped and stay tracked.
+pub(crate) async fn delete(
+    db: &DatabasePool,
+    local_node_id: EndpointId,
+    node_id: EndpointId,
+    selections: &[DownloadedFileSelectionModel],
+) -> anyhow::Result<DeletedDownloadsModel> {
+    let files = {
+        let db = db.get();
+        db.get_downloaded_files_by_node(local_node_id, node_id)
+            .context("failed to get downloaded files")?
+    }
+    .into_iter()
+    .filter(|file| selections.iter().any(|selection| selection.matches(file)))
+    .collect::<Vec<_>>();
+
+    let mut result = DeletedDownloadsModel::default();
+    let mut deleted = Vec::new();
+    for file in files {
+        let local_path = TreePath::new(file.local_tree.clone(), file.local_path.clone().into())?;
+        if local_path.exists()
+            && let Err(e) = crate::fs::remove_file(&local_path).await
+        {
+            warn!("failed to delete {}: {e:#}", file.local_path);
+            result.failed_files += 1;
+            continue;
+        }
+
+        debug!("deleted downloaded file {}", file.local_path);
+        result.deleted_files += 1;
+        result.deleted_bytes += file
+            .manifest
+            .as_ref()
+            .map_or(0, |manifest| manifest.file_size);
+        deleted.push((file.local_tree, file.local_path));
+    }
+
+    let db = db.get();
+    db.remove_files_by_local_treepath(deleted.into_iter())?;
+
+    Ok(result)
+}
+
+/// Measures the files downloaded from each server on disk, counting the ones that no longer exist.
+pub(crate) async fn measure(
+    db: &DatabasePool,
+    local_node_id: EndpointId,
+) -> anyhow::Result<Vec<DownloadedServerModel>> {
+    let files = {
+        let db = db.get();
+        db.get_downloaded_files(local_node_id)
+            .context("failed to get downloaded files")?
+    };
+
+    let mut servers = BTreeMap::new();
+    for (node_id, file) in files {
+        let server = servers
+            .entry(node_id.to_string())
+            .or_insert_with_key(|endpoint_id| DownloadedServerModel {
+                endpoint_id: endpoint_id.clone(),
+                files: 0,
+                bytes: 0,
+                missing_files: Some(0),
+            });
+        server.files += 1;
+
+        let local_path = TreePath::new(file.local_tree, file.local_path.into())?;
+        match crate::fs::metadata(&local_path).await {
+            Ok(metadata) => server.bytes += metadata.len,
+            Err(_) if !local_path.exists() => {
+                *server.missing_files.get_or_insert(0) += 1;
+            }
+            Err(e) => warn!("failed to measure {:?}: {e:#}", local_path.path()),
+        }
+    }
+
+    Ok(servers.into_values().collect())
+}
+
+/// Moves the files downloaded to the download directory to the paths that the template gives
+/// them, or to the server's folder structure without one, and updates their records and
+/// playlists. Files whose new path is taken are reported as conflicts and left where they are. In
+/// a dry run, nothing is moved.
+///
+/// Files are laid out by the tags of connected servers' indexes if available, since only they
+/// have track numbers, and by the tags recorded when they were downloaded otherwise.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn reorganize(
+    db: &DatabasePool,
+    local_node_id: EndpointId,
+    download_directory: &str,
+    template: Option<&PathTemplate>,
+    index_metadata: &HashMap<(EndpointId, String, String), ItemMetadata>,
+    index_extra_metadata: &HashMap<(EndpointId, String, String), ItemExtraMetadata>,
+    playlists: PlaylistSettings,
+    dry_run: bool,
+) -> anyhow::Result<ReorganizedDownloadsModel> {
+    let files = {
+        let db = db.get();
+        db.get_downloaded_files(local_node_id)
+            .context("failed to get downloaded files")?
+    }
+    .into_iter()
+    .filter(|(_, file)| file.local_tree == download_directory)
+    .collect::<Vec<_>>();
+
+    let mut result = ReorganizedDownloadsModel::default();
+    let mut targets = HashSet::new();
+    let mut moves: BTreeMap<EndpointId, Vec<(String, String)>> = BTreeMap::new();
+    let mut emptied_dirs = BTreeSet::new();
+    for (node_id, file) in files {
+        let key = (node_id, file.root.clone(), file.path.clone());
+        let metadata = index_metadata.get(&key).cloned().or_else(|| {
+            file.manifest.as_ref().map(|manifest| ItemMetadata {
+                title: manifest.title.clone(),
+                artist: manifest.artist.clone(),
+                album: manifest.album.clone(),
+                ..Default::default()
+            })
+        });
+        let extra_metadata = index_extra_metadata.get(&key);
+
+        let from = TreePath::new(file.local_tree.clone(), file.local_path.clone().into())?;
+        // keep the extension the file was downloaded with, e.g. of a transcode
+        let extension = from.extension().map(|extension| extension.into_owned());
+        let (to, _) = {
+            let db = db.get();
+            build_download_path(
+                &db,
+                download_directory.to_string(),
+                template,
+                node_id,
+                TemplateValues {
+                    root: &file.root,
+                    path: &file.path,
+                    metadata: metadata.as_ref(),
+                    extra_metadata,
+                },
+                extension.as_deref(),
+            )?
+        };
+        let target = to.path().into_owned();
+        if target == file.local_path {
+            continue;
+        }
+
+        let reason = if !targets.insert(target.clone()) {
+            Some(ReorganizeConflictReasonModel::Duplicate)
+        } else if to.exists() {
+            Some(ReorganizeConflictReasonModel::Exists)
+        } else if dry_run {
+            None
+        } else {
+            move_file(&from, &to)
+                .await
+                .err()
+                .map(|e| ReorganizeConflictReasonModel::Failed {
+                    error: format!("{e:#}"),
+                })
+        };
+        if let Some(reason) = reason {
+            debug!(
+                "can't move downloaded file {} to {target}: {reason:?}",
+                file.local_path
+            );
+            result.conflicts.push(ReorganizeConflictModel {
+                endpoint_id: node_id.to_string(),
+                root: file.root,
+                path: file.path,
+                local_path: file.local_path,
+                target,
+                reason,
+            });
+            continue;
+        }
+
+        if !dry_run {
+            debug!("moved downloaded file {} to {target}", file.local_path);
+            {
+                let db = db.get();
+                db.set_file_local_path(download_directory, &file.local_path, &target)?;
+            }
+            if let Some(parent) = from.parent() {
+                emptied_dirs.insert(parent.path().into_owned());
+            }
+            moves
+                .entry(node_id)
+                .or_default()
+                .push((file.local_path.clone(), target.clone()));
+        }
+        result.moved.push(MovedDownloadModel {
+            endpoint_id: node_id.to_string(),
+            root: file.root,
+            path: file.path,
+            from: file.local_path,
+            to: target,
+        });
+    }
+
+    for (node_id, moves) in moves {
+        if let Err(e) =
+            playlist::update_moved(db, node_id, download_directory, playlists, &moves).await
+        {
+            warn!("failed to update playlists: {e:#}");
+        }
+    }
+
+    // remove the directories that are left empty, deepest first
+    for dir in emptied_dirs.iter().rev() {
+        let dir = TreePath::new(download_directory.to_string(), dir.into())?;
+        remove_empty_dirs(dir).await;
+    }
+
+    Ok(result)
+}
+
+/// Moves a file, creating the directories of its new path.
+async fn move_file(from: &TreePath, to: &TreePath) -> anyhow::Result<()> {
+    if let Some(parent) = to.parent()
+        && !parent.is_empty()
+    {
+        crate::fs::create_dir_all(&parent)
+            .await
+            .context("failed to create directory")?;
+    }
+    crate::fs::rename(from, to)
+        .await
+        .context("failed to move file")
+}
+
+/// Removes a directory and its parents in the download directory, as long as they're empty.
+async fn remove_empty_dirs(mut dir: TreePath) {
+    while !dir.is_empty() && dir.exists() {
+        // fails if anything is left in the directory, like files that weren't downloaded
+        if let Err(e) = crate::fs::remove_dir(&dir).await {
+            debug!("not removing directory {:?}: {e:#}", dir.path());
+            break;
+        }
+        debug!("removed empty directory {:?}", dir.path());
+
+        let Some(parent) = dir.parent() else {
+            break;
+        };
+        dir = parent;
+    }
+}
+
+/// Matches the untracked files in a folder of the download directory to the files in a server's
+/// index that aren't downloaded yet, and tracks the matches as downloaded from the server so
+/// they're skipped by syncs. In a dry run, nothing is tracked.
+///
+/// Files are matched by their audio content hashes where they can be hashed, and by their
+/// extensions and sizes otherwise. Matching by size only works for files that weren't transcoded,
+/// so only candidates with exact sizes are considered.
+pub(crate) async fn import(
+    db: &DatabasePool,
+    hash_cache: &HashCache,
+    local_node_id: EndpointId,
+    node_id: EndpointId,
+    folder: &TreePath,
+    candidates: Vec<ImportCandidate>,
+    dry_run: bool,
+) -> anyhow::Result<ImportedDownloadsModel> {
+    // files that are already tracked, e.g. downloaded from another server
+    let tracked = {
+        let db = db.get();
+        db.get_downloaded_files(local_node_id)
+            .context("failed to get downloaded files")?
+    }
+    .into_iter()
+    .filter(|(_, file)| file.local_tree == folder.root())
+    .map(|(_, file)| file.local_path)
+    .collect::<HashSet<_>>();
+
+    // skip hidden files like partial downloads
+    let files = crate::fs::walk_files(folder)
+        .await
+        .context("failed to list files")?
+        .into_iter()
+        .filter(|file| {
+            !file
+                .file_name()
+                .is_some_and(|file_name| file_name.starts_with('.'))
+                && !tracked.contains(file.path().as_ref())
+        })
+        .collect::<Vec<_>>();
+
+    let mut by_hash = HashMap::new();
+    let mut by_size: HashMap<(u64, String), Vec<usize>> = HashMap::new();
+    for (i, candidate) in candidates.iter().enumerate() {
+        if let Some(content_hash) = &candidate.content_hash {
+            by_hash.insert((content_hash.kind.as_str(), content_hash.hash), i);
+        }
+        if let Some(file_size) = candidate.file_size {
+            let extension = extension_of(&candidate.path);
+            by_size.entry((file_size, extension)).or_default().push(i);
+        }
+    }
+
+    let hashes = {
+        let hash_cache = hash_cache.clone();
+        let files = files.clone();
+        tokio::task::spawn_blocking(move || {
+            files
+                .iter()
+                .map(|file| local_content_hash(&hash_cache, file))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .context("failed to hash files")?
+    };
+
+    let mut result = ImportedDownloadsModel::default();
+    let mut matched = HashSet::new();
+    for (file, hash) in files.into_iter().zip(hashes) {
+        let by_content = hash
+            .as_ref()
+            .and_then(|(kind, hash)| by_hash.get(&(kind.as_str(), *hash)))
+            .map(|i| (*i, ImportMatchModel::ContentHash));
+        let file_size = match crate::fs::metadata(&file).await {
+            Ok(metadata) => metadata.len,
+            Err(e) => {
+                warn!("failed to read metadata of {:?}: {e:#}", file.path());
+                continue;
+            }
+        };
+        let by_file_size = || {
+            let extension = extension_of(&file.path());
+            match by_size.get(&(file_size, extension)).map(Vec::as_slice) {
+                Some([i]) => Some((*i, ImportMatchModel::FileSize)),
+                _ => None,
+            }
+        };
+        let Some((i, matched_by)) = by_content
+            .or_else(by_file_size)
+            .filter(|(i, _)| !matched.contains(i))
+        else {
+            result.unmatched_files += 1;
+            continue;
+        };
+        matched.insert(i);
+
+        let candidate = &candidates[i];
+        let local_path = file.path().into_owned();
+        if !dry_run {
+            let checksum = file_checksum(&file)
+                .await
+                .with_context(|| format!("failed to read {local_path}"))?;
+            let metadata = candidate.metadata.clone().unwrap_or_default();
+            let manifest = FileManifest {
+                file_size,
+                checksum,
+                title: metadata.title,
+                artist: metadata.artist,
+                album: metadata.album,
+            };
+
+            let mut db = db.get();
+            db.insert_remote_file(
+                node_id,
+                InsertFile {
+                    root: &candidate.root,
+                    path: &candidate.path,
+                    local_tree: folder.root(),
+                    local_path: &local_path,
+                },
+                candidate
+                    .content_hash
+                    .as_ref()
+                    .map(|content_hash| (content_hash.kind.as_str(), content_hash.hash)),
+                Some(&manifest),
+            )
+            .context("failed to insert remote file in database")?;
+        }
+
+        debug!(
+            "matched {local_path} to {}/{} by {matched_by:?}",
+            candidate.root, candidate.path
+        );
+        result.imported.push(ImportedDownloadModel {
+            root: candidate.root.clone(),
+            path: candidate.path.clone(),
+            local_path,
+            matched_by,
+        });
+    }
+
+    info!(
+        "imported {} existing files, {} didn't match",
+        result.imported.len(),
+        result.unmatched_files
+    );
+
+    Ok(result)
+}
+
+/// Gets the lowercase extension of a path, or an empty string if it has none.
+fn extension_of(path: &str) -> String {
+    std::path::Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Gets the content hash of a local file, or None if it can't be hashed, e.g. because it isn't an
+/// audio file or files can't be hashed on this platform.
+fn local_content_hash(hash_cache: &HashCache, file: &TreePath) -> Option<(String, [u8; 16])> {
+    #[cfg(target_os = "android")]
+    {
+        let _ = (hash_cache, file);
+        None
+    }
+
+    #[cfg(not(target_os = "android"))]
+    {
+        if crate::fs::is_document_tree(file.root()) {
+            return None;
+        }
+        match hash_cache.get_hash(&file.resolve_path()) {
+            Ok((kind, hash)) => Some((kind.into_owned(), hash)),
+            Err(e) => {
+                debug!("failed to hash {:?}: {e:#}", file.path());
+                None
+            }
+        }
+    }
+}
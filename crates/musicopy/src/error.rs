@@ -10,6 +10,39 @@ impl CoreError {
     fn message(&self) -> String {
         self.to_string()
     }
+
+    /// What kind of error this is, so the UI can show a localized message or offer a fix.
+    pub fn kind(&self) -> CoreErrorKind {
+        if let Some(error) = self.e.downcast_ref::<ConnectionError>() {
+            CoreErrorKind::Connection {
+                error: error.clone(),
+            }
+        } else if let Some(error) = self.e.downcast_ref::<LibraryError>() {
+            CoreErrorKind::Library {
+                error: error.clone(),
+            }
+        } else if let Some(error) = self.e.downcast_ref::<TransferError>() {
+            CoreErrorKind::Transfer {
+                error: error.clone(),
+            }
+        } else if let Some(error) = self.e.downcast_ref::<ProfileError>() {
+            CoreErrorKind::Profile {
+                error: error.clone(),
+            }
+        } else if let Some(error) = self.e.downcast_ref::<FsError>() {
+            CoreErrorKind::Fs {
+                error: error.clone(),
+            }
+        } else if let Some(error) = self
+            .e
+            .downcast_ref::<std::io::Error>()
+            .and_then(FsError::from_io)
+        {
+            CoreErrorKind::Fs { error }
+        } else {
+            CoreErrorKind::Other
+        }
+    }
 }
 
 impl From<anyhow::Error> for CoreError {
@@ -18,6 +51,133 @@ impl From<anyhow::Error> for CoreError {
     }
 }
 
+/// The kind of a [`CoreError`]. Errors are found by their domain anywhere in the error's context,
+/// so a failure keeps its kind when more context is added to it.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Enum)]
+pub enum CoreErrorKind {
+    Connection {
+        error: ConnectionError,
+    },
+    Library {
+        error: LibraryError,
+    },
+    Transfer {
+        error: TransferError,
+    },
+    Fs {
+        error: FsError,
+    },
+    Profile {
+        error: ProfileError,
+    },
+    /// An unexpected error, which only has a message.
+    Other,
+}
+
+/// Errors connecting to other nodes.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, uniffi::Enum)]
+pub enum ConnectionError {
+    #[error("invalid endpoint id")]
+    InvalidEndpointId,
+    #[error("invalid pairing ticket")]
+    InvalidPairingTicket,
+    /// The link isn't a deep link or share link that the app can handle.
+    #[error("invalid link")]
+    InvalidLink,
+    /// The node couldn't be reached, e.g. because it's offline.
+    #[error("failed to reach node")]
+    Unreachable,
+    #[error("connection timed out")]
+    TimedOut,
+    /// The device has no network.
+    #[error("device is offline")]
+    Offline,
+    /// Connecting on metered networks is turned off in the settings.
+    #[error("connecting on metered networks is disabled")]
+    Metered,
+    /// The command needs a connection to the node, which isn't open.
+    #[error("not connected to {endpoint_id}")]
+    NotConnected { endpoint_id: String },
+}
+
+/// Errors with the local library.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, uniffi::Enum)]
+pub enum LibraryError {
+    #[error("library root path not found: {path}")]
+    RootPathNotFound { path: String },
+    #[error("invalid transcode format: {format}")]
+    InvalidTranscodeFormat { format: String },
+    /// Transcoding ahead of time was enabled without choosing a format.
+    #[error("transcoding ahead of time requires a transcode format")]
+    TranscodeFormatRequired,
+}
+
+/// Errors with downloads.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, uniffi::Enum)]
+pub enum TransferError {
+    /// The server hasn't sent its index yet.
+    #[error("no index available from {endpoint_id}")]
+    IndexUnavailable { endpoint_id: String },
+    #[error("download directory not set")]
+    DownloadDirectoryNotSet,
+    #[error("invalid download path template")]
+    InvalidPathTemplate,
+}
+
+/// Errors with profiles.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, uniffi::Enum)]
+pub enum ProfileError {
+    /// The name can't be used as a directory name, e.g. because it contains a slash.
+    #[error("invalid profile name: {name}")]
+    InvalidName { name: String },
+}
+
+/// Errors accessing files.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, uniffi::Enum)]
+pub enum FsError {
+    /// The app doesn't have permission, e.g. to a folder that has to be picked again.
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("file not found")]
+    NotFound,
+    #[error("storage is full")]
+    StorageFull,
+    #[error("storage is read-only")]
+    ReadOnly,
+}
+
+impl FsError {
+    /// Gets the kind of an IO error, or None if it's not one the UI can act on.
+    fn from_io(e: &std::io::Error) -> Option<Self> {
+        match e.kind() {
+            std::io::ErrorKind::PermissionDenied => Some(FsError::PermissionDenied),
+            std::io::ErrorKind::NotFound => Some(FsError::NotFound),
+            std::io::ErrorKind::StorageFull => Some(FsError::StorageFull),
+            std::io::ErrorKind::ReadOnlyFilesystem => Some(FsError::ReadOnly),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! impl_from_domain_error {
+    ($($error:ty),*) => {
+        $(
+            impl From<$error> for CoreError {
+                fn from(e: $error) -> Self {
+                    Self { e: e.into() }
+                }
+            }
+        )*
+    };
+}
+impl_from_domain_error!(
+    ConnectionError,
+    LibraryError,
+    TransferError,
+    FsError,
+    ProfileError
+);
+
 /// Creates a CoreError by wrapping anyhow::anyhow!.
 macro_rules! core_error {
     ($msg:literal $(,)?) => {
@@ -31,3 +191,54 @@ macro_rules! core_error {
     };
 }
 pub(crate) use core_error;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_error_kind() {
+        // kinds are found under added context
+        let e = CoreError::from(
+            Err::<(), _>(ConnectionError::TimedOut)
+                .context("failed to connect")
+                .unwrap_err(),
+        );
+        assert_eq!(
+            e.kind(),
+            CoreErrorKind::Connection {
+                error: ConnectionError::TimedOut
+            }
+        );
+
+        // context can set the kind of an underlying error
+        let e = CoreError::from(
+            "nope"
+                .parse::<u32>()
+                .context(LibraryError::TranscodeFormatRequired)
+                .unwrap_err(),
+        );
+        assert_eq!(
+            e.kind(),
+            CoreErrorKind::Library {
+                error: LibraryError::TranscodeFormatRequired
+            }
+        );
+
+        // IO errors are mapped to file system errors
+        let e = CoreError::from(
+            std::fs::read("/nonexistent/musicopy")
+                .context("failed to read file")
+                .unwrap_err(),
+        );
+        assert_eq!(
+            e.kind(),
+            CoreErrorKind::Fs {
+                error: FsError::NotFound
+            }
+        );
+
+        assert_eq!(core_error!("something broke").kind(), CoreErrorKind::Other);
+    }
+}
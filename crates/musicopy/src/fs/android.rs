@@ -12,7 +12,7 @@
 //! it in a std or tokio File and use that. We don't run Drop for the File since we're only using
 //! it to wrap the file descriptor, which we manually close using the ParcelFileDescriptor via JNI.
 
-use crate::fs::{OpenMode, TreePath};
+use crate::fs::{Metadata, OpenMode, TreePath};
 use anyhow::Context;
 use jni::{
     JNIEnv, JavaVM,
@@ -20,12 +20,29 @@ use jni::{
     strings::JNIString,
     sys::{jint, jsize},
 };
-use std::{borrow::Cow, collections::HashMap, mem::ManuallyDrop, ops::Deref, os::fd::FromRawFd};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    mem::ManuallyDrop,
+    ops::Deref,
+    os::fd::FromRawFd,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
 use tokio::fs::File as TokioFile;
 use tracing::error;
 
 const MIME_TYPE_DIR: &str = "vnd.android.document/directory";
 
+/// Authority of the document provider for shared and removable storage.
+const EXTERNAL_STORAGE_AUTHORITY: &str = "com.android.externalstorage.documents";
+
+/// Volume name used in document IDs from the external storage provider for shared storage.
+const EXTERNAL_STORAGE_PRIMARY_VOLUME: &str = "primary";
+
+/// Maximum size of each blocking read or write on an opened document.
+const FILE_BUFFER_SIZE: usize = 1024 * 1024;
+
 /// Recursively resolve or create a series of directories,
 pub fn create_dir_all(path: &TreePath) -> anyhow::Result<String> {
     if path.is_empty() {
@@ -83,6 +100,321 @@ pub fn exists(path: &TreePath) -> anyhow::Result<bool> {
     Ok(child_uri.is_some())
 }
 
+/// Delete the document at the given path.
+///
+/// Directories are deleted recursively by the document provider.
+pub fn remove(path: &TreePath) -> anyhow::Result<()> {
+    if path.is_empty() {
+        anyhow::bail!("path is empty");
+    }
+
+    let vm = get_vm();
+    let mut env = vm.attach_current_thread()?;
+
+    let mut segments = path
+        .path
+        .components()
+        .map(|s| s.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>();
+
+    let Some(filename) = segments.pop() else {
+        anyhow::bail!("path is empty");
+    };
+
+    let tree_uri = Uri::parse(&mut env, &path.tree).context("Uri::parse failed")?;
+    let parent_uri = resolve_dirs(&mut env, &tree_uri, segments, false)
+        .context("failed to resolve parent directories")?;
+
+    let content_resolver = ContentResolver::get(&mut env).context("ContentResolver::get failed")?;
+
+    let Some(document_uri) = content_resolver
+        .find_child(&mut env, &tree_uri, &parent_uri, &filename)
+        .context("ContentResolver::find_child failed")?
+    else {
+        anyhow::bail!("file not found: {:?}", path);
+    };
+
+    DocumentsContract::jni_delete_document(&mut env, &content_resolver, &document_uri)
+        .context("jni_delete_document failed")?;
+
+    Ok(())
+}
+
+/// Move and/or rename the document at `from` to `to`.
+///
+/// Both paths must be in the same tree, and the parent directory of `to` must already exist. If a
+/// document already exists at `to`, it is replaced.
+pub fn rename(from: &TreePath, to: &TreePath) -> anyhow::Result<()> {
+    if from.is_empty() || to.is_empty() {
+        anyhow::bail!("path is empty");
+    }
+    if from.tree != to.tree {
+        anyhow::bail!("can't move documents between trees");
+    }
+
+    let vm = get_vm();
+    let mut env = vm.attach_current_thread()?;
+
+    let mut from_segments = from
+        .path
+        .components()
+        .map(|s| s.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>();
+    let Some(from_filename) = from_segments.pop() else {
+        anyhow::bail!("path is empty");
+    };
+
+    let mut to_segments = to
+        .path
+        .components()
+        .map(|s| s.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>();
+    let Some(to_filename) = to_segments.pop() else {
+        anyhow::bail!("path is empty");
+    };
+
+    let same_parent = from_segments == to_segments;
+
+    let tree_uri = Uri::parse(&mut env, &from.tree).context("Uri::parse failed")?;
+    let from_parent_uri = resolve_dirs(&mut env, &tree_uri, from_segments, false)
+        .context("failed to resolve source parent directories")?;
+    let to_parent_uri = resolve_dirs(&mut env, &tree_uri, to_segments, false)
+        .context("failed to resolve destination parent directories")?;
+
+    let content_resolver = ContentResolver::get(&mut env).context("ContentResolver::get failed")?;
+
+    let Some(mut document_uri) = content_resolver
+        .find_child(&mut env, &tree_uri, &from_parent_uri, &from_filename)
+        .context("ContentResolver::find_child failed")?
+    else {
+        anyhow::bail!("file not found: {:?}", from);
+    };
+
+    // replace the destination if it exists, matching std::fs::rename
+    if let Some(existing_uri) = content_resolver
+        .find_child(&mut env, &tree_uri, &to_parent_uri, &to_filename)
+        .context("ContentResolver::find_child failed")?
+    {
+        if same_parent && from_filename == to_filename {
+            // renaming to itself is a no-op
+            return Ok(());
+        }
+
+        DocumentsContract::jni_delete_document(&mut env, &content_resolver, &existing_uri)
+            .context("failed to delete existing destination document")?;
+    }
+
+    if !same_parent {
+        document_uri = DocumentsContract::jni_move_document(
+            &mut env,
+            &content_resolver,
+            &document_uri,
+            &from_parent_uri,
+            &to_parent_uri,
+        )
+        .context("jni_move_document failed")?;
+    }
+
+    if from_filename != to_filename {
+        let display_name = env.new_string(to_filename)?;
+        DocumentsContract::jni_rename_document(
+            &mut env,
+            &content_resolver,
+            &document_uri,
+            &display_name,
+        )
+        .context("jni_rename_document failed")?;
+    }
+
+    Ok(())
+}
+
+/// Get the size and last modified time of the document at the given path.
+pub fn metadata(path: &TreePath) -> anyhow::Result<Metadata> {
+    let vm = get_vm();
+    let mut env = vm.attach_current_thread()?;
+
+    let segments = path
+        .path
+        .components()
+        .map(|s| s.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>();
+
+    let tree_uri = Uri::parse(&mut env, &path.tree).context("Uri::parse failed")?;
+    let document_uri =
+        resolve_dirs(&mut env, &tree_uri, segments, false).context("failed to resolve document")?;
+
+    let content_resolver = ContentResolver::get(&mut env).context("ContentResolver::get failed")?;
+
+    let rows = content_resolver.query(
+        &mut env,
+        &document_uri,
+        &[
+            DocumentsContract::COLUMN_SIZE,
+            DocumentsContract::COLUMN_LAST_MODIFIED,
+        ],
+    )?;
+    let Some(row) = rows.first() else {
+        anyhow::bail!("no rows returned for document: {:?}", path);
+    };
+
+    let len = match row.size()? {
+        Some(size) => {
+            let size: String = env.get_string(size.into())?.into();
+            size.parse::<u64>()
+                .context("failed to parse document size")?
+        }
+        None => 0,
+    };
+
+    let modified = match row.last_modified()? {
+        Some(last_modified) => {
+            let last_modified: String = env.get_string(last_modified.into())?.into();
+            last_modified
+                .parse::<u64>()
+                .ok()
+                .map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+        }
+        None => None,
+    };
+
+    Ok(Metadata { len, modified })
+}
+
+/// Recursively list the files under the given path.
+///
+/// Returns the paths of the files relative to the tree, including the given path.
+pub fn walk_files(path: &TreePath) -> anyhow::Result<Vec<PathBuf>> {
+    let vm = get_vm();
+    let mut env = vm.attach_current_thread()?;
+
+    let segments = path
+        .path
+        .components()
+        .map(|s| s.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>();
+
+    let tree_uri = Uri::parse(&mut env, &path.tree).context("Uri::parse failed")?;
+    let start_uri = resolve_dirs(&mut env, &tree_uri, segments, false)
+        .context("failed to resolve start directory")?;
+
+    let content_resolver = ContentResolver::get(&mut env).context("ContentResolver::get failed")?;
+
+    let start_document_id = DocumentsContract::jni_get_document_id(&mut env, &start_uri)?;
+    let start_document_id: String = env.get_string((&*start_document_id).into())?.into();
+
+    let mut files = Vec::new();
+    let mut stack = vec![(path.path.clone(), start_document_id)];
+
+    while let Some((dir_path, dir_document_id)) = stack.pop() {
+        // query each directory in its own local frame, so large trees don't exhaust local refs
+        let children = env.with_local_frame(
+            64,
+            |env| -> anyhow::Result<Vec<(String, String, Option<String>)>> {
+                let dir_document_id = DocumentId::Owned(env.new_string(&dir_document_id)?.into());
+                let children_uri = DocumentsContract::jni_build_child_documents_uri_using_tree(
+                    env,
+                    &tree_uri,
+                    &dir_document_id,
+                )?;
+
+                let mut children = Vec::new();
+                for row in content_resolver.query(
+                    env,
+                    &children_uri,
+                    &[
+                        DocumentsContract::COLUMN_DOCUMENT_ID,
+                        DocumentsContract::COLUMN_DISPLAY_NAME,
+                        DocumentsContract::COLUMN_MIME_TYPE,
+                    ],
+                )? {
+                    let (Some(child_document_id), Some(child_display_name)) =
+                        (row.document_id()?, row.display_name()?)
+                    else {
+                        continue;
+                    };
+
+                    let child_document_id: String =
+                        env.get_string((&*child_document_id).into())?.into();
+                    let child_display_name: String =
+                        env.get_string(child_display_name.into())?.into();
+                    let child_mime_type: Option<String> = match row.mime_type()? {
+                        Some(mime_type) => Some(env.get_string(mime_type.into())?.into()),
+                        None => None,
+                    };
+
+                    children.push((child_document_id, child_display_name, child_mime_type));
+                }
+
+                Ok(children)
+            },
+        )?;
+
+        for (child_document_id, child_display_name, child_mime_type) in children {
+            let child_path = dir_path.join(child_display_name);
+            if child_mime_type.as_deref() == Some(MIME_TYPE_DIR) {
+                stack.push((child_path, child_document_id));
+            } else {
+                files.push(child_path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Get the number of bytes available on the storage volume containing the tree.
+///
+/// Only trees from the external storage provider are backed by a known volume, so this returns
+/// None for trees from other document providers.
+pub fn available_space(path: &TreePath) -> anyhow::Result<Option<u64>> {
+    let vm = get_vm();
+    let mut env = vm.attach_current_thread()?;
+
+    let tree_uri = Uri::parse(&mut env, &path.tree).context("Uri::parse failed")?;
+
+    let Some(authority) = tree_uri
+        .jni_get_authority(&mut env)
+        .context("Uri::jni_get_authority failed")?
+    else {
+        return Ok(None);
+    };
+    if authority != EXTERNAL_STORAGE_AUTHORITY {
+        return Ok(None);
+    }
+
+    // document IDs from the external storage provider look like "<volume>:<path>", where the
+    // volume is either "primary" or the filesystem UUID of removable storage
+    let tree_document_id = DocumentsContract::jni_get_tree_document_id(&mut env, &tree_uri)
+        .context("jni_get_tree_document_id failed")?;
+    let tree_document_id: String = env.get_string((&*tree_document_id).into())?.into();
+    let Some((volume, _)) = tree_document_id.split_once(':') else {
+        return Ok(None);
+    };
+
+    if volume == EXTERNAL_STORAGE_PRIMARY_VOLUME {
+        let storage_manager =
+            StorageManager::get(&mut env).context("StorageManager::get failed")?;
+        let uuid = StorageManager::jni_uuid_default(&mut env)?;
+        match storage_manager.jni_get_allocatable_bytes(&mut env, &uuid) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) => {
+                // getAllocatableBytes throws IOException if the volume can't be found, which
+                // must be cleared before the env is used again
+                if env.exception_check()? {
+                    env.exception_clear()?;
+                }
+                error!("fs: failed to get allocatable bytes: {e:#}");
+                Ok(None)
+            }
+        }
+    } else {
+        // removable volumes are mounted at /storage/<uuid>, which can be queried directly
+        let volume_path = PathBuf::from("/storage").join(volume);
+        Ok(Some(super::available_space_at(&volume_path)?))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AccessMode {
     /// Attempt to open the file, failing if it doesn't exist.
@@ -184,6 +516,8 @@ impl FileHandle {
         let mode_str = match mode {
             OpenMode::Read => "r",
             OpenMode::Write => "wt",
+            OpenMode::Append => "wa",
+            OpenMode::Update => "rw",
         };
         let mode_string = env.new_string(mode_str).context("new_string failed")?;
 
@@ -192,7 +526,10 @@ impl FileHandle {
 
         let fd = env.call_method(&parcel, "getFd", "()I", &[])?.i()?;
 
-        let file = unsafe { TokioFile::from_raw_fd(fd) };
+        let mut file = unsafe { TokioFile::from_raw_fd(fd) };
+        // use larger chunks for each blocking read/write to reduce the number of round trips
+        // through the blocking pool
+        file.set_max_buf_size(FILE_BUFFER_SIZE);
 
         Ok(Self {
             parcel,
@@ -528,6 +865,30 @@ impl<'local> Row<'local> {
             .map(|v| v.borrow().l())
             .transpose()?)
     }
+
+    fn mime_type(&'local self) -> anyhow::Result<Option<&'local JObject<'local>>> {
+        Ok(self
+            .0
+            .get(DocumentsContract::COLUMN_MIME_TYPE)
+            .map(|v| v.borrow().l())
+            .transpose()?)
+    }
+
+    fn size(&'local self) -> anyhow::Result<Option<&'local JObject<'local>>> {
+        Ok(self
+            .0
+            .get(DocumentsContract::COLUMN_SIZE)
+            .map(|v| v.borrow().l())
+            .transpose()?)
+    }
+
+    fn last_modified(&'local self) -> anyhow::Result<Option<&'local JObject<'local>>> {
+        Ok(self
+            .0
+            .get(DocumentsContract::COLUMN_LAST_MODIFIED)
+            .map(|v| v.borrow().l())
+            .transpose()?)
+    }
 }
 
 /// Newtype for Cursor JObjects.
@@ -633,6 +994,20 @@ impl<'local> Uri<'local> {
         Ok(Self(uri))
     }
 
+    /// https://developer.android.com/reference/android/net/Uri#getAuthority()
+    fn jni_get_authority<'other_local>(
+        &self,
+        env: &mut JNIEnv<'other_local>,
+    ) -> anyhow::Result<Option<String>> {
+        let string = env
+            .call_method(&self.0, "getAuthority", "()Ljava/lang/String;", &[])?
+            .l()?;
+        if string.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(env.get_string(&string.into())?.into()))
+    }
+
     /// https://developer.android.com/reference/android/net/Uri#toString()
     fn jni_to_string<'other_local>(
         &self,
@@ -654,6 +1029,60 @@ impl<'local> Deref for Uri<'local> {
     }
 }
 
+/// Newtype for StorageManager JObjects.
+struct StorageManager<'local>(JObject<'local>);
+
+impl<'local> StorageManager<'local> {
+    /// https://developer.android.com/reference/android/content/Context#getSystemService(java.lang.Class%3CT%3E)
+    fn get(env: &mut JNIEnv<'local>) -> anyhow::Result<Self> {
+        let context = get_context();
+        let class = env.find_class("android/os/storage/StorageManager")?;
+        let storage_manager = env
+            .call_method(
+                context,
+                "getSystemService",
+                "(Ljava/lang/Class;)Ljava/lang/Object;",
+                &[JValue::Object(&class)],
+            )?
+            .l()?;
+        anyhow::ensure!(
+            !storage_manager.is_null(),
+            "Context#getSystemService returned null"
+        );
+        Ok(Self(storage_manager))
+    }
+
+    /// https://developer.android.com/reference/android/os/storage/StorageManager#UUID_DEFAULT
+    fn jni_uuid_default(env: &mut JNIEnv<'local>) -> anyhow::Result<JObject<'local>> {
+        let uuid = env
+            .get_static_field(
+                "android/os/storage/StorageManager",
+                "UUID_DEFAULT",
+                "Ljava/util/UUID;",
+            )?
+            .l()?;
+        anyhow::ensure!(!uuid.is_null(), "StorageManager#UUID_DEFAULT is null");
+        Ok(uuid)
+    }
+
+    /// https://developer.android.com/reference/android/os/storage/StorageManager#getAllocatableBytes(java.util.UUID)
+    fn jni_get_allocatable_bytes<'other_local_1, 'other_local_2>(
+        &self,
+        env: &mut JNIEnv<'other_local_1>,
+        uuid: &JObject<'other_local_2>,
+    ) -> anyhow::Result<u64> {
+        let bytes = env
+            .call_method(
+                &self.0,
+                "getAllocatableBytes",
+                "(Ljava/util/UUID;)J",
+                &[JValue::Object(uuid)],
+            )?
+            .j()?;
+        Ok(bytes.max(0) as u64)
+    }
+}
+
 /// Newtype for document IDs wrapping owned or borrowed String JObjects.
 ///
 /// TODO: is the enum the best way to do this? would 2 types + Deref or something be better?
@@ -680,6 +1109,12 @@ impl DocumentsContract {
     const COLUMN_DISPLAY_NAME: &str = "_display_name";
     /// https://developer.android.com/reference/android/provider/DocumentsContract.Document#COLUMN_DOCUMENT_ID
     const COLUMN_DOCUMENT_ID: &str = "document_id";
+    /// https://developer.android.com/reference/android/provider/DocumentsContract.Document#COLUMN_LAST_MODIFIED
+    const COLUMN_LAST_MODIFIED: &str = "last_modified";
+    /// https://developer.android.com/reference/android/provider/DocumentsContract.Document#COLUMN_MIME_TYPE
+    const COLUMN_MIME_TYPE: &str = "mime_type";
+    /// https://developer.android.com/reference/android/provider/DocumentsContract.Document#COLUMN_SIZE
+    const COLUMN_SIZE: &str = "_size";
 
     /// Build URI representing the children of the target directory URI.
     ///
@@ -777,6 +1212,87 @@ impl DocumentsContract {
         Ok(Uri(uri))
     }
 
+    /// Delete the given document.
+    ///
+    /// https://developer.android.com/reference/android/provider/DocumentsContract#deleteDocument(android.content.ContentResolver,%20android.net.Uri)
+    fn jni_delete_document<'local, 'other_local_1, 'other_local_2>(
+        env: &mut JNIEnv<'local>,
+        content_resolver: &ContentResolver<'other_local_1>,
+        document_uri: &Uri<'other_local_2>,
+    ) -> anyhow::Result<()> {
+        let deleted = env
+            .call_static_method(
+                "android/provider/DocumentsContract",
+                "deleteDocument",
+                "(Landroid/content/ContentResolver;Landroid/net/Uri;)Z",
+                &[
+                    JValue::Object(content_resolver),
+                    JValue::Object(document_uri),
+                ],
+            )?
+            .z()?;
+        anyhow::ensure!(deleted, "DocumentsContract#deleteDocument returned false");
+        Ok(())
+    }
+
+    /// Change the display name of an existing document, returning the document's new URI.
+    ///
+    /// https://developer.android.com/reference/android/provider/DocumentsContract#renameDocument(android.content.ContentResolver,%20android.net.Uri,%20java.lang.String)
+    fn jni_rename_document<'local, 'other_local_1, 'other_local_2, 'other_local_3>(
+        env: &mut JNIEnv<'local>,
+        content_resolver: &ContentResolver<'other_local_1>,
+        document_uri: &Uri<'other_local_2>,
+        display_name: &JObject<'other_local_3>,
+    ) -> anyhow::Result<Uri<'local>> {
+        let uri = env
+            .call_static_method(
+                "android/provider/DocumentsContract",
+                "renameDocument",
+                "(Landroid/content/ContentResolver;Landroid/net/Uri;Ljava/lang/String;)Landroid/net/Uri;",
+                &[
+                    JValue::Object(content_resolver),
+                    JValue::Object(document_uri),
+                    JValue::Object(display_name),
+                ],
+            )?
+            .l()?;
+        anyhow::ensure!(
+            !uri.is_null(),
+            "DocumentsContract#renameDocument returned null"
+        );
+        Ok(Uri(uri))
+    }
+
+    /// Move a document from one parent directory to another, returning the document's new URI.
+    ///
+    /// https://developer.android.com/reference/android/provider/DocumentsContract#moveDocument(android.content.ContentResolver,%20android.net.Uri,%20android.net.Uri,%20android.net.Uri)
+    fn jni_move_document<'local, 'other_local_1, 'other_local_2, 'other_local_3, 'other_local_4>(
+        env: &mut JNIEnv<'local>,
+        content_resolver: &ContentResolver<'other_local_1>,
+        source_document_uri: &Uri<'other_local_2>,
+        source_parent_document_uri: &Uri<'other_local_3>,
+        target_parent_document_uri: &Uri<'other_local_4>,
+    ) -> anyhow::Result<Uri<'local>> {
+        let uri = env
+            .call_static_method(
+                "android/provider/DocumentsContract",
+                "moveDocument",
+                "(Landroid/content/ContentResolver;Landroid/net/Uri;Landroid/net/Uri;Landroid/net/Uri;)Landroid/net/Uri;",
+                &[
+                    JValue::Object(content_resolver),
+                    JValue::Object(source_document_uri),
+                    JValue::Object(source_parent_document_uri),
+                    JValue::Object(target_parent_document_uri),
+                ],
+            )?
+            .l()?;
+        anyhow::ensure!(
+            !uri.is_null(),
+            "DocumentsContract#moveDocument returned null"
+        );
+        Ok(Uri(uri))
+    }
+
     /// Extract the `Document.COLUMN_DOCUMENT_ID` from the given URI.
     ///
     /// This should be a document URI.
@@ -12,20 +12,67 @@
 //!
 //! Document picking, bookmark creation, and storage are done in the UI layer.
 //! The core layer is responsible for bookmark resolution and tracking access
-//! to security-scoped resources.
+//! to security-scoped resources. Stale bookmarks are refreshed by calling back
+//! into the UI layer through the registered `IosBookmarkResolver`.
 
 use anyhow::Context;
 use base64::{Engine, prelude::BASE64_STANDARD};
 use objc2_core_foundation::{CFData, CFRetained, CFURL, CFURLBookmarkResolutionOptions};
-use std::path::PathBuf;
-use tracing::{error, warn};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex, RwLock},
+};
+use tracing::{debug, error, warn};
+
+/// Callback that creates a fresh bookmark for a stale one, given the path the
+/// stale bookmark resolved to.
+type BookmarkRefresher = Box<dyn Fn(&str, &Path) -> Option<String> + Send + Sync>;
+
+/// The registered bookmark refresher, if any.
+static BOOKMARK_REFRESHER: RwLock<Option<BookmarkRefresher>> = RwLock::new(None);
+
+/// Stale bookmarks that have already been refreshed, mapped to their replacements.
+///
+/// This lets existing references to a stale bookmark keep working without
+/// refreshing it again.
+static REFRESHED_BOOKMARKS: LazyLock<Mutex<HashMap<String, String>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Registers the callback used to refresh stale bookmarks.
+pub fn set_bookmark_refresher(
+    refresher: impl Fn(&str, &Path) -> Option<String> + Send + Sync + 'static,
+) {
+    let mut bookmark_refresher = BOOKMARK_REFRESHER.write().unwrap();
+    *bookmark_refresher = Some(Box::new(refresher));
+}
+
+/// Result of resolving a bookmark.
+pub struct ResolvedBookmark {
+    /// The bookmark, which is different from the original if it was refreshed.
+    pub bookmark: String,
+    /// The file path the bookmark resolved to.
+    pub path: PathBuf,
+    /// Access guard, which should be held until access is no longer needed.
+    pub guard: IosUrlAccessGuard,
+}
 
 /// Resolves a bookmark base64 string, returning the file path and an access
-/// guard. The guard should be held until access is no longer needed.
-pub fn resolve_bookmark(bookmark: String) -> anyhow::Result<(PathBuf, IosUrlAccessGuard)> {
+/// guard. If the bookmark is stale, it's refreshed using the registered
+/// bookmark refresher.
+pub fn resolve_bookmark(bookmark: String) -> anyhow::Result<ResolvedBookmark> {
+    // use the replacement if this bookmark was already refreshed
+    let bookmark = {
+        let refreshed_bookmarks = REFRESHED_BOOKMARKS.lock().unwrap();
+        refreshed_bookmarks
+            .get(&bookmark)
+            .cloned()
+            .unwrap_or(bookmark)
+    };
+
     // decode bookmark
     let bookmark_bytes = BASE64_STANDARD
-        .decode(bookmark)
+        .decode(&bookmark)
         .context("failed to decode bookmark string")?;
 
     let bookmark_data = CFData::from_bytes(&bookmark_bytes);
@@ -46,14 +93,6 @@ pub fn resolve_bookmark(bookmark: String) -> anyhow::Result<(PathBuf, IosUrlAcce
         return Err(anyhow::anyhow!("failed to resolve bookmark data"));
     };
 
-    if is_stale == 1 {
-        warn!("resolved bookmark is stale");
-
-        // TODO: handle refreshing the bookmark. annoying bc we should update
-        // references to the stale bookmark in the database, and the bookmark
-        // is currently stored in the ui layer
-    }
-
     // start accessing security-scoped resource
     let guard = IosUrlAccessGuard::new(bookmark_url.clone());
 
@@ -62,7 +101,45 @@ pub fn resolve_bookmark(bookmark: String) -> anyhow::Result<(PathBuf, IosUrlAcce
         .to_file_path()
         .ok_or_else(|| anyhow::anyhow!("failed to get file path from URL"))?;
 
-    Ok((path, guard))
+    let bookmark = if is_stale == 1 {
+        refresh_bookmark(bookmark, &path)
+    } else {
+        bookmark
+    };
+
+    Ok(ResolvedBookmark {
+        bookmark,
+        path,
+        guard,
+    })
+}
+
+/// Refreshes a stale bookmark using the registered bookmark refresher,
+/// returning the original bookmark if it couldn't be refreshed.
+///
+/// This should be called while accessing the security-scoped resource, since
+/// creating a new bookmark requires access.
+fn refresh_bookmark(stale_bookmark: String, path: &Path) -> String {
+    let bookmark_refresher = BOOKMARK_REFRESHER.read().unwrap();
+    let Some(bookmark_refresher) = bookmark_refresher.as_ref() else {
+        warn!("resolved bookmark is stale, but no bookmark refresher is registered");
+        return stale_bookmark;
+    };
+
+    match bookmark_refresher(&stale_bookmark, path) {
+        Some(fresh_bookmark) => {
+            debug!("refreshed stale bookmark for {}", path.display());
+
+            let mut refreshed_bookmarks = REFRESHED_BOOKMARKS.lock().unwrap();
+            refreshed_bookmarks.insert(stale_bookmark, fresh_bookmark.clone());
+
+            fresh_bookmark
+        }
+        None => {
+            warn!("resolved bookmark is stale, and refreshing it failed");
+            stale_bookmark
+        }
+    }
 }
 
 /// Guard that starts accessing a URL on creation and stops accessing it on drop.
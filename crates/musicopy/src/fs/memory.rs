@@ -0,0 +1,458 @@
+//! In-memory filesystem backend for tests.
+//!
+//! Trees with a root starting with `memory://` are stored in a global
+//! in-memory map instead of on disk, so transfer and download logic can be
+//! tested deterministically without touching real disks or platform-specific
+//! code. Each tree is independent, so tests should use unique tree names.
+//!
+//! Files are shared between open handles, like on a real filesystem. Renaming
+//! a file moves the shared data, so open handles keep working.
+
+use crate::fs::{Metadata, OpenMode, TreePath};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex},
+    task::Poll,
+    time::SystemTime,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Prefix of memory tree roots.
+pub const MEMORY_TREE_PREFIX: &str = "memory://";
+
+/// All memory trees by root.
+static TREES: LazyLock<Mutex<HashMap<String, MemoryTree>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Default)]
+struct MemoryTree {
+    /// Directories in the tree. The root directory always exists and isn't stored.
+    dirs: HashSet<PathBuf>,
+    files: HashMap<PathBuf, Arc<Mutex<FileData>>>,
+    /// Space reported by `available_space`, which tests can lower to simulate a full disk. Trees
+    /// have unlimited space by default.
+    available_space: Option<u64>,
+}
+
+impl MemoryTree {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.as_os_str().is_empty() || self.dirs.contains(path)
+    }
+
+    fn parent_exists(&self, path: &Path) -> bool {
+        path.parent().is_none_or(|parent| self.is_dir(parent))
+    }
+}
+
+#[derive(Debug)]
+struct FileData {
+    content: Vec<u8>,
+    modified: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessMode {
+    /// Open the file if it exists, otherwise fail.
+    Open,
+    /// Create the file, truncating it if it exists.
+    Create,
+    /// Open the file if it exists, otherwise create it.
+    OpenOrCreate,
+}
+
+/// Returns true if the root is a memory tree.
+pub fn is_memory_tree(root: &str) -> bool {
+    root.starts_with(MEMORY_TREE_PREFIX)
+}
+
+/// Sets the space reported by `available_space` for a memory tree.
+pub fn set_available_space(root: &str, available_space: u64) {
+    let mut trees = TREES.lock().unwrap();
+    let tree = trees.entry(root.to_string()).or_default();
+    tree.available_space = Some(available_space);
+}
+
+/// Writes a file, creating it and its parent directories if needed.
+pub fn write(path: &TreePath, content: impl Into<Vec<u8>>) {
+    let mut trees = TREES.lock().unwrap();
+    let tree = trees.entry(path.tree.clone()).or_default();
+
+    insert_dirs(tree, path.path.parent());
+    tree.files.insert(
+        path.path.clone(),
+        Arc::new(Mutex::new(FileData {
+            content: content.into(),
+            modified: SystemTime::now(),
+        })),
+    );
+}
+
+/// Reads the contents of a file, or returns None if it doesn't exist.
+pub fn read(path: &TreePath) -> Option<Vec<u8>> {
+    let trees = TREES.lock().unwrap();
+    let file = trees.get(&path.tree)?.files.get(&path.path)?;
+    let data = file.lock().unwrap();
+    Some(data.content.clone())
+}
+
+/// Removes all files and directories in a tree.
+pub fn clear(root: &str) {
+    let mut trees = TREES.lock().unwrap();
+    trees.remove(root);
+}
+
+pub fn create_dir_all(path: &TreePath) -> anyhow::Result<()> {
+    let mut trees = TREES.lock().unwrap();
+    let tree = trees.entry(path.tree.clone()).or_default();
+
+    if tree.files.contains_key(&path.path) {
+        anyhow::bail!("file exists at {:?}", path);
+    }
+
+    insert_dirs(tree, Some(&path.path));
+    Ok(())
+}
+
+pub fn exists(path: &TreePath) -> bool {
+    let trees = TREES.lock().unwrap();
+    trees
+        .get(&path.tree)
+        .is_some_and(|tree| tree.is_dir(&path.path) || tree.files.contains_key(&path.path))
+}
+
+pub fn open(
+    path: &TreePath,
+    access_mode: AccessMode,
+    mode: OpenMode,
+) -> anyhow::Result<MemoryFile> {
+    let mut trees = TREES.lock().unwrap();
+    let tree = trees.entry(path.tree.clone()).or_default();
+
+    if tree.is_dir(&path.path) {
+        anyhow::bail!("is a directory: {:?}", path);
+    }
+
+    let data = match (tree.files.get(&path.path), access_mode) {
+        (Some(data), _) => data.clone(),
+        (None, AccessMode::Open) => anyhow::bail!("file not found: {:?}", path),
+        (None, AccessMode::Create | AccessMode::OpenOrCreate) => {
+            if !tree.parent_exists(&path.path) {
+                anyhow::bail!("parent directory not found: {:?}", path);
+            }
+
+            let data = Arc::new(Mutex::new(FileData {
+                content: Vec::new(),
+                modified: SystemTime::now(),
+            }));
+            tree.files.insert(path.path.clone(), data.clone());
+            data
+        }
+    };
+
+    let writable = matches!(mode, OpenMode::Write | OpenMode::Append | OpenMode::Update);
+    let position = {
+        let mut data = data.lock().unwrap();
+        if matches!(mode, OpenMode::Write) || access_mode == AccessMode::Create {
+            data.content.clear();
+            data.modified = SystemTime::now();
+        }
+        match mode {
+            OpenMode::Append => data.content.len(),
+            _ => 0,
+        }
+    };
+
+    Ok(MemoryFile {
+        data,
+        position,
+        writable,
+    })
+}
+
+pub fn remove_file(path: &TreePath) -> anyhow::Result<()> {
+    let mut trees = TREES.lock().unwrap();
+    let removed = trees
+        .get_mut(&path.tree)
+        .and_then(|tree| tree.files.remove(&path.path));
+    if removed.is_none() {
+        anyhow::bail!("file not found: {:?}", path);
+    }
+    Ok(())
+}
+
+pub fn remove_dir(path: &TreePath) -> anyhow::Result<()> {
+    let mut trees = TREES.lock().unwrap();
+    let Some(tree) = trees.get_mut(&path.tree) else {
+        anyhow::bail!("directory not found: {:?}", path);
+    };
+    if !tree.dirs.contains(&path.path) {
+        anyhow::bail!("directory not found: {:?}", path);
+    }
+    let has_children = tree
+        .dirs
+        .iter()
+        .chain(tree.files.keys())
+        .any(|child| child.parent() == Some(path.path.as_path()));
+    if has_children {
+        anyhow::bail!("directory not empty: {:?}", path);
+    }
+
+    tree.dirs.remove(&path.path);
+    Ok(())
+}
+
+pub fn remove_dir_all(path: &TreePath) -> anyhow::Result<()> {
+    let mut trees = TREES.lock().unwrap();
+    let Some(tree) = trees.get_mut(&path.tree) else {
+        anyhow::bail!("directory not found: {:?}", path);
+    };
+    if !tree.dirs.contains(&path.path) {
+        anyhow::bail!("directory not found: {:?}", path);
+    }
+
+    tree.dirs.retain(|dir| !dir.starts_with(&path.path));
+    tree.files.retain(|file, _| !file.starts_with(&path.path));
+    Ok(())
+}
+
+pub fn rename(from: &TreePath, to: &TreePath) -> anyhow::Result<()> {
+    if from.tree != to.tree {
+        anyhow::bail!("can't rename between trees");
+    }
+
+    let mut trees = TREES.lock().unwrap();
+    let Some(tree) = trees.get_mut(&from.tree) else {
+        anyhow::bail!("file not found: {:?}", from);
+    };
+
+    if !tree.parent_exists(&to.path) {
+        anyhow::bail!("parent directory not found: {:?}", to);
+    }
+
+    if let Some(data) = tree.files.remove(&from.path) {
+        tree.files.insert(to.path.clone(), data);
+        return Ok(());
+    }
+
+    if tree.dirs.contains(&from.path) {
+        let rebase = |p: &Path| to.path.join(p.strip_prefix(&from.path).unwrap());
+
+        tree.dirs = tree
+            .dirs
+            .drain()
+            .map(|dir| {
+                if dir.starts_with(&from.path) {
+                    rebase(&dir)
+                } else {
+                    dir
+                }
+            })
+            .collect();
+        tree.files = tree
+            .files
+            .drain()
+            .map(|(file, data)| {
+                if file.starts_with(&from.path) {
+                    (rebase(&file), data)
+                } else {
+                    (file, data)
+                }
+            })
+            .collect();
+        return Ok(());
+    }
+
+    anyhow::bail!("file not found: {:?}", from);
+}
+
+pub fn metadata(path: &TreePath) -> anyhow::Result<Metadata> {
+    let trees = TREES.lock().unwrap();
+    let Some(file) = trees
+        .get(&path.tree)
+        .and_then(|tree| tree.files.get(&path.path))
+    else {
+        anyhow::bail!("file not found: {:?}", path);
+    };
+
+    let data = file.lock().unwrap();
+    Ok(Metadata {
+        len: data.content.len() as u64,
+        modified: Some(data.modified),
+    })
+}
+
+pub fn walk_files(path: &TreePath) -> anyhow::Result<Vec<PathBuf>> {
+    let trees = TREES.lock().unwrap();
+    let Some(tree) = trees.get(&path.tree).filter(|tree| tree.is_dir(&path.path)) else {
+        anyhow::bail!("directory not found: {:?}", path);
+    };
+
+    let mut files = tree
+        .files
+        .keys()
+        .filter(|file| file.starts_with(&path.path))
+        .cloned()
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+pub fn available_space(root: &str) -> u64 {
+    let trees = TREES.lock().unwrap();
+    trees
+        .get(root)
+        .and_then(|tree| tree.available_space)
+        .unwrap_or(u64::MAX)
+}
+
+/// Inserts a directory and all of its ancestors.
+fn insert_dirs(tree: &mut MemoryTree, dir: Option<&Path>) {
+    let Some(dir) = dir else {
+        return;
+    };
+
+    for ancestor in dir.ancestors() {
+        if !ancestor.as_os_str().is_empty() {
+            tree.dirs.insert(ancestor.to_path_buf());
+        }
+    }
+}
+
+/// An open handle to a memory file.
+#[derive(Debug)]
+pub struct MemoryFile {
+    data: Arc<Mutex<FileData>>,
+    position: usize,
+    writable: bool,
+}
+
+impl MemoryFile {
+    /// Truncates or extends the file to the given length.
+    pub fn set_len(&mut self, len: u64) -> anyhow::Result<()> {
+        anyhow::ensure!(self.writable, "file not opened for writing");
+
+        let mut data = self.data.lock().unwrap();
+        data.content.resize(len as usize, 0);
+        data.modified = SystemTime::now();
+        Ok(())
+    }
+
+    /// Moves the position that the next read or write starts at.
+    pub fn seek(&mut self, position: u64) {
+        self.position = position as usize;
+    }
+}
+
+impl AsyncRead for MemoryFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let n = {
+            let data = self.data.lock().unwrap();
+            let remaining = data.content.get(self.position..).unwrap_or_default();
+            let n = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..n]);
+            n
+        };
+        self.position += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MemoryFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        if !self.writable {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "file not opened for writing",
+            )));
+        }
+
+        {
+            let position = self.position;
+            let mut data = self.data.lock().unwrap();
+            let end = position + buf.len();
+            if data.content.len() < end {
+                data.content.resize(end, 0);
+            }
+            data.content[position..end].copy_from_slice(buf);
+            data.modified = SystemTime::now();
+        }
+        self.position += buf.len();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree_path(root: &str, path: &str) -> TreePath {
+        TreePath::new(root.to_string(), PathBuf::from(path)).unwrap()
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let root = "memory://test_write_and_read";
+        let path = tree_path(root, "a/b/c.flac");
+        write(&path, b"hello".to_vec());
+
+        assert_eq!(read(&path).as_deref(), Some(b"hello".as_slice()));
+        assert!(exists(&tree_path(root, "a/b")));
+        assert_eq!(metadata(&path).unwrap().len, 5);
+
+        clear(root);
+        assert!(!exists(&path));
+    }
+
+    #[test]
+    fn test_open_requires_parent() {
+        let root = "memory://test_open_requires_parent";
+        let path = tree_path(root, "missing/c.flac");
+        assert!(open(&path, AccessMode::Create, OpenMode::Write).is_err());
+
+        create_dir_all(&tree_path(root, "missing")).unwrap();
+        assert!(open(&path, AccessMode::Create, OpenMode::Write).is_ok());
+
+        clear(root);
+    }
+
+    #[test]
+    fn test_rename_dir() {
+        let root = "memory://test_rename_dir";
+        write(&tree_path(root, "a/b/c.flac"), b"c".to_vec());
+        write(&tree_path(root, "a/d.flac"), b"d".to_vec());
+
+        rename(&tree_path(root, "a"), &tree_path(root, "x")).unwrap();
+
+        assert_eq!(
+            walk_files(&tree_path(root, "")).unwrap(),
+            vec![PathBuf::from("x/b/c.flac"), PathBuf::from("x/d.flac")]
+        );
+        assert!(!exists(&tree_path(root, "a")));
+
+        clear(root);
+    }
+}
@@ -15,16 +15,39 @@
 mod android;
 #[cfg(target_os = "ios")]
 mod ios;
+#[cfg(feature = "memory-fs")]
+pub mod memory;
+pub mod path_policy;
+pub mod sanitize;
+pub mod template;
 
-use std::{borrow::Cow, path::PathBuf, pin::Pin};
+#[cfg(target_os = "ios")]
+pub use ios::set_bookmark_refresher;
+
+use std::{borrow::Cow, io::SeekFrom, path::PathBuf, pin::Pin, time::SystemTime};
 use tokio::{
     fs::{File as TokioFile, OpenOptions},
-    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
 };
 
 pub enum OpenMode {
     Read,
+    /// Open for writing, truncating the file.
     Write,
+    /// Open for writing, keeping existing contents and writing at the end.
+    Append,
+    /// Open for writing, keeping existing contents and writing from the position set with
+    /// [`TreeFile::seek`].
+    Update,
+}
+
+/// Metadata about a file.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    /// The size of the file in bytes.
+    pub len: u64,
+    /// The last modification time, if available.
+    pub modified: Option<SystemTime>,
 }
 
 /// Struct representing a path in a subtree of the filesystem.
@@ -40,7 +63,9 @@ pub enum OpenMode {
 /// be resolved immediately, but the result will be stored separately so that
 /// the original bookmark can be retrieved later. It also starts accessing
 /// security-scoped resources for the resolved URL. When the TreePath is
-/// dropped, it stops accessing security-scoped resources for the URL.
+/// dropped, it stops accessing security-scoped resources for the URL. If the
+/// bookmark is stale, it's refreshed and the tree is replaced with the fresh
+/// bookmark.
 #[derive(Debug, Clone)]
 pub struct TreePath {
     /// The URI of the tree.
@@ -67,11 +92,12 @@ impl TreePath {
     ///
     /// On iOS, the root should be a base64-encoded bookmark.
     pub fn new(root: String, path: PathBuf) -> anyhow::Result<Self> {
-        // resolve bookmark
+        // resolve bookmark, which might be refreshed if it was stale
         #[cfg(target_os = "ios")]
-        let (resolved_bookmark, url_access_guard) = {
+        let (root, resolved_bookmark, url_access_guard) = {
             use anyhow::Context;
-            ios::resolve_bookmark(root.clone()).context("failed to resolve ios bookmark")?
+            let resolved = ios::resolve_bookmark(root).context("failed to resolve ios bookmark")?;
+            (resolved.bookmark, resolved.path, resolved.guard)
         };
 
         Ok(Self {
@@ -126,6 +152,14 @@ impl TreePath {
         self.path.set_extension(extension);
     }
 
+    pub fn file_name(&self) -> Option<Cow<'_, str>> {
+        self.path.file_name().map(|s| s.to_string_lossy())
+    }
+
+    pub fn set_file_name(&mut self, file_name: &str) {
+        self.path.set_file_name(file_name);
+    }
+
     pub fn parent(&self) -> Option<Self> {
         self.path.parent().map(|parent_path| Self {
             tree: self.tree.clone(),
@@ -142,6 +176,19 @@ impl TreePath {
         self.path.as_os_str().is_empty()
     }
 
+    /// Creates a TreePath in the same tree with a different subpath.
+    fn with_path(&self, path: PathBuf) -> Self {
+        Self {
+            tree: self.tree.clone(),
+            path,
+
+            #[cfg(target_os = "ios")]
+            resolved_bookmark: self.resolved_bookmark.clone(),
+            #[cfg(target_os = "ios")]
+            url_access_guard: self.url_access_guard.clone(),
+        }
+    }
+
     // on desktop, just join the tree and path
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     pub fn resolve_path(&self) -> PathBuf {
@@ -157,6 +204,11 @@ impl TreePath {
     }
 
     pub fn exists(&self) -> bool {
+        #[cfg(feature = "memory-fs")]
+        if memory::is_memory_tree(&self.tree) {
+            return memory::exists(self);
+        }
+
         #[cfg(not(target_os = "android"))]
         {
             let path = self.resolve_path();
@@ -170,30 +222,66 @@ impl TreePath {
 }
 
 pub struct TreeFile {
-    #[cfg(not(target_os = "android"))]
-    file: TokioFile,
+    inner: TreeFileInner,
+}
 
+enum TreeFileInner {
+    #[cfg(not(target_os = "android"))]
+    Native(TokioFile),
     #[cfg(target_os = "android")]
-    file: android::FileHandle,
+    Native(android::FileHandle),
+
+    #[cfg(feature = "memory-fs")]
+    Memory(memory::MemoryFile),
 }
 
+/// Helper trait for borrowing the inner file as a trait object.
+trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncReadWrite for T {}
+
 impl TreeFile {
+    fn native(file: NativeFile) -> Self {
+        Self {
+            inner: TreeFileInner::Native(file),
+        }
+    }
+
     pub async fn create(path: &TreePath) -> anyhow::Result<Self> {
+        #[cfg(feature = "memory-fs")]
+        if memory::is_memory_tree(&path.tree) {
+            let file = memory::open(path, memory::AccessMode::Create, OpenMode::Write)?;
+            return Ok(Self {
+                inner: TreeFileInner::Memory(file),
+            });
+        }
+
         #[cfg(not(target_os = "android"))]
         {
             let resolved_path = path.resolve_path();
             let file = TokioFile::create(&resolved_path).await?;
-            Ok(Self { file })
+            Ok(Self::native(file))
         }
         #[cfg(target_os = "android")]
         {
-            let file =
-                android::open_or_create_file(path, android::AccessMode::Create, OpenMode::Write)?;
-            Ok(Self { file })
+            let path = path.clone();
+            let file = android_blocking(move || {
+                android::open_or_create_file(&path, android::AccessMode::Create, OpenMode::Write)
+            })
+            .await?;
+            Ok(Self::native(file))
         }
     }
 
     pub async fn open(path: &TreePath, mode: OpenMode) -> anyhow::Result<Self> {
+        #[cfg(feature = "memory-fs")]
+        if memory::is_memory_tree(&path.tree) {
+            let file = memory::open(path, memory::AccessMode::Open, mode)?;
+            return Ok(Self {
+                inner: TreeFileInner::Memory(file),
+            });
+        }
+
         #[cfg(not(target_os = "android"))]
         {
             let resolved_path = path.resolve_path();
@@ -206,17 +294,31 @@ impl TreeFile {
                         .open(&resolved_path)
                         .await?
                 }
+                OpenMode::Append => OpenOptions::new().append(true).open(&resolved_path).await?,
+                OpenMode::Update => OpenOptions::new().write(true).open(&resolved_path).await?,
             };
-            Ok(Self { file })
+            Ok(Self::native(file))
         }
         #[cfg(target_os = "android")]
         {
-            let file = android::open_or_create_file(path, android::AccessMode::Open, mode)?;
-            Ok(Self { file })
+            let path = path.clone();
+            let file = android_blocking(move || {
+                android::open_or_create_file(&path, android::AccessMode::Open, mode)
+            })
+            .await?;
+            Ok(Self::native(file))
         }
     }
 
     pub async fn open_or_create(path: &TreePath, mode: OpenMode) -> anyhow::Result<Self> {
+        #[cfg(feature = "memory-fs")]
+        if memory::is_memory_tree(&path.tree) {
+            let file = memory::open(path, memory::AccessMode::OpenOrCreate, mode)?;
+            return Ok(Self {
+                inner: TreeFileInner::Memory(file),
+            });
+        }
+
         #[cfg(not(target_os = "android"))]
         {
             let resolved_path = path.resolve_path();
@@ -237,51 +339,172 @@ impl TreeFile {
                         .open(&resolved_path)
                         .await?
                 }
+                OpenMode::Append => {
+                    OpenOptions::new()
+                        .append(true)
+                        .create(true)
+                        .open(&resolved_path)
+                        .await?
+                }
+                OpenMode::Update => {
+                    OpenOptions::new()
+                        .write(true)
+                        .truncate(false)
+                        .create(true)
+                        .open(&resolved_path)
+                        .await?
+                }
             };
-            Ok(Self { file })
+            Ok(Self::native(file))
         }
         #[cfg(target_os = "android")]
         {
-            let file = android::open_or_create_file(path, android::AccessMode::OpenOrCreate, mode)?;
-            Ok(Self { file })
+            let path = path.clone();
+            let file = android_blocking(move || {
+                android::open_or_create_file(&path, android::AccessMode::OpenOrCreate, mode)
+            })
+            .await?;
+            Ok(Self::native(file))
         }
     }
 
-    fn file(&self) -> &TokioFile {
-        #[cfg(not(target_os = "android"))]
-        {
-            &self.file
+    /// Creates a file that's written to a temporary sibling path and renamed
+    /// into place when committed.
+    ///
+    /// If the returned file is dropped without being committed, the final path
+    /// is left untouched and the temporary file is left behind.
+    pub async fn create_atomic(path: &TreePath) -> anyhow::Result<AtomicTreeFile> {
+        let temp_path = Self::atomic_temp_path(path)?;
+        let file = Self::open_or_create(&temp_path, OpenMode::Write).await?;
+
+        Ok(AtomicTreeFile {
+            file,
+            temp_path,
+            path: path.clone(),
+        })
+    }
+
+    /// Like [`create_atomic`](Self::create_atomic), but keeps any data left in
+    /// the temporary file by a previous attempt and appends to it.
+    ///
+    /// Use [`atomic_temp_path`](Self::atomic_temp_path) to check how much data
+    /// was already written.
+    pub async fn resume_atomic(path: &TreePath) -> anyhow::Result<AtomicTreeFile> {
+        let temp_path = Self::atomic_temp_path(path)?;
+        let file = Self::open_or_create(&temp_path, OpenMode::Append).await?;
+
+        Ok(AtomicTreeFile {
+            file,
+            temp_path,
+            path: path.clone(),
+        })
+    }
+
+    /// Returns the temporary path used while writing the given path atomically.
+    pub fn atomic_temp_path(path: &TreePath) -> anyhow::Result<TreePath> {
+        let Some(file_name) = path.path.file_name() else {
+            anyhow::bail!("path has no file name: {:?}", path);
+        };
+
+        let mut temp_path = path.clone();
+        temp_path
+            .path
+            .set_file_name(format!(".{}.part", file_name.to_string_lossy()));
+        Ok(temp_path)
+    }
+
+    fn io(&self) -> &dyn AsyncReadWrite {
+        match &self.inner {
+            #[cfg(not(target_os = "android"))]
+            TreeFileInner::Native(file) => file,
+            #[cfg(target_os = "android")]
+            TreeFileInner::Native(file) => file.file(),
+
+            #[cfg(feature = "memory-fs")]
+            TreeFileInner::Memory(file) => file,
         }
-        #[cfg(target_os = "android")]
-        {
-            self.file.file()
+    }
+
+    fn io_mut(&mut self) -> &mut dyn AsyncReadWrite {
+        match &mut self.inner {
+            #[cfg(not(target_os = "android"))]
+            TreeFileInner::Native(file) => file,
+            #[cfg(target_os = "android")]
+            TreeFileInner::Native(file) => file.file_mut(),
+
+            #[cfg(feature = "memory-fs")]
+            TreeFileInner::Memory(file) => file,
         }
     }
 
-    fn file_mut(&mut self) -> &mut TokioFile {
-        #[cfg(not(target_os = "android"))]
-        {
-            &mut self.file
+    pub async fn write_all(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        self.io_mut().write_all(buf).await?;
+        Ok(())
+    }
+
+    /// Truncates or extends the file to the given length.
+    pub async fn set_len(&mut self, len: u64) -> anyhow::Result<()> {
+        self.io_mut().flush().await?;
+        match &mut self.inner {
+            #[cfg(not(target_os = "android"))]
+            TreeFileInner::Native(file) => file.set_len(len).await?,
+            #[cfg(target_os = "android")]
+            TreeFileInner::Native(file) => file.file_mut().set_len(len).await?,
+
+            #[cfg(feature = "memory-fs")]
+            TreeFileInner::Memory(file) => file.set_len(len)?,
         }
-        #[cfg(target_os = "android")]
-        {
-            self.file.file_mut()
+        Ok(())
+    }
+
+    /// Moves the position that the next read or write starts at.
+    pub async fn seek(&mut self, position: u64) -> anyhow::Result<()> {
+        match &mut self.inner {
+            #[cfg(not(target_os = "android"))]
+            TreeFileInner::Native(file) => {
+                file.seek(SeekFrom::Start(position)).await?;
+            }
+            #[cfg(target_os = "android")]
+            TreeFileInner::Native(file) => {
+                file.file_mut().seek(SeekFrom::Start(position)).await?;
+            }
+
+            #[cfg(feature = "memory-fs")]
+            TreeFileInner::Memory(file) => file.seek(position),
         }
+        Ok(())
     }
 
-    pub async fn write_all(&mut self, buf: &[u8]) -> anyhow::Result<()> {
-        self.file_mut().write_all(buf).await?;
+    /// Flushes buffered data and waits for it to be written to disk.
+    pub async fn sync_all(&mut self) -> anyhow::Result<()> {
+        self.io_mut().flush().await?;
+        match &mut self.inner {
+            #[cfg(not(target_os = "android"))]
+            TreeFileInner::Native(file) => file.sync_all().await?,
+            #[cfg(target_os = "android")]
+            TreeFileInner::Native(file) => file.file_mut().sync_all().await?,
+
+            // memory files are always "on disk"
+            #[cfg(feature = "memory-fs")]
+            TreeFileInner::Memory(_) => {}
+        }
         Ok(())
     }
 }
 
+/// The type of native file wrapped by a TreeFile.
+#[cfg(not(target_os = "android"))]
+type NativeFile = TokioFile;
+#[cfg(target_os = "android")]
+type NativeFile = android::FileHandle;
+
 impl AsyncRead for TreeFile {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        Pin::new(self.file_mut()).poll_read(cx, buf)
+        Pin::new(self.io_mut()).poll_read(cx, buf)
     }
 }
 
@@ -291,21 +514,21 @@ impl AsyncWrite for TreeFile {
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        Pin::new(self.file_mut()).poll_write(cx, buf)
+        Pin::new(self.io_mut()).poll_write(cx, buf)
     }
 
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(self.file_mut()).poll_flush(cx)
+        Pin::new(self.io_mut()).poll_flush(cx)
     }
 
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(self.file_mut()).poll_shutdown(cx)
+        Pin::new(self.io_mut()).poll_shutdown(cx)
     }
 
     fn poll_write_vectored(
@@ -313,15 +536,191 @@ impl AsyncWrite for TreeFile {
         cx: &mut std::task::Context<'_>,
         bufs: &[std::io::IoSlice<'_>],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        Pin::new(self.file_mut()).poll_write_vectored(cx, bufs)
+        Pin::new(self.io_mut()).poll_write_vectored(cx, bufs)
     }
 
     fn is_write_vectored(&self) -> bool {
-        self.file().is_write_vectored()
+        self.io().is_write_vectored()
+    }
+}
+
+/// A file being written to a temporary path, created by [`TreeFile::create_atomic`].
+pub struct AtomicTreeFile {
+    file: TreeFile,
+    temp_path: TreePath,
+    path: TreePath,
+}
+
+impl AtomicTreeFile {
+    /// Flushes the file and renames it into place, replacing any existing file.
+    pub async fn commit(mut self) -> anyhow::Result<()> {
+        self.file.flush().await?;
+        drop(self.file);
+
+        rename(&self.temp_path, &self.path).await
+    }
+
+    /// Flushes buffered data and waits for it to be written to disk.
+    pub async fn sync_all(&mut self) -> anyhow::Result<()> {
+        self.file.sync_all().await
+    }
+
+    /// Truncates or extends the temporary file to the given length.
+    pub async fn set_len(&mut self, len: u64) -> anyhow::Result<()> {
+        self.file.set_len(len).await
+    }
+
+    /// Closes and removes the temporary file, leaving the final path untouched.
+    pub async fn abort(self) -> anyhow::Result<()> {
+        drop(self.file);
+
+        remove_file(&self.temp_path).await
     }
 }
 
+impl AsyncWrite for AtomicTreeFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.file).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.file.is_write_vectored()
+    }
+}
+
+/// Runs a blocking SAF operation on the blocking thread pool.
+///
+/// SAF operations go through synchronous JNI calls and content provider IPC,
+/// which can be slow on SD cards, so they shouldn't run on the async runtime.
+#[cfg(target_os = "android")]
+async fn android_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(f).await?
+}
+
+/// Size of the buffer used by [`copy`].
+const COPY_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Copies the contents of one file to another, creating or truncating the destination.
+///
+/// `progress` is called with the total number of bytes copied so far after each chunk is written.
+/// Returns the total number of bytes copied.
+pub async fn copy(
+    from: &TreePath,
+    to: &TreePath,
+    mut progress: impl FnMut(u64),
+) -> anyhow::Result<u64> {
+    let mut reader = TreeFile::open(from, OpenMode::Read).await?;
+    let mut writer = TreeFile::open_or_create(to, OpenMode::Write).await?;
+
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        writer.write_all(&buf[..n]).await?;
+        copied += n as u64;
+        progress(copied);
+    }
+
+    writer.flush().await?;
+
+    Ok(copied)
+}
+
+/// Returns true if the root is an Android document tree URI rather than a filesystem path.
+pub fn is_document_tree(root: &str) -> bool {
+    root.starts_with("content://")
+}
+
+/// Returns true if the root is a filesystem path that can be checked directly, rather than a
+/// document tree URI, an iOS bookmark, or an in-memory tree.
+pub fn is_plain_path(root: &str) -> bool {
+    // on ios, roots are always bookmarks
+    if cfg!(target_os = "ios") {
+        return false;
+    }
+
+    #[cfg(feature = "memory-fs")]
+    if memory::is_memory_tree(root) {
+        return false;
+    }
+
+    !is_document_tree(root)
+}
+
+/// Recursively lists the files under the given directory.
+pub async fn walk_files(root: &TreePath) -> anyhow::Result<Vec<TreePath>> {
+    #[cfg(feature = "memory-fs")]
+    if memory::is_memory_tree(&root.tree) {
+        let paths = memory::walk_files(root)?;
+        return Ok(paths.into_iter().map(|path| root.with_path(path)).collect());
+    }
+
+    #[cfg(not(target_os = "android"))]
+    let paths = {
+        let mut paths = Vec::new();
+        let mut stack = vec![root.path.clone()];
+        while let Some(dir_path) = stack.pop() {
+            let mut entries =
+                tokio::fs::read_dir(root.with_path(dir_path.clone()).resolve_path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                let child_path = dir_path.join(entry.file_name());
+                if file_type.is_dir() {
+                    stack.push(child_path);
+                } else if file_type.is_file() {
+                    paths.push(child_path);
+                }
+            }
+        }
+        paths
+    };
+    #[cfg(target_os = "android")]
+    let paths = {
+        let walk_root = root.clone();
+        android_blocking(move || android::walk_files(&walk_root)).await?
+    };
+
+    Ok(paths.into_iter().map(|path| root.with_path(path)).collect())
+}
+
 pub async fn create_dir_all(path: &TreePath) -> anyhow::Result<()> {
+    #[cfg(feature = "memory-fs")]
+    if memory::is_memory_tree(&path.tree) {
+        return memory::create_dir_all(path);
+    }
+
     #[cfg(not(target_os = "android"))]
     {
         let resolved_path = path.resolve_path();
@@ -330,7 +729,222 @@ pub async fn create_dir_all(path: &TreePath) -> anyhow::Result<()> {
     }
     #[cfg(target_os = "android")]
     {
-        android::create_dir_all(path)?;
+        let path = path.clone();
+        android_blocking(move || android::create_dir_all(&path)).await?;
+        Ok(())
+    }
+}
+
+pub async fn remove_file(path: &TreePath) -> anyhow::Result<()> {
+    #[cfg(feature = "memory-fs")]
+    if memory::is_memory_tree(&path.tree) {
+        return memory::remove_file(path);
+    }
+
+    #[cfg(not(target_os = "android"))]
+    {
+        let resolved_path = path.resolve_path();
+        tokio::fs::remove_file(&resolved_path).await?;
+        Ok(())
+    }
+    #[cfg(target_os = "android")]
+    {
+        let path = path.clone();
+        android_blocking(move || android::remove(&path)).await
+    }
+}
+
+/// Removes an empty directory. Fails if it has anything in it, including symlinks and empty
+/// directories.
+pub async fn remove_dir(path: &TreePath) -> anyhow::Result<()> {
+    #[cfg(feature = "memory-fs")]
+    if memory::is_memory_tree(&path.tree) {
+        return memory::remove_dir(path);
+    }
+
+    #[cfg(not(target_os = "android"))]
+    {
+        let resolved_path = path.resolve_path();
+        tokio::fs::remove_dir(&resolved_path).await?;
         Ok(())
     }
+    #[cfg(target_os = "android")]
+    {
+        // deleting a directory document is recursive, and documents can't be symlinks, so only
+        // check for files
+        let path = path.clone();
+        android_blocking(move || {
+            anyhow::ensure!(
+                android::walk_files(&path)?.is_empty(),
+                "directory not empty: {:?}",
+                path
+            );
+            android::remove(&path)
+        })
+        .await
+    }
+}
+
+pub async fn remove_dir_all(path: &TreePath) -> anyhow::Result<()> {
+    #[cfg(feature = "memory-fs")]
+    if memory::is_memory_tree(&path.tree) {
+        return memory::remove_dir_all(path);
+    }
+
+    #[cfg(not(target_os = "android"))]
+    {
+        let resolved_path = path.resolve_path();
+        tokio::fs::remove_dir_all(&resolved_path).await?;
+        Ok(())
+    }
+    #[cfg(target_os = "android")]
+    {
+        // deleting a directory document is recursive
+        let path = path.clone();
+        android_blocking(move || android::remove(&path)).await
+    }
+}
+
+/// Renames a file or directory, moving it to a different directory if needed.
+///
+/// The parent of `to` must already exist. If `to` already exists, it is replaced.
+pub async fn rename(from: &TreePath, to: &TreePath) -> anyhow::Result<()> {
+    #[cfg(feature = "memory-fs")]
+    if memory::is_memory_tree(&from.tree) {
+        return memory::rename(from, to);
+    }
+
+    #[cfg(not(target_os = "android"))]
+    {
+        let from_path = from.resolve_path();
+        let to_path = to.resolve_path();
+        tokio::fs::rename(&from_path, &to_path).await?;
+        Ok(())
+    }
+    #[cfg(target_os = "android")]
+    {
+        let (from, to) = (from.clone(), to.clone());
+        android_blocking(move || android::rename(&from, &to)).await
+    }
+}
+
+pub async fn metadata(path: &TreePath) -> anyhow::Result<Metadata> {
+    #[cfg(feature = "memory-fs")]
+    if memory::is_memory_tree(&path.tree) {
+        return memory::metadata(path);
+    }
+
+    #[cfg(not(target_os = "android"))]
+    {
+        let resolved_path = path.resolve_path();
+        let metadata = tokio::fs::metadata(&resolved_path).await?;
+        Ok(Metadata {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+    #[cfg(target_os = "android")]
+    {
+        let path = path.clone();
+        android_blocking(move || android::metadata(&path)).await
+    }
+}
+
+/// Syncs a directory's entries to disk, so that newly created or renamed files
+/// survive a crash.
+///
+/// This only does anything on Unix desktop and iOS. Windows doesn't support
+/// syncing directories, and SAF doesn't expose directory file descriptors.
+pub async fn sync_dir(path: &TreePath) -> anyhow::Result<()> {
+    #[cfg(feature = "memory-fs")]
+    if memory::is_memory_tree(&path.tree) {
+        return Ok(());
+    }
+
+    #[cfg(all(unix, not(target_os = "android")))]
+    {
+        let resolved_path = path.resolve_path();
+        let dir = TokioFile::open(&resolved_path).await?;
+        dir.sync_all().await?;
+        Ok(())
+    }
+    #[cfg(not(all(unix, not(target_os = "android"))))]
+    {
+        let _ = path;
+        Ok(())
+    }
+}
+
+/// Returns the number of bytes available to the current user on the volume containing the path,
+/// or None if it can't be known.
+///
+/// On Android, this is only known for trees from the external storage provider.
+pub async fn available_space(path: &TreePath) -> anyhow::Result<Option<u64>> {
+    #[cfg(feature = "memory-fs")]
+    if memory::is_memory_tree(&path.tree) {
+        return Ok(Some(memory::available_space(&path.tree)));
+    }
+
+    #[cfg(not(target_os = "android"))]
+    {
+        // the path might not exist yet, so use its closest existing ancestor
+        let mut resolved_path = path.resolve_path();
+        while !resolved_path.exists() {
+            if !resolved_path.pop() {
+                anyhow::bail!("no existing ancestor for path {:?}", path.resolve_path());
+            }
+        }
+
+        let available =
+            tokio::task::spawn_blocking(move || available_space_at(&resolved_path)).await??;
+        Ok(Some(available))
+    }
+    #[cfg(target_os = "android")]
+    {
+        let path = path.clone();
+        android_blocking(move || android::available_space(&path)).await
+    }
+}
+
+#[cfg(unix)]
+fn available_space_at(path: &std::path::Path) -> anyhow::Result<u64> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    let ret = unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let stat = unsafe { stat.assume_init() };
+
+    // field widths differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    let available = stat.f_bavail as u64 * stat.f_frsize as u64;
+    Ok(available)
+}
+
+#[cfg(windows)]
+fn available_space_at(path: &std::path::Path) -> anyhow::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide_path = path
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect::<Vec<u16>>();
+    let mut available = 0u64;
+    let ret = unsafe {
+        GetDiskFreeSpaceExW(
+            wide_path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ret == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(available)
 }
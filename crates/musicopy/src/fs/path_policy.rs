@@ -0,0 +1,337 @@
+//! Length limits for the paths of downloaded files.
+//!
+//! Sanitizing makes each name valid on its own, but some destinations also
+//! limit the length of the whole path. Windows limits paths to `MAX_PATH`
+//! unless they're verbatim (`\\?\C:\...`), and SD cards are usually FAT32,
+//! where paths longer than `MAX_PATH` from the root of the card can't be
+//! created by Android or read on a Windows PC. Deep folder trees, like
+//! classical music laid out by composer, work, and performer, easily go past
+//! that.
+//!
+//! The policy shortens the directories of a path from the deepest one up, and
+//! then the file name, until the path fits. Shortened names end with a hash of
+//! the original name, so different long names stay different, and the same
+//! file is shortened the same way every time it's downloaded.
+
+use super::{
+    TreePath, is_document_tree,
+    sanitize::{MAX_COMPONENT_LEN, SanitizeRules, split_extension, trim_end_dots_and_spaces},
+};
+use std::path::{Component, PathBuf};
+
+/// Maximum length of a path on Windows without the verbatim prefix, not
+/// counting the terminating NUL.
+const WINDOWS_MAX_PATH: usize = 259;
+
+/// Maximum length of a path from the root of a FAT32 SD card, so the card
+/// also works in a Windows PC.
+const FAT_MAX_PATH: usize = WINDOWS_MAX_PATH - "X:\\".len();
+
+/// Room left in the file name for the temporary `.<name>.part` file that's
+/// written while downloading, and the suffix that's added to a name that
+/// collides with another file, e.g. `~1a2b3c4d` or ` (12)`.
+const FILE_NAME_OVERHEAD: usize = 16;
+
+/// Length of the file name that's reserved when shortening directories.
+///
+/// Directories are shortened as if every file name had this length, so the
+/// files of an album end up in the same directory even if some of their names
+/// are longer than others.
+const RESERVED_FILE_NAME_LEN: usize = 64;
+
+/// Names are never shortened below this length, so they keep a few characters
+/// of the original name before the hash.
+const MIN_SHORTENED_LEN: usize = 16;
+
+/// The limits of the filesystem that files are downloaded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathPolicy {
+    /// The rules that names are sanitized with.
+    pub rules: SanitizeRules,
+    /// Maximum length of the whole path, including the tree it's in.
+    max_path_len: Option<usize>,
+    /// Length of the tree's own path, counted towards `max_path_len`.
+    tree_len: usize,
+}
+
+impl PathPolicy {
+    /// Returns the policy for downloading to the given tree on this platform.
+    pub fn for_tree(tree: &str) -> Self {
+        let rules = SanitizeRules::current();
+
+        if is_document_tree(tree) {
+            // shared storage on the device itself has no path limit, but SD
+            // cards are usually FAT32
+            return match document_tree_path(tree) {
+                Some((volume, path)) if volume != "primary" => Self {
+                    rules,
+                    max_path_len: Some(FAT_MAX_PATH),
+                    tree_len: rules.len(&path),
+                },
+                _ => Self::unlimited(rules),
+            };
+        }
+
+        if cfg!(windows) && !tree.starts_with(r"\\?\") {
+            return Self {
+                rules,
+                max_path_len: Some(WINDOWS_MAX_PATH),
+                tree_len: rules.len(tree),
+            };
+        }
+
+        Self::unlimited(rules)
+    }
+
+    /// Returns a policy that only limits the length of each name.
+    pub fn unlimited(rules: SanitizeRules) -> Self {
+        Self {
+            rules,
+            max_path_len: None,
+            tree_len: 0,
+        }
+    }
+
+    /// Shortens the path of a file in the tree until it fits the limits.
+    ///
+    /// The path is left as is if it can't be shortened enough, so the error
+    /// from writing it explains what went wrong.
+    pub fn apply(&self, path: &mut TreePath) {
+        let mut components = path
+            .path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let Some(mut file_name) = components.pop() else {
+            return;
+        };
+        let mut dirs = components;
+
+        // the temporary file's name has to fit too
+        let max_file_name_len = MAX_COMPONENT_LEN - FILE_NAME_OVERHEAD;
+        if file_name.len() > max_file_name_len {
+            file_name = self.shorten(&file_name, max_file_name_len, true);
+        }
+
+        if let Some(max_path_len) = self.max_path_len {
+            // the tree and the path are joined with a separator
+            let budget = max_path_len.saturating_sub(self.tree_len + 1);
+
+            let dirs_budget = budget.saturating_sub(RESERVED_FILE_NAME_LEN + FILE_NAME_OVERHEAD);
+            for i in (0..dirs.len()).rev() {
+                let excess = self.joined_len(&dirs).saturating_sub(dirs_budget);
+                if excess == 0 {
+                    break;
+                }
+
+                let len = self.rules.len(&dirs[i]);
+                if len > MIN_SHORTENED_LEN {
+                    let max_len = len.saturating_sub(excess).max(MIN_SHORTENED_LEN);
+                    dirs[i] = self.shorten(&dirs[i], max_len, false);
+                }
+            }
+
+            let dirs_len = match self.joined_len(&dirs) {
+                0 => 0,
+                len => len + 1,
+            };
+            let file_name_budget = budget
+                .saturating_sub(dirs_len + FILE_NAME_OVERHEAD)
+                .max(MIN_SHORTENED_LEN);
+            if self.rules.len(&file_name) > file_name_budget {
+                file_name = self.shorten(&file_name, file_name_budget, true);
+            }
+        }
+
+        let mut shortened = dirs.into_iter().collect::<PathBuf>();
+        shortened.push(file_name);
+        path.path = shortened;
+    }
+
+    /// Length of the components joined with separators.
+    fn joined_len(&self, components: &[String]) -> usize {
+        let names_len = components
+            .iter()
+            .map(|component| self.rules.len(component))
+            .sum::<usize>();
+        names_len + components.len().saturating_sub(1)
+    }
+
+    /// Shortens a name to at most `max_len`, ending it with a hash of the
+    /// original name, and keeping the extension of file names.
+    fn shorten(&self, name: &str, max_len: usize, is_file: bool) -> String {
+        if self.rules.len(name) <= max_len {
+            return name.to_string();
+        }
+
+        let (stem, extension) = if is_file {
+            split_extension(name)
+        } else {
+            (name, "")
+        };
+
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        let suffix = format!("~{:08x}", crc.checksum(name.as_bytes()));
+
+        let stem_len = max_len.saturating_sub(suffix.len() + self.rules.len(extension));
+        let mut shortened = self.rules.truncate(stem, stem_len).to_string();
+        if self.rules == SanitizeRules::Windows {
+            trim_end_dots_and_spaces(&mut shortened);
+        }
+
+        format!("{shortened}{suffix}{extension}")
+    }
+}
+
+impl SanitizeRules {
+    /// Length of a name as the filesystem counts it. Windows and FAT count
+    /// UTF-16 units, and Unix filesystems count bytes.
+    fn len(self, name: &str) -> usize {
+        match self {
+            Self::Windows => name.encode_utf16().count(),
+            Self::Unix => name.len(),
+        }
+    }
+
+    /// Returns the longest prefix of a name that's at most `max_len` long.
+    fn truncate(self, name: &str, max_len: usize) -> &str {
+        let mut len = 0;
+        for (i, c) in name.char_indices() {
+            len += match self {
+                Self::Windows => c.len_utf16(),
+                Self::Unix => c.len_utf8(),
+            };
+            if len > max_len {
+                return &name[..i];
+            }
+        }
+        name
+    }
+}
+
+/// Returns the volume and the path in it of an Android document tree URI,
+/// e.g. `1234-5678` and `Music/Classical` for
+/// `content://com.android.externalstorage.documents/tree/1234-5678%3AMusic%2FClassical`.
+fn document_tree_path(tree: &str) -> Option<(String, String)> {
+    let document_id = tree.split("/tree/").nth(1)?.split('/').next()?;
+    let document_id = percent_decode(document_id)?;
+    let (volume, path) = document_id.split_once(':')?;
+    Some((volume.to_string(), path.to_string()))
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fat_policy(tree_len: usize) -> PathPolicy {
+        PathPolicy {
+            rules: SanitizeRules::Windows,
+            max_path_len: Some(FAT_MAX_PATH),
+            tree_len,
+        }
+    }
+
+    fn apply(policy: PathPolicy, path: &str) -> String {
+        let mut tree_path = TreePath::new("/music".to_string(), path.into()).unwrap();
+        policy.apply(&mut tree_path);
+        tree_path
+            .path
+            .iter()
+            .map(|component| component.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[test]
+    fn test_short_paths_unchanged() {
+        let path = "Bach/Goldberg Variations/01 Aria.flac";
+        assert_eq!(apply(fat_policy(5), path), path);
+        assert_eq!(
+            apply(PathPolicy::unlimited(SanitizeRules::Unix), path),
+            path
+        );
+    }
+
+    #[test]
+    fn test_shortens_deep_paths() {
+        let policy = fat_policy("Music".len());
+        let composer = "Johann Sebastian Bach";
+        let work = format!(
+            "Das wohltemperierte Klavier, Book I, BWV 846-869 {}",
+            "x".repeat(60)
+        );
+        let album = format!(
+            "András Schiff - Recorded Live at Wigmore Hall {}",
+            "y".repeat(60)
+        );
+        let file = format!(
+            "01 Prelude and Fugue No. 1 in C major {}.flac",
+            "z".repeat(60)
+        );
+
+        let shortened = apply(policy, &format!("{composer}/{work}/{album}/{file}"));
+        let components = shortened.split('/').collect::<Vec<_>>();
+        assert_eq!(components.len(), 4);
+        let len = policy.tree_len + 1 + policy.rules.len(&shortened) + FILE_NAME_OVERHEAD;
+        assert!(len <= FAT_MAX_PATH);
+
+        // the deepest directory is shortened first
+        assert_eq!(components[0], composer);
+        assert!(components[2].starts_with("András Schiff"));
+        assert!(components[3].ends_with(".flac"));
+
+        // other files in the album end up in the same directory
+        let other = apply(
+            policy,
+            &format!("{composer}/{work}/{album}/02 Prelude.flac"),
+        );
+        assert_eq!(
+            other.rsplit_once('/').unwrap().0,
+            shortened.rsplit_once('/').unwrap().0
+        );
+    }
+
+    #[test]
+    fn test_shortened_names_stay_different() {
+        let rules = SanitizeRules::Windows;
+        let policy = PathPolicy::unlimited(rules);
+        let a = policy.shorten(&format!("{}a.flac", "x".repeat(300)), 100, true);
+        let b = policy.shorten(&format!("{}b.flac", "x".repeat(300)), 100, true);
+        assert_ne!(a, b);
+        assert!(rules.len(&a) <= 100);
+        assert!(a.ends_with(".flac"));
+    }
+
+    #[test]
+    fn test_document_tree_path() {
+        assert_eq!(
+            document_tree_path(
+                "content://com.android.externalstorage.documents/tree/1234-5678%3AMusic%2FClassical"
+            ),
+            Some(("1234-5678".to_string(), "Music/Classical".to_string()))
+        );
+        assert_eq!(
+            document_tree_path("content://com.android.externalstorage.documents/tree/primary%3A"),
+            Some(("primary".to_string(), String::new()))
+        );
+        assert_eq!(document_tree_path("/home/user/Music"), None);
+    }
+}
@@ -0,0 +1,256 @@
+//! Filename sanitization for downloaded files.
+//!
+//! Paths in the index come from the server's filesystem, which might allow
+//! characters or names that aren't allowed on the client's filesystem. For
+//! example, `AC/DC: Live?` is a fine directory name on Linux, but can't be
+//! created on Windows or on a FAT-formatted SD card.
+
+/// Maximum length of a single path component in bytes.
+///
+/// Most filesystems limit names to 255 bytes (or UTF-16 units on Windows).
+pub(super) const MAX_COMPONENT_LEN: usize = 255;
+
+/// Names reserved by Windows, which can't be used even with an extension.
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Character used to replace invalid characters.
+const REPLACEMENT_CHAR: char = '_';
+
+/// The set of rules used to sanitize names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeRules {
+    /// Rules for Windows and FAT/exFAT filesystems.
+    Windows,
+    /// Rules for Unix filesystems, which only disallow `/` and NUL.
+    Unix,
+}
+
+impl SanitizeRules {
+    /// Returns the rules for the current platform.
+    ///
+    /// Android uses the Windows rules, since SD cards are usually formatted as
+    /// FAT32 or exFAT.
+    pub fn current() -> Self {
+        if cfg!(any(windows, target_os = "android")) {
+            Self::Windows
+        } else {
+            Self::Unix
+        }
+    }
+
+    fn is_invalid_char(self, c: char) -> bool {
+        match self {
+            Self::Windows => {
+                c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')
+            }
+            Self::Unix => matches!(c, '\0' | '/'),
+        }
+    }
+}
+
+/// Sanitizes a single path component, such as a file or directory name.
+pub fn sanitize_component(name: &str, rules: SanitizeRules) -> String {
+    let mut sanitized = name
+        .chars()
+        .map(|c| {
+            if rules.is_invalid_char(c) {
+                REPLACEMENT_CHAR
+            } else {
+                c
+            }
+        })
+        .collect::<String>();
+
+    if rules == SanitizeRules::Windows {
+        // windows silently strips trailing dots and spaces
+        trim_end_dots_and_spaces(&mut sanitized);
+
+        // reserved names are reserved with any extension, e.g. `CON.mp3`
+        let stem = sanitized.split('.').next().unwrap_or_default().trim_end();
+        if WINDOWS_RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+        {
+            sanitized.insert(0, REPLACEMENT_CHAR);
+        }
+    }
+
+    if sanitized.is_empty() || sanitized == "." || sanitized == ".." {
+        sanitized = REPLACEMENT_CHAR.to_string();
+    }
+
+    truncate_component(sanitized, MAX_COMPONENT_LEN, rules)
+}
+
+/// Sanitizes a slash-separated relative path, component by component.
+///
+/// Empty components are removed, so the result never starts or ends with a
+/// separator.
+pub fn sanitize_path(path: &str, rules: SanitizeRules) -> String {
+    path.split('/')
+        .filter(|component| !component.is_empty())
+        .map(|component| sanitize_component(component, rules))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Makes a file name unique by inserting a short hash of the original path
+/// before the extension, e.g. `song~1a2b3c4d.mp3`.
+///
+/// This is used as a fallback when sanitizing maps two different paths to the
+/// same name.
+pub fn unique_file_name(name: &str, original_path: &str, rules: SanitizeRules) -> String {
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+    let suffix = format!("~{:08x}", crc.checksum(original_path.as_bytes()));
+
+    let (stem, extension) = split_extension(name);
+    let stem_len = floor_char_boundary(
+        stem,
+        MAX_COMPONENT_LEN.saturating_sub(suffix.len() + extension.len()),
+    );
+
+    let mut stem = stem[..stem_len].to_string();
+    if rules == SanitizeRules::Windows {
+        trim_end_dots_and_spaces(&mut stem);
+    }
+
+    format!("{stem}{suffix}{extension}")
+}
+
+/// Adds a number before the extension of a file name, e.g. `song (1).mp3`.
+///
+/// This is used to keep an existing file when downloading a different file to
+/// the same name.
+pub fn numbered_file_name(name: &str, number: u32, rules: SanitizeRules) -> String {
+    let suffix = format!(" ({number})");
+
+    let (stem, extension) = split_extension(name);
+    let stem_len = floor_char_boundary(
+        stem,
+        MAX_COMPONENT_LEN.saturating_sub(suffix.len() + extension.len()),
+    );
+
+    let mut stem = stem[..stem_len].to_string();
+    if rules == SanitizeRules::Windows {
+        trim_end_dots_and_spaces(&mut stem);
+    }
+
+    format!("{stem}{suffix}{extension}")
+}
+
+/// Truncates a component to at most `max_len` bytes, preserving its extension.
+fn truncate_component(name: String, max_len: usize, rules: SanitizeRules) -> String {
+    if name.len() <= max_len {
+        return name;
+    }
+
+    let (stem, extension) = split_extension(&name);
+    let stem_len = floor_char_boundary(stem, max_len.saturating_sub(extension.len()));
+
+    let mut stem = stem[..stem_len].to_string();
+    if rules == SanitizeRules::Windows {
+        trim_end_dots_and_spaces(&mut stem);
+    }
+    if stem.is_empty() {
+        stem.push(REPLACEMENT_CHAR);
+    }
+
+    format!("{stem}{extension}")
+}
+
+/// Splits a name into its stem and extension, including the leading dot.
+///
+/// Only short extensions are split off, so that a name like `Vol. 2 (Live at
+/// some very long venue name)` isn't treated as having a huge extension.
+pub(super) fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= 16 => name.split_at(i),
+        _ => (name, ""),
+    }
+}
+
+/// Returns the largest char boundary in `s` that's at most `index`.
+fn floor_char_boundary(s: &str, index: usize) -> usize {
+    if index >= s.len() {
+        return s.len();
+    }
+
+    let mut index = index;
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+pub(super) fn trim_end_dots_and_spaces(s: &mut String) {
+    let trimmed_len = s.trim_end_matches(['.', ' ']).len();
+    s.truncate(trimmed_len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_windows() {
+        let rules = SanitizeRules::Windows;
+        assert_eq!(sanitize_component("AC:DC", rules), "AC_DC");
+        assert_eq!(sanitize_component("Why?", rules), "Why_");
+        assert_eq!(sanitize_component("Vol. 1...", rules), "Vol. 1");
+        assert_eq!(sanitize_component("CON", rules), "_CON");
+        assert_eq!(sanitize_component("con.mp3", rules), "_con.mp3");
+        assert_eq!(sanitize_component("Console.mp3", rules), "Console.mp3");
+        assert_eq!(sanitize_component("..", rules), "_");
+    }
+
+    #[test]
+    fn test_sanitize_unix() {
+        let rules = SanitizeRules::Unix;
+        assert_eq!(sanitize_component("AC:DC", rules), "AC:DC");
+        assert_eq!(sanitize_component("CON", rules), "CON");
+        assert_eq!(sanitize_component("..", rules), "_");
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        let rules = SanitizeRules::Windows;
+        assert_eq!(
+            sanitize_path("Artist: Name/Album?/01 Track.flac", rules),
+            "Artist_ Name/Album_/01 Track.flac"
+        );
+        assert_eq!(sanitize_path("/a//b/", rules), "a/b");
+    }
+
+    #[test]
+    fn test_truncate_preserves_extension() {
+        let name = format!("{}.flac", "é".repeat(200));
+        let sanitized = sanitize_component(&name, SanitizeRules::Unix);
+        assert!(sanitized.len() <= MAX_COMPONENT_LEN);
+        assert!(sanitized.ends_with(".flac"));
+    }
+
+    #[test]
+    fn test_unique_file_name() {
+        let rules = SanitizeRules::Windows;
+        let a = unique_file_name("a_b.mp3", "a?b.mp3", rules);
+        let b = unique_file_name("a_b.mp3", "a*b.mp3", rules);
+        assert_ne!(a, b);
+        assert!(a.starts_with("a_b~"));
+        assert!(a.ends_with(".mp3"));
+    }
+
+    #[test]
+    fn test_numbered_file_name() {
+        let rules = SanitizeRules::Windows;
+        assert_eq!(numbered_file_name("song.mp3", 1, rules), "song (1).mp3");
+        assert_eq!(numbered_file_name("song", 2, rules), "song (2)");
+
+        let name = format!("{}.flac", "a".repeat(300));
+        let numbered = numbered_file_name(&name, 10, rules);
+        assert!(numbered.len() <= MAX_COMPONENT_LEN);
+        assert!(numbered.ends_with(" (10).flac"));
+    }
+}
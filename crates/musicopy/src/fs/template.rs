@@ -0,0 +1,389 @@
+//! Templates for the paths of downloaded files.
+//!
+//! By default, downloads keep the server's folder structure. A template like
+//! `{artist}/{album}/{track:02} - {title}` lays them out by their tags
+//! instead. Each component of the template is sanitized after its fields are
+//! filled in, so a tag like `AC/DC` doesn't create an extra directory.
+
+use crate::{
+    fs::sanitize::{SanitizeRules, sanitize_component},
+    protocol::{ItemExtraMetadata, ItemMetadata},
+};
+
+/// Used when an item has no artist tag.
+const UNKNOWN_ARTIST: &str = "Unknown Artist";
+/// Used when an item has no album tag.
+const UNKNOWN_ALBUM: &str = "Unknown Album";
+
+/// Maximum zero-padded width of a number field, e.g. `{track:02}`.
+const MAX_WIDTH: usize = 9;
+
+/// A parsed path template.
+///
+/// Fields are written in braces, and literal braces are written as `{{` and
+/// `}}`. The supported fields are:
+///
+/// - `{artist}`, or `Unknown Artist` if untagged
+/// - `{albumartist}`, or `{artist}` if untagged or the server is too old to
+///   send it
+/// - `{album}`, or `Unknown Album` if untagged
+/// - `{title}`, or the original file name without its extension if untagged
+/// - `{track}`, or 0 if untagged, optionally zero-padded like `{track:02}`
+/// - `{disc}`, or 1 if untagged, optionally zero-padded like `{disc:02}`
+/// - `{root}`, the name of the server's library root
+/// - `{filename}`, the original file name without its extension
+///
+/// The rendered path doesn't include an extension, so the caller can add the
+/// original or transcoded one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathTemplate {
+    components: Vec<Vec<Segment>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field { field: Field, width: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Artist,
+    AlbumArtist,
+    Album,
+    Title,
+    Track,
+    Disc,
+    Root,
+    FileName,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "artist" => Some(Self::Artist),
+            "albumartist" => Some(Self::AlbumArtist),
+            "album" => Some(Self::Album),
+            "title" => Some(Self::Title),
+            "track" => Some(Self::Track),
+            "disc" => Some(Self::Disc),
+            "root" => Some(Self::Root),
+            "filename" => Some(Self::FileName),
+            _ => None,
+        }
+    }
+}
+
+/// The values that a template is rendered with.
+#[derive(Debug, Clone, Copy)]
+pub struct TemplateValues<'a> {
+    /// The name of the server's library root.
+    pub root: &'a str,
+    /// The path of the file in the server's library root.
+    pub path: &'a str,
+    /// The file's tags, if the server sent them.
+    pub metadata: Option<&'a ItemMetadata>,
+    /// The file's tags that aren't in its metadata, if the server sent them.
+    pub extra_metadata: Option<&'a ItemExtraMetadata>,
+}
+
+impl PathTemplate {
+    /// Parses a template, checking that it only uses known fields.
+    pub fn parse(template: &str) -> anyhow::Result<Self> {
+        let mut components = Vec::new();
+        for component in template.split('/') {
+            // skip empty components, like the sanitized paths do
+            if component.trim().is_empty() {
+                continue;
+            }
+            components.push(parse_component(component)?);
+        }
+
+        anyhow::ensure!(!components.is_empty(), "template is empty");
+
+        Ok(Self { components })
+    }
+
+    /// Renders the template to a sanitized relative path, without an
+    /// extension.
+    pub fn render(&self, values: TemplateValues<'_>, rules: SanitizeRules) -> String {
+        self.render_checked(values, rules).0
+    }
+
+    /// Renders the template like [`render`](Self::render), and also returns
+    /// whether sanitizing changed the rendered names.
+    pub fn render_checked(
+        &self,
+        values: TemplateValues<'_>,
+        rules: SanitizeRules,
+    ) -> (String, bool) {
+        let file_name = values.path.rsplit('/').next().unwrap_or_default();
+        let file_stem = match file_name.rfind('.') {
+            Some(i) if i > 0 => &file_name[..i],
+            _ => file_name,
+        };
+
+        let metadata = values.metadata;
+        let tag = |get: fn(&ItemMetadata) -> &Option<String>| {
+            metadata
+                .and_then(|metadata| get(metadata).as_deref())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+
+        let mut sanitized = false;
+        let path = self
+            .components
+            .iter()
+            .map(|segments| {
+                let mut rendered = String::new();
+                for segment in segments {
+                    match segment {
+                        Segment::Literal(literal) => rendered.push_str(literal),
+                        Segment::Field { field, width } => {
+                            let value = match field {
+                                Field::Artist => {
+                                    tag(|metadata| &metadata.artist).unwrap_or(UNKNOWN_ARTIST)
+                                }
+                                Field::AlbumArtist => values
+                                    .extra_metadata
+                                    .and_then(|extra| extra.album_artist.as_deref())
+                                    .map(str::trim)
+                                    .filter(|value| !value.is_empty())
+                                    .or_else(|| tag(|metadata| &metadata.artist))
+                                    .unwrap_or(UNKNOWN_ARTIST),
+                                Field::Album => {
+                                    tag(|metadata| &metadata.album).unwrap_or(UNKNOWN_ALBUM)
+                                }
+                                Field::Title => {
+                                    tag(|metadata| &metadata.title).unwrap_or(file_stem)
+                                }
+                                Field::Root => values.root,
+                                Field::FileName => file_stem,
+                                Field::Track => {
+                                    let track = metadata
+                                        .and_then(|metadata| metadata.track_number)
+                                        .unwrap_or(0);
+                                    rendered.push_str(&format!("{track:0width$}"));
+                                    continue;
+                                }
+                                Field::Disc => {
+                                    let disc = values
+                                        .extra_metadata
+                                        .and_then(|extra| extra.disc_number)
+                                        .unwrap_or(1);
+                                    rendered.push_str(&format!("{disc:0width$}"));
+                                    continue;
+                                }
+                            };
+                            rendered.push_str(value);
+                        }
+                    }
+                }
+                let rendered = rendered.trim();
+                let component = sanitize_component(rendered, rules);
+                sanitized |= component != rendered;
+                component
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        (path, sanitized)
+    }
+}
+
+/// Parses a single path component of a template into literal text and fields.
+fn parse_component(component: &str) -> anyhow::Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = component.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut spec = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => spec.push(c),
+                        None => anyhow::bail!("unclosed field in template: {{{spec}"),
+                    }
+                }
+
+                let (name, width) = match spec.split_once(':') {
+                    Some((name, format)) => {
+                        let width = format
+                            .strip_prefix('0')
+                            .and_then(|width| width.parse::<usize>().ok())
+                            .filter(|width| *width <= MAX_WIDTH)
+                            .ok_or_else(|| {
+                                anyhow::anyhow!("invalid format in template field: {{{spec}}}")
+                            })?;
+                        (name, width)
+                    }
+                    None => (spec.as_str(), 0),
+                };
+                let field = Field::parse(name)
+                    .ok_or_else(|| anyhow::anyhow!("unknown template field: {{{name}}}"))?;
+                anyhow::ensure!(
+                    width == 0 || matches!(field, Field::Track | Field::Disc),
+                    "only number fields can be padded: {{{spec}}}"
+                );
+
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Field { field, width });
+            }
+            '}' => anyhow::bail!("unmatched }} in template"),
+            c => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> ItemMetadata {
+        ItemMetadata {
+            title: Some("Back in Black".into()),
+            artist: Some("AC/DC".into()),
+            album: Some("Back in Black".into()),
+            track_number: Some(6),
+            duration: None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let template = PathTemplate::parse("{albumartist}/{album}/{track:02} - {title}").unwrap();
+        let metadata = metadata();
+        let values = TemplateValues {
+            root: "music",
+            path: "AC-DC/1980/06.flac",
+            metadata: Some(&metadata),
+            extra_metadata: None,
+        };
+        assert_eq!(
+            template.render(values, SanitizeRules::Windows),
+            "AC_DC/Back in Black/06 - Back in Black"
+        );
+    }
+
+    #[test]
+    fn test_render_extra_metadata() {
+        let template =
+            PathTemplate::parse("{albumartist}/{album}/{disc}-{track:02} - {title}").unwrap();
+        let metadata = ItemMetadata {
+            artist: Some("Brian Johnson".into()),
+            ..metadata()
+        };
+        let extra_metadata = ItemExtraMetadata {
+            album_artist: Some("AC/DC".into()),
+            disc_number: Some(2),
+        };
+        let values = TemplateValues {
+            root: "music",
+            path: "AC-DC/1980/06.flac",
+            metadata: Some(&metadata),
+            extra_metadata: Some(&extra_metadata),
+        };
+        assert_eq!(
+            template.render(values, SanitizeRules::Unix),
+            "AC_DC/Back in Black/2-06 - Back in Black"
+        );
+    }
+
+    #[test]
+    fn test_render_fallbacks() {
+        let template = PathTemplate::parse("{artist}/{album}/{track:02} - {title}").unwrap();
+        let values = TemplateValues {
+            root: "music",
+            path: "Some Folder/untagged song.mp3",
+            metadata: None,
+            extra_metadata: None,
+        };
+        assert_eq!(
+            template.render(values, SanitizeRules::Unix),
+            "Unknown Artist/Unknown Album/00 - untagged song"
+        );
+    }
+
+    #[test]
+    fn test_render_sanitizes_values() {
+        let template = PathTemplate::parse("{root}/{album}").unwrap();
+        let metadata = ItemMetadata {
+            album: Some("..".into()),
+            ..metadata()
+        };
+        let values = TemplateValues {
+            root: "music",
+            path: "a.flac",
+            metadata: Some(&metadata),
+            extra_metadata: None,
+        };
+        assert_eq!(template.render(values, SanitizeRules::Unix), "music/_");
+    }
+
+    #[test]
+    fn test_render_checked() {
+        let metadata = metadata();
+        let values = TemplateValues {
+            root: "music",
+            path: "AC:DC/06?.flac",
+            metadata: Some(&metadata),
+            extra_metadata: None,
+        };
+
+        // the server's path isn't valid, but the names rendered from the tags are
+        let template = PathTemplate::parse("{album}/{track:02} - {title}").unwrap();
+        assert_eq!(
+            template.render_checked(values, SanitizeRules::Windows),
+            ("Back in Black/06 - Back in Black".to_string(), false)
+        );
+
+        // a tag that isn't valid is sanitized
+        let template = PathTemplate::parse("{artist}/{title}").unwrap();
+        assert_eq!(
+            template.render_checked(values, SanitizeRules::Windows),
+            ("AC_DC/Back in Black".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(PathTemplate::parse("").is_err());
+        assert!(PathTemplate::parse("/").is_err());
+        assert!(PathTemplate::parse("{genre}").is_err());
+        assert!(PathTemplate::parse("{title").is_err());
+        assert!(PathTemplate::parse("title}").is_err());
+        assert!(PathTemplate::parse("{title:02}").is_err());
+        assert!(PathTemplate::parse("{track:2}").is_err());
+        assert_eq!(
+            PathTemplate::parse("{{{title}}}").unwrap(),
+            PathTemplate {
+                components: vec![vec![
+                    Segment::Literal("{".into()),
+                    Segment::Field {
+                        field: Field::Title,
+                        width: 0,
+                    },
+                    Segment::Literal("}".into()),
+                ]]
+            }
+        );
+    }
+}
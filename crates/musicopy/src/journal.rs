@@ -0,0 +1,423 @@
+//! Journal of in-flight downloads, so a crash doesn't leave unexplained partial files behind.
+//!
+//! A download is journaled in the database when its partial file is opened, the partial file's
+//! length is checkpointed while it downloads, and the entry is removed when the file is moved
+//! into place or discarded. Downloads that fail keep their partial files and entries, so they can
+//! be resumed. At startup, every entry left in the journal belongs to a download that didn't
+//! finish, so its partial file is cleaned up and the download is offered to be resumed.
+//!
+//! Partial files that aren't in the journal, e.g. left by versions before it or after the database
+//! was reset, are found by their names in the download directory and deleted or kept per the
+//! [`PartialFilePolicy`].
+
+use crate::{
+    database::{DatabasePool, DownloadJournalEntry},
+    fs::{OpenMode, TreeFile, TreePath},
+    node::unix_epoch_now_secs,
+};
+use anyhow::Context;
+use iroh::EndpointId;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// How often the length of a partial file is checkpointed while it downloads.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A download that didn't finish before the app stopped, whose partial file can be resumed.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct InterruptedDownloadModel {
+    /// The server the file was being downloaded from.
+    pub endpoint_id: String,
+    pub root: String,
+    pub path: String,
+    pub local_tree: String,
+    pub local_path: String,
+    /// Bytes kept in the partial file, which won't be downloaded again.
+    pub bytes_written: u64,
+    pub expected_size: Option<u64>,
+    pub started_at: u64,
+}
+
+impl From<DownloadJournalEntry> for InterruptedDownloadModel {
+    fn from(entry: DownloadJournalEntry) -> Self {
+        Self {
+            endpoint_id: entry.node_id.to_string(),
+            root: entry.root,
+            path: entry.path,
+            local_tree: entry.local_tree,
+            local_path: entry.local_path,
+            bytes_written: entry.bytes_written,
+            expected_size: entry.expected_size,
+            started_at: entry.started_at,
+        }
+    }
+}
+
+/// What happens to the partial files in the download directory that aren't in the journal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, uniffi::Enum)]
+pub enum PartialFilePolicy {
+    /// Delete them at startup.
+    #[default]
+    Delete,
+    /// Keep them, so downloading the same file to the same path again resumes from them if the
+    /// server's file starts with the same bytes.
+    Resume,
+}
+
+/// Result of cleaning up the partial files that aren't in the journal.
+#[derive(Debug, Clone, Default, PartialEq, Eq, uniffi::Record)]
+pub struct PartialCleanupModel {
+    pub deleted_files: u32,
+    /// Space freed by deleting them.
+    pub reclaimed_bytes: u64,
+    /// Number of partial files kept to resume from with [`PartialFilePolicy::Resume`].
+    pub kept_files: u32,
+    pub kept_bytes: u64,
+}
+
+/// Periodically checkpoints the length of a download's partial file until dropped.
+pub(crate) struct Checkpoints {
+    task: JoinHandle<()>,
+}
+
+impl Checkpoints {
+    pub(crate) fn spawn(db: Arc<DatabasePool>, local_path: &TreePath) -> anyhow::Result<Self> {
+        let temp_path = TreeFile::atomic_temp_path(local_path)?;
+        let local_tree = local_path.root().to_string();
+        let local_path = local_path.path().into_owned();
+
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECKPOINT_INTERVAL);
+            // the first tick is immediate, and the entry already has the starting length
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+
+                let bytes_written = match crate::fs::metadata(&temp_path).await {
+                    Ok(metadata) => metadata.len,
+                    Err(e) => {
+                        warn!("failed to checkpoint partial file: {e:#}");
+                        continue;
+                    }
+                };
+
+                // getting a connection can block, so keep it off the async threads
+                let db = db.clone();
+                let local_tree = local_tree.clone();
+                let local_path = local_path.clone();
+                let res = tokio::task::spawn_blocking(move || {
+                    let db = db.get();
+                    db.update_download_journal_progress(
+                        &local_tree,
+                        &local_path,
+                        bytes_written,
+                        unix_epoch_now_secs(),
+                    )
+                })
+                .await;
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("failed to checkpoint download journal: {e:#}"),
+                    Err(e) => warn!("failed to checkpoint download journal: {e}"),
+                }
+            }
+        });
+
+        Ok(Self { task })
+    }
+}
+
+impl Drop for Checkpoints {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Cleans up the partial files of the downloads left in the journal, and marks the ones that can
+/// be resumed as interrupted.
+///
+/// Partial files that are empty, missing, or longer than the file they're for are removed, and
+/// ones that grew after their last checkpoint are truncated to it, since the bytes after it might
+/// not have been written completely when the app stopped.
+pub(crate) async fn recover(db: &DatabasePool) -> anyhow::Result<()> {
+    let entries = {
+        let db = db.get();
+        db.get_download_journal(false)
+            .context("failed to get download journal")?
+    };
+
+    for entry in entries {
+        match recover_entry(&entry).await {
+            Ok(Some(bytes_written)) => {
+                let db = db.get();
+                db.update_download_journal_progress(
+                    &entry.local_tree,
+                    &entry.local_path,
+                    bytes_written,
+                    entry.updated_at,
+                )?;
+            }
+            Ok(None) => {
+                let db = db.get();
+                db.delete_download_journal_entry(&entry.local_tree, &entry.local_path)?;
+            }
+            Err(e) => {
+                warn!(
+                    "failed to recover partial download of {}/{}: {e:#}",
+                    entry.root, entry.path
+                );
+            }
+        }
+    }
+
+    let db = db.get();
+    db.set_downloads_interrupted(None, true)?;
+
+    Ok(())
+}
+
+/// Cleans up the partial file of a journaled download. Returns the length of the partial file to
+/// resume from, or None if it was removed.
+async fn recover_entry(entry: &DownloadJournalEntry) -> anyhow::Result<Option<u64>> {
+    let local_path = TreePath::new(entry.local_tree.clone(), entry.local_path.clone().into())?;
+    let temp_path = TreeFile::atomic_temp_path(&local_path)?;
+
+    // the file might have been moved into place just before the app stopped
+    if !temp_path.exists() {
+        return Ok(None);
+    }
+
+    let len = crate::fs::metadata(&temp_path).await?.len;
+    let keep = len.min(entry.bytes_written);
+    if keep == 0
+        || entry
+            .expected_size
+            .is_some_and(|expected_size| len > expected_size)
+    {
+        debug!("removing partial file {temp_path:?}");
+        crate::fs::remove_file(&temp_path).await?;
+        return Ok(None);
+    }
+
+    if len > keep {
+        debug!("truncating partial file {temp_path:?} from {len} to {keep} bytes");
+        let mut file = TreeFile::open(&temp_path, OpenMode::Append).await?;
+        file.set_len(keep).await?;
+    }
+
+    Ok(Some(keep))
+}
+
+/// Removes the partial files of the interrupted downloads, optionally only from one server, and
+/// their journal entries. Returns the number of downloads discarded.
+pub(crate) async fn discard(db: &DatabasePool, node_id: Option<EndpointId>) -> anyhow::Result<u32> {
+    let entries = {
+        let db = db.get();
+        db.get_download_journal(true)
+            .context("failed to get interrupted downloads")?
+    };
+
+    let mut discarded = 0;
+    for entry in entries {
+        if node_id.is_some_and(|node_id| node_id != entry.node_id) {
+            continue;
+        }
+
+        let temp_path = TreePath::new(entry.local_tree.clone(), entry.local_path.clone().into())
+            .and_then(|local_path| TreeFile::atomic_temp_path(&local_path))?;
+        if temp_path.exists()
+            && let Err(e) = crate::fs::remove_file(&temp_path).await
+        {
+            warn!("failed to remove partial file {temp_path:?}: {e:#}");
+            continue;
+        }
+
+        let db = db.get();
+        db.delete_download_journal_entry(&entry.local_tree, &entry.local_path)?;
+        discarded += 1;
+    }
+
+    Ok(discarded)
+}
+
+/// Finds the partial files in the download directory that don't belong to a journaled download,
+/// and deletes them or keeps them per the policy. Must run before downloads start, since the
+/// partial files of running downloads would look orphaned until they're journaled.
+pub(crate) async fn clean_orphans(
+    db: &DatabasePool,
+    download_directory: &str,
+    policy: PartialFilePolicy,
+) -> anyhow::Result<PartialCleanupModel> {
+    let journaled = {
+        let db = db.get();
+        db.get_download_journal(false)
+            .context("failed to get download journal")?
+    }
+    .into_iter()
+    .filter(|entry| entry.local_tree == download_directory)
+    .map(|entry| {
+        TreePath::new(entry.local_tree, entry.local_path.into())
+            .and_then(|local_path| TreeFile::atomic_temp_path(&local_path))
+            .map(|temp_path| temp_path.path().into_owned())
+    })
+    .collect::<anyhow::Result<HashSet<_>>>()?;
+
+    let root = TreePath::from_root(download_directory.to_string())?;
+    let files = crate::fs::walk_files(&root)
+        .await
+        .context("failed to list files")?;
+
+    let mut result = PartialCleanupModel::default();
+    for file in files {
+        if !is_partial_file(&file) || journaled.contains(file.path().as_ref()) {
+            continue;
+        }
+
+        let len = match crate::fs::metadata(&file).await {
+            Ok(metadata) => metadata.len,
+            Err(e) => {
+                warn!("failed to read metadata of partial file {file:?}: {e:#}");
+                continue;
+            }
+        };
+        match policy {
+            PartialFilePolicy::Delete => {
+                if let Err(e) = crate::fs::remove_file(&file).await {
+                    warn!("failed to remove partial file {file:?}: {e:#}");
+                    continue;
+                }
+                debug!("removed orphaned partial file {file:?}");
+                result.deleted_files += 1;
+                result.reclaimed_bytes += len;
+            }
+            PartialFilePolicy::Resume => {
+                result.kept_files += 1;
+                result.kept_bytes += len;
+            }
+        }
+    }
+
+    if result.deleted_files > 0 || result.kept_files > 0 {
+        info!(
+            "cleaned up orphaned partial files: deleted {} ({} bytes), kept {} ({} bytes)",
+            result.deleted_files, result.reclaimed_bytes, result.kept_files, result.kept_bytes
+        );
+    }
+
+    Ok(result)
+}
+
+/// Checks whether a file is named like the partial files of atomic writes, `.<name>.part`.
+fn is_partial_file(path: &TreePath) -> bool {
+    path.file_name().is_some_and(|file_name| {
+        file_name
+            .strip_prefix('.')
+            .and_then(|name| name.strip_suffix(".part"))
+            .is_some_and(|name| !name.is_empty())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(local_tree: &str, local_path: &str, bytes_written: u64) -> DownloadJournalEntry {
+        let node_id = iroh::SecretKey::generate().public();
+        DownloadJournalEntry {
+            node_id,
+            file_node_id: node_id,
+            root: "music".to_string(),
+            path: local_path.to_string(),
+            local_tree: local_tree.to_string(),
+            local_path: local_path.to_string(),
+            expected_size: Some(100),
+            expected_checksum: None,
+            bytes_written,
+            started_at: 0,
+            updated_at: 0,
+            interrupted: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let dir = testdir::testdir!();
+        let tree = dir.to_string_lossy().into_owned();
+        let db = DatabasePool::open_in_memory().unwrap();
+
+        // grew after the last checkpoint
+        std::fs::write(dir.join(".a.flac.part"), [1; 60]).unwrap();
+        // nothing checkpointed yet
+        std::fs::write(dir.join(".b.flac.part"), [1; 10]).unwrap();
+        // longer than the file it's for
+        std::fs::write(dir.join(".c.flac.part"), [1; 120]).unwrap();
+        // already moved into place
+        std::fs::write(dir.join("d.flac"), [1; 100]).unwrap();
+        {
+            let db = db.get();
+            for entry in [
+                entry(&tree, "a.flac", 40),
+                entry(&tree, "b.flac", 0),
+                entry(&tree, "c.flac", 100),
+                entry(&tree, "d.flac", 100),
+            ] {
+                db.upsert_download_journal_entry(&entry).unwrap();
+            }
+        }
+
+        recover(&db).await.unwrap();
+
+        let interrupted = db.get().get_download_journal(true).unwrap();
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].local_path, "a.flac");
+        assert_eq!(interrupted[0].bytes_written, 40);
+        assert_eq!(
+            std::fs::metadata(dir.join(".a.flac.part")).unwrap().len(),
+            40
+        );
+        assert!(!dir.join(".b.flac.part").exists());
+        assert!(!dir.join(".c.flac.part").exists());
+        assert!(dir.join("d.flac").exists());
+
+        assert_eq!(discard(&db, None).await.unwrap(), 1);
+        assert!(!dir.join(".a.flac.part").exists());
+        assert!(db.get().get_download_journal(false).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_clean_orphans() {
+        let dir = testdir::testdir!();
+        let tree = dir.to_string_lossy().into_owned();
+        let db = DatabasePool::open_in_memory().unwrap();
+
+        std::fs::create_dir_all(dir.join("album")).unwrap();
+        // journaled, so it can be resumed
+        std::fs::write(dir.join(".a.flac.part"), [1; 40]).unwrap();
+        // orphaned
+        std::fs::write(dir.join("album").join(".b.flac.part"), [1; 30]).unwrap();
+        // not partial files
+        std::fs::write(dir.join("c.flac"), [1; 100]).unwrap();
+        std::fs::write(dir.join(".part"), [1; 10]).unwrap();
+        db.get()
+            .upsert_download_journal_entry(&entry(&tree, "a.flac", 40))
+            .unwrap();
+
+        let result = clean_orphans(&db, &tree, PartialFilePolicy::Resume)
+            .await
+            .unwrap();
+        assert_eq!(result.deleted_files, 0);
+        assert_eq!((result.kept_files, result.kept_bytes), (1, 30));
+        assert!(dir.join("album").join(".b.flac.part").exists());
+
+        let result = clean_orphans(&db, &tree, PartialFilePolicy::Delete)
+            .await
+            .unwrap();
+        assert_eq!((result.deleted_files, result.reclaimed_bytes), (1, 30));
+        assert!(!dir.join("album").join(".b.flac.part").exists());
+        assert!(dir.join(".a.flac.part").exists());
+        assert!(dir.join("c.flac").exists());
+        assert!(dir.join(".part").exists());
+    }
+}
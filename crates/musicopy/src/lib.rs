@@ -1,31 +1,75 @@
 pub mod database;
+pub mod deep_link;
 pub mod device_name;
+pub mod diagnostics;
+pub mod downloads;
 pub mod error;
 pub mod file_dialog;
 pub mod fs;
+pub mod journal;
 pub mod library;
 pub mod logging;
+pub mod metrics;
 pub mod model;
 pub mod node;
+pub mod node_settings;
+pub mod operation;
+pub mod pairing;
+pub mod playlist;
+pub mod profile;
 pub mod protocol;
+pub mod sas;
+pub mod schedule;
+pub mod settings;
+pub mod share;
+pub mod status;
+pub mod sync_group;
 
 use crate::{
-    database::Database,
-    error::{CoreError, core_error},
+    database::DatabasePool,
+    deep_link::{DeepLink, DeepLinkModel},
+    downloads::{
+        DeletedDownloadsModel, DownloadedFileModel, DownloadedFileSelectionModel,
+        DownloadedServerModel, ImportedDownloadsModel, ReorganizedDownloadsModel,
+    },
+    error::{ConnectionError, CoreError, LibraryError, TransferError, core_error},
+    fs::template::PathTemplate,
     library::{
-        Library, LibraryCommand, LibraryModel,
+        Library, LibraryAlbumModel, LibraryCommand, LibraryModel, LibraryModelDiff,
+        LibraryTrackModel, VersionedLibraryModel,
         hash::HashCache,
+        suggest::{self, SuggestedRootModel},
         transcode::{TranscodeFormat, TranscodeStatusCache},
     },
-    node::{DownloadRequestModel, Node, NodeCommand, NodeModel},
+    logging::{LogEntryModel, LogFilter},
+    metrics::MetricsSnapshot,
+    node::{
+        AutoDownloadModel, BackgroundProgressEvent, ChangedDownloadModel, ClientStateModel,
+        CollisionPolicy, ConnectionLostEvent, DownloadFilterModel, DownloadIssueModel,
+        DownloadProgressModel, DownloadRequestModel, DownloadSelectionModel, MirrorDeletionModel,
+        Node, NodeCommand, NodeModel, NodeModelDiff, NodeShareModel, RelayConfig, RemoteAlbumModel,
+        ServerStateModel, ShareFilterModel, TransferJobFailedEvent, TransferSessionCompletedEvent,
+        TransferSessionFileModel, TransferSessionModel, VersionedNodeModel,
+    },
+    operation::{
+        DownloadResultModel, OperationEvent, OperationProgress, OperationProgressHandler,
+        PROGRESS_INTERVAL, ScanResultModel, connection_closed_error,
+    },
+    pairing::{PairingTicket, PairingToken},
+    protocol::SyncConflictPolicy,
+    schedule::{DeviceState, MeteredPolicy, NetworkState, TransferSchedule},
+    settings::SettingsModel,
+    status::StatusModel,
 };
 use anyhow::Context;
 use iroh::{EndpointAddr, EndpointId, SecretKey};
 use std::{
+    collections::HashSet,
     path::PathBuf,
     sync::{Arc, Mutex},
     time::Duration,
 };
+use tokio::sync::broadcast::{self, error::RecvError};
 use tracing::{debug, error, info, trace, warn};
 
 uniffi::setup_scaffolding!();
@@ -48,19 +92,137 @@ pub trait EventHandler: Send + Sync {
     fn on_library_model_snapshot(&self, model: LibraryModel);
     fn on_node_model_snapshot(&self, model: NodeModel);
     fn on_stats_model_snapshot(&self, model: StatsModel);
+    fn on_settings_model_snapshot(&self, model: SettingsModel);
+
+    /// Called instead of [`EventHandler::on_library_model_snapshot`] when model diffs are enabled.
+    fn on_library_model_diff(&self, diff: LibraryModelDiff);
+    /// Called instead of [`EventHandler::on_node_model_snapshot`] when model diffs are enabled.
+    fn on_node_model_diff(&self, diff: NodeModelDiff);
+
+    /// Called when all the downloads from a server have finished or failed.
+    fn on_transfer_session_completed(&self, event: TransferSessionCompletedEvent);
+    /// Called when a download fails, unless it was cancelled.
+    fn on_transfer_job_failed(&self, event: TransferJobFailedEvent);
+    /// Called when a connection closes with an error.
+    fn on_connection_lost(&self, event: ConnectionLostEvent);
+    /// Called at startup if the database was corrupted and had to be recovered.
+    fn on_database_recovered(&self, event: DatabaseRecoveredEvent);
+    /// Called as a shutdown started by `Core::shutdown_with_deadline` progresses.
+    fn on_shutdown_progress(&self, event: ShutdownProgressEvent);
+    /// Called instead of the model callbacks while the app is in the background, when the
+    /// progress of the transfers changes.
+    fn on_background_progress(&self, event: BackgroundProgressEvent);
+}
+
+/// Event sent at startup when the database file was corrupted. The corrupted file is moved aside
+/// and as much of it as possible is copied into a new database.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct DatabaseRecoveredEvent {
+    /// The problems found by the integrity check.
+    pub problems: Vec<String>,
+    /// Where the corrupted database was moved to.
+    pub backup_path: String,
+    /// Tables whose data was lost, or empty if everything was recovered.
+    pub lost_tables: Vec<String>,
+}
+
+/// A step of a graceful shutdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum ShutdownPhase {
+    /// Waiting for active transfers and transcodes to finish.
+    Draining,
+    /// Writing pending changes to the database file.
+    FlushingDatabase,
+    /// The core has stopped, so the process can exit.
+    Stopped,
 }
 
-#[derive(Debug, uniffi::Record)]
+/// Event sent as a graceful shutdown progresses, e.g. to update a notification while the OS waits
+/// for the app.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ShutdownProgressEvent {
+    pub phase: ShutdownPhase,
+    /// Number of connections that haven't closed yet.
+    pub open_connections: u32,
+    /// Number of transcodes still running.
+    pub active_transcodes: u32,
+    /// Whether a library scan is still running.
+    pub scanning: bool,
+    /// Seconds left until the deadline.
+    pub remaining_secs: u64,
+    /// Whether the deadline passed before the transfers, transcodes, and scan finished. Interrupted
+    /// downloads keep their partial files and resume later.
+    pub deadline_passed: bool,
+}
+
+/// How often a graceful shutdown checks whether the active work has finished.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Foreign trait implemented in Swift for refreshing stale iOS bookmarks.
+#[uniffi::export(with_foreign)]
+pub trait IosBookmarkResolver: Send + Sync {
+    /// Creates a fresh base64-encoded bookmark for the given path, which a stale
+    /// bookmark resolved to. The UI should also replace its stored copy of the
+    /// stale bookmark. Returns None if the bookmark couldn't be created.
+    fn refresh_bookmark(&self, stale_bookmark: String, path: String) -> Option<String>;
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
 pub struct ProjectDirsOptions {
     pub data_dir: String,
     pub cache_dir: String,
 }
 
-#[derive(Debug, uniffi::Record)]
+#[derive(Debug, Clone, uniffi::Record)]
 pub struct CoreOptions {
     pub init_logging: bool,
     pub in_memory: bool,
     pub project_dirs: Option<ProjectDirsOptions>,
+    /// Which relays to use, or None to use the default relays.
+    #[uniffi(default = None)]
+    pub relay_config: Option<RelayConfig>,
+    /// Whether to only connect to peers on the local network, without using relays or address
+    /// lookup services. Peers have to be reached by their local addresses, e.g. from a pairing
+    /// ticket.
+    #[uniffi(default = false)]
+    pub lan_only: bool,
+    /// Local socket addresses to bind the endpoint to, like `0.0.0.0:41641`, instead of all
+    /// interfaces on random ports. Pinning a port lets it be forwarded through a strict firewall.
+    #[uniffi(default = None)]
+    pub bind_addrs: Option<Vec<String>>,
+    /// Whether to send changes to the library and node models as diffs of the parts that changed,
+    /// instead of full snapshots. The UI should apply diffs to a snapshot fetched with
+    /// `get_library_model_versioned` and `get_node_model_versioned`, and fetch again if it misses
+    /// a version.
+    #[uniffi(default = false)]
+    pub model_diffs: bool,
+    /// Name of the profile to start, or None for the default profile. Each profile has its own
+    /// database, identity, library roots, trusted nodes, and transcodes. See `list_profiles`.
+    #[uniffi(default = None)]
+    pub profile: Option<String>,
+}
+
+/// How long active transfers can take to finish when switching profiles.
+const PROFILE_SWITCH_DEADLINE_SECS: u64 = 10;
+
+/// Returns the data and cache directories to use, which are the platform's directories unless
+/// they're overridden.
+pub(crate) fn project_dirs(
+    project_dirs: Option<&ProjectDirsOptions>,
+) -> anyhow::Result<(PathBuf, PathBuf)> {
+    match project_dirs {
+        Some(project_dirs) => Ok((
+            PathBuf::from(&project_dirs.data_dir),
+            PathBuf::from(&project_dirs.cache_dir),
+        )),
+        None => {
+            let project_dirs = directories_next::ProjectDirs::from("", "", "musicopy")
+                .context("failed to get project directories")?;
+            let data_dir = project_dirs.data_local_dir().to_owned();
+            let cache_dir = project_dirs.cache_dir().to_owned();
+            Ok((data_dir, cache_dir))
+        }
+    }
 }
 
 /// Long-lived object created by Compose as the entry point to the Rust core.
@@ -70,13 +232,19 @@ pub struct CoreOptions {
 /// and queries from the UI.
 #[derive(uniffi::Object)]
 pub struct Core {
-    db: Arc<Mutex<Database>>,
+    event_handler: Arc<dyn EventHandler>,
+    db: Arc<DatabasePool>,
+    settings: Mutex<SettingsModel>,
 
     node: Arc<Node>,
     library: Arc<Library>,
 
+    /// The options the core was started with, to start another profile with.
+    options: CoreOptions,
+
     log_dir: Option<PathBuf>,
-    _log_guard: Option<tracing_appender::non_blocking::WorkerGuard>,
+    /// Flushes the log files when dropped. Handed over to the new core when switching profiles.
+    log_guard: Mutex<Option<tracing_appender::non_blocking::WorkerGuard>>,
 }
 
 // stub debug implementation
@@ -100,19 +268,7 @@ impl Core {
         let dirs: Option<(PathBuf, PathBuf)> = if options.in_memory {
             None
         } else {
-            let (data_dir, cache_dir) = match &options.project_dirs {
-                Some(project_dirs) => (
-                    PathBuf::from(&project_dirs.data_dir),
-                    PathBuf::from(&project_dirs.cache_dir),
-                ),
-                None => {
-                    let project_dirs = directories_next::ProjectDirs::from("", "", "musicopy")
-                        .context("failed to get project directories")?;
-                    let data_dir = project_dirs.data_local_dir().to_owned();
-                    let cache_dir = project_dirs.cache_dir().to_owned();
-                    (data_dir, cache_dir)
-                }
-            };
+            let (data_dir, cache_dir) = project_dirs(options.project_dirs.as_ref())?;
 
             // try to create data and cache dirs
             if let Err(e) = std::fs::create_dir_all(&data_dir) {
@@ -138,10 +294,24 @@ impl Core {
             None
         };
 
-        debug!("core: starting core");
+        // named profiles live in subdirectories
+        let dirs = match dirs {
+            Some((data_dir, cache_dir)) => {
+                let (data_dir, cache_dir) =
+                    profile::dirs(&data_dir, &cache_dir, options.profile.as_deref())?;
+                std::fs::create_dir_all(&data_dir)
+                    .context("failed to create profile data directory")?;
+                std::fs::create_dir_all(&cache_dir)
+                    .context("failed to create profile cache directory")?;
+                Some((data_dir, cache_dir))
+            }
+            None => None,
+        };
+
+        debug!("core: starting core with profile {:?}", options.profile);
 
         let (db, secret_key, transcodes_dir) = if options.in_memory {
-            let db = Database::open_in_memory().context("failed to open database")?;
+            let db = DatabasePool::open_in_memory().context("failed to open database")?;
 
             let secret_key = SecretKey::generate();
 
@@ -158,7 +328,15 @@ impl Core {
             let (data_dir, cache_dir) = dirs.unwrap();
 
             let db_path = data_dir.join("musicopy_v1.db");
-            let db = Database::open_file(&db_path).context("failed to open database")?;
+            let (db, recovery) =
+                DatabasePool::open_file(&db_path).context("failed to open database")?;
+            if let Some(recovery) = recovery {
+                event_handler.on_database_recovered(DatabaseRecoveredEvent {
+                    problems: recovery.problems,
+                    backup_path: recovery.backup_path.to_string_lossy().to_string(),
+                    lost_tables: recovery.lost_tables,
+                });
+            }
 
             let key_path = data_dir.join("secret_key");
             let secret_key = if key_path.exists() {
@@ -180,17 +358,27 @@ impl Core {
 
             (db, secret_key, transcodes_dir)
         };
-        let db = Arc::new(Mutex::new(db));
+        let db = Arc::new(db);
+
+        let settings = {
+            let db = db.get();
+            SettingsModel::load(&db).context("failed to load settings")?
+        };
 
         let transcode_status_cache = TranscodeStatusCache::new();
         let hash_cache = HashCache::new(db.clone());
 
         let endpoint_id = EndpointId::from(secret_key.public());
+        let relay_config = options.relay_config.clone().unwrap_or_default();
+        let lan_only = options.lan_only;
+        let bind_addrs = options.bind_addrs.clone().unwrap_or_default();
+        let model_diffs = options.model_diffs;
 
         let (res_tx, res_rx) = tokio::sync::oneshot::channel();
 
         // spawn node thread
         std::thread::spawn({
+            let event_handler = event_handler.clone();
             let db = db.clone();
             move || {
                 let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
@@ -212,13 +400,18 @@ impl Core {
                                 transcodes_dir.clone(),
                                 transcode_status_cache.clone(),
                                 hash_cache.clone(),
+                                model_diffs,
                             ),
                             Node::new(
                                 event_handler,
                                 secret_key,
+                                relay_config,
+                                lan_only,
+                                bind_addrs,
                                 db,
                                 transcode_status_cache,
                                 hash_cache,
+                                model_diffs,
                                 #[cfg(feature = "test-hooks")]
                                 test_hooks,
                             ),
@@ -299,13 +492,165 @@ impl Core {
             })?
             .context("core components failed to initialize")?;
 
-        Ok(Arc::new(Self {
+        let core = Arc::new(Self {
+            event_handler,
             db,
+            settings: Mutex::new(settings.clone()),
             library,
             node,
+            options,
             log_dir,
-            _log_guard: log_guard,
-        }))
+            log_guard: Mutex::new(log_guard),
+        });
+
+        // apply the stored settings
+        core.apply_settings(&settings)?;
+
+        Ok(core)
+    }
+
+    /// Counts the connections that haven't closed yet, for shutting down.
+    fn count_open_connections(&self) -> u32 {
+        let model = self.node.get_model();
+        let servers = model
+            .servers
+            .values()
+            .filter(|server| !matches!(server.state, ServerStateModel::Closed { .. }))
+            .count();
+        let clients = model
+            .clients
+            .values()
+            .filter(|client| !matches!(client.state, ClientStateModel::Closed { .. }))
+            .count();
+        (servers + clients) as u32
+    }
+
+    /// Sends settings to the components that use them.
+    fn apply_settings(&self, settings: &SettingsModel) -> Result<(), CoreError> {
+        let template = settings
+            .download_path_template
+            .as_deref()
+            .map(PathTemplate::parse)
+            .transpose()
+            .context(TransferError::InvalidPathTemplate)?;
+
+        self.library
+            .send(LibraryCommand::SetTranscodePolicy(
+                settings.transcode_policy,
+                settings.transcode_format,
+            ))
+            .context("failed to send to library")?;
+        self.library
+            .send(LibraryCommand::SetTranscodeWorkers(
+                settings.transcode_workers,
+            ))
+            .context("failed to send to library")?;
+
+        // the policy is used when the download directory is first set, to clean up partial files
+        for command in [
+            NodeCommand::SetPartialFilePolicy(settings.partial_file_policy),
+            NodeCommand::SetDownloadDirectory(settings.download_directory.clone()),
+            NodeCommand::SetDownloadPathTemplate(template),
+            NodeCommand::SetPlaylistSettings(settings.playlists),
+            NodeCommand::SetMaxConcurrentTransfers(settings.max_concurrent_transfers),
+            NodeCommand::SetMaxTotalDownloads(settings.max_total_downloads),
+            NodeCommand::SetMaxDownloadRate(settings.max_download_rate),
+            NodeCommand::SetMaxUploadRatePerClient(settings.max_upload_rate_per_client),
+            NodeCommand::SetNetworkPolicy(settings.network_policy()),
+            NodeCommand::SetAcceptIncoming(settings.accept_incoming),
+            NodeCommand::SetTransferHistoryRetention(settings.transfer_history_retention()),
+        ] {
+            self.node
+                .send(command)
+                .context("failed to send to node thread")?;
+        }
+
+        Ok(())
+    }
+
+    /// Changes some settings, then stores and applies them. The lock is held throughout, so
+    /// concurrent changes are applied in the order they're stored.
+    fn modify_settings(&self, f: impl FnOnce(&mut SettingsModel)) -> Result<(), CoreError> {
+        let mut current = self.settings.lock().unwrap();
+
+        let mut settings = current.clone();
+        f(&mut settings);
+        let settings = settings.validate()?;
+
+        {
+            let mut db = self.db.get();
+            settings.save(&mut db).context("failed to save settings")?;
+        }
+        self.apply_settings(&settings)?;
+
+        *current = settings.clone();
+        drop(current);
+
+        self.event_handler.on_settings_model_snapshot(settings);
+
+        Ok(())
+    }
+
+    /// Connects to a node at the given address.
+    async fn connect_addr(
+        &self,
+        transcode_format: Option<TranscodeFormat>,
+        addr: EndpointAddr,
+        pairing_token: Option<PairingToken>,
+    ) -> Result<(), CoreError> {
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::Connect {
+                transcode_format,
+                addr,
+                pairing_token,
+                callback: callback_tx,
+            })
+            .context("failed to send to node thread")?;
+
+        async_std::future::timeout(Duration::from_secs(10), callback_rx)
+            .await
+            .map_err(|_elapsed| ConnectionError::TimedOut)?
+            .map_err(|_dropped| core_error!("connect failed, sender dropped"))?
+            .map_err(CoreError::from)
+    }
+
+    /// Waits for the connection to a server to be accepted and its index to arrive.
+    async fn wait_for_client_ready(
+        &self,
+        endpoint_id: EndpointId,
+        events: &mut broadcast::Receiver<OperationEvent>,
+    ) -> Result<(), CoreError> {
+        loop {
+            match events.recv().await {
+                Ok(OperationEvent::ClientReady(id)) if id == endpoint_id => return Ok(()),
+                Ok(OperationEvent::ClientClosed {
+                    endpoint_id: id,
+                    error,
+                }) if id == endpoint_id => {
+                    return Err(connection_closed_error(error));
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => {
+                    // some events were missed, so check the connection's state instead
+                    match self.node.get_client_state(endpoint_id) {
+                        Some((_, true)) => return Ok(()),
+                        Some((ClientStateModel::Closed { error }, _)) => {
+                            return Err(connection_closed_error(error));
+                        }
+                        Some(_) => {}
+                        None => {
+                            return Err(ConnectionError::NotConnected {
+                                endpoint_id: endpoint_id.to_string(),
+                            }
+                            .into());
+                        }
+                    }
+                }
+                Err(RecvError::Closed) => return Err(core_error!("node stopped")),
+            }
+        }
     }
 
     /// Get an EndpointAddr to use with MemoryLookup in tests
@@ -347,6 +692,8 @@ impl Core {
         .await
     }
 
+    /// Stops the core immediately, closing connections even if transfers are
+    /// active. See `shutdown_with_deadline` to let them finish first.
     pub fn shutdown(&self) -> Result<(), CoreError> {
         debug!("core: shutting down");
 
@@ -361,22 +708,254 @@ impl Core {
         Ok(())
     }
 
+    /// Gets the name of the running profile, or None for the default profile.
+    pub fn get_profile(&self) -> Option<String> {
+        self.options.profile.clone()
+    }
+
+    /// Switches to another profile, or to the default profile if None.
+    ///
+    /// This core is shut down, giving active transfers a few seconds to
+    /// finish, and a new core is started with the same options and event
+    /// handler. The returned core replaces this one, which can't be used
+    /// anymore. The profile is created if it doesn't exist yet.
+    ///
+    /// If the new profile fails to start, this core's profile is started
+    /// again and returned instead, so the app keeps a running core. Check
+    /// `get_profile` on the returned core to tell whether the switch worked.
+    pub async fn switch_profile(&self, profile: Option<String>) -> Result<Arc<Core>, CoreError> {
+        if let Some(name) = &profile {
+            profile::validate_name(name)?;
+        }
+
+        info!(
+            "core: switching from profile {:?} to {profile:?}",
+            self.options.profile
+        );
+        self.shutdown_with_deadline(PROFILE_SWITCH_DEADLINE_SECS)
+            .await?;
+
+        let options = CoreOptions {
+            // logging is already initialized, and the guard is handed over below
+            init_logging: false,
+            profile,
+            ..self.options.clone()
+        };
+        let core = match Self::start_inner(
+            self.event_handler.clone(),
+            options.clone(),
+            #[cfg(feature = "test-hooks")]
+            Arc::new(TestHooks::default()),
+        )
+        .await
+        {
+            Ok(core) => core,
+            Err(e) => {
+                error!(
+                    "core: failed to start profile {:?}, restarting profile {:?}: {e:#}",
+                    options.profile, self.options.profile
+                );
+                Self::start_inner(
+                    self.event_handler.clone(),
+                    CoreOptions {
+                        init_logging: false,
+                        ..self.options.clone()
+                    },
+                    #[cfg(feature = "test-hooks")]
+                    Arc::new(TestHooks::default()),
+                )
+                .await?
+            }
+        };
+        *core.log_guard.lock().unwrap() = self.log_guard.lock().unwrap().take();
+
+        Ok(core)
+    }
+
+    /// Shuts down gracefully, e.g. when the OS is about to suspend the app.
+    ///
+    /// Stops opening and accepting connections, scanning, and starting
+    /// transcodes, then waits until the active transfers, transcodes, and
+    /// scan finish or the deadline passes. Downloads interrupted by the deadline
+    /// keep their partial files, so they can resume later. The database is
+    /// then flushed and the core stops. Progress is reported with
+    /// `on_shutdown_progress`.
+    pub async fn shutdown_with_deadline(&self, deadline_secs: u64) -> Result<(), CoreError> {
+        debug!("core: shutting down with a deadline of {deadline_secs}s");
+
+        let deadline = Duration::from_secs(deadline_secs);
+        let start = std::time::Instant::now();
+
+        self.library
+            .send(LibraryCommand::Drain)
+            .context("failed to send to library thread")?;
+        self.node
+            .send(NodeCommand::DrainAll { timeout: deadline })
+            .context("failed to send to node thread")?;
+
+        // wait for the active work to finish, reporting progress when it changes
+        let mut last_reported = None;
+        let (open_connections, active_transcodes, scanning, deadline_passed) = loop {
+            let open_connections = self.count_open_connections();
+            let library = self.library.get_model();
+            let active_transcodes = library.transcode_count_inprogress.get() as u32;
+            let scanning = library.is_scanning;
+
+            if open_connections == 0 && active_transcodes == 0 && !scanning {
+                break (open_connections, active_transcodes, scanning, false);
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= deadline {
+                warn!(
+                    "core: shutdown deadline passed with {open_connections} connections and {active_transcodes} transcodes active, scanning: {scanning}"
+                );
+                break (open_connections, active_transcodes, scanning, true);
+            }
+
+            if last_reported != Some((open_connections, active_transcodes, scanning)) {
+                last_reported = Some((open_connections, active_transcodes, scanning));
+                self.event_handler
+                    .on_shutdown_progress(ShutdownProgressEvent {
+                        phase: ShutdownPhase::Draining,
+                        open_connections,
+                        active_transcodes,
+                        scanning,
+                        remaining_secs: (deadline - elapsed).as_secs(),
+                        deadline_passed: false,
+                    });
+            }
+
+            async_std::task::sleep(SHUTDOWN_CHECK_INTERVAL).await;
+        };
+
+        let remaining_secs = deadline.saturating_sub(start.elapsed()).as_secs();
+        self.event_handler
+            .on_shutdown_progress(ShutdownProgressEvent {
+                phase: ShutdownPhase::FlushingDatabase,
+                open_connections,
+                active_transcodes,
+                scanning,
+                remaining_secs,
+                deadline_passed,
+            });
+
+        // checkpointing blocks, and this runs on the UI's async runtime
+        let (checkpoint_tx, checkpoint_rx) = tokio::sync::oneshot::channel();
+        std::thread::spawn({
+            let db = self.db.clone();
+            move || {
+                let _ = checkpoint_tx.send(db.get().checkpoint());
+            }
+        });
+        match checkpoint_rx.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("core: failed to flush database: {e:#}"),
+            Err(_) => warn!("core: failed to flush database, checkpoint thread panicked"),
+        }
+
+        self.shutdown()?;
+
+        self.event_handler
+            .on_shutdown_progress(ShutdownProgressEvent {
+                phase: ShutdownPhase::Stopped,
+                open_connections,
+                active_transcodes,
+                scanning,
+                remaining_secs,
+                deadline_passed,
+            });
+
+        Ok(())
+    }
+
     pub fn get_node_model(&self) -> Result<NodeModel, CoreError> {
         Ok(self.node.get_model())
     }
 
+    /// Gets the node model with the version of the last change it includes,
+    /// to apply diffs to.
+    pub fn get_node_model_versioned(&self) -> Result<VersionedNodeModel, CoreError> {
+        Ok(self.node.get_versioned_model())
+    }
+
+    /// Gets the combined progress of the downloads from all connected servers.
+    pub fn get_download_progress(&self) -> Result<DownloadProgressModel, CoreError> {
+        Ok(self.node.get_download_progress())
+    }
+
+    /// Gets a compact summary of the scan state, transcode backlog, active
+    /// transfers, connections, and recent errors. This is much cheaper than
+    /// getting the full models, so widgets and scripts can poll it.
+    pub fn get_status(&self) -> Result<StatusModel, CoreError> {
+        let node = self.node.get_status();
+        let library = self.library.get_status();
+
+        let mut recent_errors = node.recent_errors;
+        recent_errors.extend(library.recent_errors);
+        recent_errors.sort_by_key(|error| std::cmp::Reverse(error.at));
+
+        Ok(StatusModel {
+            endpoint_id: node.endpoint_id,
+
+            is_scanning: library.is_scanning,
+            library_files: library.library_files,
+
+            transcodes_queued: library.transcodes_queued,
+            transcodes_in_progress: library.transcodes_in_progress,
+            transcodes_failed: library.transcodes_failed,
+            transcodes_dir_size: library.transcodes_dir_size,
+
+            open_connections: node.open_connections,
+            pending_connections: node.pending_connections,
+            active_downloads: node.active_downloads,
+            queued_downloads: node.queued_downloads,
+            active_uploads: node.active_uploads,
+            transfer_hold_reasons: node.transfer_hold_reasons,
+
+            recent_errors,
+        })
+    }
+
     pub fn get_library_model(&self) -> Result<LibraryModel, CoreError> {
         Ok(self.library.get_model())
     }
 
+    /// Gets the library model with the version of the last change it includes,
+    /// to apply diffs to.
+    pub fn get_library_model_versioned(&self) -> Result<VersionedLibraryModel, CoreError> {
+        Ok(self.library.get_versioned_model())
+    }
+
+    /// Searches the local library's tracks by title, artist or album, most
+    /// relevant first. Each word matches words that start with it, so this
+    /// can be called as the user types.
+    pub fn search_library(&self, query: &str) -> Result<Vec<LibraryTrackModel>, CoreError> {
+        self.library.search(query).map_err(CoreError::from)
+    }
+
+    /// Lists the albums in the local library, sorted by artist and album.
+    pub fn browse_library(&self) -> Result<Vec<LibraryAlbumModel>, CoreError> {
+        self.library.browse_albums().map_err(CoreError::from)
+    }
+
     pub fn get_stats_model(&self) -> Result<StatsModel, CoreError> {
-        let db = self
-            .db
-            .lock()
-            .map_err(|_| core_error!("failed to lock database"))?;
+        let db = self.db.get();
         db.get_stats().map_err(CoreError::from)
     }
 
+    pub fn get_settings_model(&self) -> Result<SettingsModel, CoreError> {
+        Ok(self.settings.lock().unwrap().clone())
+    }
+
+    /// Replaces the settings, which are stored in the database and applied to the library and
+    /// node. Numbers are clamped to their bounds, and the new settings are sent as a snapshot.
+    ///
+    /// Fails without changing anything if the download path template is invalid, or if
+    /// transcoding ahead of time is enabled without a transcode format.
+    pub fn update_settings(&self, settings: SettingsModel) -> Result<(), CoreError> {
+        self.modify_settings(|current| *current = settings)
+    }
+
     /// Connects to a node.
     ///
     /// Takes the transcode format to send in the initial handshake and use for the connection,
@@ -386,157 +965,1533 @@ impl Core {
         transcode_format: Option<TranscodeFormat>,
         endpoint_id: &str,
     ) -> Result<(), CoreError> {
-        let endpoint_id: EndpointId = endpoint_id.parse().context("failed to parse endpoint id")?;
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
         let node_addr = EndpointAddr::from(endpoint_id);
 
-        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+        self.connect_addr(transcode_format, node_addr, None).await
+    }
 
-        self.node
-            .send(NodeCommand::Connect {
-                transcode_format,
-                addr: node_addr,
-                callback: callback_tx,
-            })
-            .context("failed to send to node thread")?;
+    /// Connects to a node using a pairing ticket created by `create_pairing_ticket`.
+    ///
+    /// If the ticket has an unused one-time token, the server accepts the connection without
+    /// asking.
+    pub async fn connect_with_ticket(
+        &self,
+        transcode_format: Option<TranscodeFormat>,
+        ticket: &str,
+    ) -> Result<(), CoreError> {
+        let ticket =
+            PairingTicket::decode(ticket).context(ConnectionError::InvalidPairingTicket)?;
+        let node_addr = ticket.endpoint_addr()?;
 
-        async_std::future::timeout(Duration::from_secs(10), callback_rx)
+        self.connect_addr(transcode_format, node_addr, ticket.token)
             .await
-            .map_err(|_elapsed| core_error!("connect timed out"))?
-            .map_err(|_dropped| core_error!("connect failed, sender dropped"))?
-            .map_err(CoreError::from)
     }
 
-    pub fn set_download_directory(&self, download_directory: &str) -> Result<(), CoreError> {
-        self.node
-            .send(NodeCommand::SetDownloadDirectory(download_directory.into()))
-            .context("failed to send to node thread")?;
+    /// Connects to a node and waits until it accepts the connection and
+    /// sends its index, so downloads can be requested as soon as this returns.
+    ///
+    /// Unlike `connect`, which returns once the connection is open, this fails
+    /// if the node denies the connection, or doesn't accept it before the
+    /// timeout set with `set_pending_timeout`. Without a pending timeout, this
+    /// waits until the node decides. `progress` is told when the connection is
+    /// waiting to be accepted.
+    pub async fn connect_and_wait(
+        &self,
+        transcode_format: Option<TranscodeFormat>,
+        endpoint_id: &str,
+        progress: Option<Arc<dyn OperationProgressHandler>>,
+    ) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
 
-        Ok(())
+        // subscribe before connecting, so the connection can't be accepted unseen
+        let mut events = self.node.subscribe_operation_events();
+        self.connect_addr(transcode_format, EndpointAddr::from(endpoint_id), None)
+            .await?;
+
+        if let Some(progress) = &progress {
+            progress.on_progress(OperationProgress::ConnectionPending);
+        }
+
+        self.wait_for_client_ready(endpoint_id, &mut events).await
     }
 
-    pub fn set_downloads(
+    /// Creates a short pairing ticket for connecting to this node, to show as
+    /// a QR code or share as text.
+    ///
+    /// If `one_time_token` is set, the first client to connect with the
+    /// ticket is accepted without asking.
+    pub fn create_pairing_ticket(&self, one_time_token: bool) -> Result<String, CoreError> {
+        Ok(self.node.create_pairing_ticket(one_time_token)?)
+    }
+
+    /// Creates a `musicopy://connect/` link with a pairing ticket, which
+    /// opens the app when tapped or scanned as a QR code.
+    pub fn create_pairing_link(&self, one_time_token: bool) -> Result<String, CoreError> {
+        let ticket = self.node.create_pairing_ticket(one_time_token)?;
+        Ok(deep_link::connect_link(&ticket))
+    }
+
+    /// Handles a link the app was opened with, e.g. a `musicopy://connect/`
+    /// link or a scanned pairing ticket.
+    ///
+    /// Connect links start connecting like `connect_with_ticket`, and return
+    /// once the connection is open. Share links can't be handled in the app,
+    /// so they're returned for the UI to open in a browser.
+    pub async fn handle_url(
+        &self,
+        url: String,
+        transcode_format: Option<TranscodeFormat>,
+    ) -> Result<DeepLinkModel, CoreError> {
+        match DeepLink::parse(&url).context(ConnectionError::InvalidLink)? {
+            DeepLink::Connect(ticket) => {
+                let endpoint_id = ticket.endpoint_id.to_string();
+                let node_addr = ticket.endpoint_addr()?;
+                self.connect_addr(transcode_format, node_addr, ticket.token)
+                    .await?;
+                Ok(DeepLinkModel::Connecting { endpoint_id })
+            }
+            DeepLink::ConnectEndpoint(endpoint_id) => {
+                self.connect_addr(transcode_format, EndpointAddr::from(endpoint_id), None)
+                    .await?;
+                Ok(DeepLinkModel::Connecting {
+                    endpoint_id: endpoint_id.to_string(),
+                })
+            }
+            DeepLink::ShareLink { url } => Ok(DeepLinkModel::ShareLink { url }),
+        }
+    }
+
+    /// Creates a sync group of devices that trust each other, with this node
+    /// as its only member. Returns the group's ID.
+    pub fn create_sync_group(&self, name: String) -> Result<String, CoreError> {
+        Ok(self.node.create_sync_group(name)?)
+    }
+
+    /// Adds a node to a sync group, so that it and the group's other members
+    /// accept each other's connections without asking.
+    ///
+    /// The node is invited to the group the next time it connects with this
+    /// node, if it trusts this node, and joins once it accepts. Other members
+    /// learn about it as members connect with each other.
+    pub fn add_sync_group_member(
         &self,
+        group_id: &str,
         endpoint_id: &str,
-        items: Vec<DownloadRequestModel>,
     ) -> Result<(), CoreError> {
-        let endpoint_id: EndpointId = endpoint_id.parse().context("failed to parse endpoint id")?;
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        Ok(self.node.add_sync_group_member(group_id, endpoint_id)?)
+    }
+
+    /// Accepts an invitation to a sync group from a trusted node, joining the
+    /// group so its members accept each other's connections without asking.
+    pub fn accept_sync_group_invitation(&self, group_id: &str) -> Result<(), CoreError> {
+        Ok(self.node.accept_sync_group_invitation(group_id)?)
+    }
+
+    /// Declines an invitation to a sync group.
+    pub fn decline_sync_group_invitation(&self, group_id: &str) -> Result<(), CoreError> {
+        Ok(self.node.decline_sync_group_invitation(group_id)?)
+    }
+
+    /// Leaves a sync group, so its members are no longer trusted through it.
+    pub fn leave_sync_group(&self, group_id: &str) -> Result<(), CoreError> {
+        Ok(self.node.leave_sync_group(group_id)?)
+    }
+
+    /// Starts a lightweight HTTP server for share links on the given address,
+    /// like `0.0.0.0:8080`, and returns the address it's bound to. Restarts
+    /// the server if it's already running.
+    ///
+    /// The server only serves plain HTTP, without TLS. To share links over
+    /// the internet, put it behind a reverse proxy that terminates TLS, and
+    /// set `public_url` to the proxy's HTTPS address so links are built from
+    /// it. Otherwise links are built from the bound address.
+    pub async fn start_share_server(
+        &self,
+        bind_addr: String,
+        public_url: Option<String>,
+    ) -> Result<String, CoreError> {
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
 
         self.node
-            .send(NodeCommand::SetDownloads {
-                client: endpoint_id,
-                items,
+            .send(NodeCommand::StartShareServer {
+                bind_addr,
+                public_url,
+                callback: callback_tx,
             })
             .context("failed to send to node thread")?;
 
-        Ok(())
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("start share server failed, sender dropped"))?
+            .map_err(CoreError::from)
     }
 
-    pub fn pause_downloads(&self, endpoint_id: &str) -> Result<(), CoreError> {
-        let endpoint_id: EndpointId = endpoint_id.parse().context("failed to parse endpoint id")?;
-
+    /// Stops the share server. Existing links work again if it's restarted
+    /// before they expire.
+    pub fn stop_share_server(&self) -> Result<(), CoreError> {
         self.node
-            .send(NodeCommand::PauseDownloads {
-                client: endpoint_id,
-            })
+            .send(NodeCommand::StopShareServer)
             .context("failed to send to node thread")?;
 
         Ok(())
     }
 
-    pub fn accept_connection(&self, endpoint_id: &str) -> Result<(), CoreError> {
-        let endpoint_id: EndpointId = endpoint_id.parse().context("failed to parse endpoint id")?;
+    /// Creates a link for downloading library files in a browser, for sharing
+    /// with someone who doesn't have the app. Returns the link's URL.
+    ///
+    /// The link shares the files in the given roots and directories, as
+    /// originals or transcoded to the given format. It expires after
+    /// `expires_in_secs`, and if `one_time` is set, it's removed once each
+    /// file has been downloaded. The share server has to be running.
+    pub fn create_share_link(
+        &self,
+        name: String,
+        selection: Vec<NodeShareModel>,
+        transcode_format: Option<TranscodeFormat>,
+        expires_in_secs: u64,
+        one_time: bool,
+    ) -> Result<String, CoreError> {
+        Ok(self.node.create_share_link(
+            name,
+            selection,
+            transcode_format,
+            Duration::from_secs(expires_in_secs),
+            one_time,
+        )?)
+    }
 
+    /// Removes a share link, so it stops working immediately.
+    pub fn revoke_share_link(&self, token: String) -> Result<(), CoreError> {
         self.node
-            .send(NodeCommand::AcceptConnection(endpoint_id))
+            .send(NodeCommand::RevokeShareLink(token))
             .context("failed to send to node thread")?;
 
         Ok(())
     }
 
-    // used by UI to accept and trust in one call
-    // TODO: remove?
-    pub fn accept_connection_and_trust(&self, endpoint_id: &str) -> Result<(), CoreError> {
-        let endpoint_id: EndpointId = endpoint_id.parse().context("failed to parse endpoint id")?;
+    /// Sets the directory that downloaded files are saved to. This is stored
+    /// in the settings.
+    pub fn set_download_directory(&self, download_directory: &str) -> Result<(), CoreError> {
+        self.modify_settings(|settings| {
+            settings.download_directory = Some(download_directory.to_string())
+        })
+    }
 
-        self.node
-            .send(NodeCommand::AcceptConnection(endpoint_id))
-            .context("failed to send to node thread")?;
+    /// Sets the template for the paths of downloaded files, relative to the
+    /// download directory, e.g. `{artist}/{album}/{track:02} - {title}`. The
+    /// extension is added automatically. If None, downloads keep the server's
+    /// folder structure under `musicopy-<node id>-<root>`.
+    ///
+    /// Fails if the template is empty or uses unknown fields. Changes apply to
+    /// files downloaded afterwards, and `reorganize_downloads` moves the files
+    /// that were already downloaded. This is stored in the settings.
+    pub fn set_download_path_template(&self, template: Option<String>) -> Result<(), CoreError> {
+        self.modify_settings(|settings| settings.download_path_template = template)
+    }
+
+    /// Sets how long connections can wait to be accepted before they're
+    /// closed, or 0 to wait forever. This applies to incoming connections
+    /// waiting for the user, and outgoing ones waiting for the server.
+    ///
+    /// Expired connections are reported as closed with an error, then removed
+    /// from the model. Changes apply to new connections.
+    pub fn set_pending_timeout(&self, timeout_secs: u64) -> Result<(), CoreError> {
+        let timeout = (timeout_secs > 0).then(|| Duration::from_secs(timeout_secs));
 
         self.node
-            .send(NodeCommand::TrustNode(endpoint_id))
+            .send(NodeCommand::SetPendingTimeout(timeout))
             .context("failed to send to node thread")?;
 
         Ok(())
     }
 
-    pub fn trust_node(&self, endpoint_id: &str) -> Result<(), CoreError> {
-        let endpoint_id: EndpointId = endpoint_id.parse().context("failed to parse endpoint id")?;
+    /// Sets whether incoming connections are accepted. When disabled,
+    /// connections from nodes that aren't trusted are closed immediately,
+    /// including ones using a pairing ticket. This is enabled by default and
+    /// stored in the settings.
+    pub fn set_accept_incoming(&self, accept_incoming: bool) -> Result<(), CoreError> {
+        self.modify_settings(|settings| settings.accept_incoming = accept_incoming)
+    }
 
+    /// Sets what to do when a download's destination already has a file that
+    /// wasn't downloaded from the same server file. Each job reports how its
+    /// collision was resolved.
+    pub fn set_collision_policy(&self, policy: CollisionPolicy) -> Result<(), CoreError> {
         self.node
-            .send(NodeCommand::TrustNode(endpoint_id))
+            .send(NodeCommand::SetCollisionPolicy(policy))
             .context("failed to send to node thread")?;
 
         Ok(())
     }
 
-    pub fn untrust_node(&self, endpoint_id: &str) -> Result<(), CoreError> {
-        let endpoint_id: EndpointId = endpoint_id.parse().context("failed to parse endpoint id")?;
-
+    /// Sets whether downloaded files are synced to disk before being marked
+    /// finished. This is enabled by default on mobile.
+    pub fn set_sync_downloads(&self, sync_downloads: bool) -> Result<(), CoreError> {
         self.node
-            .send(NodeCommand::UntrustNode(endpoint_id))
+            .send(NodeCommand::SetSyncDownloads(sync_downloads))
             .context("failed to send to node thread")?;
 
         Ok(())
     }
 
-    pub fn deny_connection(&self, endpoint_id: &str) -> Result<(), CoreError> {
-        let endpoint_id: EndpointId = endpoint_id.parse().context("failed to parse endpoint id")?;
-
+    /// Sets whether syncing checks that previously downloaded files still
+    /// exist on disk, and downloads them again if they don't. This is
+    /// disabled by default, since checking can be slow on mobile.
+    pub fn set_verify_downloads(&self, verify_downloads: bool) -> Result<(), CoreError> {
         self.node
-            .send(NodeCommand::DenyConnection(endpoint_id))
+            .send(NodeCommand::SetVerifyDownloads(verify_downloads))
             .context("failed to send to node thread")?;
 
         Ok(())
     }
 
-    pub fn close_client(&self, endpoint_id: &str) -> Result<(), CoreError> {
-        let endpoint_id: EndpointId = endpoint_id.parse().context("failed to parse endpoint id")?;
-
+    /// Sets whether files that changed on a server since they were
+    /// downloaded, e.g. because an album was ripped again, are downloaded
+    /// again when connecting to the server. This is disabled by default.
+    pub fn set_redownload_changed(&self, redownload_changed: bool) -> Result<(), CoreError> {
         self.node
-            .send(NodeCommand::CloseClient(endpoint_id))
+            .send(NodeCommand::SetRedownloadChanged(redownload_changed))
             .context("failed to send to node thread")?;
 
         Ok(())
     }
 
-    pub fn refresh_client_index(&self, endpoint_id: &str) -> Result<(), CoreError> {
-        let endpoint_id: EndpointId = endpoint_id.parse().context("failed to parse endpoint id")?;
-
+    /// Sets the number of streams used to download each large file, which
+    /// can help saturate fast links. Files are only split into parts of at
+    /// least 8 MiB, and only with servers that support it. Clamped to 1..=8,
+    /// and defaults to 1.
+    pub fn set_parallel_streams(&self, streams: u32) -> Result<(), CoreError> {
         self.node
-            .send(NodeCommand::RefreshClientIndex(endpoint_id))
+            .send(NodeCommand::SetParallelStreams(streams))
             .context("failed to send to node thread")?;
 
         Ok(())
     }
 
-    pub fn close_server(&self, endpoint_id: &str) -> Result<(), CoreError> {
-        let endpoint_id: EndpointId = endpoint_id.parse().context("failed to parse endpoint id")?;
-
+    /// Sets the size in bytes of the buffers that files are sent and received
+    /// in. Larger buffers help on fast links, and smaller ones use less memory
+    /// per transfer. Clamped to 16 KiB..=16 MiB, and defaults to 256 KiB.
+    /// Changes apply to transfers that start afterwards.
+    pub fn set_transfer_buffer_size(&self, buffer_size: u32) -> Result<(), CoreError> {
         self.node
-            .send(NodeCommand::CloseServer(endpoint_id))
+            .send(NodeCommand::SetTransferBufferSize(buffer_size))
             .context("failed to send to node thread")?;
 
         Ok(())
     }
 
-    pub fn add_library_root(&self, name: String, path: String) -> Result<(), CoreError> {
-        self.library
-            .send(LibraryCommand::AddRoot { name, path })
+    /// Sets the maximum number of files downloaded or sent at once per
+    /// connection. Low-end phones do better with fewer streams.
+    ///
+    /// Clients use the lower of their own setting and the one the server
+    /// advertised when it accepted the connection. Changes apply to existing
+    /// downloads as their active transfers finish. This is stored in the
+    /// settings.
+    pub fn set_max_concurrent_transfers(
+        &self,
+        max_concurrent_transfers: u32,
+    ) -> Result<(), CoreError> {
+        self.modify_settings(|settings| {
+            settings.max_concurrent_transfers = max_concurrent_transfers
+        })
+    }
+
+    /// Sets the maximum number of files downloaded at once across all
+    /// connections, or 0 for no limit. This applies on top of the limit per
+    /// connection, so downloads from several servers share one budget. This
+    /// is stored in the settings.
+    pub fn set_max_total_downloads(&self, max_total_downloads: u32) -> Result<(), CoreError> {
+        self.modify_settings(|settings| settings.max_total_downloads = max_total_downloads)
+    }
+
+    /// Sets the maximum download rate across all connections in bytes per
+    /// second, or 0 for no limit. This is stored in the settings.
+    pub fn set_max_download_rate(&self, max_download_rate: u64) -> Result<(), CoreError> {
+        self.modify_settings(|settings| settings.max_download_rate = max_download_rate)
+    }
+
+    /// Sets what happens to downloads on metered networks, and the maximum
+    /// download rate there in bytes per second for [`MeteredPolicy::Limit`],
+    /// or 0 for no limit. Held downloads stay queued, and the node model
+    /// reports why. This is stored in the settings.
+    pub fn set_metered_policy(
+        &self,
+        metered_policy: MeteredPolicy,
+        metered_download_rate: u64,
+    ) -> Result<(), CoreError> {
+        self.modify_settings(|settings| {
+            settings.metered_policy = metered_policy;
+            settings.metered_download_rate = metered_download_rate;
+        })
+    }
+
+    /// Sets whether connections to other nodes are opened on metered
+    /// networks. When they aren't, connecting fails right away. This is
+    /// stored in the settings.
+    pub fn set_connect_on_metered(&self, connect_on_metered: bool) -> Result<(), CoreError> {
+        self.modify_settings(|settings| settings.connect_on_metered = connect_on_metered)
+    }
+
+    /// Sets the maximum number of files sent at once to each client, or 0 to
+    /// only use the limit per connection. This keeps one client downloading a
+    /// whole library from starving other clients.
+    ///
+    /// The limit is advertised to clients when they connect, so changes apply
+    /// to new connections.
+    pub fn set_max_uploads_per_client(&self, max_uploads_per_client: u32) -> Result<(), CoreError> {
+        self.node
+            .send(NodeCommand::SetMaxUploadsPerClient(max_uploads_per_client))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Sets the maximum upload rate to each client in bytes per second, or 0
+    /// for no limit. Changes apply to existing connections. This is stored in
+    /// the settings.
+    pub fn set_max_upload_rate_per_client(
+        &self,
+        max_upload_rate_per_client: u64,
+    ) -> Result<(), CoreError> {
+        self.modify_settings(|settings| {
+            settings.max_upload_rate_per_client = max_upload_rate_per_client
+        })
+    }
+
+    /// Sets the rules for when queued downloads can run, like only while
+    /// charging, on Wi-Fi, or at night. Downloads that are held stay queued,
+    /// and the node model reports why. Active downloads finish when a rule
+    /// stops being met.
+    pub fn set_transfer_schedule(&self, schedule: TransferSchedule) -> Result<(), CoreError> {
+        self.node
+            .send(NodeCommand::SetTransferSchedule(schedule))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Reports the power and network state of the device and its UTC offset,
+    /// which the transfer schedule is checked against. Shells should call this
+    /// on startup and whenever the state changes. Until then, the device is
+    /// assumed to be charging and unmetered, in UTC.
+    pub fn set_device_state(&self, device: DeviceState) -> Result<(), CoreError> {
+        self.node
+            .send(NodeCommand::SetDeviceState(device))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Reports the state of the device's network, which the metered network
+    /// settings are checked against. Shells should call this on startup and
+    /// whenever the network changes. Mobile data counts as metered. While
+    /// offline, queued downloads are held and connecting fails right away.
+    ///
+    /// This replaces the network part of the state reported with
+    /// `set_device_state`.
+    pub fn set_network_state(
+        &self,
+        metered: bool,
+        wifi: bool,
+        offline: bool,
+    ) -> Result<(), CoreError> {
+        self.node
+            .send(NodeCommand::SetNetworkState(NetworkState {
+                metered,
+                wifi,
+                offline,
+            }))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Tells the core that the app moved to the background, e.g. when its
+    /// activity stops on Android.
+    ///
+    /// While in the background, model snapshots and diffs aren't sent.
+    /// Instead, `on_background_progress` is called when the progress of the
+    /// transfers changes, for a foreground service notification that keeps
+    /// them running. It's called right away, so the shell knows whether there
+    /// are any transfers to keep alive.
+    pub fn enter_background(&self) -> Result<(), CoreError> {
+        self.node
+            .send(NodeCommand::SetBackground(true))
+            .context("failed to send to node thread")?;
+        self.library
+            .send(LibraryCommand::SetBackground(true))
+            .context("failed to send to library thread")?;
+
+        Ok(())
+    }
+
+    /// Tells the core that the app moved back to the foreground, so model
+    /// changes are sent again, starting with a fresh snapshot.
+    ///
+    /// With model diffs, the diffs missed in the background aren't sent.
+    /// Instead, the node model is resynced with a `NodeModelPatch::Snapshot`.
+    pub fn enter_foreground(&self) -> Result<(), CoreError> {
+        self.node
+            .send(NodeCommand::SetBackground(false))
+            .context("failed to send to node thread")?;
+        self.library
+            .send(LibraryCommand::SetBackground(false))
+            .context("failed to send to library thread")?;
+
+        Ok(())
+    }
+
+    /// Sets how many days finished download sessions are kept in the transfer
+    /// history, or None to keep them forever. Defaults to 90 days. Older
+    /// sessions are deleted right away and whenever a session finishes. This
+    /// is stored in the settings.
+    pub fn set_transfer_history_retention(&self, days: Option<u32>) -> Result<(), CoreError> {
+        self.modify_settings(|settings| settings.transfer_history_retention_days = days)
+    }
+
+    /// Gets the most recent finished download sessions, most recent first,
+    /// optionally only those with the server with the given endpoint id.
+    pub fn get_transfer_history(
+        &self,
+        endpoint_id: Option<String>,
+        limit: u32,
+    ) -> Result<Vec<TransferSessionModel>, CoreError> {
+        let endpoint_id: Option<EndpointId> = endpoint_id
+            .map(|endpoint_id| endpoint_id.parse())
+            .transpose()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        let db = self.db.get();
+        let sessions = db
+            .get_transfer_sessions(endpoint_id, limit as u64)
+            .context("failed to get transfer sessions")?;
+
+        Ok(sessions
+            .into_iter()
+            .map(TransferSessionModel::from)
+            .collect())
+    }
+
+    /// Gets the outcomes of the files in a download session from the
+    /// transfer history.
+    pub fn get_transfer_session_files(
+        &self,
+        session_id: u64,
+    ) -> Result<Vec<TransferSessionFileModel>, CoreError> {
+        let db = self.db.get();
+        let files = db
+            .get_transfer_session_files(session_id)
+            .context("failed to get transfer session files")?;
+
+        Ok(files
+            .into_iter()
+            .map(TransferSessionFileModel::from)
+            .collect())
+    }
+
+    /// Registers the resolver used to refresh stale iOS bookmarks.
+    ///
+    /// Refreshed bookmarks replace the stale ones in the database and the
+    /// download directory. This does nothing on other platforms.
+    pub fn set_ios_bookmark_resolver(
+        &self,
+        resolver: Arc<dyn IosBookmarkResolver>,
+    ) -> Result<(), CoreError> {
+        #[cfg(target_os = "ios")]
+        {
+            let node = self.node.clone();
+            crate::fs::set_bookmark_refresher(move |stale, path| {
+                let fresh = resolver
+                    .refresh_bookmark(stale.to_string(), path.to_string_lossy().into_owned())?;
+
+                if let Err(e) = node.send(NodeCommand::ReplaceBookmark {
+                    stale: stale.to_string(),
+                    fresh: fresh.clone(),
+                }) {
+                    warn!("core: failed to send refreshed bookmark to node: {e:#}");
+                }
+
+                Some(fresh)
+            });
+        }
+        #[cfg(not(target_os = "ios"))]
+        {
+            let _ = resolver;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_downloads(
+        &self,
+        endpoint_id: &str,
+        items: Vec<DownloadRequestModel>,
+    ) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::SetDownloads {
+                client: endpoint_id,
+                items,
+                callback: None,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Requests downloads from a connected server like `set_downloads`, and
+    /// waits until the downloads it queued have finished or failed.
+    ///
+    /// Only the files queued by this call are waited for and counted in the
+    /// result, not other downloads from the same server, or files that were
+    /// already downloaded or queued. Fails if the downloads can't be queued,
+    /// e.g. because the server isn't connected, or if the connection closes
+    /// before they finish. Downloads that fail are counted in the result
+    /// instead. `progress` is sent the progress of the server's downloads
+    /// while waiting.
+    pub async fn download_and_wait(
+        &self,
+        endpoint_id: &str,
+        items: Vec<DownloadRequestModel>,
+        progress: Option<Arc<dyn OperationProgressHandler>>,
+    ) -> Result<DownloadResultModel, CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+        let endpoint_id_string = endpoint_id.to_string();
+
+        // subscribe before queueing, so the downloads can't finish unseen
+        let mut events = self.node.subscribe_operation_events();
+
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::SetDownloads {
+                client: endpoint_id,
+                items,
+                callback: Some(callback_tx),
+            })
+            .context("failed to send to node thread")?;
+
+        let queued = callback_rx
+            .await
+            .map_err(|_dropped| core_error!("set downloads failed, sender dropped"))?
+            .map_err(CoreError::from)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        if let Some(progress) = &progress {
+            progress.on_progress(OperationProgress::DownloadsQueued {
+                queued_files: queued.len() as u32,
+            });
+        }
+
+        // everything was already downloaded or queued, so there's nothing to wait for
+        if queued.is_empty() {
+            return Ok(DownloadResultModel::default());
+        }
+
+        // the jobs are checked after every event about the server, instead of matching a
+        // completed session, since the session can include downloads queued by others
+        let finished_result = || match self.node.get_client_jobs_result(endpoint_id, &queued) {
+            Some((0, result)) => Ok(Some(result)),
+            Some(_) => Ok(None),
+            None => Err(CoreError::from(ConnectionError::NotConnected {
+                endpoint_id: endpoint_id_string.clone(),
+            })),
+        };
+
+        loop {
+            let event = match async_std::future::timeout(PROGRESS_INTERVAL, events.recv()).await {
+                Ok(event) => event,
+                Err(_elapsed) => {
+                    if let Some(result) = finished_result()? {
+                        return Ok(result);
+                    }
+
+                    if let Some(progress) = &progress
+                        && let Some(download_progress) =
+                            self.node.get_client_download_progress(endpoint_id)
+                    {
+                        progress.on_progress(OperationProgress::Downloading {
+                            progress: download_progress,
+                        });
+                    }
+                    continue;
+                }
+            };
+
+            match event {
+                Ok(event) if !event.is_for_client(endpoint_id) => continue,
+                Ok(OperationEvent::JobFailed(event)) => {
+                    let is_queued =
+                        queued.contains(&(event.file_root.clone(), event.file_path.clone()));
+                    if is_queued && let Some(progress) = &progress {
+                        progress.on_progress(OperationProgress::DownloadFailed { event });
+                    }
+                }
+                Ok(OperationEvent::ClientClosed { error, .. }) => {
+                    return Err(connection_closed_error(error));
+                }
+                Ok(OperationEvent::SessionCompleted(_) | OperationEvent::ClientReady(_)) => {}
+                // missed events are made up for by checking the jobs
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Err(core_error!("node stopped")),
+            }
+
+            if let Some(result) = finished_result()? {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Moves queued downloads to the front of the queue, e.g. to get an album
+    /// before the rest of a large sync. Items requested later go before ones
+    /// requested earlier. The server is asked to transcode them first too.
+    pub fn prioritize_downloads(
+        &self,
+        endpoint_id: &str,
+        items: Vec<DownloadRequestModel>,
+    ) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::PrioritizeDownloads {
+                client: endpoint_id,
+                items,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    pub fn pause_downloads(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::PauseDownloads {
+                client: endpoint_id,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Pauses a single transfer job from the server with the given endpoint id.
+    pub fn pause_transfer(&self, endpoint_id: &str, job_id: u64) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::PauseTransfer {
+                client: endpoint_id,
+                job_id,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Resumes a transfer job paused with [`pause_transfer`](Self::pause_transfer).
+    pub fn resume_transfer(&self, endpoint_id: &str, job_id: u64) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::ResumeTransfer {
+                client: endpoint_id,
+                job_id,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Cancels a single transfer job from the server with the given endpoint id.
+    ///
+    /// The job is marked as failed and any partially downloaded data is discarded.
+    pub fn cancel_transfer(&self, endpoint_id: &str, job_id: u64) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::CancelTransfer {
+                client: endpoint_id,
+                job_id,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Asks the server with the given endpoint id to rescan its library, e.g.
+    /// after files were just added to it. New and removed files show up in the
+    /// client's index once the scan finishes.
+    ///
+    /// The server only rescans if it trusts this node, and ignores requests
+    /// made too soon after the last one.
+    pub fn request_remote_rescan(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::RequestRemoteRescan(endpoint_id))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Starts a two-way sync with the server with the given endpoint id.
+    ///
+    /// Downloads the original files the server has that the local library
+    /// lacks, then asks the server to connect back and do the same. The server
+    /// only does so if it trusts this node. The connection must not be
+    /// transcoding. Files at the same path with different content are handled
+    /// by the conflict policy.
+    pub fn two_way_sync(
+        &self,
+        endpoint_id: &str,
+        conflict_policy: SyncConflictPolicy,
+    ) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::TwoWaySync {
+                client: endpoint_id,
+                conflict_policy,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Asks the client with the given endpoint id to download files from the
+    /// local library, for push mode.
+    ///
+    /// Only trusted clients are asked, and clients only download the files if
+    /// they trust this node. The files are downloaded to the client's download
+    /// directory like any other download.
+    pub fn push_files(
+        &self,
+        endpoint_id: &str,
+        items: Vec<DownloadRequestModel>,
+    ) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::PushFiles {
+                server: endpoint_id,
+                items,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Asks the trusted client with the given endpoint id to download the
+    /// files shared with it that haven't been sent to it yet, for push mode.
+    ///
+    /// Files are matched by content hash, like the library coverage in the
+    /// trusted nodes model.
+    pub fn push_missing_files(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::PushMissingFiles {
+                server: endpoint_id,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Deletes files downloaded from the server with the given endpoint id
+    /// that no longer exist in its index, so the download directory mirrors
+    /// the server's library. Returns the deleted files.
+    ///
+    /// If `dry_run` is set, nothing is deleted, and the returned files are a
+    /// preview of what would be deleted.
+    pub async fn mirror_downloads(
+        &self,
+        endpoint_id: &str,
+        dry_run: bool,
+    ) -> Result<Vec<MirrorDeletionModel>, CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::MirrorDownloads {
+                client: endpoint_id,
+                dry_run,
+                callback: callback_tx,
+            })
+            .context("failed to send to node thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("mirror downloads failed, sender dropped"))?
+            .map_err(CoreError::from)
+    }
+
+    /// Requests the interrupted downloads from a server again, resuming them
+    /// from their partial files. The server must be connected.
+    ///
+    /// Downloads are interrupted when the app stops before they finish, and
+    /// are listed in the node model's `interrupted_downloads`.
+    pub fn resume_interrupted_downloads(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::ResumeInterruptedDownloads {
+                client: endpoint_id,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Removes the partial files of the interrupted downloads from a server,
+    /// or from all servers if None. Returns the number of downloads discarded.
+    pub async fn discard_interrupted_downloads(
+        &self,
+        endpoint_id: Option<String>,
+    ) -> Result<u32, CoreError> {
+        let endpoint_id: Option<EndpointId> = endpoint_id
+            .map(|endpoint_id| endpoint_id.parse())
+            .transpose()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::DiscardInterruptedDownloads {
+                client: endpoint_id,
+                callback: callback_tx,
+            })
+            .context("failed to send to node thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| {
+                core_error!("discard interrupted downloads failed, sender dropped")
+            })?
+            .map_err(CoreError::from)
+    }
+
+    /// Checks files downloaded from all servers against the manifests the
+    /// servers sent with them, without connecting to the servers. Returns the
+    /// files that are missing or don't match their manifest. Files downloaded
+    /// by older versions are only checked for existence.
+    ///
+    /// If `repair` is set, the returned files are deleted and untracked, so
+    /// they're downloaded again the next time their server is synced.
+    pub async fn check_downloaded_files(
+        &self,
+        repair: bool,
+    ) -> Result<Vec<DownloadIssueModel>, CoreError> {
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::CheckDownloadedFiles {
+                repair,
+                callback: callback_tx,
+            })
+            .context("failed to send to node thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("check downloaded files failed, sender dropped"))?
+            .map_err(CoreError::from)
+    }
+
+    /// Lists the servers that files were downloaded from, with the number
+    /// and recorded size of their files.
+    pub fn list_downloaded_servers(&self) -> Result<Vec<DownloadedServerModel>, CoreError> {
+        downloads::list_servers(&self.db, self.node.endpoint_id()).map_err(CoreError::from)
+    }
+
+    /// Lists the files downloaded from the server with the given endpoint
+    /// id, in all download directories. The server doesn't have to be
+    /// connected.
+    pub fn list_downloaded_files(
+        &self,
+        endpoint_id: &str,
+    ) -> Result<Vec<DownloadedFileModel>, CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+        if endpoint_id == self.node.endpoint_id() {
+            return Err(core_error!(
+                "the local library isn't downloaded from a server"
+            ));
+        }
+
+        downloads::list_files(&self.db, self.node.endpoint_id(), endpoint_id)
+            .map_err(CoreError::from)
+    }
+
+    /// Deletes the selected files downloaded from the server with the given
+    /// endpoint id, and untracks them so they can be downloaded again. Pass
+    /// `DownloadedFileSelectionModel::All` to delete everything from the
+    /// server.
+    pub async fn delete_downloaded_files(
+        &self,
+        endpoint_id: &str,
+        selections: Vec<DownloadedFileSelectionModel>,
+    ) -> Result<DeletedDownloadsModel, CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+        if endpoint_id == self.node.endpoint_id() {
+            return Err(core_error!(
+                "the local library isn't downloaded from a server"
+            ));
+        }
+
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::DeleteDownloadedFiles {
+                client: endpoint_id,
+                selections,
+                callback: callback_tx,
+            })
+            .context("failed to send to node thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("delete downloaded files failed, sender dropped"))?
+            .map_err(CoreError::from)
+    }
+
+    /// Moves the files already downloaded to the download directory to the
+    /// paths the current download path template gives them, e.g. after the
+    /// template changed, and updates their records and playlists. Files whose
+    /// new path is taken are left where they are and returned as conflicts.
+    ///
+    /// If `dry_run` is set, nothing is moved, and the result shows what would
+    /// be. Fails while files are downloading.
+    pub async fn reorganize_downloads(
+        &self,
+        dry_run: bool,
+    ) -> Result<ReorganizedDownloadsModel, CoreError> {
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::ReorganizeDownloads {
+                dry_run,
+                callback: callback_tx,
+            })
+            .context("failed to send to node thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("reorganize downloads failed, sender dropped"))?
+            .map_err(CoreError::from)
+    }
+
+    /// Measures the files downloaded from each server on disk, instead of
+    /// using the sizes recorded when they were downloaded, and counts the
+    /// ones that no longer exist.
+    pub async fn measure_downloaded_files(&self) -> Result<Vec<DownloadedServerModel>, CoreError> {
+        downloads::measure(&self.db, self.node.endpoint_id())
+            .await
+            .map_err(CoreError::from)
+    }
+
+    /// Rehashes the files downloaded from the server with the given endpoint
+    /// id, and checks them against the manifests the server sent with them
+    /// and its current index. Returns the files that are missing, corrupted,
+    /// or outdated. The server must be connected.
+    ///
+    /// If `requeue` is set, the returned files are deleted and untracked, and
+    /// the ones the server still has are downloaded again.
+    pub async fn verify_downloads(
+        &self,
+        endpoint_id: &str,
+        requeue: bool,
+    ) -> Result<Vec<DownloadIssueModel>, CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::VerifyDownloads {
+                client: endpoint_id,
+                requeue,
+                callback: callback_tx,
+            })
+            .context("failed to send to node thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("verify downloads failed, sender dropped"))?
+            .map_err(CoreError::from)
+    }
+
+    /// Lists the files downloaded from the server with the given endpoint id
+    /// to the current download directory whose originals changed on the
+    /// server since, by their content hashes. Unlike `verify_downloads`, the
+    /// files aren't rehashed. The server must be connected.
+    ///
+    /// If `requeue` is set, the returned files are downloaded again,
+    /// replacing the old files once the downloads finish.
+    pub async fn changed_downloads(
+        &self,
+        endpoint_id: &str,
+        requeue: bool,
+    ) -> Result<Vec<ChangedDownloadModel>, CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::ChangedDownloads {
+                client: endpoint_id,
+                requeue,
+                callback: callback_tx,
+            })
+            .context("failed to send to node thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("changed downloads failed, sender dropped"))?
+            .map_err(CoreError::from)
+    }
+
+    /// Matches the files in the download directory that weren't downloaded
+    /// by the app, e.g. ones copied by hand, to the files in the index of the
+    /// server with the given endpoint id, and tracks the matches as downloaded
+    /// so syncs skip them. The server must be connected.
+    ///
+    /// Files are matched by their audio content where they can be hashed, and
+    /// by their sizes otherwise. `folder` limits the search to a folder in
+    /// the download directory. If `dry_run` is set, nothing is tracked, and
+    /// the result shows what would be.
+    pub async fn import_downloads(
+        &self,
+        endpoint_id: &str,
+        folder: Option<String>,
+        dry_run: bool,
+    ) -> Result<ImportedDownloadsModel, CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::ImportDownloads {
+                client: endpoint_id,
+                folder,
+                dry_run,
+                callback: callback_tx,
+            })
+            .context("failed to send to node thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("import downloads failed, sender dropped"))?
+            .map_err(CoreError::from)
+    }
+
+    /// Lists the albums in the library of the server with the given endpoint
+    /// id, by the artist and album tags of its files, so it can be browsed
+    /// before choosing what to download.
+    pub async fn browse_remote_library(
+        &self,
+        endpoint_id: &str,
+    ) -> Result<Vec<RemoteAlbumModel>, CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::BrowseRemoteLibrary {
+                client: endpoint_id,
+                callback: callback_tx,
+            })
+            .context("failed to send to node thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("browse remote library failed, sender dropped"))?
+            .map_err(CoreError::from)
+    }
+
+    /// Expands selections of artists and albums into the matching files in
+    /// the index of the server with the given endpoint id. The returned items
+    /// can be passed to `set_downloads`.
+    pub async fn expand_download_selection(
+        &self,
+        endpoint_id: &str,
+        selections: Vec<DownloadSelectionModel>,
+    ) -> Result<Vec<DownloadRequestModel>, CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.node
+            .send(NodeCommand::ExpandDownloadSelection {
+                client: endpoint_id,
+                selections,
+                callback: callback_tx,
+            })
+            .context("failed to send to node thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("expand download selection failed, sender dropped"))?
+            .map_err(CoreError::from)
+    }
+
+    pub fn accept_connection(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::AcceptConnection(endpoint_id))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    // used by UI to accept and trust in one call
+    // TODO: remove?
+    pub fn accept_connection_and_trust(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::AcceptConnection(endpoint_id))
+            .context("failed to send to node thread")?;
+
+        self.node
+            .send(NodeCommand::TrustNode(endpoint_id))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    pub fn trust_node(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::TrustNode(endpoint_id))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    pub fn untrust_node(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::UntrustNode(endpoint_id))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Sets the roots and directories shared with a node. An empty list shares the whole library.
+    pub fn set_node_shares(
+        &self,
+        endpoint_id: &str,
+        shares: Vec<NodeShareModel>,
+    ) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::SetNodeShares {
+                endpoint_id,
+                shares,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Sets limits on the files shared with a trusted node, e.g. to keep huge
+    /// WAV stems off a phone, or removes them with None. Files over the size
+    /// or duration limits, or with an excluded extension, are left out of the
+    /// node's index and can't be downloaded by it. Limits are checked against
+    /// the original files.
+    pub fn set_share_filter(
+        &self,
+        endpoint_id: &str,
+        share_filter: Option<ShareFilterModel>,
+    ) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::SetShareFilter {
+                endpoint_id,
+                share_filter,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Sets the settings for automatically downloading new files from a trusted server, or
+    /// disables auto-download with None.
+    pub fn set_auto_download(
+        &self,
+        endpoint_id: &str,
+        auto_download: Option<AutoDownloadModel>,
+    ) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::SetAutoDownload {
+                endpoint_id,
+                auto_download,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Sets the maximum total size of the files auto-downloaded from a server,
+    /// e.g. 20 GB on a phone, or removes the limit with None.
+    ///
+    /// Files already downloaded from the server count towards the quota.
+    /// Auto-download fills the rest by priority, starting with the files in
+    /// its first selection, then the most recently added files. What didn't
+    /// fit is reported in the client model. Downloads chosen by the user
+    /// aren't limited.
+    pub fn set_storage_quota(
+        &self,
+        endpoint_id: &str,
+        max_bytes: Option<u64>,
+    ) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::SetStorageQuota {
+                endpoint_id,
+                max_bytes,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Sets limits on the files downloaded from a server, e.g. to skip files
+    /// longer than 30 minutes or larger than 300 MB, or removes them with None.
+    ///
+    /// Auto-download skips files over the limits, and they're marked as
+    /// filtered in the server's index so downloading everything can skip them
+    /// too. Limits are checked against the size of the file to be transferred,
+    /// and only if its size or duration is known. Downloads chosen by the user
+    /// aren't limited.
+    pub fn set_download_filter(
+        &self,
+        endpoint_id: &str,
+        download_filter: Option<DownloadFilterModel>,
+    ) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::SetDownloadFilter {
+                endpoint_id,
+                download_filter,
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Sets a local label for a trusted node or recent server, which is shown
+    /// instead of the name it reports. An empty or None label removes it.
+    pub fn set_node_label(
+        &self,
+        endpoint_id: &str,
+        label: Option<String>,
+    ) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+        let label = label
+            .map(|label| label.trim().to_string())
+            .filter(|label| !label.is_empty());
+
+        self.node
+            .send(NodeCommand::SetNodeLabel { endpoint_id, label })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    pub fn deny_connection(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::DenyConnection(endpoint_id))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    pub fn close_client(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::CloseClient(endpoint_id))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Closes a client after its active downloads finish, without starting new ones. If they
+    /// don't finish within the timeout, the connection closes anyway and the downloads resume
+    /// from their partial files next time.
+    pub fn drain_client(&self, endpoint_id: &str, timeout_secs: u64) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::DrainClient {
+                endpoint_id,
+                timeout: Duration::from_secs(timeout_secs),
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    pub fn refresh_client_index(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::RefreshClientIndex(endpoint_id))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    pub fn close_server(&self, endpoint_id: &str) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::CloseServer(endpoint_id))
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    /// Closes a server after its active uploads finish, refusing new downloads in the meantime.
+    pub fn drain_server(&self, endpoint_id: &str, timeout_secs: u64) -> Result<(), CoreError> {
+        let endpoint_id: EndpointId = endpoint_id
+            .parse()
+            .context(ConnectionError::InvalidEndpointId)?;
+
+        self.node
+            .send(NodeCommand::DrainServer {
+                endpoint_id,
+                timeout: Duration::from_secs(timeout_secs),
+            })
+            .context("failed to send to node thread")?;
+
+        Ok(())
+    }
+
+    pub fn add_library_root(&self, name: String, path: String) -> Result<(), CoreError> {
+        // document trees, bookmarks, and memory trees can't be checked here, they're resolved by
+        // the library
+        if crate::fs::is_plain_path(&path) && !std::path::Path::new(&path).exists() {
+            return Err(LibraryError::RootPathNotFound { path }.into());
+        }
+
+        self.library
+            .send(LibraryCommand::AddRoot { name, path })
             .context("failed to send to library thread")?;
 
         Ok(())
     }
 
+    /// Suggests folders to add as library roots, like the platform's music
+    /// folder, for onboarding. Folders without music or that are already
+    /// roots are left out, and the rest are sorted by how many files they
+    /// have. On Android, folders can't be read until they're picked, so each
+    /// storage volume's music folder is suggested with a hint to open the
+    /// folder picker at instead.
+    pub fn suggest_library_roots(&self) -> Result<Vec<SuggestedRootModel>, CoreError> {
+        let existing_roots: Vec<String> = self
+            .library
+            .get_model()
+            .local_roots
+            .into_iter()
+            .map(|root| root.path)
+            .collect();
+
+        Ok(suggest::suggest_roots(&existing_roots))
+    }
+
     pub fn remove_library_root(&self, name: String) -> Result<(), CoreError> {
         self.library
             .send(LibraryCommand::RemoveRoot { name })
@@ -552,6 +2507,72 @@ impl Core {
         Ok(())
     }
 
+    /// Rescans the library like `rescan_library`, and waits for the scan to
+    /// finish. If a scan is already running, this waits for another one
+    /// after it, so changes made before calling this are always seen.
+    pub async fn rescan_library_and_wait(
+        &self,
+        progress: Option<Arc<dyn OperationProgressHandler>>,
+    ) -> Result<ScanResultModel, CoreError> {
+        // rescans are ignored while shutting down, so they'd never finish
+        if self.library.is_draining() {
+            return Err(core_error!("library is shutting down"));
+        }
+
+        let mut scans = self.library.subscribe_scans();
+        let start = *scans.borrow_and_update();
+        let queued = self.library.get_status().is_scanning;
+        let target = start + if queued { 2 } else { 1 };
+
+        self.rescan_library()?;
+
+        if let Some(progress) = &progress {
+            progress.on_progress(if queued {
+                OperationProgress::ScanQueued
+            } else {
+                OperationProgress::ScanStarted
+            });
+        }
+
+        loop {
+            scans
+                .changed()
+                .await
+                .map_err(|_closed| core_error!("library stopped before the scan finished"))?;
+
+            let finished = *scans.borrow_and_update();
+            if finished >= target {
+                break;
+            }
+            if let Some(progress) = &progress {
+                progress.on_progress(OperationProgress::ScanStarted);
+            }
+        }
+
+        let (_, scan_durations) = self.library.scan_metrics();
+        Ok(ScanResultModel {
+            library_files: self.library.get_status().library_files,
+            duration_ms: scan_durations
+                .last
+                .map_or(0, |duration| duration.as_millis() as u64),
+        })
+    }
+
+    /// Waits until the transcodes requested so far are queued, e.g. after
+    /// setting the transcode policy to transcode ahead of time, so the
+    /// library model's transcode counts include them.
+    pub async fn wait_for_transcodes_queued(&self) -> Result<(), CoreError> {
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.library
+            .send(LibraryCommand::FlushTranscodes(callback_tx))
+            .context("failed to send to library thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("library stopped before the transcodes were queued"))
+    }
+
     // TODO: used in test
     pub fn request_transcodes(
         &self,
@@ -580,10 +2601,7 @@ impl Core {
     }
 
     pub fn reset_database(&self) -> Result<(), CoreError> {
-        let db = self
-            .db
-            .lock()
-            .map_err(|_elapsed| core_error!("failed to lock database"))?;
+        let db = self.db.get();
 
         db.reset()?;
 
@@ -594,10 +2612,7 @@ impl Core {
     }
 
     pub fn reset_caches(&self) -> Result<(), CoreError> {
-        let db = self
-            .db
-            .lock()
-            .map_err(|_elapsed| core_error!("failed to lock database"))?;
+        let db = self.db.get();
 
         db.reset_caches()?;
 
@@ -660,13 +2675,90 @@ impl Core {
 
         Ok(combined)
     }
+
+    /// Writes a zip file to the given path with the recent logs, the
+    /// settings, a summary of the status, row counts of the database, and
+    /// platform information, to attach to bug reports. The database's contents
+    /// aren't included, but logs and settings can include paths.
+    pub fn export_diagnostics(&self, path: String) -> Result<(), CoreError> {
+        let mut zip = diagnostics::ZipWriter::new();
+
+        zip.add_file("platform.txt", diagnostics::platform_summary().as_bytes())?;
+        zip.add_file(
+            "profile.txt",
+            format!(
+                "profile: {:?}\nin memory: {}\n",
+                self.options.profile, self.options.in_memory
+            )
+            .as_bytes(),
+        )?;
+        zip.add_file(
+            "settings.txt",
+            format!("{:#?}\n", self.get_settings_model()?).as_bytes(),
+        )?;
+        zip.add_file(
+            "status.txt",
+            format!("{:#?}\n", self.get_status()?).as_bytes(),
+        )?;
+        {
+            let db = self.db.get();
+            zip.add_file(
+                "database.txt",
+                diagnostics::database_summary(&db)?.as_bytes(),
+            )?;
+        }
+
+        // running in-memory, no logs written to files
+        if let Some(log_dir) = &self.log_dir {
+            diagnostics::add_logs(&mut zip, log_dir)?;
+        }
+
+        std::fs::write(&path, zip.finish()?).context("failed to write diagnostics")?;
+
+        Ok(())
+    }
+
+    /// Gets the most recent log entries matching a filter, oldest first, so
+    /// they can be viewed and shared from inside the app. Returns nothing if
+    /// logs aren't written to files.
+    pub fn get_recent_logs(
+        &self,
+        filter: LogFilter,
+        limit: u32,
+    ) -> Result<Vec<LogEntryModel>, CoreError> {
+        // running in-memory, no logs written to files
+        let Some(log_dir) = &self.log_dir else {
+            return Ok(Vec::new());
+        };
+
+        logging::read_recent_logs(log_dir, &filter, limit as usize).map_err(CoreError::from)
+    }
+
+    /// Exports metrics about the library, transcodes, connections, and
+    /// transfers in the OpenMetrics text format, to serve to Prometheus.
+    pub fn export_metrics(&self) -> Result<String, CoreError> {
+        let (scans, scan_durations) = self.library.scan_metrics();
+        let snapshot = MetricsSnapshot {
+            library: self.library.get_model(),
+            scans,
+            scan_durations,
+            node: self.node.get_metrics(),
+            download_progress: self.node.get_download_progress(),
+            stats: self.db.get().get_stats()?,
+        };
+
+        Ok(snapshot.render())
+    }
 }
 
 /// Hooks for integration tests.
 #[cfg(feature = "test-hooks")]
 #[derive(Debug)]
 pub struct TestHooks {
-    download_gate: Mutex<Option<Arc<tokio::sync::Semaphore>>>,
+    download_gate: std::sync::Mutex<Option<Arc<tokio::sync::Semaphore>>>,
+    keep_alive: std::sync::Mutex<Option<(Duration, Duration)>>,
+    pings_paused: std::sync::atomic::AtomicBool,
+    corrupt_checksums: std::sync::atomic::AtomicBool,
 }
 
 #[cfg(feature = "test-hooks")]
@@ -676,7 +2768,10 @@ impl TestHooks {
     /// Does nothing by default.
     pub fn new() -> Self {
         Self {
-            download_gate: Mutex::new(None),
+            download_gate: std::sync::Mutex::new(None),
+            keep_alive: std::sync::Mutex::new(None),
+            pings_paused: std::sync::atomic::AtomicBool::new(false),
+            corrupt_checksums: std::sync::atomic::AtomicBool::new(false),
         }
     }
 
@@ -721,6 +2816,40 @@ impl TestHooks {
         };
         download_gate.add_permits(n);
     }
+
+    /// Sets how often connections send pings, and how long they wait without messages before
+    /// timing out. Only applies to connections made afterwards.
+    pub fn set_keep_alive(&self, interval: Duration, timeout: Duration) {
+        *self.keep_alive.lock().unwrap() = Some((interval, timeout));
+    }
+
+    /// Gets the keep-alive interval and timeout, if they were set.
+    pub fn keep_alive(&self) -> Option<(Duration, Duration)> {
+        *self.keep_alive.lock().unwrap()
+    }
+
+    /// Stops or resumes sending pings, so the peers of idle connections see them go quiet.
+    pub fn set_pings_paused(&self, paused: bool) {
+        self.pings_paused
+            .store(paused, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Whether sending pings is stopped.
+    pub fn pings_paused(&self) -> bool {
+        self.pings_paused.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Makes the server send wrong checksums for the files it serves, so clients discard them.
+    pub fn set_corrupt_checksums(&self, corrupt: bool) {
+        self.corrupt_checksums
+            .store(corrupt, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Whether the server sends wrong checksums.
+    pub fn corrupt_checksums(&self) -> bool {
+        self.corrupt_checksums
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
 }
 
 #[cfg(feature = "test-hooks")]
@@ -1,15 +1,25 @@
-use crate::database::{Database, FileHash, FileSize, InsertFileHash, InsertFileSize};
+use crate::database::{
+    DatabasePool, FileHash, FileSize, FileTags, InsertFileHash, InsertFileSize, InsertFileTags,
+};
 use anyhow::Context;
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use std::{
     borrow::Cow,
     collections::HashSet,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::Arc,
     time::SystemTime,
 };
 use tracing::warn;
 
+/// A change to the cached tags of a file, found while preparing tags.
+enum TagsChange<'a> {
+    /// The file is new or changed, and its tags were read.
+    Insert(InsertFileTags<'a>),
+    /// The file changed or is gone, and its tags couldn't be read, so the cached tags are stale.
+    Remove(Cow<'a, str>),
+}
+
 pub(crate) struct CacheKey<'a> {
     file_size: u64,
     modified_at: u64,
@@ -42,6 +52,10 @@ impl<'a> CacheKey<'a> {
         self.file_size == file_size.last_file_size && self.modified_at == file_size.last_modified_at
     }
 
+    fn matches_file_tags(&self, file_tags: &FileTags) -> bool {
+        self.file_size == file_tags.last_file_size && self.modified_at == file_tags.last_modified_at
+    }
+
     pub fn file_size(&self) -> u64 {
         self.file_size
     }
@@ -49,11 +63,11 @@ impl<'a> CacheKey<'a> {
 
 #[derive(Debug, Clone)]
 pub struct HashCache {
-    db: Arc<Mutex<Database>>,
+    db: Arc<DatabasePool>,
 }
 
 impl HashCache {
-    pub fn new(db: Arc<Mutex<Database>>) -> Self {
+    pub fn new(db: Arc<DatabasePool>) -> Self {
         Self { db }
     }
 
@@ -74,7 +88,7 @@ impl HashCache {
     ) -> anyhow::Result<Option<(Cow<'static, str>, [u8; 16])>> {
         // check for cached hash
         let cached = {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
             db.get_file_hash_by_path(key.path)?
         };
 
@@ -96,7 +110,7 @@ impl HashCache {
 
         // check for cached hash
         let cached = {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
             db.get_file_hash_by_path(path)?
         };
 
@@ -112,7 +126,7 @@ impl HashCache {
 
         // store new hash
         {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
             db.insert_file_hash(InsertFileHash {
                 path: path.to_string_lossy(),
                 last_file_size: key.file_size,
@@ -134,7 +148,7 @@ impl HashCache {
     ) -> anyhow::Result<HashSet<(Cow<'static, str>, [u8; 16])>> {
         // get cached hashes
         let cached = {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
             db.get_file_hashes_by_paths(paths.iter().map(|p| p.to_string_lossy()))?
         };
 
@@ -191,7 +205,7 @@ impl HashCache {
 
         // store new hashes
         {
-            let mut db = self.db.lock().unwrap();
+            let mut db = self.db.get();
             db.insert_file_hashes(insert_hashes.into_iter().flatten())
                 .context("failed to insert file hashes")?;
         }
@@ -206,7 +220,7 @@ impl HashCache {
     pub(crate) fn get_cached_duration(&self, key: &CacheKey) -> anyhow::Result<Option<f64>> {
         // check for cached duration
         let cached = {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
             db.get_file_size_by_path(key.path)?
         };
 
@@ -228,12 +242,26 @@ impl HashCache {
     /// has less effect on duration than replacing the audio data, so this will often be correct
     /// anyway.
     pub fn get_cached_duration_unvalidated(&self, path: &Path) -> anyhow::Result<Option<f64>> {
-        let db = self.db.lock().unwrap();
+        let db = self.db.get();
         Ok(db
             .get_file_size_by_path(path)?
             .map(|cached| cached.duration))
     }
 
+    /// Cheaply gets the hash of a file from cache without validating it.
+    ///
+    /// This does not require reading the cache key first, which requires accessing the file and can
+    /// be expensive. This should be used if using a stale hash is allowable and needs to be fast.
+    pub(crate) fn get_cached_hash_unvalidated(
+        &self,
+        path: &Path,
+    ) -> anyhow::Result<Option<(Cow<'static, str>, [u8; 16])>> {
+        let db = self.db.get();
+        Ok(db
+            .get_file_hash_by_path(path)?
+            .map(|cached| (cached.hash_kind.into(), cached.hash)))
+    }
+
     /// Cheaply gets the size of a file from cache without validating it.
     ///
     /// This does not require reading the cache key first, which requires accessing the file and can
@@ -242,17 +270,26 @@ impl HashCache {
     /// transferring originals. Sort of a hack... but it's faster than reading the files and we want
     /// the index to prepare quickly.
     pub fn get_cached_file_size_unvalidated(&self, path: &Path) -> anyhow::Result<Option<u64>> {
-        let db = self.db.lock().unwrap();
+        let db = self.db.get();
         Ok(db
             .get_file_size_by_path(path)?
             .map(|cached| cached.last_file_size))
     }
 
+    /// Cheaply gets the tags of a file from cache without validating it.
+    ///
+    /// This does not require reading the cache key first, which requires accessing the file and can
+    /// be expensive. This should be used if using stale tags is allowable and needs to be fast.
+    pub fn get_cached_tags_unvalidated(&self, path: &Path) -> anyhow::Result<Option<FileTags>> {
+        let db = self.db.get();
+        db.get_file_tags_by_path(path)
+    }
+
     /// Prepares durations for multiple files.
     pub fn batch_get_durations(&self, paths: Vec<PathBuf>) -> anyhow::Result<()> {
         // get cached durations
         let cached = {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
             db.get_file_sizes_by_paths(paths.iter().map(|p| p.to_string_lossy()))?
         };
 
@@ -304,11 +341,94 @@ impl HashCache {
 
         // store new sizes
         {
-            let mut db = self.db.lock().unwrap();
+            let mut db = self.db.get();
             db.insert_file_sizes(insert_sizes.into_iter().flatten())
                 .context("failed to insert file sizes")?;
         }
 
         Ok(())
     }
+
+    /// Prepares tags for multiple files.
+    pub fn batch_get_tags(&self, paths: Vec<PathBuf>) -> anyhow::Result<()> {
+        // get cached tags
+        let cached = {
+            let db = self.db.get();
+            db.get_file_tags_by_paths(paths.iter().map(|p| p.to_string_lossy()))?
+        };
+
+        let mut changes = Vec::new();
+        paths
+            .par_iter()
+            .map(|path| {
+                let cached = cached.get(path.to_string_lossy().as_ref());
+
+                // get file metadata
+                let key = match CacheKey::read_metadata(path) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        warn!(
+                            "failed to read metadata for file: {}: {:#}",
+                            path.display(),
+                            e
+                        );
+                        return cached.map(|_| TagsChange::Remove(path.to_string_lossy()));
+                    }
+                };
+
+                // check if cached tags match current metadata
+                if let Some(cached) = cached {
+                    if key.matches_file_tags(cached) {
+                        return None;
+                    }
+                }
+
+                // get new tags
+                let tags = match musicopy_transcode::hash::get_file_tags(path) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("failed to get file tags for {}: {:#}", path.display(), e);
+                        return cached.map(|_| TagsChange::Remove(path.to_string_lossy()));
+                    }
+                };
+
+                Some(TagsChange::Insert(InsertFileTags {
+                    path: path.to_string_lossy(),
+                    last_file_size: key.file_size,
+                    last_modified_at: key.modified_at,
+                    title: tags.title,
+                    artist: tags.artist,
+                    album: tags.album,
+                    album_artist: tags.album_artist,
+                    track_number: tags.track_number,
+                    disc_number: tags.disc_number,
+                    duration: tags.duration,
+                    codec: tags.codec,
+                    sample_rate: tags.sample_rate,
+                }))
+            })
+            .collect_into_vec(&mut changes);
+
+        let mut insert_tags = Vec::new();
+        let mut remove_tags = Vec::new();
+        for change in changes.into_iter().flatten() {
+            match change {
+                TagsChange::Insert(tags) => insert_tags.push(tags),
+                TagsChange::Remove(path) => remove_tags.push(path),
+            }
+        }
+
+        // store new tags and remove stale tags of files that changed but couldn't be read
+        {
+            let mut db = self.db.get();
+            db.insert_file_tags(insert_tags.into_iter())
+                .context("failed to insert file tags")?;
+            if !remove_tags.is_empty() {
+                db.remove_file_tags(remove_tags.into_iter())
+                    .context("failed to remove stale file tags")?;
+            }
+        }
+
+        Ok(())
+    }
 }
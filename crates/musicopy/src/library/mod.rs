@@ -1,35 +1,89 @@
 pub mod hash;
+pub mod suggest;
 pub mod transcode;
 
 use crate::{
     EventHandler,
-    database::{Database, InsertFile},
+    database::{DatabasePool, InsertFile},
+    fs::TreePath,
     library::{
         hash::HashCache,
         transcode::{TranscodeCommand, TranscodeFormat, TranscodePool, TranscodeStatusCache},
     },
-    model::CounterModel,
+    model::{CounterModel, SharedList},
     node::FileSizeModel,
+    settings::TranscodePolicy,
+    status::{LibraryStatus, RecentErrors, StatusErrorSource},
 };
 use anyhow::Context;
 use iroh::EndpointId;
-use itertools::Itertools;
 use std::{
     collections::HashSet,
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
     time::Duration,
 };
-use tokio::sync::{Notify, mpsc};
+use tokio::sync::{Notify, mpsc, watch};
 use tracing::{debug, error, info, warn};
 
-#[derive(Debug, Clone, uniffi::Record)]
+/// Maximum number of tracks returned by a search.
+const SEARCH_LIMIT: u64 = 200;
+
+/// Extensions of files included in the library.
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "ogg", "opus", "m4a", "wav", "aif", "aiff"];
+
+/// Number of files found by a scan that are buffered before walking waits for them to be inserted.
+const SCAN_CHANNEL_CAPACITY: usize = 4096;
+
+/// Number of files found by a scan that are inserted into the database at a time.
+const SCAN_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
 pub struct LibraryRootModel {
     pub name: String,
     pub path: String,
     pub num_files: u64,
 }
 
+/// Model of the library roots, shared by the snapshots it's in.
+pub type LibraryRootsModel = SharedList<LibraryRootModel>;
+
+uniffi::custom_type!(LibraryRootsModel, Vec<LibraryRootModel>, {
+    lower: |roots| roots.into(),
+    try_lift: |roots| Ok(roots.into()),
+});
+
+/// A track in the local library.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct LibraryTrackModel {
+    pub root: String,
+    pub path: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+    pub codec: Option<String>,
+    pub sample_rate: Option<u32>,
+}
+
+/// An album in the local library.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct LibraryAlbumModel {
+    /// The album artist, or the artist if the tracks don't have one.
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_count: u64,
+    /// Total duration in seconds.
+    pub duration: f64,
+}
+
 /// Library state sent to the UI.
 ///
 /// Needs to be Clone to send snapshots to the UI.
@@ -37,7 +91,7 @@ pub struct LibraryRootModel {
 pub struct LibraryModel {
     pub is_scanning: bool,
 
-    pub local_roots: Vec<LibraryRootModel>,
+    pub local_roots: LibraryRootsModel,
 
     pub transcodes_dir: String,
     pub transcodes_dir_size: FileSizeModel,
@@ -48,19 +102,74 @@ pub struct LibraryModel {
     pub transcode_count_failed: Arc<CounterModel>,
 }
 
+/// A change to the library model, sent instead of a full snapshot when model diffs are enabled.
+///
+/// Versions count up by one with each change. If a diff's version isn't one more than the last
+/// one applied, the UI missed a change and should fetch a versioned snapshot to resync.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct LibraryModelDiff {
+    pub version: u64,
+    pub patch: LibraryModelPatch,
+}
+
+/// The part of the library model that changed, with its new value.
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum LibraryModelPatch {
+    LocalRoots { local_roots: LibraryRootsModel },
+    TranscodesDirSize { transcodes_dir_size: FileSizeModel },
+    Scanning { is_scanning: bool },
+}
+
+impl LibraryModel {
+    /// Applies a patch from a diff to the model.
+    pub fn apply_patch(&mut self, patch: LibraryModelPatch) {
+        match patch {
+            LibraryModelPatch::LocalRoots { local_roots } => self.local_roots = local_roots,
+            LibraryModelPatch::TranscodesDirSize {
+                transcodes_dir_size,
+            } => self.transcodes_dir_size = transcodes_dir_size,
+            LibraryModelPatch::Scanning { is_scanning } => self.is_scanning = is_scanning,
+        }
+    }
+}
+
+/// A snapshot of the library model with the version of the last change it includes.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct VersionedLibraryModel {
+    pub version: u64,
+    pub model: LibraryModel,
+}
+
 #[derive(Debug)]
 pub enum LibraryCommand {
-    AddRoot { name: String, path: String },
-    RemoveRoot { name: String },
+    AddRoot {
+        name: String,
+        path: String,
+    },
+    RemoveRoot {
+        name: String,
+    },
     Rescan,
 
     RequestTranscodes(TranscodeFormat, HashSet<PathBuf>),
+    PrioritizeTranscodes(TranscodeFormat, HashSet<PathBuf>),
 
     DeleteUnusedTranscodes,
     DeleteAllTranscodes,
 
+    /// Set when local files are transcoded, and the format to transcode them to ahead of time.
+    SetTranscodePolicy(TranscodePolicy, Option<TranscodeFormat>),
+    SetTranscodeWorkers(u32),
+    /// Called once the transcodes requested before it are queued.
+    FlushTranscodes(tokio::sync::oneshot::Sender<()>),
+
     RefreshModel,
+    /// Set whether the app is in the background, where model changes aren't sent.
+    SetBackground(bool),
 
+    /// Stop scanning and starting transcodes, and let the current transcodes finish, before
+    /// shutting down.
+    Drain,
     Stop,
 }
 
@@ -71,18 +180,44 @@ enum LibraryModelUpdate {
     SetScanning(bool),
 }
 
+/// How long the library scans since launch took.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanDurations {
+    pub last: Option<Duration>,
+    pub total: Duration,
+}
+
 pub struct Library {
     event_handler: Arc<dyn EventHandler>,
-    db: Arc<Mutex<Database>>,
+    db: Arc<DatabasePool>,
     local_endpoint_id: EndpointId,
 
+    hash_cache: HashCache,
     transcode_pool: TranscodePool,
+    transcode_policy: Mutex<(TranscodePolicy, Option<TranscodeFormat>)>,
 
     command_tx: mpsc::UnboundedSender<LibraryCommand>,
 
     scan_notify: Arc<Notify>,
+    /// Number of finished scans, so the node can send clients the changes to their index.
+    scans: watch::Sender<u64>,
+    /// Whether the tags of local files were loaded at startup, so the node can send clients the
+    /// tags missing from indexes sent before.
+    startup_tags_loaded: watch::Sender<bool>,
+    /// How long scans took, for metrics.
+    scan_durations: Mutex<ScanDurations>,
+    /// Whether the library is shutting down, so rescans are ignored.
+    draining: AtomicBool,
 
     model: Mutex<LibraryModel>,
+    /// Version of the last change to the model, counted while holding the model lock.
+    model_version: AtomicU64,
+    /// Whether model changes are sent as diffs instead of full snapshots.
+    model_diffs: bool,
+    /// Whether the app is in the background, so model changes aren't sent.
+    background: AtomicBool,
+    /// Recent scan errors, for the status summary.
+    recent_errors: RecentErrors,
 }
 
 // stub debug implementation
@@ -105,22 +240,26 @@ pub struct LibraryRun {
 impl Library {
     pub async fn new(
         event_handler: Arc<dyn EventHandler>,
-        db: Arc<Mutex<Database>>,
+        db: Arc<DatabasePool>,
         local_endpoint_id: EndpointId,
         transcodes_dir: PathBuf,
         transcode_status_cache: TranscodeStatusCache,
         hash_cache: HashCache,
+        model_diffs: bool,
     ) -> anyhow::Result<(Arc<Self>, LibraryRun)> {
         // spawn transcode pool task
-        let transcode_pool =
-            TranscodePool::spawn(transcodes_dir.clone(), transcode_status_cache, hash_cache);
+        let transcode_pool = TranscodePool::spawn(
+            transcodes_dir.clone(),
+            transcode_status_cache,
+            hash_cache.clone(),
+        );
 
         let (command_tx, command_rx) = mpsc::unbounded_channel();
 
         let model = LibraryModel {
             is_scanning: false,
 
-            local_roots: Vec::new(),
+            local_roots: LibraryRootsModel::default(),
 
             transcodes_dir: transcode_pool.transcodes_dir(),
             transcodes_dir_size: transcode_pool.transcodes_dir_size(),
@@ -136,13 +275,23 @@ impl Library {
             db,
             local_endpoint_id,
 
+            hash_cache,
             transcode_pool,
+            transcode_policy: Mutex::new((TranscodePolicy::default(), None)),
 
             command_tx,
 
             scan_notify: Arc::new(Notify::new()),
+            scans: watch::Sender::new(0),
+            startup_tags_loaded: watch::Sender::new(false),
+            scan_durations: Mutex::new(ScanDurations::default()),
+            draining: AtomicBool::new(false),
 
             model: Mutex::new(model),
+            model_version: AtomicU64::new(0),
+            model_diffs,
+            background: AtomicBool::new(false),
+            recent_errors: RecentErrors::default(),
         });
 
         // initialize model
@@ -150,10 +299,19 @@ impl Library {
         library.update_model(LibraryModelUpdate::UpdateLocalRoots);
 
         // send all local files to the transcode pool to be transcoded if needed
-        library
+        let items = library
             .check_transcodes()
             .context("failed to check transcodes")?;
 
+        // load tags in the background, since they're usually cached from the last launch
+        tokio::spawn({
+            let library = library.clone();
+            async move {
+                library.load_tags(items.into_iter().collect()).await;
+                library.startup_tags_loaded.send_replace(true);
+            }
+        });
+
         // spawn scan task
         tokio::spawn({
             let library = library.clone();
@@ -171,22 +329,33 @@ impl Library {
                         error!("Library: error during scan: {e:#}");
                     }
 
-                    let elapsed = start.elapsed().as_secs_f64();
-                    debug!("Library: finished library scan in {elapsed:.2}s");
+                    let elapsed = start.elapsed();
+                    debug!(
+                        "Library: finished library scan in {:.2}s",
+                        elapsed.as_secs_f64()
+                    );
+
+                    {
+                        let mut scan_durations = library.scan_durations.lock().unwrap();
+                        scan_durations.last = Some(elapsed);
+                        scan_durations.total += elapsed;
+                    }
 
                     // update root file counts and clear scanning flag in model
                     library.update_model(LibraryModelUpdate::UpdateLocalRoots);
                     library.update_model(LibraryModelUpdate::SetScanning(false));
+
+                    library.scans.send_modify(|scans| *scans += 1);
                 }
             }
         });
 
-        // spawn transcodes dir size polling task
+        // spawn task to update the transcodes dir size when transcodes finish or are deleted
         tokio::spawn({
             let library = library.clone();
+            let mut transcodes_dir_size = library.transcode_pool.subscribe_transcodes_dir_size();
             async move {
-                loop {
-                    tokio::time::sleep(Duration::from_secs(1)).await;
+                while transcodes_dir_size.changed().await.is_ok() {
                     library.update_model(LibraryModelUpdate::UpdateTranscodesDirSize);
                 }
             }
@@ -197,6 +366,21 @@ impl Library {
         Ok((library, library_run))
     }
 
+    /// Subscribes to finished scans, which can add, change, or remove files.
+    pub fn subscribe_scans(&self) -> watch::Receiver<u64> {
+        self.scans.subscribe()
+    }
+
+    /// Subscribes to the tags of local files being loaded at startup.
+    pub fn subscribe_startup_tags(&self) -> watch::Receiver<bool> {
+        self.startup_tags_loaded.subscribe()
+    }
+
+    /// Whether the library is shutting down, so rescans are ignored.
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
     pub async fn run(self: &Arc<Self>, run_token: LibraryRun) -> anyhow::Result<()> {
         let LibraryRun { mut command_rx } = run_token;
 
@@ -206,10 +390,16 @@ impl Library {
                     match command {
                         LibraryCommand::AddRoot { name, path } => {
                             {
-                                let db = self.db.lock().unwrap();
-                                let path = PathBuf::from(path);
-                                let path = path.canonicalize().context("failed to canonicalize path")?;
-                                db.add_root(self.local_endpoint_id, &name, &path.to_string_lossy()).context("failed to add root")?;
+                                let db = self.db.get();
+                                // document tree URIs are opaque and can't be canonicalized
+                                let path = if crate::fs::is_document_tree(&path) {
+                                    path
+                                } else {
+                                    let path = PathBuf::from(path);
+                                    let path = path.canonicalize().context("failed to canonicalize path")?;
+                                    path.to_string_lossy().to_string()
+                                };
+                                db.add_root(self.local_endpoint_id, &name, &path).context("failed to add root")?;
                             }
 
                             // update model
@@ -221,7 +411,7 @@ impl Library {
 
                         LibraryCommand::RemoveRoot { name } => {
                             {
-                                let db = self.db.lock().unwrap();
+                                let db = self.db.get();
                                 db.delete_root_by_name(self.local_endpoint_id, &name).context("failed to delete root")?;
                             }
 
@@ -235,7 +425,11 @@ impl Library {
                         }
 
                         LibraryCommand::Rescan => {
-                            self.scan_notify.notify_one();
+                            if self.draining.load(Ordering::Relaxed) {
+                                debug!("LibraryCommand::Rescan: ignoring rescan while shutting down");
+                            } else {
+                                self.scan_notify.notify_one();
+                            }
                         }
 
                         LibraryCommand::RequestTranscodes(format, paths) => {
@@ -243,11 +437,16 @@ impl Library {
                                 warn!("LibraryCommand::RequestTranscodes: failed to send to transcode pool: {e:#}");
                             }
                         }
+                        LibraryCommand::PrioritizeTranscodes(format, paths) => {
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::Prioritize(format, paths)) {
+                                warn!("LibraryCommand::PrioritizeTranscodes: failed to send to transcode pool: {e:#}");
+                            }
+                        }
 
                         LibraryCommand::DeleteUnusedTranscodes => {
                             // get local file paths
                             let local_files = {
-                                let db = self.db.lock().expect("failed to lock database");
+                                let db = self.db.get();
                                 db.get_files_by_node_id(self.local_endpoint_id)
                                     .context("failed to get local files")?
                                     .into_iter()
@@ -266,11 +465,46 @@ impl Library {
                             }
                         }
 
+                        LibraryCommand::SetTranscodePolicy(policy, format) => {
+                            *self.transcode_policy.lock().unwrap() = (policy, format);
+
+                            // transcode the existing files if the policy now needs it
+                            let res = self.local_files().and_then(|items| self.transcode_ahead(items));
+                            if let Err(e) = res {
+                                warn!("LibraryCommand::SetTranscodePolicy: failed to request transcodes: {e:#}");
+                            }
+                        }
+                        LibraryCommand::FlushTranscodes(callback) => {
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::Flush(callback)) {
+                                warn!("LibraryCommand::FlushTranscodes: failed to send to transcode pool: {e:#}");
+                            }
+                        }
+                        LibraryCommand::SetTranscodeWorkers(workers) => {
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::SetWorkers(workers)) {
+                                warn!("LibraryCommand::SetTranscodeWorkers: failed to send to transcode pool: {e:#}");
+                            }
+                        }
+
                         LibraryCommand::RefreshModel => {
                             self.update_model(LibraryModelUpdate::UpdateLocalRoots);
                             self.update_model(LibraryModelUpdate::UpdateTranscodesDirSize);
                         }
 
+                        LibraryCommand::SetBackground(background) => {
+                            self.background.store(background, Ordering::Relaxed);
+                            if !background {
+                                // catch the UI up on the changes that weren't sent
+                                self.update_model(LibraryModelUpdate::UpdateLocalRoots);
+                            }
+                        }
+
+                        LibraryCommand::Drain => {
+                            self.draining.store(true, Ordering::Relaxed);
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::Drain) {
+                                warn!("LibraryCommand::Drain: failed to send to transcode pool: {e:#}");
+                            }
+                        }
+
                         LibraryCommand::Stop => {
                             break;
                         }
@@ -287,175 +521,282 @@ impl Library {
         Ok(())
     }
 
+    /// Scans the local roots and replaces the local files in the database with the files found.
+    ///
+    /// Roots are walked in parallel, and the files found are streamed through a bounded channel
+    /// into batched inserts, so big libraries aren't held in memory. Each batch is inserted in its
+    /// own transaction and the root file counts are updated after it, so other work isn't blocked
+    /// and the counts grow while a long scan runs. Files that weren't found are removed once every
+    /// root is walked.
     async fn scan(self: &Arc<Self>) -> anyhow::Result<()> {
-        let mut errors = Vec::new();
-
         let roots = {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
+            db.clear_scanned_files()?;
             db.get_roots_by_node_id(self.local_endpoint_id)
                 .context("failed to get local roots")?
         };
 
         info!("scan: scanning {} roots", roots.len());
 
-        // remove roots that don't exist
-        let roots = roots
-            .into_iter()
-            .filter(|root| {
-                let path = PathBuf::from(&root.path);
-                if path.exists() {
-                    true
-                } else {
-                    errors.push(anyhow::anyhow!(
-                        "root path `{}` does not exist",
-                        path.display()
-                    ));
-                    false
-                }
-            })
-            .collect::<Vec<_>>();
-
-        // walk roots and collect entries
-        let (entries, walk_errors): (Vec<_>, Vec<_>) = roots
-            .iter()
-            .flat_map(|root| {
-                let walker = globwalk::GlobWalkerBuilder::new(
-                    &root.path,
-                    "*.{mp3,flac,ogg,opus,m4a,wav,aif,aiff}",
-                )
-                .file_type(globwalk::FileType::FILE)
-                .build()
-                .expect("glob shouldn't fail");
-
-                walker.into_iter().map_ok(move |entry| (root, entry))
-            })
-            .partition_result();
-
-        info!("scan: found {} files", entries.len());
-
-        // extend errors
-        errors.extend(
-            walk_errors
-                .into_iter()
-                .map(|e| anyhow::anyhow!("failed to scan file {:?}: {}", e.path(), e)),
-        );
-
-        struct ScanItem {
-            root: String,
-            path: String,
-            local_path: String,
+        let (item_tx, mut item_rx) = mpsc::channel(SCAN_CHANNEL_CAPACITY);
+        for root in roots {
+            let item_tx = item_tx.clone();
+            if crate::fs::is_document_tree(&root.path) {
+                // document tree roots are walked separately using the fs module
+                tokio::spawn(async move { walk_tree_root(&root.name, &root.path, item_tx).await });
+            } else {
+                tokio::task::spawn_blocking(move || walk_root(&root.name, &root.path, item_tx));
+            }
         }
+        drop(item_tx);
 
-        let (items, scan_errors): (Vec<_>, Vec<_>) = entries
-            .into_iter()
-            .map(|(root, entry)| {
-                let local_path = entry.into_path();
-
-                // get path without root
-                let path = local_path
-                    .strip_prefix(&root.path)
-                    .context("failed to strip root path prefix")?;
-
-                // strip leading separator if present
-                //
-                // this happens when the root is a verbatim UNC path like \\?\UNC\\server\share,
-                // which is parsed as Component::Prefix instead of Component::Prefix + Component::RootDir,
-                // so the leading separator isn't stripped above and needs to be stripped here.
-                //
-                // even if some other case is possible, the path is always supposed to be
-                // relative to the root, so it should be fine to strip it here.
-                let path = if path.starts_with(std::path::MAIN_SEPARATOR_STR) {
-                    path.strip_prefix(std::path::MAIN_SEPARATOR_STR)
-                        .context("failed to strip leading separator")?
-                } else {
-                    path
-                };
+        // insert files in batches as they're found
+        let mut errors = Vec::new();
+        let mut num_files = 0;
+        let mut batch: Vec<ScanItem> = Vec::with_capacity(SCAN_BATCH_SIZE);
+        loop {
+            let item = item_rx.recv().await;
+            let done = item.is_none();
+            match item {
+                Some(Ok(item)) => batch.push(item),
+                Some(Err(e)) => errors.push(e),
+                None => {}
+            }
 
-                // convert to slash path (replace backslashes on windows)
-                use path_slash::PathExt;
-                let path = path.to_slash_lossy().to_string();
+            if batch.len() >= SCAN_BATCH_SIZE || (done && !batch.is_empty()) {
+                {
+                    let mut db = self.db.get();
+                    db.insert_scanned_files(
+                        self.local_endpoint_id,
+                        batch.iter().map(|item| InsertFile {
+                            root: &item.root,
+                            path: &item.path,
+                            // local_tree is only used for remote files and document tree roots
+                            local_tree: &item.local_tree,
+                            local_path: &item.local_path,
+                        }),
+                    )
+                    .context("failed to insert files into database")?;
+                }
+                num_files += batch.len();
+                batch.clear();
 
-                anyhow::Result::Ok(ScanItem {
-                    root: root.name.clone(),
-                    path,
-                    local_path: local_path.to_string_lossy().to_string(),
-                })
-            })
-            .partition_result();
+                // show progress and let other work use the database between batches
+                self.update_model(LibraryModelUpdate::UpdateLocalRoots);
+                tokio::task::yield_now().await;
+            }
+            if done {
+                break;
+            }
+        }
 
-        // extend errors
-        errors.extend(
-            scan_errors
-                .into_iter()
-                .map(|e: anyhow::Error| e.context("failed to scan file")),
-        );
+        info!("scan: found {num_files} files");
 
+        if let Some(first) = errors.first() {
+            self.recent_errors.push(
+                StatusErrorSource::Scan,
+                format!("{} errors scanning library, first: {first:#}", errors.len()),
+            );
+        }
         for error in errors {
             error!("error scanning library: {error:#}");
         }
 
         {
-            let mut db = self.db.lock().unwrap();
-            db.replace_local_files(
-                self.local_endpoint_id,
-                items.iter().map(|item| InsertFile {
-                    root: &item.root,
-                    path: &item.path,
-                    local_tree: "", // local_tree is only used for remote files
-                    local_path: &item.local_path,
-                }),
-            )
-            .context("failed to insert files into database")?;
+            let mut db = self.db.get();
+            db.remove_unscanned_files(self.local_endpoint_id)
+                .context("failed to remove missing files from database")?;
         }
 
-        info!("scan: inserted {} files into database", items.len());
+        info!("scan: inserted {num_files} files into database");
 
         // send local files to transcode pool
-        let items = items
-            .into_iter()
-            .map(|item| PathBuf::from(item.local_path))
-            .collect::<HashSet<_>>();
+        let items = self.check_transcodes()?;
 
-        self.transcode_pool.send(TranscodeCommand::Load(items))?;
+        // load tags before the scan finishes, so the index sent to clients after it has them
+        self.load_tags(items.into_iter().collect()).await;
 
         Ok(())
     }
 
-    /// Send all local files to the transcode pool to be transcoded if needed.
-    fn check_transcodes(&self) -> anyhow::Result<()> {
+    /// Send all local files to the transcode pool to be transcoded if needed, returning them.
+    fn check_transcodes(&self) -> anyhow::Result<HashSet<PathBuf>> {
+        let items = self.local_files()?;
+
+        self.transcode_pool
+            .send(TranscodeCommand::Load(items.clone()))?;
+        self.transcode_ahead(items.clone())?;
+
+        Ok(items)
+    }
+
+    /// Gets the paths of local files that can be transcoded.
+    // TODO: support transcoding files in document trees
+    fn local_files(&self) -> anyhow::Result<HashSet<PathBuf>> {
         let local_files = {
-            let db = self.db.lock().expect("failed to lock database");
+            let db = self.db.get();
             db.get_files_by_node_id(self.local_endpoint_id)
                 .context("failed to get local files")?
         };
 
-        let items = local_files
+        Ok(local_files
             .into_iter()
+            .filter(|file| file.local_tree.is_empty())
             .map(|file| PathBuf::from(file.local_path))
-            .collect::<HashSet<_>>();
+            .collect())
+    }
 
-        self.transcode_pool.send(TranscodeCommand::Load(items))?;
+    /// Requests transcodes of local files if the transcode policy is to transcode them ahead of
+    /// time. Files that are already transcoded are skipped by the transcode pool.
+    fn transcode_ahead(&self, items: HashSet<PathBuf>) -> anyhow::Result<()> {
+        let (policy, format) = *self.transcode_policy.lock().unwrap();
+        if let (TranscodePolicy::AheadOfTime, Some(format)) = (policy, format) {
+            self.transcode_pool
+                .send(TranscodeCommand::Request(format, items))?;
+        }
 
         Ok(())
     }
 
+    /// Reads and caches the tags of local files, so they can be sent to clients for browsing by
+    /// artist and album, then rebuilds the tracks from them.
+    // TODO: support reading tags of files in document trees
+    async fn load_tags(&self, items: Vec<PathBuf>) {
+        let start = std::time::Instant::now();
+        info!("Library: getting tags for {} files", items.len());
+
+        let hash_cache = self.hash_cache.clone();
+        let Ok(res) = tokio::task::spawn_blocking(move || hash_cache.batch_get_tags(items)).await
+        else {
+            error!("Library: failed to join file tags task");
+            return;
+        };
+
+        match res {
+            Ok(_) => {
+                let elapsed = start.elapsed().as_secs_f64();
+                info!("Library: finished getting file tags in {elapsed:.2}s");
+
+                let mut db = self.db.get();
+                if let Err(e) = db.refresh_tracks(self.local_endpoint_id) {
+                    error!("Library: failed to refresh tracks: {e:#}");
+                }
+            }
+            Err(e) => {
+                error!("Library: failed to get file tags: {e:#}");
+            }
+        }
+    }
+
     pub fn send(self: &Arc<Self>, command: LibraryCommand) -> anyhow::Result<()> {
         self.command_tx
             .send(command)
             .map_err(|e| anyhow::anyhow!("failed to send command: {e:?}"))
     }
 
+    /// Searches the local library's tracks by title, artist or album, most relevant first.
+    pub fn search(self: &Arc<Self>, query: &str) -> anyhow::Result<Vec<LibraryTrackModel>> {
+        let tracks = {
+            let db = self.db.get();
+            db.search_tracks(self.local_endpoint_id, query, SEARCH_LIMIT)
+                .context("failed to search tracks")?
+        };
+
+        Ok(tracks
+            .into_iter()
+            .map(|(file, track)| LibraryTrackModel {
+                root: file.root,
+                path: file.path,
+                title: track.title,
+                artist: track.artist,
+                album: track.album,
+                album_artist: track.album_artist,
+                track_number: track.track_number,
+                disc_number: track.disc_number,
+                duration: track.duration,
+                codec: track.codec,
+                sample_rate: track.sample_rate,
+            })
+            .collect())
+    }
+
+    /// Lists the local library's albums, sorted by artist and album.
+    pub fn browse_albums(self: &Arc<Self>) -> anyhow::Result<Vec<LibraryAlbumModel>> {
+        let albums = {
+            let db = self.db.get();
+            db.get_albums_by_node_id(self.local_endpoint_id)
+                .context("failed to get albums")?
+        };
+
+        Ok(albums
+            .into_iter()
+            .map(|album| LibraryAlbumModel {
+                artist: album.artist,
+                album: album.album,
+                track_count: album.track_count,
+                duration: album.duration,
+            })
+            .collect())
+    }
+
     pub fn get_model(self: &Arc<Self>) -> LibraryModel {
         let model = self.model.lock().unwrap();
         model.clone()
     }
 
+    /// Gets the library's part of the status summary without copying the model.
+    pub(crate) fn get_status(&self) -> LibraryStatus {
+        let model = self.model.lock().unwrap();
+        LibraryStatus {
+            is_scanning: model.is_scanning,
+            library_files: model.local_roots.iter().map(|root| root.num_files).sum(),
+            transcodes_queued: model.transcode_count_queued.get(),
+            transcodes_in_progress: model.transcode_count_inprogress.get(),
+            transcodes_failed: model.transcode_count_failed.get(),
+            transcodes_dir_size: model.transcodes_dir_size.clone(),
+            recent_errors: self.recent_errors.list(),
+        }
+    }
+
+    /// Gets the number of finished scans since launch and how long they took.
+    pub fn scan_metrics(&self) -> (u64, ScanDurations) {
+        (*self.scans.borrow(), *self.scan_durations.lock().unwrap())
+    }
+
+    /// Gets a snapshot of the model with its version, to resync after missing a diff.
+    pub fn get_versioned_model(self: &Arc<Self>) -> VersionedLibraryModel {
+        let model = self.model.lock().unwrap();
+        VersionedLibraryModel {
+            version: self.model_version.load(Ordering::SeqCst),
+            model: model.clone(),
+        }
+    }
+
+    /// Sends a change to the model as a diff if diffs are enabled, or as a full snapshot.
+    ///
+    /// Must be called while holding the model lock, so versions are sent in order.
+    fn send_model_change(&self, model: &LibraryModel, patch: impl FnOnce() -> LibraryModelPatch) {
+        let version = self.model_version.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.background.load(Ordering::Relaxed) {
+            // the UI isn't shown, and gets a fresh model when it's back in the foreground
+            return;
+        }
+        if self.model_diffs {
+            self.event_handler.on_library_model_diff(LibraryModelDiff {
+                version,
+                patch: patch(),
+            });
+        } else {
+            self.event_handler.on_library_model_snapshot(model.clone());
+        }
+    }
+
     // TODO: throttle pushing updates?
     fn update_model(self: &Arc<Self>, update: LibraryModelUpdate) {
         match update {
             LibraryModelUpdate::UpdateLocalRoots => {
                 let local_roots = {
-                    let db = self.db.lock().unwrap();
+                    let db = self.db.get();
                     db.get_roots_by_node_id(self.local_endpoint_id)
                         .expect("failed to get local roots")
                         .into_iter()
@@ -474,28 +815,159 @@ impl Library {
                                 num_files: count,
                             }
                         })
-                        .collect()
+                        .collect::<LibraryRootsModel>()
                 };
 
                 let mut model = self.model.lock().unwrap();
+                if model.local_roots == local_roots {
+                    return;
+                }
                 model.local_roots = local_roots;
 
-                self.event_handler.on_library_model_snapshot(model.clone());
+                self.send_model_change(&model, || LibraryModelPatch::LocalRoots {
+                    local_roots: model.local_roots.clone(),
+                });
             }
 
             LibraryModelUpdate::UpdateTranscodesDirSize => {
                 let mut model = self.model.lock().unwrap();
-                model.transcodes_dir_size = self.transcode_pool.transcodes_dir_size();
+                let transcodes_dir_size = self.transcode_pool.transcodes_dir_size();
+                if model.transcodes_dir_size == transcodes_dir_size {
+                    return;
+                }
+                model.transcodes_dir_size = transcodes_dir_size;
 
-                self.event_handler.on_library_model_snapshot(model.clone());
+                self.send_model_change(&model, || LibraryModelPatch::TranscodesDirSize {
+                    transcodes_dir_size: model.transcodes_dir_size.clone(),
+                });
             }
 
             LibraryModelUpdate::SetScanning(scanning) => {
                 let mut model = self.model.lock().unwrap();
                 model.is_scanning = scanning;
 
-                self.event_handler.on_library_model_snapshot(model.clone());
+                self.send_model_change(&model, || LibraryModelPatch::Scanning {
+                    is_scanning: scanning,
+                });
+            }
+        }
+    }
+}
+
+/// A file found by a scan.
+struct ScanItem {
+    root: String,
+    path: String,
+    local_tree: String,
+    local_path: String,
+}
+
+/// Walks a root on the local filesystem and sends the audio files in it to the scan, along with
+/// any errors. Stops early if the scan stops receiving.
+fn walk_root(root_name: &str, root_path: &str, item_tx: mpsc::Sender<anyhow::Result<ScanItem>>) {
+    let path = PathBuf::from(root_path);
+    if !path.exists() {
+        let _ = item_tx.blocking_send(Err(anyhow::anyhow!(
+            "root path `{}` does not exist",
+            path.display()
+        )));
+        return;
+    }
+
+    let walker = globwalk::GlobWalkerBuilder::new(
+        root_path,
+        format!("*.{{{}}}", AUDIO_EXTENSIONS.join(",")),
+    )
+    .file_type(globwalk::FileType::FILE)
+    .build()
+    .expect("glob shouldn't fail");
+
+    for entry in walker {
+        let item = match entry {
+            Ok(entry) => {
+                scan_item(root_name, root_path, entry.into_path()).context("failed to scan file")
             }
+            Err(e) => Err(anyhow::anyhow!("failed to scan file {:?}: {}", e.path(), e)),
+        };
+        if item_tx.blocking_send(item).is_err() {
+            return;
+        }
+    }
+}
+
+/// Builds the scan item of a file found in a root on the local filesystem.
+fn scan_item(root_name: &str, root_path: &str, local_path: PathBuf) -> anyhow::Result<ScanItem> {
+    // get path without root
+    let path = local_path
+        .strip_prefix(root_path)
+        .context("failed to strip root path prefix")?;
+
+    // strip leading separator if present
+    //
+    // this happens when the root is a verbatim UNC path like \\?\UNC\\server\share,
+    // which is parsed as Component::Prefix instead of Component::Prefix + Component::RootDir,
+    // so the leading separator isn't stripped above and needs to be stripped here.
+    //
+    // even if some other case is possible, the path is always supposed to be
+    // relative to the root, so it should be fine to strip it here.
+    let path = if path.starts_with(std::path::MAIN_SEPARATOR_STR) {
+        path.strip_prefix(std::path::MAIN_SEPARATOR_STR)
+            .context("failed to strip leading separator")?
+    } else {
+        path
+    };
+
+    // convert to slash path (replace backslashes on windows)
+    use path_slash::PathExt;
+    let path = path.to_slash_lossy().to_string();
+
+    Ok(ScanItem {
+        root: root_name.to_string(),
+        path,
+        local_tree: String::new(),
+        local_path: local_path.to_string_lossy().to_string(),
+    })
+}
+
+/// Walks a document tree root and sends the audio files in it to the scan, or the error walking
+/// it. Stops early if the scan stops receiving.
+async fn walk_tree_root(
+    root_name: &str,
+    root_path: &str,
+    item_tx: mpsc::Sender<anyhow::Result<ScanItem>>,
+) {
+    let tree_files = match TreePath::from_root(root_path.to_string()) {
+        Ok(tree_path) => crate::fs::walk_files(&tree_path).await,
+        Err(e) => Err(e),
+    };
+    let tree_files = match tree_files {
+        Ok(tree_files) => tree_files,
+        Err(e) => {
+            let _ = item_tx
+                .send(Err(e.context(format!("failed to walk root `{root_path}`"))))
+                .await;
+            return;
+        }
+    };
+
+    let audio_files = tree_files.into_iter().filter(|file| {
+        file.extension()
+            .is_some_and(|extension| AUDIO_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+    });
+    for file in audio_files {
+        use path_slash::PathExt;
+        let path = std::path::Path::new(&*file.path())
+            .to_slash_lossy()
+            .to_string();
+
+        let item = ScanItem {
+            root: root_name.to_string(),
+            path: path.clone(),
+            local_tree: root_path.to_string(),
+            local_path: path,
+        };
+        if item_tx.send(Ok(item)).await.is_err() {
+            return;
         }
     }
 }
@@ -0,0 +1,198 @@
+//! Suggestions for library roots, to make onboarding quicker.
+//!
+//! On desktop, the platform's standard music folders are probed and suggested if they contain
+//! music. On Android, files outside the app can only be read through folders the user picks, so
+//! the music folders of the storage volumes are suggested as places to open the picker at.
+
+#[cfg(not(target_os = "android"))]
+use crate::library::AUDIO_EXTENSIONS;
+#[cfg(not(target_os = "android"))]
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Maximum number of files counted in each suggested folder, to keep probing fast.
+#[cfg(not(target_os = "android"))]
+const MAX_COUNTED_FILES: u64 = 10_000;
+
+/// A folder suggested as a library root.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct SuggestedRootModel {
+    /// Suggested name for the root, like "Music".
+    pub name: String,
+    /// Path to add as a root, or empty if the folder has to be picked first.
+    pub path: String,
+    /// Number of audio files in the folder, or None if it can't be read until it's picked.
+    pub num_files: Option<u64>,
+    /// Whether counting stopped early, so the folder has even more files.
+    pub more_files: bool,
+    /// Document URI to open the Android folder picker at, if the folder has to be picked.
+    pub picker_hint: Option<String>,
+}
+
+/// Suggests library roots that aren't already added, most files first.
+pub(crate) fn suggest_roots(existing_roots: &[String]) -> Vec<SuggestedRootModel> {
+    #[cfg(target_os = "android")]
+    {
+        let _ = existing_roots;
+        android_suggestions()
+    }
+
+    #[cfg(not(target_os = "android"))]
+    {
+        suggest_folders(standard_music_folders(), existing_roots)
+    }
+}
+
+/// Returns the platform's standard music folders that exist, without duplicates.
+#[cfg(not(target_os = "android"))]
+fn standard_music_folders() -> Vec<PathBuf> {
+    let Some(user_dirs) = directories_next::UserDirs::new() else {
+        return Vec::new();
+    };
+
+    // the XDG music dir on Linux, ~/Music on macOS, and the Music known folder on Windows, and
+    // ~/Music in case XDG points elsewhere
+    let mut candidates = Vec::new();
+    if let Some(audio_dir) = user_dirs.audio_dir() {
+        candidates.push(audio_dir.to_owned());
+    }
+    candidates.push(user_dirs.home_dir().join("Music"));
+
+    let mut folders: Vec<PathBuf> = Vec::new();
+    for candidate in candidates {
+        let Ok(canonical) = candidate.canonicalize() else {
+            continue;
+        };
+        if canonical.is_dir() && !folders.contains(&canonical) {
+            folders.push(canonical);
+        }
+    }
+    folders
+}
+
+/// Counts the music in each folder and suggests the ones that have some and aren't roots yet.
+#[cfg(not(target_os = "android"))]
+fn suggest_folders(folders: Vec<PathBuf>, existing_roots: &[String]) -> Vec<SuggestedRootModel> {
+    let existing_roots: Vec<PathBuf> = existing_roots
+        .iter()
+        .filter_map(|root| Path::new(root).canonicalize().ok())
+        .collect();
+
+    let mut suggestions: Vec<SuggestedRootModel> = folders
+        .into_iter()
+        .filter(|folder| !existing_roots.contains(folder))
+        .filter_map(|folder| {
+            let (num_files, more_files) = count_audio_files(&folder);
+            if num_files == 0 {
+                return None;
+            }
+
+            let name = folder
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "Music".to_string());
+            Some(SuggestedRootModel {
+                name,
+                path: folder.to_string_lossy().into_owned(),
+                num_files: Some(num_files),
+                more_files,
+                picker_hint: None,
+            })
+        })
+        .collect();
+    suggestions.sort_by_key(|suggestion| std::cmp::Reverse(suggestion.num_files));
+    suggestions
+}
+
+/// Counts the audio files under a folder, up to the limit. Returns the count and whether the
+/// limit was reached.
+#[cfg(not(target_os = "android"))]
+fn count_audio_files(folder: &Path) -> (u64, bool) {
+    let walker = match globwalk::GlobWalkerBuilder::new(
+        folder,
+        format!("*.{{{}}}", AUDIO_EXTENSIONS.join(",")),
+    )
+    .file_type(globwalk::FileType::FILE)
+    .build()
+    {
+        Ok(walker) => walker,
+        Err(e) => {
+            warn!("failed to walk {}: {e:#}", folder.display());
+            return (0, false);
+        }
+    };
+
+    let num_files = walker
+        .filter_map(Result::ok)
+        .take(MAX_COUNTED_FILES as usize + 1)
+        .count() as u64;
+    (
+        num_files.min(MAX_COUNTED_FILES),
+        num_files > MAX_COUNTED_FILES,
+    )
+}
+
+/// Suggests the music folder of each storage volume, to be picked with the folder picker.
+#[cfg(target_os = "android")]
+fn android_suggestions() -> Vec<SuggestedRootModel> {
+    // /storage has a directory for each mounted volume, named by its UUID, and `emulated` for
+    // the primary storage
+    let mut volumes = vec!["primary".to_string()];
+    match std::fs::read_dir("/storage") {
+        Ok(entries) => {
+            volumes.extend(
+                entries
+                    .filter_map(Result::ok)
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .filter(|name| name != "emulated" && name != "self"),
+            );
+        }
+        Err(e) => warn!("failed to list storage volumes: {e:#}"),
+    }
+
+    volumes
+        .into_iter()
+        .map(|volume| SuggestedRootModel {
+            name: if volume == "primary" {
+                "Music".to_string()
+            } else {
+                "SD card".to_string()
+            },
+            path: String::new(),
+            num_files: None,
+            more_files: false,
+            picker_hint: Some(format!(
+                "content://com.android.externalstorage.documents/document/{volume}%3AMusic"
+            )),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggest_folders() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let minimal = fixtures.join("minimal").canonicalize().unwrap();
+        let multiple = fixtures.join("multiple").canonicalize().unwrap();
+        let empty = testdir::testdir!();
+
+        let suggestions =
+            suggest_folders(vec![minimal.clone(), multiple.clone(), empty.clone()], &[]);
+        let paths: Vec<_> = suggestions.iter().map(|s| PathBuf::from(&s.path)).collect();
+        assert_eq!(paths, vec![multiple.clone(), minimal.clone()]);
+        assert_eq!(suggestions[0].name, "multiple");
+        assert_eq!(suggestions[0].num_files, Some(2));
+        assert!(!suggestions[0].more_files);
+
+        // existing roots aren't suggested again
+        let suggestions = suggest_folders(
+            vec![minimal.clone(), multiple],
+            &[minimal.to_string_lossy().into_owned()],
+        );
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].name, "multiple");
+    }
+}
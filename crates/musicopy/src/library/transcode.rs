@@ -1,4 +1,9 @@
-use crate::{error::CoreError, library::hash::HashCache, model::CounterModel, node::FileSizeModel};
+use crate::{
+    error::{CoreError, LibraryError},
+    library::hash::HashCache,
+    model::CounterModel,
+    node::{FILE_CRC, FileSizeModel},
+};
 use anyhow::Context;
 use dashmap::DashMap;
 use musicopy_transcode::{Mp3Preset, OpusPreset, TranscodePreset, transcode};
@@ -13,11 +18,11 @@ use std::{
     path::{Path, PathBuf},
     str::FromStr,
     sync::{
-        Arc, Condvar, Mutex,
-        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, OnceLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
     },
 };
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, watch};
 use tracing::{debug, error, info, trace, warn};
 
 /// The transcode status of a file.
@@ -30,12 +35,36 @@ pub enum TranscodeStatus {
     Ready {
         transcode_path: PathBuf,
         file_size: u64,
+        /// Checksum of the transcoded file. It's computed after transcoding, or by the first
+        /// transfer of a transcode found in the cache directory at startup.
+        checksum: FileChecksum,
     },
 
     /// Transcoding the file failed.
     Failed { error: anyhow::Error },
 }
 
+/// Checksum of a file that's ready to send, computed at most once and shared by the jobs that send
+/// the file.
+pub type FileChecksum = Arc<OnceLock<u64>>;
+
+/// Computes the checksum of a file, like the one sent to clients that verify received files.
+fn file_checksum(path: &Path) -> anyhow::Result<u64> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path).context("failed to open file")?;
+    let mut digest = FILE_CRC.digest();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).context("failed to read file")?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+    }
+    Ok(digest.finalize())
+}
+
 /// Helper trait for creating a borrowed hash key.
 ///
 /// This is required because we can't use a tuple of borrowed parts, we need a
@@ -126,13 +155,15 @@ impl Deref for TranscodeStatusCacheEntry<'_> {
 /// at any time, and source files can be renamed or moved. This also accounts
 /// for multiple copies of the same file existing in the library.
 ///
-/// Also keeps counts of the number of items with each status.
+/// Also keeps counts of the number of items with each status, and the total size of the ready
+/// transcodes, which is watched to update the model when it changes.
 #[derive(Debug, Clone)]
 pub struct TranscodeStatusCache {
     cache: Arc<DashMap<(TranscodeFormat, String, [u8; 16]), TranscodeStatus>>,
 
     ready_counter: Arc<AtomicU64>,
     failed_counter: Arc<AtomicU64>,
+    ready_size: Arc<watch::Sender<u64>>,
 }
 
 impl TranscodeStatusCache {
@@ -143,6 +174,7 @@ impl TranscodeStatusCache {
 
             ready_counter: Arc::new(AtomicU64::new(0)),
             failed_counter: Arc::new(AtomicU64::new(0)),
+            ready_size: Arc::new(watch::Sender::new(0)),
         }
     }
 
@@ -166,9 +198,11 @@ impl TranscodeStatusCache {
         hash: [u8; 16],
         status: TranscodeStatus,
     ) {
+        let mut added_size = 0;
         match status {
-            TranscodeStatus::Ready { .. } => {
+            TranscodeStatus::Ready { file_size, .. } => {
                 self.ready_counter.fetch_add(1, Ordering::Relaxed);
+                added_size = file_size;
             }
             TranscodeStatus::Failed { .. } => {
                 self.failed_counter.fetch_add(1, Ordering::Relaxed);
@@ -177,15 +211,30 @@ impl TranscodeStatusCache {
 
         let prev = self.cache.insert((format, hash_kind, hash), status);
 
+        let mut removed_size = 0;
         match prev {
-            Some(TranscodeStatus::Ready { .. }) => {
+            Some(TranscodeStatus::Ready { file_size, .. }) => {
                 self.ready_counter.fetch_sub(1, Ordering::Relaxed);
+                removed_size = file_size;
             }
             Some(TranscodeStatus::Failed { .. }) => {
                 self.failed_counter.fetch_sub(1, Ordering::Relaxed);
             }
             None => {}
         }
+
+        self.change_ready_size(added_size, removed_size);
+    }
+
+    /// Adds and removes bytes from the size of the ready transcodes, notifying watchers if it
+    /// changed.
+    fn change_ready_size(&self, added: u64, removed: u64) {
+        self.ready_size.send_if_modified(|size| {
+            let new_size = (*size + added).saturating_sub(removed);
+            let modified = new_size != *size;
+            *size = new_size;
+            modified
+        });
     }
 
     /// Retain elements according to the predicate, updating counters as needed.
@@ -193,12 +242,14 @@ impl TranscodeStatusCache {
         &self,
         mut f: impl FnMut(&(TranscodeFormat, String, [u8; 16]), &TranscodeStatus) -> bool,
     ) {
+        let mut removed_size = 0;
         self.cache.retain(|key, status| {
             let keep = f(key, status);
             if !keep {
                 match status {
-                    TranscodeStatus::Ready { .. } => {
+                    TranscodeStatus::Ready { file_size, .. } => {
                         self.ready_counter.fetch_sub(1, Ordering::Relaxed);
+                        removed_size += *file_size;
                     }
                     TranscodeStatus::Failed { .. } => {
                         self.failed_counter.fetch_sub(1, Ordering::Relaxed);
@@ -207,6 +258,7 @@ impl TranscodeStatusCache {
             }
             keep
         });
+        self.change_ready_size(0, removed_size);
     }
 
     pub fn ready_counter(&self) -> &Arc<AtomicU64> {
@@ -216,6 +268,16 @@ impl TranscodeStatusCache {
     pub fn failed_counter(&self) -> &Arc<AtomicU64> {
         &self.failed_counter
     }
+
+    /// Gets the total size of the ready transcodes in bytes.
+    pub fn ready_size(&self) -> u64 {
+        *self.ready_size.borrow()
+    }
+
+    /// Subscribes to changes of the total size of the ready transcodes.
+    pub fn subscribe_ready_size(&self) -> watch::Receiver<u64> {
+        self.ready_size.subscribe()
+    }
 }
 
 impl Default for TranscodeStatusCache {
@@ -232,15 +294,30 @@ pub enum TranscodeFormat {
     Opus64,
     Mp3V0,
     Mp3V5,
+    Opus192,
+    Opus96,
 }
 
 impl TranscodeFormat {
     pub fn extension(&self) -> &'static str {
         match self {
-            TranscodeFormat::Opus128 | TranscodeFormat::Opus64 => "ogg",
+            TranscodeFormat::Opus192
+            | TranscodeFormat::Opus128
+            | TranscodeFormat::Opus96
+            | TranscodeFormat::Opus64 => "ogg",
             TranscodeFormat::Mp3V0 | TranscodeFormat::Mp3V5 => "mp3",
         }
     }
+
+    /// Returns the closest format supported by servers that predate quality negotiation, which
+    /// fail to parse newer formats.
+    pub fn legacy_fallback(&self) -> TranscodeFormat {
+        match self {
+            TranscodeFormat::Opus192 => TranscodeFormat::Opus128,
+            TranscodeFormat::Opus96 => TranscodeFormat::Opus64,
+            format => *format,
+        }
+    }
 }
 
 #[uniffi::export]
@@ -254,7 +331,9 @@ pub fn parse_transcode_format(s: &str) -> Result<Option<TranscodeFormat>, CoreEr
 impl Display for TranscodeFormat {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            TranscodeFormat::Opus192 => write!(f, "opus192"),
             TranscodeFormat::Opus128 => write!(f, "opus128"),
+            TranscodeFormat::Opus96 => write!(f, "opus96"),
             TranscodeFormat::Opus64 => write!(f, "opus64"),
             TranscodeFormat::Mp3V0 => write!(f, "mp3v0"),
             TranscodeFormat::Mp3V5 => write!(f, "mp3v5"),
@@ -267,21 +346,39 @@ impl FromStr for TranscodeFormat {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
+            "opus192" => Ok(TranscodeFormat::Opus192),
             "opus128" => Ok(TranscodeFormat::Opus128),
+            "opus96" => Ok(TranscodeFormat::Opus96),
             "opus64" => Ok(TranscodeFormat::Opus64),
             "mp3v0" => Ok(TranscodeFormat::Mp3V0),
             "mp3v5" => Ok(TranscodeFormat::Mp3V5),
-            _ => anyhow::bail!("invalid transcode format: {s}"),
+            _ => Err(LibraryError::InvalidTranscodeFormat {
+                format: s.to_string(),
+            }
+            .into()),
         }
     }
 }
 
+/// Default number of transcode worker threads.
+pub const DEFAULT_TRANSCODE_WORKERS: u32 = 8;
+
+/// Upper bound for the configurable number of transcode worker threads.
+pub const MAX_TRANSCODE_WORKERS: u32 = 32;
+
 /// The queue of items to be transcoded.
 #[derive(Debug)]
 struct TranscodeQueue {
     queue: Mutex<PriorityQueue<(TranscodeFormat, PathBuf), u64>>,
     ready: Condvar,
     ready_counter: Arc<AtomicU64>,
+    /// Priority for the next prioritized items, so later requests go before earlier ones.
+    next_priority: AtomicU64,
+    /// Number of workers that should be running.
+    max_workers: AtomicUsize,
+    /// Number of workers that are running. Workers above the maximum exit when they're done with
+    /// their current job.
+    live_workers: AtomicUsize,
 }
 
 impl TranscodeQueue {
@@ -291,6 +388,56 @@ impl TranscodeQueue {
             queue: Mutex::new(PriorityQueue::new()),
             ready: Condvar::new(),
             ready_counter: Arc::new(AtomicU64::new(0)),
+            next_priority: AtomicU64::new(2),
+            max_workers: AtomicUsize::new(0),
+            live_workers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the number of workers that should be running, and returns how many new workers need
+    /// to be started. Extra workers exit when they're done with their current job.
+    pub fn set_max_workers(&self, max_workers: usize) -> usize {
+        self.max_workers.store(max_workers, Ordering::SeqCst);
+
+        // reserve slots for the new workers
+        let mut spawn = 0;
+        loop {
+            let live = self.live_workers.load(Ordering::SeqCst);
+            if live >= max_workers {
+                break;
+            }
+            if self
+                .live_workers
+                .compare_exchange(live, live + 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                spawn += 1;
+            }
+        }
+
+        // wake idle workers so extra ones can exit. take the lock so a worker can't miss the
+        // notification between checking the limit and waiting
+        drop(self.queue.lock().unwrap());
+        self.ready.notify_all();
+
+        spawn
+    }
+
+    /// Releases the slot of a worker if there are more workers running than the maximum, in which
+    /// case the worker should exit.
+    fn retire_worker(&self) -> bool {
+        loop {
+            let live = self.live_workers.load(Ordering::SeqCst);
+            if live <= self.max_workers.load(Ordering::SeqCst) {
+                return false;
+            }
+            if self
+                .live_workers
+                .compare_exchange(live, live - 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return true;
+            }
         }
     }
 
@@ -313,6 +460,15 @@ impl TranscodeQueue {
         self.ready.notify_all();
     }
 
+    /// Moves queued items to the front of the queue. Items that aren't queued are ignored.
+    pub fn prioritize(&self, format: TranscodeFormat, items: impl IntoIterator<Item = PathBuf>) {
+        let priority = self.next_priority.fetch_add(1, Ordering::Relaxed);
+        let mut queue = self.queue.lock().unwrap();
+        for item in items {
+            queue.change_priority(&(format, item), priority);
+        }
+    }
+
     /// Removes items from the queue if their paths aren't in the given HashSet.
     pub fn remove_missing(&self, items: &HashSet<PathBuf>) {
         {
@@ -327,10 +483,15 @@ impl TranscodeQueue {
         }
     }
 
-    /// Waits for a job and takes it from the queue.
-    pub fn wait(&self) -> (TranscodeFormat, PathBuf) {
+    /// Waits for a job and takes it from the queue, or returns None if the worker should exit
+    /// because the number of workers was lowered.
+    pub fn wait(&self) -> Option<(TranscodeFormat, PathBuf)> {
         let mut queue = self.queue.lock().unwrap();
         loop {
+            if self.retire_worker() {
+                return None;
+            }
+
             // check for a job
             let next = queue.pop();
 
@@ -339,7 +500,7 @@ impl TranscodeQueue {
                     // decrease ready counter
                     self.ready_counter.fetch_sub(1, Ordering::Relaxed);
 
-                    return item;
+                    return Some(item);
                 }
                 None => {
                     // no job, wait for notification
@@ -359,11 +520,25 @@ pub enum TranscodeCommand {
     /// Request transcoding of some files.
     Request(TranscodeFormat, HashSet<PathBuf>),
 
+    /// Transcode some queued files before the rest of the queue.
+    Prioritize(TranscodeFormat, HashSet<PathBuf>),
+
     /// Delete transcodes of files that aren't in the library anymore.
     DeleteMissing(Vec<PathBuf>),
 
     /// Delete all transcodes.
     DeleteAll,
+
+    /// Set the number of worker threads. Clamped to 1..=MAX_TRANSCODE_WORKERS.
+    SetWorkers(u32),
+
+    /// Let the workers finish their current transcodes and exit without starting new ones, before
+    /// shutting down. Queued files stay queued.
+    Drain,
+
+    /// Called once the commands sent before it are handled, so the files they requested are
+    /// queued.
+    Flush(oneshot::Sender<()>),
 }
 
 /// A handle to a pool of worker threads for transcoding files.
@@ -482,6 +657,7 @@ impl TranscodePool {
                 TranscodeStatus::Ready {
                     transcode_path,
                     file_size,
+                    checksum: FileChecksum::default(),
                 },
             );
         }
@@ -573,16 +749,19 @@ impl TranscodePool {
         mut rx: mpsc::UnboundedReceiver<TranscodeCommand>,
     ) -> anyhow::Result<()> {
         // spawn transcode workers
-        // TODO
-        for _ in 0..8 {
-            TranscodeWorker::new(
-                transcodes_dir.clone(),
-                status_cache.clone(),
-                hash_cache.clone(),
-                queue.clone(),
-                inprogress_counter.clone(),
-            );
-        }
+        let spawn_workers = |workers: u32| {
+            let workers = workers.clamp(1, MAX_TRANSCODE_WORKERS) as usize;
+            for _ in 0..queue.set_max_workers(workers) {
+                TranscodeWorker::new(
+                    transcodes_dir.clone(),
+                    status_cache.clone(),
+                    hash_cache.clone(),
+                    queue.clone(),
+                    inprogress_counter.clone(),
+                );
+            }
+        };
+        spawn_workers(DEFAULT_TRANSCODE_WORKERS);
 
         loop {
             tokio::select! {
@@ -674,6 +853,10 @@ impl TranscodePool {
                             queue.extend(format, items);
                         },
 
+                        TranscodeCommand::Prioritize(format, items) => {
+                            queue.prioritize(format, items);
+                        },
+
                         TranscodeCommand::DeleteMissing(items) => {
                             Self::delete_missing(&status_cache, &hash_cache, items);
                         },
@@ -681,6 +864,20 @@ impl TranscodePool {
                         TranscodeCommand::DeleteAll => {
                             Self::delete_all(&status_cache);
                         },
+
+                        TranscodeCommand::SetWorkers(workers) => {
+                            debug!("TranscodePool: setting number of workers to {workers}");
+                            spawn_workers(workers);
+                        },
+
+                        TranscodeCommand::Drain => {
+                            debug!("TranscodePool: draining workers");
+                            queue.set_max_workers(0);
+                        },
+
+                        TranscodeCommand::Flush(callback) => {
+                            let _ = callback.send(());
+                        },
                     }
                 }
             }
@@ -698,34 +895,13 @@ impl TranscodePool {
     }
 
     pub fn transcodes_dir_size(&self) -> FileSizeModel {
-        let size = self
-            .status_cache
-            .cache
-            .iter()
-            .fold(0, |acc_size, e| match e.value() {
-                TranscodeStatus::Ready { file_size, .. } => acc_size + file_size,
-                TranscodeStatus::Failed { .. } => acc_size,
-            });
-        FileSizeModel::Actual(size)
-
         // TODO: expose separately current used size and total size if everything was transcoded?
-        // TODO: old code that dealt with estimated sizes
-        // let (size, estimated) = self.status_cache.cache.iter().fold(
-        //     (0, false),
-        //     |(acc_size, acc_estimated), e| match &*e {
-        //         TranscodeStatus::Waiting { estimated_size } => {
-        //             (acc_size + estimated_size.unwrap_or(0), true)
-        //         }
-        //         TranscodeStatus::Ready { file_size, .. } => (acc_size + file_size, acc_estimated),
-        //         TranscodeStatus::Failed { .. } => (acc_size, acc_estimated),
-        //     },
-        // );
-
-        // if estimated {
-        //     FileSizeModel::Estimated(size)
-        // } else {
-        //     FileSizeModel::Actual(size)
-        // }
+        FileSizeModel::Actual(self.status_cache.ready_size())
+    }
+
+    /// Subscribes to changes of the size of the transcodes directory.
+    pub fn subscribe_transcodes_dir_size(&self) -> watch::Receiver<u64> {
+        self.status_cache.subscribe_ready_size()
     }
 
     pub fn queued_count_model(&self) -> CounterModel {
@@ -771,7 +947,12 @@ impl TranscodePool {
 
         status_cache.retain(|(_format, hash_kind, hash), status| {
             // ignore if not Ready
-            let TranscodeStatus::Ready { transcode_path, file_size } = status else {
+            let TranscodeStatus::Ready {
+                transcode_path,
+                file_size,
+                ..
+            } = status
+            else {
                 return true;
             };
 
@@ -810,6 +991,7 @@ impl TranscodePool {
             let TranscodeStatus::Ready {
                 transcode_path,
                 file_size,
+                ..
             } = status
             else {
                 return true;
@@ -872,7 +1054,9 @@ impl TranscodeWorker {
     ) -> anyhow::Result<()> {
         loop {
             // wait for a job
-            let (format, job) = queue.wait();
+            let Some((format, job)) = queue.wait() else {
+                break;
+            };
 
             // mark thread as in-progress
             let _counter_guard = inprogress_counter.entered();
@@ -918,7 +1102,9 @@ impl TranscodeWorker {
 
             info!("transcoding file: {format} {}", job.display());
             let transcode_preset = match format {
+                TranscodeFormat::Opus192 => TranscodePreset::Opus(OpusPreset::Opus192),
                 TranscodeFormat::Opus128 => TranscodePreset::Opus(OpusPreset::Opus128),
+                TranscodeFormat::Opus96 => TranscodePreset::Opus(OpusPreset::Opus96),
                 TranscodeFormat::Opus64 => TranscodePreset::Opus(OpusPreset::Opus64),
                 TranscodeFormat::Mp3V0 => TranscodePreset::Mp3(Mp3Preset::Mp3V0),
                 TranscodeFormat::Mp3V5 => TranscodePreset::Mp3(Mp3Preset::Mp3V5),
@@ -978,6 +1164,20 @@ impl TranscodeWorker {
                 final_path.display()
             );
 
+            // checksum the transcode now, so transfers don't have to read it first
+            let checksum = FileChecksum::default();
+            match file_checksum(&final_path) {
+                Ok(file_checksum) => {
+                    let _ = checksum.set(file_checksum);
+                }
+                Err(e) => {
+                    warn!(
+                        "failed to checksum transcoded file: {}: {e:#}",
+                        final_path.display()
+                    );
+                }
+            }
+
             // set status to Ready
             status_cache.insert(
                 format,
@@ -986,6 +1186,7 @@ impl TranscodeWorker {
                 TranscodeStatus::Ready {
                     transcode_path: final_path,
                     file_size,
+                    checksum,
                 },
             );
         }
@@ -1037,7 +1238,9 @@ impl Drop for RegionCounterGuard<'_> {
 /// Estimates the file size in bytes of a transcode, given the transcode format and file duration in seconds.
 pub fn estimate_file_size(format: TranscodeFormat, duration: f64) -> u64 {
     let bitrate = match format {
+        TranscodeFormat::Opus192 => 192_000.0,
         TranscodeFormat::Opus128 => 128_000.0,
+        TranscodeFormat::Opus96 => 96_000.0,
         TranscodeFormat::Opus64 => 64_000.0,
         // https://trac.ffmpeg.org/wiki/Encode/MP3
         TranscodeFormat::Mp3V0 => 245_000.0,
@@ -1102,6 +1305,56 @@ mod tests {
         panic!("thread timed out");
     }
 
+    #[test]
+    fn test_status_cache_ready_size() {
+        let cache = TranscodeStatusCache::new();
+        let mut ready_size = cache.subscribe_ready_size();
+        let ready = |file_size| TranscodeStatus::Ready {
+            transcode_path: PathBuf::from("transcode"),
+            file_size,
+            checksum: FileChecksum::default(),
+        };
+
+        cache.insert(
+            TranscodeFormat::Opus128,
+            "blake3".into(),
+            [1; 16],
+            ready(100),
+        );
+        cache.insert(
+            TranscodeFormat::Opus128,
+            "blake3".into(),
+            [2; 16],
+            ready(50),
+        );
+        assert_eq!(cache.ready_size(), 150);
+        assert!(ready_size.has_changed().unwrap());
+        ready_size.mark_unchanged();
+
+        // failures don't change the size
+        cache.insert(
+            TranscodeFormat::Opus128,
+            "blake3".into(),
+            [3; 16],
+            TranscodeStatus::Failed {
+                error: anyhow::anyhow!("failed"),
+            },
+        );
+        assert!(!ready_size.has_changed().unwrap());
+
+        // replacing a transcode counts only the new one
+        cache.insert(
+            TranscodeFormat::Opus128,
+            "blake3".into(),
+            [1; 16],
+            ready(80),
+        );
+        assert_eq!(cache.ready_size(), 130);
+
+        cache.retain(|(_, _, hash), _| *hash != [2; 16]);
+        assert_eq!(cache.ready_size(), 80);
+    }
+
     /// Asserts that a condition is true for a given duration.
     fn assert_duration(timeout: std::time::Duration, condition: impl Fn() -> bool) {
         let now = std::time::Instant::now();
@@ -1186,9 +1439,9 @@ mod tests {
         // wait after adding item
         let thread = std::thread::spawn(move || {
             let item = queue.wait();
-            assert_eq!(item, (format, PathBuf::from("item_1")));
+            assert_eq!(item, Some((format, PathBuf::from("item_1"))));
             let item = queue.wait();
-            assert_eq!(item, (format, PathBuf::from("item_2")));
+            assert_eq!(item, Some((format, PathBuf::from("item_2"))));
         });
 
         join_timeout(std::time::Duration::from_secs(1), thread);
@@ -1204,9 +1457,9 @@ mod tests {
             let queue = queue.clone();
             move || {
                 let item = queue.wait();
-                assert_eq!(item, (format, PathBuf::from("item_1")));
+                assert_eq!(item, Some((format, PathBuf::from("item_1"))));
                 let item = queue.wait();
-                assert_eq!(item, (format, PathBuf::from("item_2")));
+                assert_eq!(item, Some((format, PathBuf::from("item_2"))));
             }
         });
 
@@ -1262,14 +1515,14 @@ mod tests {
 
         // wait for next
         let item = queue.wait();
-        assert_eq!(item, (format, PathBuf::from("item_1")));
+        assert_eq!(item, Some((format, PathBuf::from("item_1"))));
 
         // remove #2 from queue
         queue.remove_missing(&HashSet::from([item_3]));
 
         // wait for next
         let item = queue.wait();
-        assert_eq!(item, (format, PathBuf::from("item_3")));
+        assert_eq!(item, Some((format, PathBuf::from("item_3"))));
     }
 
     #[test]
@@ -1306,4 +1559,28 @@ mod tests {
         // should have 5 ready
         assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 5);
     }
+
+    #[test]
+    fn test_queue_set_max_workers() {
+        let queue = Arc::new(TranscodeQueue::new());
+
+        // starting workers reserves a slot for each
+        assert_eq!(queue.set_max_workers(4), 4);
+        assert_eq!(queue.set_max_workers(4), 0);
+
+        // lowering the limit makes extra workers exit instead of waiting
+        assert_eq!(queue.set_max_workers(2), 0);
+        assert_eq!(queue.wait(), None);
+        assert_eq!(queue.wait(), None);
+
+        // the remaining workers keep taking jobs
+        queue.extend(TranscodeFormat::Opus128, vec![PathBuf::from("item_1")]);
+        assert_eq!(
+            queue.wait(),
+            Some((TranscodeFormat::Opus128, PathBuf::from("item_1")))
+        );
+
+        // raising the limit only starts the missing workers
+        assert_eq!(queue.set_max_workers(3), 1);
+    }
 }
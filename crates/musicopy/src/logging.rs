@@ -1,4 +1,8 @@
-use std::path::Path;
+use anyhow::Context;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::{
     EnvFilter, Registry,
@@ -12,6 +16,9 @@ use tracing_subscriber::{
 /// - `iroh=error`: suppress noisy warnings from Iroh
 const DEFAULT_ENV_FILTER: &str = "warn,musicopy=debug,iroh=error";
 
+/// Name of the current log file. Rotated files add a suffix to it.
+const LOG_FILE_NAME: &str = "musicopy.log";
+
 /// Initialize logging.
 ///
 /// If `log_dir` is provided, logs will be written to files, rotated at 5 MB per file with 5 files
@@ -32,7 +39,7 @@ pub fn init(log_dir: Option<&Path>) -> anyhow::Result<Option<WorkerGuard>> {
             );
         }
 
-        let roller = logroller::LogRollerBuilder::new(log_dir, Path::new("musicopy.log"))
+        let roller = logroller::LogRollerBuilder::new(log_dir, Path::new(LOG_FILE_NAME))
             .rotation(logroller::Rotation::SizeBased(logroller::RotationSize::MB(
                 5,
             )))
@@ -115,3 +122,243 @@ pub fn init(log_dir: Option<&Path>) -> anyhow::Result<Option<WorkerGuard>> {
 
     Ok(guard)
 }
+
+/// Severity of a log entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, uniffi::Enum)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "TRACE" => Some(LogLevel::Trace),
+            "DEBUG" => Some(LogLevel::Debug),
+            "INFO" => Some(LogLevel::Info),
+            "WARN" => Some(LogLevel::Warn),
+            "ERROR" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Which log entries to return.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct LogFilter {
+    /// Only return entries at least this severe.
+    pub min_level: LogLevel,
+    /// Only return entries whose target or message contains this text, ignoring case.
+    pub contains: Option<String>,
+}
+
+/// An entry read from the log files.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct LogEntryModel {
+    /// When the entry was logged, as an RFC 3339 timestamp in UTC.
+    pub timestamp: String,
+    pub level: LogLevel,
+    /// The module that logged the entry, e.g. `musicopy::node`.
+    pub target: String,
+    /// The message and its fields. Messages that span several lines, like panics, keep their
+    /// line breaks.
+    pub message: String,
+}
+
+impl LogFilter {
+    fn matches(&self, entry: &LogEntryModel) -> bool {
+        if entry.level < self.min_level {
+            return false;
+        }
+
+        match &self.contains {
+            Some(contains) => {
+                let contains = contains.to_lowercase();
+                entry.target.to_lowercase().contains(&contains)
+                    || entry.message.to_lowercase().contains(&contains)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Reads the most recent log entries matching a filter from the log files, oldest first.
+///
+/// Entries that are still buffered by the log writer aren't included.
+pub fn read_recent_logs(
+    log_dir: &Path,
+    filter: &LogFilter,
+    limit: usize,
+) -> anyhow::Result<Vec<LogEntryModel>> {
+    if !log_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    // read the newest files first, and stop once there are enough entries
+    let mut log_files: Vec<(std::time::SystemTime, PathBuf)> = std::fs::read_dir(log_dir)
+        .context("failed to read log dir")?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with(LOG_FILE_NAME))
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .collect();
+    log_files.sort_by(|a, b| b.cmp(a));
+
+    let mut entries = Vec::new();
+    for (_, path) in log_files {
+        let content = match read_log_file(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                tracing::warn!("failed to read log file {}: {e:#}", path.display());
+                continue;
+            }
+        };
+
+        let mut file_entries: Vec<_> = parse_log_entries(&content)
+            .into_iter()
+            .filter(|entry| filter.matches(entry))
+            .collect();
+        file_entries.append(&mut entries);
+        entries = file_entries;
+
+        if entries.len() >= limit {
+            break;
+        }
+    }
+
+    let skip = entries.len().saturating_sub(limit);
+    entries.drain(..skip);
+    Ok(entries)
+}
+
+/// Reads a log file, decompressing it if it was compressed when rotated.
+fn read_log_file(path: &Path) -> anyhow::Result<String> {
+    let content = std::fs::read(path)?;
+
+    if path.extension().is_some_and(|e| e == "gz") {
+        let mut decoder = flate2::read::GzDecoder::new(content.as_slice());
+        let mut decompressed = Vec::new();
+        decoder
+            .read_to_end(&mut decompressed)
+            .context("failed to decompress log file")?;
+        Ok(String::from_utf8_lossy(&decompressed).into_owned())
+    } else {
+        Ok(String::from_utf8_lossy(&content).into_owned())
+    }
+}
+
+/// Parses lines written by the file layer, like
+/// `2025-01-01T00:00:00.000000Z  INFO musicopy::node: message`. Lines that don't start an entry
+/// are continuations of the previous one.
+fn parse_log_entries(content: &str) -> Vec<LogEntryModel> {
+    let mut entries: Vec<LogEntryModel> = Vec::new();
+
+    for line in content.lines() {
+        match parse_log_line(line) {
+            Some(entry) => entries.push(entry),
+            None => {
+                if let Some(last) = entries.last_mut() {
+                    last.message.push('\n');
+                    last.message.push_str(line);
+                }
+            }
+        }
+    }
+
+    entries
+}
+
+fn parse_log_line(line: &str) -> Option<LogEntryModel> {
+    let (timestamp, rest) = line.split_once(' ')?;
+    if !timestamp.ends_with('Z') || !timestamp.contains('T') {
+        return None;
+    }
+
+    let rest = rest.trim_start();
+    let (level, rest) = rest.split_once(' ')?;
+    let level = LogLevel::parse(level)?;
+
+    // spans are written before the target like `name{field=value}: `
+    let mut rest = rest;
+    while let Some((_span, tail)) = rest
+        .split_once(": ")
+        .filter(|(span, _)| span.ends_with('}'))
+    {
+        rest = tail;
+    }
+    let (target, message) = rest.split_once(": ").unwrap_or(("", rest));
+
+    Some(LogEntryModel {
+        timestamp: timestamp.to_string(),
+        level,
+        target: target.to_string(),
+        message: message.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_entries() {
+        let content = "\
+2025-01-01T00:00:00.000001Z DEBUG musicopy::library: starting scan
+2025-01-01T00:00:01.000001Z  WARN musicopy::node: failed to connect: timed out
+thread 'main' panicked
+  at src/node.rs:1
+2025-01-01T00:00:02.000001Z ERROR connect{id=1}: musicopy::node: closed";
+
+        let entries = parse_log_entries(content);
+        assert_eq!(entries.len(), 3);
+
+        assert_eq!(entries[0].timestamp, "2025-01-01T00:00:00.000001Z");
+        assert_eq!(entries[0].level, LogLevel::Debug);
+        assert_eq!(entries[0].target, "musicopy::library");
+        assert_eq!(entries[0].message, "starting scan");
+
+        // the message keeps its own colons and continuation lines
+        assert_eq!(entries[1].level, LogLevel::Warn);
+        assert_eq!(
+            entries[1].message,
+            "failed to connect: timed out\nthread 'main' panicked\n  at src/node.rs:1"
+        );
+
+        // spans are skipped
+        assert_eq!(entries[2].target, "musicopy::node");
+        assert_eq!(entries[2].message, "closed");
+    }
+
+    #[test]
+    fn test_read_recent_logs() {
+        let log_dir = testdir::testdir!();
+        std::fs::write(
+            log_dir.join(LOG_FILE_NAME),
+            "\
+2025-01-01T00:00:00.000001Z  INFO musicopy::library: one
+2025-01-01T00:00:01.000001Z DEBUG musicopy::node: two
+2025-01-01T00:00:02.000001Z  WARN musicopy::node: three
+2025-01-01T00:00:03.000001Z ERROR musicopy::library: four
+",
+        )
+        .unwrap();
+
+        let filter = LogFilter {
+            min_level: LogLevel::Info,
+            contains: None,
+        };
+        let entries = read_recent_logs(&log_dir, &filter, 2).unwrap();
+        let messages: Vec<_> = entries.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, ["three", "four"]);
+
+        let filter = LogFilter {
+            min_level: LogLevel::Trace,
+            contains: Some("NODE".to_string()),
+        };
+        let entries = read_recent_logs(&log_dir, &filter, 10).unwrap();
+        let messages: Vec<_> = entries.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, ["two", "three"]);
+    }
+}
@@ -0,0 +1,314 @@
+//! Metrics in the OpenMetrics text format, which Prometheus can scrape.
+//!
+//! The metrics are gathered from the counters and models the core already keeps, when they're
+//! exported. They're meant for people running the app on a home server, so it can be graphed
+//! alongside their other services.
+
+use crate::{
+    StatsModel,
+    library::{LibraryModel, ScanDurations},
+    model::CounterModel,
+    node::{DownloadProgressModel, FileSizeModel},
+};
+use std::fmt::Write;
+
+/// Content type of the exported metrics, for serving them over HTTP.
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Everything the metrics are gathered from.
+pub(crate) struct MetricsSnapshot {
+    pub library: LibraryModel,
+    pub scans: u64,
+    pub scan_durations: ScanDurations,
+    pub node: NodeMetrics,
+    pub download_progress: DownloadProgressModel,
+    pub stats: StatsModel,
+}
+
+/// The node's connection counts and endpoint counters, read from its model without copying it.
+#[derive(Debug, Default)]
+pub(crate) struct NodeMetrics {
+    pub incoming_pending: u64,
+    pub incoming_accepted: u64,
+    pub outgoing_pending: u64,
+    pub outgoing_accepted: u64,
+
+    pub send_ipv4: u64,
+    pub send_ipv6: u64,
+    pub send_relay: u64,
+    pub recv_ipv4: u64,
+    pub recv_ipv6: u64,
+    pub recv_relay: u64,
+}
+
+impl MetricsSnapshot {
+    /// Renders the metrics in the OpenMetrics text format.
+    pub fn render(&self) -> String {
+        let mut w = MetricsWriter::default();
+
+        // library
+        w.family(
+            "musicopy_library_files",
+            "gauge",
+            "Number of files in each library root.",
+        );
+        for root in &self.library.local_roots {
+            w.sample(
+                "musicopy_library_files",
+                &[("root", &root.name)],
+                root.num_files,
+            );
+        }
+
+        w.family(
+            "musicopy_library_scanning",
+            "gauge",
+            "Whether the library is being scanned.",
+        );
+        w.sample(
+            "musicopy_library_scanning",
+            &[],
+            self.library.is_scanning as u64,
+        );
+
+        w.family(
+            "musicopy_library_scans",
+            "counter",
+            "Number of finished library scans since launch.",
+        );
+        w.sample("musicopy_library_scans_total", &[], self.scans);
+
+        w.family(
+            "musicopy_library_scan_duration_seconds",
+            "counter",
+            "Total time spent scanning the library since launch.",
+        );
+        w.sample(
+            "musicopy_library_scan_duration_seconds_total",
+            &[],
+            self.scan_durations.total.as_secs_f64(),
+        );
+
+        w.family(
+            "musicopy_library_last_scan_duration_seconds",
+            "gauge",
+            "Time the last library scan took.",
+        );
+        if let Some(last) = self.scan_durations.last {
+            w.sample(
+                "musicopy_library_last_scan_duration_seconds",
+                &[],
+                last.as_secs_f64(),
+            );
+        }
+
+        // transcodes
+        w.family(
+            "musicopy_transcodes",
+            "gauge",
+            "Number of transcodes in each state.",
+        );
+        let transcode_counts: [(&str, &CounterModel); 4] = [
+            ("queued", &*self.library.transcode_count_queued),
+            ("in_progress", &*self.library.transcode_count_inprogress),
+            ("ready", &*self.library.transcode_count_ready),
+            ("failed", &*self.library.transcode_count_failed),
+        ];
+        for (state, count) in transcode_counts {
+            w.sample("musicopy_transcodes", &[("state", state)], count.get());
+        }
+
+        w.family(
+            "musicopy_transcodes_dir_bytes",
+            "gauge",
+            "Size of the transcodes directory.",
+        );
+        match self.library.transcodes_dir_size {
+            FileSizeModel::Actual(size) | FileSizeModel::Estimated(size) => {
+                w.sample("musicopy_transcodes_dir_bytes", &[], size);
+            }
+            FileSizeModel::Unknown => {}
+        }
+
+        // connections
+        w.family(
+            "musicopy_connections",
+            "gauge",
+            "Number of connections by direction and state. Incoming connections are from clients \
+             downloading from this node.",
+        );
+        for (direction, state, count) in [
+            ("incoming", "pending", self.node.incoming_pending),
+            ("incoming", "accepted", self.node.incoming_accepted),
+            ("outgoing", "pending", self.node.outgoing_pending),
+            ("outgoing", "accepted", self.node.outgoing_accepted),
+        ] {
+            w.sample(
+                "musicopy_connections",
+                &[("direction", direction), ("state", state)],
+                count,
+            );
+        }
+
+        w.family(
+            "musicopy_network_sent_bytes",
+            "counter",
+            "Bytes sent by the endpoint since launch, by path type.",
+        );
+        for (path, bytes) in [
+            ("ipv4", self.node.send_ipv4),
+            ("ipv6", self.node.send_ipv6),
+            ("relay", self.node.send_relay),
+        ] {
+            w.sample(
+                "musicopy_network_sent_bytes_total",
+                &[("path", path)],
+                bytes,
+            );
+        }
+
+        w.family(
+            "musicopy_network_received_bytes",
+            "counter",
+            "Bytes received by the endpoint since launch, by path type.",
+        );
+        for (path, bytes) in [
+            ("ipv4", self.node.recv_ipv4),
+            ("ipv6", self.node.recv_ipv6),
+            ("relay", self.node.recv_relay),
+        ] {
+            w.sample(
+                "musicopy_network_received_bytes_total",
+                &[("path", path)],
+                bytes,
+            );
+        }
+
+        // transfers
+        w.family(
+            "musicopy_download_jobs",
+            "gauge",
+            "Number of download jobs of the current sessions in each state.",
+        );
+        for (state, count) in [
+            ("queued", self.download_progress.queued_jobs),
+            ("active", self.download_progress.active_jobs),
+            ("finished", self.download_progress.finished_jobs),
+            ("failed", self.download_progress.failed_jobs),
+        ] {
+            w.sample("musicopy_download_jobs", &[("state", state)], count);
+        }
+
+        w.family(
+            "musicopy_download_session_bytes",
+            "gauge",
+            "Bytes downloaded in the current sessions.",
+        );
+        w.sample(
+            "musicopy_download_session_bytes",
+            &[],
+            self.download_progress.downloaded_bytes,
+        );
+
+        w.family(
+            "musicopy_transferred_files",
+            "counter",
+            "Files transferred over the app's lifetime, by direction.",
+        );
+        w.sample(
+            "musicopy_transferred_files_total",
+            &[("direction", "upload")],
+            self.stats.server_files,
+        );
+        w.sample(
+            "musicopy_transferred_files_total",
+            &[("direction", "download")],
+            self.stats.client_files,
+        );
+
+        w.family(
+            "musicopy_transferred_bytes",
+            "counter",
+            "Bytes transferred over the app's lifetime, by direction.",
+        );
+        w.sample(
+            "musicopy_transferred_bytes_total",
+            &[("direction", "upload")],
+            self.stats.server_bytes,
+        );
+        w.sample(
+            "musicopy_transferred_bytes_total",
+            &[("direction", "download")],
+            self.stats.client_bytes,
+        );
+
+        w.finish()
+    }
+}
+
+/// Writes metric families and samples in the text format.
+#[derive(Default)]
+struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# TYPE {name} {kind}");
+        let _ = writeln!(self.out, "# HELP {name} {}", escape(help));
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (key, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{key}=\"{}\"", escape(value));
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {value}");
+    }
+
+    fn finish(mut self) -> String {
+        self.out.push_str("# EOF\n");
+        self.out
+    }
+}
+
+/// Escapes a label value or help text.
+fn escape(s: &str) -> String {
+    s.replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_writer() {
+        let mut w = MetricsWriter::default();
+        w.family("musicopy_test", "counter", "A test.");
+        w.sample("musicopy_test_total", &[], 1);
+        w.sample(
+            "musicopy_test_total",
+            &[("root", "my \"music\""), ("state", "ready")],
+            2.5,
+        );
+
+        assert_eq!(
+            w.finish(),
+            "# TYPE musicopy_test counter\n\
+             # HELP musicopy_test A test.\n\
+             musicopy_test_total 1\n\
+             musicopy_test_total{root=\"my \\\"music\\\"\",state=\"ready\"} 2.5\n\
+             # EOF\n"
+        );
+    }
+}
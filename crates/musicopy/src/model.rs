@@ -7,9 +7,12 @@
 //! requires serialization, so when possible we should slice the model into
 //! subtrees and send snapshots independently.
 
-use std::sync::{
-    Arc,
-    atomic::{AtomicU64, Ordering},
+use std::{
+    ops::Deref,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
 /// A counter that can be updated across the FFI boundary.
@@ -42,3 +45,84 @@ impl From<&Arc<AtomicU64>> for CounterModel {
         Self(counter.clone())
     }
 }
+
+/// Counters are equal if they read the same atomic, since their values change in place.
+impl PartialEq for CounterModel {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// A list in the model that's shared by the snapshots it's in instead of copied.
+///
+/// Cloning the model only clones a reference to each list, so pushing a snapshot doesn't copy
+/// the lists that didn't change, like the indexes of the other connections. A list is replaced
+/// when it changes rather than mutated. It's still copied when it's sent across the FFI boundary,
+/// where it's a plain list.
+///
+/// Each list type needs a `uniffi::custom_type!` to be used in the model.
+#[derive(Debug)]
+pub struct SharedList<T>(Arc<Vec<T>>);
+
+impl<T> Clone for SharedList<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Default for SharedList<T> {
+    fn default() -> Self {
+        Self(Arc::new(Vec::new()))
+    }
+}
+
+impl<T> Deref for SharedList<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.0
+    }
+}
+
+impl<T: PartialEq> PartialEq for SharedList<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl<T> From<Vec<T>> for SharedList<T> {
+    fn from(items: Vec<T>) -> Self {
+        Self(Arc::new(items))
+    }
+}
+
+impl<T: Clone> From<SharedList<T>> for Vec<T> {
+    /// Takes the items out of the list, only copying them if the list is still shared.
+    fn from(list: SharedList<T>) -> Self {
+        Arc::unwrap_or_clone(list.0)
+    }
+}
+
+impl<T> FromIterator<T> for SharedList<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self(Arc::new(iter.into_iter().collect()))
+    }
+}
+
+impl<T: Clone> IntoIterator for SharedList<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Vec::from(self).into_iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a SharedList<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
@@ -12,45 +12,79 @@
 use crate::TestHooks;
 use crate::{
     EventHandler,
-    database::{Database, InsertFile},
+    database::{
+        AutoDownload, AutoDownloadSelection, Database, DownloadFilter, DownloadJournalEntry,
+        DownloadedFile, File, FileManifest, InsertFile, InsertTransferSession, LocalFileHash,
+        NodeShare, ShareFilter, Track, TransferSession, TransferSessionFile,
+    },
     device_name::device_name,
-    fs::{OpenMode, TreeFile, TreePath},
+    downloads::{
+        self, DeletedDownloadsModel, DownloadedFileSelectionModel, ImportCandidate,
+        ImportedDownloadsModel, ReorganizedDownloadsModel,
+    },
+    error::{ConnectionError, TransferError},
+    fs::{
+        OpenMode, TreeFile, TreePath,
+        path_policy::PathPolicy,
+        sanitize::{
+            SanitizeRules, numbered_file_name, sanitize_component, sanitize_path, unique_file_name,
+        },
+        template::{PathTemplate, TemplateValues},
+    },
+    journal::{self, InterruptedDownloadModel, PartialCleanupModel, PartialFilePolicy},
     library::{
         Library, LibraryCommand,
         hash::HashCache,
         transcode::{
-            TranscodeFormat, TranscodeStatus, TranscodeStatusCache, estimate_file_size,
-            estimate_file_size_without_duration, estimate_original_file_size,
+            FileChecksum, TranscodeFormat, TranscodeStatus, TranscodeStatusCache,
+            estimate_file_size, estimate_file_size_without_duration, estimate_original_file_size,
         },
     },
-    model::CounterModel,
+    metrics::NodeMetrics,
+    model::{CounterModel, SharedList},
+    operation::{DownloadResultModel, OPERATION_EVENTS_CAPACITY, OperationEvent},
+    pairing::{PairingTicket, PairingToken, generate_token},
+    playlist::{self, PlaylistSettings, PlaylistWriteModel},
     protocol::{
-        ClientMessageV1, DownloadItem, FileSize, IndexItem, IndexUpdateItem, JobStatusItem,
-        ServerMessageV1,
+        ClientMessageV1, ContentHash, DownloadItem, FileSize, INDEX_PAGE_SIZE, IdentifyOptions,
+        IndexChanges, IndexItem, IndexPageItems, IndexUpdateItem, ItemExtraMetadata, ItemMetadata,
+        JobStatusItem, PushItem, ServerMessageV1, SyncConflictPolicy,
     },
+    sas::verification_phrase,
+    schedule::{
+        DeviceState, NetworkPolicy, NetworkState, TransferGate, TransferHoldReason,
+        TransferSchedule,
+    },
+    share::{ShareLink, ShareLinkFile, ShareLinks, ShareServer, remove_expired_links},
+    status::{NodeStatus, RecentErrors, StatusErrorSource},
+    sync_group::{self, GroupId, GroupRecord},
 };
 use anyhow::Context;
 use dashmap::DashMap;
-use futures::{SinkExt, StreamExt, TryStreamExt};
+use futures::{Sink, SinkExt, StreamExt, TryStreamExt, stream::FuturesUnordered};
 use iroh::{
-    Endpoint, EndpointAddr, EndpointId, SecretKey, Watcher,
-    endpoint::{Connection, presets::N0},
+    Endpoint, EndpointAddr, EndpointId, RelayMap, RelayMode, RelayUrl, SecretKey, TransportAddr,
+    Watcher,
+    endpoint::{Connection, PathInfoList, SendStream, presets::N0},
     protocol::{AcceptError, ProtocolHandler, Router},
 };
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
     pin::Pin,
     sync::{
         Arc, Mutex,
-        atomic::{AtomicBool, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
     },
     time::{Duration, Instant, SystemTime},
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    sync::{Notify, mpsc, oneshot},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    sync::{Notify, Semaphore, broadcast, mpsc, oneshot, watch},
+    task::JoinHandle,
 };
 use tokio_util::{
     bytes::Bytes,
@@ -59,11 +93,16 @@ use tokio_util::{
 use tracing::{debug, error, info, warn};
 
 /// Model of progress for a transfer job.
-#[derive(Debug, Clone, uniffi::Enum)]
+#[derive(Debug, Clone, PartialEq, uniffi::Enum)]
 pub enum TransferJobProgressModel {
     Requested,
     Transcoding,
     Ready,
+    /// The job was paused by the user.
+    Paused {
+        /// Number of bytes written so far, or None if the transfer hadn't started.
+        bytes: Option<Arc<CounterModel>>,
+    },
     InProgress {
         started_at: u64,
         /// Number of bytes written so far.
@@ -74,17 +113,458 @@ pub enum TransferJobProgressModel {
     },
     Failed {
         error: String,
+        reason: TransferErrorReasonModel,
+        /// Whether retrying the job might succeed without the user changing anything.
+        retryable: bool,
     },
 }
 
+/// Why a transfer job failed, so failures can be grouped and offered sensible actions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum TransferErrorReasonModel {
+    /// The connection failed, timed out, or is closing.
+    Network,
+    /// The file is no longer in the server's library.
+    RemoteFileMissing,
+    /// The server couldn't read or transcode the file.
+    DecodeFailed,
+    /// The device ran out of space while writing the file.
+    DiskFull,
+    /// The received file didn't match the checksum sent by the server.
+    ChecksumMismatch,
+    /// The file couldn't be written because of its permissions or the download directory's.
+    PermissionDenied,
+    /// The job was cancelled by the user.
+    Cancelled,
+    /// Any other error.
+    Other,
+}
+
+impl TransferErrorReasonModel {
+    /// Returns whether retrying a job that failed for this reason might succeed without the user
+    /// changing anything.
+    pub fn is_retryable(self) -> bool {
+        matches!(self, Self::Network | Self::ChecksumMismatch | Self::Other)
+    }
+
+    /// Classifies an error returned while transferring a file.
+    fn from_error(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if cause.is::<ConnectionTimedOut>()
+                || cause.is::<iroh::endpoint::ConnectionError>()
+                || cause.is::<iroh::endpoint::ReadError>()
+                || cause.is::<iroh::endpoint::WriteError>()
+            {
+                return Self::Network;
+            }
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                match e.kind() {
+                    std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+                        return Self::DiskFull;
+                    }
+                    std::io::ErrorKind::PermissionDenied => return Self::PermissionDenied,
+                    std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::UnexpectedEof => return Self::Network,
+                    _ => {}
+                }
+            }
+        }
+        Self::Other
+    }
+
+    /// Classifies an error sent by the server, which only sends the error's message.
+    fn from_remote_error(error: &str) -> Self {
+        if error == REMOTE_FILE_NOT_FOUND_ERROR {
+            Self::RemoteFileMissing
+        } else if error == SERVER_CLOSING_ERROR {
+            Self::Network
+        } else if error.starts_with(TRANSCODING_FAILED_ERROR) {
+            Self::DecodeFailed
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// Error sent by the server for a requested file that isn't in its library.
+const REMOTE_FILE_NOT_FOUND_ERROR: &str = "file not found";
+
+/// Error sent by the server for jobs requested or started while it's closing the connection.
+const SERVER_CLOSING_ERROR: &str = "server is closing";
+
+/// Prefix of errors sent by the server when it fails to transcode a file.
+const TRANSCODING_FAILED_ERROR: &str = "transcoding failed";
+
+/// What to do when a download's destination already has a file that wasn't downloaded from the
+/// same server file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, uniffi::Enum)]
+pub enum CollisionPolicy {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Keep the existing file and don't download.
+    Skip,
+    /// Download to a new name with a numbered suffix, e.g. `song (1).ogg`.
+    Rename,
+    /// Keep the existing file if it's identical to the downloaded one, otherwise replace it.
+    ///
+    /// Older servers don't send checksums, so their files always replace existing files.
+    OverwriteIfDifferent,
+}
+
+/// Model of how a download's collision with an existing file was resolved.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Enum)]
+pub enum CollisionOutcomeModel {
+    /// The existing file was replaced.
+    Overwritten,
+    /// The existing file was kept and the file wasn't downloaded.
+    Skipped,
+    /// The file was downloaded to a new name, relative to the download directory.
+    Renamed { local_path: String },
+    /// The existing file was identical to the downloaded one, so it was kept.
+    Unchanged,
+}
+
+/// Model of a download in the current session that needs the user's attention, e.g. because it
+/// replaced an existing file.
+///
+/// Conflicts are collected from the transfer jobs, so they can be resolved in one pass instead of
+/// job by job.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct DownloadConflictModel {
+    pub job_id: u64,
+    pub file_root: String,
+    pub file_path: String,
+    pub reason: DownloadConflictReasonModel,
+    pub action: DownloadConflictActionModel,
+}
+
+/// Model of why a download is in the conflict report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum DownloadConflictReasonModel {
+    /// The destination already had a file that wasn't downloaded from the same server file.
+    Collision,
+    /// The file's path isn't valid on this platform, so it was changed.
+    UnsupportedFileName,
+    /// The file couldn't be written because of its permissions or the download directory's.
+    PermissionDenied,
+}
+
+/// Model of what was done about a download in the conflict report.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Enum)]
+pub enum DownloadConflictActionModel {
+    /// The existing file was replaced.
+    Overwritten,
+    /// The existing file was kept and the file wasn't downloaded.
+    Skipped,
+    /// The file was downloaded to a different path, relative to the download directory.
+    Renamed { local_path: String },
+    /// The existing file was identical to the downloaded one, so it was kept.
+    Unchanged,
+    /// The download failed.
+    Failed { error: String },
+}
+
+impl From<CollisionOutcomeModel> for DownloadConflictActionModel {
+    fn from(collision: CollisionOutcomeModel) -> Self {
+        match collision {
+            CollisionOutcomeModel::Overwritten => Self::Overwritten,
+            CollisionOutcomeModel::Skipped => Self::Skipped,
+            CollisionOutcomeModel::Renamed { local_path } => Self::Renamed { local_path },
+            CollisionOutcomeModel::Unchanged => Self::Unchanged,
+        }
+    }
+}
+
 /// Model of a transfer job.
-#[derive(Debug, Clone, uniffi::Record)]
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
 pub struct TransferJobModel {
     pub job_id: u64,
     pub file_root: String,
     pub file_path: String,
     pub file_size: Option<u64>,
     pub progress: TransferJobProgressModel,
+    /// How a collision with an existing file was resolved, for downloads.
+    #[uniffi(default = None)]
+    pub collision: Option<CollisionOutcomeModel>,
+    /// Whether the download is ready but held until the transfer schedule and the network allow
+    /// it. See `NodeModel::transfer_hold_reasons`.
+    #[uniffi(default = false)]
+    pub held: bool,
+}
+
+/// Model of the transfer jobs of a connection, shared by the snapshots it's in.
+pub type TransferJobsModel = SharedList<TransferJobModel>;
+
+uniffi::custom_type!(TransferJobsModel, Vec<TransferJobModel>, {
+    lower: |jobs| jobs.into(),
+    try_lift: |jobs| Ok(jobs.into()),
+});
+
+/// Model of the combined progress of the transfer jobs of a connection.
+///
+/// Computed when the transfer jobs change, so the byte counts of active jobs are as of the last
+/// snapshot.
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct SessionProgressModel {
+    /// Number of jobs waiting for their files to be transcoded or transferred.
+    pub queued_files: u32,
+    pub active_files: u32,
+    pub paused_files: u32,
+    pub completed_files: u32,
+    pub failed_files: u32,
+    /// Total size of the jobs whose file sizes are known.
+    pub expected_bytes: u64,
+    /// Number of bytes transferred by active, paused, and completed jobs.
+    pub transferred_bytes: u64,
+    /// Estimated seconds until the known bytes are transferred, based on the rate of the active
+    /// jobs, or None if nothing is being transferred.
+    pub eta_secs: Option<u64>,
+}
+
+impl SessionProgressModel {
+    /// Number of jobs that are queued, active, or paused.
+    fn unfinished_files(&self) -> u32 {
+        self.queued_files + self.active_files + self.paused_files
+    }
+
+    fn from_jobs(jobs: &[TransferJobModel]) -> Self {
+        let mut progress = Self::default();
+        let now = unix_epoch_now_secs();
+
+        // bytes transferred by active jobs and when the earliest of them started, for the ETA
+        let mut active_bytes = 0;
+        let mut active_since = None;
+
+        for job in jobs {
+            progress.expected_bytes += job.file_size.unwrap_or(0);
+            match &job.progress {
+                TransferJobProgressModel::Requested
+                | TransferJobProgressModel::Transcoding
+                | TransferJobProgressModel::Ready => {
+                    progress.queued_files += 1;
+                }
+                TransferJobProgressModel::Paused { bytes } => {
+                    progress.paused_files += 1;
+                    progress.transferred_bytes += bytes.as_ref().map_or(0, |bytes| bytes.get());
+                }
+                TransferJobProgressModel::InProgress { started_at, bytes } => {
+                    progress.active_files += 1;
+                    progress.transferred_bytes += bytes.get();
+                    active_bytes += bytes.get();
+                    active_since =
+                        Some(active_since.map_or(*started_at, |since: u64| since.min(*started_at)));
+                }
+                TransferJobProgressModel::Finished { .. } => {
+                    progress.completed_files += 1;
+                    progress.transferred_bytes += job.file_size.unwrap_or(0);
+                }
+                TransferJobProgressModel::Failed { .. } => {
+                    progress.failed_files += 1;
+                }
+            }
+        }
+
+        let elapsed = active_since.map_or(0, |since| now.saturating_sub(since));
+        if active_bytes > 0 && elapsed > 0 {
+            let remaining = progress
+                .expected_bytes
+                .saturating_sub(progress.transferred_bytes);
+            progress.eta_secs = Some(remaining * elapsed / active_bytes);
+        }
+
+        progress
+    }
+}
+
+/// Event sent when all the downloads from a server have finished or failed, e.g. for showing a
+/// notification when a sync finishes in the background.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct TransferSessionCompletedEvent {
+    pub endpoint_id: String,
+    pub name: String,
+    pub completed_files: u32,
+    pub failed_files: u32,
+    pub transferred_bytes: u64,
+}
+
+/// A finished download session in the transfer history.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct TransferSessionModel {
+    pub id: u64,
+    pub endpoint_id: String,
+    pub name: String,
+    pub finished_at: u64,
+    pub completed_files: u32,
+    pub failed_files: u32,
+    pub transferred_bytes: u64,
+}
+
+impl From<TransferSession> for TransferSessionModel {
+    fn from(session: TransferSession) -> Self {
+        Self {
+            id: session.id,
+            endpoint_id: session.node_id.to_string(),
+            name: session.name,
+            finished_at: session.finished_at,
+            completed_files: session.completed_files,
+            failed_files: session.failed_files,
+            transferred_bytes: session.transferred_bytes,
+        }
+    }
+}
+
+/// The outcome of a file in a download session in the transfer history.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct TransferSessionFileModel {
+    pub root: String,
+    pub path: String,
+    pub file_size: Option<u64>,
+    /// The error the download failed with, or None if it finished.
+    pub error: Option<String>,
+}
+
+impl From<TransferSessionFile> for TransferSessionFileModel {
+    fn from(file: TransferSessionFile) -> Self {
+        Self {
+            root: file.root,
+            path: file.path,
+            file_size: file.file_size,
+            error: file.error,
+        }
+    }
+}
+
+/// Event sent when a download fails, unless it was cancelled by the user.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct TransferJobFailedEvent {
+    pub endpoint_id: String,
+    pub job_id: u64,
+    pub file_root: String,
+    pub file_path: String,
+    pub error: String,
+}
+
+/// Event sent when a connection closes with an error, rather than being closed by either side.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ConnectionLostEvent {
+    pub endpoint_id: String,
+    pub name: String,
+    /// Whether we were downloading from the remote node, rather than sending to it.
+    pub is_client: bool,
+    pub error: String,
+    /// Number of downloads that hadn't finished, which have to be requested again after
+    /// reconnecting.
+    pub unfinished_files: u32,
+}
+
+/// Event sent instead of model snapshots while the app is in the background, with compact
+/// progress of the active transfers for a foreground service notification.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct BackgroundProgressEvent {
+    /// Whether any transfers are queued or running. The shell can stop its foreground service
+    /// once this is false.
+    pub active: bool,
+    /// Short description of the transfers, like "Downloading from Laptop".
+    pub title: String,
+    /// Percent of the known bytes that have been transferred, or None if no sizes are known.
+    pub percent: Option<u8>,
+    /// Name of a file being transferred, if any.
+    pub current_file: Option<String>,
+    pub completed_files: u32,
+    pub total_files: u32,
+}
+
+impl BackgroundProgressEvent {
+    /// Computes the progress of the sessions that have unfinished transfers. Downloads are shown
+    /// in the title over uploads, since they're what the user is usually waiting for.
+    fn from_model(model: &NodeModel) -> Self {
+        let downloads: Vec<_> = model
+            .clients
+            .values()
+            .filter(|client| client.session.unfinished_files() > 0)
+            .map(|client| (client.name.as_str(), &client.session, &client.transfer_jobs))
+            .collect();
+        let uploads: Vec<_> = model
+            .servers
+            .values()
+            .filter(|server| server.session.unfinished_files() > 0)
+            .map(|server| (server.name.as_str(), &server.session, &server.transfer_jobs))
+            .collect();
+
+        let title = match (downloads.as_slice(), uploads.as_slice()) {
+            ([(name, ..)], _) => format!("Downloading from {name}"),
+            ([], []) => "Transfers finished".to_string(),
+            ([], [(name, ..)]) => format!("Sending to {name}"),
+            ([], uploads) => format!("Sending to {} devices", uploads.len()),
+            (downloads, _) => format!("Downloading from {} devices", downloads.len()),
+        };
+
+        let mut event = Self {
+            active: !downloads.is_empty() || !uploads.is_empty(),
+            title,
+            percent: None,
+            current_file: None,
+            completed_files: 0,
+            total_files: 0,
+        };
+
+        let mut expected_bytes = 0;
+        let mut transferred_bytes = 0;
+        let mut current_started_at = None;
+        for (_, session, jobs) in downloads.iter().chain(uploads.iter()) {
+            event.completed_files += session.completed_files;
+            event.total_files +=
+                session.unfinished_files() + session.completed_files + session.failed_files;
+            expected_bytes += session.expected_bytes;
+            transferred_bytes += session.transferred_bytes;
+
+            // show the file that has been transferring the longest
+            for job in jobs.iter() {
+                if let TransferJobProgressModel::InProgress { started_at, .. } = job.progress
+                    && current_started_at.is_none_or(|current| started_at < current)
+                {
+                    current_started_at = Some(started_at);
+                    event.current_file = Some(
+                        Path::new(&job.file_path)
+                            .file_name()
+                            .map(|name| name.to_string_lossy().into_owned())
+                            .unwrap_or_else(|| job.file_path.clone()),
+                    );
+                }
+            }
+        }
+
+        if expected_bytes > 0 {
+            event.percent =
+                Some((transferred_bytes.min(expected_bytes) * 100 / expected_bytes) as u8);
+        }
+
+        event
+    }
+}
+
+/// A discrete event for the UI, sent after the model snapshot that it describes.
+enum TransferEvent {
+    SessionCompleted(TransferSessionCompletedEvent),
+    JobFailed(TransferJobFailedEvent),
+    ConnectionLost(ConnectionLostEvent),
+}
+
+impl TransferEvent {
+    fn dispatch(self, event_handler: &dyn EventHandler) {
+        match self {
+            TransferEvent::SessionCompleted(event) => {
+                event_handler.on_transfer_session_completed(event)
+            }
+            TransferEvent::JobFailed(event) => event_handler.on_transfer_job_failed(event),
+            TransferEvent::ConnectionLost(event) => event_handler.on_connection_lost(event),
+        }
+    }
 }
 
 /// Model of the state of a server connection.
@@ -103,15 +583,29 @@ pub struct ServerModel {
     pub connected_at: u64,
 
     pub state: ServerStateModel,
+    /// Whether the connection is closing after its active transfers finish.
+    #[uniffi(default = false)]
+    pub draining: bool,
 
+    /// Whether the selected path is "direct" or goes through a "relay", or "unknown".
     pub connection_type: String,
     pub latency_ms: Option<u64>,
-
-    pub transfer_jobs: Vec<TransferJobModel>,
+    /// Remote address of the selected path, i.e. an IP address and port or a relay URL.
+    #[uniffi(default = "")]
+    pub remote_addr: String,
+
+    /// Short phrase derived from both endpoint IDs, for the user to compare with the phrase shown
+    /// on the client before accepting.
+    #[uniffi(default = "")]
+    pub verification_phrase: String,
+
+    pub transfer_jobs: TransferJobsModel,
+    /// Combined progress of the transfer jobs.
+    pub session: SessionProgressModel,
 }
 
 /// Model of an unknown, estimated, or actual file size.
-#[derive(Debug, Clone, uniffi::Enum)]
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Enum)]
 pub enum FileSizeModel {
     Unknown,
     Estimated(u64),
@@ -125,6 +619,8 @@ pub enum IndexItemDownloadStatusModel {
     InProgress,
     Downloaded,
     Failed,
+    /// Downloaded, but the file changed on the server since.
+    Changed,
 }
 
 /// Model of an item in the index sent by the server.
@@ -134,9 +630,86 @@ pub struct IndexItemModel {
     pub root: String,
     pub path: String,
 
+    /// The size of the file to be transferred, which is the estimated transcoded size if the
+    /// connection is transcoding.
     pub file_size: FileSizeModel,
 
     pub download_status: Option<IndexItemDownloadStatusModel>,
+
+    // Tags and duration of the original file, if the server sent them.
+    #[uniffi(default = None)]
+    pub title: Option<String>,
+    #[uniffi(default = None)]
+    pub artist: Option<String>,
+    #[uniffi(default = None)]
+    pub album: Option<String>,
+    #[uniffi(default = None)]
+    pub track_number: Option<u32>,
+    /// Duration in seconds.
+    #[uniffi(default = None)]
+    pub duration: Option<f64>,
+
+    /// Whether the server's download filter excludes the file, so auto-download and downloading
+    /// everything skip it.
+    #[uniffi(default = false)]
+    pub filtered: bool,
+}
+
+/// Model of the index sent by a server, shared by the snapshots it's in.
+pub type IndexModel = SharedList<IndexItemModel>;
+
+uniffi::custom_type!(IndexModel, Vec<IndexItemModel>, {
+    lower: |index| index.into(),
+    try_lift: |index| Ok(index.into()),
+});
+
+/// Model of a downloaded file that mirroring deletes, or would delete in a dry run.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct MirrorDeletionModel {
+    pub root: String,
+    pub path: String,
+    pub local_path: String,
+}
+
+/// Model of a downloaded file that doesn't match what the server sent when it was downloaded.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct DownloadIssueModel {
+    pub endpoint_id: String,
+    pub root: String,
+    pub path: String,
+    pub local_path: String,
+    pub issue: DownloadIssueKindModel,
+    /// Whether the file was deleted and untracked, so the next sync downloads it again.
+    pub repaired: bool,
+    /// Whether the file was requested from the server again.
+    #[uniffi(default = false)]
+    pub requeued: bool,
+}
+
+/// Model of a downloaded file whose original changed on the server since it was downloaded.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ChangedDownloadModel {
+    pub endpoint_id: String,
+    pub root: String,
+    pub path: String,
+    pub local_path: String,
+    /// Whether the file was requested from the server again.
+    pub requeued: bool,
+}
+
+/// Model of what's wrong with a downloaded file.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Enum)]
+pub enum DownloadIssueKindModel {
+    /// The file no longer exists.
+    Missing,
+    /// The file's size doesn't match its manifest.
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The file's checksum doesn't match its manifest.
+    ChecksumMismatch,
+    /// The original file changed on the server since it was downloaded.
+    Outdated,
+    /// The file couldn't be read to check it.
+    Unreadable { error: String },
 }
 
 /// Model of the state of a client connection.
@@ -155,31 +728,364 @@ pub struct ClientModel {
     pub connected_at: u64,
 
     pub state: ClientStateModel,
+    /// Whether the connection is closing after its active transfers finish.
+    #[uniffi(default = false)]
+    pub draining: bool,
 
+    /// Whether the selected path is "direct" or goes through a "relay", or "unknown".
     pub connection_type: String,
     pub latency_ms: Option<u64>,
-
-    pub index: Option<Vec<IndexItemModel>>,
-    pub transfer_jobs: Vec<TransferJobModel>,
+    /// Remote address of the selected path, i.e. an IP address and port or a relay URL.
+    #[uniffi(default = "")]
+    pub remote_addr: String,
+
+    /// Short phrase derived from both endpoint IDs, for the user to compare with the phrase shown
+    /// on the server.
+    #[uniffi(default = "")]
+    pub verification_phrase: String,
+
+    pub index: Option<IndexModel>,
+    /// Whether all pages of the index have arrived. Until then, the index only has some of the
+    /// server's files, and downloads can't be requested.
+    #[uniffi(default = false)]
+    pub index_complete: bool,
+    pub transfer_jobs: TransferJobsModel,
+    /// Combined progress of the transfer jobs.
+    pub session: SessionProgressModel,
     pub paused: bool,
+    /// Set when the last selection of downloads didn't fit in the free space of the download
+    /// directory, so only part of it was queued.
+    #[uniffi(default = None)]
+    pub insufficient_space: Option<InsufficientSpaceModel>,
+    /// Set when auto-download last ran with a storage quota for the server, with what didn't fit.
+    #[uniffi(default = None)]
+    pub storage_quota: Option<StorageQuotaModel>,
+    /// Downloads in the transfer jobs that collided with existing files, had unsupported names,
+    /// or couldn't be written, ordered by job.
+    #[uniffi(default = [])]
+    pub conflicts: Vec<DownloadConflictModel>,
+}
+
+/// Model of a selection of downloads that didn't fit in the free space of the download directory.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct InsufficientSpaceModel {
+    /// Expected size of the new items in the selection.
+    pub required_bytes: u64,
+    /// Free space in the download directory when the selection was made.
+    pub available_bytes: u64,
+    /// Number of items that weren't queued because they didn't fit.
+    pub skipped_items: u64,
+}
+
+/// Model of how auto-download filled the storage quota for a server.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct StorageQuotaModel {
+    /// Maximum total size of the files downloaded from the server.
+    pub quota_bytes: u64,
+    /// Size of the files downloaded or queued from the server before auto-download ran.
+    pub used_bytes: u64,
+    /// Number of files that weren't queued because they didn't fit in the quota.
+    pub skipped_items: u64,
+    /// Expected size of the skipped files.
+    pub skipped_bytes: u64,
 }
 
 /// Model of a trusted node.
 #[derive(Debug, Clone, uniffi::Record)]
 pub struct TrustedNodeModel {
     pub endpoint_id: String,
+    /// The node's label if it has one, otherwise its device name.
     pub name: String,
+    /// Local label set by the user, if any.
+    #[uniffi(default = None)]
+    pub label: Option<String>,
     pub connected_at: Option<u64>,
+    /// Roots and directories shared with the node. If empty, the node can see the whole library.
+    pub shares: Vec<NodeShareModel>,
+    /// Limits on the files shared with the node, or None if it isn't filtered.
+    #[uniffi(default = None)]
+    pub share_filter: Option<ShareFilterModel>,
+    /// Settings for automatically downloading new files from the node, or None if disabled.
+    #[uniffi(default = None)]
+    pub auto_download: Option<AutoDownloadModel>,
+    /// Maximum total size of the files auto-downloaded from the node, or None for no limit.
+    #[uniffi(default = None)]
+    pub storage_quota: Option<u64>,
+    /// Limits on the files downloaded from the node, or None if they aren't filtered.
+    #[uniffi(default = None)]
+    pub download_filter: Option<DownloadFilterModel>,
+    /// How much of the library shared with the node has been sent to it, or None if unknown.
+    #[uniffi(default = None)]
+    pub library_coverage: Option<LibraryCoverageModel>,
+}
+
+/// Model of how much of the local library has been sent to a trusted node.
+///
+/// Files are matched by content hash, so files that haven't been hashed yet count as not sent.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct LibraryCoverageModel {
+    /// Number of files shared with the node that it has downloaded.
+    pub delivered_files: u64,
+    /// Number of files shared with the node.
+    pub library_files: u64,
+}
+
+/// Model of the settings for automatically downloading new files from a trusted server.
+///
+/// When connected, files in the server's index that aren't downloaded yet are queued
+/// automatically, so the download directory mirrors the server's library.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct AutoDownloadModel {
+    /// Artists and albums to download, or empty to download everything.
+    pub selections: Vec<DownloadSelectionModel>,
+    /// Maximum total size of the files queued on each connection, or None for no limit.
+    pub max_bytes: Option<u64>,
+}
+
+impl From<AutoDownload> for AutoDownloadModel {
+    fn from(auto_download: AutoDownload) -> Self {
+        AutoDownloadModel {
+            selections: auto_download
+                .selections
+                .into_iter()
+                .map(|selection| match selection.album {
+                    Some(album) => DownloadSelectionModel::Album {
+                        artist: selection.artist,
+                        album,
+                    },
+                    None => DownloadSelectionModel::Artist {
+                        artist: selection.artist.unwrap_or_default(),
+                    },
+                })
+                .collect(),
+            max_bytes: auto_download.max_bytes,
+        }
+    }
+}
+
+impl From<AutoDownloadModel> for AutoDownload {
+    fn from(auto_download: AutoDownloadModel) -> Self {
+        AutoDownload {
+            selections: auto_download
+                .selections
+                .into_iter()
+                .map(|selection| match selection {
+                    DownloadSelectionModel::Artist { artist } => AutoDownloadSelection {
+                        artist: Some(artist),
+                        album: None,
+                    },
+                    DownloadSelectionModel::Album { artist, album } => AutoDownloadSelection {
+                        artist,
+                        album: Some(album),
+                    },
+                })
+                .collect(),
+            max_bytes: auto_download.max_bytes,
+        }
+    }
+}
+
+/// Model of a root, or a directory in a root, that is shared with a node.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct NodeShareModel {
+    pub root: String,
+    /// Slash path of a directory or file in the root, or empty to share the whole root.
+    pub path_prefix: String,
+}
+
+/// Model of the limits on the files shared with a node.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct ShareFilterModel {
+    /// Maximum size of an original file in bytes, or None for no limit.
+    pub max_file_size: Option<u64>,
+    /// Maximum duration in seconds, or None for no limit.
+    pub max_duration: Option<f64>,
+    /// File extensions that aren't shared, e.g. `wav`.
+    pub excluded_formats: Vec<String>,
+}
+
+impl From<ShareFilter> for ShareFilterModel {
+    fn from(share_filter: ShareFilter) -> Self {
+        ShareFilterModel {
+            max_file_size: share_filter.max_file_size,
+            max_duration: share_filter.max_duration,
+            excluded_formats: share_filter.excluded_formats,
+        }
+    }
+}
+
+/// Model of the limits on the files downloaded from a server.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct DownloadFilterModel {
+    /// Maximum size of a downloaded file in bytes, or None for no limit.
+    pub max_file_size: Option<u64>,
+    /// Maximum duration in seconds, or None for no limit.
+    pub max_duration: Option<f64>,
+}
+
+impl From<DownloadFilter> for DownloadFilterModel {
+    fn from(download_filter: DownloadFilter) -> Self {
+        DownloadFilterModel {
+            max_file_size: download_filter.max_file_size,
+            max_duration: download_filter.max_duration,
+        }
+    }
+}
+
+impl From<DownloadFilterModel> for DownloadFilter {
+    fn from(download_filter: DownloadFilterModel) -> Self {
+        DownloadFilter {
+            max_file_size: download_filter.max_file_size,
+            max_duration: download_filter.max_duration,
+        }
+    }
+}
+
+impl From<ShareFilterModel> for ShareFilter {
+    fn from(share_filter: ShareFilterModel) -> Self {
+        ShareFilter {
+            max_file_size: share_filter.max_file_size,
+            max_duration: share_filter.max_duration,
+            excluded_formats: share_filter
+                .excluded_formats
+                .iter()
+                .map(|format| format.trim().trim_start_matches('.').to_lowercase())
+                .filter(|format| !format.is_empty())
+                .unique()
+                .collect(),
+        }
+    }
 }
 
 /// Model of a recently connected server.
 #[derive(Debug, Clone, uniffi::Record)]
 pub struct RecentServerModel {
     pub endpoint_id: String,
+    /// The server's label if it has one, otherwise its device name.
     pub name: String,
+    /// Local label set by the user, if any.
+    #[uniffi(default = None)]
+    pub label: Option<String>,
     pub connected_at: u64,
 }
 
+/// Model of the bytes of files transferred with a node, for keeping track of data usage.
+///
+/// Bytes are counted when each file finishes transferring. Resumed downloads only count the
+/// remaining part of the file.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct PeerTrafficModel {
+    pub endpoint_id: String,
+    /// Bytes uploaded to the node since the app started.
+    pub session_sent_bytes: u64,
+    /// Bytes downloaded from the node since the app started.
+    pub session_received_bytes: u64,
+    /// Bytes uploaded to the node in total.
+    pub lifetime_sent_bytes: u64,
+    /// Bytes downloaded from the node in total.
+    pub lifetime_received_bytes: u64,
+}
+
+/// Model of the storage used by the files downloaded from a server.
+///
+/// Totals are kept up to date as files are downloaded and deleted, so they don't notice files that
+/// were changed or deleted outside the app.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct DownloadUsageModel {
+    pub endpoint_id: String,
+    pub files: u64,
+    /// Total size of the files. Files downloaded before sizes were recorded count as empty.
+    pub bytes: u64,
+}
+
+/// Model of a sync group that this node is a member of.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct SyncGroupModel {
+    pub group_id: String,
+    pub name: String,
+    pub founder: String,
+    /// Endpoint IDs of the members this node knows about, including itself.
+    pub members: Vec<String>,
+}
+
+/// Model of an invitation to join a sync group, received from a trusted node. The group's members
+/// are only trusted once the invitation is accepted.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct SyncGroupInvitationModel {
+    pub group_id: String,
+    pub name: String,
+    pub founder: String,
+    /// Endpoint ID of the trusted node that added this node to the group.
+    pub inviter: String,
+    /// Endpoint IDs of the group's members, including this node.
+    pub members: Vec<String>,
+}
+
+/// Model of a share link for sending files to someone without the app.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ShareLinkModel {
+    pub token: String,
+    pub url: String,
+    pub name: String,
+    /// Transcode format the files are served in, or None for the original files.
+    pub transcode_format: Option<TranscodeFormat>,
+    pub file_count: u64,
+    /// Number of files that have been downloaded completely at least once.
+    pub downloaded_files: u64,
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// Whether the link is removed once each of its files has been downloaded.
+    pub one_time: bool,
+}
+
+/// Model of the progress of downloads across all connections.
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct DownloadProgressModel {
+    /// Number of connections with transfer jobs.
+    pub servers: u32,
+    /// Number of jobs waiting for their files to be transcoded or downloaded.
+    pub queued_jobs: u32,
+    pub active_jobs: u32,
+    pub finished_jobs: u32,
+    pub failed_jobs: u32,
+    /// Total size of the jobs whose file sizes are known.
+    pub total_bytes: u64,
+    /// Number of bytes downloaded by active and finished jobs.
+    pub downloaded_bytes: u64,
+}
+
+/// Which relays the node uses to reach peers it can't connect to directly.
+#[derive(Debug, Clone, Default, PartialEq, Eq, uniffi::Enum)]
+pub enum RelayConfig {
+    /// Use the default public relays.
+    #[default]
+    Default,
+    /// Use only the given relays instead of the default ones, e.g. a self-hosted relay.
+    Custom { urls: Vec<String> },
+    /// Don't use any relays, so peers can only be reached directly.
+    Disabled,
+}
+
+impl RelayConfig {
+    fn relay_mode(&self) -> anyhow::Result<RelayMode> {
+        match self {
+            RelayConfig::Default => Ok(RelayMode::Default),
+            RelayConfig::Custom { urls } => {
+                let urls = urls
+                    .iter()
+                    .map(|url| {
+                        url.parse::<RelayUrl>()
+                            .with_context(|| format!("failed to parse relay url {url:?}"))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                anyhow::ensure!(!urls.is_empty(), "no custom relay urls");
+                Ok(RelayMode::Custom(RelayMap::from_iter(urls)))
+            }
+            RelayConfig::Disabled => Ok(RelayMode::Disabled),
+        }
+    }
+}
+
 /// Node state sent to the UI.
 ///
 /// Needs to be Clone to send snapshots to the UI.
@@ -188,6 +1094,14 @@ pub struct NodeModel {
     pub endpoint_id: String,
 
     pub home_relay: String,
+    /// The relays the node was configured to use.
+    pub relay_config: RelayConfig,
+    /// Whether the node only connects to peers on the local network.
+    pub lan_only: bool,
+    /// Whether the node accepts incoming connections from nodes that aren't trusted.
+    pub accept_incoming: bool,
+    /// The local addresses the endpoint's sockets are bound to.
+    pub bound_sockets: Vec<String>,
 
     pub send_ipv4: u64,
     pub send_ipv6: u64,
@@ -203,46 +1117,578 @@ pub struct NodeModel {
 
     pub trusted_nodes: Vec<TrustedNodeModel>,
     pub recent_servers: Vec<RecentServerModel>,
+    /// Bytes transferred with each node, most traffic first.
+    pub peer_traffic: Vec<PeerTrafficModel>,
+    /// Storage used by the files downloaded from each server, most bytes first.
+    pub download_usage: Vec<DownloadUsageModel>,
+
+    /// Local address of the share server, if it's running.
+    pub share_server_addr: Option<String>,
+    /// Share links that haven't expired or been used up, newest first.
+    pub share_links: Vec<ShareLinkModel>,
+
+    /// Sync groups this node is a member of, whose members trust each other.
+    pub sync_groups: Vec<SyncGroupModel>,
+    /// Invitations to sync groups waiting for the user to accept or decline them.
+    #[uniffi(default = [])]
+    pub sync_group_invitations: Vec<SyncGroupInvitationModel>,
+
+    /// Rules for when queued downloads can run.
+    pub transfer_schedule: TransferSchedule,
+    /// Why queued downloads are held, or empty if they can run. Held downloads stay queued until
+    /// the schedule is met.
+    pub transfer_hold_reasons: Vec<TransferHoldReason>,
+    /// The device's network, as reported by the shell.
+    pub network_state: NetworkState,
+    /// The download rate limit in effect in bytes per second, or 0 for no limit. This is lower
+    /// than the maximum download rate on metered networks with `MeteredPolicy::Limit`.
+    pub download_rate_limit: u64,
+
+    /// Downloads that didn't finish before the app last stopped, which can be resumed or
+    /// discarded.
+    pub interrupted_downloads: Vec<InterruptedDownloadModel>,
+    /// Result of cleaning up the partial files in the download directory that weren't from
+    /// interrupted downloads at startup, or None until it finishes.
+    pub partial_cleanup: Option<PartialCleanupModel>,
+    /// The playlists written after the last sync, or None if none were written since launch.
+    pub last_playlist_write: Option<PlaylistWriteModel>,
 }
 
-/// Model of an item selected to be downloaded.
-#[derive(Debug, Clone, uniffi::Record)]
-pub struct DownloadRequestModel {
-    pub endpoint_id: String,
-    pub root: String,
-    pub path: String,
-}
-
-/// A command sent by the UI to the node.
-#[derive(Debug)]
-pub enum NodeCommand {
-    SetDownloadDirectory(String),
+impl NodeModel {
+    /// Applies a patch from a diff to the model.
+    pub fn apply_patch(&mut self, patch: NodeModelPatch) {
+        match patch {
+            NodeModelPatch::Node { model } => {
+                let servers = std::mem::take(&mut self.servers);
+                let clients = std::mem::take(&mut self.clients);
+                *self = NodeModel {
+                    servers,
+                    clients,
+                    ..model
+                };
+            }
+            NodeModelPatch::Snapshot { model } => {
+                *self = model;
+            }
+            NodeModelPatch::ServerUpdated { server } => {
+                self.servers.insert(server.endpoint_id.clone(), server);
+            }
+            NodeModelPatch::ServerRemoved { endpoint_id } => {
+                self.servers.remove(&endpoint_id);
+            }
+            NodeModelPatch::ClientUpdated { client } => {
+                let index = self
+                    .clients
+                    .get_mut(&client.endpoint_id)
+                    .and_then(|client| client.index.take());
+                self.clients
+                    .insert(client.endpoint_id.clone(), ClientModel { index, ..client });
+            }
+            NodeModelPatch::ClientTransferJobsUpdated {
+                client,
+                changed_jobs,
+                removed_job_ids,
+            } => {
+                let (index, transfer_jobs) = match self.clients.get_mut(&client.endpoint_id) {
+                    Some(client) => (
+                        client.index.take(),
+                        std::mem::take(&mut client.transfer_jobs),
+                    ),
+                    None => (None, TransferJobsModel::default()),
+                };
 
-    Connect {
-        /// Transcode format for transcoding, or None to transfer original files.
-        transcode_format: Option<TranscodeFormat>,
-        addr: EndpointAddr,
-        callback: oneshot::Sender<anyhow::Result<()>>,
+                let mut changed_jobs = changed_jobs
+                    .into_iter()
+                    .map(|job| (job.job_id, job))
+                    .collect::<HashMap<_, _>>();
+                let mut jobs = Vec::from(transfer_jobs)
+                    .into_iter()
+                    .filter(|job| !removed_job_ids.contains(&job.job_id))
+                    .map(|job| changed_jobs.remove(&job.job_id).unwrap_or(job))
+                    .collect::<Vec<_>>();
+                jobs.extend(changed_jobs.into_values());
+
+                self.clients.insert(
+                    client.endpoint_id.clone(),
+                    ClientModel {
+                        index,
+                        transfer_jobs: jobs.into(),
+                        ..client
+                    },
+                );
+            }
+            NodeModelPatch::ClientIndexUpdated {
+                endpoint_id,
+                index,
+                index_complete,
+            } => {
+                if let Some(client) = self.clients.get_mut(&endpoint_id) {
+                    client.index = index;
+                    client.index_complete = index_complete;
+                }
+            }
+            NodeModelPatch::ClientRemoved { endpoint_id } => {
+                self.clients.remove(&endpoint_id);
+            }
+        }
+    }
+
+    /// Copies the model without its servers and clients, which are patched separately.
+    fn without_connections(&self) -> NodeModel {
+        NodeModel {
+            endpoint_id: self.endpoint_id.clone(),
+
+            home_relay: self.home_relay.clone(),
+            relay_config: self.relay_config.clone(),
+            lan_only: self.lan_only,
+            accept_incoming: self.accept_incoming,
+            bound_sockets: self.bound_sockets.clone(),
+
+            send_ipv4: self.send_ipv4,
+            send_ipv6: self.send_ipv6,
+            send_relay: self.send_relay,
+            recv_ipv4: self.recv_ipv4,
+            recv_ipv6: self.recv_ipv6,
+            recv_relay: self.recv_relay,
+            conn_success: self.conn_success,
+            conn_direct: self.conn_direct,
+
+            servers: HashMap::new(),
+            clients: HashMap::new(),
+
+            trusted_nodes: self.trusted_nodes.clone(),
+            recent_servers: self.recent_servers.clone(),
+            peer_traffic: self.peer_traffic.clone(),
+            download_usage: self.download_usage.clone(),
+
+            share_server_addr: self.share_server_addr.clone(),
+            share_links: self.share_links.clone(),
+
+            sync_groups: self.sync_groups.clone(),
+            sync_group_invitations: self.sync_group_invitations.clone(),
+
+            transfer_schedule: self.transfer_schedule,
+            transfer_hold_reasons: self.transfer_hold_reasons.clone(),
+            network_state: self.network_state,
+            download_rate_limit: self.download_rate_limit,
+
+            interrupted_downloads: self.interrupted_downloads.clone(),
+            partial_cleanup: self.partial_cleanup.clone(),
+            last_playlist_write: self.last_playlist_write.clone(),
+        }
+    }
+}
+
+impl ClientModel {
+    /// Copies the model without its index, which can be large and is patched separately.
+    fn without_index(&self) -> ClientModel {
+        ClientModel {
+            name: self.name.clone(),
+            endpoint_id: self.endpoint_id.clone(),
+            connected_at: self.connected_at,
+
+            state: self.state.clone(),
+            draining: self.draining,
+
+            connection_type: self.connection_type.clone(),
+            latency_ms: self.latency_ms,
+            remote_addr: self.remote_addr.clone(),
+
+            verification_phrase: self.verification_phrase.clone(),
+
+            index: None,
+            index_complete: self.index_complete,
+            transfer_jobs: self.transfer_jobs.clone(),
+            session: self.session.clone(),
+            paused: self.paused,
+            insufficient_space: self.insufficient_space.clone(),
+            storage_quota: self.storage_quota.clone(),
+            conflicts: self.conflicts.clone(),
+        }
+    }
+
+    /// Copies the model without its index or transfer jobs, for patches that only include the
+    /// jobs that changed.
+    fn without_index_or_jobs(&self) -> ClientModel {
+        ClientModel {
+            transfer_jobs: TransferJobsModel::default(),
+            ..self.without_index()
+        }
+    }
+
+    /// Whether the connection is accepted and all of the server's index has arrived, so downloads
+    /// can be requested.
+    fn is_ready(&self) -> bool {
+        matches!(self.state, ClientStateModel::Accepted) && self.index_complete
+    }
+}
+
+/// A change to the node model, sent instead of a full snapshot when model diffs are enabled.
+///
+/// Versions count up by one with each change. If a diff's version isn't one more than the last
+/// one applied, the UI missed a change and should fetch a versioned snapshot to resync.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct NodeModelDiff {
+    pub version: u64,
+    pub patch: NodeModelPatch,
+}
+
+/// The part of the node model that changed, with its new value.
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum NodeModelPatch {
+    /// Fields of the node itself changed. The model's servers and clients are empty, and should
+    /// be kept as they are.
+    Node {
+        model: NodeModel,
+    },
+    /// The whole model should be replaced, e.g. after the changes made while the app was in the
+    /// background, which weren't sent.
+    Snapshot {
+        model: NodeModel,
+    },
+
+    /// A server was added or changed.
+    ServerUpdated {
+        server: ServerModel,
+    },
+    ServerRemoved {
+        endpoint_id: String,
+    },
+
+    /// A client was added or changed. The client's index is left out and should be kept as it is.
+    ClientUpdated {
+        client: ClientModel,
+    },
+    /// The transfer jobs of a client changed. The client's index and transfer jobs are left out.
+    /// Only the jobs that were added or changed are included, and the other jobs should be kept
+    /// as they are unless they were removed.
+    ClientTransferJobsUpdated {
+        client: ClientModel,
+        changed_jobs: Vec<TransferJobModel>,
+        removed_job_ids: Vec<u64>,
+    },
+    /// The index of a client changed.
+    ClientIndexUpdated {
+        endpoint_id: String,
+        index: Option<IndexModel>,
+        index_complete: bool,
+    },
+    ClientRemoved {
+        endpoint_id: String,
+    },
+}
+
+/// A snapshot of the node model with the version of the last change it includes.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct VersionedNodeModel {
+    pub version: u64,
+    pub model: NodeModel,
+}
+
+/// Model of an item selected to be downloaded.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct DownloadRequestModel {
+    pub endpoint_id: String,
+    pub root: String,
+    pub path: String,
+}
+
+/// Model of an album in a server's library, for browsing it by artist and album.
+///
+/// Files that haven't been tagged, or whose tags the server hasn't read yet, are grouped under
+/// a missing artist and album.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct RemoteAlbumModel {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub file_count: u64,
+    pub estimated_size: u64,
+}
+
+/// A selection of files in a server's library, expanded into the matching files to download.
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum DownloadSelectionModel {
+    /// All files by the artist.
+    Artist { artist: String },
+    /// All files in the album. Different artists can have albums with the same name, so the
+    /// artist can be given to only select one of them.
+    Album {
+        artist: Option<String>,
+        album: String,
+    },
+}
+
+impl DownloadSelectionModel {
+    fn matches(&self, metadata: &ItemMetadata) -> bool {
+        match self {
+            DownloadSelectionModel::Artist { artist } => metadata.artist.as_ref() == Some(artist),
+            DownloadSelectionModel::Album { artist, album } => {
+                metadata.album.as_ref() == Some(album)
+                    && artist
+                        .as_ref()
+                        .is_none_or(|artist| metadata.artist.as_ref() == Some(artist))
+            }
+        }
+    }
+}
+
+/// A command sent by the UI to the node.
+#[derive(Debug)]
+pub enum NodeCommand {
+    SetDownloadDirectory(Option<String>),
+    /// Set what happens to partial files that aren't in the download journal when the download
+    /// directory is first set.
+    SetPartialFilePolicy(PartialFilePolicy),
+    /// Set the template for the paths of downloaded files, or None to keep the server's folder
+    /// structure.
+    SetDownloadPathTemplate(Option<PathTemplate>),
+    /// Set which playlists are written to the download directory after a sync.
+    SetPlaylistSettings(PlaylistSettings),
+    /// Set what to do when a download's destination already has a file.
+    SetCollisionPolicy(CollisionPolicy),
+    /// Set how long incoming and outgoing connections can wait to be accepted before they're
+    /// closed, or None to wait forever.
+    SetPendingTimeout(Option<Duration>),
+    /// Set whether incoming connections from nodes that aren't trusted are accepted.
+    SetAcceptIncoming(bool),
+    /// Set whether downloaded files are synced to disk before being marked finished.
+    SetSyncDownloads(bool),
+    SetVerifyDownloads(bool),
+    /// Set whether files that changed on the server since they were downloaded are downloaded
+    /// again when connecting.
+    SetRedownloadChanged(bool),
+    /// Set the number of streams used to download each large file, or 1 to use a single stream.
+    SetParallelStreams(u32),
+    /// Set the size in bytes of the buffers that files are sent and received in.
+    SetTransferBufferSize(u32),
+    /// Set the maximum number of concurrent file transfers per connection.
+    SetMaxConcurrentTransfers(u32),
+    /// Set the maximum number of concurrent downloads across all connections, or 0 for no limit.
+    SetMaxTotalDownloads(u32),
+    /// Set the maximum download rate across all connections in bytes per second, or 0 for no
+    /// limit.
+    SetMaxDownloadRate(u64),
+    /// Set the maximum number of files sent at once to each client, or 0 to only use the limit per
+    /// connection.
+    SetMaxUploadsPerClient(u32),
+    /// Set the maximum upload rate to each client in bytes per second, or 0 for no limit.
+    SetMaxUploadRatePerClient(u64),
+    /// Set the rules for when queued downloads can run.
+    SetTransferSchedule(TransferSchedule),
+    /// Set the power and network state of the device, reported by the shell.
+    SetDeviceState(DeviceState),
+    /// Set the state of the device's network, reported by the shell.
+    SetNetworkState(NetworkState),
+    /// Set how transfers are restricted on metered networks.
+    SetNetworkPolicy(NetworkPolicy),
+    /// Set whether the app is in the background, where background progress is sent instead of
+    /// model changes.
+    SetBackground(bool),
+    /// Set how long finished download sessions are kept in the transfer history, or None to keep
+    /// them forever.
+    SetTransferHistoryRetention(Option<Duration>),
+    /// Replace references to a stale iOS bookmark with a refreshed one.
+    ReplaceBookmark {
+        stale: String,
+        fresh: String,
+    },
+
+    Connect {
+        /// Transcode format for transcoding, or None to transfer original files.
+        transcode_format: Option<TranscodeFormat>,
+        addr: EndpointAddr,
+        /// One-time token from a pairing ticket, if connecting with one.
+        pairing_token: Option<PairingToken>,
+        callback: oneshot::Sender<anyhow::Result<()>>,
     },
 
     AcceptConnection(EndpointId),
     DenyConnection(EndpointId),
 
+    /// Ask a trusted client to download files from our library, for push mode.
+    PushFiles {
+        server: EndpointId,
+        items: Vec<DownloadRequestModel>,
+    },
+    /// Ask a trusted client to download the files shared with it that it hasn't downloaded yet.
+    PushMissingFiles {
+        server: EndpointId,
+    },
+
     CloseClient(EndpointId),
     CloseServer(EndpointId),
+    /// Close a client after its active downloads finish, without starting new ones.
+    DrainClient {
+        endpoint_id: EndpointId,
+        timeout: Duration,
+    },
+    /// Close a server after its active uploads finish, without starting new ones.
+    DrainServer {
+        endpoint_id: EndpointId,
+        timeout: Duration,
+    },
+    /// Stop opening and accepting connections, and drain all open ones, before shutting down.
+    DrainAll {
+        timeout: Duration,
+    },
 
     RefreshClientIndex(EndpointId),
 
     SetDownloads {
         client: EndpointId,
         items: Vec<DownloadRequestModel>,
+        /// Called with the root and path of each new download once the client has queued them.
+        callback: Option<oneshot::Sender<anyhow::Result<Vec<(String, String)>>>>,
+    },
+    /// Move queued downloads to the front of the queue.
+    PrioritizeDownloads {
+        client: EndpointId,
+        items: Vec<DownloadRequestModel>,
     },
     PauseDownloads {
         client: EndpointId,
     },
+    /// Pause a single transfer job, keeping the rest of the session running.
+    PauseTransfer {
+        client: EndpointId,
+        job_id: u64,
+    },
+    /// Resume a transfer job paused with PauseTransfer.
+    ResumeTransfer {
+        client: EndpointId,
+        job_id: u64,
+    },
+    /// Cancel a single transfer job, discarding any partially downloaded data.
+    CancelTransfer {
+        client: EndpointId,
+        job_id: u64,
+    },
+    /// Ask the server to rescan its library.
+    RequestRemoteRescan(EndpointId),
+    /// Download the original files the server has that the local library lacks, and ask the
+    /// server to do the same in the other direction.
+    TwoWaySync {
+        client: EndpointId,
+        conflict_policy: SyncConflictPolicy,
+    },
+    /// Delete downloaded files that no longer exist in the server's index.
+    MirrorDownloads {
+        client: EndpointId,
+        /// If set, only list the files that would be deleted.
+        dry_run: bool,
+        callback: oneshot::Sender<anyhow::Result<Vec<MirrorDeletionModel>>>,
+    },
+    /// Request the interrupted downloads from a server again, resuming from their partial files.
+    ResumeInterruptedDownloads {
+        client: EndpointId,
+    },
+    /// Remove the partial files of the interrupted downloads, optionally only from one server.
+    DiscardInterruptedDownloads {
+        client: Option<EndpointId>,
+        callback: oneshot::Sender<anyhow::Result<u32>>,
+    },
+    /// Check downloaded files against the manifests the servers sent with them.
+    CheckDownloadedFiles {
+        /// If set, delete and untrack files with issues so they're downloaded again.
+        repair: bool,
+        callback: oneshot::Sender<anyhow::Result<Vec<DownloadIssueModel>>>,
+    },
+    /// Move downloaded files to the paths the download path template gives them.
+    ReorganizeDownloads {
+        /// If set, only report what would be moved.
+        dry_run: bool,
+        callback: oneshot::Sender<anyhow::Result<ReorganizedDownloadsModel>>,
+    },
+    /// Delete selected files downloaded from a server and untrack them.
+    DeleteDownloadedFiles {
+        client: EndpointId,
+        selections: Vec<DownloadedFileSelectionModel>,
+        callback: oneshot::Sender<anyhow::Result<DeletedDownloadsModel>>,
+    },
+    /// Rehash files downloaded from a server and check them against their manifests and the
+    /// server's index.
+    VerifyDownloads {
+        client: EndpointId,
+        /// If set, delete and untrack files with issues and download them again.
+        requeue: bool,
+        callback: oneshot::Sender<anyhow::Result<Vec<DownloadIssueModel>>>,
+    },
+    /// List the files downloaded from a server that changed on the server since.
+    ChangedDownloads {
+        client: EndpointId,
+        /// If set, download the changed files again.
+        requeue: bool,
+        callback: oneshot::Sender<anyhow::Result<Vec<ChangedDownloadModel>>>,
+    },
+    /// Match existing local files to a server's index and track them as downloaded.
+    ImportDownloads {
+        client: EndpointId,
+        /// Folder in the download directory to look in, or the whole directory if None.
+        folder: Option<String>,
+        /// If set, only report what would be imported.
+        dry_run: bool,
+        callback: oneshot::Sender<anyhow::Result<ImportedDownloadsModel>>,
+    },
+    /// List the albums in a server's index by their tags.
+    BrowseRemoteLibrary {
+        client: EndpointId,
+        callback: oneshot::Sender<anyhow::Result<Vec<RemoteAlbumModel>>>,
+    },
+    /// Expand selections of artists and albums into the matching files in a server's index.
+    ExpandDownloadSelection {
+        client: EndpointId,
+        selections: Vec<DownloadSelectionModel>,
+        callback: oneshot::Sender<anyhow::Result<Vec<DownloadRequestModel>>>,
+    },
 
     TrustNode(EndpointId),
     UntrustNode(EndpointId),
+    /// Replace the roots and directories shared with a node. An empty list shares the whole
+    /// library.
+    SetNodeShares {
+        endpoint_id: EndpointId,
+        shares: Vec<NodeShareModel>,
+    },
+    /// Replace the limits on the files shared with a trusted node, or remove them with None.
+    SetShareFilter {
+        endpoint_id: EndpointId,
+        share_filter: Option<ShareFilterModel>,
+    },
+    /// Replace the auto-download settings for a trusted server, or disable auto-download with
+    /// None.
+    SetAutoDownload {
+        endpoint_id: EndpointId,
+        auto_download: Option<AutoDownloadModel>,
+    },
+    /// Set the maximum total size of the files auto-downloaded from a server, or remove the limit
+    /// with None.
+    SetStorageQuota {
+        endpoint_id: EndpointId,
+        max_bytes: Option<u64>,
+    },
+    /// Set the limits on the files downloaded from a server, or remove them with None.
+    SetDownloadFilter {
+        endpoint_id: EndpointId,
+        download_filter: Option<DownloadFilterModel>,
+    },
+    /// Set the local label of a trusted node or recent server, or remove it with None.
+    SetNodeLabel {
+        endpoint_id: EndpointId,
+        label: Option<String>,
+    },
+
+    /// Start serving share links over HTTP, replacing the running share server if there is one.
+    StartShareServer {
+        bind_addr: String,
+        /// Base URL to build links from, e.g. behind a reverse proxy, or None to use the bound
+        /// address.
+        public_url: Option<String>,
+        callback: oneshot::Sender<anyhow::Result<String>>,
+    },
+    /// Stop serving share links. Links are kept until they expire.
+    StopShareServer,
+    /// Remove a share link.
+    RevokeShareLink(String),
 
     RefreshModel,
 
@@ -256,9 +1702,19 @@ pub enum NodeCommand {
 #[derive(Debug)]
 enum NodeEvent {
     FilesRequested(TranscodeFormat, HashSet<PathBuf>),
+    /// A client moved some files to the front of its download queue.
+    FilesPrioritized(TranscodeFormat, HashSet<PathBuf>),
 
     TrustedNodesChanged,
     RecentServersChanged,
+    /// A share link's files were downloaded, or a link expired or was used up.
+    ShareLinksChanged,
+    /// Membership records of sync groups were received from another node.
+    SyncGroupsChanged,
+    /// A trusted node invited this node to sync groups it isn't a member of.
+    SyncGroupsInvited(Vec<sync_group::Invitation>),
+    /// An interrupted download was started again, so it isn't interrupted anymore.
+    InterruptedDownloadsChanged,
 
     ServerOpened {
         endpoint_id: EndpointId,
@@ -274,6 +1730,8 @@ enum NodeEvent {
     ServerClosed {
         endpoint_id: EndpointId,
         error: Option<String>,
+        /// Whether the connection was closed because it wasn't accepted in time.
+        expired: bool,
     },
 
     ClientOpened {
@@ -290,12 +1748,27 @@ enum NodeEvent {
     ClientClosed {
         endpoint_id: EndpointId,
         error: Option<String>,
+        /// Whether the connection was closed because the server didn't accept it in time.
+        expired: bool,
+    },
+
+    /// A trusted client asked us to rescan the library.
+    RescanRequested {
+        endpoint_id: EndpointId,
+    },
+
+    /// A trusted client asked us to connect back and download the files we lack.
+    SyncBackRequested {
+        endpoint_id: EndpointId,
+        conflict_policy: SyncConflictPolicy,
     },
 
     ServerTransferCompleted {
         endpoint_id: EndpointId,
         bytes: u64,
         is_first_transfer: bool,
+        /// Hash of the original file that was sent, if known.
+        source_hash: Option<ContentHash>,
     },
     ClientTransferCompleted {
         endpoint_id: EndpointId,
@@ -308,30 +1781,24 @@ enum NodeEvent {
 #[derive(Debug)]
 enum ServerModelUpdate {
     Accept,
-    UpdateConnectionInfo {
-        remote_addr: String,
-        rtt_ms: Option<u64>,
-    },
+    Drain,
+    UpdateConnectionInfo(ConnectionInfo),
     UpdateTransferJobs,
-    Close {
-        error: Option<String>,
-    },
+    Close { error: Option<String> },
 }
 
 /// An update to a client model.
 #[derive(Debug)]
 enum ClientModelUpdate {
     Accept,
-    UpdateConnectionInfo {
-        remote_addr: String,
-        rtt_ms: Option<u64>,
-    },
+    Drain,
+    UpdateConnectionInfo(ConnectionInfo),
     UpdateIndex,
     UpdateTransferJobs,
     UpdatePaused,
-    Close {
-        error: Option<String>,
-    },
+    UpdateInsufficientSpace(Option<InsufficientSpaceModel>),
+    UpdateStorageQuota(Option<StorageQuotaModel>),
+    Close { error: Option<String> },
 }
 
 /// An update to the node model.
@@ -341,8 +1808,19 @@ enum NodeModelUpdate {
     UpdateHomeRelay {
         home_relay: String,
     },
+    UpdateAcceptIncoming {
+        accept_incoming: bool,
+    },
     UpdateTrustedNodes,
     UpdateRecentServers,
+    UpdatePeerTraffic,
+    UpdateDownloadUsage,
+    UpdateShareLinks,
+    UpdateSyncGroups,
+    UpdateTransferHold,
+    UpdateInterruptedDownloads,
+    UpdatePartialCleanup(PartialCleanupModel),
+    UpdatePlaylistWrite(PlaylistWriteModel),
 
     CreateServer {
         endpoint_id: EndpointId,
@@ -353,6 +1831,9 @@ enum NodeModelUpdate {
         endpoint_id: EndpointId,
         update: ServerModelUpdate,
     },
+    RemoveServer {
+        endpoint_id: EndpointId,
+    },
 
     CreateClient {
         endpoint_id: EndpointId,
@@ -363,23 +1844,105 @@ enum NodeModelUpdate {
         endpoint_id: EndpointId,
         update: ClientModelUpdate,
     },
+    RemoveClient {
+        endpoint_id: EndpointId,
+    },
 }
 
 pub struct Node {
     event_handler: Arc<dyn EventHandler>,
-    db: Arc<Mutex<Database>>,
+    db: Arc<DatabasePool>,
+    /// The node's key, for signing sync group records.
+    secret_key: SecretKey,
 
     router: Router,
+    /// Whether to only connect to peers on the local network.
+    lan_only: bool,
 
     command_tx: mpsc::UnboundedSender<NodeCommand>,
     event_tx: mpsc::UnboundedSender<NodeEvent>,
 
     servers: Mutex<HashMap<EndpointId, ServerHandle>>,
     clients: Mutex<HashMap<EndpointId, ClientHandle>>,
+    /// Two-way syncs to start once a client connection opens, requested by the server.
+    pending_syncs: Mutex<HashMap<EndpointId, SyncConflictPolicy>>,
+    /// Invitations to sync groups by group ID, until the user accepts or declines them.
+    sync_group_invitations: Mutex<HashMap<GroupId, sync_group::Invitation>>,
+    /// When a client last made us rescan the library, to limit how often clients can do so.
+    last_remote_rescan: Mutex<Option<Instant>>,
+    /// Bytes sent to and received from each node since the app started.
+    session_traffic: Mutex<HashMap<EndpointId, (u64, u64)>>,
+    /// Unused one-time tokens from pairing tickets created by this node.
+    pairing_tokens: PairingTokens,
+    /// Share links by token, shared with the share server.
+    share_links: ShareLinks,
+    /// The running share server, if any.
+    share_server: Mutex<Option<ShareServer>>,
+    hash_cache: HashCache,
+    transcode_status_cache: TranscodeStatusCache,
+    /// The files sent in indexes, shared by all server connections.
+    index_cache: Arc<IndexCache>,
 
     download_directory: Arc<Mutex<Option<String>>>,
+    download_path_template: Arc<Mutex<Option<PathTemplate>>>,
+    playlist_settings: Mutex<PlaylistSettings>,
+    partial_file_policy: Mutex<PartialFilePolicy>,
+    /// Whether the partial files in the download directory were cleaned up since startup.
+    partials_cleaned: AtomicBool,
+    /// False while the partial files are being cleaned up, which holds new downloads back so their
+    /// partial files aren't mistaken for orphans.
+    partials_idle: watch::Sender<bool>,
+    collision_policy: Arc<Mutex<CollisionPolicy>>,
+    sync_downloads: Arc<AtomicBool>,
+    /// Whether to check that downloaded files still exist on disk before skipping them in a sync.
+    verify_downloads: Arc<AtomicBool>,
+    /// Whether to download files again when they changed on the server since they were downloaded.
+    redownload_changed: Arc<AtomicBool>,
+    /// Number of streams used to download each large file from servers that support it.
+    parallel_streams: Arc<AtomicU32>,
+    /// Size of the buffers that files are sent and received in, shared with servers and clients.
+    transfer_buffer_size: Arc<AtomicU32>,
+    max_concurrent_transfers: watch::Sender<u32>,
+    /// Download slots shared by all clients, to limit concurrent downloads across servers.
+    download_slots: Arc<DownloadSlots>,
+    /// Download rate limit shared by all clients.
+    download_bandwidth: Arc<BandwidthLimiter>,
+    /// Maximum download rate from the settings, which is lowered on metered networks.
+    max_download_rate: AtomicU64,
+    /// Holds queued downloads on all clients while the transfer schedule isn't met.
+    transfer_gate: Arc<TransferGate>,
+    /// Maximum number of files sent at once to each client, or 0 for no extra limit.
+    max_uploads_per_client: Arc<AtomicU32>,
+    /// Upload rate limit for each client, in bytes per second, or 0 for no limit.
+    max_upload_rate_per_client: Arc<AtomicU64>,
+    /// How long connections can wait to be accepted, or None to wait forever.
+    pending_timeout: PendingTimeout,
+    /// Whether incoming connections from nodes that aren't trusted are accepted, shared with the
+    /// protocol handler.
+    accept_incoming: Arc<AtomicBool>,
+    /// Whether the node is shutting down, so no new connections are opened or accepted. Shared
+    /// with the protocol handler.
+    shutting_down: Arc<AtomicBool>,
+    /// How long finished download sessions are kept in the transfer history, or None for forever.
+    transfer_history_retention: Mutex<Option<Duration>>,
+    /// IDs of the jobs of each client that were recorded in a finished download session. Jobs are
+    /// still listed after their session, so the next session only records the rest.
+    recorded_session_jobs: Mutex<HashMap<EndpointId, HashSet<u64>>>,
 
     model: Mutex<NodeModel>,
+    /// Version of the last change to the model, counted while holding the model lock.
+    model_version: AtomicU64,
+    /// Whether model changes are sent as diffs instead of full snapshots.
+    model_diffs: bool,
+    /// Whether the app is in the background, so background progress is sent instead of model
+    /// changes.
+    background: AtomicBool,
+    /// The last background progress sent, to only send it when it changes.
+    last_background_progress: Mutex<Option<BackgroundProgressEvent>>,
+    /// Recent transfer and connection errors, for the status summary.
+    recent_errors: RecentErrors,
+    /// Events that can finish operations awaited by the core.
+    operation_events: broadcast::Sender<OperationEvent>,
 
     #[cfg(feature = "test-hooks")]
     test_hooks: Arc<TestHooks>,
@@ -409,9 +1972,13 @@ impl Node {
     pub async fn new(
         event_handler: Arc<dyn EventHandler>,
         secret_key: SecretKey,
-        db: Arc<Mutex<Database>>,
+        relay_config: RelayConfig,
+        lan_only: bool,
+        bind_addrs: Vec<String>,
+        db: Arc<DatabasePool>,
         transcode_status_cache: TranscodeStatusCache,
         hash_cache: HashCache,
+        model_diffs: bool,
         #[cfg(feature = "test-hooks")] test_hooks: Arc<TestHooks>,
     ) -> anyhow::Result<(Arc<Self>, NodeRun)> {
         let (command_tx, command_rx) = mpsc::unbounded_channel();
@@ -421,27 +1988,81 @@ impl Node {
         let memory_lookup = iroh::address_lookup::memory::MemoryLookup::new();
 
         let endpoint = {
-            let builder = Endpoint::builder(N0).secret_key(secret_key);
+            let builder = Endpoint::builder(N0).secret_key(secret_key.clone());
+            // in LAN-only mode, don't use relays or publish our address to lookup services
+            let builder = if lan_only {
+                builder
+                    .relay_mode(RelayMode::Disabled)
+                    .clear_address_lookup()
+            } else {
+                builder.relay_mode(relay_config.relay_mode()?)
+            };
+            // bind to specific addresses instead of all interfaces on random ports, e.g. to use
+            // a single forwarded port
+            let mut builder = builder;
+            for addr in &bind_addrs {
+                let addr = addr
+                    .parse::<SocketAddr>()
+                    .with_context(|| format!("failed to parse bind address {addr:?}"))?;
+                builder = builder
+                    .bind_addr(addr)
+                    .with_context(|| format!("invalid bind address {addr}"))?;
+            }
             #[cfg(feature = "test-hooks")]
             let builder = builder.address_lookup(memory_lookup.clone());
             builder.bind().await?
         };
 
+        let max_concurrent_transfers = watch::Sender::new(DEFAULT_MAX_CONCURRENT_TRANSFERS);
+        let max_uploads_per_client = Arc::new(AtomicU32::new(0));
+        let max_upload_rate_per_client = Arc::new(AtomicU64::new(0));
+        let transfer_buffer_size = Arc::new(AtomicU32::new(DEFAULT_TRANSFER_BUFFER_SIZE));
+        let pending_timeout = PendingTimeout::default();
+        let pairing_tokens = PairingTokens::default();
+        let accept_incoming = Arc::new(AtomicBool::new(true));
+        let shutting_down = Arc::new(AtomicBool::new(false));
+
         let protocol = Protocol::new(
+            endpoint.id(),
             db.clone(),
             transcode_status_cache.clone(),
             hash_cache.clone(),
             event_tx.clone(),
+            max_concurrent_transfers.subscribe(),
+            max_uploads_per_client.clone(),
+            max_upload_rate_per_client.clone(),
+            transfer_buffer_size.clone(),
+            pending_timeout.clone(),
+            pairing_tokens.clone(),
+            accept_incoming.clone(),
+            shutting_down.clone(),
+            lan_only,
+            #[cfg(feature = "test-hooks")]
+            test_hooks.clone(),
         );
 
         let router = Router::builder(endpoint)
             .accept(Protocol::ALPN, protocol.clone())
             .spawn();
 
+        // clean up the partial files of downloads that didn't finish before the app last stopped
+        if let Err(e) = journal::recover(&db).await {
+            error!("Node::new: failed to recover download journal: {e:#}");
+        }
+
         let model = NodeModel {
             endpoint_id: router.endpoint().id().to_string(),
 
             home_relay: "none".to_string(), // TODO
+            relay_config,
+            lan_only,
+            accept_incoming: true,
+            bound_sockets: router
+                .endpoint()
+                .bound_sockets()
+                .into_iter()
+                .map(|addr| addr.to_string())
+                .collect(),
 
             send_ipv4: 0,
             send_ipv6: 0,
@@ -457,23 +2078,86 @@ impl Node {
 
             trusted_nodes: Default::default(),
             recent_servers: Vec::new(),
+            peer_traffic: Vec::new(),
+            download_usage: Vec::new(),
+
+            share_server_addr: None,
+            share_links: Vec::new(),
+
+            sync_groups: Vec::new(),
+            sync_group_invitations: Vec::new(),
+
+            transfer_schedule: TransferSchedule::default(),
+            transfer_hold_reasons: Vec::new(),
+            network_state: NetworkState::default(),
+            download_rate_limit: 0,
+
+            interrupted_downloads: Vec::new(),
+            partial_cleanup: None,
+            last_playlist_write: None,
         };
 
         let node = Arc::new(Self {
             event_handler,
             db,
+            secret_key,
 
             router,
+            lan_only,
 
             command_tx,
             event_tx,
 
             servers: Mutex::new(HashMap::new()),
             clients: Mutex::new(HashMap::new()),
+            pending_syncs: Mutex::new(HashMap::new()),
+            sync_group_invitations: Mutex::new(HashMap::new()),
+            last_remote_rescan: Mutex::new(None),
+            session_traffic: Mutex::new(HashMap::new()),
+            pairing_tokens,
+            share_links: ShareLinks::default(),
+            share_server: Mutex::new(None),
+            hash_cache,
+            transcode_status_cache,
+            index_cache: Arc::default(),
 
             download_directory: Arc::new(Mutex::new(None)),
+            download_path_template: Arc::new(Mutex::new(None)),
+            playlist_settings: Mutex::new(PlaylistSettings::default()),
+            partial_file_policy: Mutex::new(PartialFilePolicy::default()),
+            partials_cleaned: AtomicBool::new(false),
+            partials_idle: watch::Sender::new(true),
+            collision_policy: Arc::new(Mutex::new(CollisionPolicy::default())),
+            // on mobile, apps can be killed or lose power at any time, so sync by default
+            sync_downloads: Arc::new(AtomicBool::new(cfg!(any(
+                target_os = "android",
+                target_os = "ios"
+            )))),
+            verify_downloads: Arc::new(AtomicBool::new(false)),
+            redownload_changed: Arc::new(AtomicBool::new(false)),
+            parallel_streams: Arc::new(AtomicU32::new(1)),
+            transfer_buffer_size,
+            max_concurrent_transfers,
+            download_slots: Arc::new(DownloadSlots::new()),
+            download_bandwidth: Arc::new(BandwidthLimiter::new()),
+            max_download_rate: AtomicU64::new(0),
+            transfer_gate: Arc::new(TransferGate::new()),
+            max_uploads_per_client,
+            max_upload_rate_per_client,
+            pending_timeout,
+            accept_incoming,
+            shutting_down,
+            // set from the settings at startup
+            transfer_history_retention: Mutex::new(None),
+            recorded_session_jobs: Mutex::new(HashMap::new()),
 
             model: Mutex::new(model),
+            model_version: AtomicU64::new(0),
+            model_diffs,
+            background: AtomicBool::new(false),
+            last_background_progress: Mutex::new(None),
+            recent_errors: RecentErrors::default(),
+            operation_events: broadcast::Sender::new(OPERATION_EVENTS_CAPACITY),
 
             #[cfg(feature = "test-hooks")]
             test_hooks,
@@ -486,6 +2170,10 @@ impl Node {
         node.update_model(NodeModelUpdate::PollMetrics);
         node.update_model(NodeModelUpdate::UpdateTrustedNodes);
         node.update_model(NodeModelUpdate::UpdateRecentServers);
+        node.update_model(NodeModelUpdate::UpdatePeerTraffic);
+        node.update_model(NodeModelUpdate::UpdateDownloadUsage);
+        node.update_model(NodeModelUpdate::UpdateSyncGroups);
+        node.update_model(NodeModelUpdate::UpdateInterruptedDownloads);
 
         // spawn task to check downloaded remote files
         tokio::spawn({
@@ -504,6 +2192,15 @@ impl Node {
                 loop {
                     tokio::time::sleep(Duration::from_secs(1)).await;
                     node.update_model(NodeModelUpdate::PollMetrics);
+
+                    if remove_expired_links(&node.share_links) {
+                        node.update_model(NodeModelUpdate::UpdateShareLinks);
+                    }
+
+                    // the time window can open or close without any setting changing
+                    if node.transfer_gate.update(unix_epoch_now_secs()) {
+                        node.update_model(NodeModelUpdate::UpdateTransferHold);
+                    }
                 }
             }
         });
@@ -545,27 +2242,183 @@ impl Node {
 
         // Track launch
         {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
             let _ = db.track_launch();
         }
         self.push_stats_model();
 
+        let mut library_scans = library.subscribe_scans();
+        let mut startup_tags = library.subscribe_startup_tags();
+
         debug!("entering Node::run loop");
 
         loop {
             tokio::select! {
+                Ok(()) = library_scans.changed() => {
+                    // send connected clients the changes to their index
+                    let servers = self.servers.lock().unwrap();
+                    for server_handle in servers.values() {
+                        let _ = server_handle.tx.send(ServerCommand::RefreshIndex);
+                    }
+                }
+
+                Ok(()) = startup_tags.changed() => {
+                    // send clients that connected before the tags were loaded their tags
+                    let servers = self.servers.lock().unwrap();
+                    for server_handle in servers.values() {
+                        let _ = server_handle.tx.send(ServerCommand::RefreshIndex);
+                    }
+                }
+
                 Some(command) = command_rx.recv() => {
                     match command {
                         NodeCommand::SetDownloadDirectory(path) => {
-                            let mut download_directory = self.download_directory.lock().unwrap();
-                            *download_directory = Some(path);
+                            {
+                                let mut download_directory = self.download_directory.lock().unwrap();
+                                *download_directory = path.clone();
+                            }
+
+                            // clean up partial files when the directory is first set at startup.
+                            // downloads requested meanwhile wait for it in SetDownloads
+                            if let Some(path) = path
+                                && !self.partials_cleaned.swap(true, Ordering::Relaxed)
+                            {
+                                let policy = *self.partial_file_policy.lock().unwrap();
+                                self.partials_idle.send_replace(false);
+                                let node = self.clone();
+                                tokio::task::spawn(async move {
+                                    match journal::clean_orphans(&node.db, &path, policy).await {
+                                        Ok(partial_cleanup) => {
+                                            node.update_model(NodeModelUpdate::UpdatePartialCleanup(partial_cleanup));
+                                        }
+                                        Err(e) => {
+                                            error!("failed to clean up partial files: {e:#}");
+                                        }
+                                    }
+                                    node.partials_idle.send_replace(true);
+                                });
+                            }
+                        },
+                        NodeCommand::SetPartialFilePolicy(policy) => {
+                            let mut partial_file_policy = self.partial_file_policy.lock().unwrap();
+                            *partial_file_policy = policy;
+                        },
+                        NodeCommand::SetDownloadPathTemplate(template) => {
+                            let mut download_path_template = self.download_path_template.lock().unwrap();
+                            *download_path_template = template;
+                        },
+                        NodeCommand::SetPlaylistSettings(settings) => {
+                            let mut playlist_settings = self.playlist_settings.lock().unwrap();
+                            *playlist_settings = settings;
+                        },
+                        NodeCommand::SetCollisionPolicy(policy) => {
+                            let mut collision_policy = self.collision_policy.lock().unwrap();
+                            *collision_policy = policy;
+                        },
+                        NodeCommand::SetPendingTimeout(timeout) => {
+                            let mut pending_timeout = self.pending_timeout.lock().unwrap();
+                            *pending_timeout = timeout;
+                        },
+                        NodeCommand::SetAcceptIncoming(accept_incoming) => {
+                            self.accept_incoming.store(accept_incoming, Ordering::Relaxed);
+                            self.update_model(NodeModelUpdate::UpdateAcceptIncoming { accept_incoming });
+                        },
+
+                        NodeCommand::SetMaxConcurrentTransfers(max_concurrent_transfers) => {
+                            let max_concurrent_transfers = max_concurrent_transfers.clamp(1, MAX_CONCURRENT_TRANSFERS);
+                            self.max_concurrent_transfers.send_replace(max_concurrent_transfers);
+                        }
+                        NodeCommand::SetMaxTotalDownloads(max_total_downloads) => {
+                            self.download_slots.set_limit(max_total_downloads);
+                        }
+                        NodeCommand::SetMaxDownloadRate(max_download_rate) => {
+                            self.max_download_rate.store(max_download_rate, Ordering::Relaxed);
+                            self.update_network_restrictions();
+                        }
+                        NodeCommand::SetMaxUploadsPerClient(max_uploads_per_client) => {
+                            let max_uploads_per_client = max_uploads_per_client.min(MAX_CONCURRENT_TRANSFERS);
+                            self.max_uploads_per_client.store(max_uploads_per_client, Ordering::Relaxed);
+                        }
+                        NodeCommand::SetMaxUploadRatePerClient(max_upload_rate_per_client) => {
+                            self.max_upload_rate_per_client.store(max_upload_rate_per_client, Ordering::Relaxed);
+                        }
+                        NodeCommand::SetTransferSchedule(schedule) => {
+                            self.transfer_gate.set_schedule(schedule);
+                            self.transfer_gate.update(unix_epoch_now_secs());
+                            self.update_model(NodeModelUpdate::UpdateTransferHold);
+                        }
+                        NodeCommand::SetDeviceState(device) => {
+                            self.transfer_gate.set_device_state(device);
+                            self.update_network_restrictions();
+                        }
+                        NodeCommand::SetNetworkState(network) => {
+                            self.transfer_gate.set_network_state(network);
+                            self.update_network_restrictions();
+                        }
+                        NodeCommand::SetNetworkPolicy(policy) => {
+                            self.transfer_gate.set_network_policy(policy);
+                            self.update_network_restrictions();
+                        }
+                        NodeCommand::SetBackground(background) => {
+                            self.background.store(background, Ordering::Relaxed);
+
+                            let model = self.model.lock().unwrap();
+                            if background {
+                                // tell the shell right away whether transfers are active
+                                self.send_background_progress(&model);
+                            } else {
+                                *self.last_background_progress.lock().unwrap() = None;
+                                // catch the UI up on the changes that weren't sent, including
+                                // the ones to servers and clients
+                                self.send_model_change(&model, || NodeModelPatch::Snapshot {
+                                    model: model.clone(),
+                                });
+                            }
+                        }
+                        NodeCommand::SetTransferHistoryRetention(retention) => {
+                            *self.transfer_history_retention.lock().unwrap() = retention;
+                            if let Err(e) = self.prune_transfer_history() {
+                                warn!("failed to prune transfer history: {e:#}");
+                            }
+                        }
+                        NodeCommand::SetSyncDownloads(sync_downloads) => {
+                            self.sync_downloads.store(sync_downloads, Ordering::Relaxed);
+                        },
+                        NodeCommand::SetVerifyDownloads(verify_downloads) => {
+                            self.verify_downloads.store(verify_downloads, Ordering::Relaxed);
+                        },
+                        NodeCommand::SetRedownloadChanged(redownload_changed) => {
+                            self.redownload_changed.store(redownload_changed, Ordering::Relaxed);
+                        },
+                        NodeCommand::SetTransferBufferSize(transfer_buffer_size) => {
+                            self.transfer_buffer_size.store(
+                                transfer_buffer_size.clamp(MIN_TRANSFER_BUFFER_SIZE, MAX_TRANSFER_BUFFER_SIZE),
+                                Ordering::Relaxed,
+                            );
+                        },
+                        NodeCommand::SetParallelStreams(parallel_streams) => {
+                            self.parallel_streams.store(parallel_streams.clamp(1, MAX_PARALLEL_STREAMS), Ordering::Relaxed);
                         },
 
-                        NodeCommand::Connect { transcode_format, addr, callback } => {
+                        NodeCommand::ReplaceBookmark { stale, fresh } => {
+                            {
+                                let mut download_directory = self.download_directory.lock().unwrap();
+                                if download_directory.as_deref() == Some(stale.as_str()) {
+                                    *download_directory = Some(fresh.clone());
+                                }
+                            }
+
+                            let db = self.db.get();
+                            if let Err(e) = db.replace_local_tree(&stale, &fresh) {
+                                error!("failed to replace refreshed bookmark in database: {e:#}");
+                            }
+                        },
+
+                        NodeCommand::Connect { transcode_format, addr, pairing_token, callback } => {
                             let node = self.clone();
                             tokio::task::spawn(async move {
                                 debug!("starting connect");
-                                let res = node.connect(transcode_format, addr).await;
+                                let res = node.connect(transcode_format, addr, pairing_token).await;
                                 debug!("connect result: {res:?}");
                                 if let Err(e) = callback.send(res) {
                                     error!("failed to send res: {e:?}");
@@ -589,6 +2442,59 @@ impl Node {
                                 error!("DenyConnection: no server found with endpoint_id: {endpoint_id}");
                             }
                         },
+                        NodeCommand::PushFiles { server, items } => {
+                            let items = items.into_iter().filter_map(|item| {
+                                let Ok(endpoint_id) = item.endpoint_id.parse() else {
+                                    warn!("PushFiles: invalid endpoint ID");
+                                    return None;
+                                };
+                                Some(PushItem {
+                                    endpoint_id,
+                                    root: item.root,
+                                    path: item.path,
+                                })
+                            }).collect();
+
+                            let servers = self.servers.lock().unwrap();
+                            if let Some(server_handle) = servers.get(&server) {
+                                server_handle.tx.send(ServerCommand::Push(items)).expect("failed to send ServerCommand::Push");
+                            } else {
+                                error!("PushFiles: no server found with endpoint_id: {server}");
+                            }
+                        },
+                        NodeCommand::PushMissingFiles { server } => {
+                            let local_endpoint_id = self.router.endpoint().id();
+                            let files = {
+                                let db = self.db.get();
+                                get_delivered_files(&db, local_endpoint_id, server)
+                            };
+                            let items = match files {
+                                Ok(files) => files
+                                    .into_iter()
+                                    .filter(|(_, delivered)| !delivered)
+                                    .map(|(file, _)| PushItem {
+                                        endpoint_id: local_endpoint_id,
+                                        root: file.root,
+                                        path: file.path,
+                                    })
+                                    .collect::<Vec<_>>(),
+                                Err(e) => {
+                                    error!("PushMissingFiles: failed to get delivered files: {e:#}");
+                                    continue;
+                                }
+                            };
+                            if items.is_empty() {
+                                info!("PushMissingFiles: {server} already has all shared files");
+                                continue;
+                            }
+
+                            let servers = self.servers.lock().unwrap();
+                            if let Some(server_handle) = servers.get(&server) {
+                                server_handle.tx.send(ServerCommand::Push(items)).expect("failed to send ServerCommand::Push");
+                            } else {
+                                error!("PushMissingFiles: no server found with endpoint_id: {server}");
+                            }
+                        },
 
                         NodeCommand::CloseClient(endpoint_id) => {
                             let clients = self.clients.lock().unwrap();
@@ -606,6 +2512,33 @@ impl Node {
                                 error!("CloseServer: no server found with endpoint_id: {endpoint_id}");
                             }
                         },
+                        NodeCommand::DrainClient { endpoint_id, timeout } => {
+                            let clients = self.clients.lock().unwrap();
+                            if let Some(client_handle) = clients.get(&endpoint_id) {
+                                client_handle.tx.send(ClientCommand::Drain { timeout }).expect("failed to send ClientCommand::Drain");
+                            } else {
+                                error!("DrainClient: no client found with endpoint_id: {endpoint_id}");
+                            }
+                        }
+                        NodeCommand::DrainServer { endpoint_id, timeout } => {
+                            let servers = self.servers.lock().unwrap();
+                            if let Some(server_handle) = servers.get(&endpoint_id) {
+                                server_handle.tx.send(ServerCommand::Drain { timeout }).expect("failed to send ServerCommand::Drain");
+                            } else {
+                                error!("DrainServer: no server found with endpoint_id: {endpoint_id}");
+                            }
+                        },
+                        NodeCommand::DrainAll { timeout } => {
+                            self.shutting_down.store(true, Ordering::Relaxed);
+
+                            // connections that just closed are removed once their events are handled
+                            for client_handle in self.clients.lock().unwrap().values() {
+                                let _ = client_handle.tx.send(ClientCommand::Drain { timeout });
+                            }
+                            for server_handle in self.servers.lock().unwrap().values() {
+                                let _ = server_handle.tx.send(ServerCommand::Drain { timeout });
+                            }
+                        }
 
                         NodeCommand::RefreshClientIndex(endpoint_id) => {
                             self.update_model(NodeModelUpdate::UpdateClient {
@@ -614,21 +2547,39 @@ impl Node {
                             });
                         }
 
-                        NodeCommand::SetDownloads { client, items } => {
+                        NodeCommand::SetDownloads { client, items, callback } => {
                             // check that download directory is set before downloading
                             {
                                 let download_directory = self.download_directory.lock().unwrap();
                                 if download_directory.is_none() {
                                     error!("SetDownloads: download directory not set");
+                                    if let Some(callback) = callback {
+                                        let _ = callback.send(Err(TransferError::DownloadDirectoryNotSet.into()));
+                                    }
                                     continue;
                                 }
                             };
 
+                            // wait for the partial files to be cleaned up first
+                            let mut partials_idle = self.partials_idle.subscribe();
+                            if !*partials_idle.borrow() {
+                                let node = self.clone();
+                                tokio::task::spawn(async move {
+                                    if partials_idle.wait_for(|idle| *idle).await.is_ok() {
+                                        node.send_downloads_to_client(client, items, callback);
+                                    }
+                                });
+                                continue;
+                            }
+
+                            self.send_downloads_to_client(client, items, callback);
+                        }
+                        NodeCommand::PrioritizeDownloads { client, items } => {
                             let clients = self.clients.lock().unwrap();
                             if let Some(client_handle) = clients.get(&client) {
-                                client_handle.tx.send(ClientCommand::SetDownloads { items }).expect("failed to send ClientCommand::SetDownloads");
+                                client_handle.tx.send(ClientCommand::PrioritizeDownloads { items }).expect("failed to send ClientCommand::PrioritizeDownloads");
                             } else {
-                                error!("SetDownloads: no client found with endpoint_id: {client}");
+                                error!("PrioritizeDownloads: no client found with endpoint_id: {client}");
                             }
                         }
                         NodeCommand::PauseDownloads { client } => {
@@ -639,11 +2590,175 @@ impl Node {
                                 error!("PauseDownloads: no client found with endpoint_id: {client}");
                             }
                         }
+                        NodeCommand::PauseTransfer { client, job_id } => {
+                            let clients = self.clients.lock().unwrap();
+                            if let Some(client_handle) = clients.get(&client) {
+                                client_handle.tx.send(ClientCommand::PauseTransfer { job_id }).expect("failed to send ClientCommand::PauseTransfer");
+                            } else {
+                                error!("PauseTransfer: no client found with endpoint_id: {client}");
+                            }
+                        }
+                        NodeCommand::ResumeTransfer { client, job_id } => {
+                            let clients = self.clients.lock().unwrap();
+                            if let Some(client_handle) = clients.get(&client) {
+                                client_handle.tx.send(ClientCommand::ResumeTransfer { job_id }).expect("failed to send ClientCommand::ResumeTransfer");
+                            } else {
+                                error!("ResumeTransfer: no client found with endpoint_id: {client}");
+                            }
+                        }
+                        NodeCommand::CancelTransfer { client, job_id } => {
+                            let clients = self.clients.lock().unwrap();
+                            if let Some(client_handle) = clients.get(&client) {
+                                client_handle.tx.send(ClientCommand::CancelTransfer { job_id }).expect("failed to send ClientCommand::CancelTransfer");
+                            } else {
+                                error!("CancelTransfer: no client found with endpoint_id: {client}");
+                            }
+                        }
+                        NodeCommand::RequestRemoteRescan(client) => {
+                            let clients = self.clients.lock().unwrap();
+                            if let Some(client_handle) = clients.get(&client) {
+                                client_handle.tx.send(ClientCommand::RequestRescan).expect("failed to send ClientCommand::RequestRescan");
+                            } else {
+                                error!("RequestRemoteRescan: no client found with endpoint_id: {client}");
+                            }
+                        }
+                        NodeCommand::TwoWaySync { client, conflict_policy } => {
+                            let clients = self.clients.lock().unwrap();
+                            if let Some(client_handle) = clients.get(&client) {
+                                client_handle.tx.send(ClientCommand::Sync { conflict_policy, sync_back: true }).expect("failed to send ClientCommand::Sync");
+                            } else {
+                                error!("TwoWaySync: no client found with endpoint_id: {client}");
+                            }
+                        }
+                        NodeCommand::MirrorDownloads { client, dry_run, callback } => {
+                            let node = self.clone();
+                            tokio::task::spawn(async move {
+                                let res = node.mirror_downloads(client, dry_run).await;
+                                if let Err(e) = callback.send(res) {
+                                    error!("failed to send res: {e:?}");
+                                }
+                            });
+                        }
+                        NodeCommand::ResumeInterruptedDownloads { client } => {
+                            let items = {
+                                let db = self.db.get();
+                                match db.get_download_journal(true) {
+                                    Ok(entries) => entries
+                                        .into_iter()
+                                        .filter(|entry| entry.node_id == client)
+                                        .map(|entry| DownloadRequestModel {
+                                            endpoint_id: entry.file_node_id.to_string(),
+                                            root: entry.root,
+                                            path: entry.path,
+                                        })
+                                        .collect::<Vec<_>>(),
+                                    Err(e) => {
+                                        error!("ResumeInterruptedDownloads: failed to get interrupted downloads: {e:#}");
+                                        continue;
+                                    }
+                                }
+                            };
+
+                            {
+                                let clients = self.clients.lock().unwrap();
+                                let Some(client_handle) = clients.get(&client) else {
+                                    error!("ResumeInterruptedDownloads: no client found with endpoint_id: {client}");
+                                    continue;
+                                };
+                                // the downloads stay interrupted until they start, when they're
+                                // journaled again
+                                client_handle.tx.send(ClientCommand::SetDownloads { items, callback: None }).expect("failed to send ClientCommand::SetDownloads");
+                            }
+                        }
+                        NodeCommand::DiscardInterruptedDownloads { client, callback } => {
+                            let node = self.clone();
+                            tokio::task::spawn(async move {
+                                let res = journal::discard(&node.db, client).await;
+                                node.update_model(NodeModelUpdate::UpdateInterruptedDownloads);
+                                if let Err(e) = callback.send(res) {
+                                    error!("failed to send res: {e:?}");
+                                }
+                            });
+                        }
+                        NodeCommand::CheckDownloadedFiles { repair, callback } => {
+                            let node = self.clone();
+                            tokio::task::spawn(async move {
+                                let res = node.check_downloaded_files(repair).await;
+                                if let Err(e) = callback.send(res) {
+                                    error!("failed to send res: {e:?}");
+                                }
+                            });
+                        }
+                        NodeCommand::VerifyDownloads { client, requeue, callback } => {
+                            let node = self.clone();
+                            tokio::task::spawn(async move {
+                                let res = node.verify_downloads(client, requeue).await;
+                                if let Err(e) = callback.send(res) {
+                                    error!("failed to send res: {e:?}");
+                                }
+                            });
+                        }
+                        NodeCommand::ReorganizeDownloads { dry_run, callback } => {
+                            let node = self.clone();
+                            tokio::task::spawn(async move {
+                                let res = node.reorganize_downloads(dry_run).await;
+                                if let Err(e) = callback.send(res) {
+                                    error!("failed to send res: {e:?}");
+                                }
+                            });
+                        }
+                        NodeCommand::DeleteDownloadedFiles { client, selections, callback } => {
+                            let node = self.clone();
+                            tokio::task::spawn(async move {
+                                let res = downloads::delete(&node.db, node.endpoint_id(), client, &selections).await;
+                                node.update_model(NodeModelUpdate::UpdateDownloadUsage);
+                                // the deleted files show as not downloaded in the server's index
+                                if res.is_ok() && node.clients.lock().unwrap().contains_key(&client) {
+                                    node.update_model(NodeModelUpdate::UpdateClient {
+                                        endpoint_id: client,
+                                        update: ClientModelUpdate::UpdateIndex,
+                                    });
+                                }
+                                if let Err(e) = callback.send(res) {
+                                    error!("failed to send res: {e:?}");
+                                }
+                            });
+                        }
+                        NodeCommand::ChangedDownloads { client, requeue, callback } => {
+                            let node = self.clone();
+                            tokio::task::spawn(async move {
+                                let res = node.changed_downloads(client, requeue).await;
+                                if let Err(e) = callback.send(res) {
+                                    error!("failed to send res: {e:?}");
+                                }
+                            });
+                        }
+                        NodeCommand::ImportDownloads { client, folder, dry_run, callback } => {
+                            let node = self.clone();
+                            tokio::task::spawn(async move {
+                                let res = node.import_downloads(client, folder, dry_run).await;
+                                if let Err(e) = callback.send(res) {
+                                    error!("failed to send res: {e:?}");
+                                }
+                            });
+                        }
+                        NodeCommand::BrowseRemoteLibrary { client, callback } => {
+                            let res = self.browse_remote_library(client);
+                            if let Err(e) = callback.send(res) {
+                                error!("failed to send res: {e:?}");
+                            }
+                        }
+                        NodeCommand::ExpandDownloadSelection { client, selections, callback } => {
+                            let res = self.expand_download_selection(client, &selections);
+                            if let Err(e) = callback.send(res) {
+                                error!("failed to send res: {e:?}");
+                            }
+                        }
 
                         NodeCommand::TrustNode(endpoint_id) => {
                             // persist to database
                             {
-                                let db = self.db.lock().unwrap();
+                                let db = self.db.get();
                                 if let Err(e) = db.add_trusted_node(endpoint_id) {
                                     error!("failed to add trusted node to database: {e:#}");
                                 }
@@ -655,7 +2770,7 @@ impl Node {
                         NodeCommand::UntrustNode(endpoint_id) => {
                             // persist to database
                             {
-                                let db = self.db.lock().unwrap();
+                                let db = self.db.get();
                                 if let Err(e) = db.remove_trusted_node(endpoint_id) {
                                     error!("failed to remove trusted node from database: {e:#}");
                                 }
@@ -664,6 +2779,136 @@ impl Node {
                             // update model
                             self.update_model(NodeModelUpdate::UpdateTrustedNodes);
                         }
+                        NodeCommand::SetNodeShares { endpoint_id, shares } => {
+                            // persist to database
+                            {
+                                let shares = shares
+                                    .into_iter()
+                                    .map(|share| NodeShare {
+                                        root: share.root,
+                                        path_prefix: share.path_prefix,
+                                    })
+                                    .collect::<Vec<_>>();
+                                let mut db = self.db.get();
+                                if let Err(e) = db.set_node_shares(endpoint_id, &shares) {
+                                    error!("failed to set node shares in database: {e:#}");
+                                }
+                            }
+
+                            // update model
+                            self.update_model(NodeModelUpdate::UpdateTrustedNodes);
+
+                            // send a connected client the files it can now see or can't anymore
+                            let servers = self.servers.lock().unwrap();
+                            if let Some(server_handle) = servers.get(&endpoint_id) {
+                                let _ = server_handle.tx.send(ServerCommand::RefreshIndex);
+                            }
+                        }
+
+                        NodeCommand::SetShareFilter { endpoint_id, share_filter } => {
+                            // persist to database
+                            {
+                                let share_filter = share_filter.map(ShareFilter::from);
+                                let db = self.db.get();
+                                if let Err(e) = db.set_share_filter(endpoint_id, share_filter.as_ref()) {
+                                    error!("failed to set share filter in database: {e:#}");
+                                }
+                            }
+
+                            // update model
+                            self.update_model(NodeModelUpdate::UpdateTrustedNodes);
+
+                            // send a connected client the files it can now see or can't anymore
+                            let servers = self.servers.lock().unwrap();
+                            if let Some(server_handle) = servers.get(&endpoint_id) {
+                                let _ = server_handle.tx.send(ServerCommand::RefreshIndex);
+                            }
+                        }
+
+                        NodeCommand::SetNodeLabel { endpoint_id, label } => {
+                            // persist to database
+                            {
+                                let db = self.db.get();
+                                if let Err(e) = db.set_node_label(endpoint_id, label.as_deref()) {
+                                    error!("failed to set node label in database: {e:#}");
+                                }
+                            }
+
+                            // update model
+                            self.update_model(NodeModelUpdate::UpdateTrustedNodes);
+                            self.update_model(NodeModelUpdate::UpdateRecentServers);
+                        }
+
+                        NodeCommand::SetAutoDownload { endpoint_id, auto_download } => {
+                            let enabled = auto_download.is_some();
+
+                            // persist to database
+                            {
+                                let auto_download = auto_download.map(AutoDownload::from);
+                                let mut db = self.db.get();
+                                if let Err(e) = db.set_auto_download(endpoint_id, auto_download.as_ref()) {
+                                    error!("failed to set auto download in database: {e:#}");
+                                }
+                            }
+
+                            // start downloading on an existing connection
+                            if enabled {
+                                let clients = self.clients.lock().unwrap();
+                                if let Some(client_handle) = clients.get(&endpoint_id) {
+                                    client_handle.tx.send(ClientCommand::AutoDownload).expect("failed to send ClientCommand::AutoDownload");
+                                }
+                            }
+
+                            // update model
+                            self.update_model(NodeModelUpdate::UpdateTrustedNodes);
+                        }
+
+                        NodeCommand::SetStorageQuota { endpoint_id, max_bytes } => {
+                            // persist to database
+                            {
+                                let db = self.db.get();
+                                if let Err(e) = db.set_storage_quota(endpoint_id, max_bytes) {
+                                    error!("failed to set storage quota in database: {e:#}");
+                                }
+                            }
+
+                            // fill a raised quota on an existing connection
+                            {
+                                let clients = self.clients.lock().unwrap();
+                                if let Some(client_handle) = clients.get(&endpoint_id) {
+                                    client_handle.tx.send(ClientCommand::AutoDownload).expect("failed to send ClientCommand::AutoDownload");
+                                }
+                            }
+
+                            // update model
+                            self.update_model(NodeModelUpdate::UpdateTrustedNodes);
+                        }
+
+                        NodeCommand::SetDownloadFilter { endpoint_id, download_filter } => {
+                            // persist to database
+                            {
+                                let db = self.db.get();
+                                let download_filter = download_filter.map(DownloadFilter::from);
+                                if let Err(e) = db.set_download_filter(endpoint_id, download_filter.as_ref()) {
+                                    error!("failed to set download filter in database: {e:#}");
+                                }
+                            }
+
+                            // auto-download files that a loosened filter lets through
+                            {
+                                let clients = self.clients.lock().unwrap();
+                                if let Some(client_handle) = clients.get(&endpoint_id) {
+                                    client_handle.tx.send(ClientCommand::AutoDownload).expect("failed to send ClientCommand::AutoDownload");
+                                }
+                            }
+
+                            // update model
+                            self.update_model(NodeModelUpdate::UpdateTrustedNodes);
+                            self.update_model(NodeModelUpdate::UpdateClient {
+                                endpoint_id,
+                                update: ClientModelUpdate::UpdateIndex,
+                            });
+                        }
 
                         NodeCommand::RefreshModel => {
                             self.update_model(NodeModelUpdate::UpdateRecentServers);
@@ -711,6 +2956,24 @@ impl Node {
                             });
                         }
 
+                        NodeCommand::StartShareServer { bind_addr, public_url, callback } => {
+                            let node = self.clone();
+                            tokio::task::spawn(async move {
+                                let res = node.start_share_server(&bind_addr, public_url).await;
+                                if let Err(e) = callback.send(res) {
+                                    error!("failed to send res: {e:?}");
+                                }
+                            });
+                        }
+                        NodeCommand::StopShareServer => {
+                            self.share_server.lock().unwrap().take();
+                            self.update_model(NodeModelUpdate::UpdateShareLinks);
+                        }
+                        NodeCommand::RevokeShareLink(token) => {
+                            self.share_links.lock().unwrap().remove(&token);
+                            self.update_model(NodeModelUpdate::UpdateShareLinks);
+                        }
+
                         NodeCommand::Stop => break,
                     }
                 }
@@ -722,13 +2985,37 @@ impl Node {
                                 error!("NodeEvent::FilesRequested: failed to send to library: {e:#}");
                             }
                         }
+                        NodeEvent::FilesPrioritized(transcode_format, files) => {
+                            if let Err(e) = library.send(LibraryCommand::PrioritizeTranscodes(transcode_format, files)) {
+                                error!("NodeEvent::FilesPrioritized: failed to send to library: {e:#}");
+                            }
+                        }
 
                         NodeEvent::TrustedNodesChanged => {
                             self.update_model(NodeModelUpdate::UpdateTrustedNodes);
                         }
+                        NodeEvent::InterruptedDownloadsChanged => {
+                            self.update_model(NodeModelUpdate::UpdateInterruptedDownloads);
+                        }
                         NodeEvent::RecentServersChanged => {
                             self.update_model(NodeModelUpdate::UpdateRecentServers);
                         }
+                        NodeEvent::ShareLinksChanged => {
+                            self.update_model(NodeModelUpdate::UpdateShareLinks);
+                        }
+                        NodeEvent::SyncGroupsChanged => {
+                            self.update_model(NodeModelUpdate::UpdateSyncGroups);
+                        }
+                        NodeEvent::SyncGroupsInvited(invitations) => {
+                            {
+                                // a newer invitation to the same group replaces the old one
+                                let mut sync_group_invitations = self.sync_group_invitations.lock().unwrap();
+                                for invitation in invitations {
+                                    sync_group_invitations.insert(invitation.group_id, invitation);
+                                }
+                            }
+                            self.update_model(NodeModelUpdate::UpdateSyncGroups);
+                        }
 
                         NodeEvent::ServerOpened { endpoint_id, handle, name, connected_at } => {
                             {
@@ -743,16 +3030,27 @@ impl Node {
                             self.update_model(NodeModelUpdate::UpdateServer { endpoint_id, update });
                         }
 
-                        NodeEvent::ServerClosed { endpoint_id, error } => {
+                        NodeEvent::ServerClosed { endpoint_id, error, expired } => {
                             {
                                 let mut servers = self.servers.lock().unwrap();
                                 servers.remove(&endpoint_id);
                             }
 
                             self.update_model(NodeModelUpdate::UpdateServer { endpoint_id, update: ServerModelUpdate::Close { error } });
+
+                            // expired requests were never accepted, so there's nothing to keep
+                            if expired {
+                                self.update_model(NodeModelUpdate::RemoveServer { endpoint_id });
+                            }
                         }
 
                         NodeEvent::ClientOpened { endpoint_id, handle, name, connected_at } => {
+                            // start a sync requested by the server before the connection opened
+                            let pending_sync = self.pending_syncs.lock().unwrap().remove(&endpoint_id);
+                            if let Some(conflict_policy) = pending_sync {
+                                handle.tx.send(ClientCommand::Sync { conflict_policy, sync_back: false }).expect("failed to send ClientCommand::Sync");
+                            }
+
                             {
                                 let mut clients = self.clients.lock().unwrap();
                                 clients.insert(endpoint_id, handle);
@@ -765,35 +3063,103 @@ impl Node {
                             self.update_model(NodeModelUpdate::UpdateClient { endpoint_id, update });
                         }
 
-                        NodeEvent::ClientClosed { endpoint_id, error } => {
+                        NodeEvent::ClientClosed { endpoint_id, error, expired } => {
                             {
                                 let mut clients = self.clients.lock().unwrap();
                                 clients.remove(&endpoint_id);
                             }
 
                             self.update_model(NodeModelUpdate::UpdateClient { endpoint_id, update: ClientModelUpdate::Close { error } });
+
+                            // expired requests were never accepted, so there's nothing to keep
+                            if expired {
+                                self.update_model(NodeModelUpdate::RemoveClient { endpoint_id });
+                            }
+                        }
+
+                        NodeEvent::RescanRequested { endpoint_id } => {
+                            {
+                                let mut last_remote_rescan = self.last_remote_rescan.lock().unwrap();
+                                if last_remote_rescan.is_some_and(|last| last.elapsed() < REMOTE_RESCAN_INTERVAL) {
+                                    warn!("ignoring rescan request from {endpoint_id}, rescanned recently");
+                                    continue;
+                                }
+                                *last_remote_rescan = Some(Instant::now());
+                            }
+
+                            info!("rescan requested by {endpoint_id}");
+                            if let Err(e) = library.send(LibraryCommand::Rescan) {
+                                error!("NodeEvent::RescanRequested: failed to send to library: {e:#}");
+                            }
                         }
 
-                        NodeEvent::ServerTransferCompleted { endpoint_id, bytes, is_first_transfer } => {
+                        NodeEvent::SyncBackRequested { endpoint_id, conflict_policy } => {
+                            info!("two-way sync requested by {endpoint_id}");
+
+                            // sync on the existing connection if there is one
                             {
-                                let db = self.db.lock().unwrap();
+                                let clients = self.clients.lock().unwrap();
+                                if let Some(client_handle) = clients.get(&endpoint_id) {
+                                    client_handle.tx.send(ClientCommand::Sync { conflict_policy, sync_back: false }).expect("failed to send ClientCommand::Sync");
+                                    continue;
+                                }
+                            }
+
+                            // otherwise connect to the client, and sync once the connection opens
+                            self.pending_syncs.lock().unwrap().insert(endpoint_id, conflict_policy);
+                            let node = self.clone();
+                            tokio::task::spawn(async move {
+                                if let Err(e) = node.connect(None, EndpointAddr::from(endpoint_id), None).await {
+                                    error!("failed to connect for two-way sync: {e:#}");
+                                    node.pending_syncs.lock().unwrap().remove(&endpoint_id);
+                                }
+                            });
+                        }
+
+                        NodeEvent::ServerTransferCompleted { endpoint_id, bytes, is_first_transfer, source_hash } => {
+                            let delivered = {
+                                let db = self.db.get();
                                 let _ = db.track_server_transfer(1, bytes);
                                 if is_first_transfer {
                                     let _ = db.track_server_session();
                                 }
-                            }
+
+                                // remember which files trusted nodes have, to show how much of the
+                                // library they have and push only the rest
+                                match source_hash {
+                                    Some(source_hash) if db.is_node_trusted(endpoint_id).unwrap_or(false) => {
+                                        let res = db.insert_delivered_hash(
+                                            endpoint_id,
+                                            &source_hash.kind,
+                                            &source_hash.hash,
+                                            unix_epoch_now_secs(),
+                                        );
+                                        if let Err(e) = &res {
+                                            error!("failed to record delivered file: {e:#}");
+                                        }
+                                        res.is_ok()
+                                    }
+                                    _ => false,
+                                }
+                            };
                             self.push_stats_model();
+                            self.track_peer_traffic(endpoint_id, bytes, 0);
+                            if delivered {
+                                self.update_model(NodeModelUpdate::UpdateTrustedNodes);
+                            }
                         }
 
                         NodeEvent::ClientTransferCompleted { endpoint_id, bytes, is_first_transfer } => {
                             {
-                                let db = self.db.lock().unwrap();
+                                let db = self.db.get();
                                 let _ = db.track_client_transfer(1, bytes);
                                 if is_first_transfer {
                                     let _ = db.track_client_session();
                                 }
                             }
                             self.push_stats_model();
+                            self.track_peer_traffic(endpoint_id, 0, bytes);
+                            self.update_model(NodeModelUpdate::UpdateDownloadUsage);
                         }
                     }
                 }
@@ -817,35 +3183,268 @@ impl Node {
         model.clone()
     }
 
-    // TODO: throttle pushing updates?
-    fn update_model(self: &Arc<Self>, update: NodeModelUpdate) {
-        match update {
-            NodeModelUpdate::PollMetrics => {
-                let metrics = self.router.endpoint().metrics();
+    /// Gets the endpoint id of this node.
+    pub(crate) fn endpoint_id(&self) -> EndpointId {
+        self.router.endpoint().id()
+    }
 
-                let mut model = self.model.lock().unwrap();
-                model.send_ipv4 = metrics.socket.send_ipv4.get();
-                model.send_ipv6 = metrics.socket.send_ipv6.get();
-                model.send_relay = metrics.socket.send_relay.get();
-                model.recv_ipv4 = metrics.socket.recv_data_ipv4.get();
-                model.recv_ipv6 = metrics.socket.recv_data_ipv6.get();
-                model.recv_relay = metrics.socket.recv_data_relay.get();
-                model.conn_success = metrics.socket.num_conns_opened.get();
-                model.conn_direct = metrics.socket.num_conns_direct.get();
+    /// Gets the node's part of the status summary without copying the model.
+    pub(crate) fn get_status(&self) -> NodeStatus {
+        let model = self.model.lock().unwrap();
 
-                self.event_handler.on_node_model_snapshot(model.clone());
+        let mut status = NodeStatus {
+            endpoint_id: model.endpoint_id.clone(),
+            open_connections: 0,
+            pending_connections: 0,
+            active_downloads: 0,
+            queued_downloads: 0,
+            active_uploads: 0,
+            transfer_hold_reasons: model.transfer_hold_reasons.clone(),
+            recent_errors: self.recent_errors.list(),
+        };
+        for client in model.clients.values() {
+            match client.state {
+                ClientStateModel::Accepted => status.open_connections += 1,
+                ClientStateModel::Pending => status.pending_connections += 1,
+                ClientStateModel::Closed { .. } => {}
+            }
+            status.active_downloads += client.session.active_files;
+            status.queued_downloads += client.session.queued_files;
+        }
+        for server in model.servers.values() {
+            match server.state {
+                ServerStateModel::Accepted => status.open_connections += 1,
+                ServerStateModel::Pending => status.pending_connections += 1,
+                ServerStateModel::Closed { .. } => {}
+            }
+            status.active_uploads += server.session.active_files;
+        }
+
+        status
+    }
+
+    /// Gets the node's part of the metrics without copying the model.
+    pub(crate) fn get_metrics(&self) -> NodeMetrics {
+        let model = self.model.lock().unwrap();
+
+        let mut metrics = NodeMetrics {
+            send_ipv4: model.send_ipv4,
+            send_ipv6: model.send_ipv6,
+            send_relay: model.send_relay,
+            recv_ipv4: model.recv_ipv4,
+            recv_ipv6: model.recv_ipv6,
+            recv_relay: model.recv_relay,
+            ..Default::default()
+        };
+        for server in model.servers.values() {
+            match server.state {
+                ServerStateModel::Pending => metrics.incoming_pending += 1,
+                ServerStateModel::Accepted => metrics.incoming_accepted += 1,
+                ServerStateModel::Closed { .. } => {}
+            }
+        }
+        for client in model.clients.values() {
+            match client.state {
+                ClientStateModel::Pending => metrics.outgoing_pending += 1,
+                ClientStateModel::Accepted => metrics.outgoing_accepted += 1,
+                ClientStateModel::Closed { .. } => {}
+            }
+        }
+
+        metrics
+    }
+
+    /// Gets a snapshot of the model with its version, to resync after missing a diff.
+    pub fn get_versioned_model(self: &Arc<Self>) -> VersionedNodeModel {
+        let model = self.model.lock().unwrap();
+        VersionedNodeModel {
+            version: self.model_version.load(Ordering::SeqCst),
+            model: model.clone(),
+        }
+    }
+
+    /// Records failures for the status summary, broadcasts the events to awaited operations, and
+    /// sends them to the UI.
+    ///
+    /// Must be called after the model change they describe has been sent.
+    fn dispatch_transfer_events(&self, events: Vec<TransferEvent>) {
+        for event in events {
+            match &event {
+                TransferEvent::JobFailed(event) => {
+                    self.recent_errors.push(
+                        StatusErrorSource::Transfer,
+                        format!("failed to download {}: {}", event.file_path, event.error),
+                    );
+                    self.send_operation_event(OperationEvent::JobFailed(event.clone()));
+                }
+                TransferEvent::ConnectionLost(event) => self.recent_errors.push(
+                    StatusErrorSource::Connection,
+                    format!("lost connection to {}: {}", event.name, event.error),
+                ),
+                TransferEvent::SessionCompleted(event) => {
+                    self.send_operation_event(OperationEvent::SessionCompleted(event.clone()));
+                }
+            }
+            event.dispatch(&*self.event_handler);
+        }
+    }
+
+    /// Broadcasts an event to the operations awaited by the core, if there are any.
+    fn send_operation_event(&self, event: OperationEvent) {
+        // sending only fails if nothing is waiting
+        let _ = self.operation_events.send(event);
+    }
+
+    /// Subscribes to the events that can finish operations awaited by the core.
+    pub(crate) fn subscribe_operation_events(&self) -> broadcast::Receiver<OperationEvent> {
+        self.operation_events.subscribe()
+    }
+
+    /// Sends a change to the model as a diff if diffs are enabled, or as a full snapshot.
+    ///
+    /// Must be called while holding the model lock, so versions are sent in order.
+    fn send_model_change(&self, model: &NodeModel, patch: impl FnOnce() -> NodeModelPatch) {
+        let version = self.model_version.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.background.load(Ordering::Relaxed) {
+            // the UI isn't shown, so only the notification is kept up to date
+            self.send_background_progress(model);
+        } else if self.model_diffs {
+            self.event_handler.on_node_model_diff(NodeModelDiff {
+                version,
+                patch: patch(),
+            });
+        } else {
+            self.event_handler.on_node_model_snapshot(model.clone());
+        }
+    }
+
+    /// Applies the network state and the restrictions on metered networks to the download rate
+    /// limit and the held downloads, after any of them changes.
+    fn update_network_restrictions(self: &Arc<Self>) {
+        let max_download_rate = self.max_download_rate.load(Ordering::Relaxed);
+        self.download_bandwidth
+            .set_limit(self.transfer_gate.download_rate_limit(max_download_rate));
+
+        self.transfer_gate.update(unix_epoch_now_secs());
+        self.update_model(NodeModelUpdate::UpdateTransferHold);
+    }
+
+    /// Sends the background progress of the transfers in the model, if it changed since the last
+    /// time it was sent.
+    fn send_background_progress(&self, model: &NodeModel) {
+        let progress = BackgroundProgressEvent::from_model(model);
+
+        let mut last_progress = self.last_background_progress.lock().unwrap();
+        if last_progress.as_ref() != Some(&progress) {
+            *last_progress = Some(progress.clone());
+            self.event_handler.on_background_progress(progress);
+        }
+    }
+
+    /// Gets the combined progress of the downloads from all servers.
+    pub fn get_download_progress(self: &Arc<Self>) -> DownloadProgressModel {
+        let clients = self.clients.lock().unwrap();
+
+        let mut progress = DownloadProgressModel::default();
+        for client_handle in clients.values() {
+            add_download_progress(&mut progress, client_handle);
+        }
+
+        progress
+    }
+
+    /// Gets the state of the connection to a server and whether it's ready for downloads, or None
+    /// if there's no connection in the model.
+    pub(crate) fn get_client_state(&self, client: EndpointId) -> Option<(ClientStateModel, bool)> {
+        let model = self.model.lock().unwrap();
+        let client = model.clients.get(&client.to_string())?;
+        Some((client.state.clone(), client.is_ready()))
+    }
+
+    /// Gets the progress of the downloads from one server, or None if it isn't connected.
+    pub(crate) fn get_client_download_progress(
+        &self,
+        client: EndpointId,
+    ) -> Option<DownloadProgressModel> {
+        let clients = self.clients.lock().unwrap();
+        let client_handle = clients.get(&client)?;
+
+        let mut progress = DownloadProgressModel::default();
+        add_download_progress(&mut progress, client_handle);
+        Some(progress)
+    }
+
+    /// Gets the number of the given files' downloads from one server that haven't finished or
+    /// failed yet, and the result of the rest, or None if the server isn't connected.
+    pub(crate) fn get_client_jobs_result(
+        &self,
+        client: EndpointId,
+        keys: &HashSet<(String, String)>,
+    ) -> Option<(u32, DownloadResultModel)> {
+        let clients = self.clients.lock().unwrap();
+        let client_handle = clients.get(&client)?;
+
+        let mut unfinished = 0;
+        let mut result = DownloadResultModel::default();
+        for job in client_handle.jobs.iter() {
+            if !keys.contains(&(job.file_root.clone(), job.file_path.clone())) {
+                continue;
+            }
+            match &job.progress {
+                ClientTransferJobProgress::Finished { file_size, .. } => {
+                    result.completed_files += 1;
+                    result.transferred_bytes += file_size;
+                }
+                ClientTransferJobProgress::Failed { .. } => result.failed_files += 1,
+                _ => unfinished += 1,
+            }
+        }
+        Some((unfinished, result))
+    }
+
+    // TODO: throttle pushing updates?
+    fn update_model(self: &Arc<Self>, update: NodeModelUpdate) {
+        match update {
+            NodeModelUpdate::PollMetrics => {
+                let metrics = self.router.endpoint().metrics();
+
+                let mut model = self.model.lock().unwrap();
+                model.send_ipv4 = metrics.socket.send_ipv4.get();
+                model.send_ipv6 = metrics.socket.send_ipv6.get();
+                model.send_relay = metrics.socket.send_relay.get();
+                model.recv_ipv4 = metrics.socket.recv_data_ipv4.get();
+                model.recv_ipv6 = metrics.socket.recv_data_ipv6.get();
+                model.recv_relay = metrics.socket.recv_data_relay.get();
+                model.conn_success = metrics.socket.num_conns_opened.get();
+                model.conn_direct = metrics.socket.num_conns_direct.get();
+
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
             }
 
             NodeModelUpdate::UpdateHomeRelay { home_relay } => {
                 let mut model = self.model.lock().unwrap();
                 model.home_relay = home_relay;
 
-                self.event_handler.on_node_model_snapshot(model.clone());
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
+            }
+
+            NodeModelUpdate::UpdateAcceptIncoming { accept_incoming } => {
+                let mut model = self.model.lock().unwrap();
+                model.accept_incoming = accept_incoming;
+
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
             }
 
             NodeModelUpdate::UpdateTrustedNodes => {
+                let local_endpoint_id = self.router.endpoint().id();
                 let trusted_nodes = {
-                    let db = self.db.lock().unwrap();
+                    let db = self.db.get();
                     let trusted_nodes = match db.get_trusted_nodes() {
                         Ok(trusted_nodes) => trusted_nodes,
                         Err(e) => {
@@ -855,10 +3454,81 @@ impl Node {
                     };
                     trusted_nodes
                         .into_iter()
-                        .map(|node| TrustedNodeModel {
-                            endpoint_id: node.node_id.to_string(),
-                            name: node.name.unwrap_or_else(|| "Unknown".to_string()),
-                            connected_at: node.connected_at,
+                        .map(|node| {
+                            let shares = match db.get_node_shares(node.node_id) {
+                                Ok(shares) => shares,
+                                Err(e) => {
+                                    error!("failed to get node shares from database: {e:#}");
+                                    Vec::new()
+                                }
+                            };
+                            let share_filter = match db.get_share_filter(node.node_id) {
+                                Ok(share_filter) => share_filter,
+                                Err(e) => {
+                                    error!("failed to get share filter from database: {e:#}");
+                                    None
+                                }
+                            };
+                            let auto_download = match db.get_auto_download(node.node_id) {
+                                Ok(auto_download) => auto_download,
+                                Err(e) => {
+                                    error!("failed to get auto download from database: {e:#}");
+                                    None
+                                }
+                            };
+                            let storage_quota = match db.get_storage_quota(node.node_id) {
+                                Ok(storage_quota) => storage_quota,
+                                Err(e) => {
+                                    error!("failed to get storage quota from database: {e:#}");
+                                    None
+                                }
+                            };
+                            let download_filter = match db.get_download_filter(node.node_id) {
+                                Ok(download_filter) => download_filter,
+                                Err(e) => {
+                                    error!("failed to get download filter from database: {e:#}");
+                                    None
+                                }
+                            };
+                            let library_coverage =
+                                match get_delivered_files(&db, local_endpoint_id, node.node_id) {
+                                    Ok(files) => Some(LibraryCoverageModel {
+                                        delivered_files: files
+                                            .iter()
+                                            .filter(|(_, delivered)| *delivered)
+                                            .count()
+                                            as u64,
+                                        library_files: files.len() as u64,
+                                    }),
+                                    Err(e) => {
+                                        error!(
+                                            "failed to get delivered files from database: {e:#}"
+                                        );
+                                        None
+                                    }
+                                };
+                            TrustedNodeModel {
+                                endpoint_id: node.node_id.to_string(),
+                                name: node
+                                    .label
+                                    .clone()
+                                    .or(node.name)
+                                    .unwrap_or_else(|| "Unknown".to_string()),
+                                label: node.label,
+                                connected_at: node.connected_at,
+                                shares: shares
+                                    .into_iter()
+                                    .map(|share| NodeShareModel {
+                                        root: share.root,
+                                        path_prefix: share.path_prefix,
+                                    })
+                                    .collect(),
+                                share_filter: share_filter.map(ShareFilterModel::from),
+                                auto_download: auto_download.map(AutoDownloadModel::from),
+                                storage_quota,
+                                download_filter: download_filter.map(DownloadFilterModel::from),
+                                library_coverage,
+                            }
                         })
                         .collect()
                 };
@@ -866,18 +3536,266 @@ impl Node {
                 let mut model = self.model.lock().unwrap();
                 model.trusted_nodes = trusted_nodes;
 
-                self.event_handler.on_node_model_snapshot(model.clone());
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
+            }
+
+            NodeModelUpdate::UpdateDownloadUsage => {
+                let download_usage = {
+                    let db = self.db.get();
+                    match db.get_download_usage(self.router.endpoint().id()) {
+                        Ok(download_usage) => download_usage,
+                        Err(e) => {
+                            error!("failed to get download usage from database: {e:#}");
+                            return;
+                        }
+                    }
+                };
+                let download_usage = download_usage
+                    .into_iter()
+                    .map(|usage| DownloadUsageModel {
+                        endpoint_id: usage.node_id.to_string(),
+                        files: usage.files,
+                        bytes: usage.bytes,
+                    })
+                    .collect::<Vec<_>>();
+
+                let mut model = self.model.lock().unwrap();
+                if model.download_usage == download_usage {
+                    return;
+                }
+                model.download_usage = download_usage;
+
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
+            }
+
+            NodeModelUpdate::UpdatePeerTraffic => {
+                let peer_traffic = {
+                    let db = self.db.get();
+                    match db.get_peer_traffic() {
+                        Ok(peer_traffic) => peer_traffic,
+                        Err(e) => {
+                            error!("failed to get peer traffic from database: {e:#}");
+                            return;
+                        }
+                    }
+                };
+                let session_traffic = self.session_traffic.lock().unwrap();
+                let peer_traffic = peer_traffic
+                    .into_iter()
+                    .map(|traffic| {
+                        let (session_sent_bytes, session_received_bytes) = session_traffic
+                            .get(&traffic.node_id)
+                            .copied()
+                            .unwrap_or_default();
+                        PeerTrafficModel {
+                            endpoint_id: traffic.node_id.to_string(),
+                            session_sent_bytes,
+                            session_received_bytes,
+                            lifetime_sent_bytes: traffic.sent_bytes,
+                            lifetime_received_bytes: traffic.received_bytes,
+                        }
+                    })
+                    .collect();
+                drop(session_traffic);
+
+                let mut model = self.model.lock().unwrap();
+                model.peer_traffic = peer_traffic;
+
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
+            }
+
+            NodeModelUpdate::UpdateSyncGroups => {
+                let sync_groups = {
+                    let db = self.db.get();
+                    let groups = match db.get_sync_groups() {
+                        Ok(groups) => groups,
+                        Err(e) => {
+                            error!("failed to get sync groups from database: {e:#}");
+                            return;
+                        }
+                    };
+                    groups
+                        .into_iter()
+                        .map(|group| {
+                            let members = match db.get_group_records(group.group_id) {
+                                Ok(records) => records
+                                    .into_iter()
+                                    .map(|record| record.member.to_string())
+                                    .collect(),
+                                Err(e) => {
+                                    error!("failed to get sync group records from database: {e:#}");
+                                    Vec::new()
+                                }
+                            };
+                            SyncGroupModel {
+                                group_id: hex::encode(group.group_id),
+                                name: group.name,
+                                founder: group.founder.to_string(),
+                                members,
+                            }
+                        })
+                        .collect()
+                };
+
+                let sync_group_invitations = {
+                    let mut invitations = self.sync_group_invitations.lock().unwrap();
+                    // drop invitations to groups that were joined another way
+                    invitations.retain(|group_id, _| {
+                        !sync_groups
+                            .iter()
+                            .any(|group: &SyncGroupModel| group.group_id == hex::encode(group_id))
+                    });
+                    invitations
+                        .values()
+                        .map(|invitation| SyncGroupInvitationModel {
+                            group_id: hex::encode(invitation.group_id),
+                            name: invitation.group_name.clone(),
+                            founder: invitation.founder.to_string(),
+                            inviter: invitation.inviter.to_string(),
+                            members: invitation
+                                .records
+                                .iter()
+                                .map(|record| record.member.to_string())
+                                .collect(),
+                        })
+                        .collect()
+                };
+
+                let mut model = self.model.lock().unwrap();
+                model.sync_groups = sync_groups;
+                model.sync_group_invitations = sync_group_invitations;
+
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
+            }
+
+            NodeModelUpdate::UpdateTransferHold => {
+                let mut model = self.model.lock().unwrap();
+                model.transfer_schedule = self.transfer_gate.schedule();
+                model.transfer_hold_reasons = self.transfer_gate.reasons();
+                model.network_state = self.transfer_gate.network();
+                model.download_rate_limit = self.download_bandwidth.limit();
+
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
+                drop(model);
+
+                // the jobs show whether they're held
+                let clients = self
+                    .clients
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .copied()
+                    .collect::<Vec<_>>();
+                for endpoint_id in clients {
+                    self.update_model(NodeModelUpdate::UpdateClient {
+                        endpoint_id,
+                        update: ClientModelUpdate::UpdateTransferJobs,
+                    });
+                }
+            }
+
+            NodeModelUpdate::UpdateInterruptedDownloads => {
+                let interrupted_downloads = {
+                    let db = self.db.get();
+                    match db.get_download_journal(true) {
+                        Ok(entries) => entries
+                            .into_iter()
+                            .map(InterruptedDownloadModel::from)
+                            .collect(),
+                        Err(e) => {
+                            error!("failed to get interrupted downloads from database: {e:#}");
+                            return;
+                        }
+                    }
+                };
+
+                let mut model = self.model.lock().unwrap();
+                model.interrupted_downloads = interrupted_downloads;
+
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
+            }
+
+            NodeModelUpdate::UpdatePartialCleanup(partial_cleanup) => {
+                let mut model = self.model.lock().unwrap();
+                model.partial_cleanup = Some(partial_cleanup);
+
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
+            }
+
+            NodeModelUpdate::UpdatePlaylistWrite(playlist_write) => {
+                let mut model = self.model.lock().unwrap();
+                model.last_playlist_write = Some(playlist_write);
+
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
+            }
+
+            NodeModelUpdate::UpdateShareLinks => {
+                remove_expired_links(&self.share_links);
+
+                let (share_server_addr, share_links) = {
+                    let share_server = self.share_server.lock().unwrap();
+                    let share_links = self.share_links.lock().unwrap();
+                    let share_links = share_links
+                        .iter()
+                        .sorted_by_key(|(_, link)| std::cmp::Reverse(link.created_at))
+                        .map(|(token, link)| ShareLinkModel {
+                            token: token.clone(),
+                            url: share_server
+                                .as_ref()
+                                .map(|server| server.link_url(token))
+                                .unwrap_or_default(),
+                            name: link.name.clone(),
+                            transcode_format: link.transcode_format,
+                            file_count: link.files.len() as u64,
+                            downloaded_files: link.downloaded.len() as u64,
+                            created_at: link.created_at,
+                            expires_at: link.expires_at,
+                            one_time: link.one_time,
+                        })
+                        .collect();
+                    (
+                        share_server
+                            .as_ref()
+                            .map(|server| server.local_addr.to_string()),
+                        share_links,
+                    )
+                };
+
+                let mut model = self.model.lock().unwrap();
+                model.share_server_addr = share_server_addr;
+                model.share_links = share_links;
+
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
             }
 
             NodeModelUpdate::UpdateRecentServers => {
                 let recent_servers = {
-                    let db = self.db.lock().unwrap();
+                    let db = self.db.get();
                     match db.get_recent_servers() {
                         Ok(recent_servers) => recent_servers
                             .into_iter()
                             .map(|node| RecentServerModel {
                                 endpoint_id: node.node_id.to_string(),
-                                name: node.name,
+                                name: node.label.clone().unwrap_or(node.name),
+                                label: node.label,
                                 connected_at: node.connected_at,
                             })
                             .collect(),
@@ -891,7 +3809,9 @@ impl Node {
                 let mut model = self.model.lock().unwrap();
                 model.recent_servers = recent_servers;
 
-                self.event_handler.on_node_model_snapshot(model.clone());
+                self.send_model_change(&model, || NodeModelPatch::Node {
+                    model: model.without_connections(),
+                });
             }
 
             NodeModelUpdate::CreateServer {
@@ -899,6 +3819,8 @@ impl Node {
                 name,
                 connected_at,
             } => {
+                let verification_phrase =
+                    verification_phrase(self.router.endpoint().id(), endpoint_id);
                 let endpoint_id = endpoint_id.to_string();
 
                 let mut model = self.model.lock().unwrap();
@@ -906,19 +3828,35 @@ impl Node {
                     endpoint_id.clone(),
                     ServerModel {
                         name,
-                        endpoint_id,
+                        endpoint_id: endpoint_id.clone(),
                         connected_at,
 
                         state: ServerStateModel::Pending,
+                        draining: false,
 
                         connection_type: "unknown".to_string(),
                         latency_ms: None,
+                        remote_addr: String::new(),
+
+                        verification_phrase,
 
-                        transfer_jobs: Vec::new(),
+                        transfer_jobs: TransferJobsModel::default(),
+                        session: SessionProgressModel::default(),
                     },
                 );
 
-                self.event_handler.on_node_model_snapshot(model.clone());
+                self.send_model_change(&model, || NodeModelPatch::ServerUpdated {
+                    server: model.servers[&endpoint_id].clone(),
+                });
+            }
+
+            NodeModelUpdate::RemoveServer { endpoint_id } => {
+                let mut model = self.model.lock().unwrap();
+                model.servers.remove(&endpoint_id.to_string());
+
+                self.send_model_change(&model, || NodeModelPatch::ServerRemoved {
+                    endpoint_id: endpoint_id.to_string(),
+                });
             }
 
             NodeModelUpdate::UpdateServer {
@@ -927,6 +3865,8 @@ impl Node {
             } => {
                 let endpoint_id_string = endpoint_id.to_string();
 
+                let mut events = Vec::new();
+
                 let mut model = self.model.lock().unwrap();
                 let Some(server) = model.servers.get_mut(&endpoint_id_string) else {
                     warn!(
@@ -942,12 +3882,13 @@ impl Node {
                     ServerModelUpdate::Accept => {
                         server.state = ServerStateModel::Accepted;
                     }
-                    ServerModelUpdate::UpdateConnectionInfo {
-                        remote_addr,
-                        rtt_ms,
-                    } => {
-                        server.connection_type = remote_addr;
-                        server.latency_ms = rtt_ms;
+                    ServerModelUpdate::Drain => {
+                        server.draining = true;
+                    }
+                    ServerModelUpdate::UpdateConnectionInfo(info) => {
+                        server.connection_type = info.connection_type;
+                        server.latency_ms = info.rtt_ms;
+                        server.remote_addr = info.remote_addr;
                     }
                     ServerModelUpdate::UpdateTransferJobs => {
                         let server_handles = self.servers.lock().unwrap();
@@ -971,14 +3912,16 @@ impl Node {
                                         (TransferJobProgressModel::Transcoding, None)
                                     }
 
-                                    ServerTransferJobProgress::Ready { file_size, .. } => {
-                                        (TransferJobProgressModel::Ready, Some(*file_size))
-                                    }
+                                    ServerTransferJobProgress::Ready { file_size, .. }
+                                    | ServerTransferJobProgress::ReadyDocument {
+                                        file_size, ..
+                                    } => (TransferJobProgressModel::Ready, Some(*file_size)),
 
                                     ServerTransferJobProgress::InProgress {
                                         started_at,
                                         file_size,
                                         sent,
+                                        ..
                                     } => (
                                         TransferJobProgressModel::InProgress {
                                             started_at: *started_at,
@@ -997,9 +3940,11 @@ impl Node {
                                         Some(*file_size),
                                     ),
 
-                                    ServerTransferJobProgress::Failed { error } => (
+                                    ServerTransferJobProgress::Failed { error, reason } => (
                                         TransferJobProgressModel::Failed {
                                             error: format!("{error:#}"),
+                                            reason: *reason,
+                                            retryable: reason.is_retryable(),
                                         },
                                         None,
                                     ),
@@ -1011,18 +3956,34 @@ impl Node {
                                     file_path: job.file_path.clone(),
                                     file_size,
                                     progress,
+                                    collision: None,
+                                    held: false,
                                 }
                             })
-                            .collect();
+                            .collect::<TransferJobsModel>();
 
+                        server.session = SessionProgressModel::from_jobs(&transfer_jobs);
                         server.transfer_jobs = transfer_jobs;
                     }
                     ServerModelUpdate::Close { error } => {
+                        if let Some(error) = &error {
+                            events.push(TransferEvent::ConnectionLost(ConnectionLostEvent {
+                                endpoint_id: server.endpoint_id.clone(),
+                                name: server.name.clone(),
+                                is_client: false,
+                                error: error.clone(),
+                                unfinished_files: 0,
+                            }));
+                        }
                         server.state = ServerStateModel::Closed { error };
                     }
                 }
 
-                self.event_handler.on_node_model_snapshot(model.clone());
+                self.send_model_change(&model, || NodeModelPatch::ServerUpdated {
+                    server: model.servers[&endpoint_id_string].clone(),
+                });
+                drop(model);
+                self.dispatch_transfer_events(events);
             }
 
             NodeModelUpdate::CreateClient {
@@ -1030,6 +3991,8 @@ impl Node {
                 name,
                 connected_at,
             } => {
+                let verification_phrase =
+                    verification_phrase(self.router.endpoint().id(), endpoint_id);
                 let endpoint_id = endpoint_id.to_string();
 
                 let mut model = self.model.lock().unwrap();
@@ -1037,21 +4000,41 @@ impl Node {
                     endpoint_id.clone(),
                     ClientModel {
                         name,
-                        endpoint_id,
+                        endpoint_id: endpoint_id.clone(),
                         connected_at,
 
                         state: ClientStateModel::Pending,
+                        draining: false,
 
                         connection_type: "unknown".to_string(),
                         latency_ms: None,
+                        remote_addr: String::new(),
+
+                        verification_phrase,
 
                         index: None,
-                        transfer_jobs: Vec::new(),
+                        index_complete: false,
+                        transfer_jobs: TransferJobsModel::default(),
+                        session: SessionProgressModel::default(),
                         paused: false,
+                        insufficient_space: None,
+                        storage_quota: None,
+                        conflicts: Vec::new(),
                     },
                 );
 
-                self.event_handler.on_node_model_snapshot(model.clone());
+                self.send_model_change(&model, || NodeModelPatch::ClientUpdated {
+                    client: model.clients[&endpoint_id].without_index(),
+                });
+            }
+
+            NodeModelUpdate::RemoveClient { endpoint_id } => {
+                let mut model = self.model.lock().unwrap();
+                model.clients.remove(&endpoint_id.to_string());
+
+                self.send_model_change(&model, || NodeModelPatch::ClientRemoved {
+                    endpoint_id: endpoint_id.to_string(),
+                });
             }
 
             NodeModelUpdate::UpdateClient {
@@ -1060,22 +4043,30 @@ impl Node {
             } => {
                 let endpoint_id_string = endpoint_id.to_string();
 
+                let mut events = Vec::new();
+                let mut finished_sessions = Vec::new();
+
                 let mut model = self.model.lock().unwrap();
                 let Some(client) = model.clients.get_mut(&endpoint_id_string) else {
                     warn!("failed to apply NodeModelUpdate::UpdateClient: no client model found");
                     return;
                 };
 
+                let index_updated = matches!(update, ClientModelUpdate::UpdateIndex);
+                let mut jobs_changes = None;
+                let was_ready = client.is_ready();
+                let mut operation_event = None;
                 match update {
                     ClientModelUpdate::Accept => {
                         client.state = ClientStateModel::Accepted;
                     }
-                    ClientModelUpdate::UpdateConnectionInfo {
-                        remote_addr,
-                        rtt_ms,
-                    } => {
-                        client.connection_type = remote_addr;
-                        client.latency_ms = rtt_ms;
+                    ClientModelUpdate::Drain => {
+                        client.draining = true;
+                    }
+                    ClientModelUpdate::UpdateConnectionInfo(info) => {
+                        client.connection_type = info.connection_type;
+                        client.latency_ms = info.rtt_ms;
+                        client.remote_addr = info.remote_addr;
                     }
                     ClientModelUpdate::UpdateIndex => {
                         let client_handles = self.clients.lock().unwrap();
@@ -1092,22 +4083,40 @@ impl Node {
                         };
 
                         let index = client_handle.index.lock().unwrap().as_ref().cloned();
+                        client.index_complete =
+                            index.is_some() && client_handle.index_complete.load(Ordering::Acquire);
+                        let index_hashes = client_handle.index_hashes.lock().unwrap().clone();
+                        let index_metadata = client_handle.index_metadata.lock().unwrap().clone();
                         if let Some(index) = index {
-                            let db = self.db.lock().unwrap();
+                            let db = self.db.get();
+                            let download_filter = match db.get_download_filter(endpoint_id) {
+                                Ok(download_filter) => download_filter,
+                                Err(e) => {
+                                    error!("failed to get download filter from database: {e:#}");
+                                    None
+                                }
+                            };
 
                             let index = index
                                 .into_iter()
                                 .map(|item| {
-                                    // check if file is downloaded to the current download directory
+                                    // check if file is downloaded to the current download directory and unchanged
+                                    let content_hash = index_hashes.get(&(
+                                        item.endpoint_id,
+                                        item.root.clone(),
+                                        item.path.clone(),
+                                    ));
                                     let file_exists = download_directory.as_ref().is_some_and(
                                         |download_directory| {
-                                            db.exists_file_by_node_root_path_localtree(
+                                            find_unchanged_download(
+                                                &db,
                                                 endpoint_id,
                                                 &item.root,
                                                 &item.path,
                                                 download_directory,
+                                                content_hash,
                                             )
-                                            .unwrap_or(false)
+                                            .is_some()
                                         },
                                     );
 
@@ -1115,6 +4124,22 @@ impl Node {
                                     let download_status = if file_exists {
                                         Some(IndexItemDownloadStatusModel::Downloaded)
                                     } else {
+                                        // a finished job may predate a change on the server, so
+                                        // check the recorded download's content hash first
+                                        let changed = content_hash
+                                            .zip(download_directory.as_ref())
+                                            .and_then(|(content_hash, download_directory)| {
+                                                find_changed_download(
+                                                    &db,
+                                                    endpoint_id,
+                                                    &item.root,
+                                                    &item.path,
+                                                    download_directory,
+                                                    content_hash,
+                                                )
+                                            })
+                                            .is_some();
+
                                         // check if there's an active transfer job for this file
                                         let transfer_job =
                                             client.transfer_jobs.iter().find(|job| {
@@ -1126,7 +4151,8 @@ impl Node {
                                             Some(job) => match &job.progress {
                                                 TransferJobProgressModel::Requested
                                                 | TransferJobProgressModel::Transcoding
-                                                | TransferJobProgressModel::Ready => {
+                                                | TransferJobProgressModel::Ready
+                                                | TransferJobProgressModel::Paused { .. } => {
                                                     Some(IndexItemDownloadStatusModel::Waiting)
                                                 }
                                                 TransferJobProgressModel::InProgress { .. } => {
@@ -1135,14 +4161,39 @@ impl Node {
                                                 TransferJobProgressModel::Failed { .. } => {
                                                     Some(IndexItemDownloadStatusModel::Failed)
                                                 }
+                                                TransferJobProgressModel::Finished { .. }
+                                                    if changed =>
+                                                {
+                                                    Some(IndexItemDownloadStatusModel::Changed)
+                                                }
                                                 TransferJobProgressModel::Finished { .. } => {
                                                     Some(IndexItemDownloadStatusModel::Downloaded)
                                                 }
                                             },
-                                            None => None,
+                                            None => changed
+                                                .then_some(IndexItemDownloadStatusModel::Changed),
                                         }
                                     };
 
+                                    let metadata = index_metadata
+                                        .get(&(
+                                            item.endpoint_id,
+                                            item.root.clone(),
+                                            item.path.clone(),
+                                        ))
+                                        .cloned()
+                                        .unwrap_or_default();
+
+                                    let filtered =
+                                        download_filter.as_ref().is_some_and(|download_filter| {
+                                            let file_size = match item.file_size {
+                                                FileSize::Unknown => None,
+                                                FileSize::Estimated(size)
+                                                | FileSize::Actual(size) => Some(size),
+                                            };
+                                            !download_filter.allows(file_size, metadata.duration)
+                                        });
+
                                     IndexItemModel {
                                         endpoint_id: endpoint_id.to_string(),
                                         root: item.root,
@@ -1155,6 +4206,14 @@ impl Node {
                                         },
 
                                         download_status,
+
+                                        title: metadata.title,
+                                        artist: metadata.artist,
+                                        album: metadata.album,
+                                        track_number: metadata.track_number,
+                                        duration: metadata.duration,
+
+                                        filtered,
                                     }
                                 })
                                 .collect();
@@ -1174,6 +4233,7 @@ impl Node {
                             return;
                         };
 
+                        let is_held = !self.transfer_gate.reasons().is_empty();
                         let transfer_jobs = client_handle
                             .jobs
                             .iter()
@@ -1193,77 +4253,446 @@ impl Node {
                                     ClientTransferJobProgress::Failed { .. } => None,
                                 };
 
-                                let progress = match &job.progress {
-                                    ClientTransferJobProgress::Requested => {
-                                        TransferJobProgressModel::Requested
-                                    }
-                                    ClientTransferJobProgress::Transcoding => {
-                                        TransferJobProgressModel::Transcoding
-                                    }
-                                    ClientTransferJobProgress::Ready { .. } => {
-                                        TransferJobProgressModel::Ready
-                                    }
+                                let is_paused = *job.control.borrow() == JobControl::Pause;
+                                let progress =
+                                    match &job.progress {
+                                        // Paused jobs are shown as Paused until they finish or fail
+                                        ClientTransferJobProgress::Requested
+                                        | ClientTransferJobProgress::Transcoding
+                                        | ClientTransferJobProgress::Ready { .. }
+                                            if is_paused =>
+                                        {
+                                            TransferJobProgressModel::Paused { bytes: None }
+                                        }
+                                        ClientTransferJobProgress::InProgress {
+                                            written, ..
+                                        } if is_paused => TransferJobProgressModel::Paused {
+                                            bytes: Some(Arc::new(CounterModel::from(written))),
+                                        },
 
-                                    ClientTransferJobProgress::InProgress {
-                                        started_at,
-                                        written,
-                                        ..
-                                    } => TransferJobProgressModel::InProgress {
-                                        started_at: *started_at,
-                                        bytes: Arc::new(CounterModel::from(written)),
-                                    },
+                                        ClientTransferJobProgress::Requested => {
+                                            TransferJobProgressModel::Requested
+                                        }
+                                        ClientTransferJobProgress::Transcoding => {
+                                            TransferJobProgressModel::Transcoding
+                                        }
+                                        ClientTransferJobProgress::Ready { .. } => {
+                                            TransferJobProgressModel::Ready
+                                        }
 
-                                    // Finished jobs are always shown as Finished
-                                    ClientTransferJobProgress::Finished { finished_at, .. } => {
-                                        TransferJobProgressModel::Finished {
+                                        ClientTransferJobProgress::InProgress {
+                                            started_at,
+                                            written,
+                                            ..
+                                        } => TransferJobProgressModel::InProgress {
+                                            started_at: *started_at,
+                                            bytes: Arc::new(CounterModel::from(written)),
+                                        },
+
+                                        // Finished jobs are always shown as Finished
+                                        ClientTransferJobProgress::Finished {
+                                            finished_at, ..
+                                        } => TransferJobProgressModel::Finished {
                                             finished_at: *finished_at,
-                                        }
-                                    }
+                                        },
 
-                                    // Failed jobs are always shown as Failed
-                                    ClientTransferJobProgress::Failed { error } => {
-                                        TransferJobProgressModel::Failed {
-                                            error: error.clone(),
+                                        // Failed jobs are always shown as Failed
+                                        ClientTransferJobProgress::Failed { error, reason } => {
+                                            TransferJobProgressModel::Failed {
+                                                error: error.clone(),
+                                                reason: *reason,
+                                                retryable: reason.is_retryable(),
+                                            }
                                         }
-                                    }
-                                };
+                                    };
 
                                 TransferJobModel {
                                     job_id: *entry.key(),
                                     file_root: job.file_root.clone(),
                                     file_path: job.file_path.clone(),
                                     file_size,
+                                    held: is_held
+                                        && matches!(progress, TransferJobProgressModel::Ready),
                                     progress,
+                                    collision: job.collision.clone(),
                                 }
                             })
-                            .collect();
+                            .collect::<Vec<_>>();
 
-                        client.transfer_jobs = transfer_jobs;
-                    }
-                    ClientModelUpdate::UpdatePaused => {
-                        let client_handles = self.clients.lock().unwrap();
-                        let Some(client_handle) = client_handles.get(&endpoint_id) else {
-                            warn!(
-                                "failed to apply ClientModelUpdate::UpdatePaused: no client handle found"
-                            );
-                            return;
-                        };
+                        let mut conflicts = Vec::new();
+                        for job in &transfer_jobs {
+                            let Some(handle_job) = client_handle.jobs.get(&job.job_id) else {
+                                continue;
+                            };
+                            let mut push_conflict = |reason, action| {
+                                conflicts.push(DownloadConflictModel {
+                                    job_id: job.job_id,
+                                    file_root: job.file_root.clone(),
+                                    file_path: job.file_path.clone(),
+                                    reason,
+                                    action,
+                                });
+                            };
+                            if let Some(sanitized_path) = &handle_job.sanitized_path {
+                                push_conflict(
+                                    DownloadConflictReasonModel::UnsupportedFileName,
+                                    DownloadConflictActionModel::Renamed {
+                                        local_path: sanitized_path.clone(),
+                                    },
+                                );
+                            }
+                            if let Some(collision) = &job.collision {
+                                push_conflict(
+                                    DownloadConflictReasonModel::Collision,
+                                    collision.clone().into(),
+                                );
+                            }
+                            if let TransferJobProgressModel::Failed {
+                                error,
+                                reason: TransferErrorReasonModel::PermissionDenied,
+                                ..
+                            } = &job.progress
+                            {
+                                push_conflict(
+                                    DownloadConflictReasonModel::PermissionDenied,
+                                    DownloadConflictActionModel::Failed {
+                                        error: error.clone(),
+                                    },
+                                );
+                            }
+                        }
+                        conflicts.sort_by_key(|conflict| conflict.job_id);
 
-                        let is_paused = client_handle.paused.load(Ordering::Relaxed);
+                        // report jobs that newly failed, unless they were cancelled
+                        for job in &transfer_jobs {
+                            let TransferJobProgressModel::Failed { error, .. } = &job.progress
+                            else {
+                                continue;
+                            };
+                            let was_failed = client.transfer_jobs.iter().any(|previous| {
+                                previous.job_id == job.job_id
+                                    && matches!(
+                                        previous.progress,
+                                        TransferJobProgressModel::Failed { .. }
+                                    )
+                            });
+                            let is_cancelled = client_handle
+                                .jobs
+                                .get(&job.job_id)
+                                .is_some_and(|job| *job.control.borrow() == JobControl::Cancel);
+                            if !was_failed && !is_cancelled {
+                                events.push(TransferEvent::JobFailed(TransferJobFailedEvent {
+                                    endpoint_id: client.endpoint_id.clone(),
+                                    job_id: job.job_id,
+                                    file_root: job.file_root.clone(),
+                                    file_path: job.file_path.clone(),
+                                    error: error.clone(),
+                                }));
+                            }
+                        }
+
+                        // the session completes when its last unfinished job finishes or fails
+                        let session = SessionProgressModel::from_jobs(&transfer_jobs);
+                        if client.session.unfinished_files() > 0 && session.unfinished_files() == 0
+                        {
+                            // only the jobs that weren't in an earlier session belong to this one
+                            let session_jobs = {
+                                let mut recorded_session_jobs =
+                                    self.recorded_session_jobs.lock().unwrap();
+                                let recorded =
+                                    recorded_session_jobs.entry(endpoint_id).or_default();
+                                let session_jobs = transfer_jobs
+                                    .iter()
+                                    .filter(|job| !recorded.contains(&job.job_id))
+                                    .cloned()
+                                    .collect::<Vec<_>>();
+                                recorded.extend(session_jobs.iter().map(|job| job.job_id));
+                                session_jobs
+                            };
+                            let finished_session = SessionProgressModel::from_jobs(&session_jobs);
+
+                            events.push(TransferEvent::SessionCompleted(
+                                TransferSessionCompletedEvent {
+                                    endpoint_id: client.endpoint_id.clone(),
+                                    name: client.name.clone(),
+                                    completed_files: finished_session.completed_files,
+                                    failed_files: finished_session.failed_files,
+                                    transferred_bytes: finished_session.transferred_bytes,
+                                },
+                            ));
+
+                            let files = session_jobs
+                                .iter()
+                                .filter_map(|job| match &job.progress {
+                                    TransferJobProgressModel::Finished { .. } => Some((job, None)),
+                                    TransferJobProgressModel::Failed { error, .. } => {
+                                        Some((job, Some(error.clone())))
+                                    }
+                                    _ => None,
+                                })
+                                .map(|(job, error)| TransferSessionFile {
+                                    root: job.file_root.clone(),
+                                    path: job.file_path.clone(),
+                                    file_size: job.file_size,
+                                    error,
+                                })
+                                .collect::<Vec<_>>();
+                            finished_sessions.push((client.name.clone(), finished_session, files));
+                        }
+
+                        // only the jobs that changed are sent in diffs
+                        let previous_jobs = client
+                            .transfer_jobs
+                            .iter()
+                            .map(|job| (job.job_id, job))
+                            .collect::<HashMap<_, _>>();
+                        let changed_jobs = transfer_jobs
+                            .iter()
+                            .filter(|job| previous_jobs.get(&job.job_id) != Some(job))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        let job_ids = transfer_jobs
+                            .iter()
+                            .map(|job| job.job_id)
+                            .collect::<HashSet<_>>();
+                        let removed_job_ids = client
+                            .transfer_jobs
+                            .iter()
+                            .map(|job| job.job_id)
+                            .filter(|job_id| !job_ids.contains(job_id))
+                            .collect::<Vec<_>>();
+                        jobs_changes = Some((changed_jobs, removed_job_ids));
+
+                        client.session = session;
+                        client.transfer_jobs = transfer_jobs.into();
+                        client.conflicts = conflicts;
+                    }
+                    ClientModelUpdate::UpdatePaused => {
+                        let client_handles = self.clients.lock().unwrap();
+                        let Some(client_handle) = client_handles.get(&endpoint_id) else {
+                            warn!(
+                                "failed to apply ClientModelUpdate::UpdatePaused: no client handle found"
+                            );
+                            return;
+                        };
+
+                        let is_paused = client_handle.paused.load(Ordering::Relaxed);
                         client.paused = is_paused;
                     }
+                    ClientModelUpdate::UpdateInsufficientSpace(insufficient_space) => {
+                        client.insufficient_space = insufficient_space;
+                    }
+                    ClientModelUpdate::UpdateStorageQuota(storage_quota) => {
+                        client.storage_quota = storage_quota;
+                    }
                     ClientModelUpdate::Close { error } => {
+                        self.recorded_session_jobs
+                            .lock()
+                            .unwrap()
+                            .remove(&endpoint_id);
+                        if let Some(error) = &error {
+                            events.push(TransferEvent::ConnectionLost(ConnectionLostEvent {
+                                endpoint_id: client.endpoint_id.clone(),
+                                name: client.name.clone(),
+                                is_client: true,
+                                error: error.clone(),
+                                unfinished_files: client.session.unfinished_files(),
+                            }));
+                        }
+                        operation_event = Some(OperationEvent::ClientClosed {
+                            endpoint_id,
+                            error: error.clone(),
+                        });
                         client.state = ClientStateModel::Closed { error };
                     }
                 }
+                if !was_ready && client.is_ready() {
+                    operation_event = Some(OperationEvent::ClientReady(endpoint_id));
+                }
+
+                // the rest of the client is derived from its jobs, so if they didn't change
+                // there's nothing to send
+                let unchanged =
+                    jobs_changes
+                        .as_ref()
+                        .is_some_and(|(changed_jobs, removed_job_ids)| {
+                            changed_jobs.is_empty() && removed_job_ids.is_empty()
+                        });
+                if !unchanged {
+                    self.send_model_change(&model, || {
+                        let client = &model.clients[&endpoint_id_string];
+                        if index_updated {
+                            NodeModelPatch::ClientIndexUpdated {
+                                endpoint_id: endpoint_id_string.clone(),
+                                index: client.index.clone(),
+                                index_complete: client.index_complete,
+                            }
+                        } else if let Some((changed_jobs, removed_job_ids)) = jobs_changes {
+                            NodeModelPatch::ClientTransferJobsUpdated {
+                                client: client.without_index_or_jobs(),
+                                changed_jobs,
+                                removed_job_ids,
+                            }
+                        } else {
+                            NodeModelPatch::ClientUpdated {
+                                client: client.without_index(),
+                            }
+                        }
+                    });
+                }
+                drop(model);
+                self.dispatch_transfer_events(events);
+                if let Some(event) = operation_event {
+                    self.send_operation_event(event);
+                }
+
+                let any_finished = !finished_sessions.is_empty();
+                for (name, session, files) in finished_sessions {
+                    if let Err(e) =
+                        self.record_transfer_session(endpoint_id, &name, &session, &files)
+                    {
+                        warn!("failed to record transfer session: {e:#}");
+                    }
+                    self.spawn_write_playlists(endpoint_id, files);
+                }
+                if any_finished {
+                    // unchanged files are tracked without a transfer completing
+                    self.update_model(NodeModelUpdate::UpdateDownloadUsage);
+                }
+            }
+        }
+    }
+
+    /// Passes downloads requested with SetDownloads on to the client connected to the server.
+    fn send_downloads_to_client(
+        &self,
+        client: EndpointId,
+        items: Vec<DownloadRequestModel>,
+        callback: Option<oneshot::Sender<anyhow::Result<Vec<(String, String)>>>>,
+    ) {
+        let clients = self.clients.lock().unwrap();
+        if let Some(client_handle) = clients.get(&client) {
+            client_handle
+                .tx
+                .send(ClientCommand::SetDownloads { items, callback })
+                .expect("failed to send ClientCommand::SetDownloads");
+        } else {
+            error!("SetDownloads: no client found with endpoint_id: {client}");
+            if let Some(callback) = callback {
+                let _ = callback.send(Err(ConnectionError::NotConnected {
+                    endpoint_id: client.to_string(),
+                }
+                .into()));
+            }
+        }
+    }
+
+    /// Writes the playlists for a finished download session in the background, if enabled, and
+    /// records the result in the model.
+    fn spawn_write_playlists(
+        self: &Arc<Self>,
+        endpoint_id: EndpointId,
+        files: Vec<TransferSessionFile>,
+    ) {
+        let settings = *self.playlist_settings.lock().unwrap();
+        let download_directory = self.download_directory.lock().unwrap().clone();
+        let Some(download_directory) = download_directory.filter(|_| settings.any()) else {
+            return;
+        };
+
+        let node = self.clone();
+        tokio::spawn(async move {
+            let (playlists, error) = match playlist::write_playlists(
+                &node.db,
+                endpoint_id,
+                &download_directory,
+                settings,
+                &files,
+            )
+            .await
+            {
+                Ok(playlists) => (playlists, None),
+                Err(e) => {
+                    warn!("failed to write playlists: {e:#}");
+                    (Vec::new(), Some(format!("{e:#}")))
+                }
+            };
+            node.update_model(NodeModelUpdate::UpdatePlaylistWrite(PlaylistWriteModel {
+                endpoint_id: endpoint_id.to_string(),
+                written_at: unix_epoch_now_secs(),
+                playlists,
+                error,
+            }));
+        });
+    }
+
+    /// Add a finished download session to the transfer history, pruning sessions older than the
+    /// retention.
+    fn record_transfer_session(
+        &self,
+        endpoint_id: EndpointId,
+        name: &str,
+        session: &SessionProgressModel,
+        files: &[TransferSessionFile],
+    ) -> anyhow::Result<()> {
+        {
+            let mut db = self.db.get();
+            db.insert_transfer_session(
+                InsertTransferSession {
+                    node_id: endpoint_id,
+                    name,
+                    finished_at: unix_epoch_now_secs(),
+                    completed_files: session.completed_files,
+                    failed_files: session.failed_files,
+                    transferred_bytes: session.transferred_bytes,
+                },
+                files.iter(),
+            )
+            .context("failed to insert transfer session")?;
+        }
+
+        self.prune_transfer_history()
+    }
+
+    /// Delete download sessions older than the retention from the transfer history.
+    fn prune_transfer_history(&self) -> anyhow::Result<()> {
+        let Some(retention) = *self.transfer_history_retention.lock().unwrap() else {
+            return Ok(());
+        };
+
+        let before = unix_epoch_now_secs().saturating_sub(retention.as_secs());
+        let mut db = self.db.get();
+        let deleted = db
+            .prune_transfer_sessions(before)
+            .context("failed to prune transfer sessions")?;
+        if deleted > 0 {
+            debug!("pruned {deleted} sessions from the transfer history");
+        }
+
+        Ok(())
+    }
 
-                self.event_handler.on_node_model_snapshot(model.clone());
+    /// Add to the bytes transferred with a node in this session and in total.
+    fn track_peer_traffic(&self, endpoint_id: EndpointId, sent_bytes: u64, received_bytes: u64) {
+        {
+            let mut session_traffic = self.session_traffic.lock().unwrap();
+            let (sent, received) = session_traffic.entry(endpoint_id).or_default();
+            *sent += sent_bytes;
+            *received += received_bytes;
+        }
+
+        {
+            let db = self.db.get();
+            if let Err(e) = db.track_peer_traffic(endpoint_id, sent_bytes, received_bytes) {
+                error!("failed to track peer traffic in database: {e:#}");
             }
         }
+
+        self.update_model(NodeModelUpdate::UpdatePeerTraffic);
     }
 
     fn push_stats_model(&self) {
-        let db = self.db.lock().unwrap();
+        let db = self.db.get();
         if let Ok(stats) = db.get_stats() {
             self.event_handler.on_stats_model_snapshot(stats);
         }
@@ -1281,6 +4710,241 @@ impl Node {
         self.memory_lookup.add_endpoint_info(addr);
     }
 
+    /// Creates a pairing ticket for connecting to this node.
+    ///
+    /// If `one_time_token` is set, the ticket includes a token that lets the first client using it
+    /// connect without the connection being accepted manually. Tokens are kept in memory until
+    /// they're used or the app restarts.
+    pub fn create_pairing_ticket(&self, one_time_token: bool) -> anyhow::Result<String> {
+        let token = if one_time_token {
+            let token = generate_token()?;
+            self.pairing_tokens.lock().unwrap().insert(token);
+            Some(token)
+        } else {
+            None
+        };
+
+        PairingTicket::new(&self.router.endpoint().addr(), token).encode()
+    }
+
+    /// Creates a sync group with this node as its founder and only member, and returns the group's
+    /// ID.
+    pub fn create_sync_group(self: &Arc<Self>, name: String) -> anyhow::Result<String> {
+        let group_id = generate_token()?;
+        let local_endpoint_id = self.router.endpoint().id();
+        let record = GroupRecord::sign(
+            &self.secret_key,
+            group_id,
+            name.clone(),
+            local_endpoint_id,
+            local_endpoint_id,
+            unix_epoch_now_secs(),
+        )?;
+
+        {
+            let db = self.db.get();
+            db.insert_sync_group(group_id, &name, local_endpoint_id)?;
+            db.insert_group_record(&record)?;
+        }
+        self.update_model(NodeModelUpdate::UpdateSyncGroups);
+
+        Ok(hex::encode(group_id))
+    }
+
+    /// Adds a node to a sync group this node is a member of.
+    ///
+    /// The node is invited to the group the next time it connects with this node, if it trusts this
+    /// node. Other members learn about it when they connect with a member that knows about it.
+    pub fn add_sync_group_member(
+        self: &Arc<Self>,
+        group_id: &str,
+        endpoint_id: EndpointId,
+    ) -> anyhow::Result<()> {
+        let group_id = parse_group_id(group_id)?;
+
+        {
+            let db = self.db.get();
+            let group = db
+                .get_sync_groups()?
+                .into_iter()
+                .find(|group| group.group_id == group_id)
+                .context("not a member of the sync group")?;
+            let record = GroupRecord::sign(
+                &self.secret_key,
+                group_id,
+                group.name,
+                group.founder,
+                endpoint_id,
+                unix_epoch_now_secs(),
+            )?;
+            db.insert_group_record(&record)?;
+            // adding a node to a group is asking to trust it again
+            db.retrust_group_member(endpoint_id)?;
+        }
+        self.update_model(NodeModelUpdate::UpdateSyncGroups);
+
+        Ok(())
+    }
+
+    /// Accepts an invitation to a sync group, joining the group so its members are trusted.
+    pub fn accept_sync_group_invitation(self: &Arc<Self>, group_id: &str) -> anyhow::Result<()> {
+        let group_id = parse_group_id(group_id)?;
+
+        let invitation = self
+            .sync_group_invitations
+            .lock()
+            .unwrap()
+            .remove(&group_id)
+            .context("no invitation to the sync group")?;
+        let res = {
+            let db = self.db.get();
+            sync_group::accept_invitation(&db, &invitation)
+        };
+        self.update_model(NodeModelUpdate::UpdateSyncGroups);
+
+        res
+    }
+
+    /// Declines an invitation to a sync group. The node can be invited again when it next
+    /// connects with a member.
+    pub fn decline_sync_group_invitation(self: &Arc<Self>, group_id: &str) -> anyhow::Result<()> {
+        let group_id = parse_group_id(group_id)?;
+
+        self.sync_group_invitations
+            .lock()
+            .unwrap()
+            .remove(&group_id)
+            .context("no invitation to the sync group")?;
+        self.update_model(NodeModelUpdate::UpdateSyncGroups);
+
+        Ok(())
+    }
+
+    /// Leaves a sync group, so its members are no longer trusted through it. Other members still
+    /// trust this node until they leave the group too.
+    pub fn leave_sync_group(self: &Arc<Self>, group_id: &str) -> anyhow::Result<()> {
+        let group_id = parse_group_id(group_id)?;
+
+        {
+            let db = self.db.get();
+            db.delete_sync_group(group_id)?;
+        }
+        self.update_model(NodeModelUpdate::UpdateSyncGroups);
+
+        Ok(())
+    }
+
+    /// Creates a share link for the library files in the given roots and directories, and returns
+    /// its URL. The share server has to be running.
+    ///
+    /// Files in document trees can't be shared, since the share server reads files by path. If a
+    /// transcode format is given, the files are transcoded ahead of time, and requests for files
+    /// that aren't ready yet ask the browser to retry.
+    pub fn create_share_link(
+        self: &Arc<Self>,
+        name: String,
+        selection: Vec<NodeShareModel>,
+        transcode_format: Option<TranscodeFormat>,
+        expires_in: Duration,
+        one_time: bool,
+    ) -> anyhow::Result<String> {
+        anyhow::ensure!(!selection.is_empty(), "no files selected");
+        let selection = selection
+            .into_iter()
+            .map(|share| NodeShare {
+                root: share.root,
+                path_prefix: share.path_prefix,
+            })
+            .collect::<Vec<_>>();
+
+        let local_endpoint_id = self.router.endpoint().id();
+        let files = {
+            let db = self.db.get();
+            db.get_files_by_node_id(local_endpoint_id)?
+        };
+        let files = files
+            .into_iter()
+            .filter(|f| f.local_tree.is_empty())
+            .filter(|f| {
+                selection
+                    .iter()
+                    .any(|share| share.contains(&f.root, &f.path))
+            })
+            .sorted_by(|a, b| (&a.root, &a.path).cmp(&(&b.root, &b.path)))
+            .map(|f| ShareLinkFile {
+                root: f.root,
+                path: f.path,
+                local_path: PathBuf::from(f.local_path),
+            })
+            .collect::<Vec<_>>();
+        anyhow::ensure!(!files.is_empty(), "no files to share");
+
+        let token = hex::encode(generate_token()?);
+        let url = {
+            let share_server = self.share_server.lock().unwrap();
+            let share_server = share_server
+                .as_ref()
+                .context("share server is not running")?;
+            share_server.link_url(&token)
+        };
+
+        // transcode the files ahead of time, so they're ready when the link is opened
+        if let Some(transcode_format) = transcode_format {
+            let paths = files.iter().map(|f| f.local_path.clone()).collect();
+            self.event_tx
+                .send(NodeEvent::FilesRequested(transcode_format, paths))
+                .expect("failed to send NodeEvent::FilesRequested");
+        }
+
+        let created_at = unix_epoch_now_secs();
+        self.share_links.lock().unwrap().insert(
+            token,
+            ShareLink {
+                name,
+                transcode_format,
+                files,
+                created_at,
+                expires_at: created_at + expires_in.as_secs(),
+                one_time,
+                downloaded: HashSet::new(),
+            },
+        );
+        self.update_model(NodeModelUpdate::UpdateShareLinks);
+
+        Ok(url)
+    }
+
+    /// Starts the share server, replacing the running one if there is one, and returns the address
+    /// it's bound to.
+    async fn start_share_server(
+        self: &Arc<Self>,
+        bind_addr: &str,
+        public_url: Option<String>,
+    ) -> anyhow::Result<String> {
+        // stop the old server first, so the new one can bind to the same address
+        self.share_server.lock().unwrap().take();
+
+        let event_tx = self.event_tx.clone();
+        let share_server = ShareServer::spawn(
+            bind_addr,
+            public_url,
+            self.share_links.clone(),
+            self.hash_cache.clone(),
+            self.transcode_status_cache.clone(),
+            move || {
+                let _ = event_tx.send(NodeEvent::ShareLinksChanged);
+            },
+        )
+        .await?;
+        let local_addr = share_server.local_addr.to_string();
+        info!("share server listening on {local_addr}");
+
+        *self.share_server.lock().unwrap() = Some(share_server);
+        self.update_model(NodeModelUpdate::UpdateShareLinks);
+
+        Ok(local_addr)
+    }
+
     // TODO: maybe replace with methods?
     pub fn send(self: &Arc<Self>, command: NodeCommand) -> anyhow::Result<()> {
         self.command_tx
@@ -1292,25 +4956,74 @@ impl Node {
         self: &Arc<Self>,
         transcode_format: Option<TranscodeFormat>,
         addr: EndpointAddr,
+        pairing_token: Option<PairingToken>,
     ) -> anyhow::Result<()> {
+        if self.shutting_down.load(Ordering::Relaxed) {
+            anyhow::bail!("node is shutting down");
+        }
+        self.transfer_gate.check_can_connect()?;
+
+        // in LAN-only mode, only try addresses on the local network
+        let addr = if self.lan_only {
+            local_endpoint_addr(&addr)
+        } else {
+            addr
+        };
+
         // connect before spawning the task, so we can return an error immediately
-        let connection = self.router.endpoint().connect(addr, Protocol::ALPN).await?;
+        let connection = self
+            .router
+            .endpoint()
+            .connect(addr, Protocol::ALPN)
+            .await
+            .context(ConnectionError::Unreachable)?;
 
         let endpoint_id = connection.remote_id();
         info!("opened connection to {endpoint_id}");
 
+        if self.lan_only {
+            close_if_not_local(connection.clone());
+        }
+
         let db = self.db.clone();
         let event_tx = self.event_tx.clone();
         let download_directory = self.download_directory.clone();
+        let download_path_template = self.download_path_template.clone();
+        let collision_policy = self.collision_policy.clone();
+        let pending_timeout = *self.pending_timeout.lock().unwrap();
+        let sync_downloads = self.sync_downloads.clone();
+        let verify_downloads = self.verify_downloads.clone();
+        let redownload_changed = self.redownload_changed.clone();
+        let parallel_streams = self.parallel_streams.clone();
+        let transfer_buffer_size = self.transfer_buffer_size.clone();
+        let max_concurrent_transfers = self.max_concurrent_transfers.subscribe();
+        let download_slots = self.download_slots.clone();
+        let download_bandwidth = self.download_bandwidth.clone();
+        let transfer_gate = self.transfer_gate.clone();
+        let local_endpoint_id = self.router.endpoint().id();
         #[cfg(feature = "test-hooks")]
         let test_hooks = self.test_hooks.clone();
         tokio::spawn(async move {
             let client = Client::new(
                 db,
+                local_endpoint_id,
                 event_tx.clone(),
                 connection,
                 transcode_format,
+                pairing_token,
+                pending_timeout,
                 download_directory,
+                download_path_template,
+                collision_policy,
+                sync_downloads,
+                verify_downloads,
+                redownload_changed,
+                parallel_streams,
+                transfer_buffer_size,
+                max_concurrent_transfers,
+                download_slots,
+                download_bandwidth,
+                transfer_gate,
                 #[cfg(feature = "test-hooks")]
                 test_hooks,
             );
@@ -1319,12 +5032,16 @@ impl Node {
             if let Err(e) = &res {
                 error!("error during client.run(): {e:#}");
             }
+            let expired = res
+                .as_ref()
+                .is_err_and(|e| e.chain().any(|e| e.is::<PendingExpired>()));
 
             // notify node
             event_tx
                 .send(NodeEvent::ClientClosed {
                     endpoint_id,
                     error: res.err().map(|e| format!("{e:#}")),
+                    expired,
                 })
                 .expect("failed to send NodeEvent::ClientClosed");
         });
@@ -1336,7 +5053,7 @@ impl Node {
     async fn check_remote_files(self: &Arc<Self>) -> anyhow::Result<()> {
         // get remote files by getting files where endpoint ID is not the local endpoint ID
         let remote_files = {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
             db.get_files_by_ne_node_id(self.router.endpoint().id())?
         };
 
@@ -1365,79 +5082,920 @@ impl Node {
                 missing_files.len()
             );
             {
-                let db = self.db.lock().unwrap();
+                let db = self.db.get();
                 db.remove_files_by_local_treepath(missing_files.into_iter())?;
             }
+            self.update_model(NodeModelUpdate::UpdateDownloadUsage);
         }
 
         Ok(())
     }
-}
 
-#[derive(Debug, Clone)]
-struct Protocol {
-    db: Arc<Mutex<Database>>,
-    transcode_status_cache: TranscodeStatusCache,
-    hash_cache: HashCache,
+    /// Gets the index of a client and the tags of its items.
+    fn get_client_index_metadata(
+        &self,
+        endpoint_id: EndpointId,
+    ) -> anyhow::Result<(Vec<IndexItem>, IndexMetadata)> {
+        let clients = self.clients.lock().unwrap();
+        let client_handle =
+            clients
+                .get(&endpoint_id)
+                .with_context(|| ConnectionError::NotConnected {
+                    endpoint_id: endpoint_id.to_string(),
+                })?;
+        let index_metadata = client_handle.index_metadata.lock().unwrap().clone();
+        let index = client_handle.complete_index(endpoint_id)?;
+        Ok((index, index_metadata))
+    }
 
-    event_tx: mpsc::UnboundedSender<NodeEvent>,
-}
+    /// Lists the files downloaded from a connected server whose originals changed on the server
+    /// since, by comparing the content hashes recorded when they were downloaded with the server's
+    /// index. If `requeue` is set, they're downloaded again, replacing the old files.
+    async fn changed_downloads(
+        &self,
+        endpoint_id: EndpointId,
+        requeue: bool,
+    ) -> anyhow::Result<Vec<ChangedDownloadModel>> {
+        let (index, index_hashes) = {
+            let clients = self.clients.lock().unwrap();
+            let client_handle =
+                clients
+                    .get(&endpoint_id)
+                    .with_context(|| ConnectionError::NotConnected {
+                        endpoint_id: endpoint_id.to_string(),
+                    })?;
+            let index_hashes = client_handle.index_hashes.lock().unwrap().clone();
+            let index = client_handle.complete_index(endpoint_id)?;
+            (index, index_hashes)
+        };
+        let download_directory = self.download_directory.lock().unwrap().clone();
+        let Some(download_directory) = download_directory else {
+            return Ok(Vec::new());
+        };
 
-impl Protocol {
-    const ALPN: &'static [u8] = b"musicopy/1";
+        let mut changed = {
+            let db = self.db.get();
+            find_changed_downloads(&db, endpoint_id, &index, &index_hashes, &download_directory)
+        };
 
-    fn new(
-        db: Arc<Mutex<Database>>,
-        transcode_status_cache: TranscodeStatusCache,
-        hash_cache: HashCache,
+        if requeue && !changed.is_empty() {
+            info!("downloading {} changed files again", changed.len());
+            let items = changed
+                .iter()
+                .map(|file| DownloadRequestModel {
+                    endpoint_id: file.endpoint_id.clone(),
+                    root: file.root.clone(),
+                    path: file.path.clone(),
+                })
+                .collect::<Vec<_>>();
+            let keys = items
+                .iter()
+                .map(|item| (item.root.clone(), item.path.clone()))
+                .collect::<HashSet<_>>();
 
-        event_tx: mpsc::UnboundedSender<NodeEvent>,
-    ) -> Self {
-        Self {
-            db,
-            transcode_status_cache,
-            hash_cache,
+            let (callback_tx, callback_rx) = oneshot::channel();
+            {
+                let clients = self.clients.lock().unwrap();
+                let client_handle =
+                    clients
+                        .get(&endpoint_id)
+                        .with_context(|| ConnectionError::NotConnected {
+                            endpoint_id: endpoint_id.to_string(),
+                        })?;
+                clear_settled_jobs(&client_handle.jobs, &keys);
+                client_handle
+                    .tx
+                    .send(ClientCommand::SetDownloads {
+                        items,
+                        callback: Some(callback_tx),
+                    })
+                    .expect("failed to send ClientCommand::SetDownloads");
+            }
 
-            event_tx,
+            // only report the files the client actually queued
+            let queued = callback_rx
+                .await
+                .context("client closed before downloading changed files")??
+                .into_iter()
+                .collect::<HashSet<_>>();
+            for file in &mut changed {
+                file.requeued = queued.contains(&(file.root.clone(), file.path.clone()));
+            }
         }
+
+        Ok(changed)
     }
-}
 
-impl ProtocolHandler for Protocol {
-    async fn accept(&self, connection: iroh::endpoint::Connection) -> Result<(), AcceptError> {
-        let endpoint_id = connection.remote_id();
-        info!("accepted connection from {endpoint_id}");
+    /// Matches the untracked files in a folder of the download directory to the files in a
+    /// connected server's index that aren't downloaded yet, and tracks the matches as downloaded
+    /// so they aren't transferred again.
+    async fn import_downloads(
+        self: &Arc<Self>,
+        endpoint_id: EndpointId,
+        folder: Option<String>,
+        dry_run: bool,
+    ) -> anyhow::Result<ImportedDownloadsModel> {
+        let (index, index_hashes, index_metadata) = {
+            let clients = self.clients.lock().unwrap();
+            let client_handle =
+                clients
+                    .get(&endpoint_id)
+                    .with_context(|| ConnectionError::NotConnected {
+                        endpoint_id: endpoint_id.to_string(),
+                    })?;
+            let index_hashes = client_handle.index_hashes.lock().unwrap().clone();
+            let index_metadata = client_handle.index_metadata.lock().unwrap().clone();
+            let index = client_handle.complete_index(endpoint_id)?;
+            (index, index_hashes, index_metadata)
+        };
+        let download_directory = {
+            let download_directory = self.download_directory.lock().unwrap();
+            download_directory
+                .clone()
+                .context(TransferError::DownloadDirectoryNotSet)?
+        };
 
-        let server = Server::new(
-            self.db.clone(),
-            self.transcode_status_cache.clone(),
-            self.hash_cache.clone(),
-            connection,
-            self.event_tx.clone(),
-        );
+        // the files in the index that aren't downloaded yet
+        let candidates = {
+            let db = self.db.get();
+            index
+                .into_iter()
+                .filter_map(|item| {
+                    let key = (item.endpoint_id, item.root, item.path);
+                    let content_hash = index_hashes.get(&key);
+                    let (_, root, path) = &key;
+                    if find_unchanged_download(
+                        &db,
+                        endpoint_id,
+                        root,
+                        path,
+                        &download_directory,
+                        content_hash,
+                    )
+                    .is_some()
+                    {
+                        return None;
+                    }
 
-        let res = server.run().await;
-        if let Err(e) = &res {
-            error!("error during server.run(): {e:#}");
-        }
+                    let file_size = match item.file_size {
+                        FileSize::Actual(file_size) => Some(file_size),
+                        FileSize::Unknown | FileSize::Estimated(_) => None,
+                    };
+                    let content_hash = content_hash.cloned();
+                    let metadata = index_metadata.get(&key).cloned();
+                    let (_, root, path) = key;
+                    Some(ImportCandidate {
+                        root,
+                        path,
+                        file_size,
+                        content_hash,
+                        metadata,
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
 
-        // notify node
-        self.event_tx
-            .send(NodeEvent::ServerClosed {
+        let folder = TreePath::new(download_directory, folder.unwrap_or_default().into())?;
+        let result = downloads::import(
+            &self.db,
+            &self.hash_cache,
+            self.router.endpoint().id(),
+            endpoint_id,
+            &folder,
+            candidates,
+            dry_run,
+        )
+        .await?;
+
+        // the imported files show as downloaded in the server's index
+        if !dry_run && !result.imported.is_empty() {
+            self.update_model(NodeModelUpdate::UpdateDownloadUsage);
+            self.update_model(NodeModelUpdate::UpdateClient {
                 endpoint_id,
-                error: res.err().map(|e| format!("{e:#}")),
-            })
-            .expect("failed to send NodeEvent::ServerClosed");
+                update: ClientModelUpdate::UpdateIndex,
+            });
+        }
 
-        Ok(())
+        Ok(result)
     }
-}
 
-/// A message sent by the client at the start of a file transfer stream.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TransferRequest {
-    job_id: u64,
-}
+    /// Lists the albums in a server's index, sorted by artist and album.
+    fn browse_remote_library(
+        &self,
+        endpoint_id: EndpointId,
+    ) -> anyhow::Result<Vec<RemoteAlbumModel>> {
+        let (index, index_metadata) = self.get_client_index_metadata(endpoint_id)?;
+
+        let mut albums: BTreeMap<(Option<String>, Option<String>), RemoteAlbumModel> =
+            BTreeMap::new();
+        for item in index {
+            let metadata = index_metadata
+                .get(&(item.endpoint_id, item.root, item.path))
+                .cloned()
+                .unwrap_or_default();
+            let album = albums
+                .entry((metadata.artist.clone(), metadata.album.clone()))
+                .or_insert_with(|| RemoteAlbumModel {
+                    artist: metadata.artist,
+                    album: metadata.album,
+                    file_count: 0,
+                    estimated_size: 0,
+                });
+            album.file_count += 1;
+            album.estimated_size += match item.file_size {
+                FileSize::Unknown => 0,
+                FileSize::Estimated(size) | FileSize::Actual(size) => size,
+            };
+        }
+
+        Ok(albums.into_values().collect())
+    }
+
+    /// Expands selections of artists and albums into the matching files in a server's index.
+    ///
+    /// Files without tags never match a selection.
+    fn expand_download_selection(
+        &self,
+        endpoint_id: EndpointId,
+        selections: &[DownloadSelectionModel],
+    ) -> anyhow::Result<Vec<DownloadRequestModel>> {
+        let (index, index_metadata) = self.get_client_index_metadata(endpoint_id)?;
+
+        let items = index
+            .into_iter()
+            .filter(|item| {
+                index_metadata
+                    .get(&(item.endpoint_id, item.root.clone(), item.path.clone()))
+                    .is_some_and(|metadata| {
+                        selections
+                            .iter()
+                            .any(|selection| selection.matches(metadata))
+                    })
+            })
+            .map(|item| DownloadRequestModel {
+                endpoint_id: item.endpoint_id.to_string(),
+                root: item.root,
+                path: item.path,
+            })
+            .collect();
+
+        Ok(items)
+    }
+
+    /// Deletes files downloaded from a server to the current download directory that no longer
+    /// exist in its index, so the download directory mirrors the server's library.
+    ///
+    /// Files are kept if their path is still in the index, or if their content hash is, e.g. if
+    /// they were renamed on the server. If `dry_run` is set, nothing is deleted.
+    async fn mirror_downloads(
+        self: &Arc<Self>,
+        endpoint_id: EndpointId,
+        dry_run: bool,
+    ) -> anyhow::Result<Vec<MirrorDeletionModel>> {
+        let (index, index_hashes) = {
+            let clients = self.clients.lock().unwrap();
+            let client_handle =
+                clients
+                    .get(&endpoint_id)
+                    .with_context(|| ConnectionError::NotConnected {
+                        endpoint_id: endpoint_id.to_string(),
+                    })?;
+            let index_hashes = client_handle.index_hashes.lock().unwrap().clone();
+            let index = client_handle.complete_index(endpoint_id)?;
+            (index, index_hashes)
+        };
+        let download_directory = {
+            let download_directory = self.download_directory.lock().unwrap();
+            download_directory
+                .clone()
+                .context(TransferError::DownloadDirectoryNotSet)?
+        };
+
+        // find downloaded files that aren't in the index
+        let index_keys: HashSet<(&str, &str)> = index
+            .iter()
+            .map(|item| (item.root.as_str(), item.path.as_str()))
+            .collect();
+        let index_hashes: HashSet<(&str, [u8; 16])> = index_hashes
+            .values()
+            .map(|content_hash| (content_hash.kind.as_str(), content_hash.hash))
+            .collect();
+        let deleted_files = {
+            let db = self.db.get();
+            db.get_downloaded_files_by_node_localtree(endpoint_id, &download_directory)?
+        }
+        .into_iter()
+        .filter(|file| {
+            !index_keys.contains(&(file.root.as_str(), file.path.as_str()))
+                && file
+                    .source_hash
+                    .as_ref()
+                    .is_none_or(|(kind, hash)| !index_hashes.contains(&(kind.as_str(), *hash)))
+        })
+        .collect::<Vec<_>>();
+
+        if dry_run {
+            return Ok(deleted_files
+                .into_iter()
+                .map(|file| MirrorDeletionModel {
+                    root: file.root,
+                    path: file.path,
+                    local_path: file.local_path,
+                })
+                .collect());
+        }
+
+        info!(
+            "mirroring downloads: deleting {} files",
+            deleted_files.len()
+        );
+
+        // delete files, skipping ones that fail so they're still tracked
+        let mut deleted = Vec::new();
+        for file in deleted_files {
+            let local_path =
+                TreePath::new(file.local_tree.clone(), file.local_path.clone().into())?;
+            if local_path.exists()
+                && let Err(e) = crate::fs::remove_file(&local_path).await
+            {
+                warn!("failed to delete {}: {e:#}", file.local_path);
+                continue;
+            }
+            deleted.push(file);
+        }
+
+        // remove from db
+        {
+            let db = self.db.get();
+            db.remove_files_by_local_treepath(
+                deleted
+                    .iter()
+                    .map(|file| (file.local_tree.clone(), file.local_path.clone())),
+            )?;
+        }
+
+        // update model
+        self.update_model(NodeModelUpdate::UpdateDownloadUsage);
+        self.update_model(NodeModelUpdate::UpdateClient {
+            endpoint_id,
+            update: ClientModelUpdate::UpdateIndex,
+        });
+
+        Ok(deleted
+            .into_iter()
+            .map(|file| MirrorDeletionModel {
+                root: file.root,
+                path: file.path,
+                local_path: file.local_path,
+            })
+            .collect())
+    }
+
+    /// Checks files downloaded from all servers against the manifests the servers sent with them,
+    /// without connecting to the servers. Files downloaded before manifests were kept are only
+    /// checked for existence.
+    ///
+    /// If `repair` is set, files with issues are deleted and untracked, so they're downloaded again
+    /// the next time their server is synced.
+    async fn check_downloaded_files(
+        self: &Arc<Self>,
+        repair: bool,
+    ) -> anyhow::Result<Vec<DownloadIssueModel>> {
+        let files = {
+            let db = self.db.get();
+            db.get_downloaded_files(self.router.endpoint().id())?
+        };
+
+        let mut issues = Vec::new();
+        for (endpoint_id, file) in files {
+            let local_path =
+                match TreePath::new(file.local_tree.clone(), file.local_path.clone().into()) {
+                    Ok(local_path) => local_path,
+                    Err(e) => {
+                        warn!("failed to check {}: {e:#}", file.local_path);
+                        continue;
+                    }
+                };
+
+            let Some(issue) = check_downloaded_file(&local_path, &file).await else {
+                continue;
+            };
+            debug!("downloaded file {} has issue {issue:?}", file.local_path);
+
+            let repaired = repair && remove_broken_download(&local_path, &issue).await;
+
+            issues.push((endpoint_id, file, issue, repaired));
+        }
+
+        // remove repaired files from db, so they're downloaded again
+        if repair {
+            {
+                let db = self.db.get();
+                db.remove_files_by_local_treepath(
+                    issues
+                        .iter()
+                        .filter(|(_, _, _, repaired)| *repaired)
+                        .map(|(_, file, _, _)| (file.local_tree.clone(), file.local_path.clone()))
+                        .collect::<Vec<_>>()
+                        .into_iter(),
+                )?;
+            }
+            self.update_model(NodeModelUpdate::UpdateDownloadUsage);
+
+            // update model of connected servers
+            let endpoint_ids = issues
+                .iter()
+                .filter(|(_, _, _, repaired)| *repaired)
+                .map(|(endpoint_id, _, _, _)| *endpoint_id)
+                .collect::<HashSet<_>>();
+            let connected = self
+                .clients
+                .lock()
+                .unwrap()
+                .keys()
+                .copied()
+                .collect::<Vec<_>>();
+            for endpoint_id in connected {
+                if endpoint_ids.contains(&endpoint_id) {
+                    self.update_model(NodeModelUpdate::UpdateClient {
+                        endpoint_id,
+                        update: ClientModelUpdate::UpdateIndex,
+                    });
+                }
+            }
+        }
+
+        Ok(issues
+            .into_iter()
+            .map(|(endpoint_id, file, issue, repaired)| DownloadIssueModel {
+                endpoint_id: endpoint_id.to_string(),
+                root: file.root,
+                path: file.path,
+                local_path: file.local_path,
+                issue,
+                repaired,
+                requeued: false,
+            })
+            .collect())
+    }
+
+    /// Moves the files downloaded to the download directory to the paths the current download path
+    /// template gives them. Fails while files are downloading, since their paths were chosen with
+    /// the old template, and holds ready jobs until the files are moved.
+    async fn reorganize_downloads(
+        self: &Arc<Self>,
+        dry_run: bool,
+    ) -> anyhow::Result<ReorganizedDownloadsModel> {
+        let download_directory = {
+            let download_directory = self.download_directory.lock().unwrap();
+            download_directory
+                .clone()
+                .context(TransferError::DownloadDirectoryNotSet)?
+        };
+        let template = self.download_path_template.lock().unwrap().clone();
+        let playlists = *self.playlist_settings.lock().unwrap();
+
+        // tags of the files of connected servers. their ready jobs are held while files are
+        // moved, so none starts writing to a path that's being moved
+        let mut index_metadata = HashMap::new();
+        let mut index_extra_metadata = HashMap::new();
+        let mut held = JobsHold::default();
+        {
+            let clients = self.clients.lock().unwrap();
+            for client_handle in clients.values() {
+                if !dry_run {
+                    held.hold(client_handle);
+                }
+                let downloading = client_handle.jobs.iter().any(|entry| {
+                    matches!(
+                        entry.value().progress,
+                        ClientTransferJobProgress::InProgress { .. }
+                    )
+                });
+                anyhow::ensure!(
+                    dry_run || !downloading,
+                    "can't reorganize downloads while files are downloading"
+                );
+                index_metadata.extend(client_handle.index_metadata.lock().unwrap().clone());
+                index_extra_metadata
+                    .extend(client_handle.index_extra_metadata.lock().unwrap().clone());
+            }
+        }
+
+        let result = downloads::reorganize(
+            &self.db,
+            self.endpoint_id(),
+            &download_directory,
+            template.as_ref(),
+            &index_metadata,
+            &index_extra_metadata,
+            playlists,
+            dry_run,
+        )
+        .await;
+        drop(held);
+        let result = result?;
+        info!(
+            "reorganized downloads: {} moved, {} conflicts",
+            result.moved.len(),
+            result.conflicts.len()
+        );
+
+        Ok(result)
+    }
+
+    /// Checks files downloaded from a connected server against their manifests and the server's
+    /// current index, rehashing each file. Files whose original changed on the server since they
+    /// were downloaded are reported as outdated.
+    ///
+    /// If `requeue` is set, files with issues are deleted and untracked, and the ones still in the
+    /// index are downloaded again.
+    async fn verify_downloads(
+        self: &Arc<Self>,
+        endpoint_id: EndpointId,
+        requeue: bool,
+    ) -> anyhow::Result<Vec<DownloadIssueModel>> {
+        let (index, index_hashes) = {
+            let clients = self.clients.lock().unwrap();
+            let client_handle =
+                clients
+                    .get(&endpoint_id)
+                    .with_context(|| ConnectionError::NotConnected {
+                        endpoint_id: endpoint_id.to_string(),
+                    })?;
+            let index_hashes = client_handle.index_hashes.lock().unwrap().clone();
+            let index = client_handle.complete_index(endpoint_id)?;
+            (index, index_hashes)
+        };
+
+        // the items in the index with their current content hashes, by root and path
+        let index_items = index
+            .iter()
+            .map(|item| {
+                let content_hash =
+                    index_hashes.get(&(item.endpoint_id, item.root.clone(), item.path.clone()));
+                (
+                    (item.root.as_str(), item.path.as_str()),
+                    (item, content_hash),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        let files = {
+            let db = self.db.get();
+            db.get_downloaded_files_by_node(self.endpoint_id(), endpoint_id)?
+        };
+
+        let mut issues = Vec::new();
+        for file in files {
+            let local_path =
+                match TreePath::new(file.local_tree.clone(), file.local_path.clone().into()) {
+                    Ok(local_path) => local_path,
+                    Err(e) => {
+                        warn!("failed to verify {}: {e:#}", file.local_path);
+                        continue;
+                    }
+                };
+
+            let mut issue = check_downloaded_file(&local_path, &file).await;
+            if issue.is_none()
+                && let Some((_, Some(content_hash))) =
+                    index_items.get(&(file.root.as_str(), file.path.as_str()))
+                && let Some((kind, hash)) = &file.source_hash
+                && (kind.as_str(), hash) != (content_hash.kind.as_str(), &content_hash.hash)
+            {
+                issue = Some(DownloadIssueKindModel::Outdated);
+            }
+            let Some(issue) = issue else {
+                continue;
+            };
+            debug!("downloaded file {} has issue {issue:?}", file.local_path);
+
+            let repaired = requeue && remove_broken_download(&local_path, &issue).await;
+            issues.push((file, issue, repaired));
+        }
+
+        let mut requeued = HashSet::new();
+        if requeue {
+            {
+                let db = self.db.get();
+                db.remove_files_by_local_treepath(
+                    issues
+                        .iter()
+                        .filter(|(_, _, repaired)| *repaired)
+                        .map(|(file, _, _)| (file.local_tree.clone(), file.local_path.clone()))
+                        .collect::<Vec<_>>()
+                        .into_iter(),
+                )?;
+            }
+            self.update_model(NodeModelUpdate::UpdateDownloadUsage);
+            self.update_model(NodeModelUpdate::UpdateClient {
+                endpoint_id,
+                update: ClientModelUpdate::UpdateIndex,
+            });
+
+            // download the repaired files again if the server still has them
+            let items = issues
+                .iter()
+                .filter(|(_, _, repaired)| *repaired)
+                .filter_map(|(file, _, _)| {
+                    index_items.get(&(file.root.as_str(), file.path.as_str()))
+                })
+                .map(|(item, _)| DownloadRequestModel {
+                    endpoint_id: item.endpoint_id.to_string(),
+                    root: item.root.clone(),
+                    path: item.path.clone(),
+                })
+                .collect::<Vec<_>>();
+            if !items.is_empty() {
+                info!("verify downloads: downloading {} files again", items.len());
+                let keys = items
+                    .iter()
+                    .map(|item| (item.root.clone(), item.path.clone()))
+                    .collect::<HashSet<_>>();
+
+                let (callback_tx, callback_rx) = oneshot::channel();
+                {
+                    let clients = self.clients.lock().unwrap();
+                    let client_handle = clients.get(&endpoint_id).with_context(|| {
+                        ConnectionError::NotConnected {
+                            endpoint_id: endpoint_id.to_string(),
+                        }
+                    })?;
+                    clear_settled_jobs(&client_handle.jobs, &keys);
+                    client_handle
+                        .tx
+                        .send(ClientCommand::SetDownloads {
+                            items,
+                            callback: Some(callback_tx),
+                        })
+                        .expect("failed to send ClientCommand::SetDownloads");
+                }
+
+                // only report the files the client actually queued
+                match callback_rx.await {
+                    Ok(Ok(queued)) => requeued = queued.into_iter().collect(),
+                    Ok(Err(e)) => warn!("verify downloads: failed to download files again: {e:#}"),
+                    Err(_) => {
+                        warn!("verify downloads: client closed before downloading files again")
+                    }
+                }
+            }
+        }
+
+        Ok(issues
+            .into_iter()
+            .map(|(file, issue, repaired)| DownloadIssueModel {
+                endpoint_id: endpoint_id.to_string(),
+                requeued: requeued.contains(&(file.root.clone(), file.path.clone())),
+                root: file.root,
+                path: file.path,
+                local_path: file.local_path,
+                issue,
+                repaired,
+            })
+            .collect())
+    }
+}
+
+/// Checks a downloaded file against the manifest the server sent with it. Files downloaded before
+/// manifests were kept are only checked for existence, and files that can't be read are reported
+/// as unreadable.
+async fn check_downloaded_file(
+    local_path: &TreePath,
+    file: &DownloadedFile,
+) -> Option<DownloadIssueKindModel> {
+    if !local_path.exists() {
+        return Some(DownloadIssueKindModel::Missing);
+    }
+    let manifest = file.manifest.as_ref()?;
+
+    let check = async {
+        let actual = crate::fs::metadata(local_path).await?.len;
+        if actual != manifest.file_size {
+            Ok(Some(DownloadIssueKindModel::SizeMismatch {
+                expected: manifest.file_size,
+                actual,
+            }))
+        } else if file_checksum(local_path).await? != manifest.checksum {
+            Ok(Some(DownloadIssueKindModel::ChecksumMismatch))
+        } else {
+            anyhow::Ok(None)
+        }
+    };
+    check.await.unwrap_or_else(|e| {
+        Some(DownloadIssueKindModel::Unreadable {
+            error: format!("{e:#}"),
+        })
+    })
+}
+
+/// Deletes a downloaded file with an issue, so it can be untracked and downloaded again. Returns
+/// false if it couldn't be deleted, so it stays tracked.
+async fn remove_broken_download(local_path: &TreePath, issue: &DownloadIssueKindModel) -> bool {
+    if *issue == DownloadIssueKindModel::Missing {
+        return true;
+    }
+
+    match crate::fs::remove_file(local_path).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("failed to delete {:?}: {e:#}", local_path.path());
+            false
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Protocol {
+    local_endpoint_id: EndpointId,
+    db: Arc<DatabasePool>,
+    transcode_status_cache: TranscodeStatusCache,
+    hash_cache: HashCache,
+
+    event_tx: mpsc::UnboundedSender<NodeEvent>,
+    max_concurrent_transfers: watch::Receiver<u32>,
+    max_uploads_per_client: Arc<AtomicU32>,
+    max_upload_rate_per_client: Arc<AtomicU64>,
+    transfer_buffer_size: Arc<AtomicU32>,
+    pending_timeout: PendingTimeout,
+    pairing_tokens: PairingTokens,
+    accept_incoming: Arc<AtomicBool>,
+    shutting_down: Arc<AtomicBool>,
+    lan_only: bool,
+
+    #[cfg(feature = "test-hooks")]
+    test_hooks: Arc<TestHooks>,
+}
+
+impl Protocol {
+    const ALPN: &'static [u8] = b"musicopy/1";
+
+    fn new(
+        local_endpoint_id: EndpointId,
+        db: Arc<DatabasePool>,
+        transcode_status_cache: TranscodeStatusCache,
+        hash_cache: HashCache,
+
+        event_tx: mpsc::UnboundedSender<NodeEvent>,
+        max_concurrent_transfers: watch::Receiver<u32>,
+        max_uploads_per_client: Arc<AtomicU32>,
+        max_upload_rate_per_client: Arc<AtomicU64>,
+        transfer_buffer_size: Arc<AtomicU32>,
+        pending_timeout: PendingTimeout,
+        pairing_tokens: PairingTokens,
+        accept_incoming: Arc<AtomicBool>,
+        shutting_down: Arc<AtomicBool>,
+        lan_only: bool,
+        #[cfg(feature = "test-hooks")] test_hooks: Arc<TestHooks>,
+    ) -> Self {
+        Self {
+            local_endpoint_id,
+            db,
+            transcode_status_cache,
+            hash_cache,
+
+            event_tx,
+            max_concurrent_transfers,
+            max_uploads_per_client,
+            max_upload_rate_per_client,
+            transfer_buffer_size,
+            pending_timeout,
+            pairing_tokens,
+            accept_incoming,
+            shutting_down,
+            lan_only,
+
+            #[cfg(feature = "test-hooks")]
+            test_hooks,
+        }
+    }
+}
+
+impl ProtocolHandler for Protocol {
+    async fn accept(&self, connection: iroh::endpoint::Connection) -> Result<(), AcceptError> {
+        let endpoint_id = connection.remote_id();
+        info!("accepted connection from {endpoint_id}");
+
+        if self.shutting_down.load(Ordering::Relaxed) {
+            info!("closing connection from {endpoint_id}: shutting down");
+            connection.close(0u32.into(), b"shutting down");
+            return Ok(());
+        }
+
+        // when not accepting incoming connections, only trusted nodes can connect
+        if !self.accept_incoming.load(Ordering::Relaxed) {
+            let is_trusted = {
+                let db = self.db.get();
+                db.is_node_trusted(endpoint_id)
+            };
+            match is_trusted {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!(
+                        "closing connection from {endpoint_id}: not accepting incoming connections"
+                    );
+                    connection.close(0u32.into(), b"not accepting connections");
+                    return Ok(());
+                }
+                Err(e) => {
+                    error!("failed to check if {endpoint_id} is trusted: {e:#}");
+                    connection.close(0u32.into(), b"not accepting connections");
+                    return Ok(());
+                }
+            }
+        }
+
+        if self.lan_only {
+            close_if_not_local(connection.clone());
+        }
+
+        // the upload limit per client applies on top of the limit per connection, and is
+        // advertised to the client so it doesn't open streams that would just wait
+        let transfer_limit = match self.max_uploads_per_client.load(Ordering::Relaxed) {
+            0 => *self.max_concurrent_transfers.borrow(),
+            max_uploads => max_uploads.min(*self.max_concurrent_transfers.borrow()),
+        };
+
+        let server = Server::new(
+            self.local_endpoint_id,
+            self.db.clone(),
+            self.transcode_status_cache.clone(),
+            self.hash_cache.clone(),
+            self.index_cache.clone(),
+            connection,
+            self.event_tx.clone(),
+            transfer_limit,
+            BandwidthLimiter::with_limit(self.max_upload_rate_per_client.clone()),
+            self.transfer_buffer_size.clone(),
+            *self.pending_timeout.lock().unwrap(),
+            self.pairing_tokens.clone(),
+            #[cfg(feature = "test-hooks")]
+            self.test_hooks.clone(),
+        );
+
+        let res = server.run().await;
+        if let Err(e) = &res {
+            error!("error during server.run(): {e:#}");
+        }
+        let expired = res
+            .as_ref()
+            .is_err_and(|e| e.chain().any(|e| e.is::<PendingExpired>()));
+
+        // notify node
+        self.event_tx
+            .send(NodeEvent::ServerClosed {
+                endpoint_id,
+                error: res.err().map(|e| format!("{e:#}")),
+                expired,
+            })
+            .expect("failed to send NodeEvent::ServerClosed");
+
+        Ok(())
+    }
+}
+
+/// A message sent by the client at the start of a file transfer stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TransferRequest {
+    job_id: u64,
+}
+
+/// A message sent by the client directly after a TransferRequest, in the same frame, with
+/// options for the transfer.
+///
+/// This is appended after the request instead of being a field, because older servers ignore
+/// trailing bytes and will still accept the request and send the whole file. Its presence also
+/// tells the server that the client understands TransferResponse::Verified.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct TransferOptions {
+    /// The partially downloaded file to resume, if any.
+    resume: Option<TransferResume>,
+}
+
+/// A message sent by the client directly after TransferOptions, in the same frame, to download a
+/// large file in several streams.
+///
+/// Only sent to servers that send ServerMessageV1::TransferParts, since older servers ignore it
+/// and send the whole file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum TransferRange {
+    /// Send the file from the start or the resumed offset as usual, but stop at this offset,
+    /// since the rest is downloaded in other streams.
+    Until(u64),
+    /// Send only this part of the file. The job's status is left to the stream that sends the
+    /// start of the file.
+    Part { start: u64, end: u64 },
+}
+
+/// A partially downloaded file the client wants to resume.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TransferResume {
+    /// Number of bytes the client already has.
+    offset: u64,
+    /// CRC-64 of the bytes the client already has, to check they match the file being sent.
+    prefix_crc: u64,
+}
 
 /// A message sent by the server in a file transfer stream in response to a TransfrRequest.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1446,8 +6004,41 @@ enum TransferResponse {
     Ok { file_size: u64 },
     /// The job was unable to be downloaded.
     Error { error: String },
+    /// The job is ready to be downloaded and will be sent by the server starting at the offset,
+    /// along with hashes the client uses to verify the received file.
+    ///
+    /// Only sent in response to a request with TransferOptions, so older clients never receive it.
+    Verified {
+        file_size: u64,
+        /// Offset the server will start sending from. Zero unless the client's TransferResume
+        /// matched the file.
+        offset: u64,
+        /// CRC-64 of the whole file being sent.
+        checksum: u64,
+        /// Hash of the original file, or None if it isn't known, e.g. for document trees.
+        source_hash: Option<ContentHash>,
+    },
+    /// The part of the file requested with TransferRange::Part will be sent by the server.
+    ///
+    /// Only sent in response to a request for a part, so older clients never receive it.
+    Part { file_size: u64 },
+    /// Like Verified, with a manifest of the file that the client keeps to verify it later.
+    ///
+    /// Only sent to clients that sent ClientMessageV1::TransferManifests, so older clients never
+    /// receive it.
+    Manifest {
+        file_size: u64,
+        offset: u64,
+        checksum: u64,
+        source_hash: Option<ContentHash>,
+        /// Tags and duration of the original file, or None if they haven't been read yet.
+        metadata: Option<ItemMetadata>,
+    },
 }
 
+/// CRC used to check partially downloaded files and verify received files.
+pub(crate) static FILE_CRC: crc::Crc<u64> = crc::Crc::<u64>::new(&crc::CRC_64_XZ);
+
 #[derive(Debug)]
 struct ServerTransferJob {
     progress: ServerTransferJobProgress,
@@ -1466,17 +6057,38 @@ enum ServerTransferJobProgress {
     Ready {
         transcode_path: PathBuf,
         file_size: u64,
+        checksum: FileChecksum,
+    },
+    /// The server is ready to send the original file from a document tree.
+    ReadyDocument {
+        document_path: TreePath,
+        file_size: u64,
+        checksum: FileChecksum,
     },
     /// The server has started sending the file.
     InProgress {
         started_at: u64,
         file_size: u64,
         sent: Arc<AtomicU64>,
+        /// The file being sent, for streams that send other parts of it.
+        serve_file: ServeFile,
     },
     /// The server has finished sending the file.
     Finished { finished_at: u64, file_size: u64 },
     /// The server failed to send the file.
-    Failed { error: anyhow::Error },
+    Failed {
+        error: anyhow::Error,
+        reason: TransferErrorReasonModel,
+    },
+}
+
+/// The file sent for a ready job.
+#[derive(Debug, Clone)]
+enum ServeFile {
+    /// A file on the filesystem, either the original or a transcode.
+    Path(PathBuf),
+    /// An original file in a document tree.
+    Document(TreePath),
 }
 
 #[derive(Debug)]
@@ -1484,12 +6096,22 @@ enum ServerCommand {
     Accept,
 
     Close,
+    /// Stop starting new transfers, and close once the active ones finish or the timeout passes.
+    Drain {
+        timeout: Duration,
+    },
 
     /// Send a message to the client.
     ///
     /// This is sort of a hack, but it's used by the task that watches for
     /// finished transcodes to send JobStatus messages to the client.
     ServerMessage(ServerMessageV1),
+
+    /// Ask the client to download items, if it's trusted.
+    Push(Vec<PushItem>),
+
+    /// Send the client the changes to its index, e.g. after a rescan.
+    RefreshIndex,
 }
 
 #[derive(Debug, Clone)]
@@ -1499,32 +6121,188 @@ struct ServerHandle {
     jobs: Arc<DashMap<u64, ServerTransferJob>>,
 }
 
-struct Server {
-    db: Arc<Mutex<Database>>,
-    transcode_status_cache: TranscodeStatusCache,
-    hash_cache: HashCache,
+/// A file in the [`IndexCache`], along with what's cached about it.
+#[derive(Debug)]
+struct IndexCacheFile {
+    endpoint_id: EndpointId,
+    root: String,
+    path: String,
+    local_path: PathBuf,
+
+    /// Cached duration of the original file.
+    duration: Option<f64>,
+    /// Cached size of the original file.
+    file_size: Option<u64>,
+    content_hash: Option<ContentHash>,
+    metadata: Option<ItemMetadata>,
+    extra_metadata: Option<ItemExtraMetadata>,
+}
 
-    connection: Connection,
-    event_tx: mpsc::UnboundedSender<NodeEvent>,
+/// The files sent in indexes, read from the database once and shared by all server connections,
+/// so accepting another client doesn't read every file again.
+///
+/// The files are read again the next time they're needed after they change in the database, e.g.
+/// when a scan finishes or more hashes are computed.
+#[derive(Debug, Default)]
+struct IndexCache {
+    /// The files, and the files version of the database that they were read at.
+    cached: Mutex<Option<(u64, Arc<Vec<IndexCacheFile>>)>>,
+}
 
-    connected_at: u64,
+impl IndexCache {
+    /// Gets the files, reading them from the database if they changed since they were cached.
+    fn get(
+        &self,
+        db: &DatabasePool,
+        hash_cache: &HashCache,
+        local_endpoint_id: EndpointId,
+    ) -> anyhow::Result<Arc<Vec<IndexCacheFile>>> {
+        // get the version before reading, so changes made while reading make the files stale
+        let version = db.files_version();
+        if let Some((cached_version, files)) = &*self.cached.lock().unwrap()
+            && *cached_version == version
+        {
+            return Ok(files.clone());
+        }
+
+        let files = Arc::new(Self::read(db, hash_cache, local_endpoint_id)?);
+
+        let mut cached = self.cached.lock().unwrap();
+        if cached
+            .as_ref()
+            .is_none_or(|(cached_version, _)| *cached_version < version)
+        {
+            *cached = Some((version, files.clone()));
+        }
+
+        Ok(files)
+    }
+
+    fn read(
+        db: &DatabasePool,
+        hash_cache: &HashCache,
+        local_endpoint_id: EndpointId,
+    ) -> anyhow::Result<Vec<IndexCacheFile>> {
+        let (files, mut tracks) = {
+            let db = db.get();
+            (
+                db.get_files()?,
+                db.get_tracks_by_node_id(local_endpoint_id)?,
+            )
+        };
+
+        let files = files
+            .into_iter()
+            .map(|file| {
+                let track = tracks.remove(&file.id);
+                let local_path = PathBuf::from(file.local_path);
+
+                // Get cached duration and size without checking validity. Validating them
+                // requires accessing the file to read its metadata, which can be expensive. We
+                // want this to be fast since it's on the user's critical path. We can tolerate the
+                // estimated sizes very rarely being incorrect.
+                let duration = hash_cache
+                    .get_cached_duration_unvalidated(&local_path)
+                    .ok()
+                    .flatten();
+                let file_size = hash_cache
+                    .get_cached_file_size_unvalidated(&local_path)
+                    .ok()
+                    .flatten();
+
+                // Get cached hash without accessing the file, like the durations above. Files in
+                // document trees aren't tracked by the hash cache.
+                let content_hash = if file.local_tree.is_empty() {
+                    match hash_cache.get_cached_hash_unvalidated(&local_path) {
+                        Ok(Some((kind, hash))) => Some(ContentHash {
+                            kind: kind.into_owned(),
+                            hash,
+                        }),
+                        _ => None,
+                    }
+                } else {
+                    None
+                };
+
+                // Tracks are built from the cached tags after each scan, so this doesn't access
+                // the file either.
+                let extra_metadata = track.as_ref().and_then(item_extra_metadata);
+                let metadata = item_metadata(track, duration);
+
+                IndexCacheFile {
+                    endpoint_id: file.node_id,
+                    root: file.root,
+                    path: file.path,
+                    local_path,
+
+                    duration,
+                    file_size,
+                    content_hash,
+                    metadata,
+                    extra_metadata,
+                }
+            })
+            .collect();
+
+        Ok(files)
+    }
+}
+
+struct Server {
+    local_endpoint_id: EndpointId,
+    db: Arc<DatabasePool>,
+    transcode_status_cache: TranscodeStatusCache,
+    hash_cache: HashCache,
+    index_cache: Arc<IndexCache>,
+
+    connection: Connection,
+    event_tx: mpsc::UnboundedSender<NodeEvent>,
+
+    connected_at: u64,
 
     jobs: Arc<DashMap<u64, ServerTransferJob>>,
+
+    /// Maximum number of files sent at once on this connection, advertised to the client.
+    transfer_limit: u32,
+    transfer_slots: Arc<Semaphore>,
+    /// Upload rate limit for this connection, shared by all of its transfers.
+    upload_bandwidth: Arc<BandwidthLimiter>,
+    /// Size of the chunks that files are sent in.
+    transfer_buffer_size: Arc<AtomicU32>,
+    /// Whether the client understands TransferResponse::Manifest.
+    transfer_manifests: Arc<AtomicBool>,
+    /// How long to wait for the user to accept the connection, or None to wait forever.
+    pending_timeout: Option<Duration>,
+
+    pairing_tokens: PairingTokens,
+
+    #[cfg(feature = "test-hooks")]
+    test_hooks: Arc<TestHooks>,
 }
 
 impl Server {
     fn new(
-        db: Arc<Mutex<Database>>,
+        local_endpoint_id: EndpointId,
+        db: Arc<DatabasePool>,
         transcode_status_cache: TranscodeStatusCache,
         hash_cache: HashCache,
+        index_cache: Arc<IndexCache>,
 
         connection: Connection,
         event_tx: mpsc::UnboundedSender<NodeEvent>,
+        transfer_limit: u32,
+        upload_bandwidth: BandwidthLimiter,
+        transfer_buffer_size: Arc<AtomicU32>,
+        pending_timeout: Option<Duration>,
+        pairing_tokens: PairingTokens,
+        #[cfg(feature = "test-hooks")] test_hooks: Arc<TestHooks>,
     ) -> Self {
         Self {
+            local_endpoint_id,
             db,
             transcode_status_cache,
             hash_cache,
+            index_cache,
 
             connection,
             event_tx,
@@ -1532,7 +6310,44 @@ impl Server {
             connected_at: unix_epoch_now_secs(),
 
             jobs: Arc::new(DashMap::new()),
+
+            transfer_limit,
+            transfer_slots: Arc::new(Semaphore::new(transfer_limit as usize)),
+            upload_bandwidth: Arc::new(upload_bandwidth),
+            transfer_buffer_size,
+            transfer_manifests: Arc::new(AtomicBool::new(false)),
+            pending_timeout,
+
+            pairing_tokens,
+
+            #[cfg(feature = "test-hooks")]
+            test_hooks,
+        }
+    }
+
+    /// Closes a connection that timed out, and fails its unfinished jobs so the model doesn't
+    /// show them as active.
+    fn close_timed_out(&self, remote_endpoint_id: EndpointId) {
+        self.connection.close(0u32.into(), b"timed out");
+
+        for mut job in self.jobs.iter_mut() {
+            if !matches!(
+                job.progress,
+                ServerTransferJobProgress::Finished { .. }
+                    | ServerTransferJobProgress::Failed { .. }
+            ) {
+                job.progress = ServerTransferJobProgress::Failed {
+                    error: anyhow::anyhow!("connection timed out"),
+                    reason: TransferErrorReasonModel::Network,
+                };
+            }
         }
+        self.event_tx
+            .send(NodeEvent::ServerChanged {
+                endpoint_id: remote_endpoint_id,
+                update: ServerModelUpdate::UpdateTransferJobs,
+            })
+            .expect("failed to send ServerModelUpdate::UpdateTransferJobs");
     }
 
     async fn run(self) -> anyhow::Result<()> {
@@ -1540,32 +6355,15 @@ impl Server {
 
         // spawn connection info watcher task
         // TODO: check that this is cancelled/cleaned up correctly?
-        tokio::spawn({
-            let connection = self.connection.clone();
+        spawn_connection_info_watcher(self.connection.clone(), {
             let event_tx = self.event_tx.clone();
-            async move {
-                let mut paths_stream = connection.paths_stream();
-                while let Some(paths) = paths_stream.next().await {
-                    let selected_path = paths.iter().find(|path| path.is_selected());
-                    let (remote_addr, rtt_ms) = match selected_path {
-                        Some(selected_path) => {
-                            let remote_addr = selected_path.remote_addr().to_string();
-                            let rtt_ms = selected_path.rtt().as_millis() as u64;
-                            (remote_addr, Some(rtt_ms))
-                        }
-                        None => ("unknown".to_string(), None),
-                    };
-
-                    event_tx
-                        .send(NodeEvent::ServerChanged {
-                            endpoint_id: remote_endpoint_id,
-                            update: ServerModelUpdate::UpdateConnectionInfo {
-                                remote_addr,
-                                rtt_ms,
-                            },
-                        })
-                        .expect("failed to send ServerModelUpdate::UpdateConnectionInfo");
-                }
+            move |info| {
+                event_tx
+                    .send(NodeEvent::ServerChanged {
+                        endpoint_id: remote_endpoint_id,
+                        update: ServerModelUpdate::UpdateConnectionInfo(info),
+                    })
+                    .expect("failed to send ServerModelUpdate::UpdateConnectionInfo");
             }
         });
 
@@ -1582,20 +6380,25 @@ impl Server {
                 futures::stream::once(futures::future::ready(Ok(Bytes::from(buf))))
             },
         );
-        let mut recv = FramedRead::new(recv, LengthDelimitedCodec::new())
-            .map_err(|e| anyhow::anyhow!("failed to read from connection: {e:?}"))
-            .map(|res| {
-                res.and_then(|bytes| {
-                    postcard::from_bytes::<ClientMessageV1>(&bytes)
-                        .map_err(|e| anyhow::anyhow!("failed to deserialize message: {e:?}"))
-                })
-            });
+        let mut frames = FramedRead::new(recv, LengthDelimitedCodec::new());
 
-        // wait for client Identify
-        let Some(Ok(message)) = recv.next().await else {
+        // wait for client Identify, followed by options in the same frame from newer clients
+        let Some(Ok(identify_buf)) = frames.next().await else {
             error!("failed to receive Identify message");
             return Ok(());
         };
+        let (message, identify_rest) =
+            match postcard::take_from_bytes::<ClientMessageV1>(&identify_buf) {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("failed to deserialize Identify message: {e:?}");
+                    return Ok(());
+                }
+            };
+        let identify_options = IdentifyOptions::from_bytes(identify_rest).unwrap_or_else(|e| {
+            warn!("failed to deserialize identify options: {e:?}");
+            IdentifyOptions::default()
+        });
         let (client_name, transcode_format) = match message {
             ClientMessageV1::Identify {
                 name,
@@ -1606,6 +6409,17 @@ impl Server {
                 return Ok(());
             }
         };
+        // prefer the format in the identify options, which can be newer than the fallback
+        let transcode_format = identify_options.transcode_format.or(transcode_format);
+
+        let mut recv = frames
+            .map_err(|e| anyhow::anyhow!("failed to read from connection: {e:?}"))
+            .map(|res| {
+                res.and_then(|bytes| {
+                    postcard::from_bytes::<ClientMessageV1>(&bytes)
+                        .map_err(|e| anyhow::anyhow!("failed to deserialize message: {e:?}"))
+                })
+            });
 
         // send server Identify
         send.send(ServerMessageV1::Identify(device_name().to_string()))
@@ -1628,28 +6442,75 @@ impl Server {
             })
             .expect("failed to send NodeEvent::ServerOpened");
 
+        // learn about sync group members from the client, which can make the client trusted.
+        // the client hasn't been accepted yet, so limit how many records it can make us verify
+        let mut group_records = identify_options.group_records;
+        if group_records.len() > sync_group::MAX_UNACCEPTED_RECORDS {
+            warn!(
+                "client {remote_endpoint_id} sent {} sync group records, only merging the first {}",
+                group_records.len(),
+                sync_group::MAX_UNACCEPTED_RECORDS
+            );
+            group_records.truncate(sync_group::MAX_UNACCEPTED_RECORDS);
+        }
+        let groups_merged = {
+            let db = self.db.get();
+            sync_group::merge_records(&db, self.local_endpoint_id, group_records)
+        };
+        match groups_merged {
+            Ok(outcome) => {
+                if outcome.changed {
+                    self.event_tx
+                        .send(NodeEvent::SyncGroupsChanged)
+                        .expect("failed to send NodeEvent::SyncGroupsChanged");
+                }
+                if !outcome.invitations.is_empty() {
+                    self.event_tx
+                        .send(NodeEvent::SyncGroupsInvited(outcome.invitations))
+                        .expect("failed to send NodeEvent::SyncGroupsInvited");
+                }
+            }
+            Err(e) => error!("failed to merge sync group records from {remote_endpoint_id}: {e:#}"),
+        }
+
         // check if remote node is trusted
         let is_trusted = {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
             db.is_node_trusted(remote_endpoint_id)?
         };
 
+        // check if the client has an unused token from a pairing ticket, and use it up
+        let is_paired = identify_options
+            .pairing_token
+            .is_some_and(|token| self.pairing_tokens.lock().unwrap().remove(&token));
+
         if is_trusted {
             info!("accepting connection from trusted node {remote_endpoint_id}");
+        } else if is_paired {
+            info!("accepting connection from node {remote_endpoint_id} with pairing token");
         } else {
             // waiting loop, wait for user to accept or deny the connection
             info!(
                 "waiting for accept or deny of connection from untrusted node {remote_endpoint_id}",
             );
+            let expire = tokio::time::sleep(self.pending_timeout.unwrap_or_default());
+            tokio::pin!(expire);
             loop {
                 tokio::select! {
+                    () = &mut expire, if self.pending_timeout.is_some() => {
+                        info!("connection from {remote_endpoint_id} wasn't accepted in time, closing");
+                        self.connection.close(0u32.into(), b"expired");
+                        return Err(PendingExpired(self.pending_timeout.unwrap_or_default()).into());
+                    }
+
                     Some(command) = rx.recv() => {
                         match command {
                             ServerCommand::Accept => {
                                 // continue to next state
                                 break;
                             },
-                            ServerCommand::Close => {
+                            ServerCommand::Close | ServerCommand::Drain { .. } => {
+                                // nothing to drain before the connection is accepted
                                 self.connection.close(0u32.into(), b"close");
                                 return Ok(());
                             },
@@ -1658,6 +6519,12 @@ impl Server {
                                     .await
                                     .expect("failed to send ServerMessageV1");
                             }
+                            ServerCommand::Push(_) => {
+                                warn!("ignoring push to client that hasn't been accepted");
+                            }
+                            ServerCommand::RefreshIndex => {
+                                // the index is sent after accepting, so it'll be up to date
+                            }
                         }
                     }
 
@@ -1696,16 +6563,95 @@ impl Server {
             .await
             .expect("failed to send Accepted message");
 
-        // send Index message
-        let index = self.get_index(transcode_format)?;
-        info!(index.len = index.len(), "sending ServerMessageV1::Index");
-        send.send(ServerMessageV1::Index(index))
+        let (index, index_hashes, index_metadata, index_extra_metadata) =
+            self.get_index(remote_endpoint_id, transcode_format)?;
+
+        // remember what the client has, so only the changes are sent after a rescan
+        let mut sent_index = SentIndex::default();
+        diff_index(
+            &mut sent_index,
+            index.clone(),
+            index_hashes.clone(),
+            index_metadata.clone(),
+            index_extra_metadata.clone(),
+        );
+
+        if identify_options.paged_index {
+            // send IndexPage messages, so huge indexes don't have to fit in one message
+            info!(
+                index.len = index.len(),
+                "sending ServerMessageV1::IndexPage"
+            );
+            let total = index.len() as u64;
+            let mut offset = 0;
+            for (((items, hashes), metadata), extra_metadata) in index
+                .chunks(INDEX_PAGE_SIZE)
+                .zip(index_hashes.chunks(INDEX_PAGE_SIZE))
+                .zip(index_metadata.chunks(INDEX_PAGE_SIZE))
+                .zip(index_extra_metadata.chunks(INDEX_PAGE_SIZE))
+            {
+                let page = IndexPageItems {
+                    items: items.to_vec(),
+                    hashes: hashes.to_vec(),
+                    metadata: metadata.to_vec(),
+                    extra_metadata: extra_metadata.to_vec(),
+                }
+                .compress(offset, total)?;
+                send.send(ServerMessageV1::IndexPage(page))
+                    .await
+                    .expect("failed to send IndexPage message");
+                offset += items.len() as u64;
+            }
+
+            // an empty index still needs a page so the client knows it's complete
+            if index.is_empty() {
+                let page = IndexPageItems::default().compress(0, 0)?;
+                send.send(ServerMessageV1::IndexPage(page))
+                    .await
+                    .expect("failed to send IndexPage message");
+            }
+        } else {
+            // send Index message
+            info!(index.len = index.len(), "sending ServerMessageV1::Index");
+            send.send(ServerMessageV1::Index(index))
+                .await
+                .expect("failed to send Index message");
+
+            // send IndexHashes message, so the client can skip files it already has
+            send.send(ServerMessageV1::IndexHashes(index_hashes))
+                .await
+                .expect("failed to send IndexHashes message");
+
+            // send IndexMetadata message, so the client can browse by artist and album
+            send.send(ServerMessageV1::IndexMetadata(index_metadata))
+                .await
+                .expect("failed to send IndexMetadata message");
+        }
+
+        // send TransferLimit message, so the client doesn't open more transfer streams than we serve at once
+        send.send(ServerMessageV1::TransferLimit(self.transfer_limit))
+            .await
+            .expect("failed to send TransferLimit message");
+
+        // send TransferParts message, so the client can download large files in parallel streams
+        send.send(ServerMessageV1::TransferParts)
             .await
-            .expect("failed to send Index message");
+            .expect("failed to send TransferParts message");
+
+        // send GroupRecords message to members of our sync groups, so they learn about each other
+        let group_records = {
+            let db = self.db.get();
+            sync_group::shared_records(&db, remote_endpoint_id)?
+        };
+        if !group_records.is_empty() {
+            send.send(ServerMessageV1::GroupRecords(group_records))
+                .await
+                .expect("failed to send GroupRecords message");
+        }
 
         // update name and connected_at for trusted nodes
         {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
             db.update_trusted_node(remote_endpoint_id, &client_name, self.connected_at)
                 .context("failed to update trusted node in database")?;
         }
@@ -1774,11 +6720,13 @@ impl Server {
                                 TranscodeStatus::Ready {
                                     transcode_path,
                                     file_size,
+                                    checksum,
                                 } => {
                                     ready_jobs.push((
                                         *job.key(),
                                         transcode_path.clone(),
                                         *file_size,
+                                        checksum.clone(),
                                     ));
                                 }
 
@@ -1788,7 +6736,7 @@ impl Server {
 
                                     failed_jobs.push((
                                         *job.key(),
-                                        anyhow::anyhow!("transcoding failed: {error}"),
+                                        anyhow::anyhow!("{TRANSCODING_FAILED_ERROR}: {error}"),
                                     ));
                                 }
                             }
@@ -1798,23 +6746,23 @@ impl Server {
                     let check_elapsed = iter_start.elapsed();
 
                     // create status changes for ready jobs
-                    let ready_jobs =
-                        ready_jobs
-                            .into_iter()
-                            .map(|(job_id, transcode_path, file_size)| {
-                                // set job status to Ready
-                                // needs to happen outside the loop, since jobs.iter() already holds the entry's lock
-                                jobs.alter(&job_id, |_, mut job| {
-                                    job.progress = ServerTransferJobProgress::Ready {
-                                        transcode_path,
-                                        file_size,
-                                    };
-                                    job
-                                });
-
-                                (job_id, JobStatusItem::Ready { file_size })
+                    let ready_jobs = ready_jobs.into_iter().map(
+                        |(job_id, transcode_path, file_size, checksum)| {
+                            // set job status to Ready
+                            // needs to happen outside the loop, since jobs.iter() already holds the entry's lock
+                            jobs.alter(&job_id, |_, mut job| {
+                                job.progress = ServerTransferJobProgress::Ready {
+                                    transcode_path,
+                                    file_size,
+                                    checksum,
+                                };
+                                job
                             });
 
+                            (job_id, JobStatusItem::Ready { file_size })
+                        },
+                    );
+
                     // create status changes for failed jobs
                     let failed_jobs = failed_jobs.into_iter().map(|(job_id, error)| {
                         let error_string = format!("{error}");
@@ -1822,7 +6770,10 @@ impl Server {
                         // set job status to Failed
                         // needs to happen outside the loop, since jobs.iter() already holds the entry's lock
                         jobs.alter(&job_id, |_, mut job| {
-                            job.progress = ServerTransferJobProgress::Failed { error };
+                            job.progress = ServerTransferJobProgress::Failed {
+                                error,
+                                reason: TransferErrorReasonModel::DecodeFailed,
+                            };
                             job
                         });
 
@@ -1876,6 +6827,18 @@ impl Server {
         // When tracking transferred files we indicate whether it's the first of this session.
         let is_first_transfer = Arc::new(AtomicBool::new(true));
 
+        // while draining, new downloads and transfers are refused, and the connection closes once
+        // the active transfers finish or the deadline passes
+        let draining = Arc::new(AtomicBool::new(false));
+        let mut drain_deadline: Option<Instant> = None;
+        let mut drain_check = tokio::time::interval(DRAIN_CHECK_INTERVAL);
+
+        // ping the client periodically, and close the connection if a client that pings goes quiet
+        let mut keep_alive = KeepAlive::new(
+            #[cfg(feature = "test-hooks")]
+            self.test_hooks.clone(),
+        );
+
         // main loop
         loop {
             tokio::select! {
@@ -1888,31 +6851,274 @@ impl Server {
                             self.connection.close(0u32.into(), b"close");
                             break;
                         },
+                        ServerCommand::Drain { timeout } => {
+                            if drain_deadline.is_some() {
+                                continue;
+                            }
+                            info!("draining connection to {remote_endpoint_id}");
+                            draining.store(true, Ordering::Relaxed);
+                            drain_deadline = Some(Instant::now() + timeout);
+
+                            self.event_tx.send(NodeEvent::ServerChanged {
+                                endpoint_id: remote_endpoint_id,
+                                update: ServerModelUpdate::Drain,
+                            }).expect("failed to send ServerModelUpdate::Drain");
+                        }
                         ServerCommand::ServerMessage(message) => {
                             send.send(message)
                                 .await
                                 .expect("failed to send ServerMessage");
                         }
+                        ServerCommand::Push(items) => {
+                            if drain_deadline.is_some() {
+                                warn!("ignoring push while draining");
+                                continue;
+                            }
+
+                            // only push files to trusted nodes
+                            let is_trusted = {
+                                let db = self.db.get();
+                                db.is_node_trusted(remote_endpoint_id)?
+                            };
+                            if !is_trusted {
+                                warn!("ignoring push to untrusted node {remote_endpoint_id}");
+                                continue;
+                            }
+
+                            // don't push files the client's share filter excludes
+                            let share_filter = {
+                                let db = self.db.get();
+                                db.get_share_filter(remote_endpoint_id)?
+                            };
+                            let items = match share_filter {
+                                Some(share_filter) => {
+                                    let files = {
+                                        let db = self.db.get();
+                                        db.get_files_by_node_root_path(
+                                            items.iter().map(|item| (item.endpoint_id, item.root.clone(), item.path.clone()))
+                                        )?
+                                    };
+                                    let allowed = files
+                                        .into_iter()
+                                        .filter(|f| self.share_filter_allows(&share_filter, f))
+                                        .map(|f| (f.node_id, f.root, f.path))
+                                        .collect::<HashSet<_>>();
+                                    items
+                                        .into_iter()
+                                        .filter(|item| allowed.contains(&(item.endpoint_id, item.root.clone(), item.path.clone())))
+                                        .collect::<Vec<_>>()
+                                }
+                                None => items,
+                            };
+                            if items.is_empty() {
+                                continue;
+                            }
+
+                            info!("pushing {} items to client", items.len());
+                            send.send(ServerMessageV1::Push(items))
+                                .await
+                                .expect("failed to send Push message");
+                        }
+                        ServerCommand::RefreshIndex => {
+                            let (index, index_hashes, index_metadata, index_extra_metadata) = match self.get_index(remote_endpoint_id, transcode_format) {
+                                Ok(index) => index,
+                                Err(e) => {
+                                    error!("failed to get index: {e:#}");
+                                    continue;
+                                }
+                            };
+                            let changes = diff_index(&mut sent_index, index, index_hashes, index_metadata, index_extra_metadata);
+                            if changes.items.is_empty() && changes.removed.is_empty() {
+                                continue;
+                            }
+
+                            info!("sending {} changed and {} removed index items", changes.items.len(), changes.removed.len());
+                            for mut changes in changes.chunks(INDEX_PAGE_SIZE) {
+                                // sent separately, since older clients fail to deserialize it
+                                if changes.extra_metadata.iter().any(Option::is_some) {
+                                    let extra_metadata = std::mem::take(&mut changes.extra_metadata);
+                                    send.send(ServerMessageV1::IndexChangesExtraMetadata(extra_metadata))
+                                        .await
+                                        .expect("failed to send IndexChangesExtraMetadata message");
+                                }
+                                send.send(ServerMessageV1::IndexChanges(changes))
+                                    .await
+                                    .expect("failed to send IndexChanges message");
+                            }
+                        }
                     }
                 }
 
                 next_message = recv.next() => {
                     match next_message {
                         Some(Ok(message)) => {
+                            keep_alive.received(matches!(message, ClientMessageV1::Ping));
                             match message {
                                 ClientMessageV1::Identify { .. } => {
                                     warn!("unexpected ClientMessageV1::Identify in main loop");
                                 }
 
+                                ClientMessageV1::Ping => {}
+
+                                ClientMessageV1::TransferManifests => {
+                                    self.transfer_manifests.store(true, Ordering::Relaxed);
+                                }
+
+                                ClientMessageV1::SyncBack { conflict_policy } => {
+                                    // only let trusted nodes make us download files
+                                    let is_trusted = {
+                                        let db = self.db.get();
+                                        db.is_node_trusted(remote_endpoint_id)?
+                                    };
+                                    if !is_trusted {
+                                        warn!("ignoring two-way sync request from untrusted node {remote_endpoint_id}");
+                                        continue;
+                                    }
+
+                                    self.event_tx.send(NodeEvent::SyncBackRequested {
+                                        endpoint_id: remote_endpoint_id,
+                                        conflict_policy,
+                                    }).expect("failed to send NodeEvent::SyncBackRequested");
+                                }
+
+                                ClientMessageV1::Rescan => {
+                                    // only let trusted nodes make us scan the library
+                                    let is_trusted = {
+                                        let db = self.db.get();
+                                        db.is_node_trusted(remote_endpoint_id)?
+                                    };
+                                    if !is_trusted {
+                                        warn!("ignoring rescan request from untrusted node {remote_endpoint_id}");
+                                        continue;
+                                    }
+
+                                    self.event_tx.send(NodeEvent::RescanRequested {
+                                        endpoint_id: remote_endpoint_id,
+                                    }).expect("failed to send NodeEvent::RescanRequested");
+                                }
+
+                                ClientMessageV1::PrioritizeTranscodes(job_ids) => {
+                                    let Some(transcode_format) = transcode_format else {
+                                        continue;
+                                    };
+
+                                    // only jobs that are still waiting for their transcodes can be moved up
+                                    let paths = job_ids.iter()
+                                        .filter_map(|job_id| {
+                                            let job = self.jobs.get(job_id)?;
+                                            match &job.progress {
+                                                ServerTransferJobProgress::Transcoding { local_path } => Some(local_path.clone()),
+                                                _ => None,
+                                            }
+                                        })
+                                        .collect::<HashSet<_>>();
+                                    if !paths.is_empty() {
+                                        self.event_tx.send(NodeEvent::FilesPrioritized(transcode_format, paths)).expect("failed to send NodeEvent::FilesPrioritized");
+                                    }
+                                }
+
+                                ClientMessageV1::Download(items) if drain_deadline.is_some() => {
+                                    // refuse new jobs while draining
+                                    let status_changes = items.into_iter().map(|item| {
+                                        self.jobs.insert(item.job_id, ServerTransferJob {
+                                            progress: ServerTransferJobProgress::Failed {
+                                                error: anyhow::anyhow!(SERVER_CLOSING_ERROR),
+                                                reason: TransferErrorReasonModel::Network,
+                                            },
+                                            file_endpoint_id: item.endpoint_id,
+                                            file_root: item.root,
+                                            file_path: item.path,
+                                        });
+
+                                        (item.job_id, JobStatusItem::Failed {
+                                            error: SERVER_CLOSING_ERROR.to_string(),
+                                        })
+                                    }).collect::<HashMap<_, _>>();
+
+                                    send.send(ServerMessageV1::JobStatus(status_changes))
+                                        .await
+                                        .expect("failed to send JobStatus message");
+
+                                    self.event_tx.send(NodeEvent::ServerChanged {
+                                        endpoint_id: remote_endpoint_id,
+                                        update: ServerModelUpdate::UpdateTransferJobs,
+                                    }).expect("failed to send ServerModelUpdate::UpdateTransferJobs");
+                                }
+
                                 ClientMessageV1::Download(items) => {
                                     // get file local paths
                                     // TODO: this could be better
-                                    let files = {
-                                        let db = self.db.lock().expect("failed to lock database");
-                                        db.get_files_by_node_root_path(
-                                            items.iter().map(|item| (item.endpoint_id, item.root.clone(), item.path.clone()))
-                                        )?.into_iter().map(|f| ((f.node_id, f.root.clone(), f.path.clone()), f)).collect::<HashMap<_, _>>()
+                                    // look them up on the blocking thread pool, so waiting for a database
+                                    // connection doesn't block the runtime
+                                    let lookup = {
+                                        let db = self.db.clone();
+                                        let keys = items.iter().map(|item| (item.endpoint_id, item.root.clone(), item.path.clone())).collect::<Vec<_>>();
+                                        tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+                                            let db = db.get();
+                                            let shares = db.get_node_shares(remote_endpoint_id)?;
+                                            let files = db.get_files_by_node_root_path(keys.into_iter())?
+                                                .into_iter()
+                                                // files that aren't shared with the client are treated as not found
+                                                .filter(|f| shares.is_empty() || shares.iter().any(|share| share.contains(&f.root, &f.path)))
+                                                .collect::<Vec<_>>();
+                                            Ok((files, db.get_share_filter(remote_endpoint_id)?))
+                                        })
+                                        .await
+                                        .context("failed to join lookup task")
+                                        .and_then(|res| res)
+                                    };
+                                    let (files, share_filter) = match lookup {
+                                        Ok(lookup) => lookup,
+                                        Err(e) => {
+                                            // reject the requested items, but keep serving the connection
+                                            error!("failed to look up requested files for {remote_endpoint_id}: {e:#}");
+
+                                            let status_changes = items.into_iter().map(|item| {
+                                                self.jobs.insert(item.job_id, ServerTransferJob {
+                                                    progress: ServerTransferJobProgress::Failed {
+                                                        error: anyhow::anyhow!("failed to look up file: {e:#}"),
+                                                        reason: TransferErrorReasonModel::Other,
+                                                    },
+                                                    file_endpoint_id: item.endpoint_id,
+                                                    file_root: item.root,
+                                                    file_path: item.path,
+                                                });
+
+                                                (item.job_id, JobStatusItem::Failed {
+                                                    error: format!("failed to look up file: {e:#}"),
+                                                })
+                                            }).collect::<HashMap<_, _>>();
+
+                                            send.send(ServerMessageV1::JobStatus(status_changes))
+                                                .await
+                                                .expect("failed to send JobStatus message");
+
+                                            self.event_tx.send(NodeEvent::ServerChanged {
+                                                endpoint_id: remote_endpoint_id,
+                                                update: ServerModelUpdate::UpdateTransferJobs,
+                                            }).expect("failed to send ServerModelUpdate::UpdateTransferJobs");
+
+                                            continue;
+                                        }
                                     };
+                                    // files excluded by the share filter are treated as not found too
+                                    let files = files
+                                        .into_iter()
+                                        .filter(|f| share_filter.as_ref().is_none_or(|share_filter| self.share_filter_allows(share_filter, f)))
+                                        .map(|f| ((f.node_id, f.root.clone(), f.path.clone()), f))
+                                        .collect::<HashMap<_, _>>();
+
+                                    // get files in document trees, which aren't tracked by the hash cache
+                                    let mut document_files = HashMap::new();
+                                    for file in files.values().filter(|f| !f.local_tree.is_empty()) {
+                                        let document = match TreePath::new(file.local_tree.clone(), PathBuf::from(&file.local_path)) {
+                                            Ok(document_path) => crate::fs::metadata(&document_path)
+                                                .await
+                                                .map(|metadata| (document_path, metadata.len)),
+                                            Err(e) => Err(e),
+                                        };
+                                        document_files.insert(file.id, document);
+                                    }
 
                                     let status_changes = items.into_iter().map(|item| {
                                         // TODO: wasteful clones
@@ -1921,17 +7127,63 @@ impl Server {
                                         // get file for requested item
                                         let Some(file) = file else {
                                             self.jobs.insert(item.job_id, ServerTransferJob {
-                                                progress: ServerTransferJobProgress::Failed { error: anyhow::anyhow!("file not found") },
+                                                progress: ServerTransferJobProgress::Failed {
+                                                    error: anyhow::anyhow!(REMOTE_FILE_NOT_FOUND_ERROR),
+                                                    reason: TransferErrorReasonModel::RemoteFileMissing,
+                                                },
                                                 file_endpoint_id: item.endpoint_id,
                                                 file_root: item.root,
                                                 file_path: item.path,
                                             });
 
                                             return (item.job_id, JobStatusItem::Failed {
-                                                error: "file not found".to_string(),
+                                                error: REMOTE_FILE_NOT_FOUND_ERROR.to_string(),
                                             });
                                         };
 
+                                        // files in document trees are sent as-is
+                                        if !file.local_tree.is_empty() {
+                                            let document = match document_files.get(&file.id) {
+                                                // TODO: support transcoding files in document trees
+                                                _ if transcode_format.is_some() => {
+                                                    Err("transcoding files in document trees is not supported".to_string())
+                                                }
+                                                Some(Ok((document_path, file_size))) => Ok((document_path.clone(), *file_size)),
+                                                Some(Err(e)) => Err(format!("{e:#}")),
+                                                None => Err("file not found".to_string()),
+                                            };
+
+                                            return match document {
+                                                Ok((document_path, file_size)) => {
+                                                    self.jobs.insert(item.job_id, ServerTransferJob {
+                                                        progress: ServerTransferJobProgress::ReadyDocument {
+                                                            document_path,
+                                                            file_size,
+                                                            checksum: FileChecksum::default(),
+                                                        },
+                                                        file_endpoint_id: item.endpoint_id,
+                                                        file_root: item.root,
+                                                        file_path: item.path,
+                                                    });
+
+                                                    (item.job_id, JobStatusItem::Ready { file_size })
+                                                }
+                                                Err(error) => {
+                                                    self.jobs.insert(item.job_id, ServerTransferJob {
+                                                        progress: ServerTransferJobProgress::Failed {
+                                                            error: anyhow::anyhow!(error.clone()),
+                                                            reason: TransferErrorReasonModel::Other,
+                                                        },
+                                                        file_endpoint_id: item.endpoint_id,
+                                                        file_root: item.root,
+                                                        file_path: item.path,
+                                                    });
+
+                                                    (item.job_id, JobStatusItem::Failed { error })
+                                                }
+                                            };
+                                        }
+
                                         let local_path = PathBuf::from(&file.local_path);
 
                                         // check for cached hash
@@ -1957,6 +7209,7 @@ impl Server {
                                                 progress: ServerTransferJobProgress::Ready {
                                                     transcode_path: local_path.clone(),
                                                     file_size: key.file_size(),
+                                                    checksum: FileChecksum::default(),
                                                 },
                                                 file_endpoint_id: item.endpoint_id,
                                                 file_root: item.root,
@@ -1988,7 +7241,7 @@ impl Server {
                                         let transcode_status = self.transcode_status_cache.get(transcode_format, &hash_kind, hash);
 
                                         match transcode_status.as_deref() {
-                                            Some(TranscodeStatus::Ready { transcode_path, file_size }) => {
+                                            Some(TranscodeStatus::Ready { transcode_path, file_size, checksum }) => {
                                                 // file is already transcoded
 
                                                 // create job
@@ -1996,6 +7249,7 @@ impl Server {
                                                     progress: ServerTransferJobProgress::Ready {
                                                         transcode_path: transcode_path.clone(),
                                                         file_size: *file_size,
+                                                        checksum: checksum.clone(),
                                                     },
                                                     file_endpoint_id: item.endpoint_id,
                                                     file_root: item.root,
@@ -2013,7 +7267,8 @@ impl Server {
                                                 // create job
                                                 self.jobs.insert(item.job_id, ServerTransferJob {
                                                     progress: ServerTransferJobProgress::Failed {
-                                                        error: anyhow::anyhow!("transcoding failed: {error}"),
+                                                        error: anyhow::anyhow!("{TRANSCODING_FAILED_ERROR}: {error}"),
+                                                        reason: TransferErrorReasonModel::DecodeFailed,
                                                     },
                                                     file_endpoint_id: item.endpoint_id,
                                                     file_root: item.root,
@@ -2021,7 +7276,7 @@ impl Server {
                                                 });
 
                                                 (item.job_id, JobStatusItem::Failed {
-                                                    error: format!("{error:#}"),
+                                                    error: format!("{TRANSCODING_FAILED_ERROR}: {error:#}"),
                                                 })
                                             }
 
@@ -2055,7 +7310,7 @@ impl Server {
 
                                     // prioritize transcodes
                                     if let Some(transcode_format) = transcode_format {
-                                        let requested_paths = files.into_values().map(|f| PathBuf::from(f.local_path)).collect::<HashSet<_>>();
+                                        let requested_paths = files.into_values().filter(|f| f.local_tree.is_empty()).map(|f| PathBuf::from(f.local_path)).collect::<HashSet<_>>();
                                         self.event_tx.send(NodeEvent::FilesRequested(transcode_format, requested_paths)).expect("failed to send NodeEvent::FilesRequested");
                                     }
                                 }
@@ -2075,9 +7330,18 @@ impl Server {
                 accept_result = self.connection.accept_bi() => {
                     match accept_result {
                         Ok((mut send, mut recv)) => {
+                            let db = self.db.clone();
+                            let hash_cache = self.hash_cache.clone();
                             let jobs = self.jobs.clone();
+                            let transfer_slots = self.transfer_slots.clone();
+                            let upload_bandwidth = self.upload_bandwidth.clone();
+                            let chunk_size = self.transfer_buffer_size.load(Ordering::Relaxed) as usize;
+                            let transfer_manifests = self.transfer_manifests.clone();
                             let event_tx = self.event_tx.clone();
                             let is_first_transfer = is_first_transfer.clone();
+                            let draining = draining.clone();
+                            #[cfg(feature = "test-hooks")]
+                            let test_hooks = self.test_hooks.clone();
                             tokio::spawn(async move {
                                 // receive transfer request with job id
                                 let transfer_req_len = recv.read_u32().await?;
@@ -2086,222 +7350,1459 @@ impl Server {
                                     .read_exact(&mut transfer_req_buf)
                                     .await
                                     .context("failed to read transfer request")?;
-                                let transfer_req: TransferRequest =
-                                    postcard::from_bytes(&transfer_req_buf).context("failed to deserialize transfer request")?;
+                                let (transfer_req, transfer_req_rest): (TransferRequest, _) =
+                                    postcard::take_from_bytes(&transfer_req_buf).context("failed to deserialize transfer request")?;
+                                let (transfer_options, transfer_range) = if transfer_req_rest.is_empty() {
+                                    (None, None)
+                                } else {
+                                    let (transfer_options, transfer_options_rest): (TransferOptions, _) =
+                                        postcard::take_from_bytes(transfer_req_rest).context("failed to deserialize transfer options")?;
+                                    let transfer_range: Option<TransferRange> = if transfer_options_rest.is_empty() {
+                                        None
+                                    } else {
+                                        Some(postcard::from_bytes(transfer_options_rest).context("failed to deserialize transfer range")?)
+                                    };
+                                    (Some(transfer_options), transfer_range)
+                                };
+
+                                // parts of a file belong to the transfer that sends its start
+                                if let Some(TransferRange::Part { start, end }) = transfer_range {
+                                    let draining = draining.load(Ordering::Relaxed);
+                                    return serve_part(&jobs, transfer_req.job_id, start, end, send, &transfer_slots, draining, &upload_bandwidth, chunk_size).await;
+                                }
+
+                                // check job status
+                                let (ready, file_key) = {
+                                    let Some(job) = jobs.get(&transfer_req.job_id) else {
+                                        anyhow::bail!("transfer request job id not found: {}", transfer_req.job_id);
+                                    };
+
+                                    let file_key = (job.file_endpoint_id, job.file_root.clone(), job.file_path.clone());
+                                    let ready = match &job.progress {
+                                        ServerTransferJobProgress::Ready { transcode_path, file_size, checksum } => {
+                                            Some((ServeFile::Path(transcode_path.clone()), *file_size, checksum.clone()))
+                                        }
+                                        ServerTransferJobProgress::ReadyDocument { document_path, file_size, checksum } => {
+                                            Some((ServeFile::Document(document_path.clone()), *file_size, checksum.clone()))
+                                        }
+                                        _ => None,
+                                    };
+                                    (ready, file_key)
+                                };
+
+                                // don't start new transfers while draining
+                                let draining = draining.load(Ordering::Relaxed);
+                                let ready = if draining { None } else { ready };
+
+                                // wait for a transfer slot, in case the client opens more streams than we allow
+                                let _transfer_slot = match &ready {
+                                    Some(_) => Some(transfer_slots.acquire_owned().await?),
+                                    None => None,
+                                };
+
+                                // checksum the file for clients that verify it, and the part
+                                // the client has if it's resuming. the file is streamed, so it's
+                                // never held in memory
+                                let transfer_resume = transfer_options.as_ref().and_then(|options| options.resume.as_ref());
+                                let ready = match ready {
+                                    Some((serve_file, file_size, checksum)) => {
+                                        let checksums = async {
+                                            // check local file exists
+                                            if let ServeFile::Path(transcode_path) = &serve_file && !transcode_path.exists() {
+                                                return Err(anyhow::anyhow!(REMOTE_FILE_NOT_FOUND_ERROR)
+                                                    .context(format!("file at transcode_path does not exist: {}", transcode_path.display())));
+                                            }
+
+                                            match &transfer_options {
+                                                Some(_) => Ok(Some(serve_file_checksums(&serve_file, &checksum, transfer_resume.map(|resume| resume.offset)).await?)),
+                                                None => Ok(None),
+                                            }
+                                        }.await;
+                                        let checksums = match checksums {
+                                            Ok(checksums) => checksums,
+                                            Err(e) => {
+                                                warn!("failed to prepare file for job {}: {e:#}", transfer_req.job_id);
+
+                                                // tell the client why, instead of dropping the stream
+                                                let error = if e.root_cause().to_string() == REMOTE_FILE_NOT_FOUND_ERROR {
+                                                    REMOTE_FILE_NOT_FOUND_ERROR.to_string()
+                                                } else {
+                                                    format!("{e:#}")
+                                                };
+                                                let transfer_res = TransferResponse::Error { error };
+                                                let transfer_res_buf = postcard::to_stdvec(&transfer_res)
+                                                    .context("failed to serialize transfer response")?;
+                                                send.write_u32(transfer_res_buf.len() as u32)
+                                                    .await
+                                                    .context("failed to write transfer response length")?;
+                                                send.write_all(&transfer_res_buf)
+                                                    .await
+                                                    .context("failed to write transfer response")?;
+
+                                                // set job status to Failed
+                                                jobs.alter(&transfer_req.job_id, |_, mut job| {
+                                                    job.progress = ServerTransferJobProgress::Failed {
+                                                        reason: TransferErrorReasonModel::from_error(&e),
+                                                        error: e,
+                                                    };
+                                                    job
+                                                });
+
+                                                // update model
+                                                event_tx.send(NodeEvent::ServerChanged {
+                                                    endpoint_id: remote_endpoint_id,
+                                                    update: ServerModelUpdate::UpdateTransferJobs,
+                                                }).expect("failed to send ServerModelUpdate::UpdateTransferJobs");
+
+                                                return Ok(());
+                                            }
+                                        };
+                                        #[cfg(feature = "test-hooks")]
+                                        let checksums = match checksums {
+                                            Some((checksum, prefix_checksum)) if test_hooks.corrupt_checksums() => Some((!checksum, prefix_checksum)),
+                                            checksums => checksums,
+                                        };
+                                        Some((checksums, file_size, serve_file))
+                                    }
+                                    None => None,
+                                };
+
+                                // look up the original file on the blocking thread pool, since the
+                                // lookups can wait for a database connection and read the file's metadata
+                                let transfer_manifests = transfer_manifests.load(Ordering::Relaxed);
+                                let with_metadata = transfer_options.is_some() && transfer_manifests;
+                                let (metadata, source_hash) = match &ready {
+                                    Some(_) => {
+                                        let db = db.clone();
+                                        let hash_cache = hash_cache.clone();
+                                        tokio::task::spawn_blocking(move || {
+                                            // look up the tags of the original file for clients that keep manifests
+                                            let metadata = if with_metadata {
+                                                get_source_metadata(&db, &hash_cache, file_key.clone())
+                                            } else {
+                                                None
+                                            };
+
+                                            // look up the hash of the original file, so the client can tell if it
+                                            // changed and we can remember that the client has it
+                                            let source_hash = get_source_hash(&db, &hash_cache, file_key);
+
+                                            (metadata, source_hash)
+                                        })
+                                        .await
+                                        .context("failed to look up original file")?
+                                    }
+                                    None => (None, None),
+                                };
+
+                                // resume from the client's offset if its partial file matches ours
+                                let offset = match (&ready, transfer_resume) {
+                                    (Some((Some((_, prefix_checksum)), _, _)), Some(resume)) => {
+                                        if *prefix_checksum == Some(resume.prefix_crc) {
+                                            resume.offset
+                                        } else {
+                                            debug!("partial file for job {} doesn't match, sending whole file", transfer_req.job_id);
+                                            0
+                                        }
+                                    }
+                                    _ => 0,
+                                };
+
+                                let transfer_res = match &ready {
+                                    Some((Some((checksum, _)), file_size, _)) if transfer_manifests => TransferResponse::Manifest {
+                                        file_size: *file_size,
+                                        offset,
+                                        checksum: *checksum,
+                                        source_hash: source_hash.clone(),
+                                        metadata,
+                                    },
+                                    Some((Some((checksum, _)), file_size, _)) => TransferResponse::Verified {
+                                        file_size: *file_size,
+                                        offset,
+                                        checksum: *checksum,
+                                        source_hash: source_hash.clone(),
+                                    },
+                                    Some((_, file_size, _)) => TransferResponse::Ok { file_size: *file_size },
+                                    None if draining => TransferResponse::Error { error: SERVER_CLOSING_ERROR.to_string() },
+                                    None => TransferResponse::Error { error: "job not ready".to_string() },
+                                };
+
+                                // send transfer response
+                                let transfer_res_buf = postcard::to_stdvec(&transfer_res)
+                                    .context("failed to serialize transfer response")?;
+                                send.write_u32(transfer_res_buf.len() as u32)
+                                    .await
+                                    .context("failed to write transfer response length")?;
+                                send.write_all(&transfer_res_buf)
+                                    .await
+                                    .context("failed to write transfer response")?;
+
+                                // TODO: could maybe be nicer
+                                let Some((_, file_size, serve_file)) = ready else {
+                                    return Ok(());
+                                };
+                                let mut file = open_serve_file(&serve_file, offset).await?;
+
+                                // start the counter at the offset so progress includes the resumed bytes
+                                let sent_counter = Arc::new(AtomicU64::new(offset));
+
+                                // set job status to InProgress
+                                jobs.alter(&transfer_req.job_id, |_, mut job| {
+                                    job.progress = ServerTransferJobProgress::InProgress {
+                                        started_at: unix_epoch_now_secs(),
+                                        file_size,
+                                        sent: sent_counter.clone(),
+                                        serve_file,
+                                    };
+                                    job
+                                });
+
+                                // update model
+                                event_tx.send(NodeEvent::ServerChanged {
+                                    endpoint_id: remote_endpoint_id,
+                                    update: ServerModelUpdate::UpdateTransferJobs,
+                                }).expect("failed to send ServerModelUpdate::UpdateTransferJobs");
+
+                                // TODO: handle errors during send
+                                // stop early if the client downloads the rest of the file in other streams
+                                let end = match transfer_range {
+                                    Some(TransferRange::Until(end)) => end.clamp(offset, file_size),
+                                    _ => file_size,
+                                };
+
+                                let send_res = send_file_stream(
+                                    &mut send,
+                                    &mut file,
+                                    end - offset,
+                                    chunk_size,
+                                    &upload_bandwidth,
+                                    &sent_counter,
+                                ).await;
+                                if let Err(e) = send_res {
+                                    // the client stops the stream if it cancels or abandons the job,
+                                    // or if it already has the file
+                                    let unchanged = stopped_with(&e, TRANSFER_UNCHANGED_ERROR_CODE);
+                                    jobs.alter(&transfer_req.job_id, |_, mut job| {
+                                        job.progress = if unchanged {
+                                            ServerTransferJobProgress::Finished { finished_at: unix_epoch_now_secs(), file_size }
+                                        } else {
+                                            let error = e.context("failed to send file");
+                                            ServerTransferJobProgress::Failed {
+                                                reason: TransferErrorReasonModel::from_error(&error),
+                                                error,
+                                            }
+                                        };
+                                        job
+                                    });
+
+                                    // update model
+                                    event_tx.send(NodeEvent::ServerChanged {
+                                        endpoint_id: remote_endpoint_id,
+                                        update: ServerModelUpdate::UpdateTransferJobs,
+                                    }).expect("failed to send ServerModelUpdate::UpdateTransferJobs");
+
+                                    return Ok(());
+                                }
+
+                                // set job status to Finished
+                                jobs.alter(&transfer_req.job_id, |_, mut job| {
+                                    job.progress = ServerTransferJobProgress::Finished { finished_at: unix_epoch_now_secs(), file_size };
+                                    job
+                                });
+
+                                // update model
+                                event_tx.send(NodeEvent::ServerChanged {
+                                    endpoint_id: remote_endpoint_id,
+                                    update: ServerModelUpdate::UpdateTransferJobs,
+                                }).expect("failed to send ServerModelUpdate::UpdateTransferJobs");
+
+                                let is_first_transfer = is_first_transfer.swap(false, Ordering::SeqCst);
+                                let _ = event_tx.send(NodeEvent::ServerTransferCompleted {
+                                    endpoint_id: remote_endpoint_id,
+                                    bytes: file_size - offset,
+                                    is_first_transfer,
+                                    source_hash,
+                                });
+
+                                Ok::<(), anyhow::Error>(())
+                            });
+                        }
+
+                        Err(e) => {
+                            error!("accept_bi error: {e}");
+                        }
+                    }
+                }
+
+                res = keep_alive.tick() => {
+                    if let Err(e) = res {
+                        warn!("connection to {remote_endpoint_id} timed out");
+                        self.close_timed_out(remote_endpoint_id);
+                        return Err(e.into());
+                    }
+
+                    keep_alive.try_ping(&mut send, ServerMessageV1::Ping);
+                }
+
+                _ = drain_check.tick(), if drain_deadline.is_some() => {
+                    let active = self.jobs.iter().any(|job| {
+                        matches!(job.progress, ServerTransferJobProgress::InProgress { .. })
+                    });
+                    let timed_out = drain_deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                    if !active || timed_out {
+                        if active {
+                            warn!("drain timed out with active transfers, closing connection to {remote_endpoint_id}");
+                        }
+                        self.connection.close(0u32.into(), b"close");
+                        break;
+                    }
+                }
+
+                else => {
+                    warn!("all senders dropped in Server::run, shutting down");
+                    break;
+                }
+            }
+        }
+
+        self.connection.closed().await;
+
+        Ok(())
+    }
+
+    /// Whether a local file passes the client's share filter. The original file's cached size and
+    /// duration are checked, so files that haven't been scanned yet are only checked by format.
+    fn share_filter_allows(&self, share_filter: &ShareFilter, file: &File) -> bool {
+        let local_path = Path::new(&file.local_path);
+        let file_size = self
+            .hash_cache
+            .get_cached_file_size_unvalidated(local_path)
+            .ok()
+            .flatten();
+        let duration = self
+            .hash_cache
+            .get_cached_duration_unvalidated(local_path)
+            .ok()
+            .flatten();
+        share_filter.allows(&file.path, file_size, duration)
+    }
+
+    /// Gets the index to send to the client, along with the cached content hashes and metadata of
+    /// its items.
+    ///
+    /// Only includes files shared with the client, if any shares are set for it, that pass its
+    /// share filter.
+    #[tracing::instrument(skip(self))]
+    fn get_index(
+        &self,
+        remote_endpoint_id: EndpointId,
+        transcode_format: Option<TranscodeFormat>,
+    ) -> anyhow::Result<(
+        Vec<IndexItem>,
+        Vec<Option<ContentHash>>,
+        Vec<Option<ItemMetadata>>,
+        Vec<Option<ItemExtraMetadata>>,
+    )> {
+        let (shares, share_filter) = {
+            let db = self.db.get();
+            (
+                db.get_node_shares(remote_endpoint_id)?,
+                db.get_share_filter(remote_endpoint_id)?,
+            )
+        };
+        let files = self
+            .index_cache
+            .get(&self.db, &self.hash_cache, self.local_endpoint_id)?;
+
+        let (index, index_hashes, index_metadata, index_extra_metadata) = files
+            .iter()
+            .filter(|file| {
+                shares.is_empty()
+                    || shares
+                        .iter()
+                        .any(|share| share.contains(&file.root, &file.path))
+            })
+            .filter(|file| {
+                share_filter.as_ref().is_none_or(|share_filter| {
+                    share_filter.allows(&file.path, file.file_size, file.duration)
+                })
+            })
+            .map(|file| {
+                let file_size = if let Some(transcode_format) = transcode_format {
+                    match file.duration {
+                        Some(duration) => {
+                            FileSize::Estimated(estimate_file_size(transcode_format, duration))
+                        }
+                        // When we don't have a cached duration, we still want to provide a guess
+                        // since we display Unknown as 0 on mobile.
+                        _ => FileSize::Estimated(estimate_file_size_without_duration(
+                            transcode_format,
+                        )),
+                    }
+                } else {
+                    match file.file_size {
+                        // This could be stale if the files were modified.
+                        Some(size) => FileSize::Estimated(size),
+                        // When we don't have a cached duration, we still want to provide a guess
+                        // since we display Unknown as 0 on mobile.
+                        _ => FileSize::Estimated(estimate_original_file_size(&file.local_path)),
+                    }
+                };
+
+                let item = IndexItem {
+                    endpoint_id: file.endpoint_id,
+                    root: file.root.clone(),
+                    path: file.path.clone(),
+
+                    file_size,
+                };
+                (
+                    item,
+                    file.content_hash.clone(),
+                    file.metadata.clone(),
+                    file.extra_metadata.clone(),
+                )
+            })
+            .multiunzip();
+
+        Ok((index, index_hashes, index_metadata, index_extra_metadata))
+    }
+}
+
+#[derive(Debug)]
+struct ClientTransferJob {
+    progress: ClientTransferJobProgress,
+    /// Set by the user to pause, resume, or cancel the job.
+    control: watch::Sender<JobControl>,
+    /// Whether the job was skipped by the ready queue while paused, and needs to be requeued when
+    /// it's resumed.
+    deferred: bool,
+    /// How a collision with an existing file was resolved, if there was one.
+    collision: Option<CollisionOutcomeModel>,
+    /// Path the file was downloaded to, relative to the download directory, if its path on the
+    /// server isn't valid on this platform.
+    sanitized_path: Option<String>,
+    /// Ready jobs with a higher priority start first. Jobs with the same priority start in the
+    /// order they became ready.
+    priority: u64,
+
+    file_endpoint_id: EndpointId,
+    file_root: String,
+    file_path: String,
+}
+
+#[derive(Debug)]
+enum ClientTransferJobProgress {
+    /// The client sent the request and is waiting for its status.
+    Requested,
+    /// The client is waiting for the file to be transcoded.
+    Transcoding,
+    /// The client is ready to download the file.
+    Ready { file_size: u64 },
+    /// The client has started downloading the file.
+    InProgress {
+        started_at: u64,
+        file_size: u64,
+        written: Arc<AtomicU64>,
+    },
+    /// The client has finished downloading the file.
+    Finished { finished_at: u64, file_size: u64 },
+    /// The client failed to download the file.
+    Failed {
+        error: String,
+        reason: TransferErrorReasonModel,
+    },
+}
+
+/// Per-job control state set by the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobControl {
+    Run,
+    Pause,
+    Cancel,
+}
+
+/// Adds the progress of a server's download jobs to the combined progress.
+fn add_download_progress(progress: &mut DownloadProgressModel, client_handle: &ClientHandle) {
+    if client_handle.jobs.is_empty() {
+        return;
+    }
+    progress.servers += 1;
+
+    for job in client_handle.jobs.iter() {
+        match &job.progress {
+            ClientTransferJobProgress::Requested | ClientTransferJobProgress::Transcoding => {
+                progress.queued_jobs += 1;
+            }
+            ClientTransferJobProgress::Ready { file_size } => {
+                progress.queued_jobs += 1;
+                progress.total_bytes += file_size;
+            }
+            ClientTransferJobProgress::InProgress {
+                file_size, written, ..
+            } => {
+                progress.active_jobs += 1;
+                progress.total_bytes += file_size;
+                progress.downloaded_bytes += written.load(Ordering::Relaxed);
+            }
+            ClientTransferJobProgress::Finished { file_size, .. } => {
+                progress.finished_jobs += 1;
+                progress.total_bytes += file_size;
+                progress.downloaded_bytes += file_size;
+            }
+            ClientTransferJobProgress::Failed { .. } => {
+                progress.failed_jobs += 1;
+            }
+        }
+    }
+}
+
+/// Takes the queued job with the highest priority, or the one that became ready first if there's
+/// a tie. `queued` must not be empty.
+fn take_next_job(jobs: &DashMap<u64, ClientTransferJob>, queued: &mut Vec<u64>) -> u64 {
+    let priority = |job_id: &u64| jobs.get(job_id).map_or(0, |job| job.priority);
+    let index = queued
+        .iter()
+        .enumerate()
+        .max_by_key(|(index, job_id)| (priority(job_id), std::cmp::Reverse(*index)))
+        .map_or(0, |(index, _)| index);
+    queued.remove(index)
+}
+
+/// Default maximum number of concurrent file transfers per connection.
+pub(crate) const DEFAULT_MAX_CONCURRENT_TRANSFERS: u32 = 4;
+
+/// Upper bound for the configurable number of concurrent file transfers per connection.
+pub(crate) const MAX_CONCURRENT_TRANSFERS: u32 = 32;
+
+/// Download slots shared by all clients, to limit the number of concurrent downloads across all
+/// connections.
+#[derive(Debug)]
+struct DownloadSlots {
+    /// Maximum number of slots in use at once, or 0 for no limit.
+    limit: watch::Sender<u32>,
+    /// Number of slots in use.
+    active: watch::Sender<u32>,
+}
+
+impl DownloadSlots {
+    fn new() -> Self {
+        Self {
+            limit: watch::Sender::new(0),
+            active: watch::Sender::new(0),
+        }
+    }
+
+    /// Sets the maximum number of slots in use at once, or 0 for no limit. Lowering the limit lets
+    /// active downloads finish instead of interrupting them.
+    fn set_limit(&self, limit: u32) {
+        self.limit.send_replace(limit);
+    }
+
+    /// Waits for a free slot and takes it. The slot is freed when it's dropped.
+    async fn acquire(self: &Arc<Self>) -> DownloadSlot {
+        let mut limit_rx = self.limit.subscribe();
+        let mut active_rx = self.active.subscribe();
+        loop {
+            let limit = *limit_rx.borrow_and_update();
+            active_rx.borrow_and_update();
+
+            let acquired = self.active.send_if_modified(|active| {
+                if limit == 0 || *active < limit {
+                    *active += 1;
+                    true
+                } else {
+                    false
+                }
+            });
+            if acquired {
+                return DownloadSlot(self.clone());
+            }
+
+            // wait for a slot to be freed or the limit to change
+            tokio::select! {
+                _ = limit_rx.changed() => {}
+                _ = active_rx.changed() => {}
+            }
+        }
+    }
+}
+
+/// A slot taken from DownloadSlots, freed when dropped.
+#[derive(Debug)]
+struct DownloadSlot(Arc<DownloadSlots>);
+
+impl Drop for DownloadSlot {
+    fn drop(&mut self) {
+        self.0.active.send_modify(|active| *active -= 1);
+    }
+}
+
+/// Reads the file sent for a job to get its checksum, and the checksum of its first `prefix_len`
+/// bytes if it has that many, so the file can be verified and resumed without reading it into
+/// memory.
+///
+/// The checksum of the whole file is cached in `checksum`, so once it's known, only the prefix is
+/// read.
+async fn serve_file_checksums(
+    serve_file: &ServeFile,
+    checksum: &FileChecksum,
+    prefix_len: Option<u64>,
+) -> anyhow::Result<(u64, Option<u64>)> {
+    if let Some(checksum) = checksum.get() {
+        let prefix_checksum = match prefix_len {
+            Some(prefix_len) => serve_file_prefix_checksum(serve_file, prefix_len).await?,
+            None => None,
+        };
+        return Ok((*checksum, prefix_checksum));
+    }
+
+    let mut file = open_serve_file(serve_file, 0).await?;
+    let mut digest = FILE_CRC.digest();
+    let mut prefix_checksum = None;
+    let mut position = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await.context("failed to read file")?;
+        if let Some(prefix_len) = prefix_len
+            && prefix_checksum.is_none()
+            && position + n as u64 >= prefix_len
+        {
+            let split = (prefix_len - position) as usize;
+            digest.update(&buf[..split]);
+            prefix_checksum = Some(digest.clone().finalize());
+            digest.update(&buf[split..n]);
+        } else {
+            digest.update(&buf[..n]);
+        }
+        if n == 0 {
+            break;
+        }
+        position += n as u64;
+    }
+    let file_checksum = digest.finalize();
+    let _ = checksum.set(file_checksum);
+    Ok((file_checksum, prefix_checksum))
+}
+
+/// Reads the first `prefix_len` bytes of the file sent for a job to get their checksum, or None if
+/// the file is shorter.
+async fn serve_file_prefix_checksum(
+    serve_file: &ServeFile,
+    prefix_len: u64,
+) -> anyhow::Result<Option<u64>> {
+    let mut file = open_serve_file(serve_file, 0).await?;
+    let mut digest = FILE_CRC.digest();
+    let mut remaining = prefix_len;
+    let mut buf = vec![0; 64 * 1024];
+    while remaining > 0 {
+        let len = remaining.min(buf.len() as u64) as usize;
+        let n = file
+            .read(&mut buf[..len])
+            .await
+            .context("failed to read file")?;
+        if n == 0 {
+            return Ok(None);
+        }
+        digest.update(&buf[..n]);
+        remaining -= n as u64;
+    }
+    Ok(Some(digest.finalize()))
+}
+
+/// Sends part of a file for a job that the client downloads in parallel streams.
+///
+/// Parts don't change the job's status, since they belong to the stream that sends the start of
+/// the file. They still take a transfer slot, and while draining, only parts of transfers that
+/// already started are sent.
+#[allow(clippy::too_many_arguments)]
+async fn serve_part(
+    jobs: &DashMap<u64, ServerTransferJob>,
+    job_id: u64,
+    start: u64,
+    end: u64,
+    mut send: SendStream,
+    transfer_slots: &Semaphore,
+    draining: bool,
+    upload_bandwidth: &BandwidthLimiter,
+    chunk_size: usize,
+) -> anyhow::Result<()> {
+    let ready = jobs.get(&job_id).and_then(|job| match &job.progress {
+        ServerTransferJobProgress::Ready {
+            transcode_path,
+            file_size,
+            ..
+        } if !draining => Some((ServeFile::Path(transcode_path.clone()), *file_size, None)),
+        ServerTransferJobProgress::ReadyDocument {
+            document_path,
+            file_size,
+            ..
+        } if !draining => Some((ServeFile::Document(document_path.clone()), *file_size, None)),
+        ServerTransferJobProgress::InProgress {
+            serve_file,
+            file_size,
+            sent,
+            ..
+        } => Some((serve_file.clone(), *file_size, Some(sent.clone()))),
+        _ => None,
+    });
+    let ready = ready.filter(|(_, file_size, _)| start <= end && end <= *file_size);
+
+    // wait for a transfer slot, like the stream that sends the start of the file
+    let _transfer_slot = match &ready {
+        Some(_) => Some(transfer_slots.acquire().await?),
+        None => None,
+    };
+    let ready = match ready {
+        Some((serve_file, file_size, sent)) => {
+            let file = open_serve_file(&serve_file, start).await?;
+            Some((file, file_size, sent))
+        }
+        None => None,
+    };
+
+    let transfer_res = match &ready {
+        Some((_, file_size, _)) => TransferResponse::Part {
+            file_size: *file_size,
+        },
+        None if draining => TransferResponse::Error {
+            error: SERVER_CLOSING_ERROR.to_string(),
+        },
+        None => TransferResponse::Error {
+            error: "part not available".to_string(),
+        },
+    };
+    let transfer_res_buf =
+        postcard::to_stdvec(&transfer_res).context("failed to serialize transfer response")?;
+    send.write_u32(transfer_res_buf.len() as u32)
+        .await
+        .context("failed to write transfer response length")?;
+    send.write_all(&transfer_res_buf)
+        .await
+        .context("failed to write transfer response")?;
+
+    let Some((mut file, _, sent)) = ready else {
+        return Ok(());
+    };
+
+    // count the part in the job's progress if it has started
+    send_file_stream(
+        &mut send,
+        &mut file,
+        end - start,
+        chunk_size,
+        upload_bandwidth,
+        &sent.unwrap_or_default(),
+    )
+    .await
+    .context("failed to send part")?;
+
+    Ok(())
+}
+
+/// Opens the file sent for a job, positioned at the given offset.
+async fn open_serve_file(
+    serve_file: &ServeFile,
+    position: u64,
+) -> anyhow::Result<Pin<Box<dyn AsyncRead + Send>>> {
+    match serve_file {
+        ServeFile::Path(transcode_path) => {
+            let mut file = tokio::fs::File::open(transcode_path).await?;
+            file.seek(std::io::SeekFrom::Start(position)).await?;
+            Ok(Box::pin(file))
+        }
+        ServeFile::Document(document_path) => {
+            let mut file = TreeFile::open(document_path, OpenMode::Read).await?;
+            file.seek(position).await?;
+            Ok(Box::pin(file))
+        }
+    }
+}
+
+/// Reads `len` bytes from a file and sends them in chunks, applying the upload rate limit and
+/// counting sent bytes.
+async fn send_file_stream(
+    send: &mut SendStream,
+    file: &mut (impl AsyncRead + Unpin + ?Sized),
+    len: u64,
+    chunk_size: usize,
+    upload_bandwidth: &BandwidthLimiter,
+    sent: &AtomicU64,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0; chunk_size.max(1)];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk_len = (remaining as usize).min(buf.len());
+        file.read_exact(&mut buf[..chunk_len])
+            .await
+            .context("failed to read file")?;
+        upload_bandwidth.consume(chunk_len as u64).await;
+        send.write_all(&buf[..chunk_len]).await?;
+        sent.fetch_add(chunk_len as u64, Ordering::Relaxed);
+        remaining -= chunk_len as u64;
+    }
+    Ok(())
+}
+
+/// Default size of the buffers that files are sent and received in. Uploads are sent in chunks
+/// of this size, so the upload rate limit can be applied.
+const DEFAULT_TRANSFER_BUFFER_SIZE: u32 = 256 * 1024;
+
+/// Lower bound for the configurable transfer buffer size.
+const MIN_TRANSFER_BUFFER_SIZE: u32 = 16 * 1024;
+
+/// Upper bound for the configurable transfer buffer size.
+const MAX_TRANSFER_BUFFER_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Rate limit shared by a set of transfers, e.g. all downloads or all uploads to one client.
+///
+/// Each chunk reserves time in proportion to its size, and waits until the time reserved before it
+/// has passed, so the combined rate of all transfers stays under the limit.
+#[derive(Debug)]
+struct BandwidthLimiter {
+    /// Maximum rate in bytes per second, or 0 for no limit.
+    limit: Arc<AtomicU64>,
+    /// Time when the next chunk can be consumed.
+    next: Mutex<Instant>,
+}
+
+impl BandwidthLimiter {
+    fn new() -> Self {
+        Self::with_limit(Arc::new(AtomicU64::new(0)))
+    }
+
+    /// Creates a limiter that reads its limit from a shared setting, so several limiters can have
+    /// the same limit while tracking their rates separately.
+    fn with_limit(limit: Arc<AtomicU64>) -> Self {
+        Self {
+            limit,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Sets the maximum rate in bytes per second, or 0 for no limit.
+    fn set_limit(&self, limit: u64) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    /// Returns the maximum rate in bytes per second, or 0 for no limit.
+    fn limit(&self) -> u64 {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Waits until `bytes` more bytes can be consumed without exceeding the limit.
+    async fn consume(&self, bytes: u64) {
+        let limit = self.limit.load(Ordering::Relaxed);
+        if limit == 0 {
+            return;
+        }
+
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = (*next).max(Instant::now());
+            *next = start + Duration::from_secs_f64(bytes as f64 / limit as f64);
+            start
+        };
+        tokio::time::sleep_until(start.into()).await;
+    }
+}
+
+/// Whether an address is on the local network, i.e. loopback, private, or link-local.
+fn is_local_addr(addr: &SocketAddr) -> bool {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+/// Returns the address with only its direct addresses on the local network, for LAN-only mode.
+fn local_endpoint_addr(addr: &EndpointAddr) -> EndpointAddr {
+    addr.ip_addrs()
+        .filter(|ip_addr| is_local_addr(ip_addr))
+        .fold(EndpointAddr::new(addr.id), |local_addr, ip_addr| {
+            local_addr.with_ip_addr(*ip_addr)
+        })
+}
+
+/// Interval for refreshing connection info, so latency changes show up while the selected path
+/// stays the same.
+const CONNECTION_INFO_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Details of the selected path of a connection.
+#[derive(Debug)]
+struct ConnectionInfo {
+    /// "direct", "relay", or "unknown" if no path is selected.
+    connection_type: String,
+    remote_addr: String,
+    rtt_ms: Option<u64>,
+}
+
+impl ConnectionInfo {
+    fn from_paths(paths: &PathInfoList) -> Self {
+        match paths.iter().find(|path| path.is_selected()) {
+            Some(selected_path) => {
+                let connection_type = match selected_path.remote_addr() {
+                    TransportAddr::Ip(_) => "direct",
+                    _ => "relay",
+                };
+                ConnectionInfo {
+                    connection_type: connection_type.to_string(),
+                    remote_addr: selected_path.remote_addr().to_string(),
+                    rtt_ms: Some(selected_path.rtt().as_millis() as u64),
+                }
+            }
+            None => ConnectionInfo {
+                connection_type: "unknown".to_string(),
+                remote_addr: String::new(),
+                rtt_ms: None,
+            },
+        }
+    }
+}
+
+/// Spawns a task that reports the connection info when the selected path changes, and
+/// periodically in between, until the connection closes.
+fn spawn_connection_info_watcher(
+    connection: Connection,
+    on_update: impl Fn(ConnectionInfo) + Send + 'static,
+) {
+    tokio::spawn(async move {
+        let mut paths_stream = connection.paths_stream();
+        let mut interval = tokio::time::interval(CONNECTION_INFO_UPDATE_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        let mut paths = None;
+        loop {
+            tokio::select! {
+                next = paths_stream.next() => {
+                    let Some(next) = next else {
+                        break;
+                    };
+                    paths = Some(next);
+                }
+                _ = interval.tick() => {}
+            }
+
+            if let Some(paths) = &paths {
+                on_update(ConnectionInfo::from_paths(paths));
+            }
+        }
+    });
+}
+
+/// Spawns a task that closes the connection if it switches to a path outside the local network,
+/// for LAN-only mode.
+fn close_if_not_local(connection: Connection) {
+    tokio::spawn(async move {
+        let remote_endpoint_id = connection.remote_id();
+        let mut paths_stream = connection.paths_stream();
+        while let Some(paths) = paths_stream.next().await {
+            let Some(selected_path) = paths.iter().find(|path| path.is_selected()) else {
+                continue;
+            };
+            let is_local = match selected_path.remote_addr() {
+                TransportAddr::Ip(addr) => is_local_addr(addr),
+                _ => false,
+            };
+            if !is_local {
+                warn!(
+                    "closing connection to {remote_endpoint_id}: path {} is outside the local network",
+                    selected_path.remote_addr()
+                );
+                connection.close(0u32.into(), b"not on local network");
+                break;
+            }
+        }
+    });
+}
+
+/// Returns the number of downloads a client should keep active, which is the lower of its own
+/// setting and the limit advertised by the server.
+fn effective_transfer_limit(local_limit: u32, remote_limit: Option<u32>) -> usize {
+    let limit = match remote_limit {
+        Some(remote_limit) => local_limit.min(remote_limit),
+        None => local_limit,
+    };
+    limit.max(1) as usize
+}
+
+/// Upper bound for the configurable number of streams used to download each large file.
+const MAX_PARALLEL_STREAMS: u32 = 8;
+
+/// Minimum size of each part when downloading a file in parallel streams, so small files use a
+/// single stream.
+const PARALLEL_STREAM_MIN_PART_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Splits the rest of a file after `offset` into up to `streams` parts of similar size, each at
+/// least PARALLEL_STREAM_MIN_PART_SIZE bytes unless there's only one.
+fn split_file_parts(offset: u64, file_size: u64, streams: u32) -> Vec<(u64, u64)> {
+    let remaining = file_size.saturating_sub(offset);
+    let count = (remaining / PARALLEL_STREAM_MIN_PART_SIZE).clamp(1, u64::from(streams.max(1)));
+    let part_size = remaining.div_ceil(count);
+    (0..count)
+        .map(|index| {
+            let start = offset + index * part_size;
+            (start, (start + part_size).min(file_size))
+        })
+        .collect()
+}
+
+/// A part of a file being downloaded in its own stream.
+struct FilePart {
+    start: u64,
+    end: u64,
+    task: JoinHandle<anyhow::Result<Option<u64>>>,
+}
+
+impl Drop for FilePart {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Downloads part of a file in its own stream, writing it to the partial file at its offset.
+///
+/// Returns None if the job was cancelled.
+#[allow(clippy::too_many_arguments)]
+async fn download_file_part(
+    connection: Connection,
+    job_id: u64,
+    start: u64,
+    end: u64,
+    temp_path: TreePath,
+    written: Arc<AtomicU64>,
+    mut control: watch::Receiver<JobControl>,
+    bandwidth: Arc<BandwidthLimiter>,
+    buffer_size: usize,
+) -> anyhow::Result<Option<u64>> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+
+    // send transfer request with job id, followed by the transfer options and the part's range
+    let transfer_req_buf = postcard::to_stdvec(&TransferRequest { job_id })
+        .context("failed to serialize transfer request")?;
+    let transfer_req_buf = postcard::to_extend(&TransferOptions::default(), transfer_req_buf)
+        .context("failed to serialize transfer options")?;
+    let transfer_req_buf =
+        postcard::to_extend(&TransferRange::Part { start, end }, transfer_req_buf)
+            .context("failed to serialize transfer range")?;
+    send.write_u32(transfer_req_buf.len() as u32)
+        .await
+        .context("failed to write transfer request length")?;
+    send.write_all(&transfer_req_buf)
+        .await
+        .context("failed to write transfer request")?;
+
+    // receive transfer response
+    let transfer_res_len = recv.read_u32().await?;
+    let mut transfer_res_buf = vec![0; transfer_res_len as usize];
+    recv.read_exact(&mut transfer_res_buf)
+        .await
+        .context("failed to read transfer response")?;
+    let transfer_res: TransferResponse = postcard::from_bytes(&transfer_res_buf)
+        .context("failed to deserialize transfer response")?;
+    match transfer_res {
+        TransferResponse::Part { file_size } => {
+            anyhow::ensure!(end <= file_size, "part ends past end of file");
+        }
+        TransferResponse::Error { error } => anyhow::bail!("failed to download part: {error}"),
+        _ => anyhow::bail!("server sent a whole file in response to a part request"),
+    }
+
+    // write the part in place, next to the bytes written by the other streams
+    let mut file = TreeFile::open(&temp_path, OpenMode::Update)
+        .await
+        .context("failed to open partial file for part")?;
+    file.seek(start)
+        .await
+        .context("failed to seek to part in partial file")?;
+
+    let len = end - start;
+    let mut part_progress = WriteProgress::new(written, file);
+    let Some(copied) = copy_with_control(
+        &mut (&mut recv).take(len),
+        &mut part_progress,
+        &mut control,
+        &bandwidth,
+        buffer_size,
+        |_| {},
+    )
+    .await?
+    else {
+        let _ = recv.stop(TRANSFER_CANCELLED_ERROR_CODE.into());
+        return Ok(None);
+    };
+    anyhow::ensure!(
+        copied == len,
+        "part stream ended after {copied} of {len} bytes"
+    );
+    part_progress
+        .into_inner()
+        .flush()
+        .await
+        .context("failed to flush part")?;
+
+    Ok(Some(copied))
+}
+
+/// Waits for each part to be written to the partial file, then reads them back in order to
+/// checksum them.
+///
+/// Returns the number of bytes in the parts, or None if the job was cancelled.
+async fn finish_file_parts(
+    parts: &mut [FilePart],
+    temp_path: &TreePath,
+    buffer_size: usize,
+    mut on_chunk: impl FnMut(&[u8]),
+) -> anyhow::Result<Option<u64>> {
+    for part in parts.iter_mut() {
+        if (&mut part.task)
+            .await
+            .context("part download panicked")??
+            .is_none()
+        {
+            return Ok(None);
+        }
+    }
 
-                                // check job status
-                                let (transfer_res, ready) = {
-                                    let Some(job) = jobs.get(&transfer_req.job_id) else {
-                                        anyhow::bail!("transfer request job id not found: {}", transfer_req.job_id);
-                                    };
+    let mut file = TreeFile::open(temp_path, OpenMode::Read)
+        .await
+        .context("failed to open partial file to checksum parts")?;
+    let mut buf = vec![0; buffer_size.max(1)];
+    let mut checked = 0;
+    for part in parts.iter() {
+        file.seek(part.start).await?;
+        let mut remaining = part.end - part.start;
+        while remaining > 0 {
+            let len = (remaining as usize).min(buf.len());
+            file.read_exact(&mut buf[..len])
+                .await
+                .context("failed to read part from partial file")?;
+            on_chunk(&buf[..len]);
+            remaining -= len as u64;
+        }
+        checked += part.end - part.start;
+    }
+    Ok(Some(checked))
+}
 
-                                    match &job.progress {
-                                        ServerTransferJobProgress::Ready { transcode_path, file_size } => {
-                                            (TransferResponse::Ok { file_size: *file_size }, Some((transcode_path.clone(), *file_size)))
-                                        }
-                                        _ => {
-                                            (TransferResponse::Error { error: "job not ready".to_string() }, None)
-                                        }
-                                    }
-                                };
+/// Error code used to stop a transfer stream when the client cancels the job.
+const TRANSFER_CANCELLED_ERROR_CODE: u32 = 1;
+
+/// Error code used to stop a transfer stream when the client already has an identical file, so
+/// the server finishes the job instead of failing it.
+const TRANSFER_UNCHANGED_ERROR_CODE: u32 = 2;
+
+/// Whether sending a file failed because the client stopped the stream with the given error code.
+fn stopped_with(error: &anyhow::Error, code: u32) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<iroh::endpoint::WriteError>(),
+            Some(iroh::endpoint::WriteError::Stopped(stopped)) if stopped.into_inner() == u64::from(code)
+        )
+    })
+}
 
-                                // send transfer response
-                                let transfer_res_buf = postcard::to_stdvec(&transfer_res)
-                                    .context("failed to serialize transfer response")?;
-                                send.write_u32(transfer_res_buf.len() as u32)
-                                    .await
-                                    .context("failed to write transfer response length")?;
-                                send.write_all(&transfer_res_buf)
-                                    .await
-                                    .context("failed to write transfer response")?;
+/// Maximum number of numbered names tried when renaming a download that collides with an
+/// existing file.
+const MAX_RENAME_ATTEMPTS: u32 = 1000;
 
-                                // TODO: could maybe be nicer
-                                let Some((transcode_path, file_size)) = ready else {
-                                    return Ok(());
-                                };
+/// How often a draining connection checks whether its active transfers have finished.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
-                                // check local file exists
-                                if !transcode_path.exists() {
-                                    // TODO: set job to failed and respond with error
-                                    anyhow::bail!("file at transcode_path does not exist: {}", transcode_path.display());
-                                }
+/// Minimum time between rescans requested by clients, so they can't keep the library scanning.
+const REMOTE_RESCAN_INTERVAL: Duration = Duration::from_secs(30);
 
-                                let sent_counter = Arc::new(AtomicU64::new(0));
+/// How often accepted connections send a Ping to show the peer they're alive.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
 
-                                // set job status to InProgress
-                                jobs.alter(&transfer_req.job_id, |_, mut job| {
-                                    job.progress = ServerTransferJobProgress::InProgress {
-                                        started_at: unix_epoch_now_secs(),
-                                        file_size,
-                                        sent: sent_counter.clone()
-                                    };
-                                    job
-                                });
+/// How long an accepted connection waits without any messages before it's closed as timed out.
+///
+/// Only applies to peers that send pings, since older peers can be quiet for any amount of time.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(60);
 
-                                // update model
-                                event_tx.send(NodeEvent::ServerChanged {
-                                    endpoint_id: remote_endpoint_id,
-                                    update: ServerModelUpdate::UpdateTransferJobs,
-                                }).expect("failed to send ServerModelUpdate::UpdateTransferJobs");
+/// Minimum time between model updates while receiving index pages, since each update rebuilds
+/// the model of the whole index received so far.
+const INDEX_PAGE_MODEL_UPDATE_INTERVAL: Duration = Duration::from_millis(250);
 
-                                // read file to buffer
-                                // TODO: stream instead of reading into memory?
-                                let file_content = tokio::fs::read(transcode_path).await?;
+/// How long a two-way sync waits for the index hashes without receiving any part of the index
+/// before it's abandoned.
+const INDEX_HASHES_TIMEOUT: Duration = Duration::from_secs(60);
 
-                                // TODO: handle errors during send
-                                let mut send_progress = WriteProgress::new(sent_counter.clone(), send);
-                                send_progress.write_all(&file_content).await?;
+#[derive(Debug)]
+enum ClientCommand {
+    Close,
+    /// Stop starting new downloads, and close once the active ones finish or the timeout passes.
+    ///
+    /// Downloads interrupted by the timeout keep their partial files, so they can resume later.
+    Drain {
+        timeout: Duration,
+    },
 
-                                // set job status to Finished
-                                jobs.alter(&transfer_req.job_id, |_, mut job| {
-                                    job.progress = ServerTransferJobProgress::Finished { finished_at: unix_epoch_now_secs(), file_size };
-                                    job
-                                });
+    SetDownloads {
+        items: Vec<DownloadRequestModel>,
+        /// Called with the root and path of each new job once they're queued.
+        callback: Option<oneshot::Sender<anyhow::Result<Vec<(String, String)>>>>,
+    },
+    /// Start the given jobs before the rest of the queue, and ask the server to transcode them
+    /// first.
+    PrioritizeDownloads {
+        items: Vec<DownloadRequestModel>,
+    },
+    PauseDownloads,
 
-                                // update model
-                                event_tx.send(NodeEvent::ServerChanged {
-                                    endpoint_id: remote_endpoint_id,
-                                    update: ServerModelUpdate::UpdateTransferJobs,
-                                }).expect("failed to send ServerModelUpdate::UpdateTransferJobs");
+    PauseTransfer {
+        job_id: u64,
+    },
+    ResumeTransfer {
+        job_id: u64,
+    },
+    CancelTransfer {
+        job_id: u64,
+    },
 
-                                let is_first_transfer = is_first_transfer.swap(false, Ordering::SeqCst);
-                                let _ = event_tx.send(NodeEvent::ServerTransferCompleted {
-                                    endpoint_id: remote_endpoint_id,
-                                    bytes: file_size,
-                                    is_first_transfer,
-                                });
+    /// Download the original files the server has that the local library lacks, and if
+    /// `sync_back` is set, ask the server to do the same.
+    Sync {
+        conflict_policy: SyncConflictPolicy,
+        sync_back: bool,
+    },
 
-                                Ok::<(), anyhow::Error>(())
-                            });
-                        }
+    /// Queue the new files in the server's index, if auto-download is enabled for the server.
+    AutoDownload,
 
-                        Err(e) => {
-                            error!("accept_bi error: {e}");
-                        }
-                    }
-                }
+    /// Ask the server to rescan its library.
+    RequestRescan,
+}
 
-                else => {
-                    warn!("all senders dropped in Server::run, shutting down");
-                    break;
-                }
-            }
+/// How long connections can wait to be accepted, shared between the node and its servers.
+type PendingTimeout = Arc<Mutex<Option<Duration>>>;
+
+/// Error returned when a connection isn't accepted before the pending timeout.
+#[derive(Debug, thiserror::Error)]
+#[error("connection wasn't accepted within {} seconds", .0.as_secs())]
+struct PendingExpired(Duration);
+
+/// The index items sent to a client by root and path, with their file sizes, hashes, and metadata.
+type SentIndex = HashMap<
+    (String, String),
+    (
+        FileSize,
+        Option<ContentHash>,
+        Option<ItemMetadata>,
+        Option<ItemExtraMetadata>,
+    ),
+>;
+
+/// Finds the changes between the index sent to a client and the current index, and updates the
+/// sent index to match.
+fn diff_index(
+    sent_index: &mut SentIndex,
+    index: Vec<IndexItem>,
+    index_hashes: Vec<Option<ContentHash>>,
+    index_metadata: Vec<Option<ItemMetadata>>,
+    index_extra_metadata: Vec<Option<ItemExtraMetadata>>,
+) -> IndexChanges {
+    let mut changes = IndexChanges::default();
+    let mut current = HashSet::new();
+
+    for (((item, hash), metadata), extra_metadata) in index
+        .into_iter()
+        .zip(index_hashes)
+        .zip(index_metadata)
+        .zip(index_extra_metadata)
+    {
+        let key = (item.root.clone(), item.path.clone());
+        current.insert(key.clone());
+
+        let value = (item.file_size, hash, metadata, extra_metadata);
+        if sent_index.get(&key) != Some(&value) {
+            changes.hashes.push(value.1.clone());
+            changes.metadata.push(value.2.clone());
+            changes.extra_metadata.push(value.3.clone());
+            changes.items.push(item);
+            sent_index.insert(key, value);
         }
+    }
 
-        self.connection.closed().await;
+    sent_index.retain(|key, _| {
+        let keep = current.contains(key);
+        if !keep {
+            changes.removed.push(key.clone());
+        }
+        keep
+    });
 
-        Ok(())
-    }
+    changes
+}
 
-    /// Gets the index to send to the client.
-    #[tracing::instrument(skip(self))]
-    fn get_index(
-        &self,
-        transcode_format: Option<TranscodeFormat>,
-    ) -> anyhow::Result<Vec<IndexItem>> {
-        let files = {
-            let db = self.db.lock().unwrap();
-            db.get_files()?
-        };
+/// Error returned when a peer stops sending messages, e.g. because its device lost its network
+/// connection without closing the connection.
+#[derive(Debug, thiserror::Error)]
+#[error("connection timed out after {} seconds without messages", .0.as_secs())]
+struct ConnectionTimedOut(Duration);
 
-        let index = files
-            .into_iter()
-            .map(|file| {
-                let local_path = PathBuf::from(file.local_path);
+/// Keep-alive state of an accepted connection, shared by servers and clients.
+///
+/// Pings are sent on each tick. The connection times out if the peer has sent pings before, but
+/// hasn't sent any messages within the timeout.
+struct KeepAlive {
+    interval: tokio::time::Interval,
+    timeout: Duration,
+    last_received: Instant,
+    peer_pings: bool,
 
-                let file_size = if let Some(transcode_format) = transcode_format {
-                    // Get cached duration without checking validity. Validating the cached duration
-                    // requires accessing the file to read its metadata, which can be expensive. We want
-                    // this to be fast since it's on the user's critical path. We can tolerate the
-                    // estimated sizes very rarely being incorrect.
-                    match self.hash_cache.get_cached_duration_unvalidated(&local_path) {
-                        Ok(Some(duration)) => {
-                            FileSize::Estimated(estimate_file_size(transcode_format, duration))
-                        }
-                        // When we don't have a cached duration, we still want to provide a guess
-                        // since we display Unknown as 0 on mobile.
-                        _ => FileSize::Estimated(estimate_file_size_without_duration(
-                            transcode_format,
-                        )),
-                    }
-                } else {
-                    // Get cached original file size without accessing the file.
-                    match self
-                        .hash_cache
-                        .get_cached_file_size_unvalidated(&local_path)
-                    {
-                        // This could be stale if the files were modified.
-                        Ok(Some(size)) => FileSize::Estimated(size),
-                        // When we don't have a cached duration, we still want to provide a guess
-                        // since we display Unknown as 0 on mobile.
-                        _ => FileSize::Estimated(estimate_original_file_size(&local_path)),
-                    }
-                };
+    #[cfg(feature = "test-hooks")]
+    test_hooks: Arc<TestHooks>,
+}
 
-                IndexItem {
-                    endpoint_id: file.node_id,
-                    root: file.root,
-                    path: file.path,
+impl KeepAlive {
+    fn new(#[cfg(feature = "test-hooks")] test_hooks: Arc<TestHooks>) -> Self {
+        #[cfg(not(feature = "test-hooks"))]
+        let (interval, timeout) = (KEEP_ALIVE_INTERVAL, KEEP_ALIVE_TIMEOUT);
+        #[cfg(feature = "test-hooks")]
+        let (interval, timeout) = test_hooks
+            .keep_alive()
+            .unwrap_or((KEEP_ALIVE_INTERVAL, KEEP_ALIVE_TIMEOUT));
 
-                    file_size,
-                }
-            })
-            .collect::<Vec<_>>();
+        Self {
+            interval: tokio::time::interval(interval),
+            timeout,
+            last_received: Instant::now(),
+            peer_pings: false,
 
-        Ok(index)
+            #[cfg(feature = "test-hooks")]
+            test_hooks,
+        }
     }
-}
 
-#[derive(Debug)]
-struct ClientTransferJob {
-    progress: ClientTransferJobProgress,
+    /// Records that a message was received from the peer.
+    fn received(&mut self, is_ping: bool) {
+        self.last_received = Instant::now();
+        self.peer_pings |= is_ping;
+    }
 
-    file_endpoint_id: EndpointId,
-    file_root: String,
-    file_path: String,
-}
+    /// Waits for the next tick, then returns an error if the connection timed out.
+    async fn tick(&mut self) -> Result<(), ConnectionTimedOut> {
+        self.interval.tick().await;
 
-#[derive(Debug)]
-enum ClientTransferJobProgress {
-    /// The client sent the request and is waiting for its status.
-    Requested,
-    /// The client is waiting for the file to be transcoded.
-    Transcoding,
-    /// The client is ready to download the file.
-    Ready { file_size: u64 },
-    /// The client has started downloading the file.
-    InProgress {
-        started_at: u64,
-        file_size: u64,
-        written: Arc<AtomicU64>,
-    },
-    /// The client has finished downloading the file.
-    Finished { finished_at: u64, file_size: u64 },
-    /// The client failed to download the file.
-    Failed { error: String },
-}
+        let elapsed = self.last_received.elapsed();
+        if self.peer_pings && elapsed >= self.timeout {
+            return Err(ConnectionTimedOut(elapsed));
+        }
+        Ok(())
+    }
 
-#[derive(Debug)]
-enum ClientCommand {
-    Close,
+    /// Sends a ping if the stream can take it right away.
+    ///
+    /// Waiting for a stream that's full would hold up the rest of the connection's main loop, and
+    /// a peer that isn't reading is timed out on its end anyway. A ping that's buffered but not
+    /// flushed yet is flushed with the next message.
+    fn try_ping<S, M>(&self, send: &mut S, ping: M)
+    where
+        S: Sink<M> + Unpin,
+        S::Error: std::fmt::Display,
+    {
+        #[cfg(feature = "test-hooks")]
+        if self.test_hooks.pings_paused() {
+            return;
+        }
 
-    SetDownloads { items: Vec<DownloadRequestModel> },
-    PauseDownloads,
+        match futures::FutureExt::now_or_never(send.send(ping)) {
+            Some(Ok(())) => {}
+            Some(Err(e)) => warn!("failed to send Ping message: {e}"),
+            None => debug!("stream is busy, skipped Ping message"),
+        }
+    }
 }
 
+/// Unused one-time tokens from pairing tickets, shared between the node and its servers.
+type PairingTokens = Arc<Mutex<HashSet<PairingToken>>>;
+
+/// Content hashes of items in a client's index, by endpoint ID, root, and path.
+type IndexHashes = HashMap<(EndpointId, String, String), ContentHash>;
+
+/// Tags of items in a client's index, by endpoint ID, root, and path.
+type IndexMetadata = HashMap<(EndpointId, String, String), ItemMetadata>;
+
+/// Tags of items in a client's index that aren't in their metadata, by endpoint ID, root, and path.
+type IndexExtraMetadata = HashMap<(EndpointId, String, String), ItemExtraMetadata>;
+
 #[derive(Debug, Clone)]
 struct ClientHandle {
     tx: mpsc::UnboundedSender<ClientCommand>,
 
     index: Arc<Mutex<Option<Vec<IndexItem>>>>,
+    /// Whether all pages of the index have arrived.
+    index_complete: Arc<AtomicBool>,
+    index_hashes: Arc<Mutex<IndexHashes>>,
+    index_metadata: Arc<Mutex<IndexMetadata>>,
+    index_extra_metadata: Arc<Mutex<IndexExtraMetadata>>,
     jobs: Arc<DashMap<u64, ClientTransferJob>>,
     paused: Arc<AtomicBool>,
+    pause_notify: Arc<Notify>,
+    /// Whether ready jobs are held from starting, like when paused, while downloads are
+    /// reorganized.
+    jobs_held: Arc<AtomicBool>,
+}
+
+impl ClientHandle {
+    /// Gets the server's index, or an error if it hasn't arrived or only some of its pages have.
+    fn complete_index(&self, endpoint_id: EndpointId) -> anyhow::Result<Vec<IndexItem>> {
+        let index = self.index.lock().unwrap();
+        match &*index {
+            Some(index) if self.index_complete.load(Ordering::Acquire) => Ok(index.clone()),
+            _ => Err(TransferError::IndexUnavailable {
+                endpoint_id: endpoint_id.to_string(),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Ready jobs of clients held from starting, released when dropped.
+#[derive(Debug, Default)]
+struct JobsHold(Vec<(Arc<AtomicBool>, Arc<Notify>)>);
+
+impl JobsHold {
+    fn hold(&mut self, client_handle: &ClientHandle) {
+        client_handle.jobs_held.store(true, Ordering::Relaxed);
+        self.0.push((
+            client_handle.jobs_held.clone(),
+            client_handle.pause_notify.clone(),
+        ));
+    }
+}
+
+impl Drop for JobsHold {
+    fn drop(&mut self) {
+        for (jobs_held, pause_notify) in &self.0 {
+            jobs_held.store(false, Ordering::Relaxed);
+            pause_notify.notify_waiters();
+        }
+    }
+}
+
+/// Removes the finished and failed jobs for the given files, so they can be downloaded again.
+/// SetDownloads skips files that already have a job.
+fn clear_settled_jobs(jobs: &DashMap<u64, ClientTransferJob>, keys: &HashSet<(String, String)>) {
+    jobs.retain(|_, job| {
+        let settled = matches!(
+            job.progress,
+            ClientTransferJobProgress::Finished { .. } | ClientTransferJobProgress::Failed { .. }
+        );
+        !(settled && keys.contains(&(job.file_root.clone(), job.file_path.clone())))
+    });
 }
 
 struct Client {
-    db: Arc<Mutex<Database>>,
+    db: Arc<DatabasePool>,
+    local_endpoint_id: EndpointId,
     download_directory: Arc<Mutex<Option<String>>>,
     transcode_format: Option<TranscodeFormat>,
+    /// One-time token from a pairing ticket, sent to the server when identifying.
+    pairing_token: Option<PairingToken>,
+    /// How long to wait for the server to accept the connection, or None to wait forever.
+    pending_timeout: Option<Duration>,
 
     event_tx: mpsc::UnboundedSender<NodeEvent>,
     connection: Connection,
@@ -2312,23 +8813,66 @@ struct Client {
     ready_tx: mpsc::UnboundedSender<u64>,
 
     index: Arc<Mutex<Option<Vec<IndexItem>>>>,
+    /// Whether all pages of the index have arrived. Only then is the index used for downloads.
+    index_complete: Arc<AtomicBool>,
+    /// Content hashes of index items, if the server sent them.
+    index_hashes: Arc<Mutex<IndexHashes>>,
+    /// Tags of index items, if the server sent them.
+    index_metadata: Arc<Mutex<IndexMetadata>>,
+    /// Tags of index items that aren't in their metadata, if the server sent them.
+    index_extra_metadata: Arc<Mutex<IndexExtraMetadata>>,
     jobs: Arc<DashMap<u64, ClientTransferJob>>,
     paused: Arc<AtomicBool>,
     pause_notify: Arc<Notify>,
+    /// Whether ready jobs are held from starting while downloads are reorganized.
+    jobs_held: Arc<AtomicBool>,
+    /// Whether the connection is closing, so ready jobs shouldn't start.
+    draining: Arc<AtomicBool>,
+    /// Whether to check that downloaded files still exist before skipping them.
+    verify_downloads: Arc<AtomicBool>,
+    /// Whether to download files again that changed on the server since they were downloaded.
+    redownload_changed: Arc<AtomicBool>,
+    /// Maximum number of concurrent transfers advertised by the server, if any.
+    remote_transfer_limit: watch::Sender<Option<u32>>,
+    /// Whether the server can send parts of a file in separate streams.
+    transfer_parts: Arc<AtomicBool>,
+
+    #[cfg(feature = "test-hooks")]
+    test_hooks: Arc<TestHooks>,
 }
 
 impl Client {
     fn new(
-        db: Arc<Mutex<Database>>,
+        db: Arc<DatabasePool>,
+        local_endpoint_id: EndpointId,
         event_tx: mpsc::UnboundedSender<NodeEvent>,
         connection: Connection,
         transcode_format: Option<TranscodeFormat>,
+        pairing_token: Option<PairingToken>,
+        pending_timeout: Option<Duration>,
         download_directory: Arc<Mutex<Option<String>>>,
+        download_path_template: Arc<Mutex<Option<PathTemplate>>>,
+        collision_policy: Arc<Mutex<CollisionPolicy>>,
+        sync_downloads: Arc<AtomicBool>,
+        verify_downloads: Arc<AtomicBool>,
+        redownload_changed: Arc<AtomicBool>,
+        parallel_streams: Arc<AtomicU32>,
+        transfer_buffer_size: Arc<AtomicU32>,
+        mut max_concurrent_transfers: watch::Receiver<u32>,
+        download_slots: Arc<DownloadSlots>,
+        download_bandwidth: Arc<BandwidthLimiter>,
+        transfer_gate: Arc<TransferGate>,
         #[cfg(feature = "test-hooks")] test_hooks: Arc<TestHooks>,
     ) -> Self {
         let jobs = Arc::new(DashMap::<u64, ClientTransferJob>::new());
+        let remote_transfer_limit = watch::Sender::new(None);
+        let transfer_parts = Arc::new(AtomicBool::new(false));
         let paused = Arc::new(AtomicBool::new(false));
         let pause_notify = Arc::new(Notify::new());
+        let jobs_held = Arc::new(AtomicBool::new(false));
+        let draining = Arc::new(AtomicBool::new(false));
+        let index_metadata = Arc::new(Mutex::new(HashMap::new()));
+        let index_extra_metadata = Arc::new(Mutex::new(HashMap::new()));
 
         // Track whether the first transfer has completed, for counting sessions with >1 transfer.
         // When tracking transferred files we indicate whether it's the first of this session.
@@ -2343,167 +8887,437 @@ impl Client {
             let event_tx = event_tx.clone();
             let connection = connection.clone();
             let download_directory = download_directory.clone();
+            let index_metadata = index_metadata.clone();
+            let index_extra_metadata = index_extra_metadata.clone();
             let paused = paused.clone();
             let pause_notify = pause_notify.clone();
+            let jobs_held = jobs_held.clone();
+            let draining = draining.clone();
+            let mut remote_transfer_limit = remote_transfer_limit.subscribe();
+            let transfer_parts = transfer_parts.clone();
+            #[cfg(feature = "test-hooks")]
+            let test_hooks = test_hooks.clone();
             async move {
-                // convert channel receiver of ready job IDs into a stream for use with buffer_unordered
+                // convert channel receiver of ready job IDs into a stream for the scheduler
                 let ready_stream = {
                     let jobs = jobs.clone();
                     async_stream::stream! {
-                        while let Some(job_id) = ready_rx.recv().await {
+                        // ready jobs that haven't started, which start in order of priority
+                        let mut queued = Vec::new();
+                        loop {
+                            if queued.is_empty() {
+                                match ready_rx.recv().await {
+                                    Some(job_id) => queued.push(job_id),
+                                    None => break,
+                                }
+                            }
+
                             // if compiled with test hooks, wait for a download permit
                             #[cfg(feature = "test-hooks")]
                             test_hooks.wait_for_download_permit().await;
 
-                            // if paused, wait for unpause before processing received item
-                            while paused.load(Ordering::Relaxed) {
-                                pause_notify.notified().await;
+                            // if paused or held, wait for unpause before processing received
+                            // item. the notification is created before checking, so an unpause
+                            // in between isn't missed
+                            loop {
+                                let notified = pause_notify.notified();
+                                if !paused.load(Ordering::Relaxed) && !jobs_held.load(Ordering::Relaxed) {
+                                    break;
+                                }
+                                notified.await;
                             }
 
-                            // check job exists: it may have been removed while paused
-                            if jobs.get(&job_id).is_none() {
-                                debug!("job {job_id} removed while paused, skipping");
+                            // hold queued jobs until the transfer schedule is met
+                            transfer_gate.wait_until_allowed().await;
+
+                            // wait for a download slot shared with other connections
+                            let slot = download_slots.acquire().await;
+
+                            // pick the next job once there's a slot for it, including jobs that
+                            // became ready or were prioritized while waiting
+                            while let Ok(job_id) = ready_rx.try_recv() {
+                                queued.push(job_id);
+                            }
+                            let job_id = take_next_job(&jobs, &mut queued);
+
+                            // don't start jobs while the connection is closing
+                            if draining.load(Ordering::Relaxed) {
+                                debug!("job {job_id} not started, connection is draining");
+                                continue;
+                            }
+
+                            // jobs held while waiting for the slot wait for the release instead
+                            if jobs_held.load(Ordering::Relaxed) {
+                                debug!("job {job_id} not started, jobs are held");
+                                queued.push(job_id);
                                 continue;
                             }
 
+                            // check job exists: it may have been removed while paused
+                            {
+                                let Some(mut job) = jobs.get_mut(&job_id) else {
+                                    debug!("job {job_id} removed while paused, skipping");
+                                    continue;
+                                };
+
+                                let control = *job.control.borrow();
+                                match control {
+                                    JobControl::Run => {}
+                                    JobControl::Pause => {
+                                        // resuming the job will send it to the ready channel again
+                                        debug!("job {job_id} is paused, deferring");
+                                        job.deferred = true;
+                                        continue;
+                                    }
+                                    JobControl::Cancel => {
+                                        debug!("job {job_id} was cancelled, skipping");
+                                        continue;
+                                    }
+                                }
+                            }
+
                             // once yielded, the job will start and can't be cancelled
-                            yield job_id;
+                            yield (job_id, slot);
                         }
                     }
                 };
 
-                // map stream of ready ids to futures that download the files
-                let buffer = ready_stream
-                    .map(|job_id| {
-                        // get download directory
-                        let download_directory = {
-                            let download_directory = download_directory.lock().unwrap();
-                            download_directory.clone()
+                // creates a future that downloads the file for a ready job, holding its download
+                // slot until it finishes
+                let download_job = |job_id: u64, slot: DownloadSlot| {
+                    // get download directory
+                    let download_directory = {
+                        let download_directory = download_directory.lock().unwrap();
+                        download_directory.clone()
+                    };
+                    let download_path_template = download_path_template.lock().unwrap().clone();
+                    let collision_policy = *collision_policy.lock().unwrap();
+                    let sync_downloads = sync_downloads.load(Ordering::Relaxed);
+                    // only servers that support parts can send a file in several streams
+                    let parallel_streams = if transfer_parts.load(Ordering::Relaxed) {
+                        parallel_streams.load(Ordering::Relaxed)
+                    } else {
+                        1
+                    };
+                    let buffer_size = transfer_buffer_size.load(Ordering::Relaxed) as usize;
+
+                    let db = db.clone();
+                    let jobs = jobs.clone();
+                    let index_metadata = index_metadata.clone();
+                    let index_extra_metadata = index_extra_metadata.clone();
+                    let event_tx = event_tx.clone();
+                    let connection = connection.clone();
+                    let is_first_transfer = is_first_transfer.clone();
+                    let download_bandwidth = download_bandwidth.clone();
+                    async move {
+                        let _slot = slot;
+                        let remote_endpoint_id = connection.remote_id();
+
+                        // check if download directory is set
+                        // we need to do this inside the async block so that the return type of the closure is always the async block's anonymous future
+                        let Some(download_directory) = download_directory else {
+                            anyhow::bail!("download directory is None, cannot download");
+                        };
+
+                        // check job exists and get details
+                        let (file_endpoint_id, file_root, file_path, mut control) = {
+                            let Some(job) = jobs.get(&job_id) else {
+                                anyhow::bail!("received ready for unknown job ID {job_id}");
+                            };
+
+                            (
+                                job.file_endpoint_id,
+                                job.file_root.clone(),
+                                job.file_path.clone(),
+                                job.control.subscribe(),
+                            )
+                        };
+
+                        // the job might have been cancelled after it was yielded
+                        if *control.borrow() == JobControl::Cancel {
+                            return Ok(());
+                        }
+
+                        debug!("downloading file: {file_root}/{file_path}");
+
+                        // build file path, sanitizing names that aren't valid on this platform
+                        let (local_path, sanitized) = {
+                            let key = (file_endpoint_id, file_root.clone(), file_path.clone());
+                            let metadata = index_metadata.lock().unwrap().get(&key).cloned();
+                            let extra_metadata =
+                                index_extra_metadata.lock().unwrap().get(&key).cloned();
+                            let db = db.get();
+                            build_download_path(
+                                &db,
+                                download_directory,
+                                download_path_template.as_ref(),
+                                file_endpoint_id,
+                                TemplateValues {
+                                    root: &file_root,
+                                    path: &file_path,
+                                    metadata: metadata.as_ref(),
+                                    extra_metadata: extra_metadata.as_ref(),
+                                },
+                                transcode_format
+                                    .map(|transcode_format| transcode_format.extension()),
+                            )?
+                        };
+
+                        // report paths that were changed to be valid on this platform
+                        if sanitized {
+                            let sanitized_path = local_path.path().into_owned();
+                            jobs.alter(&job_id, |_, mut job| {
+                                job.sanitized_path = Some(sanitized_path);
+                                job
+                            });
+                        }
+
+                        // apply the collision policy if the destination has a file that wasn't downloaded from this file
+                        let mut local_path = local_path;
+                        let mut check_unchanged = false;
+                        let is_own_download = {
+                            let db = db.get();
+                            db.get_files_by_node_root_path(std::iter::once((
+                                file_endpoint_id,
+                                file_root.clone(),
+                                file_path.clone(),
+                            )))?
+                            .iter()
+                            .any(|file| {
+                                file.local_tree == local_path.root()
+                                    && file.local_path == local_path.path()
+                            })
                         };
+                        if !is_own_download && local_path.exists() {
+                            let collision = match collision_policy {
+                                CollisionPolicy::Overwrite => CollisionOutcomeModel::Overwritten,
+                                CollisionPolicy::Skip => CollisionOutcomeModel::Skipped,
+                                CollisionPolicy::Rename => {
+                                    let rules = SanitizeRules::current();
+                                    let file_name = local_path
+                                        .file_name()
+                                        .map(|name| name.into_owned())
+                                        .unwrap_or_default();
+                                    let renamed = (1..=MAX_RENAME_ATTEMPTS).any(|number| {
+                                        local_path.set_file_name(&numbered_file_name(
+                                            &file_name, number, rules,
+                                        ));
+                                        !local_path.exists()
+                                    });
+                                    anyhow::ensure!(
+                                        renamed,
+                                        "no free name for {file_name} after {MAX_RENAME_ATTEMPTS} attempts"
+                                    );
+                                    CollisionOutcomeModel::Renamed {
+                                        local_path: local_path.path().into_owned(),
+                                    }
+                                }
+                                CollisionPolicy::OverwriteIfDifferent => {
+                                    // decided once the server sends the file's checksum
+                                    check_unchanged = true;
+                                    CollisionOutcomeModel::Overwritten
+                                }
+                            };
+                            debug!("{local_path:?} already exists: {collision:?}");
+
+                            let skipped = collision == CollisionOutcomeModel::Skipped;
+                            jobs.alter(&job_id, |_, mut job| {
+                                job.collision = Some(collision);
+                                if skipped {
+                                    let file_size = match job.progress {
+                                        ClientTransferJobProgress::Ready { file_size } => file_size,
+                                        _ => 0,
+                                    };
+                                    job.progress = ClientTransferJobProgress::Finished {
+                                        finished_at: unix_epoch_now_secs(),
+                                        file_size,
+                                    };
+                                }
+                                job
+                            });
 
-                        let db = db.clone();
-                        let jobs = jobs.clone();
-                        let event_tx = event_tx.clone();
-                        let connection = connection.clone();
-                        let is_first_transfer = is_first_transfer.clone();
-                        async move {
-                            let remote_endpoint_id = connection.remote_id();
-
-                            // check if download directory is set
-                            // we need to do this inside the async block so that the return type of the closure is always the async block's anonymous future
-                            let Some(download_directory) = download_directory else {
-                                anyhow::bail!("download directory is None, cannot download");
-                            };
+                            if skipped {
+                                event_tx
+                                    .send(NodeEvent::ClientChanged {
+                                        endpoint_id: remote_endpoint_id,
+                                        update: ClientModelUpdate::UpdateTransferJobs,
+                                    })
+                                    .expect("failed to send ClientModelUpdate::UpdateTransferJobs");
 
-                            // check job exists and get details
-                            let (file_endpoint_id, file_root, file_path) = {
-                                let Some(job) = jobs.get(&job_id) else {
-                                    anyhow::bail!("received ready for unknown job ID {job_id}");
-                                };
+                                return Ok(());
+                            }
+                        }
 
-                                (
-                                    job.file_endpoint_id,
-                                    job.file_root.clone(),
-                                    job.file_path.clone(),
-                                )
+                        // check for a partial file left by an interrupted download
+                        let (transfer_resume, prefix_digest) =
+                            match read_partial_download(&local_path).await {
+                                Ok(Some((transfer_resume, prefix_digest))) => {
+                                    (Some(transfer_resume), Some(prefix_digest))
+                                }
+                                Ok(None) => (None, None),
+                                Err(e) => {
+                                    warn!(
+                                        "failed to read partial file, downloading whole file: {e:#}"
+                                    );
+                                    (None, None)
+                                }
                             };
 
-                            debug!("downloading file: {file_root}/{file_path}");
-
-                            // open a bidirectional stream
-                            let (mut send, mut recv) = connection.open_bi().await?;
+                        // split large files into parts downloaded in parallel streams. this
+                        // stream downloads the first part, and other streams download the rest
+                        let expected_size = jobs.get(&job_id).and_then(|job| match job.progress {
+                            ClientTransferJobProgress::Ready { file_size } => Some(file_size),
+                            _ => None,
+                        });
+                        let part_ranges = match expected_size {
+                            Some(expected_size) if parallel_streams > 1 => split_file_parts(
+                                transfer_resume.as_ref().map_or(0, |resume| resume.offset),
+                                expected_size,
+                                parallel_streams,
+                            ),
+                            _ => Vec::new(),
+                        };
+                        let (first_part, other_parts) = match part_ranges.split_first() {
+                            Some((first_part, other_parts)) if !other_parts.is_empty() => {
+                                (Some(*first_part), other_parts.to_vec())
+                            }
+                            _ => (None, Vec::new()),
+                        };
 
-                            // send transfer request with job id
-                            let transfer_req = TransferRequest { job_id };
-                            let transfer_req_buf = postcard::to_stdvec(&transfer_req)
-                                .context("failed to serialize transfer request")?;
-                            send.write_u32(transfer_req_buf.len() as u32)
-                                .await
-                                .context("failed to write transfer request length")?;
-                            send.write_all(&transfer_req_buf)
-                                .await
-                                .context("failed to write transfer request")?;
+                        // open a bidirectional stream
+                        let (mut send, mut recv) = connection.open_bi().await?;
 
-                            // receive transfer response with metadata
-                            let transfer_res_len = recv.read_u32().await?;
-                            let mut transfer_res_buf = vec![0; transfer_res_len as usize];
-                            recv.read_exact(&mut transfer_res_buf)
-                                .await
-                                .context("failed to read transfer response")?;
-                            let transfer_res: TransferResponse =
-                                postcard::from_bytes(&transfer_res_buf)
-                                    .context("failed to deserialize transfer response")?;
-
-                            // check transfer response
-                            let file_size = match transfer_res {
-                                TransferResponse::Ok { file_size } => file_size,
+                        // send transfer request with job id, followed by the transfer options and range
+                        let transfer_req = TransferRequest { job_id };
+                        let transfer_options = TransferOptions {
+                            resume: transfer_resume.clone(),
+                        };
+                        let transfer_req_buf = postcard::to_stdvec(&transfer_req)
+                            .context("failed to serialize transfer request")?;
+                        let mut transfer_req_buf =
+                            postcard::to_extend(&transfer_options, transfer_req_buf)
+                                .context("failed to serialize transfer options")?;
+                        if let Some((_, first_part_end)) = first_part {
+                            transfer_req_buf = postcard::to_extend(
+                                &TransferRange::Until(first_part_end),
+                                transfer_req_buf,
+                            )
+                            .context("failed to serialize transfer range")?;
+                        }
+                        send.write_u32(transfer_req_buf.len() as u32)
+                            .await
+                            .context("failed to write transfer request length")?;
+                        send.write_all(&transfer_req_buf)
+                            .await
+                            .context("failed to write transfer request")?;
+
+                        // receive transfer response with metadata
+                        let transfer_res_len = recv.read_u32().await?;
+                        let mut transfer_res_buf = vec![0; transfer_res_len as usize];
+                        recv.read_exact(&mut transfer_res_buf)
+                            .await
+                            .context("failed to read transfer response")?;
+                        let transfer_res: TransferResponse =
+                            postcard::from_bytes(&transfer_res_buf)
+                                .context("failed to deserialize transfer response")?;
+
+                        // check transfer response
+                        let (file_size, offset, checksum, source_hash, metadata) =
+                            match transfer_res {
+                                // older servers don't send checksums
+                                TransferResponse::Ok { file_size } => {
+                                    (file_size, 0, None, None, None)
+                                }
+                                // older servers don't send manifests
+                                TransferResponse::Verified {
+                                    file_size,
+                                    offset,
+                                    checksum,
+                                    source_hash,
+                                } => (file_size, offset, Some(checksum), source_hash, None),
+                                TransferResponse::Manifest {
+                                    file_size,
+                                    offset,
+                                    checksum,
+                                    source_hash,
+                                    metadata,
+                                } => (file_size, offset, Some(checksum), source_hash, metadata),
                                 TransferResponse::Error { error } => {
                                     // set job status to Failed
                                     jobs.alter(&job_id, |_, mut job| {
-                                        job.progress = ClientTransferJobProgress::Failed { error };
+                                        job.progress = ClientTransferJobProgress::Failed {
+                                            reason: TransferErrorReasonModel::from_remote_error(
+                                                &error,
+                                            ),
+                                            error,
+                                        };
                                         job
                                     });
 
                                     return Ok(());
                                 }
+                                TransferResponse::Part { .. } => {
+                                    anyhow::bail!(
+                                        "server sent a part in response to a transfer request"
+                                    );
+                                }
                             };
+                        anyhow::ensure!(
+                            offset == 0
+                                || transfer_resume
+                                    .as_ref()
+                                    .is_some_and(|resume| resume.offset == offset),
+                            "server resumed from unexpected offset {offset}"
+                        );
+                        anyhow::ensure!(
+                            offset <= file_size,
+                            "server resumed from offset {offset} past end of file"
+                        );
 
-                            // set job status to InProgress
-                            let written = Arc::new(AtomicU64::new(0));
-                            jobs.alter(&job_id, |_, mut job| {
-                                job.progress = ClientTransferJobProgress::InProgress {
-                                    started_at: unix_epoch_now_secs(),
-                                    file_size,
-                                    written: written.clone(),
-                                };
-
-                                job
+                        // keep what the server sent about the file, so it can be verified later
+                        // without the server. the index has the tags if the server didn't send them
+                        let manifest = checksum.map(|checksum| {
+                            let metadata = metadata.or_else(|| {
+                                index_metadata
+                                    .lock()
+                                    .unwrap()
+                                    .get(&(file_endpoint_id, file_root.clone(), file_path.clone()))
+                                    .cloned()
                             });
-
-                            // update model
-                            event_tx
-                                .send(NodeEvent::ClientChanged {
-                                    endpoint_id: remote_endpoint_id,
-                                    update: ClientModelUpdate::UpdateTransferJobs,
-                                })
-                                .expect("failed to send ClientModelUpdate::UpdateTransferJobs");
-
-                            // build file path
-                            let local_path = {
-                                let root_dir_name =
-                                    format!("musicopy-{}-{}", &file_endpoint_id, &file_root);
-                                let mut local_path =
-                                    TreePath::new(download_directory, root_dir_name.into())?;
-                                local_path.push(&file_path);
-                                // If transcoding, overwrite the transferred file's extension
-                                if let Some(transcode_format) = transcode_format {
-                                    local_path.set_extension(transcode_format.extension());
-                                }
-                                local_path
-                            };
-
-                            // create parent directories
-                            let parent_dir_path = local_path.parent();
-                            if let Some(parent) = parent_dir_path {
-                                crate::fs::create_dir_all(&parent)
-                                    .await
-                                    .context("failed to create directory for root")?;
+                            let (title, artist, album) = metadata
+                                .map(|metadata| (metadata.title, metadata.artist, metadata.album))
+                                .unwrap_or_default();
+                            FileManifest {
+                                file_size,
+                                checksum,
+                                title,
+                                artist,
+                                album,
                             }
+                        });
 
-                            // open file for writing
-                            let file = TreeFile::open_or_create(&local_path, OpenMode::Write)
-                                .await
-                                .context("failed to open file")?;
-
-                            // copy from stream to file
-                            let mut file_progress = WriteProgress::new(written.clone(), file);
-                            tokio::io::copy(&mut recv.take(file_size), &mut file_progress).await?;
+                        // the other parts were split from the size the server announced
+                        if first_part.is_some() {
+                            anyhow::ensure!(
+                                expected_size == Some(file_size),
+                                "file size changed from {expected_size:?} to {file_size}"
+                            );
+                        }
 
-                            // TODO: handle errors above and update job status
+                        // keep the existing file if it's identical, instead of downloading it again
+                        if check_unchanged
+                            && offset == 0
+                            && let Some(checksum) = checksum
+                            && file_checksum(&local_path)
+                                .await
+                                .is_ok_and(|existing_checksum| existing_checksum == checksum)
+                        {
+                            debug!("{local_path:?} is unchanged, not downloading");
+                            let _ = recv.stop(TRANSFER_UNCHANGED_ERROR_CODE.into());
 
-                            // insert or update file in database
+                            // track the existing file as downloaded, since it has the same content
                             {
-                                let mut db = db.lock().unwrap();
+                                let mut db = db.get();
                                 db.insert_remote_file(
                                     remote_endpoint_id,
                                     InsertFile {
@@ -2512,20 +9326,22 @@ impl Client {
                                         local_tree: local_path.root(),
                                         local_path: &local_path.path(),
                                     },
+                                    source_hash.as_ref().map(|source_hash| {
+                                        (source_hash.kind.as_str(), source_hash.hash)
+                                    }),
+                                    manifest.as_ref(),
                                 )
                                 .context("failed to insert remote file in database")?;
                             }
 
-                            // set job status to Finished
                             jobs.alter(&job_id, |_, mut job| {
+                                job.collision = Some(CollisionOutcomeModel::Unchanged);
                                 job.progress = ClientTransferJobProgress::Finished {
                                     finished_at: unix_epoch_now_secs(),
                                     file_size,
                                 };
                                 job
                             });
-
-                            // update model
                             event_tx
                                 .send(NodeEvent::ClientChanged {
                                     endpoint_id: remote_endpoint_id,
@@ -2533,26 +9349,374 @@ impl Client {
                                 })
                                 .expect("failed to send ClientModelUpdate::UpdateTransferJobs");
 
-                            let is_first_transfer = is_first_transfer.swap(false, Ordering::SeqCst);
-                            let _ = event_tx.send(NodeEvent::ClientTransferCompleted {
+                            return Ok(());
+                        }
+
+                        if offset > 0 {
+                            debug!(
+                                "resuming download of {file_root}/{file_path} from byte {offset}"
+                            );
+                        }
+
+                        // set job status to InProgress, counting the resumed bytes as already written
+                        let written = Arc::new(AtomicU64::new(offset));
+                        jobs.alter(&job_id, |_, mut job| {
+                            job.progress = ClientTransferJobProgress::InProgress {
+                                started_at: unix_epoch_now_secs(),
+                                file_size,
+                                written: written.clone(),
+                            };
+
+                            job
+                        });
+
+                        // update model
+                        event_tx
+                            .send(NodeEvent::ClientChanged {
                                 endpoint_id: remote_endpoint_id,
-                                bytes: file_size,
-                                is_first_transfer,
+                                update: ClientModelUpdate::UpdateTransferJobs,
+                            })
+                            .expect("failed to send ClientModelUpdate::UpdateTransferJobs");
+
+                        // create parent directories
+                        let parent_dir_path = local_path.parent();
+                        if let Some(parent) = &parent_dir_path {
+                            crate::fs::create_dir_all(parent)
+                                .await
+                                .context("failed to create directory for root")?;
+                        }
+
+                        // open temporary file for writing, appending to the partial file if resuming
+                        let file = if offset > 0 {
+                            TreeFile::resume_atomic(&local_path).await
+                        } else {
+                            TreeFile::create_atomic(&local_path).await
+                        }
+                        .context("failed to open file")?;
+
+                        // journal the partial file, so it can be cleaned up or resumed if the app stops
+                        let resumed_interrupted = {
+                            let db = db.get();
+                            db.upsert_download_journal_entry(&DownloadJournalEntry {
+                                node_id: remote_endpoint_id,
+                                file_node_id: file_endpoint_id,
+                                root: file_root.clone(),
+                                path: file_path.clone(),
+                                local_tree: local_path.root().to_string(),
+                                local_path: local_path.path().into_owned(),
+                                expected_size: Some(file_size),
+                                expected_checksum: checksum,
+                                bytes_written: offset,
+                                started_at: unix_epoch_now_secs(),
+                                updated_at: unix_epoch_now_secs(),
+                                interrupted: false,
+                            })
+                            .context("failed to journal download")?
+                        };
+                        if resumed_interrupted {
+                            event_tx
+                                .send(NodeEvent::InterruptedDownloadsChanged)
+                                .expect("failed to send NodeEvent::InterruptedDownloadsChanged");
+                        }
+                        let checkpoints = journal::Checkpoints::spawn(db.clone(), &local_path)?;
+                        let remove_journal_entry = || {
+                            let db = db.get();
+                            if let Err(e) = db.delete_download_journal_entry(
+                                local_path.root(),
+                                &local_path.path(),
+                            ) {
+                                warn!("failed to remove download from journal: {e:#}");
+                            }
+                        };
+
+                        // checksum the received bytes, continuing from the partial file if resuming
+                        let mut digest = match prefix_digest {
+                            Some(prefix_digest) if offset > 0 => prefix_digest,
+                            _ => FILE_CRC.digest(),
+                        };
+
+                        // start downloading the other parts, which are aborted if the download stops
+                        let temp_path = TreeFile::atomic_temp_path(&local_path)?;
+                        let mut parts = other_parts
+                            .into_iter()
+                            .map(|(start, end)| FilePart {
+                                start,
+                                end,
+                                task: tokio::spawn(download_file_part(
+                                    connection.clone(),
+                                    job_id,
+                                    start,
+                                    end,
+                                    temp_path.clone(),
+                                    written.clone(),
+                                    control.clone(),
+                                    download_bandwidth.clone(),
+                                    buffer_size,
+                                )),
+                            })
+                            .collect::<Vec<_>>();
+                        let has_parts = !parts.is_empty();
+
+                        // copy from stream to file, stopping while the job is paused
+                        let remaining = match first_part {
+                            Some((_, first_part_end)) => first_part_end.saturating_sub(offset),
+                            None => file_size - offset,
+                        };
+                        let mut file_progress = WriteProgress::new(written.clone(), file);
+                        let mut main_copied = 0;
+                        let copy_res = copy_with_control(
+                            &mut (&mut recv).take(remaining),
+                            &mut file_progress,
+                            &mut control,
+                            &download_bandwidth,
+                            buffer_size,
+                            |chunk| {
+                                digest.update(chunk);
+                                main_copied += chunk.len() as u64;
+                            },
+                        )
+                        .await;
+                        let mut file = file_progress.into_inner();
+
+                        // the parts written by other streams are checksummed after the start of the file
+                        let copy_res = match copy_res {
+                            Ok(Some(copied)) if copied == remaining && has_parts => {
+                                match file.flush().await {
+                                    Ok(()) => finish_file_parts(
+                                        &mut parts,
+                                        &temp_path,
+                                        buffer_size,
+                                        |chunk| digest.update(chunk),
+                                    )
+                                    .await
+                                    .map(|checked| checked.map(|checked| copied + checked)),
+                                    Err(e) => {
+                                        Err(anyhow::Error::from(e).context("failed to flush file"))
+                                    }
+                                }
+                            }
+                            copy_res => copy_res.map_err(anyhow::Error::from),
+                        };
+                        // stop any parts still downloading if the download failed
+                        drop(parts);
+
+                        // if the job was cancelled, tell the server to stop sending and discard the partial file
+                        let Some(copy_res) = copy_res.transpose() else {
+                            debug!("job {job_id} cancelled");
+                            let _ = recv.stop(TRANSFER_CANCELLED_ERROR_CODE.into());
+                            drop(checkpoints);
+                            if let Err(abort_err) = file.abort().await {
+                                warn!("failed to remove partial file: {abort_err:#}");
+                            }
+                            remove_journal_entry();
+
+                            jobs.alter(&job_id, |_, mut job| {
+                                job.progress = ClientTransferJobProgress::Failed {
+                                    error: "cancelled".to_string(),
+                                    reason: TransferErrorReasonModel::Cancelled,
+                                };
+                                job
                             });
+                            event_tx
+                                .send(NodeEvent::ClientChanged {
+                                    endpoint_id: remote_endpoint_id,
+                                    update: ClientModelUpdate::UpdateTransferJobs,
+                                })
+                                .expect("failed to send ClientModelUpdate::UpdateTransferJobs");
 
-                            debug!("saved file to {local_path:?}");
+                            return Ok(());
+                        };
+                        let copy_res = copy_res.and_then(|copied| {
+                            anyhow::ensure!(
+                                copied == file_size - offset,
+                                "stream ended after {} of {file_size} bytes",
+                                offset + copied
+                            );
+                            Ok(())
+                        });
 
-                            Ok::<(), anyhow::Error>(())
+                        // move the file into place, or keep the partial file so a later attempt can resume
+                        drop(checkpoints);
+                        if let Err(e) = copy_res {
+                            if written.load(Ordering::Relaxed) > 0 {
+                                if let Err(flush_err) = file.flush().await {
+                                    warn!("failed to flush partial file: {flush_err:#}");
+                                }
+                                // only the start of the file is known to be complete, so drop the
+                                // parts written by other streams
+                                if has_parts
+                                    && let Err(truncate_err) =
+                                        file.set_len(offset + main_copied).await
+                                {
+                                    warn!("failed to truncate partial file: {truncate_err:#}");
+                                }
+                                // checkpoint the partial file, which is kept to resume from
+                                if let Ok(metadata) = crate::fs::metadata(&temp_path).await {
+                                    let db = db.get();
+                                    if let Err(e) = db.update_download_journal_progress(
+                                        local_path.root(),
+                                        &local_path.path(),
+                                        metadata.len,
+                                        unix_epoch_now_secs(),
+                                    ) {
+                                        warn!("failed to checkpoint download journal: {e:#}");
+                                    }
+                                }
+                            } else {
+                                if let Err(abort_err) = file.abort().await {
+                                    warn!("failed to remove partial file: {abort_err:#}");
+                                }
+                                remove_journal_entry();
+                            }
+                            return Err(e);
                         }
-                    })
-                    .buffer_unordered(4);
 
-                tokio::pin!(buffer);
+                        // discard the file if it doesn't match what the server sent
+                        if let Some(checksum) = checksum
+                            && digest.finalize() != checksum
+                        {
+                            warn!("checksum mismatch for {file_root}/{file_path}");
+                            if let Err(abort_err) = file.abort().await {
+                                warn!("failed to remove corrupted file: {abort_err:#}");
+                            }
+                            remove_journal_entry();
 
-                // poll the stream to download items with limited concurrency
-                while let Some(res) = buffer.next().await {
-                    if let Err(e) = res {
-                        error!("error downloading item: {e:#}");
+                            jobs.alter(&job_id, |_, mut job| {
+                                job.progress = ClientTransferJobProgress::Failed {
+                                    error: "checksum mismatch".to_string(),
+                                    reason: TransferErrorReasonModel::ChecksumMismatch,
+                                };
+                                job
+                            });
+                            event_tx
+                                .send(NodeEvent::ClientChanged {
+                                    endpoint_id: remote_endpoint_id,
+                                    update: ClientModelUpdate::UpdateTransferJobs,
+                                })
+                                .expect("failed to send ClientModelUpdate::UpdateTransferJobs");
+
+                            return Ok(());
+                        }
+
+                        if sync_downloads {
+                            file.sync_all().await.context("failed to sync file")?;
+                        }
+                        file.commit()
+                            .await
+                            .context("failed to move file into place")?;
+                        remove_journal_entry();
+                        if sync_downloads && let Some(parent) = &parent_dir_path {
+                            crate::fs::sync_dir(parent)
+                                .await
+                                .context("failed to sync parent directory")?;
+                        }
+
+                        // TODO: handle errors above and update job status
+
+                        // insert or update file in database
+                        {
+                            let mut db = db.get();
+                            db.insert_remote_file(
+                                remote_endpoint_id,
+                                InsertFile {
+                                    root: &file_root,
+                                    path: &file_path,
+                                    local_tree: local_path.root(),
+                                    local_path: &local_path.path(),
+                                },
+                                source_hash.as_ref().map(|source_hash| {
+                                    (source_hash.kind.as_str(), source_hash.hash)
+                                }),
+                                manifest.as_ref(),
+                            )
+                            .context("failed to insert remote file in database")?;
+                        }
+
+                        // set job status to Finished
+                        jobs.alter(&job_id, |_, mut job| {
+                            job.progress = ClientTransferJobProgress::Finished {
+                                finished_at: unix_epoch_now_secs(),
+                                file_size,
+                            };
+                            job
+                        });
+
+                        // update model
+                        event_tx
+                            .send(NodeEvent::ClientChanged {
+                                endpoint_id: remote_endpoint_id,
+                                update: ClientModelUpdate::UpdateTransferJobs,
+                            })
+                            .expect("failed to send ClientModelUpdate::UpdateTransferJobs");
+
+                        let is_first_transfer = is_first_transfer.swap(false, Ordering::SeqCst);
+                        let _ = event_tx.send(NodeEvent::ClientTransferCompleted {
+                            endpoint_id: remote_endpoint_id,
+                            bytes: file_size - offset,
+                            is_first_transfer,
+                        });
+
+                        debug!("saved file to {local_path:?}");
+
+                        Ok::<(), anyhow::Error>(())
+                    }
+                };
+
+                // keep up to the negotiated number of downloads active and the rest queued. the
+                // limit is checked each time a job finishes or the limit changes, so lowering it
+                // lets active downloads finish instead of interrupting them
+                tokio::pin!(ready_stream);
+                let mut active = FuturesUnordered::new();
+                let mut ready_closed = false;
+                loop {
+                    if ready_closed && active.is_empty() {
+                        break;
+                    }
+
+                    let limit = effective_transfer_limit(
+                        *max_concurrent_transfers.borrow(),
+                        *remote_transfer_limit.borrow(),
+                    );
+                    tokio::select! {
+                        job = ready_stream.next(), if !ready_closed && active.len() < limit => {
+                            match job {
+                                Some((job_id, slot)) => {
+                                    let download = download_job(job_id, slot);
+                                    active.push(async move { (job_id, download.await) });
+                                }
+                                None => ready_closed = true,
+                            }
+                        }
+                        Some((job_id, res)) = active.next(), if !active.is_empty() => {
+                            if let Err(e) = res {
+                                error!("error downloading item: {e:#}");
+
+                                // mark the job as failed, unless it already finished or failed
+                                let reason = TransferErrorReasonModel::from_error(&e);
+                                jobs.alter(&job_id, |_, mut job| {
+                                    if !matches!(
+                                        job.progress,
+                                        ClientTransferJobProgress::Finished { .. }
+                                            | ClientTransferJobProgress::Failed { .. }
+                                    ) {
+                                        job.progress = ClientTransferJobProgress::Failed {
+                                            error: format!("{e:#}"),
+                                            reason,
+                                        };
+                                    }
+                                    job
+                                });
+                                event_tx
+                                    .send(NodeEvent::ClientChanged {
+                                        endpoint_id: connection.remote_id(),
+                                        update: ClientModelUpdate::UpdateTransferJobs,
+                                    })
+                                    .expect("failed to send ClientModelUpdate::UpdateTransferJobs");
+                            }
+                        }
+                        Ok(()) = max_concurrent_transfers.changed() => {}
+                        Ok(()) = remote_transfer_limit.changed() => {}
+                        else => break,
                     }
                 }
             }
@@ -2560,8 +9724,11 @@ impl Client {
 
         Self {
             db,
+            local_endpoint_id,
             download_directory,
             transcode_format,
+            pairing_token,
+            pending_timeout,
 
             event_tx,
             connection,
@@ -2572,59 +9739,85 @@ impl Client {
             ready_tx,
 
             index: Arc::new(Mutex::new(None)),
+            index_complete: Arc::new(AtomicBool::new(false)),
+            index_hashes: Arc::new(Mutex::new(HashMap::new())),
+            index_metadata,
+            index_extra_metadata,
             jobs,
             paused,
             pause_notify,
+            jobs_held,
+            draining,
+            verify_downloads,
+            redownload_changed,
+            remote_transfer_limit,
+            transfer_parts,
+
+            #[cfg(feature = "test-hooks")]
+            test_hooks,
         }
     }
 
+    /// Closes a connection that timed out, and fails its unfinished jobs so the model doesn't
+    /// show them as active.
+    fn close_timed_out(&self, remote_endpoint_id: EndpointId) {
+        self.connection.close(0u32.into(), b"timed out");
+
+        for mut job in self.jobs.iter_mut() {
+            if !matches!(
+                job.progress,
+                ClientTransferJobProgress::Finished { .. }
+                    | ClientTransferJobProgress::Failed { .. }
+            ) {
+                job.progress = ClientTransferJobProgress::Failed {
+                    error: "connection timed out".to_string(),
+                    reason: TransferErrorReasonModel::Network,
+                };
+            }
+        }
+        self.event_tx
+            .send(NodeEvent::ClientChanged {
+                endpoint_id: remote_endpoint_id,
+                update: ClientModelUpdate::UpdateTransferJobs,
+            })
+            .expect("failed to send ClientModelUpdate::UpdateTransferJobs");
+    }
+
     async fn run(self) -> anyhow::Result<()> {
         let remote_endpoint_id = self.connection.remote_id();
 
         // spawn connection info watcher task
         // TODO: check that this is cancelled/cleaned up correctly?
-        tokio::spawn({
-            let connection = self.connection.clone();
+        spawn_connection_info_watcher(self.connection.clone(), {
             let event_tx = self.event_tx.clone();
-            async move {
-                let mut paths_stream = connection.paths_stream();
-                while let Some(paths) = paths_stream.next().await {
-                    let selected_path = paths.iter().find(|path| path.is_selected());
-                    let (remote_addr, rtt_ms) = match selected_path {
-                        Some(selected_path) => {
-                            let remote_addr = selected_path.remote_addr().to_string();
-                            let rtt_ms = selected_path.rtt().as_millis() as u64;
-                            (remote_addr, Some(rtt_ms))
-                        }
-                        None => ("unknown".to_string(), None),
-                    };
-
-                    event_tx
-                        .send(NodeEvent::ClientChanged {
-                            endpoint_id: remote_endpoint_id,
-                            update: ClientModelUpdate::UpdateConnectionInfo {
-                                remote_addr,
-                                rtt_ms,
-                            },
-                        })
-                        .expect("failed to send ClientModelUpdate::UpdateConnectionInfo");
-                }
+            move |info| {
+                event_tx
+                    .send(NodeEvent::ClientChanged {
+                        endpoint_id: remote_endpoint_id,
+                        update: ClientModelUpdate::UpdateConnectionInfo(info),
+                    })
+                    .expect("failed to send ClientModelUpdate::UpdateConnectionInfo");
             }
         });
 
         let (tx, mut rx) = mpsc::unbounded_channel();
+        let command_tx = tx.clone();
+
+        // a two-way sync waits for the index hashes to compare against the local library
+        let mut pending_sync: Option<(SyncConflictPolicy, bool)> = None;
+        let mut received_index_hashes = false;
+
+        // extra metadata of the items in the next index changes, sent before them
+        let mut pending_extra_metadata: Option<Vec<Option<ItemExtraMetadata>>> = None;
+
+        // when the model was last updated while receiving index pages
+        let mut last_index_page_update: Option<Instant> = None;
 
         // open a bidirectional QUIC stream
         let (send, recv) = self.connection.open_bi().await?;
 
         // wrap in framed codecs
-        let mut send = FramedWrite::new(send, LengthDelimitedCodec::new()).with_flat_map(
-            |message: ClientMessageV1| {
-                let buf: Vec<u8> =
-                    postcard::to_stdvec(&message).expect("failed to serialize message");
-                futures::stream::once(futures::future::ready(Ok(Bytes::from(buf))))
-            },
-        );
+        let mut frames = FramedWrite::new(send, LengthDelimitedCodec::new());
         let mut recv = FramedRead::new(recv, LengthDelimitedCodec::new())
             .map_err(|e| anyhow::anyhow!("failed to read from connection: {e:?}"))
             .map(|res| {
@@ -2634,13 +9827,35 @@ impl Client {
                 })
             });
 
-        // send client Identify
-        send.send(ClientMessageV1::Identify {
+        // send client Identify, followed by the identify options
+        let identify_buf = postcard::to_stdvec(&ClientMessageV1::Identify {
             name: device_name().to_string(),
-            transcode_format: self.transcode_format,
+            transcode_format: self
+                .transcode_format
+                .map(|transcode_format| transcode_format.legacy_fallback()),
         })
-        .await
-        .expect("failed to send Identify message");
+        .context("failed to serialize Identify message")?;
+        let group_records = {
+            let db = self.db.get();
+            sync_group::shared_records(&db, remote_endpoint_id)?
+        };
+        let identify_options = IdentifyOptions {
+            paged_index: true,
+            pairing_token: self.pairing_token,
+            transcode_format: self.transcode_format,
+            group_records,
+        };
+        let identify_buf = postcard::to_extend(&identify_options, identify_buf)
+            .context("failed to serialize identify options")?;
+        frames
+            .send(Bytes::from(identify_buf))
+            .await
+            .expect("failed to send Identify message");
+
+        let mut send = frames.with_flat_map(|message: ClientMessageV1| {
+            let buf: Vec<u8> = postcard::to_stdvec(&message).expect("failed to serialize message");
+            futures::stream::once(futures::future::ready(Ok(Bytes::from(buf))))
+        });
 
         // wait for server Identify
         // TODO: also wait for commands
@@ -2661,8 +9876,14 @@ impl Client {
             tx,
 
             index: self.index.clone(),
+            index_complete: self.index_complete.clone(),
+            index_hashes: self.index_hashes.clone(),
+            index_metadata: self.index_metadata.clone(),
+            index_extra_metadata: self.index_extra_metadata.clone(),
             jobs: self.jobs.clone(),
             paused: self.paused.clone(),
+            pause_notify: self.pause_notify.clone(),
+            jobs_held: self.jobs_held.clone(),
         };
         self.event_tx
             .send(NodeEvent::ClientOpened {
@@ -2675,20 +9896,49 @@ impl Client {
             .expect("failed to send NodeEvent::ClientOpened");
 
         // waiting loop, wait for server Accepted
+        let expire = tokio::time::sleep(self.pending_timeout.unwrap_or_default());
+        tokio::pin!(expire);
         loop {
             tokio::select! {
+                () = &mut expire, if self.pending_timeout.is_some() => {
+                    info!("server {remote_endpoint_id} didn't accept the connection in time, closing");
+                    self.connection.close(0u32.into(), b"expired");
+                    return Err(PendingExpired(self.pending_timeout.unwrap_or_default()).into());
+                }
+
                 Some(command) = rx.recv() => {
                     match command {
-                        ClientCommand::Close => {
+                        ClientCommand::Close | ClientCommand::Drain { .. } => {
                             return Ok(());
                         }
 
-                        ClientCommand::SetDownloads { .. } => {
-                            warn!("unexpected SetDownloads command in waiting loop");
+                        ClientCommand::SetDownloads { callback, .. } => {
+                            warn!("unexpected download command in waiting loop");
+                            if let Some(callback) = callback {
+                                let _ = callback.send(Err(anyhow::anyhow!("connection not accepted yet")));
+                            }
+                        }
+                        ClientCommand::PrioritizeDownloads { .. } => {
+                            warn!("unexpected download command in waiting loop");
                         }
                         ClientCommand::PauseDownloads => {
                             warn!("unexpected PauseDownloads command in waiting loop");
                         }
+                        ClientCommand::PauseTransfer { .. }
+                        | ClientCommand::ResumeTransfer { .. }
+                        | ClientCommand::CancelTransfer { .. } => {
+                            warn!("unexpected transfer control command in waiting loop");
+                        }
+                        ClientCommand::Sync { conflict_policy, sync_back } => {
+                            pending_sync = Some((conflict_policy, sync_back));
+                        }
+                        ClientCommand::AutoDownload => {
+                            // the index is received after the connection is accepted, which
+                            // starts auto-download anyway
+                        }
+                        ClientCommand::RequestRescan => {
+                            warn!("ignoring rescan request before the connection is accepted");
+                        }
                     }
                 }
 
@@ -2732,7 +9982,7 @@ impl Client {
 
         // update recent servers in database
         {
-            let db = self.db.lock().unwrap();
+            let db = self.db.get();
             db.update_recent_server(remote_endpoint_id, &server_name, self.connected_at)
                 .context("failed to update recent server in database")?;
         }
@@ -2740,6 +9990,30 @@ impl Client {
             .send(NodeEvent::RecentServersChanged)
             .expect("failed to send NodeEvent::RecentServersChanged");
 
+        // tell the server to send manifests, so downloaded files can be verified later
+        send.send(ClientMessageV1::TransferManifests)
+            .await
+            .expect("failed to send TransferManifests message");
+
+        // while draining, the connection closes once the active downloads finish or the deadline
+        // passes
+        let mut drain_deadline: Option<Instant> = None;
+        let mut drain_check = tokio::time::interval(DRAIN_CHECK_INTERVAL);
+
+        // priority for the next prioritized jobs, so later requests go before earlier ones
+        let mut next_priority = 1;
+
+        // a sync waiting for the index hashes is abandoned if the index stops arriving
+        let mut pending_sync_deadline = pending_sync
+            .is_some()
+            .then(|| tokio::time::Instant::now() + INDEX_HASHES_TIMEOUT);
+
+        // ping the server periodically, and close the connection if a server that pings goes quiet
+        let mut keep_alive = KeepAlive::new(
+            #[cfg(feature = "test-hooks")]
+            self.test_hooks.clone(),
+        );
+
         // main loop
         loop {
             tokio::select! {
@@ -2750,16 +10024,42 @@ impl Client {
                             break;
                         }
 
-                        ClientCommand::SetDownloads { items } => {
+                        ClientCommand::Drain { timeout } => {
+                            if drain_deadline.is_some() {
+                                continue;
+                            }
+                            info!("draining connection to {remote_endpoint_id}");
+                            self.draining.store(true, Ordering::Relaxed);
+                            drain_deadline = Some(Instant::now() + timeout);
+
+                            self.event_tx.send(NodeEvent::ClientChanged {
+                                endpoint_id: remote_endpoint_id,
+                                update: ClientModelUpdate::Drain,
+                            }).expect("failed to send ClientModelUpdate::Drain");
+                        }
+
+                        ClientCommand::SetDownloads { callback, .. } if drain_deadline.is_some() => {
+                            warn!("ignoring SetDownloads while draining");
+                            if let Some(callback) = callback {
+                                let _ = callback.send(Err(anyhow::anyhow!("connection is draining")));
+                            }
+                        }
+
+                        ClientCommand::SetDownloads { items, callback } => {
                             info!("setting downloads: {} items", items.len());
 
                             // get index
                             let index = {
                                 let index = self.index.lock().unwrap();
-                                index.clone()
+                                index.clone().filter(|_| self.index_complete.load(Ordering::Acquire))
                             };
                             let Some(index) = index else {
-                                error!("SetDownloads: no index available");
+                                error!("SetDownloads: no complete index available");
+                                if let Some(callback) = callback {
+                                    let _ = callback.send(Err(TransferError::IndexUnavailable {
+                                        endpoint_id: remote_endpoint_id.to_string(),
+                                    }.into()));
+                                }
                                 continue;
                             };
 
@@ -2810,11 +10110,18 @@ impl Client {
                                 download_directory.clone()
                             };
 
-                            // create jobs for new items
-                            let download_requests = {
-                                let db = self.db.lock().unwrap();
+                            // get content hashes to skip files that are already downloaded and unchanged
+                            let index_hashes = {
+                                let index_hashes = self.index_hashes.lock().unwrap();
+                                index_hashes.clone()
+                            };
+                            let verify_downloads = self.verify_downloads.load(Ordering::Relaxed);
+
+                            // find new items and their expected sizes
+                            let new_items = {
+                                let mut db = self.db.get();
                                 items.into_iter().flat_map(|item| {
-                                    let Ok(file_endpoint_id) = item.endpoint_id.parse() else {
+                                    let Ok(file_endpoint_id) = item.endpoint_id.parse::<EndpointId>() else {
                                         warn!("SetDownloads: invalid endpoint ID");
                                         return None;
                                     };
@@ -2825,41 +10132,119 @@ impl Client {
                                     }
 
                                     // find item in index
-                                    let Some(_index_item) = index.iter().find(|i| {
+                                    let Some(index_item) = index.iter().find(|i| {
                                         i.endpoint_id == file_endpoint_id && i.root == item.root && i.path == item.path
                                     }) else {
                                         warn!("SetDownloads: item not found in index: {item:?}");
                                         return None;
                                     };
 
-                                    // check if file is downloaded to the current download directory
+                                    // check if file is downloaded to the current download directory and unchanged
+                                    let content_hash = index_hashes.get(&(file_endpoint_id, item.root.clone(), item.path.clone()));
                                     let downloaded = download_directory.as_ref().is_some_and(|download_directory| {
-                                        db.exists_file_by_node_root_path_localtree(
-                                            file_endpoint_id, &item.root, &item.path, download_directory,
+                                        is_already_downloaded(
+                                            &mut db,
+                                            file_endpoint_id,
+                                            &item.root,
+                                            &item.path,
+                                            download_directory,
+                                            content_hash,
+                                            verify_downloads,
                                         )
-                                        .unwrap_or(false)
                                     });
                                     if downloaded {
                                         // TODO: maybe add a finished job instead
                                         return None;
                                     }
 
+                                    let expected_size = match index_item.file_size {
+                                        FileSize::Estimated(size) | FileSize::Actual(size) => size,
+                                        FileSize::Unknown => 0,
+                                    };
+                                    Some((file_endpoint_id, item, expected_size))
+                                }).collect::<Vec<_>>()
+                            };
+
+                            // queue only the items that fit in the free space of the download
+                            // directory, instead of failing with write errors halfway through
+                            let required_bytes = new_items.iter().map(|(_, _, expected_size)| expected_size).sum::<u64>();
+                            let available_bytes = match download_directory.clone().map(TreePath::from_root) {
+                                Some(Ok(download_directory)) if !new_items.is_empty() => {
+                                    match crate::fs::available_space(&download_directory).await {
+                                        Ok(available_bytes) => available_bytes,
+                                        Err(e) => {
+                                            warn!("SetDownloads: failed to check free space: {e:#}");
+                                            None
+                                        }
+                                    }
+                                }
+                                _ => None,
+                            };
+                            let new_items = match available_bytes {
+                                Some(available_bytes) if required_bytes > available_bytes => {
+                                    let num_items = new_items.len();
+                                    let mut remaining_bytes = available_bytes;
+                                    let new_items = new_items.into_iter()
+                                        .take_while(|(_, _, expected_size)| {
+                                            let fits = *expected_size <= remaining_bytes;
+                                            remaining_bytes = remaining_bytes.saturating_sub(*expected_size);
+                                            fits
+                                        })
+                                        .collect::<Vec<_>>();
+                                    let skipped_items = (num_items - new_items.len()) as u64;
+                                    warn!("SetDownloads: {required_bytes} bytes needed but {available_bytes} available, skipping {skipped_items} items");
+
+                                    self.event_tx.send(NodeEvent::ClientChanged {
+                                        endpoint_id: remote_endpoint_id,
+                                        update: ClientModelUpdate::UpdateInsufficientSpace(Some(InsufficientSpaceModel {
+                                            required_bytes,
+                                            available_bytes,
+                                            skipped_items,
+                                        })),
+                                    }).expect("failed to send ClientModelUpdate::UpdateInsufficientSpace");
+
+                                    new_items
+                                }
+                                _ => {
+                                    if !new_items.is_empty() {
+                                        self.event_tx.send(NodeEvent::ClientChanged {
+                                            endpoint_id: remote_endpoint_id,
+                                            update: ClientModelUpdate::UpdateInsufficientSpace(None),
+                                        }).expect("failed to send ClientModelUpdate::UpdateInsufficientSpace");
+                                    }
+                                    new_items
+                                }
+                            };
+
+                            // create jobs for new items
+                            let download_requests = new_items
+                                .into_iter()
+                                .map(|(file_endpoint_id, item, _)| {
                                     let job_id = self.next_job_id.fetch_add(1, Ordering::Relaxed);
                                     self.jobs.insert(job_id, ClientTransferJob {
                                         progress: ClientTransferJobProgress::Requested,
+                                        control: watch::Sender::new(JobControl::Run),
+                                        deferred: false,
+                                        collision: None,
+                                        sanitized_path: None,
+                                        priority: 0,
                                         file_endpoint_id,
                                         file_root: item.root.clone(),
                                         file_path: item.path.clone(),
                                     });
 
-                                    Some(DownloadItem {
+                                    DownloadItem {
                                         job_id,
                                         endpoint_id: file_endpoint_id,
                                         root: item.root,
                                         path: item.path,
-                                    })
-                                }).collect::<Vec<_>>()
-                            };
+                                    }
+                                })
+                                .collect::<Vec<_>>();
+                            let queued_items = download_requests
+                                .iter()
+                                .map(|item| (item.root.clone(), item.path.clone()))
+                                .collect::<Vec<_>>();
 
                             // send download request for new jobs
                             if !download_requests.is_empty() {
@@ -2885,6 +10270,44 @@ impl Client {
                                 endpoint_id: remote_endpoint_id,
                                 update: ClientModelUpdate::UpdatePaused,
                             }).expect("failed to send ClientModelUpdate::UpdatePaused");
+
+                            if let Some(callback) = callback {
+                                let _ = callback.send(Ok(queued_items));
+                            }
+                        }
+
+                        ClientCommand::PrioritizeDownloads { items } => {
+                            info!("prioritizing downloads: {} items", items.len());
+
+                            let requested_keys: HashSet<(&str, &str)> = items.iter()
+                                .map(|item| (item.root.as_str(), item.path.as_str()))
+                                .collect();
+
+                            // move up the jobs that haven't started yet
+                            let mut transcoding_job_ids = Vec::new();
+                            for mut entry in self.jobs.iter_mut() {
+                                let job_id = *entry.key();
+                                let job = entry.value_mut();
+                                if !requested_keys.contains(&(job.file_root.as_str(), job.file_path.as_str())) {
+                                    continue;
+                                }
+                                match job.progress {
+                                    ClientTransferJobProgress::Requested | ClientTransferJobProgress::Transcoding => {
+                                        transcoding_job_ids.push(job_id);
+                                    }
+                                    ClientTransferJobProgress::Ready { .. } => {}
+                                    _ => continue,
+                                }
+                                job.priority = next_priority;
+                            }
+                            next_priority += 1;
+
+                            // jobs that aren't ready yet are waiting for the server to transcode them
+                            if !transcoding_job_ids.is_empty() {
+                                send.send(ClientMessageV1::PrioritizeTranscodes(transcoding_job_ids))
+                                    .await
+                                    .expect("failed to send PrioritizeTranscodes message");
+                            }
                         }
 
                         ClientCommand::PauseDownloads => {
@@ -2908,61 +10331,550 @@ impl Client {
                                 update: ClientModelUpdate::UpdatePaused,
                             }).expect("failed to send ClientModelUpdate::UpdatePaused");
                         }
+
+                        ClientCommand::PauseTransfer { job_id } => {
+                            info!("pausing job {job_id}");
+
+                            {
+                                let Some(job) = self.jobs.get(&job_id) else {
+                                    warn!("PauseTransfer: no job found with id {job_id}");
+                                    continue;
+                                };
+                                // don't un-cancel a cancelled job
+                                job.control.send_if_modified(|control| {
+                                    let modified = *control == JobControl::Run;
+                                    if modified {
+                                        *control = JobControl::Pause;
+                                    }
+                                    modified
+                                });
+                            }
+
+                            // update model
+                            self.event_tx.send(NodeEvent::ClientChanged {
+                                endpoint_id: remote_endpoint_id,
+                                update: ClientModelUpdate::UpdateTransferJobs,
+                            }).expect("failed to send ClientModelUpdate::UpdateTransferJobs");
+                            self.event_tx.send(NodeEvent::ClientChanged {
+                                endpoint_id: remote_endpoint_id,
+                                update: ClientModelUpdate::UpdateIndex,
+                            }).expect("failed to send ClientModelUpdate::UpdateIndex");
+                        }
+
+                        ClientCommand::ResumeTransfer { job_id } => {
+                            info!("resuming job {job_id}");
+
+                            let requeue = {
+                                let Some(mut job) = self.jobs.get_mut(&job_id) else {
+                                    warn!("ResumeTransfer: no job found with id {job_id}");
+                                    continue;
+                                };
+                                job.control.send_if_modified(|control| {
+                                    let modified = *control == JobControl::Pause;
+                                    if modified {
+                                        *control = JobControl::Run;
+                                    }
+                                    modified
+                                });
+                                std::mem::take(&mut job.deferred)
+                            };
+
+                            // send job id to ready channel again if it was skipped while paused
+                            if requeue {
+                                self.ready_tx.send(job_id).context("failed to send job id to ready channel")?;
+                            }
+
+                            // update model
+                            self.event_tx.send(NodeEvent::ClientChanged {
+                                endpoint_id: remote_endpoint_id,
+                                update: ClientModelUpdate::UpdateTransferJobs,
+                            }).expect("failed to send ClientModelUpdate::UpdateTransferJobs");
+                            self.event_tx.send(NodeEvent::ClientChanged {
+                                endpoint_id: remote_endpoint_id,
+                                update: ClientModelUpdate::UpdateIndex,
+                            }).expect("failed to send ClientModelUpdate::UpdateIndex");
+                        }
+
+                        ClientCommand::CancelTransfer { job_id } => {
+                            info!("cancelling job {job_id}");
+
+                            {
+                                let Some(mut job) = self.jobs.get_mut(&job_id) else {
+                                    warn!("CancelTransfer: no job found with id {job_id}");
+                                    continue;
+                                };
+                                if matches!(
+                                    job.progress,
+                                    ClientTransferJobProgress::Finished { .. }
+                                    | ClientTransferJobProgress::Failed { .. }
+                                ) {
+                                    continue;
+                                }
+                                job.control.send_replace(JobControl::Cancel);
+
+                                // jobs that haven't started can be marked as failed now, otherwise
+                                // the download task stops the transfer and marks it as failed
+                                if !matches!(job.progress, ClientTransferJobProgress::InProgress { .. }) {
+                                    job.progress = ClientTransferJobProgress::Failed {
+                                        error: "cancelled".to_string(),
+                                        reason: TransferErrorReasonModel::Cancelled,
+                                    };
+                                }
+                            }
+
+                            // update model
+                            self.event_tx.send(NodeEvent::ClientChanged {
+                                endpoint_id: remote_endpoint_id,
+                                update: ClientModelUpdate::UpdateTransferJobs,
+                            }).expect("failed to send ClientModelUpdate::UpdateTransferJobs");
+                            self.event_tx.send(NodeEvent::ClientChanged {
+                                endpoint_id: remote_endpoint_id,
+                                update: ClientModelUpdate::UpdateIndex,
+                            }).expect("failed to send ClientModelUpdate::UpdateIndex");
+                        }
+
+                        ClientCommand::Sync { .. } | ClientCommand::AutoDownload if drain_deadline.is_some() => {
+                            warn!("ignoring sync or auto-download while draining");
+                        }
+
+                        ClientCommand::AutoDownload => {
+                            if !received_index_hashes {
+                                // started again once the index is complete
+                                continue;
+                            }
+
+                            // download files again that changed on the server since they were downloaded
+                            let mut items = Vec::new();
+                            if self.redownload_changed.load(Ordering::Relaxed) {
+                                match self.changed_download_items() {
+                                    Ok(changed) => {
+                                        if !changed.is_empty() {
+                                            info!("downloading {} changed items again", changed.len());
+                                            let changed_keys = changed.iter()
+                                                .map(|item| (item.root.clone(), item.path.clone()))
+                                                .collect();
+                                            clear_settled_jobs(&self.jobs, &changed_keys);
+                                        }
+                                        items = changed;
+                                    }
+                                    Err(e) => error!("failed to get changed items: {e:#}"),
+                                }
+                            }
+
+                            // only auto-download from trusted nodes
+                            let (auto_download, storage_quota) = {
+                                let db = self.db.get();
+                                if db.is_node_trusted(remote_endpoint_id)? {
+                                    (db.get_auto_download(remote_endpoint_id)?, db.get_storage_quota(remote_endpoint_id)?)
+                                } else {
+                                    (None, None)
+                                }
+                            };
+                            if let Some(auto_download) = auto_download {
+                                match self.auto_download_items(&auto_download.into(), storage_quota) {
+                                    Ok((auto_items, storage_quota)) => {
+                                        if let Some(storage_quota) = &storage_quota
+                                            && storage_quota.skipped_items > 0
+                                        {
+                                            info!("auto-download: {} items don't fit in the storage quota", storage_quota.skipped_items);
+                                        }
+                                        self.event_tx.send(NodeEvent::ClientChanged {
+                                            endpoint_id: remote_endpoint_id,
+                                            update: ClientModelUpdate::UpdateStorageQuota(storage_quota),
+                                        }).expect("failed to send ClientModelUpdate::UpdateStorageQuota");
+
+                                        // changed files can also be chosen by auto-download
+                                        let changed_keys: HashSet<(String, String)> = items.iter()
+                                            .map(|item| (item.root.clone(), item.path.clone()))
+                                            .collect();
+                                        let auto_items = auto_items.into_iter()
+                                            .filter(|item| !changed_keys.contains(&(item.root.clone(), item.path.clone())))
+                                            .collect::<Vec<_>>();
+                                        if !auto_items.is_empty() {
+                                            info!("auto-download: downloading {} new items", auto_items.len());
+                                        }
+                                        items.extend(auto_items);
+                                    }
+                                    Err(e) => error!("failed to get items for auto-download: {e:#}"),
+                                }
+                            }
+                            if items.is_empty() {
+                                continue;
+                            }
+
+                            let items = self.with_existing_jobs(items);
+                            command_tx.send(ClientCommand::SetDownloads { items, callback: None }).expect("failed to send ClientCommand::SetDownloads");
+                        }
+
+                        ClientCommand::RequestRescan => {
+                            send.send(ClientMessageV1::Rescan)
+                                .await
+                                .expect("failed to send Rescan message");
+                        }
+
+                        ClientCommand::Sync { conflict_policy, sync_back } => {
+                            if !received_index_hashes {
+                                debug!("waiting for index hashes before syncing");
+                                pending_sync = Some((conflict_policy, sync_back));
+                                pending_sync_deadline.get_or_insert_with(|| tokio::time::Instant::now() + INDEX_HASHES_TIMEOUT);
+                                continue;
+                            }
+
+                            info!("starting two-way sync");
+                            let items = match self.two_way_sync_items(conflict_policy) {
+                                Ok(items) => items,
+                                Err(e) => {
+                                    error!("failed to get items for two-way sync: {e:#}");
+                                    continue;
+                                }
+                            };
+                            info!("two-way sync: downloading {} items", items.len());
+                            command_tx.send(ClientCommand::SetDownloads { items, callback: None }).expect("failed to send ClientCommand::SetDownloads");
+
+                            // ask the server to download what it lacks from us
+                            if sync_back {
+                                send.send(ClientMessageV1::SyncBack { conflict_policy })
+                                    .await
+                                    .expect("failed to send SyncBack message");
+                            }
+                        }
                     }
                 }
 
-                next_message = recv.next() => {
-                    match next_message {
-                        Some(Ok(message)) => {
-                            match message {
-                                ServerMessageV1::Index(new_index) => {
-                                    info!("received index with {} items", new_index.len());
-                                    {
-                                        let mut index = self.index.lock().unwrap();
-                                        *index = Some(new_index);
+                next_message = recv.next() => {
+                    match next_message {
+                        Some(Ok(message)) => {
+                            keep_alive.received(matches!(message, ServerMessageV1::Ping));
+                            match message {
+                                ServerMessageV1::Ping => {}
+
+                                ServerMessageV1::Index(new_index) => {
+                                    info!("received index with {} items", new_index.len());
+                                    {
+                                        let mut index = self.index.lock().unwrap();
+                                        *index = Some(new_index);
+                                        self.index_complete.store(true, Ordering::Release);
+                                    }
+                                    // hashes and tags of the previous index no longer apply
+                                    self.index_hashes.lock().unwrap().clear();
+                                    self.index_metadata.lock().unwrap().clear();
+                                    self.index_extra_metadata.lock().unwrap().clear();
+
+                                    // update model
+                                    self.event_tx.send(NodeEvent::ClientChanged {
+                                        endpoint_id: remote_endpoint_id,
+                                        update: ClientModelUpdate::UpdateIndex,
+                                    }).expect("failed to send ClientModelUpdate::UpdateIndex");
+                                }
+
+                                // Current servers don't send IndexUpdate messages, but this branch
+                                // was kept for backwards compatibility.
+                                #[allow(deprecated)]
+                                ServerMessageV1::IndexUpdate(updates) => {
+                                    info!("received index update with {} items", updates.len());
+                                    {
+                                        let mut index = self.index.lock().unwrap();
+                                        if let Some(index) = index.as_mut() {
+                                            for update in updates {
+                                                match update {
+                                                    IndexUpdateItem::FileSize { endpoint_id, root, path, file_size } => {
+                                                        // TODO: don't be exponential
+                                                        for item in index.iter_mut() {
+                                                            if item.endpoint_id == endpoint_id && item.root == root && item.path == path {
+                                                                item.file_size = file_size;
+                                                            }
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                        } else {
+                                            warn!("received index update but index is None, ignoring");
+                                        }
+                                    }
+
+                                    // update model
+                                    self.event_tx.send(NodeEvent::ClientChanged {
+                                        endpoint_id: remote_endpoint_id,
+                                        update: ClientModelUpdate::UpdateIndex,
+                                    }).expect("failed to send ClientModelUpdate::UpdateIndex");
+                                }
+
+                                ServerMessageV1::IndexHashes(hashes) => {
+                                    info!("received {} index hashes", hashes.len());
+                                    {
+                                        let index = self.index.lock().unwrap();
+                                        let Some(index) = index.as_ref() else {
+                                            warn!("received index hashes but index is None, ignoring");
+                                            continue;
+                                        };
+                                        if index.len() != hashes.len() {
+                                            warn!("received {} index hashes for {} index items, ignoring", hashes.len(), index.len());
+                                            continue;
+                                        }
+
+                                        let mut index_hashes = self.index_hashes.lock().unwrap();
+                                        *index_hashes = index.iter()
+                                            .zip(hashes)
+                                            .filter_map(|(item, hash)| {
+                                                Some(((item.endpoint_id, item.root.clone(), item.path.clone()), hash?))
+                                            })
+                                            .collect();
+                                    }
+                                    received_index_hashes = true;
+
+                                    // start a sync that was waiting for the hashes
+                                    pending_sync_deadline = None;
+                                    if let Some((conflict_policy, sync_back)) = pending_sync.take() {
+                                        command_tx.send(ClientCommand::Sync { conflict_policy, sync_back }).expect("failed to send ClientCommand::Sync");
+                                    }
+
+                                    // update model
+                                    self.event_tx.send(NodeEvent::ClientChanged {
+                                        endpoint_id: remote_endpoint_id,
+                                        update: ClientModelUpdate::UpdateIndex,
+                                    }).expect("failed to send ClientModelUpdate::UpdateIndex");
+                                }
+
+                                ServerMessageV1::IndexMetadata(metadata) => {
+                                    info!("received {} index metadata items", metadata.len());
+                                    {
+                                        let index = self.index.lock().unwrap();
+                                        let Some(index) = index.as_ref() else {
+                                            warn!("received index metadata but index is None, ignoring");
+                                            continue;
+                                        };
+                                        if index.len() != metadata.len() {
+                                            warn!("received {} index metadata items for {} index items, ignoring", metadata.len(), index.len());
+                                            continue;
+                                        }
+
+                                        let mut index_metadata = self.index_metadata.lock().unwrap();
+                                        *index_metadata = index.iter()
+                                            .zip(metadata)
+                                            .filter_map(|(item, metadata)| {
+                                                Some(((item.endpoint_id, item.root.clone(), item.path.clone()), metadata?))
+                                            })
+                                            .collect();
+                                    }
+
+                                    // update model
+                                    self.event_tx.send(NodeEvent::ClientChanged {
+                                        endpoint_id: remote_endpoint_id,
+                                        update: ClientModelUpdate::UpdateIndex,
+                                    }).expect("failed to send ClientModelUpdate::UpdateIndex");
+
+                                    // the metadata is sent last, so the index is complete
+                                    command_tx.send(ClientCommand::AutoDownload).expect("failed to send ClientCommand::AutoDownload");
+                                }
+
+                                ServerMessageV1::IndexChanges(changes) => {
+                                    info!("received index changes with {} changed and {} removed items", changes.items.len(), changes.removed.len());
+                                    {
+                                        let mut index = self.index.lock().unwrap();
+                                        let mut index_hashes = self.index_hashes.lock().unwrap();
+                                        let mut index_metadata = self.index_metadata.lock().unwrap();
+                                        let mut index_extra_metadata = self.index_extra_metadata.lock().unwrap();
+                                        let Some(index) = index.as_mut() else {
+                                            warn!("received index changes but index is None, ignoring");
+                                            continue;
+                                        };
+
+                                        // older servers don't send extra metadata, so the changed items have none
+                                        let extra_metadata = pending_extra_metadata
+                                            .take()
+                                            .filter(|extra_metadata| extra_metadata.len() == changes.items.len())
+                                            .unwrap_or_else(|| vec![None; changes.items.len()]);
+
+                                        let removed = changes.removed.into_iter().collect::<HashSet<_>>();
+                                        let changed = changes.items.iter().map(|item| (item.root.clone(), item.path.clone())).collect::<HashSet<_>>();
+                                        index.retain(|item| {
+                                            let key = (item.root.clone(), item.path.clone());
+                                            !removed.contains(&key) && !changed.contains(&key)
+                                        });
+                                        index_hashes.retain(|(_, root, path), _| !removed.contains(&(root.clone(), path.clone())));
+                                        index_metadata.retain(|(_, root, path), _| !removed.contains(&(root.clone(), path.clone())));
+                                        index_extra_metadata.retain(|(_, root, path), _| !removed.contains(&(root.clone(), path.clone())));
+
+                                        for (((item, hash), metadata), extra_metadata) in changes.items.into_iter().zip(changes.hashes).zip(changes.metadata).zip(extra_metadata) {
+                                            let key = (item.endpoint_id, item.root.clone(), item.path.clone());
+                                            match hash {
+                                                Some(hash) => index_hashes.insert(key.clone(), hash),
+                                                None => index_hashes.remove(&key),
+                                            };
+                                            match metadata {
+                                                Some(metadata) => index_metadata.insert(key.clone(), metadata),
+                                                None => index_metadata.remove(&key),
+                                            };
+                                            match extra_metadata {
+                                                Some(extra_metadata) => index_extra_metadata.insert(key, extra_metadata),
+                                                None => index_extra_metadata.remove(&key),
+                                            };
+                                            index.push(item);
+                                        }
+                                    }
+
+                                    // download new files from servers that are mirrored automatically
+                                    command_tx.send(ClientCommand::AutoDownload).expect("failed to send ClientCommand::AutoDownload");
+
+                                    // update model
+                                    self.event_tx.send(NodeEvent::ClientChanged {
+                                        endpoint_id: remote_endpoint_id,
+                                        update: ClientModelUpdate::UpdateIndex,
+                                    }).expect("failed to send ClientModelUpdate::UpdateIndex");
+                                }
+
+                                ServerMessageV1::IndexPage(page) => {
+                                    let page_items = match IndexPageItems::decompress(&page) {
+                                        Ok(page_items) => page_items,
+                                        Err(e) => {
+                                            error!("failed to read index page: {e:#}");
+                                            continue;
+                                        }
+                                    };
+                                    debug!("received index page with {} items at offset {} of {}", page_items.items.len(), page.offset, page.total);
+
+                                    // the index is still arriving, so keep waiting for its hashes
+                                    if let Some(deadline) = &mut pending_sync_deadline {
+                                        *deadline = tokio::time::Instant::now() + INDEX_HASHES_TIMEOUT;
+                                    }
+
+                                    let complete = {
+                                        let mut index = self.index.lock().unwrap();
+                                        let mut index_hashes = self.index_hashes.lock().unwrap();
+                                        let mut index_metadata = self.index_metadata.lock().unwrap();
+                                        let mut index_extra_metadata = self.index_extra_metadata.lock().unwrap();
+
+                                        // the first page replaces the previous index
+                                        if page.offset == 0 {
+                                            *index = Some(Vec::new());
+                                            self.index_complete.store(false, Ordering::Release);
+                                            index_hashes.clear();
+                                            index_metadata.clear();
+                                            index_extra_metadata.clear();
+                                            received_index_hashes = false;
+                                        }
+
+                                        let Some(index) = index.as_mut() else {
+                                            warn!("received index page but index is None, ignoring");
+                                            continue;
+                                        };
+                                        if index.len() as u64 != page.offset {
+                                            warn!("received index page at offset {} with {} items, ignoring", page.offset, index.len());
+                                            continue;
+                                        }
+
+                                        // older servers don't send extra metadata
+                                        let mut extra_metadata = page_items.extra_metadata.into_iter();
+                                        for ((item, hash), metadata) in page_items.items.into_iter().zip(page_items.hashes).zip(page_items.metadata) {
+                                            let key = (item.endpoint_id, item.root.clone(), item.path.clone());
+                                            if let Some(hash) = hash {
+                                                index_hashes.insert(key.clone(), hash);
+                                            }
+                                            if let Some(metadata) = metadata {
+                                                index_metadata.insert(key.clone(), metadata);
+                                            }
+                                            if let Some(extra_metadata) = extra_metadata.next().flatten() {
+                                                index_extra_metadata.insert(key, extra_metadata);
+                                            }
+                                            index.push(item);
+                                        }
+
+                                        let complete = index.len() as u64 >= page.total;
+                                        if complete {
+                                            self.index_complete.store(true, Ordering::Release);
+                                        }
+                                        complete
+                                    };
+
+                                    if complete {
+                                        info!("received index with {} items", page.total);
+                                        received_index_hashes = true;
+
+                                        // start a sync that was waiting for the hashes
+                                        pending_sync_deadline = None;
+                                        if let Some((conflict_policy, sync_back)) = pending_sync.take() {
+                                            command_tx.send(ClientCommand::Sync { conflict_policy, sync_back }).expect("failed to send ClientCommand::Sync");
+                                        }
+
+                                        command_tx.send(ClientCommand::AutoDownload).expect("failed to send ClientCommand::AutoDownload");
+                                    }
+
+                                    // update model as pages arrive, but not for every page of a huge index
+                                    if complete || last_index_page_update.is_none_or(|t| t.elapsed() >= INDEX_PAGE_MODEL_UPDATE_INTERVAL) {
+                                        last_index_page_update = Some(Instant::now());
+                                        self.event_tx.send(NodeEvent::ClientChanged {
+                                            endpoint_id: remote_endpoint_id,
+                                            update: ClientModelUpdate::UpdateIndex,
+                                        }).expect("failed to send ClientModelUpdate::UpdateIndex");
                                     }
+                                }
 
-                                    // update model
-                                    self.event_tx.send(NodeEvent::ClientChanged {
-                                        endpoint_id: remote_endpoint_id,
-                                        update: ClientModelUpdate::UpdateIndex,
-                                    }).expect("failed to send ClientModelUpdate::UpdateIndex");
+                                ServerMessageV1::TransferLimit(limit) => {
+                                    info!("server allows {limit} concurrent transfers");
+                                    self.remote_transfer_limit.send_replace(Some(limit));
                                 }
 
-                                // Current servers don't send IndexUpdate messages, but this branch
-                                // was kept for backwards compatibility.
-                                #[allow(deprecated)]
-                                ServerMessageV1::IndexUpdate(updates) => {
-                                    info!("received index update with {} items", updates.len());
-                                    {
-                                        let mut index = self.index.lock().unwrap();
-                                        if let Some(index) = index.as_mut() {
-                                            for update in updates {
-                                                match update {
-                                                    IndexUpdateItem::FileSize { endpoint_id, root, path, file_size } => {
-                                                        // TODO: don't be exponential
-                                                        for item in index.iter_mut() {
-                                                            if item.endpoint_id == endpoint_id && item.root == root && item.path == path {
-                                                                item.file_size = file_size;
-                                                            }
-                                                        }
-                                                    }
-                                                }
+                                ServerMessageV1::TransferParts => {
+                                    self.transfer_parts.store(true, Ordering::Relaxed);
+                                }
+
+                                ServerMessageV1::IndexChangesExtraMetadata(extra_metadata) => {
+                                    pending_extra_metadata = Some(extra_metadata);
+                                }
+
+                                ServerMessageV1::GroupRecords(records) => {
+                                    let merged = {
+                                        let db = self.db.get();
+                                        sync_group::merge_records(&db, self.local_endpoint_id, records)
+                                    };
+                                    match merged {
+                                        Ok(outcome) => {
+                                            if outcome.changed {
+                                                self.event_tx.send(NodeEvent::SyncGroupsChanged).expect("failed to send NodeEvent::SyncGroupsChanged");
                                             }
-                                        } else {
-                                            warn!("received index update but index is None, ignoring");
+                                            if !outcome.invitations.is_empty() {
+                                                self.event_tx.send(NodeEvent::SyncGroupsInvited(outcome.invitations)).expect("failed to send NodeEvent::SyncGroupsInvited");
+                                            }
+                                        }
+                                        Err(e) => {
+                                            error!("failed to merge sync group records from {remote_endpoint_id}: {e:#}");
                                         }
                                     }
+                                }
 
-                                    // update model
-                                    self.event_tx.send(NodeEvent::ClientChanged {
-                                        endpoint_id: remote_endpoint_id,
-                                        update: ClientModelUpdate::UpdateIndex,
-                                    }).expect("failed to send ClientModelUpdate::UpdateIndex");
+                                ServerMessageV1::Push(_) if drain_deadline.is_some() => {
+                                    warn!("ignoring push while draining");
+                                }
+
+                                ServerMessageV1::Push(items) => {
+                                    // only let trusted nodes make us download files
+                                    let is_trusted = {
+                                        let db = self.db.get();
+                                        db.is_node_trusted(remote_endpoint_id)?
+                                    };
+                                    if !is_trusted {
+                                        warn!("ignoring push from untrusted node {remote_endpoint_id}");
+                                        continue;
+                                    }
+
+                                    info!("server pushed {} items", items.len());
+                                    let download_items = self.with_existing_jobs(items.into_iter().map(|item| DownloadRequestModel {
+                                        endpoint_id: item.endpoint_id.to_string(),
+                                        root: item.root,
+                                        path: item.path,
+                                    }).collect());
+                                    command_tx.send(ClientCommand::SetDownloads { items: download_items, callback: None }).expect("failed to send ClientCommand::SetDownloads");
                                 }
 
                                 ServerMessageV1::JobStatus(status_changes) => {
                                     for (job_id, status) in status_changes {
+                                        // cancelled jobs stay failed
+                                        let is_cancelled = self.jobs.get(&job_id)
+                                            .is_some_and(|job| *job.control.borrow() == JobControl::Cancel);
+                                        if is_cancelled {
+                                            continue;
+                                        }
+
                                         match status {
                                             JobStatusItem::Transcoding => {
                                                 // set job status to Transcoding
@@ -2984,7 +10896,10 @@ impl Client {
                                             JobStatusItem::Failed { error } => {
                                                 // set job status to Failed
                                                 self.jobs.alter(&job_id, |_, mut job| {
-                                                    job.progress = ClientTransferJobProgress::Failed { error };
+                                                    job.progress = ClientTransferJobProgress::Failed {
+                                                        reason: TransferErrorReasonModel::from_remote_error(&error),
+                                                        error,
+                                                    };
                                                     job
                                                 });
                                             },
@@ -3018,6 +10933,37 @@ impl Client {
                     break;
                 }
 
+                res = keep_alive.tick() => {
+                    if let Err(e) = res {
+                        warn!("connection to {remote_endpoint_id} timed out");
+                        self.close_timed_out(remote_endpoint_id);
+                        return Err(e.into());
+                    }
+
+                    keep_alive.try_ping(&mut send, ClientMessageV1::Ping);
+                }
+
+                _ = tokio::time::sleep_until(pending_sync_deadline.unwrap_or_else(tokio::time::Instant::now)), if pending_sync_deadline.is_some() => {
+                    pending_sync_deadline = None;
+                    if pending_sync.take().is_some() {
+                        warn!("timed out waiting for index hashes from {remote_endpoint_id}, abandoning sync");
+                    }
+                }
+
+                _ = drain_check.tick(), if drain_deadline.is_some() => {
+                    let active = self.jobs.iter().any(|job| {
+                        matches!(job.progress, ClientTransferJobProgress::InProgress { .. })
+                    });
+                    let timed_out = drain_deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                    if !active || timed_out {
+                        if active {
+                            warn!("drain timed out with active downloads, closing connection to {remote_endpoint_id}");
+                        }
+                        self.connection.close(0u32.into(), b"close");
+                        break;
+                    }
+                }
+
                 else => {
                     warn!("all senders dropped in Client::run, shutting down");
                     break;
@@ -3029,10 +10975,814 @@ impl Client {
 
         Ok(())
     }
+
+    /// Adds the items of the existing jobs to the given items, since SetDownloads removes unstarted
+    /// jobs that aren't requested when paused.
+    fn with_existing_jobs(&self, items: Vec<DownloadRequestModel>) -> Vec<DownloadRequestModel> {
+        let mut download_items = self
+            .jobs
+            .iter()
+            .map(|entry| {
+                let job = entry.value();
+                DownloadRequestModel {
+                    endpoint_id: job.file_endpoint_id.to_string(),
+                    root: job.file_root.clone(),
+                    path: job.file_path.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+        download_items.extend(items);
+        download_items
+    }
+
+    /// Finds the files in the server's index that were downloaded to the current download
+    /// directory, but changed on the server since.
+    fn changed_download_items(&self) -> anyhow::Result<Vec<DownloadRequestModel>> {
+        let download_directory = self.download_directory.lock().unwrap().clone();
+        let download_directory = download_directory.context("no download directory set")?;
+        let index = self.index.lock().unwrap().clone();
+        let index = index
+            .filter(|_| self.index_complete.load(Ordering::Acquire))
+            .context("no complete index available")?;
+        let index_hashes = self.index_hashes.lock().unwrap().clone();
+
+        let db = self.db.get();
+        let changed = find_changed_downloads(
+            &db,
+            self.connection.remote_id(),
+            &index,
+            &index_hashes,
+            &download_directory,
+        );
+
+        Ok(changed
+            .into_iter()
+            .map(|file| DownloadRequestModel {
+                endpoint_id: file.endpoint_id,
+                root: file.root,
+                path: file.path,
+            })
+            .collect())
+    }
+
+    /// Chooses the files in the server's index to download automatically.
+    ///
+    /// Files are skipped if they're already downloaded to the current download directory or have
+    /// a job, or if they don't match the selections or the server's download filter. The rest are
+    /// queued by priority: files matching earlier selections first, then the most recently added
+    /// files, since the server lists files in the order they were added to its library. Files
+    /// that don't fit in the remaining size limit or storage quota are skipped, so smaller files
+    /// can still be queued.
+    ///
+    /// Also returns how the storage quota was filled, if there is one.
+    fn auto_download_items(
+        &self,
+        auto_download: &AutoDownloadModel,
+        storage_quota: Option<u64>,
+    ) -> anyhow::Result<(Vec<DownloadRequestModel>, Option<StorageQuotaModel>)> {
+        let download_directory = self.download_directory.lock().unwrap().clone();
+        let download_directory = download_directory.context("no download directory set")?;
+        let index = self.index.lock().unwrap().clone();
+        let index = index
+            .filter(|_| self.index_complete.load(Ordering::Acquire))
+            .context("no complete index available")?;
+        let index_hashes = self.index_hashes.lock().unwrap().clone();
+        let index_metadata = self.index_metadata.lock().unwrap().clone();
+        let remote_endpoint_id = self.connection.remote_id();
+
+        let existing_keys: HashSet<(String, String)> = self
+            .jobs
+            .iter()
+            .map(|entry| {
+                let job = entry.value();
+                (job.file_root.clone(), job.file_path.clone())
+            })
+            .collect();
+
+        let db = self.db.get();
+        let download_filter = db.get_download_filter(remote_endpoint_id)?;
+
+        // files downloaded from the server, and jobs that will add more
+        let used_bytes = match storage_quota {
+            Some(_) => {
+                let index_sizes: HashMap<(&str, &str), u64> = index
+                    .iter()
+                    .map(|item| {
+                        let file_size = match item.file_size {
+                            FileSize::Unknown => 0,
+                            FileSize::Estimated(size) | FileSize::Actual(size) => size,
+                        };
+                        ((item.root.as_str(), item.path.as_str()), file_size)
+                    })
+                    .collect();
+                let queued_bytes = self
+                    .jobs
+                    .iter()
+                    .map(|entry| {
+                        let job = entry.value();
+                        match &job.progress {
+                            ClientTransferJobProgress::Requested
+                            | ClientTransferJobProgress::Transcoding => index_sizes
+                                .get(&(job.file_root.as_str(), job.file_path.as_str()))
+                                .copied()
+                                .unwrap_or(0),
+                            ClientTransferJobProgress::Ready { file_size }
+                            | ClientTransferJobProgress::InProgress { file_size, .. } => *file_size,
+                            // finished files are counted as downloaded
+                            ClientTransferJobProgress::Finished { .. }
+                            | ClientTransferJobProgress::Failed { .. } => 0,
+                        }
+                    })
+                    .sum::<u64>();
+                db.get_downloaded_bytes(remote_endpoint_id)? + queued_bytes
+            }
+            None => 0,
+        };
+
+        let mut candidates = Vec::new();
+        for (position, item) in index.into_iter().enumerate() {
+            if existing_keys.contains(&(item.root.clone(), item.path.clone())) {
+                continue;
+            }
+
+            let key = (item.endpoint_id, item.root.clone(), item.path.clone());
+            let selection = if auto_download.selections.is_empty() {
+                Some(0)
+            } else {
+                index_metadata.get(&key).and_then(|metadata| {
+                    auto_download
+                        .selections
+                        .iter()
+                        .position(|selection| selection.matches(metadata))
+                })
+            };
+            let Some(selection) = selection else {
+                continue;
+            };
+
+            if let Some(download_filter) = &download_filter {
+                let file_size = match item.file_size {
+                    FileSize::Unknown => None,
+                    FileSize::Estimated(size) | FileSize::Actual(size) => Some(size),
+                };
+                let duration = index_metadata
+                    .get(&key)
+                    .and_then(|metadata| metadata.duration);
+                if !download_filter.allows(file_size, duration) {
+                    continue;
+                }
+            }
+
+            let downloaded = find_unchanged_download(
+                &db,
+                remote_endpoint_id,
+                &item.root,
+                &item.path,
+                &download_directory,
+                index_hashes.get(&key),
+            );
+            if downloaded.is_some() {
+                continue;
+            }
+
+            candidates.push((selection, position, item));
+        }
+        candidates
+            .sort_by_key(|(selection, position, _)| (*selection, std::cmp::Reverse(*position)));
+
+        let mut remaining_bytes = auto_download.max_bytes;
+        let mut quota_remaining = storage_quota.map(|quota| quota.saturating_sub(used_bytes));
+        let mut skipped_items = 0;
+        let mut skipped_bytes = 0;
+        let mut items = Vec::new();
+        for (_, _, item) in candidates {
+            let file_size = match item.file_size {
+                FileSize::Unknown => 0,
+                FileSize::Estimated(size) | FileSize::Actual(size) => size,
+            };
+            if remaining_bytes.is_some_and(|remaining_bytes| file_size > remaining_bytes) {
+                continue;
+            }
+            if quota_remaining.is_some_and(|quota_remaining| file_size > quota_remaining) {
+                skipped_items += 1;
+                skipped_bytes += file_size;
+                continue;
+            }
+            if let Some(remaining_bytes) = remaining_bytes.as_mut() {
+                *remaining_bytes -= file_size;
+            }
+            if let Some(quota_remaining) = quota_remaining.as_mut() {
+                *quota_remaining -= file_size;
+            }
+
+            items.push(DownloadRequestModel {
+                endpoint_id: item.endpoint_id.to_string(),
+                root: item.root,
+                path: item.path,
+            });
+        }
+
+        let storage_quota = storage_quota.map(|quota_bytes| StorageQuotaModel {
+            quota_bytes,
+            used_bytes,
+            skipped_items,
+            skipped_bytes,
+        });
+
+        Ok((items, storage_quota))
+    }
+
+    /// Chooses the files in the server's index that the local library lacks, for two-way sync.
+    ///
+    /// Files are skipped if their content is already in the library at any path, or if they
+    /// originally came from this node. A file at the same path as a local file with different
+    /// content is a conflict, handled by the conflict policy. Without hashes for both files, they
+    /// are assumed to be the same.
+    fn two_way_sync_items(
+        &self,
+        conflict_policy: SyncConflictPolicy,
+    ) -> anyhow::Result<Vec<DownloadRequestModel>> {
+        anyhow::ensure!(
+            self.transcode_format.is_none(),
+            "two-way sync requires original files, but the connection is transcoding"
+        );
+
+        let index = self.index.lock().unwrap().clone();
+        let index = index
+            .filter(|_| self.index_complete.load(Ordering::Acquire))
+            .context("no complete index available")?;
+        let index_hashes = self.index_hashes.lock().unwrap().clone();
+        let local_files: Vec<LocalFileHash> = {
+            let db = self.db.get();
+            db.get_file_hashes_by_node_id(self.local_endpoint_id)?
+        };
+
+        let local_hashes: HashSet<(&str, [u8; 16])> = local_files
+            .iter()
+            .filter_map(|file| {
+                file.hash
+                    .as_ref()
+                    .map(|(kind, hash)| (kind.as_str(), *hash))
+            })
+            .collect();
+        let local_paths: HashMap<(&str, &str), bool> = local_files
+            .iter()
+            .map(|file| {
+                (
+                    (file.root.as_str(), file.path.as_str()),
+                    file.hash.is_some(),
+                )
+            })
+            .collect();
+
+        let items = index
+            .into_iter()
+            .filter(|item| {
+                if item.endpoint_id == self.local_endpoint_id {
+                    return false;
+                }
+
+                let content_hash =
+                    index_hashes.get(&(item.endpoint_id, item.root.clone(), item.path.clone()));
+                if let Some(content_hash) = content_hash
+                    && local_hashes.contains(&(content_hash.kind.as_str(), content_hash.hash))
+                {
+                    return false;
+                }
+
+                match local_paths.get(&(item.root.as_str(), item.path.as_str())) {
+                    None => true,
+                    Some(local_hashed) if !local_hashed || content_hash.is_none() => false,
+                    Some(_) => {
+                        debug!("two-way sync conflict: {}/{}", item.root, item.path);
+                        conflict_policy == SyncConflictPolicy::KeepBoth
+                    }
+                }
+            })
+            .map(|item| DownloadRequestModel {
+                endpoint_id: item.endpoint_id.to_string(),
+                root: item.root,
+                path: item.path,
+            })
+            .collect();
+
+        Ok(items)
+    }
+}
+
+/// Builds the path in the download directory that a file from a server is downloaded to.
+///
+/// The path follows the template if set, and the server's folder structure otherwise. Names are
+/// sanitized and shortened for the download directory's filesystem, and a path that another
+/// downloaded file already has gets a unique file name. `extension` replaces the original file's
+/// extension, e.g. when transcoding.
+///
+/// The values are the file's root and path on the server, and its tags if the server sent them.
+/// Also returns whether sanitizing changed the file's path or the names rendered from the template.
+pub(crate) fn build_download_path(
+    db: &Database,
+    download_directory: String,
+    template: Option<&PathTemplate>,
+    file_endpoint_id: EndpointId,
+    values: TemplateValues<'_>,
+    extension: Option<&str>,
+) -> anyhow::Result<(TreePath, bool)> {
+    let TemplateValues {
+        root: file_root,
+        path: file_path,
+        ..
+    } = values;
+    let policy = PathPolicy::for_tree(&download_directory);
+    let rules = policy.rules;
+    let (mut local_path, sanitized) = match template {
+        Some(template) => {
+            // lay out the file by its tags, keeping the transferred file's extension
+            let (mut relative_path, mut sanitized) = template.render_checked(values, rules);
+            let extension = match extension {
+                Some(extension) => Some(extension.to_string()),
+                None => Path::new(file_path)
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().into_owned()),
+            };
+            if let Some(extension) = extension {
+                let with_extension = format!("{relative_path}.{extension}");
+                relative_path = sanitize_path(&with_extension, rules);
+                sanitized |= relative_path != with_extension;
+            }
+            (
+                TreePath::new(download_directory, relative_path.into())?,
+                sanitized,
+            )
+        }
+        None => {
+            let root_dir_name =
+                sanitize_component(&format!("musicopy-{file_endpoint_id}-{file_root}"), rules);
+            let mut local_path = TreePath::new(download_directory, root_dir_name.into())?;
+            local_path.push(&sanitize_path(file_path, rules));
+            // If transcoding, overwrite the transferred file's extension
+            if let Some(extension) = extension {
+                local_path.set_extension(extension);
+            }
+            let sanitized = sanitize_path(file_path, rules) != file_path.trim_matches('/');
+            (local_path, sanitized)
+        }
+    };
+
+    // shorten paths that are too long for the download directory's filesystem
+    policy.apply(&mut local_path);
+
+    // if sanitizing or the template mapped a different file to the same path, fall back to a unique name
+    let collides = db.exists_other_file_by_local_treepath(
+        file_endpoint_id,
+        file_root,
+        file_path,
+        local_path.root(),
+        &local_path.path(),
+    )?;
+    if collides && let Some(file_name) = local_path.file_name().map(|name| name.into_owned()) {
+        let file_name = unique_file_name(&file_name, file_path, rules);
+        local_path.set_file_name(&file_name);
+    }
+
+    Ok((local_path, sanitized))
+}
+
+/// Checks whether a file from the server's index is already downloaded to the download directory
+/// and unchanged, so a sync can skip it.
+///
+/// If the download directory was moved, files downloaded to the old directory are found at the
+/// same paths in the new one by their content hashes and tracked again. If `verify_on_disk` is
+/// set, downloaded files that no longer exist are downloaded again.
+fn is_already_downloaded(
+    db: &mut Database,
+    endpoint_id: EndpointId,
+    root: &str,
+    path: &str,
+    download_directory: &str,
+    content_hash: Option<&ContentHash>,
+    verify_on_disk: bool,
+) -> bool {
+    if let Some(file) = find_unchanged_download(
+        db,
+        endpoint_id,
+        root,
+        path,
+        download_directory,
+        content_hash,
+    ) {
+        return !verify_on_disk || downloaded_file_exists(&file.local_tree, &file.local_path);
+    }
+
+    let Some(file) = content_hash.and_then(|content_hash| {
+        find_moved_download(
+            db,
+            endpoint_id,
+            root,
+            path,
+            download_directory,
+            content_hash,
+        )
+    }) else {
+        return false;
+    };
+
+    // track the moved file in the current download directory
+    debug!(
+        "found moved download of {root}/{path} at {}",
+        file.local_path
+    );
+    let res = db.insert_remote_file(
+        endpoint_id,
+        InsertFile {
+            root,
+            path,
+            local_tree: download_directory,
+            local_path: &file.local_path,
+        },
+        file.source_hash
+            .as_ref()
+            .map(|(kind, hash)| (kind.as_str(), *hash)),
+        file.manifest.as_ref(),
+    );
+    if let Err(e) = res {
+        warn!("failed to track moved download: {e:#}");
+    }
+
+    true
+}
+
+/// Finds a file downloaded to the download directory, if the server's copy hasn't changed since
+/// it was downloaded.
+///
+/// Files downloaded without a source hash, or without a content hash in the index, e.g. from
+/// older servers, are assumed to be unchanged.
+fn find_unchanged_download(
+    db: &Database,
+    endpoint_id: EndpointId,
+    root: &str,
+    path: &str,
+    download_directory: &str,
+    content_hash: Option<&ContentHash>,
+) -> Option<DownloadedFile> {
+    let downloaded = db
+        .get_downloaded_files_by_node_root_path(endpoint_id, root, path)
+        .ok()?;
+    downloaded.into_iter().find(|file| {
+        file.local_tree == download_directory
+            && match (&file.source_hash, content_hash) {
+                (Some((kind, hash)), Some(content_hash)) => {
+                    *kind == content_hash.kind && *hash == content_hash.hash
+                }
+                _ => true,
+            }
+    })
+}
+
+/// Finds a file downloaded to the download directory whose original changed on the server since
+/// it was downloaded.
+///
+/// Files downloaded without a source hash are assumed to be unchanged, like in
+/// `find_unchanged_download`.
+fn find_changed_download(
+    db: &Database,
+    endpoint_id: EndpointId,
+    root: &str,
+    path: &str,
+    download_directory: &str,
+    content_hash: &ContentHash,
+) -> Option<DownloadedFile> {
+    let downloaded = db
+        .get_downloaded_files_by_node_root_path(endpoint_id, root, path)
+        .ok()?;
+    let downloaded = downloaded
+        .into_iter()
+        .filter(|file| file.local_tree == download_directory)
+        .collect::<Vec<_>>();
+
+    let unchanged = downloaded.iter().any(|file| match &file.source_hash {
+        Some((kind, hash)) => *kind == content_hash.kind && *hash == content_hash.hash,
+        None => true,
+    });
+    if unchanged {
+        return None;
+    }
+    downloaded.into_iter().next()
+}
+
+/// Finds the files in a server's index that were downloaded to the download directory, but
+/// changed on the server since.
+fn find_changed_downloads(
+    db: &Database,
+    endpoint_id: EndpointId,
+    index: &[IndexItem],
+    index_hashes: &IndexHashes,
+    download_directory: &str,
+) -> Vec<ChangedDownloadModel> {
+    index
+        .iter()
+        .filter_map(|item| {
+            let content_hash =
+                index_hashes.get(&(item.endpoint_id, item.root.clone(), item.path.clone()))?;
+            let file = find_changed_download(
+                db,
+                endpoint_id,
+                &item.root,
+                &item.path,
+                download_directory,
+                content_hash,
+            )?;
+            Some(ChangedDownloadModel {
+                endpoint_id: item.endpoint_id.to_string(),
+                root: item.root.clone(),
+                path: item.path.clone(),
+                local_path: file.local_path,
+                requeued: false,
+            })
+        })
+        .collect()
+}
+
+/// Finds a file downloaded to another download directory that still exists at the same path in
+/// the current download directory, with the same content hash as the server's copy.
+fn find_moved_download(
+    db: &Database,
+    endpoint_id: EndpointId,
+    root: &str,
+    path: &str,
+    download_directory: &str,
+    content_hash: &ContentHash,
+) -> Option<DownloadedFile> {
+    let downloaded = db
+        .get_downloaded_files_by_node_root_path(endpoint_id, root, path)
+        .ok()?;
+    downloaded.into_iter().find(|file| {
+        file.local_tree != download_directory
+            && file.source_hash.as_ref().is_some_and(|(kind, hash)| {
+                *kind == content_hash.kind && *hash == content_hash.hash
+            })
+            && downloaded_file_exists(download_directory, &file.local_path)
+    })
+}
+
+/// Checks whether a downloaded file exists on disk.
+fn downloaded_file_exists(local_tree: &str, local_path: &str) -> bool {
+    TreePath::new(local_tree.to_string(), PathBuf::from(local_path))
+        .is_ok_and(|local_path| local_path.exists())
+}
+
+/// Gets the local files shared with a node, and whether a file with the same content hash was
+/// sent to it. Files that haven't been hashed count as not sent.
+fn get_delivered_files(
+    db: &Database,
+    local_endpoint_id: EndpointId,
+    node_id: EndpointId,
+) -> anyhow::Result<Vec<(LocalFileHash, bool)>> {
+    let files = db.get_delivered_files(local_endpoint_id, node_id)?;
+    let shares = db.get_node_shares(node_id)?;
+    if shares.is_empty() {
+        return Ok(files);
+    }
+
+    // group the shares by root so each file is only checked against the shares in its root
+    let mut shares_by_root: HashMap<&str, Vec<&NodeShare>> = HashMap::new();
+    for share in &shares {
+        shares_by_root
+            .entry(share.root.as_str())
+            .or_default()
+            .push(share);
+    }
+
+    Ok(files
+        .into_iter()
+        .filter(|(file, _)| {
+            shares_by_root
+                .get(file.root.as_str())
+                .is_some_and(|shares| {
+                    shares
+                        .iter()
+                        .any(|share| share.contains(&file.root, &file.path))
+                })
+        })
+        .collect())
+}
+
+/// Gets the cached hash of the original file for a job, or None if it isn't in a local root or
+/// hasn't been hashed.
+fn get_source_hash(
+    db: &DatabasePool,
+    hash_cache: &HashCache,
+    (endpoint_id, root, path): (EndpointId, String, String),
+) -> Option<ContentHash> {
+    let file = {
+        let db = db.get();
+        db.get_file_by_node_root_path(endpoint_id, &root, &path)
+    };
+    let file = match file {
+        Ok(Some(file)) => file,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!("failed to get file for source hash: {e:#}");
+            return None;
+        }
+    };
+
+    // files in document trees aren't tracked by the hash cache
+    if !file.local_tree.is_empty() {
+        return None;
+    }
+
+    let local_path = PathBuf::from(&file.local_path);
+    let key = hash_cache.read_cache_key(&local_path).ok()?;
+    let (kind, hash) = hash_cache.get_cached_hash(&key).ok()??;
+    Some(ContentHash {
+        kind: kind.into_owned(),
+        hash,
+    })
+}
+
+/// Looks up the cached tags and duration of the original file for a job, to send in its manifest.
+fn get_source_metadata(
+    db: &DatabasePool,
+    hash_cache: &HashCache,
+    (endpoint_id, root, path): (EndpointId, String, String),
+) -> Option<ItemMetadata> {
+    let file = {
+        let db = db.get();
+        db.get_file_by_node_root_path(endpoint_id, &root, &path)
+    };
+    let file = match file {
+        Ok(Some(file)) => file,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!("failed to get file for source metadata: {e:#}");
+            return None;
+        }
+    };
+
+    // files in document trees aren't tracked by the hash cache
+    if !file.local_tree.is_empty() {
+        return None;
+    }
+
+    let track = {
+        let db = db.get();
+        db.get_track_by_file_id(file.id)
+    };
+    let track = match track {
+        Ok(track) => track,
+        Err(e) => {
+            warn!("failed to get track for source metadata: {e:#}");
+            None
+        }
+    };
+    let duration = hash_cache
+        .get_cached_duration_unvalidated(&PathBuf::from(&file.local_path))
+        .ok()
+        .flatten();
+    item_metadata(track, duration)
+}
+
+/// Combines a file's track and cached duration into the metadata sent to clients, or None if
+/// neither is known. The track's duration is used if there's no cached duration.
+fn item_metadata(track: Option<Track>, duration: Option<f64>) -> Option<ItemMetadata> {
+    let duration = duration.or_else(|| track.as_ref().and_then(|track| track.duration));
+    match (track, duration) {
+        (None, None) => None,
+        (track, duration) => {
+            let (title, artist, album, track_number) = track
+                .map(|track| (track.title, track.artist, track.album, track.track_number))
+                .unwrap_or_default();
+            Some(ItemMetadata {
+                title,
+                artist,
+                album,
+                track_number,
+                duration,
+            })
+        }
+    }
+}
+
+/// Gets the extra metadata of a track to send to clients, or None if it has none of the tags.
+fn item_extra_metadata(track: &Track) -> Option<ItemExtraMetadata> {
+    let extra_metadata = ItemExtraMetadata {
+        album_artist: track.album_artist.clone(),
+        disc_number: track.disc_number,
+    };
+    (extra_metadata != ItemExtraMetadata::default()).then_some(extra_metadata)
+}
+
+/// Copies from the reader to the writer like `tokio::io::copy`, but stops reading while the job
+/// is paused and stops early if it's cancelled.
+///
+/// Not reading while paused also pauses the sender, since the stream's flow control window fills
+/// up. Each chunk is passed to `on_chunk` after it's written. Returns None if the job was
+/// cancelled.
+async fn copy_with_control<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    control: &mut watch::Receiver<JobControl>,
+    bandwidth: &BandwidthLimiter,
+    buffer_size: usize,
+    mut on_chunk: impl FnMut(&[u8]),
+) -> std::io::Result<Option<u64>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; buffer_size];
+    let mut copied = 0;
+    loop {
+        // wait until the job isn't paused. if the sender was dropped, the job was removed
+        let state = match control
+            .wait_for(|control| *control != JobControl::Pause)
+            .await
+        {
+            Ok(control) => *control,
+            Err(_) => JobControl::Cancel,
+        };
+        if state == JobControl::Cancel {
+            return Ok(None);
+        }
+
+        // stop waiting for data if the job is paused or cancelled
+        let n = tokio::select! {
+            res = reader.read(&mut buf) => res?,
+            _ = control.changed() => continue,
+        };
+        if n == 0 {
+            break;
+        }
+
+        bandwidth.consume(n as u64).await;
+        writer.write_all(&buf[..n]).await?;
+        on_chunk(&buf[..n]);
+        copied += n as u64;
+    }
+
+    writer.flush().await?;
+    Ok(Some(copied))
+}
+
+/// Checks for a partial file left by an interrupted download of the given path, returning the
+/// resume message to send to the server and the digest of the partial file, to continue
+/// checksumming the rest of the file.
+async fn read_partial_download(
+    local_path: &TreePath,
+) -> anyhow::Result<Option<(TransferResume, crc::Digest<'static, u64>)>> {
+    let temp_path = TreeFile::atomic_temp_path(local_path)?;
+    if !temp_path.exists() {
+        return Ok(None);
+    }
+
+    let mut file = TreeFile::open(&temp_path, OpenMode::Read).await?;
+    let mut digest = FILE_CRC.digest();
+    let mut offset = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+        offset += n as u64;
+    }
+
+    if offset == 0 {
+        return Ok(None);
+    }
+
+    let resume = TransferResume {
+        offset,
+        prefix_crc: digest.clone().finalize(),
+    };
+    Ok(Some((resume, digest)))
+}
+
+/// Computes the checksum of a whole file, to compare an existing file with the one the server
+/// sends.
+pub(crate) async fn file_checksum(path: &TreePath) -> anyhow::Result<u64> {
+    let mut file = TreeFile::open(path, OpenMode::Read).await?;
+    let mut digest = FILE_CRC.digest();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+    }
+    Ok(digest.finalize())
+}
+
+/// Parses a sync group ID from its hex string.
+fn parse_group_id(group_id: &str) -> anyhow::Result<GroupId> {
+    let group_id = hex::decode(group_id).context("failed to parse sync group id")?;
+    GroupId::try_from(group_id.as_slice()).context("failed to parse sync group id")
 }
 
 /// Returns the current system time in seconds since the Unix epoch.
-fn unix_epoch_now_secs() -> u64 {
+pub(crate) fn unix_epoch_now_secs() -> u64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap_or_default()
@@ -3049,6 +11799,10 @@ impl<T> WriteProgress<T> {
     fn new(written: Arc<AtomicU64>, inner: T) -> Self {
         Self { inner, written }
     }
+
+    fn into_inner(self) -> T {
+        self.inner
+    }
 }
 
 impl<T: AsyncWrite + Unpin> AsyncWrite for WriteProgress<T> {
@@ -3094,3 +11848,109 @@ impl<T: AsyncWrite + Unpin> AsyncWrite for WriteProgress<T> {
         self.inner.is_write_vectored()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_mode() {
+        assert!(matches!(
+            RelayConfig::Default.relay_mode(),
+            Ok(RelayMode::Default)
+        ));
+        assert!(matches!(
+            RelayConfig::Disabled.relay_mode(),
+            Ok(RelayMode::Disabled)
+        ));
+
+        let custom = RelayConfig::Custom {
+            urls: vec!["https://relay.example.com".to_string()],
+        };
+        assert!(matches!(custom.relay_mode(), Ok(RelayMode::Custom(_))));
+
+        // custom relays need at least one valid url
+        let empty = RelayConfig::Custom { urls: Vec::new() };
+        assert!(empty.relay_mode().is_err());
+        let invalid = RelayConfig::Custom {
+            urls: vec![
+                "https://relay.example.com".to_string(),
+                "not a url".to_string(),
+            ],
+        };
+        assert!(invalid.relay_mode().is_err());
+    }
+
+    #[test]
+    fn test_is_local_addr() {
+        for addr in [
+            "127.0.0.1:1234",
+            "10.1.2.3:1234",
+            "172.16.0.1:1234",
+            "192.168.1.10:1234",
+            "169.254.0.1:1234",
+            "[::1]:1234",
+            "[fd00::1]:1234",
+            "[fe80::1]:1234",
+            "[::ffff:192.168.1.10]:1234",
+        ] {
+            let addr = addr.parse().unwrap();
+            assert!(is_local_addr(&addr), "{addr} should be local");
+        }
+
+        for addr in [
+            "8.8.8.8:1234",
+            "172.32.0.1:1234",
+            "[2001:db8::1]:1234",
+            "[::ffff:8.8.8.8]:1234",
+        ] {
+            let addr = addr.parse().unwrap();
+            assert!(!is_local_addr(&addr), "{addr} should not be local");
+        }
+    }
+
+    #[test]
+    fn test_local_endpoint_addr() {
+        let endpoint_id = SecretKey::generate().public();
+        let local: SocketAddr = "192.168.1.10:1234".parse().unwrap();
+        let public: SocketAddr = "8.8.8.8:1234".parse().unwrap();
+        let addr = EndpointAddr::new(endpoint_id)
+            .with_ip_addr(local)
+            .with_ip_addr(public);
+
+        let local_addr = local_endpoint_addr(&addr);
+        assert_eq!(local_addr.id, endpoint_id);
+        assert_eq!(local_addr.ip_addrs().collect::<Vec<_>>(), vec![&local]);
+    }
+
+    #[tokio::test]
+    async fn test_serve_file_checksums_cached() {
+        let path = testdir::testdir!().join("file");
+        std::fs::write(&path, b"hello world").unwrap();
+        let serve_file = ServeFile::Path(path.clone());
+        let checksum = FileChecksum::default();
+
+        let (file_checksum, prefix_checksum) =
+            serve_file_checksums(&serve_file, &checksum, Some(5))
+                .await
+                .unwrap();
+        assert_eq!(file_checksum, FILE_CRC.checksum(b"hello world"));
+        assert_eq!(prefix_checksum, Some(FILE_CRC.checksum(b"hello")));
+        assert_eq!(checksum.get(), Some(&file_checksum));
+
+        // once cached, the whole file isn't read again, so a changed file keeps its checksum
+        std::fs::write(&path, b"hello there").unwrap();
+        let (cached_checksum, prefix_checksum) =
+            serve_file_checksums(&serve_file, &checksum, Some(5))
+                .await
+                .unwrap();
+        assert_eq!(cached_checksum, file_checksum);
+        assert_eq!(prefix_checksum, Some(FILE_CRC.checksum(b"hello")));
+
+        // prefixes longer than the file have no checksum
+        let (_, prefix_checksum) = serve_file_checksums(&serve_file, &checksum, Some(100))
+            .await
+            .unwrap();
+        assert_eq!(prefix_checksum, None);
+    }
+}
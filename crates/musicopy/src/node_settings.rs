@@ -0,0 +1,130 @@
+//! Settings stored per node.
+//!
+//! Each setting is a type implementing [`NodeSetting`], which names the key it's stored under and
+//! the type of its value. Values are serialized with postcard, so changing a setting's value type
+//! needs a new key.
+//!
+//! Components that cache settings can register a hook on the [`DatabasePool`] to be told when a
+//! node's settings change.
+//!
+//! [`DatabasePool`]: crate::database::DatabasePool
+
+use crate::library::transcode::TranscodeFormat;
+use iroh::EndpointId;
+use serde::{Serialize, de::DeserializeOwned};
+use std::sync::RwLock;
+
+/// A setting stored per node.
+pub trait NodeSetting {
+    /// The key the setting is stored under. Must be unique across settings.
+    const KEY: &'static str;
+    type Value: Serialize + DeserializeOwned;
+}
+
+/// The directory that files downloaded from the node are saved to, instead of the default
+/// download directory.
+pub struct DownloadDirectory;
+
+impl NodeSetting for DownloadDirectory {
+    const KEY: &'static str = "download_directory";
+    type Value = String;
+}
+
+/// The format to request files from the node in, or None for the original files.
+pub struct TranscodeFormatPreference;
+
+impl NodeSetting for TranscodeFormatPreference {
+    const KEY: &'static str = "transcode_format";
+    type Value = Option<TranscodeFormat>;
+}
+
+/// Whether to sync with the node automatically when it connects.
+pub struct AutoSync;
+
+impl NodeSetting for AutoSync {
+    const KEY: &'static str = "auto_sync";
+    type Value = bool;
+}
+
+/// Called with the node and key after one of its settings is set or removed.
+pub type NodeSettingsHook = Box<dyn Fn(EndpointId, &str) + Send + Sync>;
+
+/// Hooks shared by all connections of a pool.
+#[derive(Default)]
+pub(crate) struct NodeSettingsHooks {
+    hooks: RwLock<Vec<NodeSettingsHook>>,
+}
+
+// stub debug implementation
+impl std::fmt::Debug for NodeSettingsHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeSettingsHooks").finish()
+    }
+}
+
+impl NodeSettingsHooks {
+    pub(crate) fn add(&self, hook: NodeSettingsHook) {
+        self.hooks.write().unwrap().push(hook);
+    }
+
+    pub(crate) fn notify(&self, node_id: EndpointId, key: &str) {
+        for hook in self.hooks.read().unwrap().iter() {
+            hook(node_id, key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DatabasePool;
+    use iroh::SecretKey;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_settings_per_node() {
+        let pool = DatabasePool::open_in_memory().unwrap();
+        let node_a = SecretKey::generate().public();
+        let node_b = SecretKey::generate().public();
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        pool.on_node_settings_changed(Box::new({
+            let changes = changes.clone();
+            move |node_id, key| changes.lock().unwrap().push((node_id, key.to_string()))
+        }));
+
+        let db = pool.get();
+        assert_eq!(db.get_node_setting::<AutoSync>(node_a).unwrap(), None);
+
+        db.set_node_setting::<AutoSync>(node_a, &true).unwrap();
+        db.set_node_setting::<TranscodeFormatPreference>(node_a, &Some(TranscodeFormat::Opus128))
+            .unwrap();
+        assert_eq!(db.get_node_setting::<AutoSync>(node_a).unwrap(), Some(true));
+        assert_eq!(
+            db.get_node_setting::<TranscodeFormatPreference>(node_a)
+                .unwrap(),
+            Some(Some(TranscodeFormat::Opus128))
+        );
+        assert_eq!(db.get_node_setting::<AutoSync>(node_b).unwrap(), None);
+
+        db.remove_node_setting::<AutoSync>(node_a).unwrap();
+        assert_eq!(db.get_node_setting::<AutoSync>(node_a).unwrap(), None);
+
+        db.remove_node_settings(node_a).unwrap();
+        assert_eq!(
+            db.get_node_setting::<TranscodeFormatPreference>(node_a)
+                .unwrap(),
+            None
+        );
+
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![
+                (node_a, AutoSync::KEY.to_string()),
+                (node_a, TranscodeFormatPreference::KEY.to_string()),
+                (node_a, AutoSync::KEY.to_string()),
+                (node_a, TranscodeFormatPreference::KEY.to_string()),
+            ]
+        );
+    }
+}
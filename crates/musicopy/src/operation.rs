@@ -0,0 +1,107 @@
+//! Operations that shells can await, instead of sending a command and watching the models for its
+//! result.
+//!
+//! The node broadcasts the events that can finish an operation, e.g. a connection being accepted
+//! or a download session completing, and the core waits for the ones about the operation it
+//! started. Progress is sent to an optional handler while waiting.
+
+use crate::{
+    error::{CoreError, core_error},
+    node::{DownloadProgressModel, TransferJobFailedEvent, TransferSessionCompletedEvent},
+};
+use iroh::EndpointId;
+use std::time::Duration;
+
+/// How often the progress of downloads is sent while waiting for them.
+pub(crate) const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Number of events kept for waiting operations that fall behind.
+pub(crate) const OPERATION_EVENTS_CAPACITY: usize = 256;
+
+/// Foreign trait implemented by shells to receive the progress of an awaited operation.
+#[uniffi::export(with_foreign)]
+pub trait OperationProgressHandler: Send + Sync {
+    fn on_progress(&self, progress: OperationProgress);
+}
+
+/// Progress of an awaited operation.
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum OperationProgress {
+    /// The connection is open and waiting for the server to accept it.
+    ConnectionPending,
+    /// A scan was already running, so the requested one starts after it finishes.
+    ScanQueued,
+    ScanStarted,
+    /// The server accepted the downloads. Files that were already downloaded or queued aren't
+    /// counted.
+    DownloadsQueued {
+        queued_files: u32,
+    },
+    Downloading {
+        progress: DownloadProgressModel,
+    },
+    /// A download failed. The operation continues with the other files.
+    DownloadFailed {
+        event: TransferJobFailedEvent,
+    },
+}
+
+/// Result of `Core::rescan_library_and_wait`.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ScanResultModel {
+    /// Number of files in all library roots after the scan.
+    pub library_files: u64,
+    pub duration_ms: u64,
+}
+
+/// Result of `Core::download_and_wait`.
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct DownloadResultModel {
+    pub completed_files: u32,
+    pub failed_files: u32,
+    pub transferred_bytes: u64,
+}
+
+impl From<TransferSessionCompletedEvent> for DownloadResultModel {
+    fn from(event: TransferSessionCompletedEvent) -> Self {
+        Self {
+            completed_files: event.completed_files,
+            failed_files: event.failed_files,
+            transferred_bytes: event.transferred_bytes,
+        }
+    }
+}
+
+/// An event that can finish an awaited operation, broadcast by the node after the model change
+/// it describes.
+#[derive(Debug, Clone)]
+pub(crate) enum OperationEvent {
+    /// A connection to a server was accepted and its index arrived, so downloads can be requested.
+    ClientReady(EndpointId),
+    ClientClosed {
+        endpoint_id: EndpointId,
+        error: Option<String>,
+    },
+    JobFailed(TransferJobFailedEvent),
+    SessionCompleted(TransferSessionCompletedEvent),
+}
+
+impl OperationEvent {
+    /// Whether the event is about the connection to the given server.
+    pub(crate) fn is_for_client(&self, client: EndpointId) -> bool {
+        match self {
+            OperationEvent::ClientReady(endpoint_id)
+            | OperationEvent::ClientClosed { endpoint_id, .. } => *endpoint_id == client,
+            OperationEvent::JobFailed(event) => event.endpoint_id == client.to_string(),
+            OperationEvent::SessionCompleted(event) => event.endpoint_id == client.to_string(),
+        }
+    }
+}
+
+/// The error for an awaited operation whose connection closed before it finished.
+pub(crate) fn connection_closed_error(error: Option<String>) -> CoreError {
+    core_error!(
+        "connection closed: {}",
+        error.as_deref().unwrap_or("no error")
+    )
+}
@@ -0,0 +1,80 @@
+//! Compact pairing tickets for connecting without typing a long endpoint ID.
+//!
+//! A ticket contains everything needed to reach a node: its endpoint ID, home relay, and direct
+//! addresses, plus an optional one-time token that lets the holder connect without the server
+//! having to accept the connection manually. Tickets are serialized with `postcard` and encoded
+//! with z-base-32, which only uses lowercase letters and digits, so they're easy to put in a QR
+//! code or read aloud.
+
+use anyhow::Context;
+use iroh::{EndpointAddr, EndpointId, RelayUrl};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// Prefix of encoded tickets, to recognize them and reject other strings early.
+const TICKET_PREFIX: &str = "mcp1";
+
+/// A one-time token in a pairing ticket.
+pub type PairingToken = [u8; 16];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairingTicket {
+    pub endpoint_id: EndpointId,
+    pub relay_url: Option<String>,
+    pub direct_addresses: Vec<SocketAddr>,
+    pub token: Option<PairingToken>,
+}
+
+impl PairingTicket {
+    /// Creates a ticket for the given address.
+    pub fn new(addr: &EndpointAddr, token: Option<PairingToken>) -> Self {
+        Self {
+            endpoint_id: addr.id,
+            relay_url: addr.relay_urls().next().map(|url| url.to_string()),
+            direct_addresses: addr.ip_addrs().copied().collect(),
+            token,
+        }
+    }
+
+    /// Encodes the ticket as a short string.
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let buf = postcard::to_stdvec(self).context("failed to serialize ticket")?;
+        Ok(format!(
+            "{TICKET_PREFIX}{}",
+            zbase32::encode_full_bytes(&buf)
+        ))
+    }
+
+    /// Decodes a ticket from a string created by `encode`.
+    pub fn decode(ticket: &str) -> anyhow::Result<Self> {
+        let encoded = ticket
+            .trim()
+            .to_lowercase()
+            .strip_prefix(TICKET_PREFIX)
+            .context("not a pairing ticket")?
+            .to_string();
+        let buf = zbase32::decode_full_bytes_str(&encoded)
+            .map_err(|e| anyhow::anyhow!("failed to decode ticket: {e:?}"))?;
+        postcard::from_bytes(&buf).context("failed to deserialize ticket")
+    }
+
+    /// Returns the address to connect to.
+    pub fn endpoint_addr(&self) -> anyhow::Result<EndpointAddr> {
+        let mut addr = EndpointAddr::new(self.endpoint_id);
+        if let Some(relay_url) = &self.relay_url {
+            let relay_url: RelayUrl = relay_url.parse().context("failed to parse relay url")?;
+            addr = addr.with_relay_url(relay_url);
+        }
+        for direct_address in &self.direct_addresses {
+            addr = addr.with_ip_addr(*direct_address);
+        }
+        Ok(addr)
+    }
+}
+
+/// Generates a random one-time token.
+pub fn generate_token() -> anyhow::Result<PairingToken> {
+    let mut token = PairingToken::default();
+    getrandom::fill(&mut token).map_err(|e| anyhow::anyhow!("failed to generate token: {e}"))?;
+    Ok(token)
+}
@@ -0,0 +1,372 @@
+//! M3U8 playlists written into the download directory after a sync, so players on the device
+//! that don't scan tags well still have the synced music in order.
+//!
+//! Album playlists are written into each directory that a sync downloaded files to, listing all
+//! the files downloaded to that directory by name. The recently synced playlist is written to the
+//! root of the download directory and lists the files of the last sync. Entries are relative to
+//! the playlist, so the download directory can be moved or copied to another device.
+
+use crate::{
+    database::{DatabasePool, DownloadedFile, TransferSessionFile},
+    fs::{OpenMode, TreeFile, TreePath},
+};
+use anyhow::Context;
+use iroh::EndpointId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Component, Path},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+/// File name of the recently synced playlist.
+const RECENTLY_SYNCED_NAME: &str = "Recently Synced.m3u8";
+
+/// Which playlists are written after a sync.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, uniffi::Record)]
+pub struct PlaylistSettings {
+    /// Whether to write a playlist into each directory that files were downloaded to.
+    pub per_album: bool,
+    /// Whether to write a playlist of the last sync's files to the root of the download directory.
+    pub recently_synced: bool,
+}
+
+impl PlaylistSettings {
+    pub(crate) fn any(&self) -> bool {
+        self.per_album || self.recently_synced
+    }
+}
+
+/// Model of the playlists written after the last sync.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct PlaylistWriteModel {
+    /// The server the files were synced from.
+    pub endpoint_id: String,
+    /// When the playlists were written, in seconds since the Unix epoch.
+    pub written_at: u64,
+    /// Paths of the playlists written, relative to the download directory.
+    pub playlists: Vec<String>,
+    /// Why writing the playlists failed, if it did.
+    pub error: Option<String>,
+}
+
+/// An entry in a playlist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PlaylistEntry {
+    /// Path of the file relative to the playlist, with `/` separators.
+    path: String,
+    /// Title to show for the entry, if the file is tagged.
+    title: Option<String>,
+}
+
+impl PlaylistEntry {
+    fn new(path: String, file: &DownloadedFile) -> Self {
+        let manifest = file.manifest.as_ref();
+        let title = manifest.and_then(|manifest| manifest.title.as_deref());
+        let artist = manifest.and_then(|manifest| manifest.artist.as_deref());
+        let title = match (artist, title) {
+            (Some(artist), Some(title)) => Some(format!("{artist} - {title}")),
+            (None, Some(title)) => Some(title.to_string()),
+            _ => None,
+        };
+
+        Self { path, title }
+    }
+}
+
+/// Writes the playlists for a finished sync from a server to the download directory. Returns the
+/// paths of the playlists written.
+pub(crate) async fn write_playlists(
+    db: &DatabasePool,
+    endpoint_id: EndpointId,
+    download_directory: &str,
+    settings: PlaylistSettings,
+    session_files: &[TransferSessionFile],
+) -> anyhow::Result<Vec<String>> {
+    let files = {
+        let db = db.get();
+        db.get_downloaded_files_by_node_localtree(endpoint_id, download_directory)
+            .context("failed to get downloaded files")?
+    };
+    let files_by_key = files
+        .iter()
+        .map(|file| ((file.root.as_str(), file.path.as_str()), file))
+        .collect::<HashMap<_, _>>();
+
+    // the files this sync downloaded, in the order they were listed
+    let synced = session_files
+        .iter()
+        .filter(|file| file.error.is_none())
+        .filter_map(|file| files_by_key.get(&(file.root.as_str(), file.path.as_str())))
+        .copied()
+        .collect::<Vec<_>>();
+    if synced.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut written = Vec::new();
+    if settings.per_album {
+        // only rewrite the playlists of albums this sync added to
+        let dirs = synced
+            .iter()
+            .map(|file| split_local_path(&file.local_path).0)
+            .collect();
+        written.extend(write_album_playlists(download_directory, &files, dirs).await?);
+    }
+
+    if settings.recently_synced {
+        let entries = synced
+            .iter()
+            .map(|file| PlaylistEntry::new(entry_path(&file.local_path), file))
+            .collect::<Vec<_>>();
+
+        let playlist_path =
+            TreePath::new(download_directory.to_string(), RECENTLY_SYNCED_NAME.into())?;
+        write_playlist(&playlist_path, &entries).await?;
+        written.push(playlist_path.path().into_owned());
+    }
+
+    Ok(written)
+}
+
+/// Updates the playlists in the download directory after files downloaded from a server were
+/// moved, given their old and new local paths.
+///
+/// The playlists of the albums the files moved out of and into are rewritten, and the ones of
+/// albums that no longer have any files are removed. Entries of the recently synced playlist are
+/// renamed.
+pub(crate) async fn update_moved(
+    db: &DatabasePool,
+    endpoint_id: EndpointId,
+    download_directory: &str,
+    settings: PlaylistSettings,
+    moves: &[(String, String)],
+) -> anyhow::Result<()> {
+    if moves.is_empty() {
+        return Ok(());
+    }
+
+    if settings.per_album {
+        let files = {
+            let db = db.get();
+            db.get_downloaded_files_by_node_localtree(endpoint_id, download_directory)
+                .context("failed to get downloaded files")?
+        };
+        let dirs = moves
+            .iter()
+            .flat_map(|(from, to)| [split_local_path(from).0, split_local_path(to).0])
+            .collect();
+        write_album_playlists(download_directory, &files, dirs).await?;
+    }
+
+    if settings.recently_synced {
+        let playlist_path =
+            TreePath::new(download_directory.to_string(), RECENTLY_SYNCED_NAME.into())?;
+        if !playlist_path.exists() {
+            return Ok(());
+        }
+
+        let mut playlist = String::new();
+        TreeFile::open(&playlist_path, OpenMode::Read)
+            .await
+            .context("failed to open playlist")?
+            .read_to_string(&mut playlist)
+            .await
+            .context("failed to read playlist")?;
+
+        let moves = moves
+            .iter()
+            .map(|(from, to)| (entry_path(from), entry_path(to)))
+            .collect::<HashMap<_, _>>();
+        if let Some(renamed) = rename_entries(&playlist, &moves) {
+            debug!("renaming entries of playlist {:?}", playlist_path.path());
+            let mut file = TreeFile::create_atomic(&playlist_path)
+                .await
+                .context("failed to create playlist")?;
+            file.write_all(renamed.as_bytes())
+                .await
+                .context("failed to write playlist")?;
+            file.commit().await.context("failed to commit playlist")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the playlists of the albums in the given directories, listing the downloaded files in
+/// each directory by name. The playlists of directories without files are removed. Returns the
+/// paths of the playlists written.
+async fn write_album_playlists(
+    download_directory: &str,
+    files: &[DownloadedFile],
+    dirs: BTreeSet<Vec<String>>,
+) -> anyhow::Result<Vec<String>> {
+    let mut albums: BTreeMap<Vec<String>, Vec<&DownloadedFile>> = BTreeMap::new();
+    for file in files {
+        let (dir, _) = split_local_path(&file.local_path);
+        albums.entry(dir).or_default().push(file);
+    }
+
+    let mut written = Vec::new();
+    for dir in dirs {
+        let Some(dir_name) = dir.last() else {
+            // files at the root of the download directory aren't in an album
+            continue;
+        };
+        let mut playlist_path = dir.iter().collect::<std::path::PathBuf>();
+        playlist_path.push(format!("{dir_name}.m3u8"));
+        let playlist_path = TreePath::new(download_directory.to_string(), playlist_path)?;
+
+        let Some(album_files) = albums.remove(&dir) else {
+            if playlist_path.exists() {
+                debug!("removing playlist {:?}", playlist_path.path());
+                crate::fs::remove_file(&playlist_path)
+                    .await
+                    .context("failed to remove playlist")?;
+            }
+            continue;
+        };
+
+        let mut entries = album_files
+            .into_iter()
+            .map(|file| {
+                let (_, file_name) = split_local_path(&file.local_path);
+                (
+                    file.local_path.as_str(),
+                    PlaylistEntry::new(file_name, file),
+                )
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let entries = entries
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect::<Vec<_>>();
+
+        write_playlist(&playlist_path, &entries).await?;
+        written.push(playlist_path.path().into_owned());
+    }
+
+    Ok(written)
+}
+
+async fn write_playlist(path: &TreePath, entries: &[PlaylistEntry]) -> anyhow::Result<()> {
+    debug!(
+        "writing playlist {:?} with {} entries",
+        path.path(),
+        entries.len()
+    );
+
+    let mut file = TreeFile::create_atomic(path)
+        .await
+        .context("failed to create playlist")?;
+    file.write_all(render(entries).as_bytes())
+        .await
+        .context("failed to write playlist")?;
+    file.commit().await.context("failed to commit playlist")
+}
+
+/// Renders an extended M3U playlist. Durations aren't known, so they're written as -1.
+fn render(entries: &[PlaylistEntry]) -> String {
+    let mut playlist = String::from("#EXTM3U\n");
+    for entry in entries {
+        if let Some(title) = &entry.title {
+            // a newline in a tag would end the directive early
+            let title = title.replace(['\r', '\n'], " ");
+            playlist.push_str(&format!("#EXTINF:-1,{title}\n"));
+        }
+        playlist.push_str(&entry.path);
+        playlist.push('\n');
+    }
+    playlist
+}
+
+/// Renames the entries of a playlist, or returns None if none of them were renamed.
+fn rename_entries(playlist: &str, moves: &HashMap<String, String>) -> Option<String> {
+    let mut renamed = false;
+    let mut result = String::with_capacity(playlist.len());
+    for line in playlist.lines() {
+        match moves.get(line) {
+            Some(to) if !line.starts_with('#') => {
+                result.push_str(to);
+                renamed = true;
+            }
+            _ => result.push_str(line),
+        }
+        result.push('\n');
+    }
+    renamed.then_some(result)
+}
+
+/// Path of a file in a playlist at the root of the download directory, with `/` separators.
+fn entry_path(local_path: &str) -> String {
+    let (mut components, file_name) = split_local_path(local_path);
+    components.push(file_name);
+    components.join("/")
+}
+
+/// Splits a local path into its directories and file name.
+fn split_local_path(local_path: &str) -> (Vec<String>, String) {
+    let mut components = Path::new(local_path)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let file_name = components.pop().unwrap_or_default();
+    (components, file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let entries = [
+            PlaylistEntry {
+                path: "01 Hells Bells.flac".to_string(),
+                title: Some("AC/DC - Hells Bells".to_string()),
+            },
+            PlaylistEntry {
+                path: "Untagged/02 track.mp3".to_string(),
+                title: None,
+            },
+        ];
+        assert_eq!(
+            render(&entries),
+            "#EXTM3U\n#EXTINF:-1,AC/DC - Hells Bells\n01 Hells Bells.flac\nUntagged/02 track.mp3\n"
+        );
+    }
+
+    #[test]
+    fn test_rename_entries() {
+        let playlist = "#EXTM3U\n#EXTINF:-1,Song\nold/01 Song.flac\nother/02 Song.flac\n";
+        let moves = HashMap::from([(
+            "old/01 Song.flac".to_string(),
+            "Artist/Album/01 Song.flac".to_string(),
+        )]);
+        assert_eq!(
+            rename_entries(playlist, &moves).as_deref(),
+            Some("#EXTM3U\n#EXTINF:-1,Song\nArtist/Album/01 Song.flac\nother/02 Song.flac\n")
+        );
+        assert_eq!(rename_entries(playlist, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn test_split_local_path() {
+        assert_eq!(
+            split_local_path("Artist/Album/01 Song.flac"),
+            (
+                vec!["Artist".to_string(), "Album".to_string()],
+                "01 Song.flac".to_string()
+            )
+        );
+        assert_eq!(
+            split_local_path("song.mp3"),
+            (Vec::new(), "song.mp3".to_string())
+        );
+    }
+}
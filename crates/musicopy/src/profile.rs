@@ -0,0 +1,155 @@
+//! Named profiles, each with its own database, identity, library roots, trusted nodes, and
+//! transcodes.
+//!
+//! One machine can host several libraries with different sharing rules, e.g. a family library
+//! and a personal one. The default profile uses the data and cache directories directly, like
+//! before profiles existed, and named profiles use subdirectories of them. Logs are shared by all
+//! profiles, since logging is set up once per process.
+
+use crate::{
+    ProjectDirsOptions,
+    error::{CoreError, ProfileError},
+};
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+/// Subdirectory of the data and cache directories that named profiles are stored in.
+const PROFILES_DIR: &str = "profiles";
+
+/// Maximum length of a profile name in bytes.
+const MAX_PROFILE_NAME_LEN: usize = 64;
+
+/// Device names that Windows reserves in every directory, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM0", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7",
+    "COM8", "COM9", "LPT0", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Checks that a profile name can be used as a directory name on every platform.
+pub(crate) fn validate_name(name: &str) -> Result<(), ProfileError> {
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    let valid = !name.is_empty()
+        && name.len() <= MAX_PROFILE_NAME_LEN
+        && name.trim() == name
+        && !name.starts_with('.')
+        && !name.ends_with('.')
+        && !name.chars().any(|c| {
+            c.is_control() || matches!(c, '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*')
+        })
+        && !RESERVED_NAMES
+            .iter()
+            .any(|reserved| stem.eq_ignore_ascii_case(reserved));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(ProfileError::InvalidName {
+            name: name.to_string(),
+        })
+    }
+}
+
+/// Returns the data and cache directories of a profile, or of the default profile if None.
+pub(crate) fn dirs(
+    data_dir: &Path,
+    cache_dir: &Path,
+    profile: Option<&str>,
+) -> Result<(PathBuf, PathBuf), ProfileError> {
+    match profile {
+        Some(name) => {
+            validate_name(name)?;
+            Ok((
+                data_dir.join(PROFILES_DIR).join(name),
+                cache_dir.join(PROFILES_DIR).join(name),
+            ))
+        }
+        None => Ok((data_dir.to_owned(), cache_dir.to_owned())),
+    }
+}
+
+/// Lists the named profiles that have been started, sorted by name, in the given project
+/// directories or the platform's directories if None. The default profile isn't included.
+#[uniffi::export]
+pub fn list_profiles(project_dirs: Option<ProjectDirsOptions>) -> Result<Vec<String>, CoreError> {
+    let (data_dir, _) = crate::project_dirs(project_dirs.as_ref())?;
+    Ok(list(&data_dir)?)
+}
+
+/// Lists the names of the named profiles that have been started, sorted by name.
+pub(crate) fn list(data_dir: &Path) -> anyhow::Result<Vec<String>> {
+    let profiles_dir = data_dir.join(PROFILES_DIR);
+    if !profiles_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(&profiles_dir).context("failed to read profiles directory")? {
+        let entry = entry.context("failed to read profiles directory entry")?;
+        if !entry.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if validate_name(&name).is_ok() {
+            names.push(name);
+        }
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("Family library").is_ok());
+        assert!(validate_name("my-library_2").is_ok());
+        assert!(validate_name("Console").is_ok());
+
+        for name in [
+            "",
+            " padded ",
+            ".hidden",
+            "trailing.",
+            "a/b",
+            "a\\b",
+            "what?",
+            "tab\t",
+            "CON",
+            "nul",
+            "Com1",
+            "aux.txt",
+            "LPT9 .library",
+        ] {
+            assert!(validate_name(name).is_err(), "{name:?} should be invalid");
+        }
+        assert!(validate_name(&"a".repeat(MAX_PROFILE_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_dirs_and_list() {
+        let data_dir = testdir::testdir!();
+        let cache_dir = data_dir.join("cache");
+
+        let (default_data, default_cache) = dirs(&data_dir, &cache_dir, None).unwrap();
+        assert_eq!(default_data, data_dir);
+        assert_eq!(default_cache, cache_dir);
+
+        assert!(list(&data_dir).unwrap().is_empty());
+
+        for name in ["My library", "Family library"] {
+            let (profile_data, _) = dirs(&data_dir, &cache_dir, Some(name)).unwrap();
+            std::fs::create_dir_all(profile_data).unwrap();
+        }
+        assert_eq!(
+            list(&data_dir).unwrap(),
+            vec!["Family library".to_string(), "My library".to_string()]
+        );
+
+        assert!(dirs(&data_dir, &cache_dir, Some("../escape")).is_err());
+    }
+}
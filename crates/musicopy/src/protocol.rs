@@ -6,9 +6,10 @@
 //! support previous protocol versions temporarily. This was not possible for v12 because of the
 //! Iroh upgrade.
 
-use crate::library::transcode::TranscodeFormat;
+use crate::{library::transcode::TranscodeFormat, pairing::PairingToken, sync_group::GroupRecord};
+use anyhow::Context;
 use iroh::EndpointId;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::collections::HashMap;
 
 /// A message sent by the server end of a connection on the control stream.
@@ -28,6 +29,58 @@ pub enum ServerMessageV1 {
     IndexUpdate(Vec<IndexUpdateItem>),
     /// Notify the client that the statuses of jobs have changed.
     JobStatus(HashMap<u64, JobStatusItem>),
+    /// Inform the client of the maximum number of files the server will send at once.
+    ///
+    /// Sent after Index. Older clients fail to deserialize this message and ignore it.
+    TransferLimit(u32),
+    /// Inform the client of the content hashes of the items in the last Index, in the same order.
+    ///
+    /// Sent after Index. Items that haven't been hashed yet are None. Older clients fail to
+    /// deserialize this message and ignore it.
+    IndexHashes(Vec<Option<ContentHash>>),
+    /// Inform the client of the tags and durations of the items in the last Index, in the same
+    /// order.
+    ///
+    /// Sent after IndexHashes. Items whose tags and duration haven't been read yet are None. Older clients fail
+    /// to deserialize this message and ignore it.
+    IndexMetadata(Vec<Option<ItemMetadata>>),
+    /// Inform the client of a page of available files, with their hashes and metadata.
+    ///
+    /// Sent instead of Index, IndexHashes, and IndexMetadata to clients that set
+    /// `IdentifyOptions::paged_index`, so older clients never receive it.
+    IndexPage(IndexPage),
+    /// Ask the client to download items from the index, for push mode.
+    ///
+    /// Only sent to trusted clients, and only honored from trusted servers. Older clients fail to
+    /// deserialize this message and ignore it.
+    Push(Vec<PushItem>),
+    /// Show the client that the connection is still alive.
+    ///
+    /// Sent periodically after the connection is accepted. Older clients fail to deserialize this
+    /// message and ignore it.
+    Ping,
+    /// Inform the client of changes to the index after the server's library was rescanned.
+    ///
+    /// Sent after the index, in chunks of up to INDEX_PAGE_SIZE items. Older clients fail to
+    /// deserialize this message and ignore it, so their index stays as it was.
+    IndexChanges(IndexChanges),
+    /// Inform the client that the server can send parts of a file in separate streams, so large
+    /// files can be downloaded in parallel.
+    ///
+    /// Sent after TransferLimit. Older clients fail to deserialize this message and ignore it.
+    TransferParts,
+    /// Inform the client of the membership records of the sync groups it shares with the server,
+    /// so it learns about members that were added by other devices.
+    ///
+    /// Sent after the connection is accepted. Older clients fail to deserialize this message and
+    /// ignore it.
+    GroupRecords(Vec<GroupRecord>),
+    /// Inform the client of the extra metadata of the items in the next IndexChanges, in the same
+    /// order.
+    ///
+    /// Sent before each IndexChanges, so the changes are complete when they arrive. Older clients
+    /// fail to deserialize this message and ignore it.
+    IndexChangesExtraMetadata(Vec<Option<ItemExtraMetadata>>),
 }
 
 /// An item available for downloading from the server.
@@ -40,6 +93,195 @@ pub struct IndexItem {
     pub file_size: FileSize,
 }
 
+/// An item in the index that the server asks the client to download.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushItem {
+    pub endpoint_id: EndpointId,
+    pub root: String,
+    pub path: String,
+}
+
+/// A page of the index, compressed with zstd.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexPage {
+    /// Position of the first item of this page in the index.
+    pub offset: u64,
+    /// Total number of items in the index.
+    pub total: u64,
+    /// The zstd-compressed, postcard-serialized IndexPageItems.
+    pub compressed: Vec<u8>,
+}
+
+/// The contents of an IndexPage.
+///
+/// New fields must only be added at the end. Older clients ignore trailing bytes, and older
+/// servers send fewer fields, which are read with [`IndexPageItems::decompress`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IndexPageItems {
+    pub items: Vec<IndexItem>,
+    /// Content hashes of the items, in the same order.
+    pub hashes: Vec<Option<ContentHash>>,
+    /// Metadata of the items, in the same order.
+    pub metadata: Vec<Option<ItemMetadata>>,
+    /// Extra metadata of the items, in the same order, or empty if the server didn't send it.
+    pub extra_metadata: Vec<Option<ItemExtraMetadata>>,
+}
+
+/// Items added to, changed in, or removed from the index since it was sent.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndexChanges {
+    /// Items that were added or changed, replacing any items with the same root and path.
+    pub items: Vec<IndexItem>,
+    /// Content hashes of the items, in the same order.
+    pub hashes: Vec<Option<ContentHash>>,
+    /// Metadata of the items, in the same order.
+    pub metadata: Vec<Option<ItemMetadata>>,
+    /// Roots and paths of the items that were removed.
+    pub removed: Vec<(String, String)>,
+    /// Extra metadata of the items, in the same order.
+    ///
+    /// Not serialized, since older clients would fail to deserialize the changes. It's sent
+    /// before the changes in IndexChangesExtraMetadata instead.
+    #[serde(skip)]
+    pub extra_metadata: Vec<Option<ItemExtraMetadata>>,
+}
+
+impl IndexChanges {
+    /// Splits the changes into chunks of up to `size` changed and `size` removed items, so large
+    /// changes don't have to fit in one message.
+    pub fn chunks(self, size: usize) -> Vec<IndexChanges> {
+        let mut items = self
+            .items
+            .into_iter()
+            .zip(self.hashes)
+            .zip(self.metadata)
+            .zip(self.extra_metadata)
+            .peekable();
+        let mut removed = self.removed.into_iter().peekable();
+
+        let mut chunks = Vec::new();
+        while items.peek().is_some() || removed.peek().is_some() {
+            let mut chunk = IndexChanges::default();
+            for (((item, hash), metadata), extra_metadata) in items.by_ref().take(size) {
+                chunk.items.push(item);
+                chunk.hashes.push(hash);
+                chunk.metadata.push(metadata);
+                chunk.extra_metadata.push(extra_metadata);
+            }
+            chunk.removed.extend(removed.by_ref().take(size));
+            chunks.push(chunk);
+        }
+        chunks
+    }
+}
+
+/// Number of items in each IndexPage.
+pub const INDEX_PAGE_SIZE: usize = 1000;
+
+/// Maximum size of a decompressed IndexPage, to protect against decompression bombs.
+const INDEX_PAGE_MAX_SIZE: usize = 64 * 1024 * 1024;
+
+/// zstd compression level for index pages.
+const INDEX_PAGE_COMPRESSION_LEVEL: i32 = 3;
+
+impl IndexPageItems {
+    /// Serializes and compresses the items into a page.
+    pub fn compress(&self, offset: u64, total: u64) -> anyhow::Result<IndexPage> {
+        let buf = postcard::to_stdvec(self).context("failed to serialize index page")?;
+        let compressed = zstd::bulk::compress(&buf, INDEX_PAGE_COMPRESSION_LEVEL)
+            .context("failed to compress index page")?;
+        Ok(IndexPage {
+            offset,
+            total,
+            compressed,
+        })
+    }
+
+    /// Decompresses and deserializes the items of a page sent by a server of any version.
+    ///
+    /// Fields the server didn't send are left empty.
+    pub fn decompress(page: &IndexPage) -> anyhow::Result<Self> {
+        let buf = zstd::bulk::decompress(&page.compressed, INDEX_PAGE_MAX_SIZE)
+            .context("failed to decompress index page")?;
+
+        let mut rest = buf.as_slice();
+        let mut items = Self::default();
+        if let Some(index_items) =
+            take_trailing(&mut rest).context("failed to deserialize index page")?
+        {
+            items.items = index_items;
+        }
+        if let Some(hashes) =
+            take_trailing(&mut rest).context("failed to deserialize index page")?
+        {
+            items.hashes = hashes;
+        }
+        if let Some(metadata) =
+            take_trailing(&mut rest).context("failed to deserialize index page")?
+        {
+            items.metadata = metadata;
+        }
+        if let Some(extra_metadata) =
+            take_trailing(&mut rest).context("failed to deserialize index page")?
+        {
+            items.extra_metadata = extra_metadata;
+        }
+
+        anyhow::ensure!(
+            items.hashes.len() == items.items.len() && items.metadata.len() == items.items.len(),
+            "index page has {} items but {} hashes and {} metadata",
+            items.items.len(),
+            items.hashes.len(),
+            items.metadata.len()
+        );
+        anyhow::ensure!(
+            items.extra_metadata.is_empty() || items.extra_metadata.len() == items.items.len(),
+            "index page has {} items but {} extra metadata",
+            items.items.len(),
+            items.extra_metadata.len()
+        );
+        Ok(items)
+    }
+}
+
+/// Deserializes the next of the fields appended to a message, or None if the peer didn't send it
+/// because it's older.
+fn take_trailing<T: DeserializeOwned>(rest: &mut &[u8]) -> postcard::Result<Option<T>> {
+    if rest.is_empty() {
+        return Ok(None);
+    }
+    let (value, remaining) = postcard::take_from_bytes(rest)?;
+    *rest = remaining;
+    Ok(Some(value))
+}
+
+/// The hash of an original file's content, as computed by the server's hash cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentHash {
+    pub kind: String,
+    pub hash: [u8; 16],
+}
+
+/// The tags and duration of an original file, as read by the server's library scan.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ItemMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u32>,
+    /// Duration in seconds.
+    pub duration: Option<f64>,
+}
+
+/// Tags of an original file that were added after ItemMetadata.
+///
+/// Sent separately from ItemMetadata, since older clients fail to deserialize fields added to it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ItemExtraMetadata {
+    pub album_artist: Option<String>,
+    pub disc_number: Option<u32>,
+}
+
 /// An update to an item in the index.
 ///
 /// Deprecated: no longer sent in current versions, but kept for backwards compatibility.
@@ -81,6 +323,89 @@ pub enum ClientMessageV1 {
     },
     /// Request to download files.
     Download(Vec<DownloadItem>),
+    /// Ask the server to connect back to the client and download the original files it lacks,
+    /// for two-way sync.
+    ///
+    /// Only honored from trusted clients. Older servers fail to deserialize this message and
+    /// ignore it.
+    SyncBack { conflict_policy: SyncConflictPolicy },
+    /// Ask the server to transcode the files for these jobs before the rest of its queue, when
+    /// the user moves them to the front of the download queue.
+    ///
+    /// Older servers fail to deserialize this message and ignore it.
+    PrioritizeTranscodes(Vec<u64>),
+    /// Show the server that the connection is still alive.
+    ///
+    /// Sent periodically after the connection is accepted. Older servers fail to deserialize this
+    /// message and ignore it.
+    Ping,
+    /// Ask the server to rescan its library, e.g. after files were added to it. Changes are sent
+    /// to the client as IndexChanges.
+    ///
+    /// Only honored from trusted clients, and not more often than the server allows. Older
+    /// servers fail to deserialize this message and ignore it.
+    Rescan,
+    /// Inform the server that the client understands manifests in transfer responses, which
+    /// describe each file before its bytes are sent.
+    ///
+    /// Sent after the connection is accepted. Older servers fail to deserialize this message and
+    /// ignore it.
+    TransferManifests,
+}
+
+/// Options sent by the client directly after Identify, in the same frame.
+///
+/// This is appended after the message instead of being a field, because older servers ignore
+/// trailing bytes and will still accept the Identify message.
+///
+/// New fields must only be added at the end. Older servers ignore the fields they don't know
+/// about, and older clients send fewer fields, which are read with [`IdentifyOptions::from_bytes`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IdentifyOptions {
+    /// Whether the client understands IndexPage messages.
+    pub paged_index: bool,
+    /// One-time token from a pairing ticket, which lets the client connect without the server
+    /// accepting the connection manually.
+    pub pairing_token: Option<PairingToken>,
+    /// Transcode format requested by the client, which overrides the one in Identify.
+    ///
+    /// Identify carries a fallback for older servers, which fail to parse formats added since.
+    pub transcode_format: Option<TranscodeFormat>,
+    /// Membership records of the sync groups the client shares with the server, which lets the
+    /// server accept the connection without asking if the client is a member.
+    pub group_records: Vec<GroupRecord>,
+}
+
+impl IdentifyOptions {
+    /// Deserializes the options sent by a client of any version.
+    ///
+    /// Fields the client didn't send are left at their defaults.
+    pub fn from_bytes(bytes: &[u8]) -> postcard::Result<Self> {
+        let mut options = Self::default();
+        let mut rest = bytes;
+        if let Some(paged_index) = take_trailing(&mut rest)? {
+            options.paged_index = paged_index;
+        }
+        if let Some(pairing_token) = take_trailing(&mut rest)? {
+            options.pairing_token = pairing_token;
+        }
+        if let Some(transcode_format) = take_trailing(&mut rest)? {
+            options.transcode_format = transcode_format;
+        }
+        if let Some(group_records) = take_trailing(&mut rest)? {
+            options.group_records = group_records;
+        }
+        Ok(options)
+    }
+}
+
+/// How two-way sync handles a file at the same path with different content on each side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, uniffi::Enum)]
+pub enum SyncConflictPolicy {
+    /// Keep the local file and don't download the other side's copy.
+    KeepLocal,
+    /// Download the other side's copy alongside the local file.
+    KeepBoth,
 }
 
 /// An item requested for downloading by the client.
@@ -92,3 +417,51 @@ pub struct DownloadItem {
     pub root: String,
     pub path: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress_page_without_extra_metadata() {
+        // pages from older servers end after the metadata
+        let metadata = vec![Some(ItemMetadata {
+            title: Some("evolution".into()),
+            ..Default::default()
+        })];
+        let items = vec![IndexItem {
+            endpoint_id: iroh::SecretKey::generate().public(),
+            root: "music".into(),
+            path: "evolution.mp3".into(),
+            file_size: FileSize::Unknown,
+        }];
+        let hashes: Vec<Option<ContentHash>> = vec![None];
+        let buf = postcard::to_stdvec(&(&items, &hashes, &metadata)).unwrap();
+        let page = IndexPage {
+            offset: 0,
+            total: 1,
+            compressed: zstd::bulk::compress(&buf, INDEX_PAGE_COMPRESSION_LEVEL).unwrap(),
+        };
+
+        let decompressed = IndexPageItems::decompress(&page).unwrap();
+        assert_eq!(decompressed.items.len(), 1);
+        assert_eq!(decompressed.metadata, metadata);
+        assert!(decompressed.extra_metadata.is_empty());
+
+        // pages from current servers have extra metadata
+        let extra_metadata = vec![Some(ItemExtraMetadata {
+            album_artist: Some("8sumint".into()),
+            disc_number: Some(1),
+        })];
+        let page = IndexPageItems {
+            items,
+            hashes,
+            metadata,
+            extra_metadata: extra_metadata.clone(),
+        }
+        .compress(0, 1)
+        .unwrap();
+        let decompressed = IndexPageItems::decompress(&page).unwrap();
+        assert_eq!(decompressed.extra_metadata, extra_metadata);
+    }
+}
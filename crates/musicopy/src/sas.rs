@@ -0,0 +1,65 @@
+//! Short authentication strings for verifying connections.
+//!
+//! Both ends of a connection derive the same short phrase from the two endpoint IDs, so users can
+//! compare the phrases shown on each device before accepting a connection. This catches a
+//! mistyped endpoint ID or a connection to the wrong device.
+
+use iroh::EndpointId;
+
+/// Number of words in a verification phrase.
+const PHRASE_WORDS: usize = 4;
+
+/// Context string for deriving verification phrases, so the hash isn't reused for anything else.
+const PHRASE_CONTEXT: &str = "musicopy 2025 verification phrase v1";
+
+/// Derives the verification phrase for a connection between two endpoints.
+///
+/// The phrase doesn't depend on which end is the client, so both ends show the same phrase.
+pub fn verification_phrase(a: EndpointId, b: EndpointId) -> String {
+    let (first, second) = if a.as_bytes() <= b.as_bytes() {
+        (a, b)
+    } else {
+        (b, a)
+    };
+
+    let mut hasher = blake3::Hasher::new_derive_key(PHRASE_CONTEXT);
+    hasher.update(first.as_bytes());
+    hasher.update(second.as_bytes());
+    let hash = hasher.finalize();
+
+    hash.as_bytes()[..PHRASE_WORDS]
+        .iter()
+        .map(|byte| WORDS[*byte as usize])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Words used in verification phrases, one for each possible byte value.
+const WORDS: [&str; 256] = [
+    "acorn", "alarm", "album", "alley", "amber", "angel", "ankle", "apple", "apron", "arena",
+    "arrow", "aspen", "atlas", "attic", "bacon", "badge", "bagel", "baker", "banjo", "barn",
+    "basil", "beach", "beard", "bell", "bench", "berry", "bison", "blade", "blaze", "bloom",
+    "boat", "boot", "brass", "bread", "brick", "brook", "broom", "brush", "bucket", "cabin",
+    "cable", "cactus", "camel", "candy", "canoe", "canyon", "cargo", "carpet", "castle", "cedar",
+    "chair", "chalk", "charm", "cheese", "cherry", "chess", "cider", "circus", "clamp", "cliff",
+    "clock", "cloud", "clover", "coast", "cobra", "cocoa", "comet", "coral", "cotton", "couch",
+    "crab", "crane", "crate", "crayon", "creek", "crown", "cube", "daisy", "dance", "delta",
+    "denim", "desert", "diary", "dock", "donkey", "dough", "dragon", "dream", "drum", "eagle",
+    "easel", "echo", "elbow", "ember", "engine", "falcon", "feast", "fence", "ferry", "fiddle",
+    "field", "finch", "flame", "flute", "fog", "forest", "fossil", "fox", "frost", "fudge",
+    "garden", "garlic", "gecko", "ghost", "giant", "ginger", "globe", "glove", "goat", "gold",
+    "goose", "grape", "guitar", "hammer", "harbor", "harp", "hawk", "hazel", "helmet", "heron",
+    "honey", "hook", "horse", "igloo", "iris", "island", "ivory", "jacket", "jaguar", "jelly",
+    "jewel", "jungle", "kayak", "kettle", "kiwi", "koala", "ladder", "lagoon", "lake", "lamp",
+    "lemon", "lily", "lion", "lizard", "llama", "lotus", "magnet", "mango", "maple", "marble",
+    "meadow", "melon", "mirror", "mitten", "moose", "mosaic", "mouse", "muffin", "needle", "nest",
+    "noodle", "oasis", "ocean", "olive", "onion", "orbit", "otter", "owl", "paddle", "palace",
+    "panda", "paper", "parrot", "peach", "pearl", "pebble", "pepper", "piano", "pickle", "pigeon",
+    "pilot", "pine", "pirate", "pizza", "planet", "plum", "pocket", "pony", "poppy", "potato",
+    "prism", "puzzle", "quartz", "quilt", "rabbit", "radio", "rain", "raven", "reef", "ribbon",
+    "river", "robin", "rocket", "rose", "ruby", "saddle", "salmon", "sand", "scarf", "shadow",
+    "shell", "silver", "skate", "sloth", "snail", "spider", "spoon", "squid", "stamp", "star",
+    "stone", "storm", "sugar", "summit", "sunset", "swan", "table", "teapot", "tiger", "toast",
+    "tomato", "tulip", "tunnel", "turtle", "valley", "velvet", "violin", "wagon", "walnut",
+    "whale", "willow", "window", "winter", "wizard", "yacht", "zebra",
+];
@@ -0,0 +1,421 @@
+//! Scheduling rules for when queued downloads can run.
+//!
+//! Phones often shouldn't download a whole library over mobile data or on battery, so the user
+//! can hold downloads until the device is charging, on an unmetered network, or within a window
+//! of hours. The shells report the device's power and network state, since the core can't read
+//! it on every platform.
+//!
+//! Rules only hold downloads that haven't started. Active downloads finish when a rule stops
+//! being met.
+//!
+//! Separately from the schedule, the settings choose what happens on metered networks: downloads
+//! can be held or slowed down, and new connections can be prevented so nothing is transferred
+//! at all. Downloads are always held while the device is offline.
+
+use crate::error::ConnectionError;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+/// Rules for when queued downloads can run. All enabled rules must be met.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, uniffi::Record)]
+pub struct TransferSchedule {
+    /// Only download while the device is charging.
+    pub require_charging: bool,
+    /// Only download while the device is on an unmetered network, like Wi-Fi.
+    pub require_unmetered: bool,
+    /// Only download within these hours of the device's local time.
+    pub hours: Option<TransferHours>,
+}
+
+/// A window of hours in local time, from `start_hour` up to `end_hour`. The window wraps around
+/// midnight if `start_hour` is after `end_hour`, e.g. 22 to 6 for nights. If they're equal, the
+/// window covers the whole day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Record)]
+pub struct TransferHours {
+    pub start_hour: u8,
+    pub end_hour: u8,
+}
+
+impl TransferHours {
+    /// Whether the window contains the given hour of the day.
+    fn contains(&self, hour: u8) -> bool {
+        let start = self.start_hour % 24;
+        let end = self.end_hour % 24;
+        if start == end {
+            true
+        } else if start < end {
+            (start..end).contains(&hour)
+        } else {
+            hour >= start || hour < end
+        }
+    }
+}
+
+/// Power and network state of the device, reported by the shell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Record)]
+pub struct DeviceState {
+    pub charging: bool,
+    /// Whether the active network is unmetered, like Wi-Fi or Ethernet.
+    pub unmetered: bool,
+    /// Offset of the device's local time from UTC in seconds.
+    pub utc_offset_secs: i32,
+}
+
+impl Default for DeviceState {
+    /// Desktops don't report their state, so they're assumed to be plugged in and unmetered.
+    fn default() -> Self {
+        Self {
+            charging: true,
+            unmetered: true,
+            utc_offset_secs: 0,
+        }
+    }
+}
+
+/// State of the device's active network, reported by the shell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Record)]
+pub struct NetworkState {
+    /// Whether the OS considers the network metered.
+    pub metered: bool,
+    /// Whether the network is Wi-Fi or Ethernet, rather than mobile data.
+    pub wifi: bool,
+    /// Whether there's no network at all.
+    pub offline: bool,
+}
+
+impl Default for NetworkState {
+    /// Desktops don't report their network, so it's assumed to be unmetered.
+    fn default() -> Self {
+        Self {
+            metered: false,
+            wifi: true,
+            offline: false,
+        }
+    }
+}
+
+impl NetworkState {
+    /// Whether the metered network policy applies. Mobile data counts as metered even if the OS
+    /// doesn't say so, since carriers often limit "unlimited" plans.
+    pub fn is_metered(&self) -> bool {
+        self.metered || !self.wifi
+    }
+}
+
+/// What happens to downloads on metered networks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, uniffi::Enum)]
+pub enum MeteredPolicy {
+    /// Download as usual.
+    #[default]
+    Allow,
+    /// Limit the download rate to the metered download rate in the settings.
+    Limit,
+    /// Hold queued downloads until the network is unmetered.
+    Pause,
+}
+
+/// How transfers are restricted on metered networks, from the settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct NetworkPolicy {
+    pub metered_policy: MeteredPolicy,
+    /// Maximum download rate for [`MeteredPolicy::Limit`] in bytes per second, or 0 for no limit.
+    pub metered_download_rate: u64,
+    /// Whether new connections are opened while the network is metered.
+    pub connect_on_metered: bool,
+}
+
+impl Default for NetworkPolicy {
+    fn default() -> Self {
+        Self {
+            metered_policy: MeteredPolicy::Allow,
+            metered_download_rate: 0,
+            connect_on_metered: true,
+        }
+    }
+}
+
+impl NetworkPolicy {
+    /// Returns the reasons that downloads are held on the given network.
+    fn hold_reasons(&self, network: &NetworkState) -> Vec<TransferHoldReason> {
+        let mut reasons = Vec::new();
+        if self.metered_policy == MeteredPolicy::Pause && network.is_metered() {
+            reasons.push(TransferHoldReason::Metered);
+        }
+        if network.offline {
+            reasons.push(TransferHoldReason::Offline);
+        }
+        reasons
+    }
+
+    /// Returns the download rate limit to use on the given network, given the limit from the
+    /// settings. Either limit can be 0 for no limit.
+    fn download_rate_limit(&self, network: &NetworkState, max_download_rate: u64) -> u64 {
+        if self.metered_policy != MeteredPolicy::Limit
+            || !network.is_metered()
+            || self.metered_download_rate == 0
+        {
+            max_download_rate
+        } else if max_download_rate == 0 {
+            self.metered_download_rate
+        } else {
+            max_download_rate.min(self.metered_download_rate)
+        }
+    }
+}
+
+/// Why queued downloads are being held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum TransferHoldReason {
+    NotCharging,
+    Metered,
+    OutsideHours,
+    /// The device has no network.
+    Offline,
+}
+
+impl TransferSchedule {
+    /// Returns the reasons that downloads are held for the given device state and time in seconds
+    /// since the Unix epoch, or an empty list if they can run.
+    pub fn hold_reasons(&self, device: &DeviceState, now_secs: u64) -> Vec<TransferHoldReason> {
+        let mut reasons = Vec::new();
+        if self.require_charging && !device.charging {
+            reasons.push(TransferHoldReason::NotCharging);
+        }
+        if self.require_unmetered && !device.unmetered {
+            reasons.push(TransferHoldReason::Metered);
+        }
+        if let Some(hours) = &self.hours {
+            let local_secs = now_secs as i64 + device.utc_offset_secs as i64;
+            let hour = local_secs.rem_euclid(24 * 60 * 60) / (60 * 60);
+            if !hours.contains(hour as u8) {
+                reasons.push(TransferHoldReason::OutsideHours);
+            }
+        }
+        reasons
+    }
+}
+
+/// Holds queued downloads on all connections while the schedule isn't met.
+#[derive(Debug)]
+pub(crate) struct TransferGate {
+    schedule: Mutex<TransferSchedule>,
+    device: Mutex<DeviceState>,
+    network: Mutex<NetworkState>,
+    network_policy: Mutex<NetworkPolicy>,
+    /// Reasons that downloads are currently held.
+    reasons: watch::Sender<Vec<TransferHoldReason>>,
+}
+
+impl TransferGate {
+    pub(crate) fn new() -> Self {
+        Self {
+            schedule: Mutex::new(TransferSchedule::default()),
+            device: Mutex::new(DeviceState::default()),
+            network: Mutex::new(NetworkState::default()),
+            network_policy: Mutex::new(NetworkPolicy::default()),
+            reasons: watch::Sender::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn schedule(&self) -> TransferSchedule {
+        *self.schedule.lock().unwrap()
+    }
+
+    pub(crate) fn set_schedule(&self, schedule: TransferSchedule) {
+        *self.schedule.lock().unwrap() = schedule;
+    }
+
+    /// Sets the device state. Its network state replaces the reported one, as if it was reported
+    /// with `set_network_state`.
+    pub(crate) fn set_device_state(&self, device: DeviceState) {
+        *self.device.lock().unwrap() = device;
+        let mut network = self.network.lock().unwrap();
+        network.metered = !device.unmetered;
+        // mobile data counts as metered, so an unmetered network must be Wi-Fi or Ethernet
+        if device.unmetered {
+            network.wifi = true;
+        }
+    }
+
+    pub(crate) fn network(&self) -> NetworkState {
+        *self.network.lock().unwrap()
+    }
+
+    /// Sets the network state, keeping the device state's unmetered flag in sync for the
+    /// schedule.
+    pub(crate) fn set_network_state(&self, network: NetworkState) {
+        *self.network.lock().unwrap() = network;
+        self.device.lock().unwrap().unmetered = !network.is_metered();
+    }
+
+    pub(crate) fn set_network_policy(&self, policy: NetworkPolicy) {
+        *self.network_policy.lock().unwrap() = policy;
+    }
+
+    /// Returns the download rate limit to use on the current network, given the limit from the
+    /// settings.
+    pub(crate) fn download_rate_limit(&self, max_download_rate: u64) -> u64 {
+        let network = self.network.lock().unwrap();
+        self.network_policy
+            .lock()
+            .unwrap()
+            .download_rate_limit(&network, max_download_rate)
+    }
+
+    /// Checks that new connections can be opened on the current network.
+    pub(crate) fn check_can_connect(&self) -> Result<(), ConnectionError> {
+        let network = self.network.lock().unwrap();
+        let policy = self.network_policy.lock().unwrap();
+        if network.offline {
+            Err(ConnectionError::Offline)
+        } else if network.is_metered() && !policy.connect_on_metered {
+            Err(ConnectionError::Metered)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the reasons that downloads are currently held.
+    pub(crate) fn reasons(&self) -> Vec<TransferHoldReason> {
+        self.reasons.borrow().clone()
+    }
+
+    /// Checks the schedule against the device state and the current time, returning whether the
+    /// reasons changed.
+    pub(crate) fn update(&self, now_secs: u64) -> bool {
+        let reasons = {
+            let schedule = self.schedule.lock().unwrap();
+            let device = self.device.lock().unwrap();
+            let mut reasons = schedule.hold_reasons(&device, now_secs);
+
+            let network = self.network.lock().unwrap();
+            let policy = self.network_policy.lock().unwrap();
+            for reason in policy.hold_reasons(&network) {
+                if !reasons.contains(&reason) {
+                    reasons.push(reason);
+                }
+            }
+            reasons
+        };
+        self.reasons.send_if_modified(|current| {
+            if *current == reasons {
+                false
+            } else {
+                *current = reasons;
+                true
+            }
+        })
+    }
+
+    /// Waits until downloads aren't held.
+    pub(crate) async fn wait_until_allowed(&self) {
+        let mut reasons_rx = self.reasons.subscribe();
+        // the sender lives as long as self, so this only fails if it's dropped
+        let _ = reasons_rx.wait_for(|reasons| reasons.is_empty()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+
+    #[test]
+    fn test_hours_wrap_around_midnight() {
+        let night = TransferHours {
+            start_hour: 22,
+            end_hour: 6,
+        };
+        assert!(night.contains(23));
+        assert!(night.contains(0));
+        assert!(night.contains(5));
+        assert!(!night.contains(6));
+        assert!(!night.contains(12));
+
+        let all_day = TransferHours {
+            start_hour: 3,
+            end_hour: 3,
+        };
+        assert!((0..24).all(|hour| all_day.contains(hour)));
+    }
+
+    #[test]
+    fn test_hold_reasons() {
+        let schedule = TransferSchedule {
+            require_charging: true,
+            require_unmetered: true,
+            hours: Some(TransferHours {
+                start_hour: 1,
+                end_hour: 5,
+            }),
+        };
+        let mut device = DeviceState {
+            charging: false,
+            unmetered: false,
+            utc_offset_secs: 0,
+        };
+        assert_eq!(
+            schedule.hold_reasons(&device, 12 * HOUR),
+            vec![
+                TransferHoldReason::NotCharging,
+                TransferHoldReason::Metered,
+                TransferHoldReason::OutsideHours,
+            ]
+        );
+
+        device.charging = true;
+        device.unmetered = true;
+        assert!(schedule.hold_reasons(&device, 2 * HOUR).is_empty());
+
+        // 23:00 UTC is 02:00 at UTC+3
+        device.utc_offset_secs = 3 * HOUR as i32;
+        assert!(schedule.hold_reasons(&device, 23 * HOUR).is_empty());
+        // 02:00 UTC is 21:00 at UTC-5
+        device.utc_offset_secs = -5 * HOUR as i32;
+        assert_eq!(
+            schedule.hold_reasons(&device, 2 * HOUR),
+            vec![TransferHoldReason::OutsideHours]
+        );
+    }
+
+    #[test]
+    fn test_network_policy() {
+        let wifi = NetworkState::default();
+        let mobile = NetworkState {
+            metered: false,
+            wifi: false,
+            offline: false,
+        };
+        let offline = NetworkState {
+            offline: true,
+            ..wifi
+        };
+
+        let pause = NetworkPolicy {
+            metered_policy: MeteredPolicy::Pause,
+            ..Default::default()
+        };
+        assert!(pause.hold_reasons(&wifi).is_empty());
+        assert_eq!(
+            pause.hold_reasons(&mobile),
+            vec![TransferHoldReason::Metered]
+        );
+        assert_eq!(
+            NetworkPolicy::default().hold_reasons(&offline),
+            vec![TransferHoldReason::Offline]
+        );
+
+        let limit = NetworkPolicy {
+            metered_policy: MeteredPolicy::Limit,
+            metered_download_rate: 100,
+            ..Default::default()
+        };
+        assert_eq!(limit.download_rate_limit(&wifi, 0), 0);
+        assert_eq!(limit.download_rate_limit(&mobile, 0), 100);
+        assert_eq!(limit.download_rate_limit(&mobile, 50), 50);
+        assert_eq!(limit.download_rate_limit(&mobile, 500), 100);
+        assert_eq!(pause.download_rate_limit(&mobile, 500), 500);
+    }
+}
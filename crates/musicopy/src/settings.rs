@@ -0,0 +1,332 @@
+//! Settings of the app, stored in the database so they persist across launches.
+//!
+//! The UI edits a [`SettingsModel`] and passes it to `Core::update_settings`, which stores it and
+//! applies it to the library, the transcode pool, and the node. Each field is stored under its
+//! own key and serialized with postcard, so fields can be added without losing the stored
+//! settings. Fields that are missing or fail to deserialize fall back to their defaults.
+
+use crate::{
+    database::Database,
+    error::{LibraryError, TransferError},
+    fs::template::PathTemplate,
+    journal::PartialFilePolicy,
+    library::transcode::{DEFAULT_TRANSCODE_WORKERS, MAX_TRANSCODE_WORKERS, TranscodeFormat},
+    node::{DEFAULT_MAX_CONCURRENT_TRANSFERS, MAX_CONCURRENT_TRANSFERS},
+    playlist::PlaylistSettings,
+    schedule::{MeteredPolicy, NetworkPolicy},
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{collections::HashMap, time::Duration};
+use tracing::warn;
+
+const DOWNLOAD_DIRECTORY: &str = "download_directory";
+const DOWNLOAD_PATH_TEMPLATE: &str = "download_path_template";
+const TRANSCODE_POLICY: &str = "transcode_policy";
+const TRANSCODE_FORMAT: &str = "transcode_format";
+const TRANSCODE_WORKERS: &str = "transcode_workers";
+const MAX_CONCURRENT_TRANSFERS_KEY: &str = "max_concurrent_transfers";
+const MAX_TOTAL_DOWNLOADS: &str = "max_total_downloads";
+const MAX_DOWNLOAD_RATE: &str = "max_download_rate";
+const MAX_UPLOAD_RATE_PER_CLIENT: &str = "max_upload_rate_per_client";
+const METERED_POLICY: &str = "metered_policy";
+const METERED_DOWNLOAD_RATE: &str = "metered_download_rate";
+const CONNECT_ON_METERED: &str = "connect_on_metered";
+const ACCEPT_INCOMING: &str = "accept_incoming";
+const PLAYLISTS: &str = "playlists";
+const PARTIAL_FILE_POLICY: &str = "partial_file_policy";
+const TRANSFER_HISTORY_RETENTION_DAYS: &str = "transfer_history_retention_days";
+
+/// How many days finished download sessions are kept in the transfer history by default.
+const DEFAULT_TRANSFER_HISTORY_RETENTION_DAYS: u32 = 90;
+
+/// When local files are transcoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, uniffi::Enum)]
+pub enum TranscodePolicy {
+    /// Transcode files when clients request them.
+    #[default]
+    OnDemand,
+    /// Also transcode the whole library in the preferred format after each scan, so clients
+    /// don't wait for transcodes when they download.
+    AheadOfTime,
+}
+
+/// The app's settings.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct SettingsModel {
+    /// The directory that downloaded files are saved to.
+    pub download_directory: Option<String>,
+    /// The template for the paths of downloaded files, or None to keep the server's folder
+    /// structure. See `Core::set_download_path_template`.
+    pub download_path_template: Option<String>,
+    /// Which M3U8 playlists are written into the download directory after a sync.
+    pub playlists: PlaylistSettings,
+    /// What happens at startup to partial files in the download directory that aren't from
+    /// interrupted downloads that can be resumed.
+    pub partial_file_policy: PartialFilePolicy,
+
+    pub transcode_policy: TranscodePolicy,
+    /// The format local files are transcoded to ahead of time. Required by
+    /// [`TranscodePolicy::AheadOfTime`].
+    pub transcode_format: Option<TranscodeFormat>,
+    /// The number of threads transcoding files. Clamped to 1..=32.
+    pub transcode_workers: u32,
+
+    /// The maximum number of files downloaded or sent at once per connection. Clamped to 1..=32.
+    pub max_concurrent_transfers: u32,
+    /// The maximum number of files downloaded at once across all connections, or 0 for no limit.
+    pub max_total_downloads: u32,
+    /// The maximum download rate across all connections in bytes per second, or 0 for no limit.
+    pub max_download_rate: u64,
+    /// The maximum upload rate to each client in bytes per second, or 0 for no limit.
+    pub max_upload_rate_per_client: u64,
+
+    /// What happens to downloads on metered networks, like mobile data.
+    pub metered_policy: MeteredPolicy,
+    /// The maximum download rate on metered networks in bytes per second with
+    /// [`MeteredPolicy::Limit`], or 0 for no limit.
+    pub metered_download_rate: u64,
+    /// Whether connections to other nodes are opened on metered networks. Incoming connections
+    /// are still accepted.
+    pub connect_on_metered: bool,
+    /// Whether incoming connections from nodes that aren't trusted are accepted.
+    pub accept_incoming: bool,
+
+    /// How many days finished download sessions are kept in the transfer history, or None to keep
+    /// them forever.
+    pub transfer_history_retention_days: Option<u32>,
+}
+
+impl Default for SettingsModel {
+    fn default() -> Self {
+        Self {
+            download_directory: None,
+            download_path_template: None,
+            playlists: PlaylistSettings::default(),
+            partial_file_policy: PartialFilePolicy::default(),
+
+            transcode_policy: TranscodePolicy::default(),
+            transcode_format: None,
+            transcode_workers: DEFAULT_TRANSCODE_WORKERS,
+
+            max_concurrent_transfers: DEFAULT_MAX_CONCURRENT_TRANSFERS,
+            max_total_downloads: 0,
+            max_download_rate: 0,
+            max_upload_rate_per_client: 0,
+
+            metered_policy: MeteredPolicy::default(),
+            metered_download_rate: 0,
+            connect_on_metered: true,
+            accept_incoming: true,
+
+            transfer_history_retention_days: Some(DEFAULT_TRANSFER_HISTORY_RETENTION_DAYS),
+        }
+    }
+}
+
+impl SettingsModel {
+    /// Loads the stored settings, using defaults for settings that were never stored.
+    pub(crate) fn load(db: &Database) -> anyhow::Result<Self> {
+        let values = db.get_settings().context("failed to get settings")?;
+        let defaults = Self::default();
+
+        Ok(Self {
+            download_directory: decode(&values, DOWNLOAD_DIRECTORY, defaults.download_directory),
+            download_path_template: decode(
+                &values,
+                DOWNLOAD_PATH_TEMPLATE,
+                defaults.download_path_template,
+            ),
+            playlists: decode(&values, PLAYLISTS, defaults.playlists),
+            partial_file_policy: decode(&values, PARTIAL_FILE_POLICY, defaults.partial_file_policy),
+
+            transcode_policy: decode(&values, TRANSCODE_POLICY, defaults.transcode_policy),
+            transcode_format: decode(&values, TRANSCODE_FORMAT, defaults.transcode_format),
+            transcode_workers: decode(&values, TRANSCODE_WORKERS, defaults.transcode_workers),
+
+            max_concurrent_transfers: decode(
+                &values,
+                MAX_CONCURRENT_TRANSFERS_KEY,
+                defaults.max_concurrent_transfers,
+            ),
+            max_total_downloads: decode(&values, MAX_TOTAL_DOWNLOADS, defaults.max_total_downloads),
+            max_download_rate: decode(&values, MAX_DOWNLOAD_RATE, defaults.max_download_rate),
+            max_upload_rate_per_client: decode(
+                &values,
+                MAX_UPLOAD_RATE_PER_CLIENT,
+                defaults.max_upload_rate_per_client,
+            ),
+
+            metered_policy: decode(&values, METERED_POLICY, defaults.metered_policy),
+            metered_download_rate: decode(
+                &values,
+                METERED_DOWNLOAD_RATE,
+                defaults.metered_download_rate,
+            ),
+            connect_on_metered: decode(&values, CONNECT_ON_METERED, defaults.connect_on_metered),
+            accept_incoming: decode(&values, ACCEPT_INCOMING, defaults.accept_incoming),
+
+            transfer_history_retention_days: decode(
+                &values,
+                TRANSFER_HISTORY_RETENTION_DAYS,
+                defaults.transfer_history_retention_days,
+            ),
+        })
+    }
+
+    /// Stores all the settings.
+    pub(crate) fn save(&self, db: &mut Database) -> anyhow::Result<()> {
+        db.set_settings([
+            (DOWNLOAD_DIRECTORY, encode(&self.download_directory)?),
+            (
+                DOWNLOAD_PATH_TEMPLATE,
+                encode(&self.download_path_template)?,
+            ),
+            (PLAYLISTS, encode(&self.playlists)?),
+            (PARTIAL_FILE_POLICY, encode(&self.partial_file_policy)?),
+            (TRANSCODE_POLICY, encode(&self.transcode_policy)?),
+            (TRANSCODE_FORMAT, encode(&self.transcode_format)?),
+            (TRANSCODE_WORKERS, encode(&self.transcode_workers)?),
+            (
+                MAX_CONCURRENT_TRANSFERS_KEY,
+                encode(&self.max_concurrent_transfers)?,
+            ),
+            (MAX_TOTAL_DOWNLOADS, encode(&self.max_total_downloads)?),
+            (MAX_DOWNLOAD_RATE, encode(&self.max_download_rate)?),
+            (
+                MAX_UPLOAD_RATE_PER_CLIENT,
+                encode(&self.max_upload_rate_per_client)?,
+            ),
+            (METERED_POLICY, encode(&self.metered_policy)?),
+            (METERED_DOWNLOAD_RATE, encode(&self.metered_download_rate)?),
+            (CONNECT_ON_METERED, encode(&self.connect_on_metered)?),
+            (ACCEPT_INCOMING, encode(&self.accept_incoming)?),
+            (
+                TRANSFER_HISTORY_RETENTION_DAYS,
+                encode(&self.transfer_history_retention_days)?,
+            ),
+        ])
+        .context("failed to set settings")
+    }
+
+    /// The restrictions on metered networks, for the node.
+    pub(crate) fn network_policy(&self) -> NetworkPolicy {
+        NetworkPolicy {
+            metered_policy: self.metered_policy,
+            metered_download_rate: self.metered_download_rate,
+            connect_on_metered: self.connect_on_metered,
+        }
+    }
+
+    /// How long finished download sessions are kept in the transfer history, for the node.
+    pub(crate) fn transfer_history_retention(&self) -> Option<Duration> {
+        self.transfer_history_retention_days
+            .map(|days| Duration::from_secs(u64::from(days) * 24 * 60 * 60))
+    }
+
+    /// Checks that the settings are valid and clamps numbers to their bounds.
+    pub(crate) fn validate(mut self) -> anyhow::Result<Self> {
+        if let Some(template) = &self.download_path_template {
+            PathTemplate::parse(template).context(TransferError::InvalidPathTemplate)?;
+        }
+
+        if self.transcode_policy == TranscodePolicy::AheadOfTime && self.transcode_format.is_none()
+        {
+            return Err(LibraryError::TranscodeFormatRequired.into());
+        }
+
+        self.transcode_workers = self.transcode_workers.clamp(1, MAX_TRANSCODE_WORKERS);
+        self.max_concurrent_transfers = self
+            .max_concurrent_transfers
+            .clamp(1, MAX_CONCURRENT_TRANSFERS);
+
+        Ok(self)
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> anyhow::Result<Vec<u8>> {
+    postcard::to_stdvec(value).context("failed to serialize setting")
+}
+
+fn decode<T: DeserializeOwned>(values: &HashMap<String, Vec<u8>>, key: &str, default: T) -> T {
+    let Some(value) = values.get(key) else {
+        return default;
+    };
+
+    match postcard::from_bytes(value) {
+        Ok(value) => value,
+        Err(e) => {
+            warn!("failed to deserialize setting {key}: {e:#}");
+            default
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DatabasePool;
+
+    #[test]
+    fn test_settings_roundtrip() {
+        let pool = DatabasePool::open_in_memory().unwrap();
+        let mut db = pool.get();
+
+        // defaults before anything is stored
+        assert_eq!(SettingsModel::load(&db).unwrap(), SettingsModel::default());
+
+        let settings = SettingsModel {
+            download_directory: Some("/music".to_string()),
+            download_path_template: Some("{artist}/{album}/{title}".to_string()),
+            playlists: PlaylistSettings {
+                per_album: true,
+                recently_synced: false,
+            },
+            partial_file_policy: PartialFilePolicy::Resume,
+            transcode_policy: TranscodePolicy::AheadOfTime,
+            transcode_format: Some(TranscodeFormat::Opus96),
+            transcode_workers: 2,
+            max_concurrent_transfers: 8,
+            max_total_downloads: 16,
+            max_download_rate: 1_000_000,
+            max_upload_rate_per_client: 500_000,
+            metered_policy: MeteredPolicy::Limit,
+            metered_download_rate: 250_000,
+            connect_on_metered: false,
+            accept_incoming: false,
+            transfer_history_retention_days: None,
+        };
+        settings.save(&mut db).unwrap();
+        assert_eq!(SettingsModel::load(&db).unwrap(), settings);
+    }
+
+    #[test]
+    fn test_settings_validate() {
+        let settings = SettingsModel {
+            transcode_workers: 0,
+            max_concurrent_transfers: 1000,
+            ..Default::default()
+        }
+        .validate()
+        .unwrap();
+        assert_eq!(settings.transcode_workers, 1);
+        assert_eq!(settings.max_concurrent_transfers, MAX_CONCURRENT_TRANSFERS);
+
+        assert!(
+            SettingsModel {
+                transcode_policy: TranscodePolicy::AheadOfTime,
+                ..Default::default()
+            }
+            .validate()
+            .is_err()
+        );
+        assert!(
+            SettingsModel {
+                download_path_template: Some("{nope}".to_string()),
+                ..Default::default()
+            }
+            .validate()
+            .is_err()
+        );
+    }
+}
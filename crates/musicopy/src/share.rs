@@ -0,0 +1,585 @@
+//! Share links for people without the app.
+//!
+//! A share link is a URL with a random token that gives access to a selection of library files,
+//! like an album or a playlist folder. The share server is a minimal HTTP/1.1 server that lists
+//! the files of a link as a web page and serves them to a browser, either as originals or as
+//! transcodes. Links expire after a set time, and one-time links are removed once each of their
+//! files has been downloaded.
+//!
+//! The server only speaks plain HTTP. To hand out links over the internet, put it behind a
+//! reverse proxy that terminates TLS, and set the public URL so links point at the proxy.
+
+use crate::{
+    library::{
+        hash::HashCache,
+        transcode::{TranscodeFormat, TranscodeStatus, TranscodeStatusCache},
+    },
+    node::unix_epoch_now_secs,
+};
+use anyhow::Context;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+
+/// Maximum size of a request line and headers. Requests are only ever simple GETs.
+const MAX_REQUEST_HEAD_SIZE: u64 = 8 * 1024;
+
+/// How long browsers should wait before retrying a file that's still being transcoded.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// How long a connection has to send its request line and headers, so idle connections don't
+/// stay open.
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of connections handled at once. Further connections wait to be accepted.
+const MAX_CONNECTIONS: usize = 64;
+
+/// A file in a share link.
+#[derive(Debug, Clone)]
+pub struct ShareLinkFile {
+    pub root: String,
+    pub path: String,
+    pub local_path: PathBuf,
+}
+
+/// A share link, kept in memory until it expires, is revoked, or the app restarts.
+#[derive(Debug, Clone)]
+pub struct ShareLink {
+    pub name: String,
+    /// Transcode format to serve the files in, or None to serve the original files.
+    pub transcode_format: Option<TranscodeFormat>,
+    pub files: Vec<ShareLinkFile>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// Whether the link is removed once each of its files has been downloaded.
+    pub one_time: bool,
+    /// Indices of the files that have been downloaded completely.
+    pub downloaded: HashSet<usize>,
+}
+
+impl ShareLink {
+    fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+}
+
+/// Share links by token.
+pub type ShareLinks = Arc<Mutex<HashMap<String, ShareLink>>>;
+
+/// Removes expired links, returning whether any were removed.
+pub fn remove_expired_links(links: &ShareLinks) -> bool {
+    let now = unix_epoch_now_secs();
+    let mut links = links.lock().unwrap();
+    let len = links.len();
+    links.retain(|_, link| !link.is_expired(now));
+    links.len() != len
+}
+
+/// A running share server.
+#[derive(Debug)]
+pub struct ShareServer {
+    pub local_addr: SocketAddr,
+    /// Base URL that links are built from, without a trailing slash.
+    pub public_url: String,
+    task: JoinHandle<()>,
+}
+
+impl ShareServer {
+    /// Binds the share server to the given address and starts serving links.
+    ///
+    /// Links are built from `public_url` if it's set, otherwise from the bound address.
+    /// `on_change` is called when a link's downloads change or a link is removed.
+    pub async fn spawn(
+        bind_addr: &str,
+        public_url: Option<String>,
+        links: ShareLinks,
+        hash_cache: HashCache,
+        transcode_status_cache: TranscodeStatusCache,
+        on_change: impl Fn() + Send + Sync + 'static,
+    ) -> anyhow::Result<Self> {
+        let bind_addr = bind_addr
+            .parse::<SocketAddr>()
+            .with_context(|| format!("failed to parse share server address {bind_addr:?}"))?;
+        let listener = TcpListener::bind(bind_addr)
+            .await
+            .with_context(|| format!("failed to bind share server to {bind_addr}"))?;
+        let local_addr = listener
+            .local_addr()
+            .context("failed to get share server address")?;
+
+        let public_url = match public_url {
+            Some(public_url) => {
+                let parsed = url::Url::parse(&public_url)
+                    .with_context(|| format!("failed to parse public url {public_url:?}"))?;
+                anyhow::ensure!(
+                    matches!(parsed.scheme(), "http" | "https"),
+                    "public url must use http or https"
+                );
+                public_url.trim_end_matches('/').to_string()
+            }
+            None => format!("http://{local_addr}"),
+        };
+
+        let state = Arc::new(ShareState {
+            links,
+            hash_cache,
+            transcode_status_cache,
+            on_change: Box::new(on_change),
+        });
+
+        let connection_slots = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+        let task = tokio::spawn(async move {
+            loop {
+                // wait for a free slot before accepting, so the backlog holds the rest
+                let connection_slot = connection_slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("connection semaphore closed");
+                let (stream, peer_addr) = match listener.accept().await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("share server: failed to accept connection: {e:#}");
+                        continue;
+                    }
+                };
+
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = state.handle_connection(stream).await {
+                        debug!("share server: error handling request from {peer_addr}: {e:#}");
+                    }
+                    drop(connection_slot);
+                });
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            public_url,
+            task,
+        })
+    }
+
+    /// Returns the URL of the link with the given token.
+    pub fn link_url(&self, token: &str) -> String {
+        format!("{}/s/{token}", self.public_url)
+    }
+}
+
+impl Drop for ShareServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct ShareState {
+    links: ShareLinks,
+    hash_cache: HashCache,
+    transcode_status_cache: TranscodeStatusCache,
+    on_change: Box<dyn Fn() + Send + Sync>,
+}
+
+/// A request for a share link or one of its files.
+#[derive(Debug, PartialEq, Eq)]
+enum ShareRequest<'a> {
+    Index { token: &'a str },
+    File { token: &'a str, index: usize },
+}
+
+/// Parses the path of a request, ignoring the query string.
+fn parse_request_path(path: &str) -> Option<ShareRequest<'_>> {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let mut segments = path.strip_prefix("/s/")?.split('/');
+
+    let token = segments.next().filter(|token| !token.is_empty())?;
+    let request = match segments.next() {
+        None | Some("") => ShareRequest::Index { token },
+        Some(index) => ShareRequest::File {
+            token,
+            index: index.parse().ok()?,
+        },
+    };
+
+    if segments.next().is_some() {
+        return None;
+    }
+    Some(request)
+}
+
+/// The file to serve for a request.
+enum ResolvedFile {
+    Ready {
+        path: PathBuf,
+        file_size: u64,
+    },
+    /// The transcode isn't ready yet.
+    Pending,
+    Failed,
+}
+
+impl ShareState {
+    async fn handle_connection(&self, stream: TcpStream) -> anyhow::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader).take(MAX_REQUEST_HEAD_SIZE);
+
+        let request_line = tokio::time::timeout(REQUEST_HEAD_TIMEOUT, async {
+            let mut request_line = String::new();
+            reader
+                .read_line(&mut request_line)
+                .await
+                .context("failed to read request line")?;
+
+            // skip the headers, nothing in them matters for serving files
+            loop {
+                let mut header = String::new();
+                let n = reader
+                    .read_line(&mut header)
+                    .await
+                    .context("failed to read header")?;
+                if n == 0 || header == "\r\n" || header == "\n" {
+                    break;
+                }
+            }
+            anyhow::Ok(request_line)
+        })
+        .await
+        .context("timed out reading request")??;
+
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return write_response(&mut writer, "400 Bad Request", "text/plain", b"bad request")
+                .await;
+        };
+        let head_only = match method {
+            "GET" => false,
+            "HEAD" => true,
+            _ => {
+                return write_response(
+                    &mut writer,
+                    "405 Method Not Allowed",
+                    "text/plain",
+                    b"method not allowed",
+                )
+                .await;
+            }
+        };
+
+        let Some(request) = parse_request_path(path) else {
+            return write_response(&mut writer, "404 Not Found", "text/plain", b"not found").await;
+        };
+
+        match request {
+            ShareRequest::Index { token } => {
+                let Some(link) = self.get_link(token) else {
+                    return write_response(
+                        &mut writer,
+                        "404 Not Found",
+                        "text/plain",
+                        b"this link has expired or doesn't exist",
+                    )
+                    .await;
+                };
+
+                let body = render_index(token, &link);
+                let body = if head_only { &[][..] } else { body.as_bytes() };
+                write_response(&mut writer, "200 OK", "text/html; charset=utf-8", body).await
+            }
+
+            ShareRequest::File { token, index } => {
+                let Some((link, file)) = self
+                    .get_link(token)
+                    .and_then(|link| link.files.get(index).cloned().map(|file| (link, file)))
+                else {
+                    return write_response(
+                        &mut writer,
+                        "404 Not Found",
+                        "text/plain",
+                        b"this link has expired or doesn't exist",
+                    )
+                    .await;
+                };
+
+                let (path, file_size) = match self.resolve_file(&file, link.transcode_format) {
+                    ResolvedFile::Ready { path, file_size } => (path, file_size),
+                    ResolvedFile::Pending => {
+                        let head = format!(
+                            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {RETRY_AFTER_SECS}\r\nContent-Type: text/plain\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        );
+                        writer.write_all(head.as_bytes()).await?;
+                        return Ok(());
+                    }
+                    ResolvedFile::Failed => {
+                        return write_response(
+                            &mut writer,
+                            "500 Internal Server Error",
+                            "text/plain",
+                            b"this file couldn't be prepared",
+                        )
+                        .await;
+                    }
+                };
+
+                let file_name = download_file_name(&file.path, link.transcode_format);
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {file_size}\r\nContent-Disposition: {}\r\nConnection: close\r\n\r\n",
+                    content_type(&file_name),
+                    content_disposition(&file_name),
+                );
+                writer.write_all(head.as_bytes()).await?;
+                if head_only {
+                    return Ok(());
+                }
+
+                let mut source = tokio::fs::File::open(&path)
+                    .await
+                    .context("failed to open shared file")?;
+                let copied = tokio::io::copy(&mut source, &mut writer)
+                    .await
+                    .context("failed to send shared file")?;
+                writer.shutdown().await?;
+
+                if copied == file_size {
+                    self.mark_downloaded(token, index);
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Gets a copy of the link with the given token, if it exists and hasn't expired.
+    fn get_link(&self, token: &str) -> Option<ShareLink> {
+        let now = unix_epoch_now_secs();
+        let mut links = self.links.lock().unwrap();
+        match links.get(token) {
+            Some(link) if link.is_expired(now) => {
+                links.remove(token);
+                drop(links);
+                (self.on_change)();
+                None
+            }
+            Some(link) => Some(link.clone()),
+            None => None,
+        }
+    }
+
+    /// Records that a file was downloaded completely, removing one-time links once all of their
+    /// files have been.
+    fn mark_downloaded(&self, token: &str, index: usize) {
+        {
+            let mut links = self.links.lock().unwrap();
+            let Some(link) = links.get_mut(token) else {
+                return;
+            };
+            if !link.downloaded.insert(index) {
+                return;
+            }
+            if link.one_time && link.downloaded.len() == link.files.len() {
+                debug!("share server: all files of one-time link downloaded, removing it");
+                links.remove(token);
+            }
+        }
+
+        (self.on_change)();
+    }
+
+    fn resolve_file(
+        &self,
+        file: &ShareLinkFile,
+        transcode_format: Option<TranscodeFormat>,
+    ) -> ResolvedFile {
+        let Ok(key) = self.hash_cache.read_cache_key(&file.local_path) else {
+            return ResolvedFile::Failed;
+        };
+
+        let Some(transcode_format) = transcode_format else {
+            return ResolvedFile::Ready {
+                path: file.local_path.clone(),
+                file_size: key.file_size(),
+            };
+        };
+
+        let Ok(Some((hash_kind, hash))) = self.hash_cache.get_cached_hash(&key) else {
+            return ResolvedFile::Pending;
+        };
+
+        match self
+            .transcode_status_cache
+            .get(transcode_format, &hash_kind, hash)
+            .as_deref()
+        {
+            Some(TranscodeStatus::Ready {
+                transcode_path,
+                file_size,
+                ..
+            }) => ResolvedFile::Ready {
+                path: transcode_path.clone(),
+                file_size: *file_size,
+            },
+            Some(TranscodeStatus::Failed { .. }) => ResolvedFile::Failed,
+            None => ResolvedFile::Pending,
+        }
+    }
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Renders the page listing the files of a link.
+fn render_index(token: &str, link: &ShareLink) -> String {
+    let name = escape_html(&link.name);
+    let mut items = String::new();
+    for (index, file) in link.files.iter().enumerate() {
+        let file_name = download_file_name(&file.path, link.transcode_format);
+        items.push_str(&format!(
+            "<li><a href=\"/s/{token}/{index}\" download>{}</a></li>\n",
+            escape_html(&file_name)
+        ));
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{name}</title>\n</head>\n<body>\n<h1>{name}</h1>\n<ol>\n{items}</ol>\n</body>\n</html>\n"
+    )
+}
+
+/// Returns the file name to download a shared file as, with the transcode's extension if it's
+/// transcoded.
+fn download_file_name(path: &str, transcode_format: Option<TranscodeFormat>) -> String {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    match transcode_format {
+        Some(format) => Path::new(file_name)
+            .with_extension(format.extension())
+            .to_string_lossy()
+            .into_owned(),
+        None => file_name.to_string(),
+    }
+}
+
+fn content_type(file_name: &str) -> &'static str {
+    let extension = Path::new(file_name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("mp3") => "audio/mpeg",
+        Some("ogg" | "opus") => "audio/ogg",
+        Some("flac") => "audio/flac",
+        Some("m4a" | "aac") => "audio/mp4",
+        Some("wav") => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Builds a Content-Disposition header value, with an ASCII fallback name for old browsers.
+fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| {
+            if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    let mut encoded = String::new();
+    for byte in file_name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request_path() {
+        assert_eq!(
+            parse_request_path("/s/abc"),
+            Some(ShareRequest::Index { token: "abc" })
+        );
+        assert_eq!(
+            parse_request_path("/s/abc/"),
+            Some(ShareRequest::Index { token: "abc" })
+        );
+        assert_eq!(
+            parse_request_path("/s/abc/2?download=1"),
+            Some(ShareRequest::File {
+                token: "abc",
+                index: 2
+            })
+        );
+        assert_eq!(parse_request_path("/s/"), None);
+        assert_eq!(parse_request_path("/s/abc/x"), None);
+        assert_eq!(parse_request_path("/s/abc/1/2"), None);
+        assert_eq!(parse_request_path("/favicon.ico"), None);
+    }
+
+    #[test]
+    fn test_download_file_name() {
+        assert_eq!(download_file_name("a/b/song.flac", None), "song.flac");
+        assert_eq!(
+            download_file_name("a/b/song.flac", Some(TranscodeFormat::Opus128)),
+            "song.ogg"
+        );
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("Café \"1\".mp3"),
+            "attachment; filename=\"Caf_ _1_.mp3\"; filename*=UTF-8''Caf%C3%A9%20%221%22.mp3"
+        );
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(escape_html("<a & 'b'>"), "&lt;a &amp; &#39;b&#39;&gt;");
+    }
+}
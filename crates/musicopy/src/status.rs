@@ -0,0 +1,125 @@
+//! A compact summary of the core's state, for widgets and scripts that poll it.
+//!
+//! The full models can be large, e.g. the node model has the indexes of all connected servers, so
+//! the summary is counted under the models' locks without copying them. Recent errors are kept
+//! here too, since they're otherwise only sent as events.
+
+use crate::{
+    node::{FileSizeModel, unix_epoch_now_secs},
+    schedule::TransferHoldReason,
+};
+use std::{collections::VecDeque, sync::Mutex};
+
+/// Number of recent errors kept by each component.
+const MAX_RECENT_ERRORS: usize = 10;
+
+/// Summary of the core's state, from `Core::get_status`.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct StatusModel {
+    pub endpoint_id: String,
+
+    pub is_scanning: bool,
+    /// Number of files in all library roots.
+    pub library_files: u64,
+
+    pub transcodes_queued: u64,
+    pub transcodes_in_progress: u64,
+    pub transcodes_failed: u64,
+    pub transcodes_dir_size: FileSizeModel,
+
+    /// Number of accepted connections in both directions.
+    pub open_connections: u32,
+    /// Number of connections waiting to be accepted in both directions.
+    pub pending_connections: u32,
+    pub active_downloads: u32,
+    pub queued_downloads: u32,
+    pub active_uploads: u32,
+    /// Why queued downloads are held, or empty if they can run.
+    pub transfer_hold_reasons: Vec<TransferHoldReason>,
+
+    /// The most recent errors, newest first.
+    pub recent_errors: Vec<StatusErrorModel>,
+}
+
+/// An error shown in the status summary.
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct StatusErrorModel {
+    /// When the error happened, in seconds since the Unix epoch.
+    pub at: u64,
+    pub source: StatusErrorSource,
+    pub message: String,
+}
+
+/// What an error in the status summary came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum StatusErrorSource {
+    Scan,
+    Transfer,
+    Connection,
+}
+
+/// The library's part of the status summary.
+pub(crate) struct LibraryStatus {
+    pub is_scanning: bool,
+    pub library_files: u64,
+    pub transcodes_queued: u64,
+    pub transcodes_in_progress: u64,
+    pub transcodes_failed: u64,
+    pub transcodes_dir_size: FileSizeModel,
+    pub recent_errors: Vec<StatusErrorModel>,
+}
+
+/// The node's part of the status summary.
+pub(crate) struct NodeStatus {
+    pub endpoint_id: String,
+    pub open_connections: u32,
+    pub pending_connections: u32,
+    pub active_downloads: u32,
+    pub queued_downloads: u32,
+    pub active_uploads: u32,
+    pub transfer_hold_reasons: Vec<TransferHoldReason>,
+    pub recent_errors: Vec<StatusErrorModel>,
+}
+
+/// The last few errors of a component.
+#[derive(Debug, Default)]
+pub(crate) struct RecentErrors {
+    errors: Mutex<VecDeque<StatusErrorModel>>,
+}
+
+impl RecentErrors {
+    pub(crate) fn push(&self, source: StatusErrorSource, message: String) {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == MAX_RECENT_ERRORS {
+            errors.pop_back();
+        }
+        errors.push_front(StatusErrorModel {
+            at: unix_epoch_now_secs(),
+            source,
+            message,
+        });
+    }
+
+    /// Returns the errors, newest first.
+    pub(crate) fn list(&self) -> Vec<StatusErrorModel> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_errors() {
+        let errors = RecentErrors::default();
+        for i in 0..MAX_RECENT_ERRORS + 2 {
+            errors.push(StatusErrorSource::Transfer, i.to_string());
+        }
+
+        let list = errors.list();
+        assert_eq!(list.len(), MAX_RECENT_ERRORS);
+        assert_eq!(list[0].message, (MAX_RECENT_ERRORS + 1).to_string());
+        assert_eq!(list[MAX_RECENT_ERRORS - 1].message, "2");
+    }
+}
@@ -0,0 +1,359 @@
+//! Sync groups of devices that trust each other.
+//!
+//! A sync group is a set of devices, like a desktop, a laptop, and a phone, where each member
+//! trusts all the others. Instead of pairing every pair of devices, each device only has to be
+//! added to the group once by a member it already trusts.
+//!
+//! Membership is made of signed records. The founder of a group signs a record adding itself,
+//! and any member can sign a record adding another device. A record is only valid if its issuer
+//! is a member, so every valid record leads back to the founder. Members exchange the records
+//! of their shared groups when they connect, so a device learns about members that were added
+//! by other devices. When a device receives a record adding it from a node it already trusts,
+//! it keeps the group as a pending invitation, and only joins once the user accepts it.
+//!
+//! Members can't be removed from a group, but each device can leave a group locally, or untrust
+//! a member so it's no longer trusted through its groups.
+
+use crate::database::Database;
+use anyhow::Context;
+use iroh::{EndpointId, SecretKey, Signature};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tracing::{info, warn};
+
+/// Random ID of a sync group.
+pub type GroupId = [u8; 16];
+
+/// Context string for signing records, so signatures aren't valid for anything else.
+const RECORD_CONTEXT: &str = "musicopy 2025 sync group record v1";
+
+/// Maximum number of records accepted from a node whose connection hasn't been accepted yet, since
+/// verifying records takes quadratic time.
+pub const MAX_UNACCEPTED_RECORDS: usize = 256;
+
+/// A signed record that adds a member to a sync group.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupRecord {
+    pub group_id: GroupId,
+    pub group_name: String,
+    pub founder: EndpointId,
+    pub member: EndpointId,
+    pub issuer: EndpointId,
+    pub issued_at: u64,
+    /// Ed25519 signature by the issuer over the other fields.
+    pub signature: Vec<u8>,
+}
+
+/// The fields of a record that are signed.
+#[derive(Serialize)]
+struct SignedFields<'a> {
+    context: &'a str,
+    group_id: &'a GroupId,
+    group_name: &'a str,
+    founder: &'a EndpointId,
+    member: &'a EndpointId,
+    issuer: &'a EndpointId,
+    issued_at: u64,
+}
+
+impl GroupRecord {
+    /// Creates a record adding `member` to the group, signed by the holder of `secret_key`.
+    pub fn sign(
+        secret_key: &SecretKey,
+        group_id: GroupId,
+        group_name: String,
+        founder: EndpointId,
+        member: EndpointId,
+        issued_at: u64,
+    ) -> anyhow::Result<Self> {
+        let mut record = Self {
+            group_id,
+            group_name,
+            founder,
+            member,
+            issuer: secret_key.public(),
+            issued_at,
+            signature: Vec::new(),
+        };
+        let signature = secret_key.sign(&record.signed_bytes()?);
+        record.signature = signature.to_bytes().to_vec();
+        Ok(record)
+    }
+
+    fn signed_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        postcard::to_stdvec(&SignedFields {
+            context: RECORD_CONTEXT,
+            group_id: &self.group_id,
+            group_name: &self.group_name,
+            founder: &self.founder,
+            member: &self.member,
+            issuer: &self.issuer,
+            issued_at: self.issued_at,
+        })
+        .context("failed to serialize group record")
+    }
+
+    /// Whether the record was signed by its issuer.
+    fn has_valid_signature(&self) -> bool {
+        let Ok(signature) = <[u8; 64]>::try_from(self.signature.as_slice()) else {
+            return false;
+        };
+        let Ok(signed_bytes) = self.signed_bytes() else {
+            return false;
+        };
+        self.issuer
+            .verify(&signed_bytes, &Signature::from_bytes(&signature))
+            .is_ok()
+    }
+}
+
+/// Returns the records of a group whose issuers lead back to its founder, with one record per
+/// member.
+///
+/// Records for other groups, with a different founder, or with invalid signatures are dropped.
+pub fn verify_records(
+    group_id: GroupId,
+    founder: EndpointId,
+    records: impl IntoIterator<Item = GroupRecord>,
+) -> Vec<GroupRecord> {
+    let mut pending = records
+        .into_iter()
+        .filter(|record| record.group_id == group_id && record.founder == founder)
+        .filter(GroupRecord::has_valid_signature)
+        .collect::<Vec<_>>();
+    // prefer the oldest record for each member
+    pending.sort_by_key(|record| record.issued_at);
+
+    let mut verified = Vec::new();
+    let mut members = HashSet::new();
+    // the founder's own record is what lets it issue others
+    loop {
+        let (ready, rest): (Vec<_>, Vec<_>) = pending.into_iter().partition(|record| {
+            (record.issuer == founder && record.member == founder)
+                || members.contains(&record.issuer)
+        });
+        pending = rest;
+        if ready.is_empty() {
+            break;
+        }
+        for record in ready {
+            if members.insert(record.member) {
+                verified.push(record);
+            }
+        }
+    }
+
+    verified
+}
+
+/// An invitation to join a sync group, from a trusted node that added this node to the group.
+#[derive(Debug, Clone)]
+pub struct Invitation {
+    pub group_id: GroupId,
+    pub group_name: String,
+    pub founder: EndpointId,
+    /// The trusted node that added this node to the group.
+    pub inviter: EndpointId,
+    /// The verified records of the group, which are stored if the invitation is accepted.
+    pub records: Vec<GroupRecord>,
+}
+
+/// The result of merging records received from a node.
+#[derive(Debug, Default)]
+pub struct MergeOutcome {
+    /// Whether records of groups this node is a member of were added.
+    pub changed: bool,
+    /// Invitations to groups this node isn't a member of yet.
+    pub invitations: Vec<Invitation>,
+}
+
+/// Merges records received from a node into the database.
+///
+/// Records for groups this node is a member of are added if they're valid. Records adding this
+/// node to a group it isn't a member of, issued by a node it trusts, are returned as invitations
+/// instead, so the user can decide whether to join.
+pub fn merge_records(
+    db: &Database,
+    local_endpoint_id: EndpointId,
+    records: Vec<GroupRecord>,
+) -> anyhow::Result<MergeOutcome> {
+    let mut outcome = MergeOutcome::default();
+    if records.is_empty() {
+        return Ok(outcome);
+    }
+
+    let groups = db
+        .get_sync_groups()?
+        .into_iter()
+        .map(|group| (group.group_id, group))
+        .collect::<HashMap<_, _>>();
+
+    let mut received: HashMap<GroupId, Vec<GroupRecord>> = HashMap::new();
+    for record in records {
+        received.entry(record.group_id).or_default().push(record);
+    }
+
+    for (group_id, records) in received {
+        let Some(group) = groups.get(&group_id) else {
+            // only trusted nodes can invite us to a group
+            let Some(invitation) = records.iter().find(|record| {
+                record.member == local_endpoint_id
+                    && db.is_node_trusted(record.issuer).unwrap_or(false)
+            }) else {
+                continue;
+            };
+            let founder = invitation.founder;
+            let inviter = invitation.issuer;
+
+            let verified = verify_records(group_id, founder, records);
+            if !verified
+                .iter()
+                .any(|record| record.member == local_endpoint_id)
+            {
+                warn!("ignoring invitation to sync group that doesn't include this node");
+                continue;
+            }
+
+            info!("invited to sync group {:?}", verified[0].group_name);
+            outcome.invitations.push(Invitation {
+                group_id,
+                group_name: verified[0].group_name.clone(),
+                founder,
+                inviter,
+                records: verified,
+            });
+            continue;
+        };
+        let founder = group.founder;
+        let known = db.get_group_records(group_id)?;
+
+        let known_members = known
+            .iter()
+            .map(|record| record.member)
+            .collect::<HashSet<_>>();
+        let verified = verify_records(group_id, founder, known.into_iter().chain(records));
+        if !verified
+            .iter()
+            .any(|record| record.member == local_endpoint_id)
+        {
+            warn!("ignoring records for sync group that doesn't include this node");
+            continue;
+        }
+
+        for record in verified {
+            if !known_members.contains(&record.member) {
+                db.insert_group_record(&record)?;
+                outcome.changed = true;
+            }
+        }
+    }
+
+    Ok(outcome)
+}
+
+/// Joins the group of an invitation the user accepted, so its members are trusted.
+pub fn accept_invitation(db: &Database, invitation: &Invitation) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        db.is_node_trusted(invitation.inviter)?,
+        "the node that sent the invitation is no longer trusted"
+    );
+
+    let known_members = db
+        .get_group_records(invitation.group_id)?
+        .into_iter()
+        .map(|record| record.member)
+        .collect::<HashSet<_>>();
+
+    info!("joining sync group {:?}", invitation.group_name);
+    db.insert_sync_group(
+        invitation.group_id,
+        &invitation.group_name,
+        invitation.founder,
+    )?;
+    for record in &invitation.records {
+        if !known_members.contains(&record.member) {
+            db.insert_group_record(record)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the records of the groups that both this node and the given node are members of, to
+/// send to it.
+pub fn shared_records(db: &Database, endpoint_id: EndpointId) -> anyhow::Result<Vec<GroupRecord>> {
+    let mut records = Vec::new();
+    for group in db.get_sync_groups()? {
+        let group_records = db.get_group_records(group.group_id)?;
+        if group_records
+            .iter()
+            .any(|record| record.member == endpoint_id)
+        {
+            records.extend(group_records);
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(
+        issuer: &SecretKey,
+        founder: &SecretKey,
+        member: EndpointId,
+        issued_at: u64,
+    ) -> GroupRecord {
+        GroupRecord::sign(
+            issuer,
+            [1; 16],
+            "group".into(),
+            founder.public(),
+            member,
+            issued_at,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_verify_records_chain() {
+        let desktop = SecretKey::generate();
+        let laptop = SecretKey::generate();
+        let phone = SecretKey::generate();
+
+        // the phone was added by the laptop, which was added by the desktop
+        let records = vec![
+            record(&laptop, &desktop, phone.public(), 3),
+            record(&desktop, &desktop, laptop.public(), 2),
+            record(&desktop, &desktop, desktop.public(), 1),
+        ];
+        let verified = verify_records([1; 16], desktop.public(), records);
+        let members = verified.iter().map(|r| r.member).collect::<HashSet<_>>();
+        assert_eq!(
+            members,
+            HashSet::from([desktop.public(), laptop.public(), phone.public()])
+        );
+    }
+
+    #[test]
+    fn test_verify_records_rejects_outsiders() {
+        let desktop = SecretKey::generate();
+        let outsider = SecretKey::generate();
+        let phone = SecretKey::generate();
+
+        let mut forged = record(&desktop, &desktop, outsider.public(), 2);
+        forged.member = phone.public();
+
+        let records = vec![
+            record(&desktop, &desktop, desktop.public(), 1),
+            // issued by a node that isn't a member
+            record(&outsider, &desktop, outsider.public(), 2),
+            // signature doesn't match the fields
+            forged,
+        ];
+        let verified = verify_records([1; 16], desktop.public(), records);
+        assert_eq!(verified.len(), 1);
+        assert_eq!(verified[0].member, desktop.public());
+    }
+}
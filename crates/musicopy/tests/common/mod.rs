@@ -1,10 +1,20 @@
 use iroh::EndpointId;
 use musicopy::{
-    Core, CoreOptions, EventHandler, ProjectDirsOptions, StatsModel, TestHooks,
-    library::LibraryModel,
-    node::{ClientModel, ClientStateModel, NodeModel, ServerModel, ServerStateModel},
+    Core, CoreOptions, DatabaseRecoveredEvent, EventHandler, ProjectDirsOptions,
+    ShutdownProgressEvent, StatsModel, TestHooks,
+    library::{LibraryModel, LibraryModelDiff},
+    node::{
+        BackgroundProgressEvent, ClientModel, ClientStateModel, ConnectionLostEvent, NodeModel,
+        NodeModelDiff, ServerModel, ServerStateModel, TransferJobFailedEvent,
+        TransferSessionCompletedEvent,
+    },
+    settings::SettingsModel,
+};
+use std::{
+    borrow::Cow,
+    path::PathBuf,
+    sync::{Arc, Mutex},
 };
-use std::{borrow::Cow, path::PathBuf, sync::Arc};
 use tracing::{debug, warn};
 
 #[derive(Debug, Clone, Copy)]
@@ -49,7 +59,14 @@ async fn wait_until(msg: &str, condition: impl Fn() -> bool, on_fail: impl Fn())
     }
 }
 
-pub struct TestEventHandler;
+/// Records the discrete events, so tests can wait for them.
+#[derive(Default)]
+pub struct TestEventHandler {
+    pub sessions_completed: Mutex<Vec<TransferSessionCompletedEvent>>,
+    pub node_model_diffs: Mutex<Vec<NodeModelDiff>>,
+    pub shutdown_progress: Mutex<Vec<ShutdownProgressEvent>>,
+    pub background_progress: Mutex<Vec<BackgroundProgressEvent>>,
+}
 
 impl EventHandler for TestEventHandler {
     fn on_library_model_snapshot(&self, _model: LibraryModel) {}
@@ -57,6 +74,32 @@ impl EventHandler for TestEventHandler {
     fn on_node_model_snapshot(&self, _model: NodeModel) {}
 
     fn on_stats_model_snapshot(&self, _model: StatsModel) {}
+
+    fn on_settings_model_snapshot(&self, _model: SettingsModel) {}
+
+    fn on_library_model_diff(&self, _diff: LibraryModelDiff) {}
+
+    fn on_node_model_diff(&self, diff: NodeModelDiff) {
+        self.node_model_diffs.lock().unwrap().push(diff);
+    }
+
+    fn on_transfer_session_completed(&self, event: TransferSessionCompletedEvent) {
+        self.sessions_completed.lock().unwrap().push(event);
+    }
+
+    fn on_transfer_job_failed(&self, _event: TransferJobFailedEvent) {}
+
+    fn on_connection_lost(&self, _event: ConnectionLostEvent) {}
+
+    fn on_database_recovered(&self, _event: DatabaseRecoveredEvent) {}
+
+    fn on_shutdown_progress(&self, event: ShutdownProgressEvent) {
+        self.shutdown_progress.lock().unwrap().push(event);
+    }
+
+    fn on_background_progress(&self, event: BackgroundProgressEvent) {
+        self.background_progress.lock().unwrap().push(event);
+    }
 }
 
 #[derive(Clone)]
@@ -75,6 +118,11 @@ pub struct TestCore {
 
 impl TestCore {
     pub async fn start(label: &str) -> Self {
+        Self::start_with_model_diffs(label, false).await
+    }
+
+    /// Start a core that sends model diffs instead of snapshots if `model_diffs` is set.
+    pub async fn start_with_model_diffs(label: &str, model_diffs: bool) -> Self {
         let _ = tracing_subscriber::fmt()
             .with_env_filter(
                 tracing_subscriber::EnvFilter::try_from_default_env()
@@ -82,7 +130,7 @@ impl TestCore {
             )
             .try_init();
 
-        let event_handler = Arc::new(TestEventHandler);
+        let event_handler = Arc::new(TestEventHandler::default());
 
         let test_dir = testdir::testdir!();
         let instance_dir = test_dir.join(label);
@@ -99,6 +147,11 @@ impl TestCore {
             init_logging: false,
             in_memory: false,
             project_dirs: Some(project_dirs),
+            relay_config: None,
+            lan_only: false,
+            bind_addrs: None,
+            model_diffs,
+            profile: None,
         };
 
         #[cfg(feature = "test-hooks")]
@@ -403,6 +456,49 @@ impl TestCore {
         .await;
     }
 
+    /// Wait until a node model diff that satisfies the given condition was sent
+    pub async fn wait_for_node_model_diff(
+        &self,
+        msg: &str,
+        condition: impl Fn(&NodeModelDiff) -> bool,
+    ) {
+        let full_msg = format!("{} sent node model diff where {}", self.label, msg);
+        wait_until(
+            &full_msg,
+            || {
+                self.event_handler
+                    .node_model_diffs
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .any(&condition)
+            },
+            || {
+                warn!(
+                    "wait_for_node_model_diff: {full_msg}: failed, diffs: {:?}",
+                    self.event_handler.node_model_diffs.lock().unwrap(),
+                );
+            },
+        )
+        .await;
+    }
+
+    /// Wait until the given number of transfer sessions have completed
+    pub async fn wait_for_sessions_completed(&self, count: usize) {
+        let full_msg = format!("{} has {} completed sessions", self.label, count);
+        wait_until(
+            &full_msg,
+            || self.event_handler.sessions_completed.lock().unwrap().len() == count,
+            || {
+                warn!(
+                    "wait_for_sessions_completed: {full_msg}: failed, sessions: {:?}",
+                    self.event_handler.sessions_completed.lock().unwrap(),
+                );
+            },
+        )
+        .await;
+    }
+
     pub fn endpoint_id(&self) -> EndpointId {
         let model = self.core.get_node_model().expect("should get node model");
         let bytes = hex::decode(&model.endpoint_id).expect("should decode endpoint id hex");
@@ -418,6 +514,15 @@ impl TestCore {
             .unwrap_or_else(|| panic!("client_model: client for {} missing?", other.label()))
             .clone()
     }
+
+    pub fn server_model(&self, other: impl TestEndpointIdExt) -> ServerModel {
+        let model = self.core.get_node_model().expect("should get node model");
+        model
+            .servers
+            .get(&other.endpoint_id_str())
+            .unwrap_or_else(|| panic!("server_model: server for {} missing?", other.label()))
+            .clone()
+    }
 }
 
 /// Helper trait to pass a TestCore or EndpointId and get a label and EndpointId
@@ -6,7 +6,12 @@ mod common;
 mod connect {
     use crate::common::{TestCore, TestEndpointIdExt};
     use musicopy::{
-        device_name::device_name, library::transcode::TranscodeFormat, node::ClientStateModel,
+        ShutdownPhase,
+        deep_link::DeepLinkModel,
+        device_name::device_name,
+        error::{ConnectionError, CoreErrorKind},
+        library::transcode::TranscodeFormat,
+        node::{ClientStateModel, NodeModel, VersionedNodeModel},
     };
     use std::time::Duration;
 
@@ -38,6 +43,219 @@ mod connect {
         core_2.wait_for_server_accepted(&core_1).await;
     }
 
+    /// Applying the diffs sent after a versioned snapshot keeps the model up to date.
+    #[tokio::test]
+    async fn model_diffs() {
+        let core_1 = TestCore::start_with_model_diffs("core 1", true).await;
+        let core_2 = TestCore::start("core 2").await;
+
+        let VersionedNodeModel { version, mut model } = core_1
+            .core
+            .get_node_model_versioned()
+            .expect("should get node model");
+
+        // core 1: connect to core 2, and core 2: accept connection
+        core_1.discover(&core_2).await;
+        core_1
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_2.wait_for_server_pending(&core_1).await;
+        core_2
+            .core
+            .accept_connection(&core_1.endpoint_id_str())
+            .expect("should accept");
+        core_1.wait_for_client_accepted(&core_2).await;
+
+        // apply the diffs after the snapshot in order
+        let mut last_version = version;
+        for diff in core_1.event_handler.node_model_diffs.lock().unwrap().iter() {
+            if diff.version <= version {
+                continue;
+            }
+            assert_eq!(diff.version, last_version + 1, "diffs should be sequential");
+            last_version = diff.version;
+            model.apply_patch(diff.patch.clone());
+        }
+
+        let client = model
+            .clients
+            .get(&core_2.endpoint_id_str())
+            .expect("should have client from diffs");
+        assert!(matches!(client.state, ClientStateModel::Accepted));
+        assert_eq!(model.endpoint_id, core_1.endpoint_id_str());
+    }
+
+    /// Both ends of a pending connection show the same verification phrase.
+    #[tokio::test]
+    async fn verification_phrase() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+
+        // core 1: connect to core 2
+        core_1.discover(&core_2).await;
+        core_1
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+
+        // should be pending
+        core_1.wait_for_client_pending(&core_2).await;
+        core_2.wait_for_server_pending(&core_1).await;
+
+        // phrases should match
+        let client_phrase = core_1.client_model(&core_2).verification_phrase;
+        let server_phrase = core_2.server_model(&core_1).verification_phrase;
+        assert_eq!(client_phrase.split(' ').count(), 4);
+        assert_eq!(client_phrase, server_phrase);
+    }
+
+    /// Both ends of a connection report the selected path and its latency.
+    #[tokio::test]
+    async fn connection_info() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+
+        // core 1: connect to core 2
+        core_1.discover(&core_2).await;
+        core_1
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+
+        // should use a direct path, since the addresses were exchanged
+        core_1
+            .wait_for_client_condition("direct path", &core_2, |client| {
+                client.connection_type == "direct"
+                    && !client.remote_addr.is_empty()
+                    && client.latency_ms.is_some()
+            })
+            .await;
+        core_2
+            .wait_for_server_condition("direct path", &core_1, |server| {
+                server.connection_type == "direct"
+                    && !server.remote_addr.is_empty()
+                    && server.latency_ms.is_some()
+            })
+            .await;
+    }
+
+    /// The node model reports the local addresses the endpoint is bound to.
+    #[tokio::test]
+    async fn bound_sockets() {
+        let core_1 = TestCore::start("core 1").await;
+
+        let model = core_1.core.get_node_model().expect("should get node model");
+        assert!(
+            !model.bound_sockets.is_empty(),
+            "endpoint should be bound to at least one socket"
+        );
+        for addr in &model.bound_sockets {
+            addr.parse::<std::net::SocketAddr>()
+                .expect("bound socket should be a socket address");
+        }
+    }
+
+    /// A pairing ticket with a one-time token connects without being accepted, but only once.
+    #[tokio::test]
+    async fn pairing_ticket() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+
+        let ticket = core_2
+            .core
+            .create_pairing_ticket(true)
+            .expect("should create ticket");
+
+        // core 1: connect to core 2 with the ticket
+        core_1.discover(&core_2).await;
+        core_1
+            .core
+            .connect_with_ticket(Some(TranscodeFormat::Opus128), &ticket)
+            .await
+            .expect("should connect");
+
+        // should be accepted without core 2 accepting
+        core_1.wait_for_client_accepted(&core_2).await;
+        core_2.wait_for_server_accepted(&core_1).await;
+
+        // core 1: close client
+        core_1
+            .core
+            .close_client(&core_2.endpoint_id_str())
+            .expect("should close client");
+        core_1.wait_for_client_closed(&core_2).await;
+        core_2.wait_for_server_closed(&core_1).await;
+
+        // the token is used up, so connecting again should be pending
+        core_1
+            .core
+            .connect_with_ticket(Some(TranscodeFormat::Opus128), &ticket)
+            .await
+            .expect("should connect");
+        core_1.wait_for_client_pending(&core_2).await;
+        core_2.wait_for_server_pending(&core_1).await;
+    }
+
+    /// A pairing link opened in the app connects like its ticket, and share links are left for a
+    /// browser.
+    #[tokio::test]
+    async fn pairing_link() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+
+        let link = core_2
+            .core
+            .create_pairing_link(true)
+            .expect("should create link");
+        assert!(link.starts_with("musicopy://connect/"));
+
+        // core 1: open the link
+        core_1.discover(&core_2).await;
+        let action = core_1
+            .core
+            .handle_url(link, Some(TranscodeFormat::Opus128))
+            .await
+            .expect("should handle link");
+        assert_eq!(
+            action,
+            DeepLinkModel::Connecting {
+                endpoint_id: core_2.endpoint_id_str()
+            }
+        );
+
+        // should be accepted without core 2 accepting
+        core_1.wait_for_client_accepted(&core_2).await;
+        core_2.wait_for_server_accepted(&core_1).await;
+
+        let action = core_1
+            .core
+            .handle_url("http://192.168.1.2:8080/s/abc123".into(), None)
+            .await
+            .expect("should handle share link");
+        assert_eq!(
+            action,
+            DeepLinkModel::ShareLink {
+                url: "http://192.168.1.2:8080/s/abc123".into()
+            }
+        );
+
+        let err = core_1
+            .core
+            .handle_url("musicopy://unknown".into(), None)
+            .await
+            .expect_err("should reject unknown link");
+        assert_eq!(
+            err.kind(),
+            CoreErrorKind::Connection {
+                error: ConnectionError::InvalidLink
+            }
+        );
+    }
+
     #[tokio::test]
     async fn deny() {
         let core_1 = TestCore::start("core 1").await;
@@ -70,6 +288,87 @@ mod connect {
         core_2.wait_for_server_closed(&core_1).await;
     }
 
+    /// Pending connections that aren't accepted in time are closed and removed from the model.
+    #[tokio::test]
+    async fn pending_timeout() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+
+        // core 2: expire pending connections quickly
+        core_2
+            .core
+            .set_pending_timeout(1)
+            .expect("should set pending timeout");
+
+        // core 1: connect to core 2
+        core_1.discover(&core_2).await;
+        core_1
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+
+        // should be pending
+        core_1.wait_for_client_pending(&core_2).await;
+        core_2.wait_for_server_pending(&core_1).await;
+
+        // should be removed from core 2 without acting on it
+        let core_1_id = core_1.endpoint_id_str();
+        core_2
+            .wait_for_node_model_condition("server is removed", |model| {
+                !model.servers.contains_key(&core_1_id)
+            })
+            .await;
+
+        // core 1 should see the connection closed
+        core_1.wait_for_client_closed(&core_2).await;
+    }
+
+    /// Idle connections stay open while both sides ping, and time out once a side goes quiet.
+    #[tokio::test]
+    async fn keep_alive() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+        for core in [&core_1, &core_2] {
+            core.test_hooks
+                .set_keep_alive(Duration::from_millis(100), Duration::from_millis(500));
+        }
+
+        // core 1: connect to core 2, and core 2: accept connection
+        core_1.discover(&core_2).await;
+        core_1
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_2.wait_for_server_pending(&core_1).await;
+        core_2
+            .core
+            .accept_connection(&core_1.endpoint_id_str())
+            .expect("should accept");
+        core_1.wait_for_client_accepted(&core_2).await;
+        core_2.wait_for_server_accepted(&core_1).await;
+
+        // the idle connection is kept alive for several timeouts
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(matches!(
+            core_1.client_model(&core_2).state,
+            ClientStateModel::Accepted
+        ));
+
+        // core 2: stop pinging, so core 1 times out the connection
+        core_2.test_hooks.set_pings_paused(true);
+        core_1.wait_for_client_closed(&core_2).await;
+        let ClientStateModel::Closed { error } = core_1.client_model(&core_2).state else {
+            panic!("client should be closed");
+        };
+        assert!(
+            error.is_some_and(|error| error.contains("timed out")),
+            "client should be closed with a timeout error"
+        );
+        core_2.wait_for_server_closed(&core_1).await;
+    }
+
     #[tokio::test]
     async fn accept_then_client_close() {
         let core_1 = TestCore::start("core 1").await;
@@ -214,6 +513,45 @@ mod connect {
         core_1.wait_for_client_closed(&core_2).await;
     }
 
+    #[tokio::test]
+    async fn accept_then_graceful_shutdown() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+
+        // core 1: connect to core 2
+        core_1.discover(&core_2).await;
+        core_1
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+
+        // core 2: accept connection
+        core_2.wait_for_server_pending(&core_1).await;
+        core_2
+            .core
+            .accept_connection(&core_1.endpoint_id_str())
+            .expect("should accept");
+        core_1.wait_for_client_accepted(&core_2).await;
+
+        // core 2: shut down, nothing is being transferred so the connection closes right away
+        core_2
+            .core
+            .shutdown_with_deadline(10)
+            .await
+            .expect("should shutdown");
+
+        // should be closed
+        core_1.wait_for_client_closed(&core_2).await;
+
+        // should have reported the shutdown
+        let progress = core_2.event_handler.shutdown_progress.lock().unwrap();
+        let last = progress.last().expect("should report progress");
+        assert_eq!(last.phase, ShutdownPhase::Stopped);
+        assert_eq!(last.open_connections, 0);
+        assert!(!last.deadline_passed);
+    }
+
     #[tokio::test]
     async fn model_trust_untrust() {
         let core_1 = TestCore::start("core 1").await;
@@ -272,6 +610,64 @@ mod connect {
         core_2.wait_for_server_accepted(&core_1).await;
     }
 
+    /// When incoming connections are turned off, only trusted nodes can connect.
+    #[tokio::test]
+    async fn accept_incoming_disabled() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+        let core_3 = TestCore::start("core 3").await;
+
+        // core 2: trust core 3, stop accepting incoming connections
+        core_2
+            .core
+            .trust_node(&core_3.endpoint_id_str())
+            .expect("should trust");
+        core_2
+            .core
+            .set_accept_incoming(false)
+            .expect("should set accept incoming");
+        core_2
+            .wait_for_node_model_condition("accept_incoming is false", |model| {
+                !model.accept_incoming
+            })
+            .await;
+
+        assert!(
+            !core_2
+                .core
+                .get_settings_model()
+                .expect("should get settings")
+                .accept_incoming,
+            "accept_incoming should be stored in the settings"
+        );
+
+        // core 1: connect to core 2, which should close the connection before the handshake
+        core_1.discover(&core_2).await;
+        if core_1
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_2.endpoint_id_str())
+            .await
+            .is_ok()
+        {
+            core_1.wait_for_client_closed(&core_2).await;
+        }
+        let model = core_2.core.get_node_model().expect("should get node model");
+        assert!(
+            !model.servers.contains_key(&core_1.endpoint_id_str()),
+            "untrusted node should not be able to connect"
+        );
+
+        // core 3: connect to core 2, which should accept the trusted node
+        core_3.discover(&core_2).await;
+        core_3
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_3.wait_for_client_accepted(&core_2).await;
+        core_2.wait_for_server_accepted(&core_3).await;
+    }
+
     #[tokio::test]
     async fn untrust() {
         let core_2 = TestCore::start("core 2").await;
@@ -441,21 +837,215 @@ mod connect {
             })
             .await;
     }
-}
-
-mod library {
-    use crate::common::{LibraryFixture, TestCore};
-    use musicopy::library::transcode::TranscodeFormat;
 
+    /// Labels replace the names of trusted nodes, and are kept after untrusting.
     #[tokio::test]
-    async fn add_root_with_files() {
-        let core = TestCore::start("core").await;
-
-        let root_dir = LibraryFixture::Minimal.path();
+    async fn model_trusted_node_label() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+        let core_2_id = core_2.endpoint_id_str();
 
-        core.core
-            .add_library_root("foo".into(), root_dir.to_string_lossy().to_string())
-            .expect("should add library root");
+        // core 1: trust and label core 2
+        core_1.core.trust_node(&core_2_id).expect("should trust");
+        core_1
+            .core
+            .set_node_label(&core_2_id, Some(" Living room ".into()))
+            .expect("should set label");
+
+        let has_label = |model: &NodeModel| {
+            model.trusted_nodes.iter().any(|trusted_node| {
+                trusted_node.endpoint_id == core_2_id
+                    && trusted_node.name == "Living room"
+                    && trusted_node.label.as_deref() == Some("Living room")
+            })
+        };
+        core_1
+            .wait_for_node_model_condition("trusted node has label", has_label)
+            .await;
+
+        // core 1: untrust and trust again
+        core_1
+            .core
+            .untrust_node(&core_2_id)
+            .expect("should untrust");
+        core_1
+            .wait_for_node_model_condition("trusted nodes is empty", |model| {
+                model.trusted_nodes.is_empty()
+            })
+            .await;
+        core_1.core.trust_node(&core_2_id).expect("should trust");
+        core_1
+            .wait_for_node_model_condition("trusted node still has label", has_label)
+            .await;
+
+        // core 1: remove label
+        core_1
+            .core
+            .set_node_label(&core_2_id, Some(String::new()))
+            .expect("should remove label");
+        core_1
+            .wait_for_node_model_condition("trusted node has no label", |model| {
+                model.trusted_nodes.iter().any(|trusted_node| {
+                    trusted_node.endpoint_id == core_2_id
+                        && trusted_node.name == "Unknown"
+                        && trusted_node.label.is_none()
+                })
+            })
+            .await;
+    }
+
+    /// Members of a sync group are trusted until they're untrusted, even though they're still in
+    /// the group.
+    #[tokio::test]
+    async fn sync_group_trust() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+        let core_2_id = core_2.endpoint_id_str();
+
+        // core 1: create a group with core 2
+        let group_id = core_1
+            .core
+            .create_sync_group("Home".into())
+            .expect("should create group");
+        core_1
+            .core
+            .add_sync_group_member(&group_id, &core_2_id)
+            .expect("should add member");
+        core_1
+            .wait_for_node_model_condition("group contains core 2", |model| {
+                model
+                    .sync_groups
+                    .iter()
+                    .any(|group| group.group_id == group_id && group.members.contains(&core_2_id))
+            })
+            .await;
+
+        // core 2: connect to core 1, which should accept the group member without asking
+        core_2.discover(&core_1).await;
+        core_2
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_1.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_2.wait_for_client_accepted(&core_1).await;
+        core_1.wait_for_server_accepted(&core_2).await;
+
+        // core 1: untrust core 2 and disconnect
+        core_1
+            .core
+            .untrust_node(&core_2_id)
+            .expect("should untrust");
+        core_1
+            .core
+            .close_server(&core_2_id)
+            .expect("should disconnect");
+        core_2.wait_for_client_closed(&core_1).await;
+
+        // core 2: connect again, which should be pending even though it's still a member
+        core_2
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_1.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_2.wait_for_client_pending(&core_1).await;
+        core_1.wait_for_server_pending(&core_2).await;
+        let model = core_1.core.get_node_model().expect("should get node model");
+        assert!(
+            model
+                .sync_groups
+                .iter()
+                .any(|group| group.group_id == group_id && group.members.contains(&core_2_id)),
+            "core 2 should still be a member"
+        );
+    }
+
+    /// A node added to a sync group by a node it trusts is only invited, and doesn't trust the
+    /// group's members until it accepts the invitation.
+    #[tokio::test]
+    async fn sync_group_invitation() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+        let core_2_id = core_2.endpoint_id_str();
+
+        // core 2: trust core 1
+        core_2
+            .core
+            .trust_node(&core_1.endpoint_id_str())
+            .expect("should trust");
+
+        // core 1: create a group with core 2
+        let group_id = core_1
+            .core
+            .create_sync_group("Home".into())
+            .expect("should create group");
+        core_1
+            .core
+            .add_sync_group_member(&group_id, &core_2_id)
+            .expect("should add member");
+
+        // core 2: connect to core 1, which sends the group's records
+        core_2.discover(&core_1).await;
+        core_2
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_1.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_2.wait_for_client_accepted(&core_1).await;
+
+        // core 2: the group is pending until it's accepted
+        core_2
+            .wait_for_node_model_condition("core 2 is invited to the group", |model| {
+                model.sync_group_invitations.iter().any(|invitation| {
+                    invitation.group_id == group_id
+                        && invitation.name == "Home"
+                        && invitation.inviter == core_1.endpoint_id_str()
+                        && invitation.members.contains(&core_2_id)
+                })
+            })
+            .await;
+        let model = core_2.core.get_node_model().expect("should get node model");
+        assert!(
+            model.sync_groups.is_empty(),
+            "core 2 shouldn't join without accepting"
+        );
+
+        core_2
+            .core
+            .accept_sync_group_invitation(&group_id)
+            .expect("should accept invitation");
+        core_2
+            .wait_for_node_model_condition("core 2 joined the group", |model| {
+                model.sync_group_invitations.is_empty()
+                    && model.sync_groups.iter().any(|group| {
+                        group.group_id == group_id && group.members.contains(&core_2_id)
+                    })
+            })
+            .await;
+
+        // accepted invitations are gone
+        assert!(core_2.core.accept_sync_group_invitation(&group_id).is_err());
+    }
+}
+
+mod library {
+    use crate::common::{LibraryFixture, TestCore};
+    use musicopy::{
+        ProjectDirsOptions,
+        error::{CoreErrorKind, LibraryError, ProfileError},
+        library::transcode::TranscodeFormat,
+        profile::list_profiles,
+        settings::{SettingsModel, TranscodePolicy},
+    };
+
+    #[tokio::test]
+    async fn add_root_with_files() {
+        let core = TestCore::start("core").await;
+
+        let root_dir = LibraryFixture::Minimal.path();
+
+        core.core
+            .add_library_root("foo".into(), root_dir.to_string_lossy().to_string())
+            .expect("should add library root");
 
         core.wait_for_library_model_condition("model has root", |model| {
             model.local_roots.len() == 1
@@ -468,6 +1058,25 @@ mod library {
         .await;
     }
 
+    #[tokio::test]
+    async fn add_root_with_missing_path() {
+        let core = TestCore::start("core").await;
+
+        let path = core.instance_dir.join("missing");
+        let error = core
+            .core
+            .add_library_root("foo".into(), path.to_string_lossy().to_string())
+            .expect_err("should fail to add library root");
+        assert_eq!(
+            error.kind(),
+            CoreErrorKind::Library {
+                error: LibraryError::RootPathNotFound {
+                    path: path.to_string_lossy().to_string()
+                }
+            }
+        );
+    }
+
     #[tokio::test]
     async fn add_root_without_files() {
         let core = TestCore::start("core").await;
@@ -507,6 +1116,154 @@ mod library {
         .await;
     }
 
+    #[tokio::test]
+    async fn export_metrics() {
+        let core = TestCore::start("core").await;
+
+        // add library root
+        core.core
+            .add_library_root(
+                "foo".into(),
+                LibraryFixture::Minimal.path().to_string_lossy().to_string(),
+            )
+            .expect("should add library root");
+        core.wait_for_library_model_condition("root has 1 file", |model| {
+            model
+                .local_roots
+                .first()
+                .is_some_and(|root| root.num_files == 1)
+        })
+        .await;
+
+        let metrics = core.core.export_metrics().expect("should export metrics");
+        assert!(metrics.contains("musicopy_library_files{root=\"foo\"} 1\n"));
+        assert!(metrics.contains("musicopy_transcodes{state=\"queued\"} "));
+        assert!(
+            metrics.contains("musicopy_connections{direction=\"incoming\",state=\"accepted\"} 0\n")
+        );
+        assert!(metrics.ends_with("# EOF\n"));
+    }
+
+    /// Profiles have their own libraries and identities, which are kept when switching away.
+    #[tokio::test]
+    async fn switch_profile() {
+        let core = TestCore::start("core").await;
+        let default_endpoint_id = core.core.get_node_model().unwrap().endpoint_id;
+
+        let error = core
+            .core
+            .switch_profile(Some("../escape".into()))
+            .await
+            .expect_err("should reject invalid name");
+        assert_eq!(
+            error.kind(),
+            CoreErrorKind::Profile {
+                error: ProfileError::InvalidName {
+                    name: "../escape".into()
+                }
+            }
+        );
+
+        // family profile: add a root
+        let family = TestCore {
+            core: core
+                .core
+                .switch_profile(Some("Family library".into()))
+                .await
+                .expect("should switch profile"),
+            ..core.clone()
+        };
+        assert_eq!(family.core.get_profile().as_deref(), Some("Family library"));
+        let family_endpoint_id = family.core.get_node_model().unwrap().endpoint_id;
+        assert_ne!(family_endpoint_id, default_endpoint_id);
+
+        family
+            .core
+            .add_library_root(
+                "foo".into(),
+                LibraryFixture::Minimal.path().to_string_lossy().to_string(),
+            )
+            .expect("should add library root");
+        family
+            .wait_for_library_model_condition("model has root", |model| {
+                model.local_roots.len() == 1
+            })
+            .await;
+
+        // default profile: the root isn't there
+        let default = TestCore {
+            core: family
+                .core
+                .switch_profile(None)
+                .await
+                .expect("should switch profile"),
+            ..core.clone()
+        };
+        assert_eq!(default.core.get_profile(), None);
+        assert_eq!(
+            default.core.get_node_model().unwrap().endpoint_id,
+            default_endpoint_id
+        );
+        assert!(
+            default
+                .core
+                .get_library_model()
+                .unwrap()
+                .local_roots
+                .is_empty()
+        );
+
+        let project_dirs = ProjectDirsOptions {
+            data_dir: core.instance_dir.join("data").to_string_lossy().to_string(),
+            cache_dir: core.cache_dir.to_string_lossy().to_string(),
+        };
+        assert_eq!(
+            list_profiles(Some(project_dirs)).expect("should list profiles"),
+            vec!["Family library".to_string()]
+        );
+
+        // a profile that fails to start: the default profile is started again
+        let broken_dir = core
+            .instance_dir
+            .join("data")
+            .join("profiles")
+            .join("Broken");
+        std::fs::create_dir_all(broken_dir.parent().unwrap()).unwrap();
+        std::fs::write(&broken_dir, "not a directory").unwrap();
+        let default = TestCore {
+            core: default
+                .core
+                .switch_profile(Some("Broken".into()))
+                .await
+                .expect("should restart the previous profile"),
+            ..core.clone()
+        };
+        assert_eq!(default.core.get_profile(), None);
+        assert_eq!(
+            default.core.get_node_model().unwrap().endpoint_id,
+            default_endpoint_id
+        );
+
+        // family profile again: the root is still there
+        let family = TestCore {
+            core: default
+                .core
+                .switch_profile(Some("Family library".into()))
+                .await
+                .expect("should switch profile"),
+            ..core.clone()
+        };
+        assert_eq!(
+            family.core.get_node_model().unwrap().endpoint_id,
+            family_endpoint_id
+        );
+        family
+            .wait_for_library_model_condition("model has root", |model| {
+                model.local_roots.len() == 1
+            })
+            .await;
+    }
+
     #[tokio::test]
     async fn remove_root() {
         let core = TestCore::start("core").await;
@@ -585,6 +1342,68 @@ mod library {
         .await;
     }
 
+    /// Tracks are built from the tags read after a scan, and can be searched and browsed.
+    #[tokio::test]
+    async fn search_and_browse() {
+        let core = TestCore::start("core").await;
+
+        let root_dir = LibraryFixture::Multiple.path();
+
+        core.core
+            .add_library_root("foo".into(), root_dir.to_string_lossy().to_string())
+            .expect("should add library root");
+
+        // tags are read before the scan finishes, so the tracks have them once it's done
+        core.wait_for_library_model_condition("scan finished with 2 files", |model| {
+            !model.is_scanning
+                && model
+                    .local_roots
+                    .first()
+                    .is_some_and(|root| root.num_files == 2)
+        })
+        .await;
+
+        let tracks = core
+            .core
+            .search_library("natu")
+            .expect("should search library");
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].root, "foo");
+        assert_eq!(tracks[0].path, "evolution.mp3");
+        assert_eq!(tracks[0].title.as_deref(), Some("evolution"));
+        assert_eq!(tracks[0].artist.as_deref(), Some("8sumint"));
+
+        // queries without words match nothing, and FTS syntax is ignored
+        let tracks = core
+            .core
+            .search_library("% \"")
+            .expect("should search library");
+        assert!(tracks.is_empty());
+
+        let tracks = core
+            .core
+            .search_library("8SUMINT")
+            .expect("should search library");
+        assert_eq!(tracks.len(), 2);
+
+        // words match prefixes, and every word has to match
+        let tracks = core
+            .core
+            .search_library("8sum evol")
+            .expect("should search library");
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].path, "evolution.mp3");
+
+        // one file has an album tag and the other doesn't
+        let albums = core.core.browse_library().expect("should browse library");
+        assert_eq!(albums.len(), 2);
+        assert_eq!(albums[0].artist.as_deref(), Some("8sumint"));
+        assert_eq!(albums[0].album, None);
+        assert_eq!(albums[0].track_count, 1);
+        assert_eq!(albums[1].album.as_deref(), Some("natu.moe"));
+        assert_eq!(albums[1].track_count, 1);
+    }
+
     #[tokio::test]
     async fn prioritize_transcodes() {
         let core = TestCore::start("core").await;
@@ -649,14 +1468,128 @@ mod library {
             1
         );
     }
+
+    #[tokio::test]
+    async fn transcodes_in_multiple_formats() {
+        let core = TestCore::start("core").await;
+
+        let fixture_path = LibraryFixture::Minimal.path();
+        let root_dir = fixture_path;
+        let file_path = root_dir.join("test.mp3");
+
+        let transcodes_dir = core.cache_dir.join("transcodes");
+
+        // add library root
+        core.core
+            .add_library_root("foo".into(), root_dir.to_string_lossy().to_string())
+            .expect("should add library root");
+
+        // wait for file
+        core.wait_for_library_model_condition("root has 1 file", |model| {
+            model
+                .local_roots
+                .first()
+                .is_some_and(|root| root.num_files == 1)
+        })
+        .await;
+
+        // prioritize transcodes of the same file in two formats
+        for format in [TranscodeFormat::Opus96, TranscodeFormat::Opus192] {
+            core.core
+                .request_transcodes(format, vec![file_path.to_string_lossy().to_string()])
+                .expect("should prioritize transcodes");
+        }
+
+        // both transcodes should coexist
+        core.wait_for_library_model_condition("2 ready transcodes", |model| {
+            model.transcode_count_ready.get() == 2
+        })
+        .await;
+        assert_eq!(
+            transcodes_dir
+                .read_dir()
+                .expect("should read transcodes dir")
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn transcode_ahead_of_time() {
+        let core = TestCore::start("core").await;
+
+        let fixture_path = LibraryFixture::Minimal.path();
+
+        // add library root
+        core.core
+            .add_library_root("foo".into(), fixture_path.to_string_lossy().to_string())
+            .expect("should add library root");
+
+        // wait for file
+        core.wait_for_library_model_condition("root has 1 file", |model| {
+            model
+                .local_roots
+                .first()
+                .is_some_and(|root| root.num_files == 1)
+        })
+        .await;
+
+        // transcoding ahead of time needs a format
+        let settings = core.core.get_settings_model().expect("should get settings");
+        core.core
+            .update_settings(SettingsModel {
+                transcode_policy: TranscodePolicy::AheadOfTime,
+                ..settings.clone()
+            })
+            .expect_err("should reject policy without format");
+        assert_eq!(
+            core.core.get_settings_model().expect("should get settings"),
+            settings
+        );
+
+        core.core
+            .update_settings(SettingsModel {
+                transcode_policy: TranscodePolicy::AheadOfTime,
+                transcode_format: Some(TranscodeFormat::Opus64),
+                transcode_workers: 0,
+                ..settings
+            })
+            .expect("should update settings");
+
+        // numbers are clamped
+        let settings = core.core.get_settings_model().expect("should get settings");
+        assert_eq!(settings.transcode_workers, 1);
+
+        // the existing file is transcoded without being requested
+        core.wait_for_library_model_condition("1 ready transcode", |model| {
+            model.transcode_count_ready.get() == 1
+        })
+        .await;
+    }
 }
 
 mod transfer {
     use crate::common::{LibraryFixture, TestCore, TestEndpointIdExt};
     use musicopy::{
+        ShutdownPhase,
+        downloads::{DownloadedFileSelectionModel, ReorganizeConflictReasonModel},
+        error::{ConnectionError, CoreErrorKind},
         library::transcode::TranscodeFormat,
-        node::{DownloadRequestModel, IndexItemDownloadStatusModel, TransferJobProgressModel},
+        node::{
+            CollisionOutcomeModel, CollisionPolicy, DownloadConflictActionModel,
+            DownloadConflictReasonModel, DownloadIssueKindModel, DownloadRequestModel,
+            DownloadSelectionModel, IndexItemDownloadStatusModel, IndexItemModel,
+            InsufficientSpaceModel, NodeModel, NodeModelPatch, NodeShareModel, ShareFilterModel,
+            TransferErrorReasonModel, TransferJobProgressModel, VersionedNodeModel,
+        },
+        operation::{OperationProgress, OperationProgressHandler},
+        playlist::PlaylistSettings,
+        schedule::{
+            DeviceState, MeteredPolicy, NetworkState, TransferHoldReason, TransferSchedule,
+        },
+        settings::SettingsModel,
     };
+    use std::sync::{Arc, Mutex};
 
     /// Prepares two TestCores for transfer tests.
     ///
@@ -667,7 +1600,15 @@ mod transfer {
     /// - 2: accept connection
     /// - 1 and 2: wait for accepted state
     async fn prepare(fixture: LibraryFixture) -> (TestCore, TestCore) {
-        let core_1 = TestCore::start("core 1").await;
+        prepare_with_model_diffs(fixture, false).await
+    }
+
+    /// Calls `prepare`, with core 1 sending model diffs if `model_diffs` is set.
+    async fn prepare_with_model_diffs(
+        fixture: LibraryFixture,
+        model_diffs: bool,
+    ) -> (TestCore, TestCore) {
+        let core_1 = TestCore::start_with_model_diffs("core 1", model_diffs).await;
         let core_2 = TestCore::start("core 2").await;
 
         // set up download directory
@@ -822,625 +1763,3960 @@ mod transfer {
         assert!(downloaded_file_path.exists());
     }
 
-    /// Test pausing downloads:
+    /// With model diffs, changes to the transfer jobs only send the jobs that changed, and
+    /// applying them keeps the jobs up to date.
+    #[tokio::test]
+    async fn model_diffs_transfer_jobs() {
+        let fixture = LibraryFixture::Multiple;
+        let (core_1, core_2) = prepare_with_model_diffs(fixture, true).await;
+        core_1
+            .wait_for_client_condition("index is complete", &core_2, |client| client.index_complete)
+            .await;
+
+        let VersionedNodeModel { version, mut model } = core_1
+            .core
+            .get_node_model_versioned()
+            .expect("should get node model");
+
+        // core 1: download all files
+        let download_items = model.clients[&core_2.endpoint_id_str()]
+            .index
+            .as_ref()
+            .expect("should have index")
+            .iter()
+            .map(|item| DownloadRequestModel {
+                endpoint_id: item.endpoint_id.clone(),
+                root: item.root.clone(),
+                path: item.path.clone(),
+            })
+            .collect::<Vec<_>>();
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+        core_1
+            .wait_for_client_condition("all jobs are finished", &core_2, |client| {
+                client.transfer_jobs.len() == fixture.num_items()
+                    && client.transfer_jobs.iter().all(|job| {
+                        matches!(job.progress, TransferJobProgressModel::Finished { .. })
+                    })
+            })
+            .await;
+
+        // apply the diffs after the snapshot in order
+        let mut last_version = version;
+        let mut job_patches = 0;
+        for diff in core_1.event_handler.node_model_diffs.lock().unwrap().iter() {
+            if diff.version <= version {
+                continue;
+            }
+            assert_eq!(diff.version, last_version + 1, "diffs should be sequential");
+            last_version = diff.version;
+            if let NodeModelPatch::ClientTransferJobsUpdated {
+                client,
+                changed_jobs,
+                removed_job_ids,
+            } = &diff.patch
+            {
+                assert!(client.transfer_jobs.is_empty(), "jobs should be left out");
+                assert!(
+                    !changed_jobs.is_empty() || !removed_job_ids.is_empty(),
+                    "patches should only be sent when jobs change"
+                );
+                job_patches += 1;
+            }
+            model.apply_patch(diff.patch.clone());
+        }
+        assert!(job_patches > 0, "should have sent transfer job patches");
+
+        let sorted_jobs = |model: &NodeModel| {
+            let mut jobs = model.clients[&core_2.endpoint_id_str()]
+                .transfer_jobs
+                .to_vec();
+            jobs.sort_by_key(|job| job.job_id);
+            jobs
+        };
+        let current = core_1.core.get_node_model().expect("should get node model");
+        assert_eq!(sorted_jobs(&model), sorted_jobs(&current));
+    }
+
+    /// Files found by a rescan on the server are added to the index of a connected client.
+    #[tokio::test]
+    async fn index_changes_after_rescan() {
+        let fixture = LibraryFixture::Minimal;
+        let (core_1, core_2, _) = prepare_with_index(fixture).await;
+
+        // core 2: add another root, which rescans the library
+        core_2
+            .core
+            .add_library_root(
+                "bar".into(),
+                LibraryFixture::Multiple
+                    .path()
+                    .to_string_lossy()
+                    .to_string(),
+            )
+            .expect("should add library root");
+
+        // core 1: index should have the new files without reconnecting
+        let expected_items = fixture.num_items() + LibraryFixture::Multiple.num_items();
+        core_1
+            .wait_for_client_condition("index has new items", &core_2, |client| {
+                client.index.as_ref().is_some_and(|index| {
+                    index.len() == expected_items && index.iter().any(|item| item.root == "bar")
+                })
+            })
+            .await;
+    }
+
+    /// Trusted clients can ask the server to rescan its library.
+    #[tokio::test]
+    async fn request_remote_rescan() {
+        let fixture = LibraryFixture::Minimal;
+        let (core_1, core_2, _) = prepare_with_index(fixture).await;
+
+        // core 2: trust core 1, and add an empty root
+        core_2
+            .core
+            .trust_node(&core_1.endpoint_id_str())
+            .expect("should trust");
+        let root_dir = core_2.instance_dir.join("bar");
+        std::fs::create_dir_all(&root_dir).expect("should create root dir");
+        core_2
+            .core
+            .add_library_root("bar".into(), root_dir.to_string_lossy().to_string())
+            .expect("should add library root");
+        core_2
+            .wait_for_library_model_condition("model has both roots", |model| {
+                model.local_roots.len() == 2 && !model.is_scanning
+            })
+            .await;
+
+        // core 2: add a file without rescanning
+        std::fs::copy(fixture.path().join("test.mp3"), root_dir.join("new.mp3"))
+            .expect("should copy file");
+
+        // core 1: ask core 2 to rescan
+        core_1
+            .core
+            .request_remote_rescan(&core_2.endpoint_id_str())
+            .expect("should request rescan");
+
+        // core 1: index should have the new file
+        core_1
+            .wait_for_client_condition("index has new file", &core_2, |client| {
+                client.index.as_ref().is_some_and(|index| {
+                    index
+                        .iter()
+                        .any(|item| item.root == "bar" && item.path == "new.mp3")
+                })
+            })
+            .await;
+    }
+
+    /// Both ends count the bytes of transferred files for each other.
+    #[tokio::test]
+    async fn peer_traffic() {
+        let fixture = LibraryFixture::Multiple;
+        let (core_1, core_2, download_items) = prepare_with_index(fixture).await;
+
+        // core 1: download all files
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        core_1
+            .wait_for_client_condition("all jobs are Finished", &core_2, |client| {
+                client.transfer_jobs.len() == fixture.num_items()
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+        let transferred_bytes = core_1.client_model(&core_2).session.transferred_bytes;
+        assert!(transferred_bytes > 0);
+
+        // core 1: should have received the files from core 2
+        let core_2_id = core_2.endpoint_id_str();
+        core_1
+            .wait_for_node_model_condition("received bytes from core 2", |model| {
+                model.peer_traffic.iter().any(|traffic| {
+                    traffic.endpoint_id == core_2_id
+                        && traffic.session_received_bytes == transferred_bytes
+                        && traffic.lifetime_received_bytes == transferred_bytes
+                        && traffic.lifetime_sent_bytes == 0
+                })
+            })
+            .await;
+
+        // core 2: should have sent the files to core 1
+        let core_1_id = core_1.endpoint_id_str();
+        core_2
+            .wait_for_node_model_condition("sent bytes to core 1", |model| {
+                model.peer_traffic.iter().any(|traffic| {
+                    traffic.endpoint_id == core_1_id
+                        && traffic.session_sent_bytes == transferred_bytes
+                        && traffic.lifetime_sent_bytes == transferred_bytes
+                        && traffic.lifetime_received_bytes == 0
+                })
+            })
+            .await;
+    }
+
+    /// The node model shows how much storage the downloads from each server use.
+    #[tokio::test]
+    async fn download_usage() {
+        let fixture = LibraryFixture::Multiple;
+        let (core_1, core_2, download_items) = prepare_with_index(fixture).await;
+        let core_2_id = core_2.endpoint_id_str();
+
+        core_1
+            .core
+            .set_downloads(&core_2_id, download_items)
+            .expect("should set downloads");
+        core_1
+            .wait_for_client_condition("all jobs are Finished", &core_2, |client| {
+                client.transfer_jobs.len() == fixture.num_items()
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+        let transferred_bytes = core_1.client_model(&core_2).session.transferred_bytes;
+
+        core_1
+            .wait_for_node_model_condition("download usage counts the files", |model| {
+                model.download_usage.iter().any(|usage| {
+                    usage.endpoint_id == core_2_id
+                        && usage.files == fixture.num_items() as u64
+                        && usage.bytes == transferred_bytes
+                })
+            })
+            .await;
+
+        // core 2: downloaded nothing
+        let model = core_2.core.get_node_model().expect("should get node model");
+        assert!(model.download_usage.is_empty());
+
+        core_1
+            .core
+            .delete_downloaded_files(&core_2_id, vec![DownloadedFileSelectionModel::All])
+            .await
+            .expect("should delete files");
+        core_1
+            .wait_for_node_model_condition("download usage is empty", |model| {
+                model.download_usage.is_empty()
+            })
+            .await;
+    }
+
+    /// Adds a root to core 2 with a file that's the minimal fixture followed by `extra_len` bytes,
+    /// and trusts core 1. Returns the file's contents.
+    async fn add_large_file(core_1: &TestCore, core_2: &TestCore, extra_len: u32) -> Vec<u8> {
+        let root_dir = core_2.instance_dir.join("large");
+        std::fs::create_dir_all(&root_dir).expect("should create root dir");
+        let mut original = std::fs::read(LibraryFixture::Minimal.path().join("test.mp3"))
+            .expect("should read fixture");
+        original.extend((0..extra_len).map(|i| (i % 251) as u8));
+        std::fs::write(root_dir.join("test.mp3"), &original).expect("should write file");
+        core_2
+            .core
+            .add_library_root("large".into(), root_dir.to_string_lossy().to_string())
+            .expect("should add library root");
+        core_2
+            .wait_for_library_model_condition("root has files", |model| {
+                model
+                    .local_roots
+                    .first()
+                    .is_some_and(|root| root.num_files == 1)
+            })
+            .await;
+        core_2
+            .core
+            .trust_node(&core_1.endpoint_id_str())
+            .expect("should trust");
+
+        original
+    }
+
+    /// Connects core 1 to core 2 for original files and returns the items of its index.
+    async fn connect_for_download(
+        core_1: &TestCore,
+        core_2: &TestCore,
+    ) -> Vec<DownloadRequestModel> {
+        core_1.discover(core_2).await;
+        core_1
+            .core
+            .connect_and_wait(None, &core_2.endpoint_id_str(), None)
+            .await
+            .expect("should connect");
+        core_1
+            .client_model(core_2)
+            .index
+            .expect("should have index")
+            .into_iter()
+            .map(|item| DownloadRequestModel {
+                endpoint_id: item.endpoint_id,
+                root: item.root,
+                path: item.path,
+            })
+            .collect()
+    }
+
+    /// Large files are downloaded in parallel streams, with each part written in place, and the
+    /// downloaded file matches the server's checksum.
+    #[tokio::test]
+    async fn transfer_in_parallel_streams() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+
+        std::fs::create_dir_all(&core_1.download_dir).expect("should create download dir");
+        core_1
+            .core
+            .set_download_directory(&core_1.download_dir.to_string_lossy())
+            .expect("should set download directory");
+        core_1
+            .core
+            .set_parallel_streams(4)
+            .expect("should set parallel streams");
+
+        // core 2: add a file large enough to be split into several parts
+        let original = add_large_file(&core_1, &core_2, 20 * 1024 * 1024).await;
+
+        // core 1: download the original file
+        let download_items = connect_for_download(&core_1, &core_2).await;
+        let result = core_1
+            .core
+            .download_and_wait(&core_2.endpoint_id_str(), download_items, None)
+            .await
+            .expect("should download");
+        assert_eq!(result.completed_files, 1);
+        assert_eq!(result.failed_files, 0);
+
+        let files = core_1
+            .core
+            .list_downloaded_files(&core_2.endpoint_id_str())
+            .expect("should list files");
+        let downloaded =
+            std::fs::read(core_1.download_dir.join(&files[0].local_path)).expect("should read");
+        assert!(downloaded == original, "downloaded file doesn't match");
+
+        // the checksum sent by the server matches too
+        let issues = core_1
+            .core
+            .verify_downloads(&core_2.endpoint_id_str(), false)
+            .await
+            .expect("should verify downloads");
+        assert!(issues.is_empty(), "unexpected issues: {issues:?}");
+    }
+
+    /// Files are streamed in chunks of the transfer buffer size, and a partial file that matches
+    /// the server's file is resumed instead of being sent again.
+    #[tokio::test]
+    async fn transfer_streams_and_resumes_file() {
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+
+        std::fs::create_dir_all(&core_1.download_dir).expect("should create download dir");
+        core_1
+            .core
+            .set_download_directory(&core_1.download_dir.to_string_lossy())
+            .expect("should set download directory");
+
+        // core 2: send files in the smallest chunks, so the file takes many of them
+        core_2
+            .core
+            .set_transfer_buffer_size(0)
+            .expect("should set transfer buffer size");
+
+        // core 2: add a file of a few hundred chunks
+        let original = add_large_file(&core_1, &core_2, 4 * 1024 * 1024).await;
+
+        // core 1: leave the start of the file from an interrupted download, after partial files
+        // that aren't from journaled downloads are cleaned up
+        core_1
+            .wait_for_node_model_condition("partial files were cleaned up", |model| {
+                model.partial_cleanup.is_some()
+            })
+            .await;
+        let partial_len = 1024 * 1024;
+        let root_dir_path = core_1
+            .download_dir
+            .join(format!("musicopy-{}-large", core_2.endpoint_id_str()));
+        std::fs::create_dir_all(&root_dir_path).expect("should create root dir");
+        std::fs::write(
+            root_dir_path.join(".test.mp3.part"),
+            &original[..partial_len],
+        )
+        .expect("should write partial file");
+
+        // core 1: download the original file
+        let download_items = connect_for_download(&core_1, &core_2).await;
+        let result = core_1
+            .core
+            .download_and_wait(&core_2.endpoint_id_str(), download_items, None)
+            .await
+            .expect("should download");
+        assert_eq!(result.completed_files, 1);
+        assert_eq!(result.failed_files, 0);
+
+        let downloaded = std::fs::read(root_dir_path.join("test.mp3")).expect("should read");
+        assert!(downloaded == original, "downloaded file doesn't match");
+
+        // only the rest of the file should have been sent
+        let expected_bytes = (original.len() - partial_len) as u64;
+        core_2
+            .wait_for_stats_condition("server sent the rest of the file", |stats| {
+                stats.server_bytes == expected_bytes
+            })
+            .await;
+    }
+
+    /// A graceful shutdown waits for an active download to finish before closing its connection.
+    #[tokio::test]
+    async fn graceful_shutdown_drains_active_download() {
+        const RATE: u64 = 256 * 1024;
+
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+
+        std::fs::create_dir_all(&core_1.download_dir).expect("should create download dir");
+        core_1
+            .core
+            .set_download_directory(&core_1.download_dir.to_string_lossy())
+            .expect("should set download directory");
+
+        // core 2: send a file that takes a few seconds at the limit
+        core_2
+            .core
+            .set_max_upload_rate_per_client(RATE)
+            .expect("should set max upload rate per client");
+        let original = add_large_file(&core_1, &core_2, 1024 * 1024).await;
+
+        // core 1: start downloading
+        let download_items = connect_for_download(&core_1, &core_2).await;
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+        core_1
+            .wait_for_client_condition("job is InProgress", &core_2, |client| {
+                matches!(
+                    client.transfer_jobs.first().map(|j| &j.progress),
+                    Some(TransferJobProgressModel::InProgress { .. })
+                )
+            })
+            .await;
+
+        // core 1: shut down with plenty of time for the download to finish
+        core_1
+            .core
+            .shutdown_with_deadline(60)
+            .await
+            .expect("should shutdown");
+
+        let downloaded = std::fs::read(
+            core_1
+                .download_dir
+                .join(format!("musicopy-{}-large", core_2.endpoint_id_str()))
+                .join("test.mp3"),
+        )
+        .expect("should read downloaded file");
+        assert!(downloaded == original, "downloaded file doesn't match");
+
+        let progress = core_1.event_handler.shutdown_progress.lock().unwrap();
+        assert!(
+            progress
+                .iter()
+                .any(|event| event.phase == ShutdownPhase::Draining && event.open_connections == 1),
+            "should report the connection while draining: {progress:?}"
+        );
+        let last = progress.last().expect("should report progress");
+        assert_eq!(last.phase, ShutdownPhase::Stopped);
+        assert_eq!(last.open_connections, 0);
+        assert!(!last.deadline_passed);
+    }
+
+    /// A graceful shutdown closes connections when the deadline passes, and the interrupted
+    /// download keeps its partial file.
+    #[tokio::test]
+    async fn graceful_shutdown_deadline_interrupts_download() {
+        const RATE: u64 = 64 * 1024;
+
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+
+        std::fs::create_dir_all(&core_1.download_dir).expect("should create download dir");
+        core_1
+            .core
+            .set_download_directory(&core_1.download_dir.to_string_lossy())
+            .expect("should set download directory");
+
+        // core 2: send a file that takes about a minute at the limit
+        core_2
+            .core
+            .set_max_upload_rate_per_client(RATE)
+            .expect("should set max upload rate per client");
+        add_large_file(&core_1, &core_2, 4 * 1024 * 1024).await;
+
+        // core 1: start downloading
+        let download_items = connect_for_download(&core_1, &core_2).await;
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+        core_1
+            .wait_for_client_condition("job is InProgress", &core_2, |client| {
+                matches!(
+                    client.transfer_jobs.first().map(|j| &j.progress),
+                    Some(TransferJobProgressModel::InProgress { .. })
+                )
+            })
+            .await;
+
+        // core 1: shut down with a deadline long before the download can finish
+        let start = std::time::Instant::now();
+        core_1
+            .core
+            .shutdown_with_deadline(1)
+            .await
+            .expect("should shutdown");
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= std::time::Duration::from_secs(1),
+            "should wait for the deadline, took {elapsed:?}"
+        );
+        assert!(
+            elapsed < std::time::Duration::from_secs(10),
+            "should stop at the deadline, took {elapsed:?}"
+        );
+
+        let root_dir_path = core_1
+            .download_dir
+            .join(format!("musicopy-{}-large", core_2.endpoint_id_str()));
+        assert!(
+            !root_dir_path.join("test.mp3").exists(),
+            "download should not have finished"
+        );
+        assert!(
+            root_dir_path.join(".test.mp3.part").exists(),
+            "interrupted download should keep its partial file"
+        );
+
+        let progress = core_1.event_handler.shutdown_progress.lock().unwrap();
+        let last = progress.last().expect("should report progress");
+        assert_eq!(last.phase, ShutdownPhase::Stopped);
+    }
+
+    /// Test that a stale partial file from an interrupted download that doesn't match the server's
+    /// file is replaced instead of being resumed.
+    #[tokio::test]
+    async fn transfer_with_mismatched_partial_file() {
+        let (core_1, core_2) = prepare(LibraryFixture::Minimal).await;
+
+        // core 1: should have index
+        core_1
+            .wait_for_client_condition("index is Some", &core_2, |client| client.index.is_some())
+            .await;
+
+        // write a partial file that doesn't match the transcoded file
+        let root_dir_path = core_1
+            .download_dir
+            .join(format!("musicopy-{}-foo", core_2.endpoint_id_str()));
+        std::fs::create_dir_all(&root_dir_path).expect("should create root dir");
+        let stale_content = b"not the start of an ogg file";
+        std::fs::write(root_dir_path.join(".test.ogg.part"), stale_content)
+            .expect("should write partial file");
+
+        // core 1: download file
+        core_1
+            .core
+            .set_downloads(
+                &core_2.endpoint_id_str(),
+                vec![DownloadRequestModel {
+                    endpoint_id: core_2.endpoint_id_str(),
+                    root: "foo".into(),
+                    path: "test.mp3".into(),
+                }],
+            )
+            .expect("should set downloads");
+
+        // wait for transfer to finish
+        core_1
+            .wait_for_client_condition("job is finished", &core_2, |client| {
+                matches!(
+                    client.transfer_jobs.first().map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Finished { .. })
+                )
+            })
+            .await;
+
+        // file should have been downloaded from the start
+        let downloaded_file =
+            std::fs::read(root_dir_path.join("test.ogg")).expect("should read downloaded file");
+        assert!(downloaded_file.starts_with(b"OggS"));
+        assert!(!root_dir_path.join(".test.ogg.part").exists());
+    }
+
+    /// Partial files in the download directory that aren't from journaled downloads are deleted
+    /// when the download directory is set at startup.
+    #[tokio::test]
+    async fn clean_orphaned_partial_files() {
+        let core = TestCore::start("core").await;
+
+        let album_dir = core.download_dir.join("album");
+        std::fs::create_dir_all(&album_dir).expect("should create dir");
+        std::fs::write(album_dir.join(".song.ogg.part"), [1; 64])
+            .expect("should write partial file");
+        std::fs::write(album_dir.join("song.ogg"), [1; 64]).expect("should write file");
+
+        core.core
+            .set_download_directory(&core.download_dir.to_string_lossy())
+            .expect("should set download directory");
+        core.wait_for_node_model_condition("partial files were cleaned up", |model| {
+            model
+                .partial_cleanup
+                .as_ref()
+                .is_some_and(|cleanup| cleanup.deleted_files == 1 && cleanup.reclaimed_bytes == 64)
+        })
+        .await;
+        assert!(!album_dir.join(".song.ogg.part").exists());
+        assert!(album_dir.join("song.ogg").exists());
+    }
+
+    /// With the Rename collision policy, a file that's already at the destination is kept and the
+    /// download gets a numbered name.
+    #[tokio::test]
+    async fn collision_policy_rename() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Minimal).await;
+
+        // write a file that wasn't downloaded by musicopy at the destination
+        let root_dir_path = core_1
+            .download_dir
+            .join(format!("musicopy-{}-foo", core_2.endpoint_id_str()));
+        std::fs::create_dir_all(&root_dir_path).expect("should create root dir");
+        let existing_content = b"my own recording";
+        std::fs::write(root_dir_path.join("test.ogg"), existing_content)
+            .expect("should write existing file");
+
+        core_1
+            .core
+            .set_collision_policy(CollisionPolicy::Rename)
+            .expect("should set collision policy");
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        // the job should finish and report the new name
+        core_1
+            .wait_for_client_condition("job is finished", &core_2, |client| {
+                client.transfer_jobs.first().is_some_and(|job| {
+                    matches!(job.progress, TransferJobProgressModel::Finished { .. })
+                        && matches!(
+                            &job.collision,
+                            Some(CollisionOutcomeModel::Renamed { local_path })
+                                if local_path.ends_with("test (1).ogg")
+                        )
+                })
+            })
+            .await;
+
+        // the existing file should be kept
+        let existing_file =
+            std::fs::read(root_dir_path.join("test.ogg")).expect("should read existing file");
+        assert_eq!(existing_file, existing_content);
+        let downloaded_file =
+            std::fs::read(root_dir_path.join("test (1).ogg")).expect("should read downloaded file");
+        assert!(downloaded_file.starts_with(b"OggS"));
+    }
+
+    /// With the OverwriteIfDifferent collision policy, an identical file that's already at the
+    /// destination is kept, and both ends finish the job without transferring it.
+    #[tokio::test]
+    async fn collision_policy_overwrite_if_different() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Minimal).await;
+
+        // core 1: download the file, to get the content that core 2 sends
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+        core_1
+            .wait_for_client_condition("job is finished", &core_2, |client| {
+                client.transfer_jobs.first().is_some_and(|job| {
+                    matches!(job.progress, TransferJobProgressModel::Finished { .. })
+                })
+            })
+            .await;
+        let root_dir_name = format!("musicopy-{}-foo", core_2.endpoint_id_str());
+        let content = std::fs::read(core_1.download_dir.join(&root_dir_name).join("test.ogg"))
+            .expect("should read downloaded file");
+
+        // core 3: put the same file at the destination without downloading it
+        let core_3 = TestCore::start("core 3").await;
+        let root_dir_path = core_3.download_dir.join(&root_dir_name);
+        std::fs::create_dir_all(&root_dir_path).expect("should create root dir");
+        std::fs::write(root_dir_path.join("test.ogg"), &content)
+            .expect("should write existing file");
+        core_3
+            .core
+            .set_download_directory(&core_3.download_dir.to_string_lossy())
+            .expect("should set download directory");
+        core_3
+            .core
+            .set_collision_policy(CollisionPolicy::OverwriteIfDifferent)
+            .expect("should set collision policy");
+
+        // core 3: connect to core 2
+        core_3.discover(&core_2).await;
+        core_3
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_2.wait_for_server_pending(&core_3).await;
+        core_2
+            .core
+            .accept_connection(&core_3.endpoint_id_str())
+            .expect("should accept");
+        core_3
+            .wait_for_client_condition("index has items", &core_2, |client| {
+                client.index_complete && client.index.as_ref().is_some_and(|idx| idx.len() == 1)
+            })
+            .await;
+
+        core_3
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        // the job should finish as unchanged on both ends
+        core_3
+            .wait_for_client_condition("job is unchanged", &core_2, |client| {
+                client.transfer_jobs.first().is_some_and(|job| {
+                    matches!(job.progress, TransferJobProgressModel::Finished { .. })
+                        && job.collision == Some(CollisionOutcomeModel::Unchanged)
+                })
+            })
+            .await;
+        core_2
+            .wait_for_server_condition("job is finished", &core_3, |server| {
+                server.transfer_jobs.first().is_some_and(|job| {
+                    matches!(job.progress, TransferJobProgressModel::Finished { .. })
+                })
+            })
+            .await;
+
+        let existing_file =
+            std::fs::read(root_dir_path.join("test.ogg")).expect("should read existing file");
+        assert_eq!(existing_file, content);
+    }
+
+    /// Downloads that collide with existing files are collected into the conflict report with
+    /// what was done about them.
+    #[tokio::test]
+    async fn conflict_report() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Minimal).await;
+
+        // write a file that wasn't downloaded by musicopy at the destination
+        let root_dir_path = core_1
+            .download_dir
+            .join(format!("musicopy-{}-foo", core_2.endpoint_id_str()));
+        std::fs::create_dir_all(&root_dir_path).expect("should create root dir");
+        std::fs::write(root_dir_path.join("test.ogg"), b"my own recording")
+            .expect("should write existing file");
+
+        core_1
+            .core
+            .set_collision_policy(CollisionPolicy::Skip)
+            .expect("should set collision policy");
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        // the skipped download should be in the conflict report
+        core_1
+            .wait_for_client_condition("conflict is reported", &core_2, |client| {
+                client.conflicts.len() == 1
+            })
+            .await;
+        let client = core_1.client_model(&core_2);
+        let conflict = &client.conflicts[0];
+        assert_eq!(conflict.job_id, client.transfer_jobs[0].job_id);
+        assert_eq!(conflict.file_path, "test.ogg");
+        assert_eq!(conflict.reason, DownloadConflictReasonModel::Collision);
+        assert_eq!(conflict.action, DownloadConflictActionModel::Skipped);
+    }
+
+    /// A prioritized download starts before downloads that became ready earlier.
+    #[tokio::test]
+    async fn prioritize_downloads() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request both items, and move the second one to the front
+        let prioritized = download_items
+            .iter()
+            .find(|item| item.path == "fbp.mp3")
+            .expect("should have item")
+            .clone();
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+        core_1
+            .core
+            .prioritize_downloads(&core_2.endpoint_id_str(), vec![prioritized])
+            .expect("should prioritize downloads");
+
+        // both jobs should reach Ready
+        core_1
+            .wait_for_client_condition("both jobs are Ready", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // allow one item to download
+        core_1.test_hooks.add_download_permits(1);
+
+        // the prioritized job should finish first
+        core_1
+            .wait_for_client_condition("prioritized job is Finished", &core_2, |client| {
+                client.transfer_jobs.iter().all(|j| {
+                    if j.file_path == "fbp.mp3" {
+                        matches!(j.progress, TransferJobProgressModel::Finished { .. })
+                    } else {
+                        matches!(j.progress, TransferJobProgressModel::Ready)
+                    }
+                })
+            })
+            .await;
+    }
+
+    /// Test pausing downloads:
+    /// - Request both items
+    /// - Both jobs should reach Ready
+    /// - Both index items should reach Waiting
+    /// - TestHooks: allow first item
+    /// - 1st job should reach Finished
+    /// - 1st index item should reach Downloaded
+    /// - 2nd job should still be Ready
+    /// - 2nd index item should still be Waiting
+    /// - Pause
+    /// - Paused should become true
+    /// - 1st job should still be Finished
+    /// - 1st index item should still be Downloaded
+    /// - 2nd job should still be Ready
+    /// - 2nd index item should still be Waiting
+    /// - Resume (using SetDownloads with the Paused item)
+    /// - Paused should become false
+    /// - 2nd job should reach Finished
+    /// - 2nd index item should reach Downloaded
+    #[tokio::test]
+    async fn pause() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request both items
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // both jobs should reach Ready
+        core_1
+            .wait_for_client_condition("both jobs are Ready", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+        // both index items should reach Waiting
+        core_1
+            .core
+            .refresh_client_index(&core_2.endpoint_id_str())
+            .expect("should refresh client index");
+        core_1
+            .wait_for_client_condition("both index items are Waiting", &core_2, |client| {
+                client.index.as_ref().unwrap().len() == 2
+                    && client.index.as_ref().unwrap().iter().all(|item| {
+                        matches!(
+                            item.download_status,
+                            Some(IndexItemDownloadStatusModel::Waiting)
+                        )
+                    })
+            })
+            .await;
+
+        // allow first item to download
+        core_1.test_hooks.add_download_permits(1);
+
+        // first job should reach Finished, second should still be Ready
+        core_1
+            .wait_for_client_condition(
+                "one job is Finished, other job is still Ready",
+                &core_2,
+                |client| {
+                    client.transfer_jobs.len() == 2
+                        && client.transfer_jobs.iter().any(|j| {
+                            matches!(j.progress, TransferJobProgressModel::Finished { .. })
+                        })
+                        && client
+                            .transfer_jobs
+                            .iter()
+                            .any(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+                },
+            )
+            .await;
+        let finished_job_path = core_1
+            .client_model(&core_2)
+            .transfer_jobs
+            .iter()
+            .find(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            .map(|j| j.file_path.clone())
+            .unwrap();
+        // finished index item should reach Downloaded, other should still be Waiting
+        core_1
+            .core
+            .refresh_client_index(&core_2.endpoint_id_str())
+            .expect("should refresh client index");
+        core_1
+            .wait_for_client_condition(
+                "finished index item is Downloaded, other index item is still Waiting",
+                &core_2,
+                |client| {
+                    client.index.as_ref().unwrap().len() == 2
+                        && matches!(
+                            client
+                                .index
+                                .as_ref()
+                                .unwrap()
+                                .iter()
+                                .find(|i| i.path == finished_job_path)
+                                .unwrap()
+                                .download_status,
+                            Some(IndexItemDownloadStatusModel::Downloaded)
+                        )
+                        && matches!(
+                            client
+                                .index
+                                .as_ref()
+                                .unwrap()
+                                .iter()
+                                .find(|i| i.path != finished_job_path)
+                                .unwrap()
+                                .download_status,
+                            Some(IndexItemDownloadStatusModel::Waiting)
+                        )
+                },
+            )
+            .await;
+
+        // pause downloads
+        core_1
+            .core
+            .pause_downloads(&core_2.endpoint_id_str())
+            .expect("should pause downloads");
+
+        // paused should become true
+        core_1
+            .wait_for_client_condition("paused is true", &core_2, |client| client.paused)
+            .await;
+
+        // finished job should still be Finished
+        core_1
+            .wait_for_client_condition("finished job is still Finished", &core_2, |client| {
+                matches!(
+                    client
+                        .transfer_jobs
+                        .iter()
+                        .find(|j| j.file_path == finished_job_path)
+                        .map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Finished { .. })
+                )
+            })
+            .await;
+        // finished index item should still be Downloaded
+        core_1
+            .core
+            .refresh_client_index(&core_2.endpoint_id_str())
+            .expect("should refresh client index");
+        core_1
+            .wait_for_client_condition(
+                "finished index item is still Downloaded",
+                &core_2,
+                |client| {
+                    client.index.as_ref().unwrap().len() == 2
+                        && matches!(
+                            client
+                                .index
+                                .as_ref()
+                                .unwrap()
+                                .iter()
+                                .find(|i| i.path == finished_job_path)
+                                .unwrap()
+                                .download_status,
+                            Some(IndexItemDownloadStatusModel::Downloaded)
+                        )
+                },
+            )
+            .await;
+
+        // other job should still be Ready
+        core_1
+            .wait_for_client_condition("other job is Ready", &core_2, |client| {
+                matches!(
+                    client
+                        .transfer_jobs
+                        .iter()
+                        .find(|j| j.file_path != finished_job_path)
+                        .map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Ready)
+                )
+            })
+            .await;
+        // other index item should still be Waiting
+        core_1
+            .core
+            .refresh_client_index(&core_2.endpoint_id_str())
+            .expect("should refresh client index");
+        core_1
+            .wait_for_client_condition("other index item is Waiting", &core_2, |client| {
+                client.index.as_ref().unwrap().len() == 2
+                    && matches!(
+                        client
+                            .index
+                            .as_ref()
+                            .unwrap()
+                            .iter()
+                            .find(|i| i.path != finished_job_path)
+                            .unwrap()
+                            .download_status,
+                        Some(IndexItemDownloadStatusModel::Waiting)
+                    )
+            })
+            .await;
+
+        // resume downloads using SetDownloads
+        let not_finished_download_item = download_items
+            .into_iter()
+            .find(|item| item.path != finished_job_path)
+            .unwrap();
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), vec![not_finished_download_item])
+            .expect("should set downloads");
+
+        // paused should become false
+        core_1
+            .wait_for_client_condition("paused is false", &core_2, |client| !client.paused)
+            .await;
+
+        // other job should be Ready
+        core_1
+            .wait_for_client_condition("other job is Ready", &core_2, |client| {
+                matches!(
+                    client
+                        .transfer_jobs
+                        .iter()
+                        .find(|j| j.file_path != finished_job_path)
+                        .map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Ready)
+                )
+            })
+            .await;
+        // other index item should be Waiting
+        core_1
+            .core
+            .refresh_client_index(&core_2.endpoint_id_str())
+            .expect("should refresh client index");
+        core_1
+            .wait_for_client_condition("other index item is Waiting", &core_2, |client| {
+                client.index.as_ref().unwrap().len() == 2
+                    && matches!(
+                        client
+                            .index
+                            .as_ref()
+                            .unwrap()
+                            .iter()
+                            .find(|i| i.path != finished_job_path)
+                            .unwrap()
+                            .download_status,
+                        Some(IndexItemDownloadStatusModel::Waiting)
+                    )
+            })
+            .await;
+
+        // allow second item to download
+        core_1.test_hooks.add_download_permits(1);
+
+        // other job should reach Finished
+        core_1
+            .wait_for_client_condition("other job is Finished", &core_2, |client| {
+                matches!(
+                    client
+                        .transfer_jobs
+                        .iter()
+                        .find(|j| j.file_path != finished_job_path)
+                        .map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Finished { .. })
+                )
+            })
+            .await;
+        // other index item should reach Downloaded
+        core_1
+            .core
+            .refresh_client_index(&core_2.endpoint_id_str())
+            .expect("should refresh client index");
+        core_1
+            .wait_for_client_condition("other index item is Downloaded", &core_2, |client| {
+                client.index.as_ref().unwrap().len() == 2
+                    && matches!(
+                        client
+                            .index
+                            .as_ref()
+                            .unwrap()
+                            .iter()
+                            .find(|i| i.path != finished_job_path)
+                            .unwrap()
+                            .download_status,
+                        Some(IndexItemDownloadStatusModel::Downloaded)
+                    )
+            })
+            .await;
+    }
+
+    /// Test pausing and resuming a single job:
+    /// - Request both items
+    /// - Both jobs should reach Ready
+    /// - Pause 1st job
+    /// - 1st job should reach Paused
+    /// - TestHooks: allow both items
+    /// - 2nd job should reach Finished, 1st job should still be Paused
+    /// - Resume 1st job
+    /// - TestHooks: allow 1st item again, since it was requeued
+    /// - 1st job should reach Finished
+    #[tokio::test]
+    async fn pause_and_resume_transfer() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request both items
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // both jobs should reach Ready
+        core_1
+            .wait_for_client_condition("both jobs are Ready", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // pause first job
+        let paused_job_id = core_1.client_model(&core_2).transfer_jobs[0].job_id;
+        core_1
+            .core
+            .pause_transfer(&core_2.endpoint_id_str(), paused_job_id)
+            .expect("should pause transfer");
+        core_1
+            .wait_for_client_condition("paused job is Paused", &core_2, |client| {
+                client.transfer_jobs.iter().any(|j| {
+                    j.job_id == paused_job_id
+                        && matches!(j.progress, TransferJobProgressModel::Paused { .. })
+                })
+            })
+            .await;
+
+        // allow both items, only the other job should download
+        core_1.test_hooks.add_download_permits(2);
+        core_1
+            .wait_for_client_condition(
+                "other job is Finished, paused job is still Paused",
+                &core_2,
+                |client| {
+                    client.transfer_jobs.iter().all(|j| {
+                        if j.job_id == paused_job_id {
+                            matches!(j.progress, TransferJobProgressModel::Paused { .. })
+                        } else {
+                            matches!(j.progress, TransferJobProgressModel::Finished { .. })
+                        }
+                    })
+                },
+            )
+            .await;
+
+        // resume paused job
+        core_1
+            .core
+            .resume_transfer(&core_2.endpoint_id_str(), paused_job_id)
+            .expect("should resume transfer");
+        core_1.test_hooks.add_download_permits(1);
+
+        // both jobs should be finished
+        core_1
+            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
+                client
+                    .transfer_jobs
+                    .iter()
+                    .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+    }
+
+    /// Test cancelling a single job before it starts:
+    /// - Request both items
+    /// - Both jobs should reach Ready
+    /// - Cancel 1st job
+    /// - 1st job should reach Failed
+    /// - TestHooks: allow both items
+    /// - 2nd job should reach Finished, 1st job should still be Failed
+    #[tokio::test]
+    async fn cancel_transfer() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request both items
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // both jobs should reach Ready
+        core_1
+            .wait_for_client_condition("both jobs are Ready", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // cancel first job
+        let cancelled_job_id = core_1.client_model(&core_2).transfer_jobs[0].job_id;
+        core_1
+            .core
+            .cancel_transfer(&core_2.endpoint_id_str(), cancelled_job_id)
+            .expect("should cancel transfer");
+        core_1
+            .wait_for_client_condition("cancelled job is Failed", &core_2, |client| {
+                client.transfer_jobs.iter().any(|j| {
+                    j.job_id == cancelled_job_id
+                        && matches!(j.progress, TransferJobProgressModel::Failed { .. })
+                })
+            })
+            .await;
+        let cancelled_job = core_1
+            .client_model(&core_2)
+            .transfer_jobs
+            .into_iter()
+            .find(|j| j.job_id == cancelled_job_id)
+            .expect("cancelled job should exist");
+        assert!(
+            matches!(
+                cancelled_job.progress,
+                TransferJobProgressModel::Failed {
+                    reason: TransferErrorReasonModel::Cancelled,
+                    retryable: false,
+                    ..
+                }
+            ),
+            "cancelled job should fail with a non-retryable Cancelled reason, got {:?}",
+            cancelled_job.progress
+        );
+
+        // allow both items, only the other job should download
+        core_1.test_hooks.add_download_permits(2);
+        core_1
+            .wait_for_client_condition(
+                "other job is Finished, cancelled job is still Failed",
+                &core_2,
+                |client| {
+                    client.transfer_jobs.iter().all(|j| {
+                        if j.job_id == cancelled_job_id {
+                            matches!(j.progress, TransferJobProgressModel::Failed { .. })
+                        } else {
+                            matches!(j.progress, TransferJobProgressModel::Finished { .. })
+                        }
+                    })
+                },
+            )
+            .await;
+    }
+
+    /// Test set downloads with the same items while waiting:
+    /// - Request both items
+    /// - Both jobs should be Ready
+    /// - Request both items again
+    /// - Both jobs should still be Ready (only 2 jobs)
+    #[tokio::test]
+    async fn set_same_downloads_while_waiting() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request both items
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // should have two Ready jobs
+        core_1
+            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // request both items again
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // should still have two Ready jobs
+        core_1
+            .wait_for_client_condition("still has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+    }
+
+    /// Test set downloads with the same items while paused:
+    /// - Request both items
+    /// - Both jobs should be Ready
+    /// - Pause downloads
+    /// - Paused should become true
+    /// - Both jobs should be Ready
+    /// - Request both items again
+    /// - Paused should become false
+    /// - Both jobs should still be Ready (only 2 jobs)
+    #[tokio::test]
+    async fn set_same_downloads_while_paused() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request both items
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // should have two Ready jobs
+        core_1
+            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // pause downloads
+        core_1
+            .core
+            .pause_downloads(&core_2.endpoint_id_str())
+            .expect("should pause downloads");
+
+        // paused should become true
+        core_1
+            .wait_for_client_condition("paused is true", &core_2, |client| client.paused)
+            .await;
+
+        // should have two Ready jobs
+        core_1
+            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // request both items again
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // paused should become false
+        core_1
+            .wait_for_client_condition("paused is false", &core_2, |client| !client.paused)
+            .await;
+
+        // should still have two Ready jobs
+        core_1
+            .wait_for_client_condition("still has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+    }
+
+    /// Test set downloads with the same items while finished:
+    /// - Request both items
+    /// - Both jobs should be Ready
+    /// - Allow both to download
+    /// - Both jobs should reach Finished
+    /// - Request both items again
+    /// - Both jobs should still be Finished (only 2 jobs)
+    #[tokio::test]
+    async fn set_same_downloads_while_finished() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request both items
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // should have two Ready jobs
+        core_1
+            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // allow both to download
+        core_1.test_hooks.add_download_permits(2);
+
+        // both jobs should reach Finished
+        core_1
+            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+
+        // request both items again
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // should still have two Finished jobs
+        core_1
+            .wait_for_client_condition("still has 2 Finished jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+    }
+
+    /// Test adding items while waiting using SetDownloads:
+    /// - Request first item
+    /// - Should be one Ready job
+    /// - Request second item
+    /// - Should be two Ready jobs
+    /// - Allow both to download
+    /// - Both jobs should reach Finished
+    #[tokio::test]
+    async fn add_items_while_waiting() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request first item
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
+            .expect("should set downloads");
+
+        // should have one Ready job
+        core_1
+            .wait_for_client_condition("has 1 Ready job", &core_2, |client| {
+                client.transfer_jobs.len() == 1
+                    && matches!(
+                        client.transfer_jobs.first().map(|j| &j.progress),
+                        Some(TransferJobProgressModel::Ready)
+                    )
+            })
+            .await;
+
+        // request second item
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // should have two Ready jobs
+        core_1
+            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // allow both to download
+        core_1.test_hooks.add_download_permits(2);
+
+        // both jobs should reach Finished
+        core_1
+            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+    }
+
+    /// Test adding items while paused using SetDownloads:
+    /// - Request first item
+    /// - Should be one Ready job
+    /// - Pause downloads
+    /// - Paused should become true
+    /// - Should be one Ready job
+    /// - Request second item (should unpause)
+    /// - Paused should become false
+    /// - Should be two Ready jobs
+    /// - Allow both to download
+    /// - Both jobs should reach Finished
+    #[tokio::test]
+    async fn add_items_while_paused() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request first item
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
+            .expect("should set downloads");
+
+        // should have one Ready job
+        core_1
+            .wait_for_client_condition("has 1 Ready job", &core_2, |client| {
+                client.transfer_jobs.len() == 1
+                    && matches!(
+                        client.transfer_jobs.first().map(|j| &j.progress),
+                        Some(TransferJobProgressModel::Ready)
+                    )
+            })
+            .await;
+
+        // pause downloads
+        core_1
+            .core
+            .pause_downloads(&core_2.endpoint_id_str())
+            .expect("should pause downloads");
+
+        // paused should become true
+        core_1
+            .wait_for_client_condition("paused is true", &core_2, |client| client.paused)
+            .await;
+
+        // should have one Ready job
+        core_1
+            .wait_for_client_condition("has 1 Ready job", &core_2, |client| {
+                client.transfer_jobs.len() == 1
+                    && matches!(
+                        client.transfer_jobs.first().map(|j| &j.progress),
+                        Some(TransferJobProgressModel::Ready)
+                    )
+            })
+            .await;
+
+        // request second item (should unpause)
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // paused should become false
+        core_1
+            .wait_for_client_condition("paused is false", &core_2, |client| !client.paused)
+            .await;
+
+        // should have two Ready jobs
+        core_1
+            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // allow both to download
+        core_1.test_hooks.add_download_permits(2);
+
+        // both jobs should reach Finished
+        core_1
+            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+    }
+
+    /// Test adding items while finished using SetDownloads:
+    /// - Request first item
+    /// - Should be one Ready job
+    /// - Allow it to download
+    /// - Should reach Finished
+    /// - Request second item
+    /// - Should be one Ready job
+    /// - Allow it to download
+    /// - Should reach Finished
+    #[tokio::test]
+    async fn add_items_while_finished() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request first item
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
+            .expect("should set downloads");
+
+        // should have one Ready job
+        core_1
+            .wait_for_client_condition("has 1 Ready job", &core_2, |client| {
+                client.transfer_jobs.len() == 1
+                    && matches!(
+                        client.transfer_jobs.first().map(|j| &j.progress),
+                        Some(TransferJobProgressModel::Ready)
+                    )
+            })
+            .await;
+
+        // allow first item to download
+        core_1.test_hooks.add_download_permits(1);
+
+        // should reach Finished
+        core_1
+            .wait_for_client_condition("job is Finished", &core_2, |client| {
+                client.transfer_jobs.len() == 1
+                    && matches!(
+                        client.transfer_jobs.first().map(|j| &j.progress),
+                        Some(TransferJobProgressModel::Finished { .. })
+                    )
+            })
+            .await;
+
+        // request second item
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[1].clone()])
+            .expect("should set downloads");
+
+        // first job should still be Finished, second should be Ready
+        core_1
+            .wait_for_client_condition(
+                "first job is Finished, second job is Ready",
+                &core_2,
+                |client| {
+                    client.transfer_jobs.len() == 2
+                        && matches!(
+                            client
+                                .transfer_jobs
+                                .iter()
+                                .find(|j| j.job_id == 0)
+                                .map(|j| &j.progress),
+                            Some(TransferJobProgressModel::Finished { .. })
+                        )
+                        && matches!(
+                            client
+                                .transfer_jobs
+                                .iter()
+                                .find(|j| j.job_id == 1)
+                                .map(|j| &j.progress),
+                            Some(TransferJobProgressModel::Ready)
+                        )
+                },
+            )
+            .await;
+
+        // allow second item to download
+        core_1.test_hooks.add_download_permits(1);
+
+        // both jobs should reach Finished
+        core_1
+            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+    }
+
+    /// Test removing items while waiting:
+    /// - Request both items
+    /// - Should be two Ready jobs
+    /// - Request only first item
+    /// - Should still be two jobs, since we only remove jobs when paused
+    #[tokio::test]
+    async fn remove_items_while_waiting() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request both items
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // should have two Ready jobs
+        core_1
+            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // request only first item
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
+            .expect("should set downloads");
+
+        // should still have two jobs, since we only remove jobs when paused
+        core_1
+            .wait_for_client_condition("still has 2 jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+            })
+            .await;
+    }
+
+    /// Test removing items while paused:
     /// - Request both items
-    /// - Both jobs should reach Ready
-    /// - Both index items should reach Waiting
-    /// - TestHooks: allow first item
-    /// - 1st job should reach Finished
-    /// - 1st index item should reach Downloaded
-    /// - 2nd job should still be Ready
-    /// - 2nd index item should still be Waiting
-    /// - Pause
+    /// - Should be two Ready jobs
+    /// - Pause downloads
     /// - Paused should become true
-    /// - 1st job should still be Finished
-    /// - 1st index item should still be Downloaded
-    /// - 2nd job should still be Ready
-    /// - 2nd index item should still be Waiting
-    /// - Resume (using SetDownloads with the Paused item)
+    /// - Should be two Ready jobs
+    /// - Request only first item (should unpause and remove second item)
     /// - Paused should become false
-    /// - 2nd job should reach Finished
-    /// - 2nd index item should reach Downloaded
+    /// - Should be only one Ready job for the first item
+    #[tokio::test]
+    async fn remove_items_while_paused() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request both items
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // should have two Ready jobs
+        core_1
+            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // pause downloads
+        core_1
+            .core
+            .pause_downloads(&core_2.endpoint_id_str())
+            .expect("should pause downloads");
+
+        // paused should become true
+        core_1
+            .wait_for_client_condition("paused is true", &core_2, |client| client.paused)
+            .await;
+
+        // should have two Ready jobs
+        core_1
+            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // request only first item
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
+            .expect("should set downloads");
+
+        // paused should become false
+        core_1
+            .wait_for_client_condition("paused is false", &core_2, |client| !client.paused)
+            .await;
+
+        // should be only one Ready job for the first item
+        core_1
+            .wait_for_client_condition(
+                "only has 1 Ready job for the first item",
+                &core_2,
+                |client| {
+                    client.transfer_jobs.len() == 1
+                        && matches!(
+                            client.transfer_jobs.first().map(|j| &j.progress),
+                            Some(TransferJobProgressModel::Ready)
+                        )
+                        && client.transfer_jobs.first().unwrap().file_path == download_items[0].path
+                },
+            )
+            .await;
+    }
+
+    /// Test removing items while finished:
+    /// - Request both items
+    /// - Should be two Ready jobs
+    /// - Allow both to download
+    /// - Should reach Finished
+    /// - Request only first item
+    /// - Should still be two Finished jobs, since we don't remove finished jobs
+    #[tokio::test]
+    async fn remove_items_while_finished() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+        core_1.test_hooks.enable_download_gate();
+
+        // request both items
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        // should have two Ready jobs
+        core_1
+            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            })
+            .await;
+
+        // allow both to download
+        core_1.test_hooks.add_download_permits(2);
+
+        // both jobs should reach Finished
+        core_1
+            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+
+        // request only first item
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
+            .expect("should set downloads");
+
+        // should still have two Finished jobs, since we don't remove finished jobs
+        core_1
+            .wait_for_client_condition("still has 2 Finished jobs", &core_2, |client| {
+                client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+    }
+
+    /// Disconnects core 1 from core 2, then reconnects and waits for a complete index with the
+    /// given number of items.
+    async fn reconnect(core_1: &TestCore, core_2: &TestCore, num_items: usize) {
+        core_1
+            .core
+            .close_client(&core_2.endpoint_id_str())
+            .expect("should disconnect");
+        core_2
+            .core
+            .close_server(&core_1.endpoint_id_str())
+            .expect("should disconnect");
+        core_1.wait_for_client_closed(core_2).await;
+
+        core_1
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_1.wait_for_client_pending(core_2).await;
+        core_2.wait_for_server_pending(core_1).await;
+        core_2
+            .core
+            .accept_connection(&core_1.endpoint_id_str())
+            .expect("should accept");
+        core_1.wait_for_client_accepted(core_2).await;
+        core_1
+            .wait_for_client_condition("index has items", core_2, |client| {
+                client.index_complete
+                    && client
+                        .index
+                        .as_ref()
+                        .is_some_and(|idx| idx.len() == num_items)
+            })
+            .await;
+    }
+
+    /// Downloads from several servers share the limit on total downloads, and are combined in the
+    /// download progress.
+    #[tokio::test]
+    async fn download_from_multiple_servers() {
+        let (core_1, core_2) = prepare_with_model_diffs(LibraryFixture::Multiple, true).await;
+        core_1
+            .wait_for_client_condition("index is complete", &core_2, |client| client.index_complete)
+            .await;
+        let download_items_2 = core_1
+            .client_model(&core_2)
+            .index
+            .unwrap()
+            .into_iter()
+            .map(|item| DownloadRequestModel {
+                endpoint_id: item.endpoint_id.clone(),
+                root: item.root.clone(),
+                path: item.path.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        // set up a second server
+        let core_3 = TestCore::start("core 3").await;
+        core_3
+            .core
+            .add_library_root(
+                "foo".into(),
+                LibraryFixture::Minimal.path().to_string_lossy().to_string(),
+            )
+            .expect("should add library root");
+        core_3
+            .wait_for_library_model_condition("root has files", |model| {
+                model.local_roots.first().is_some_and(|root| {
+                    root.num_files == LibraryFixture::Minimal.num_items() as u64
+                })
+            })
+            .await;
+
+        // core 1: connect to core 3
+        core_1.discover(&core_3).await;
+        core_1
+            .core
+            .connect(Some(TranscodeFormat::Opus128), &core_3.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_3.wait_for_server_pending(&core_1).await;
+        core_3
+            .core
+            .accept_connection(&core_1.endpoint_id_str())
+            .expect("should accept");
+        core_1
+            .wait_for_client_condition("index has items", &core_3, |client| {
+                client
+                    .index
+                    .as_ref()
+                    .is_some_and(|idx| idx.len() == LibraryFixture::Minimal.num_items())
+            })
+            .await;
+        let download_items_3 = core_1
+            .client_model(&core_3)
+            .index
+            .unwrap()
+            .into_iter()
+            .map(|item| DownloadRequestModel {
+                endpoint_id: item.endpoint_id.clone(),
+                root: item.root.clone(),
+                path: item.path.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        // only download one file at a time across both servers, and send slowly enough that
+        // downloads would overlap without the limit
+        core_1
+            .core
+            .set_max_total_downloads(1)
+            .expect("should set max total downloads");
+        for server in [&core_2, &core_3] {
+            server
+                .core
+                .set_max_upload_rate_per_client(64 * 1024)
+                .expect("should set max upload rate per client");
+        }
+
+        let VersionedNodeModel { version, mut model } = core_1
+            .core
+            .get_node_model_versioned()
+            .expect("should get node model");
+
+        // request files from both servers
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items_2)
+            .expect("should set downloads");
+        core_1
+            .core
+            .set_downloads(&core_3.endpoint_id_str(), download_items_3)
+            .expect("should set downloads");
+
+        // all jobs should finish
+        for (server, num_items) in [
+            (&core_2, LibraryFixture::Multiple.num_items()),
+            (&core_3, LibraryFixture::Minimal.num_items()),
+        ] {
+            core_1
+                .wait_for_client_condition("all jobs are Finished", server, |client| {
+                    client.transfer_jobs.len() == num_items
+                        && client.transfer_jobs.iter().all(|j| {
+                            matches!(j.progress, TransferJobProgressModel::Finished { .. })
+                        })
+                })
+                .await;
+        }
+
+        // replay the changes to the jobs, no more than one should have been active at a time
+        let mut max_active = 0;
+        for diff in core_1.event_handler.node_model_diffs.lock().unwrap().iter() {
+            if diff.version <= version {
+                continue;
+            }
+            model.apply_patch(diff.patch.clone());
+            let active = model
+                .clients
+                .values()
+                .flat_map(|client| client.transfer_jobs.iter())
+                .filter(|job| matches!(job.progress, TransferJobProgressModel::InProgress { .. }))
+                .count();
+            max_active = max_active.max(active);
+        }
+        assert_eq!(max_active, 1, "one job should be active at a time");
+
+        // progress should combine both servers
+        let progress = core_1
+            .core
+            .get_download_progress()
+            .expect("should get download progress");
+        assert_eq!(progress.servers, 2);
+        assert_eq!(progress.finished_jobs, 3);
+        assert_eq!(
+            progress.queued_jobs + progress.active_jobs + progress.failed_jobs,
+            0
+        );
+        assert_eq!(progress.downloaded_bytes, progress.total_bytes);
+
+        // each connection should summarize its own jobs
+        let session = core_1.client_model(&core_2).session;
+        assert_eq!(session.completed_files, 2);
+        assert_eq!(
+            session.queued_files + session.active_files + session.failed_files,
+            0
+        );
+        assert_eq!(session.transferred_bytes, session.expected_bytes);
+    }
+
+    /// Queued downloads are held while the transfer schedule isn't met, and start once it is.
+    #[tokio::test]
+    async fn transfer_schedule() {
+        let fixture = LibraryFixture::Multiple;
+        let (core_1, core_2, download_items) = prepare_with_index(fixture).await;
+
+        // core 1: only download while charging, on a device that isn't
+        core_1
+            .core
+            .set_transfer_schedule(TransferSchedule {
+                require_charging: true,
+                require_unmetered: false,
+                hours: None,
+            })
+            .expect("should set transfer schedule");
+        core_1
+            .core
+            .set_device_state(DeviceState {
+                charging: false,
+                unmetered: true,
+                utc_offset_secs: 0,
+            })
+            .expect("should set device state");
+        core_1
+            .wait_for_node_model_condition("downloads are held", |model| {
+                model.transfer_hold_reasons == vec![TransferHoldReason::NotCharging]
+            })
+            .await;
+
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        // jobs should stay queued
+        core_1
+            .wait_for_client_condition("all jobs are Ready and held", &core_2, |client| {
+                client.transfer_jobs.len() == fixture.num_items()
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready) && j.held)
+            })
+            .await;
+
+        // core 1: plug in
+        core_1
+            .core
+            .set_device_state(DeviceState {
+                charging: true,
+                unmetered: true,
+                utc_offset_secs: 0,
+            })
+            .expect("should set device state");
+
+        core_1
+            .wait_for_client_condition("all jobs are Finished", &core_2, |client| {
+                client
+                    .transfer_jobs
+                    .iter()
+                    .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+        assert!(
+            core_1
+                .core
+                .get_node_model()
+                .expect("should get node model")
+                .transfer_hold_reasons
+                .is_empty()
+        );
+    }
+
+    /// With the metered policy set to pause, queued downloads are held on mobile data and start on
+    /// Wi-Fi, and connecting can be turned off on metered networks.
+    #[tokio::test]
+    async fn metered_network() {
+        let fixture = LibraryFixture::Multiple;
+        let (core_1, core_2, download_items) = prepare_with_index(fixture).await;
+
+        // core 1: pause on metered networks, on mobile data
+        core_1
+            .core
+            .set_metered_policy(MeteredPolicy::Pause, 0)
+            .expect("should set metered policy");
+        core_1
+            .core
+            .set_network_state(false, false, false)
+            .expect("should set network state");
+        core_1
+            .wait_for_node_model_condition("downloads are held", |model| {
+                model.transfer_hold_reasons == vec![TransferHoldReason::Metered]
+                    && model.network_state
+                        == NetworkState {
+                            metered: false,
+                            wifi: false,
+                            offline: false,
+                        }
+            })
+            .await;
+
+        // connecting fails right away when it's turned off
+        core_1
+            .core
+            .set_connect_on_metered(false)
+            .expect("should set connect on metered");
+        let error = core_1
+            .core
+            .connect(None, &core_2.endpoint_id_str())
+            .await
+            .expect_err("should fail to connect");
+        assert_eq!(
+            error.kind(),
+            CoreErrorKind::Connection {
+                error: ConnectionError::Metered
+            }
+        );
+
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        // jobs should stay queued
+        core_1
+            .wait_for_client_condition("all jobs are Ready and held", &core_2, |client| {
+                client.transfer_jobs.len() == fixture.num_items()
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready) && j.held)
+            })
+            .await;
+
+        // core 1: join Wi-Fi
+        core_1
+            .core
+            .set_network_state(false, true, false)
+            .expect("should set network state");
+
+        core_1
+            .wait_for_client_condition("all jobs are Finished", &core_2, |client| {
+                client
+                    .transfer_jobs
+                    .iter()
+                    .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+        assert!(
+            core_1
+                .core
+                .get_node_model()
+                .expect("should get node model")
+                .transfer_hold_reasons
+                .is_empty()
+        );
+    }
+
+    /// The server's upload rate limit per client applies to existing connections.
+    #[tokio::test]
+    async fn max_upload_rate_per_client() {
+        const RATE: u64 = 64 * 1024;
+
+        let fixture = LibraryFixture::Multiple;
+        let (core_1, core_2, download_items) = prepare_with_index(fixture).await;
+
+        // core 2: limit uploads to each client
+        core_2
+            .core
+            .set_max_upload_rate_per_client(RATE)
+            .expect("should set max upload rate per client");
+
+        let start = std::time::Instant::now();
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        core_1
+            .wait_for_client_condition("all jobs are Finished", &core_2, |client| {
+                client.transfer_jobs.len() == fixture.num_items()
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+
+        // only the first chunk is sent without waiting, so the rest should take at least as long
+        // as the limit allows
+        let transferred_bytes = core_1.client_model(&core_2).session.transferred_bytes;
+        let min_elapsed = transferred_bytes.saturating_sub(64 * 1024) as f64 / RATE as f64;
+        assert!(start.elapsed().as_secs_f64() >= min_elapsed);
+    }
+
+    /// Files downloaded before the download directory was moved aren't downloaded again.
+    #[tokio::test]
+    async fn moved_download_directory_skips_downloaded_files() {
+        let fixture = LibraryFixture::Minimal;
+        let (core_1, core_2, download_items) = prepare_with_index(fixture).await;
+
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .expect("should set downloads");
+
+        core_1
+            .wait_for_client_condition("job is Finished", &core_2, |client| {
+                matches!(
+                    client.transfer_jobs.first().map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Finished { .. })
+                )
+            })
+            .await;
+
+        // move the download directory
+        let moved_dir = core_1.instance_dir.join("moved downloads");
+        std::fs::rename(&core_1.download_dir, &moved_dir).expect("should move download dir");
+        core_1
+            .core
+            .set_download_directory(&moved_dir.to_string_lossy())
+            .expect("should set download directory");
+
+        // reconnect, so the new index includes the hashes computed while transcoding
+        reconnect(&core_1, &core_2, fixture.num_items()).await;
+
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        // should find the moved file instead of creating a new job
+        core_1
+            .wait_for_client_condition("items are Downloaded without jobs", &core_2, |client| {
+                client.transfer_jobs.is_empty()
+                    && client.index.as_ref().is_some_and(|index| {
+                        index.iter().all(|item| {
+                            matches!(
+                                item.download_status,
+                                Some(IndexItemDownloadStatusModel::Downloaded)
+                            )
+                        })
+                    })
+            })
+            .await;
+    }
+
+    /// Mirroring deletes downloaded files that were removed from the server, after previewing them
+    /// with a dry run.
+    #[tokio::test]
+    async fn mirror_downloads() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Minimal).await;
+
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        core_1
+            .wait_for_client_condition("job is Finished", &core_2, |client| {
+                matches!(
+                    client.transfer_jobs.first().map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Finished { .. })
+                )
+            })
+            .await;
+
+        // nothing to delete while the file is still on the server
+        let deletions = core_1
+            .core
+            .mirror_downloads(&core_2.endpoint_id_str(), true)
+            .await
+            .expect("should preview mirror");
+        assert!(deletions.is_empty());
+
+        // remove the file from the server
+        core_2
+            .core
+            .remove_library_root("foo".into())
+            .expect("should remove library root");
+        core_2
+            .wait_for_library_model_condition("model has 0 roots", |model| {
+                model.local_roots.is_empty()
+            })
+            .await;
+        reconnect(&core_1, &core_2, 0).await;
+
+        // dry run should list the file without deleting it
+        let deletions = core_1
+            .core
+            .mirror_downloads(&core_2.endpoint_id_str(), true)
+            .await
+            .expect("should preview mirror");
+        assert_eq!(deletions.len(), 1);
+        let local_path = core_1.download_dir.join(&deletions[0].local_path);
+        assert!(local_path.exists(), "dry run should not delete files");
+
+        // mirroring should delete the file
+        let deletions = core_1
+            .core
+            .mirror_downloads(&core_2.endpoint_id_str(), false)
+            .await
+            .expect("should mirror");
+        assert_eq!(deletions.len(), 1);
+        assert!(!local_path.exists(), "mirroring should delete files");
+    }
+
+    /// Downloads that don't fit in the free space of the download directory aren't queued.
+    #[tokio::test]
+    async fn insufficient_space() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+
+        // download to a memory tree without space for any file
+        core_1
+            .core
+            .set_download_directory("memory://insufficient-space")
+            .expect("should set download directory");
+        musicopy::fs::memory::set_available_space("memory://insufficient-space", 1);
+
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        core_1
+            .wait_for_client_condition("insufficient space is set", &core_2, |client| {
+                client.insufficient_space.is_some()
+            })
+            .await;
+
+        let client = core_1.client_model(&core_2);
+        assert!(
+            matches!(
+                client.insufficient_space,
+                Some(InsufficientSpaceModel {
+                    available_bytes: 1,
+                    skipped_items: 2,
+                    ..
+                })
+            ),
+            "unexpected insufficient space: {:?}",
+            client.insufficient_space
+        );
+        assert!(client.transfer_jobs.is_empty(), "no jobs should be queued");
+    }
+
+    /// Downloaded files are checked against their manifests without the server, and repairing
+    /// untracks broken files so they're downloaded again.
+    #[tokio::test]
+    async fn check_downloaded_files() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Minimal).await;
+
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        core_1
+            .wait_for_client_condition("job is Finished", &core_2, |client| {
+                matches!(
+                    client.transfer_jobs.first().map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Finished { .. })
+                )
+            })
+            .await;
+
+        // the downloaded file should match its manifest
+        let issues = core_1
+            .core
+            .check_downloaded_files(false)
+            .await
+            .expect("should check downloaded files");
+        assert!(issues.is_empty(), "unexpected issues: {issues:?}");
+
+        // corrupt the downloaded file
+        let local_path = std::fs::read_dir(&core_1.download_dir)
+            .expect("should read download dir")
+            .flatten()
+            .flat_map(|entry| std::fs::read_dir(entry.path()).expect("should read root dir"))
+            .flatten()
+            .map(|entry| entry.path())
+            .next()
+            .expect("should have downloaded file");
+        std::fs::write(&local_path, b"corrupted").expect("should corrupt file");
+
+        // checking should report the file without changing it
+        let issues = core_1
+            .core
+            .check_downloaded_files(false)
+            .await
+            .expect("should check downloaded files");
+        assert_eq!(issues.len(), 1);
+        assert!(matches!(
+            issues[0].issue,
+            DownloadIssueKindModel::SizeMismatch { actual: 9, .. }
+        ));
+        assert!(!issues[0].repaired);
+        assert!(local_path.exists(), "checking should not delete files");
+
+        // repairing should delete and untrack the file
+        let issues = core_1
+            .core
+            .check_downloaded_files(true)
+            .await
+            .expect("should repair downloaded files");
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].repaired);
+        assert!(!local_path.exists(), "repairing should delete files");
+
+        let issues = core_1
+            .core
+            .check_downloaded_files(false)
+            .await
+            .expect("should check downloaded files");
+        assert!(issues.is_empty(), "repaired files should be untracked");
+    }
+
+    /// Verifying rehashes downloads against the server, and requeueing downloads broken files
+    /// again.
+    #[tokio::test]
+    async fn verify_downloads() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Minimal).await;
+
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        core_1
+            .wait_for_client_condition("job is Finished", &core_2, |client| {
+                matches!(
+                    client.transfer_jobs.first().map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Finished { .. })
+                )
+            })
+            .await;
+
+        let issues = core_1
+            .core
+            .verify_downloads(&core_2.endpoint_id_str(), false)
+            .await
+            .expect("should verify downloads");
+        assert!(issues.is_empty(), "unexpected issues: {issues:?}");
+
+        // corrupt the downloaded file
+        let files = core_1
+            .core
+            .list_downloaded_files(&core_2.endpoint_id_str())
+            .expect("should list files");
+        let local_path = core_1.download_dir.join(&files[0].local_path);
+        let original = std::fs::read(&local_path).expect("should read file");
+        std::fs::write(&local_path, b"corrupted").expect("should corrupt file");
+
+        let issues = core_1
+            .core
+            .verify_downloads(&core_2.endpoint_id_str(), false)
+            .await
+            .expect("should verify downloads");
+        assert_eq!(issues.len(), 1);
+        assert!(matches!(
+            issues[0].issue,
+            DownloadIssueKindModel::SizeMismatch { actual: 9, .. }
+        ));
+        assert!(!issues[0].repaired && !issues[0].requeued);
+
+        // requeueing should download the file again
+        let issues = core_1
+            .core
+            .verify_downloads(&core_2.endpoint_id_str(), true)
+            .await
+            .expect("should verify downloads");
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].repaired && issues[0].requeued);
+
+        core_1
+            .wait_for_client_condition("file is downloaded again", &core_2, |client| {
+                std::fs::read(&local_path).is_ok_and(|contents| contents == original)
+                    && client.index.as_ref().is_some_and(|index| {
+                        index.iter().all(|item| {
+                            matches!(
+                                item.download_status,
+                                Some(IndexItemDownloadStatusModel::Downloaded)
+                            )
+                        })
+                    })
+            })
+            .await;
+    }
+
+    /// Files that changed on the server since they were downloaded are detected by their content
+    /// hashes, and downloaded again when requested.
     #[tokio::test]
-    async fn pause() {
-        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
-        core_1.test_hooks.enable_download_gate();
+    async fn redownload_changed_files() {
+        let (core_1, core_2, _) = prepare_with_index(LibraryFixture::Minimal).await;
 
-        // request both items
+        // core 2: trust core 1 so it can request rescans, and add a root with a file to change
+        core_2
+            .core
+            .trust_node(&core_1.endpoint_id_str())
+            .expect("should trust");
+        let root_dir = core_2.instance_dir.join("bar");
+        std::fs::create_dir_all(&root_dir).expect("should create root dir");
+        std::fs::copy(
+            LibraryFixture::Minimal.path().join("test.mp3"),
+            root_dir.join("song.mp3"),
+        )
+        .expect("should copy file");
+        core_2
+            .core
+            .add_library_root("bar".into(), root_dir.to_string_lossy().to_string())
+            .expect("should add library root");
+        core_2
+            .wait_for_library_model_condition("model has both roots", |model| {
+                model.local_roots.len() == 2 && !model.is_scanning
+            })
+            .await;
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
-            .expect("should set downloads");
-
-        // both jobs should reach Ready
+            .request_remote_rescan(&core_2.endpoint_id_str())
+            .expect("should request rescan");
         core_1
-            .wait_for_client_condition("both jobs are Ready", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
+            .wait_for_client_condition("index has file", &core_2, |client| {
+                client.index.as_ref().is_some_and(|index| {
+                    index
                         .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+                        .any(|item| item.root == "bar" && item.path == "song.mp3")
+                })
             })
             .await;
-        // both index items should reach Waiting
+
+        // core 1: download the file
         core_1
             .core
-            .refresh_client_index(&core_2.endpoint_id_str())
-            .expect("should refresh client index");
+            .set_downloads(
+                &core_2.endpoint_id_str(),
+                vec![DownloadRequestModel {
+                    endpoint_id: core_2.endpoint_id_str(),
+                    root: "bar".into(),
+                    path: "song.mp3".into(),
+                }],
+            )
+            .expect("should set downloads");
+        let is_song = |item: &&IndexItemModel| item.root == "bar" && item.path == "song.mp3";
         core_1
-            .wait_for_client_condition("both index items are Waiting", &core_2, |client| {
-                client.index.as_ref().unwrap().len() == 2
-                    && client.index.as_ref().unwrap().iter().all(|item| {
+            .wait_for_client_condition("file is downloaded", &core_2, |client| {
+                client.index.as_ref().is_some_and(|index| {
+                    index.iter().filter(is_song).all(|item| {
                         matches!(
                             item.download_status,
-                            Some(IndexItemDownloadStatusModel::Waiting)
+                            Some(IndexItemDownloadStatusModel::Downloaded)
+                        )
+                    })
+                })
+            })
+            .await;
+        let changed = core_1
+            .core
+            .changed_downloads(&core_2.endpoint_id_str(), false)
+            .await
+            .expect("should list changed downloads");
+        assert!(changed.is_empty(), "unexpected changes: {changed:?}");
+
+        // core 2: replace the file with different content
+        std::fs::copy(
+            LibraryFixture::Multiple.path().join("fbp.mp3"),
+            root_dir.join("song.mp3"),
+        )
+        .expect("should replace file");
+        core_1
+            .core
+            .request_remote_rescan(&core_2.endpoint_id_str())
+            .expect("should request rescan");
+        core_1
+            .wait_for_client_condition("file is changed", &core_2, |client| {
+                client.index.as_ref().is_some_and(|index| {
+                    index.iter().filter(is_song).all(|item| {
+                        matches!(
+                            item.download_status,
+                            Some(IndexItemDownloadStatusModel::Changed)
                         )
                     })
+                })
             })
             .await;
 
-        // allow first item to download
-        core_1.test_hooks.add_download_permits(1);
+        let changed = core_1
+            .core
+            .changed_downloads(&core_2.endpoint_id_str(), false)
+            .await
+            .expect("should list changed downloads");
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].path, "song.mp3");
+        assert!(!changed[0].requeued);
+
+        // requeueing should download the file again, replacing the old one
+        let changed = core_1
+            .core
+            .changed_downloads(&core_2.endpoint_id_str(), true)
+            .await
+            .expect("should requeue changed downloads");
+        assert_eq!(changed.len(), 1);
+        assert!(changed[0].requeued);
 
-        // first job should reach Finished, second should still be Ready
         core_1
-            .wait_for_client_condition(
-                "one job is Finished, other job is still Ready",
-                &core_2,
-                |client| {
-                    client.transfer_jobs.len() == 2
-                        && client.transfer_jobs.iter().any(|j| {
-                            matches!(j.progress, TransferJobProgressModel::Finished { .. })
-                        })
-                        && client
-                            .transfer_jobs
-                            .iter()
-                            .any(|j| matches!(j.progress, TransferJobProgressModel::Ready))
-                },
+            .wait_for_client_condition("file is downloaded again", &core_2, |client| {
+                client.index.as_ref().is_some_and(|index| {
+                    index.iter().filter(is_song).all(|item| {
+                        matches!(
+                            item.download_status,
+                            Some(IndexItemDownloadStatusModel::Downloaded)
+                        )
+                    })
+                })
+            })
+            .await;
+        let files = core_1
+            .core
+            .list_downloaded_files(&core_2.endpoint_id_str())
+            .expect("should list files");
+        assert_eq!(
+            files
+                .iter()
+                .filter(|file| file.root == "bar" && file.path == "song.mp3")
+                .count(),
+            1,
+            "the old file should be replaced: {files:?}"
+        );
+    }
+
+    /// Downloaded files are listed per server, measured on disk, and deleted by selection.
+    #[tokio::test]
+    async fn manage_downloaded_files() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Minimal).await;
+
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        core_1
+            .wait_for_client_condition("job is Finished", &core_2, |client| {
+                matches!(
+                    client.transfer_jobs.first().map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Finished { .. })
+                )
+            })
+            .await;
+
+        let servers = core_1
+            .core
+            .list_downloaded_servers()
+            .expect("should list servers");
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].endpoint_id, core_2.endpoint_id_str());
+        assert_eq!(servers[0].files, 1);
+        assert_eq!(servers[0].missing_files, None);
+
+        let files = core_1
+            .core
+            .list_downloaded_files(&core_2.endpoint_id_str())
+            .expect("should list files");
+        assert_eq!(files.len(), 1);
+        let local_path = core_1.download_dir.join(&files[0].local_path);
+        assert!(local_path.exists());
+
+        let measured = core_1
+            .core
+            .measure_downloaded_files()
+            .await
+            .expect("should measure files");
+        assert_eq!(measured.len(), 1);
+        assert_eq!(measured[0].missing_files, Some(0));
+        assert_eq!(
+            measured[0].bytes,
+            std::fs::metadata(&local_path)
+                .expect("should read metadata")
+                .len()
+        );
+
+        // selections that match nothing delete nothing
+        let deleted = core_1
+            .core
+            .delete_downloaded_files(
+                &core_2.endpoint_id_str(),
+                vec![DownloadedFileSelectionModel::File {
+                    root: "foo".into(),
+                    path: "missing.mp3".into(),
+                }],
+            )
+            .await
+            .expect("should delete files");
+        assert_eq!(deleted.deleted_files, 0);
+        assert!(local_path.exists());
+
+        let deleted = core_1
+            .core
+            .delete_downloaded_files(
+                &core_2.endpoint_id_str(),
+                vec![DownloadedFileSelectionModel::All],
+            )
+            .await
+            .expect("should delete files");
+        assert_eq!(deleted.deleted_files, 1);
+        assert_eq!(deleted.failed_files, 0);
+        assert!(!local_path.exists(), "deleting should delete files");
+
+        let servers = core_1
+            .core
+            .list_downloaded_servers()
+            .expect("should list servers");
+        assert!(servers.is_empty(), "deleted files should be untracked");
+    }
+
+    /// The local library isn't treated as files downloaded from the local node, so it can't be
+    /// listed or deleted as downloads.
+    #[tokio::test]
+    async fn delete_downloaded_files_rejects_local_node() {
+        let core = TestCore::start("core").await;
+
+        // copy the fixture, so deleting it by mistake doesn't delete the fixture
+        let root_dir = core.instance_dir.join("library");
+        std::fs::create_dir_all(&root_dir).expect("should create root dir");
+        let local_path = root_dir.join("test.mp3");
+        std::fs::copy(LibraryFixture::Minimal.path().join("test.mp3"), &local_path)
+            .expect("should copy fixture");
+
+        core.core
+            .add_library_root("foo".into(), root_dir.to_string_lossy().to_string())
+            .expect("should add library root");
+        core.wait_for_library_model_condition("root has 1 file", |model| {
+            model
+                .local_roots
+                .first()
+                .is_some_and(|root| root.num_files == 1)
+        })
+        .await;
+
+        core.core
+            .list_downloaded_files(&core.endpoint_id_str())
+            .expect_err("should fail to list the local library");
+        core.core
+            .delete_downloaded_files(
+                &core.endpoint_id_str(),
+                vec![DownloadedFileSelectionModel::All],
             )
+            .await
+            .expect_err("should fail to delete the local library");
+        assert!(local_path.exists(), "library files should be kept");
+    }
+
+    /// Downloaded files are moved to the paths a new download path template gives them.
+    #[tokio::test]
+    async fn reorganize_downloads() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Minimal).await;
+
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+        core_1
+            .wait_for_client_condition("job is Finished", &core_2, |client| {
+                matches!(
+                    client.transfer_jobs.first().map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Finished { .. })
+                )
+            })
             .await;
-        let finished_job_path = core_1
-            .client_model(&core_2)
-            .transfer_jobs
-            .iter()
-            .find(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
-            .map(|j| j.file_path.clone())
-            .unwrap();
-        // finished index item should reach Downloaded, other should still be Waiting
+
+        // the file keeps the server's folder structure without a template
+        let files = core_1
+            .core
+            .list_downloaded_files(&core_2.endpoint_id_str())
+            .expect("should list files");
+        let old_path = core_1.download_dir.join(&files[0].local_path);
+        assert!(old_path.exists());
+
+        // nothing to move with the same layout
+        let result = core_1
+            .core
+            .reorganize_downloads(false)
+            .await
+            .expect("should reorganize");
+        assert!(result.moved.is_empty() && result.conflicts.is_empty());
+
         core_1
             .core
-            .refresh_client_index(&core_2.endpoint_id_str())
-            .expect("should refresh client index");
+            .set_download_path_template(Some("{root}/{filename}".to_string()))
+            .expect("should set template");
+
+        // a dry run doesn't move anything
+        let result = core_1
+            .core
+            .reorganize_downloads(true)
+            .await
+            .expect("should reorganize");
+        assert_eq!(result.moved.len(), 1);
+        assert!(result.conflicts.is_empty());
+        assert!(old_path.exists());
+
+        let result = core_1
+            .core
+            .reorganize_downloads(false)
+            .await
+            .expect("should reorganize");
+        assert_eq!(result.moved.len(), 1);
+        assert_eq!(result.moved[0].from, files[0].local_path);
+        let new_path = core_1.download_dir.join(&result.moved[0].to);
+        assert_eq!(new_path, core_1.download_dir.join("foo").join("test.ogg"));
+        assert!(new_path.exists());
+        assert!(!old_path.exists());
+        assert!(
+            !old_path.parent().unwrap().exists(),
+            "emptied directories should be removed"
+        );
+
+        // the record follows the file, so it still counts as downloaded
+        let files = core_1
+            .core
+            .list_downloaded_files(&core_2.endpoint_id_str())
+            .expect("should list files");
+        assert_eq!(files[0].local_path, result.moved[0].to);
+    }
+
+    /// Files whose new path is taken, by a file that wasn't downloaded or by another downloaded
+    /// file, are left in place, and directories that still hold anything aren't removed.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reorganize_downloads_conflicts() {
+        let fixture = LibraryFixture::Multiple;
+        let (core_1, core_2, download_items) = prepare_with_index(fixture).await;
+
         core_1
-            .wait_for_client_condition(
-                "finished index item is Downloaded, other index item is still Waiting",
-                &core_2,
-                |client| {
-                    client.index.as_ref().unwrap().len() == 2
-                        && matches!(
-                            client
-                                .index
-                                .as_ref()
-                                .unwrap()
-                                .iter()
-                                .find(|i| i.path == finished_job_path)
-                                .unwrap()
-                                .download_status,
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+        core_1
+            .wait_for_client_condition("all jobs are Finished", &core_2, |client| {
+                client.transfer_jobs.len() == fixture.num_items()
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
+            .await;
+
+        let files = core_1
+            .core
+            .list_downloaded_files(&core_2.endpoint_id_str())
+            .expect("should list files");
+        let old_dir = core_1
+            .download_dir
+            .join(&files[0].local_path)
+            .parent()
+            .expect("should have parent")
+            .to_path_buf();
+        std::os::unix::fs::symlink("elsewhere", old_dir.join("link"))
+            .expect("should create symlink");
+
+        // a file that wasn't downloaded is where one of the files would go
+        core_1
+            .core
+            .set_download_path_template(Some("{root}/{filename}".to_string()))
+            .expect("should set template");
+        let new_dir = core_1.download_dir.join("foo");
+        let taken = new_dir.join("evolution.ogg");
+        std::fs::create_dir_all(&new_dir).expect("should create dir");
+        std::fs::write(&taken, "not downloaded").expect("should write file");
+
+        let result = core_1
+            .core
+            .reorganize_downloads(false)
+            .await
+            .expect("should reorganize");
+        assert_eq!(result.moved.len(), 1);
+        assert_eq!(
+            core_1.download_dir.join(&result.moved[0].to),
+            new_dir.join("fbp.ogg")
+        );
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(
+            result.conflicts[0].reason,
+            ReorganizeConflictReasonModel::Exists
+        );
+        assert_eq!(
+            std::fs::read(&taken).expect("should read file"),
+            b"not downloaded",
+            "existing files should be kept"
+        );
+        assert!(
+            core_1
+                .download_dir
+                .join(&result.conflicts[0].local_path)
+                .exists(),
+            "conflicting files should be left in place"
+        );
+
+        // once the path is free, the file is moved, and its directory is kept for the symlink
+        std::fs::remove_file(&taken).expect("should remove file");
+        let result = core_1
+            .core
+            .reorganize_downloads(false)
+            .await
+            .expect("should reorganize");
+        assert_eq!(result.moved.len(), 1);
+        assert!(result.conflicts.is_empty());
+        assert!(taken.exists());
+        assert!(
+            old_dir.join("link").symlink_metadata().is_ok(),
+            "directories with symlinks should be kept"
+        );
+
+        // both files would go to the same path
+        core_1
+            .core
+            .set_download_path_template(Some("{root}/same".to_string()))
+            .expect("should set template");
+        let result = core_1
+            .core
+            .reorganize_downloads(false)
+            .await
+            .expect("should reorganize");
+        assert_eq!(result.moved.len(), 1);
+        assert_eq!(
+            core_1.download_dir.join(&result.moved[0].to),
+            new_dir.join("same.ogg")
+        );
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(
+            result.conflicts[0].reason,
+            ReorganizeConflictReasonModel::Duplicate
+        );
+        assert!(
+            core_1
+                .download_dir
+                .join(&result.conflicts[0].local_path)
+                .exists(),
+            "conflicting files should be left in place"
+        );
+    }
+
+    /// Files copied to the download directory by hand are matched to the server's index and
+    /// tracked as downloaded, so they aren't transferred again.
+    #[tokio::test]
+    async fn import_existing_downloads() {
+        let (core_1, core_2, _) = prepare_with_index(LibraryFixture::Minimal).await;
+        let core_2_id = core_2.endpoint_id_str();
+
+        // core 1: copy the file by hand under another name, next to a file that isn't music
+        let copied_dir = core_1.download_dir.join("copied");
+        std::fs::create_dir_all(&copied_dir).expect("should create dir");
+        std::fs::copy(
+            LibraryFixture::Minimal.path().join("test.mp3"),
+            copied_dir.join("Song.mp3"),
+        )
+        .expect("should copy file");
+        std::fs::write(copied_dir.join("notes.txt"), "not music").expect("should write file");
+
+        // a dry run doesn't track anything
+        let result = core_1
+            .core
+            .import_downloads(&core_2_id, Some("copied".into()), true)
+            .await
+            .expect("should import");
+        assert_eq!(result.imported.len(), 1);
+        assert_eq!(result.unmatched_files, 1);
+        assert!(
+            core_1
+                .core
+                .list_downloaded_files(&core_2_id)
+                .expect("should list files")
+                .is_empty()
+        );
+
+        let result = core_1
+            .core
+            .import_downloads(&core_2_id, None, false)
+            .await
+            .expect("should import");
+        assert_eq!(result.imported.len(), 1);
+        assert_eq!(result.imported[0].root, "foo");
+        assert_eq!(result.imported[0].path, "test.mp3");
+        assert_eq!(result.imported[0].local_path, "copied/Song.mp3");
+
+        core_1
+            .wait_for_client_condition("file is downloaded", &core_2, |client| {
+                client.index.as_ref().is_some_and(|index| {
+                    index.iter().all(|item| {
+                        matches!(
+                            item.download_status,
                             Some(IndexItemDownloadStatusModel::Downloaded)
                         )
-                        && matches!(
-                            client
-                                .index
-                                .as_ref()
-                                .unwrap()
-                                .iter()
-                                .find(|i| i.path != finished_job_path)
-                                .unwrap()
-                                .download_status,
-                            Some(IndexItemDownloadStatusModel::Waiting)
-                        )
-                },
-            )
+                    })
+                })
+            })
             .await;
 
-        // pause downloads
-        core_1
+        // imported files are tracked, so they aren't imported again
+        let result = core_1
             .core
-            .pause_downloads(&core_2.endpoint_id_str())
-            .expect("should pause downloads");
+            .import_downloads(&core_2_id, None, false)
+            .await
+            .expect("should import");
+        assert!(result.imported.is_empty());
+    }
+
+    /// A file that doesn't match the checksum sent by the server is discarded, and its job fails
+    /// with a retryable ChecksumMismatch reason.
+    #[tokio::test]
+    async fn checksum_mismatch() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Minimal).await;
+
+        core_2.test_hooks.set_corrupt_checksums(true);
 
-        // paused should become true
         core_1
-            .wait_for_client_condition("paused is true", &core_2, |client| client.paused)
-            .await;
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
 
-        // finished job should still be Finished
         core_1
-            .wait_for_client_condition("finished job is still Finished", &core_2, |client| {
+            .wait_for_client_condition("job is Failed", &core_2, |client| {
                 matches!(
-                    client
-                        .transfer_jobs
-                        .iter()
-                        .find(|j| j.file_path == finished_job_path)
-                        .map(|j| &j.progress),
-                    Some(TransferJobProgressModel::Finished { .. })
+                    client.transfer_jobs.first().map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Failed { .. })
                 )
             })
             .await;
-        // finished index item should still be Downloaded
-        core_1
+        let job = core_1.client_model(&core_2).transfer_jobs.remove(0);
+        assert!(
+            matches!(
+                job.progress,
+                TransferJobProgressModel::Failed {
+                    reason: TransferErrorReasonModel::ChecksumMismatch,
+                    retryable: true,
+                    ..
+                }
+            ),
+            "job should fail with a retryable ChecksumMismatch reason, got {:?}",
+            job.progress
+        );
+
+        // the received file and its partial file are removed, and it isn't recorded
+        let files = core_1
             .core
-            .refresh_client_index(&core_2.endpoint_id_str())
-            .expect("should refresh client index");
+            .list_downloaded_files(&core_2.endpoint_id_str())
+            .expect("should list files");
+        assert!(files.is_empty());
+        let mut dirs = vec![core_1.download_dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir).expect("should read download directory") {
+                let path = entry.expect("should read entry").path();
+                assert!(path.is_dir(), "file should be removed: {path:?}");
+                dirs.push(path);
+            }
+        }
+    }
+
+    /// Playlists of the synced files are written to the download directory after a sync: one for
+    /// each album directory, and one of the files of the last sync.
+    #[tokio::test]
+    async fn write_playlists_after_sync() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Minimal).await;
+
+        let settings = core_1
+            .core
+            .get_settings_model()
+            .expect("should get settings");
         core_1
-            .wait_for_client_condition(
-                "finished index item is still Downloaded",
-                &core_2,
-                |client| {
-                    client.index.as_ref().unwrap().len() == 2
-                        && matches!(
-                            client
-                                .index
-                                .as_ref()
-                                .unwrap()
-                                .iter()
-                                .find(|i| i.path == finished_job_path)
-                                .unwrap()
-                                .download_status,
-                            Some(IndexItemDownloadStatusModel::Downloaded)
-                        )
+            .core
+            .update_settings(SettingsModel {
+                playlists: PlaylistSettings {
+                    per_album: true,
+                    recently_synced: true,
                 },
-            )
-            .await;
+                ..settings
+            })
+            .expect("should update settings");
 
-        // other job should still be Ready
         core_1
-            .wait_for_client_condition("other job is Ready", &core_2, |client| {
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        core_1
+            .wait_for_client_condition("job is Finished", &core_2, |client| {
                 matches!(
-                    client
-                        .transfer_jobs
-                        .iter()
-                        .find(|j| j.file_path != finished_job_path)
-                        .map(|j| &j.progress),
-                    Some(TransferJobProgressModel::Ready)
+                    client.transfer_jobs.first().map(|j| &j.progress),
+                    Some(TransferJobProgressModel::Finished { .. })
                 )
             })
             .await;
-        // other index item should still be Waiting
-        core_1
+
+        let files = core_1
             .core
-            .refresh_client_index(&core_2.endpoint_id_str())
-            .expect("should refresh client index");
+            .list_downloaded_files(&core_2.endpoint_id_str())
+            .expect("should list files");
+        assert_eq!(files.len(), 1);
+        let local_path = files[0].local_path.replace('\\', "/");
+        let (album_dir, file_name) = local_path
+            .rsplit_once('/')
+            .expect("file should be in a directory");
+
+        // playlists are written in the background after the session is recorded
         core_1
-            .wait_for_client_condition("other index item is Waiting", &core_2, |client| {
-                client.index.as_ref().unwrap().len() == 2
-                    && matches!(
-                        client
-                            .index
-                            .as_ref()
-                            .unwrap()
-                            .iter()
-                            .find(|i| i.path != finished_job_path)
-                            .unwrap()
-                            .download_status,
-                        Some(IndexItemDownloadStatusModel::Waiting)
-                    )
+            .wait_for_node_model_condition("playlists were written", |model| {
+                model.last_playlist_write.is_some()
             })
             .await;
-
-        // resume downloads using SetDownloads
-        let not_finished_download_item = download_items
-            .into_iter()
-            .find(|item| item.path != finished_job_path)
-            .unwrap();
-        core_1
+        let playlist_write = core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), vec![not_finished_download_item])
-            .expect("should set downloads");
+            .get_node_model()
+            .expect("should get node model")
+            .last_playlist_write
+            .expect("should have written playlists");
+        assert_eq!(playlist_write.endpoint_id, core_2.endpoint_id_str());
+        assert_eq!(playlist_write.error, None);
+        let album_playlist = format!("{album_dir}/{album_dir}.m3u8");
+        assert_eq!(
+            playlist_write
+                .playlists
+                .iter()
+                .map(|path| path.replace('\\', "/"))
+                .collect::<Vec<_>>(),
+            vec![album_playlist.clone(), "Recently Synced.m3u8".to_string()]
+        );
 
-        // paused should become false
+        // the album playlist lists the file by name
+        let playlist = std::fs::read_to_string(core_1.download_dir.join(&album_playlist))
+            .expect("should write album playlist");
+        assert!(playlist.starts_with("#EXTM3U\n"));
+        assert!(
+            playlist.lines().any(|line| line == file_name),
+            "album playlist should list the synced file: {playlist:?}"
+        );
+
+        // the recently synced playlist lists the file by its path in the download directory
+        let playlist = std::fs::read_to_string(core_1.download_dir.join("Recently Synced.m3u8"))
+            .expect("should write recently synced playlist");
+        assert!(playlist.starts_with("#EXTM3U\n"));
+        assert!(
+            playlist.lines().any(|line| line == local_path),
+            "playlist should list the synced file: {playlist:?}"
+        );
+    }
+
+    /// The server's library can be browsed by album, and artists and albums can be expanded into
+    /// the files to download.
+    #[tokio::test]
+    async fn browse_and_select_by_artist_and_album() {
+        let fixture = LibraryFixture::Multiple;
+        let (core_1, core_2, _) = prepare_with_index(fixture).await;
+
+        // tags can be read after the index is sent, and are sent to the client as changes
         core_1
-            .wait_for_client_condition("paused is false", &core_2, |client| !client.paused)
+            .wait_for_client_condition("index items have tags", &core_2, |client| {
+                client.index.as_ref().is_some_and(|index| {
+                    index.len() == fixture.num_items()
+                        && index.iter().all(|item| item.artist.is_some())
+                })
+            })
             .await;
 
-        // other job should be Ready
+        let albums = core_1
+            .core
+            .browse_remote_library(&core_2.endpoint_id_str())
+            .await
+            .expect("should browse remote library");
+
+        // one file has an album tag and the other doesn't
+        assert_eq!(albums.len(), 2);
+        assert_eq!(albums[0].artist.as_deref(), Some("8sumint"));
+        assert_eq!(albums[0].album, None);
+        assert_eq!(albums[0].file_count, 1);
+        assert_eq!(albums[1].artist.as_deref(), Some("8sumint"));
+        assert_eq!(albums[1].album.as_deref(), Some("natu.moe"));
+        assert_eq!(albums[1].file_count, 1);
+
+        // index items should have the tags too
         core_1
-            .wait_for_client_condition("other job is Ready", &core_2, |client| {
-                matches!(
-                    client
-                        .transfer_jobs
-                        .iter()
-                        .find(|j| j.file_path != finished_job_path)
-                        .map(|j| &j.progress),
-                    Some(TransferJobProgressModel::Ready)
-                )
+            .wait_for_client_condition("index item has tags", &core_2, |client| {
+                client.index.as_ref().is_some_and(|index| {
+                    index.iter().any(|item| {
+                        item.path == "evolution.mp3"
+                            && item.title.as_deref() == Some("evolution")
+                            && item.artist.as_deref() == Some("8sumint")
+                            && item.album.as_deref() == Some("natu.moe")
+                    })
+                })
             })
             .await;
-        // other index item should be Waiting
-        core_1
+
+        let items = core_1
             .core
-            .refresh_client_index(&core_2.endpoint_id_str())
-            .expect("should refresh client index");
+            .expand_download_selection(
+                &core_2.endpoint_id_str(),
+                vec![DownloadSelectionModel::Artist {
+                    artist: "8sumint".into(),
+                }],
+            )
+            .await
+            .expect("should expand artist selection");
+        assert_eq!(items.len(), 2);
+
+        let items = core_1
+            .core
+            .expand_download_selection(
+                &core_2.endpoint_id_str(),
+                vec![DownloadSelectionModel::Album {
+                    artist: None,
+                    album: "natu.moe".into(),
+                }],
+            )
+            .await
+            .expect("should expand album selection");
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].path, "evolution.mp3");
+    }
+
+    /// Downloads are laid out by their tags when a download path template is set, with fallbacks
+    /// for missing tags.
+    #[tokio::test]
+    async fn download_path_template() {
+        let fixture = LibraryFixture::Multiple;
+        let (core_1, core_2, _) = prepare_with_index(fixture).await;
+
+        // tags can be read after the index is sent, and are sent to the client as changes
         core_1
-            .wait_for_client_condition("other index item is Waiting", &core_2, |client| {
-                client.index.as_ref().unwrap().len() == 2
-                    && matches!(
-                        client
-                            .index
-                            .as_ref()
-                            .unwrap()
-                            .iter()
-                            .find(|i| i.path != finished_job_path)
-                            .unwrap()
-                            .download_status,
-                        Some(IndexItemDownloadStatusModel::Waiting)
-                    )
+            .wait_for_client_condition("index items have tags", &core_2, |client| {
+                client.index.as_ref().is_some_and(|index| {
+                    index.len() == fixture.num_items()
+                        && index.iter().all(|item| item.artist.is_some())
+                })
             })
             .await;
 
-        // allow second item to download
-        core_1.test_hooks.add_download_permits(1);
+        // unknown fields should be rejected
+        assert!(
+            core_1
+                .core
+                .set_download_path_template(Some("{genre}/{title}".into()))
+                .is_err()
+        );
 
-        // other job should reach Finished
         core_1
-            .wait_for_client_condition("other job is Finished", &core_2, |client| {
-                matches!(
-                    client
+            .core
+            .set_download_path_template(Some("{artist}/{album}/{title}".into()))
+            .expect("should set download path template");
+
+        let download_items = core_1
+            .client_model(&core_2)
+            .index
+            .unwrap()
+            .into_iter()
+            .map(|item| DownloadRequestModel {
+                endpoint_id: item.endpoint_id.clone(),
+                root: item.root.clone(),
+                path: item.path.clone(),
+            })
+            .collect::<Vec<_>>();
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
+
+        core_1
+            .wait_for_client_condition("all jobs are Finished", &core_2, |client| {
+                client.transfer_jobs.len() == fixture.num_items()
+                    && client
                         .transfer_jobs
                         .iter()
-                        .find(|j| j.file_path != finished_job_path)
-                        .map(|j| &j.progress),
-                    Some(TransferJobProgressModel::Finished { .. })
-                )
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
             })
             .await;
-        // other index item should reach Downloaded
+
+        // the tagged file should be under its album, with the transcoded extension
+        let artist_dir = core_1.download_dir.join("8sumint");
+        assert!(artist_dir.join("natu.moe/evolution.ogg").exists());
+
+        // the file without an album tag should use the fallback
+        let unknown_album_files = std::fs::read_dir(artist_dir.join("Unknown Album"))
+            .expect("should read fallback album dir")
+            .count();
+        assert_eq!(unknown_album_files, 1);
+    }
+
+    /// Files that aren't shared with a client aren't in its index, and can't be downloaded.
+    #[tokio::test]
+    async fn node_shares() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
+
+        // core 2: only share one file with core 1
+        core_2
+            .core
+            .set_node_shares(
+                &core_1.endpoint_id_str(),
+                vec![NodeShareModel {
+                    root: "foo".into(),
+                    path_prefix: "evolution.mp3".into(),
+                }],
+            )
+            .expect("should set node shares");
+
+        // index should only have the shared file
+        reconnect(&core_1, &core_2, 1).await;
+        let index = core_1.client_model(&core_2).index.unwrap();
+        assert_eq!(index[0].path, "evolution.mp3");
+
+        // request both files, only the shared file should download
         core_1
             .core
-            .refresh_client_index(&core_2.endpoint_id_str())
-            .expect("should refresh client index");
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
+            .expect("should set downloads");
         core_1
-            .wait_for_client_condition("other index item is Downloaded", &core_2, |client| {
-                client.index.as_ref().unwrap().len() == 2
-                    && matches!(
-                        client
-                            .index
-                            .as_ref()
-                            .unwrap()
-                            .iter()
-                            .find(|i| i.path != finished_job_path)
-                            .unwrap()
-                            .download_status,
-                        Some(IndexItemDownloadStatusModel::Downloaded)
-                    )
-            })
+            .wait_for_client_condition(
+                "shared job is Finished, other job is Failed",
+                &core_2,
+                |client| {
+                    client.transfer_jobs.len() == 2
+                        && client.transfer_jobs.iter().all(|j| {
+                            if j.file_path == "evolution.mp3" {
+                                matches!(j.progress, TransferJobProgressModel::Finished { .. })
+                            } else {
+                                matches!(j.progress, TransferJobProgressModel::Failed { .. })
+                            }
+                        })
+                },
+            )
             .await;
+
+        // core 2: share everything again
+        core_2
+            .core
+            .set_node_shares(&core_1.endpoint_id_str(), Vec::new())
+            .expect("should set node shares");
+        reconnect(&core_1, &core_2, 2).await;
     }
 
-    /// Test set downloads with the same items while waiting:
-    /// - Request both items
-    /// - Both jobs should be Ready
-    /// - Request both items again
-    /// - Both jobs should still be Ready (only 2 jobs)
+    /// Files excluded by a client's share filter aren't in its index, and can't be downloaded.
     #[tokio::test]
-    async fn set_same_downloads_while_waiting() {
+    async fn share_filter() {
         let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
-        core_1.test_hooks.enable_download_gate();
 
-        // request both items
-        core_1
+        // core 2: don't share mp3s with core 1
+        core_2
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
-            .expect("should set downloads");
+            .set_share_filter(
+                &core_1.endpoint_id_str(),
+                Some(ShareFilterModel {
+                    max_file_size: None,
+                    max_duration: None,
+                    excluded_formats: vec![".MP3".into()],
+                }),
+            )
+            .expect("should set share filter");
 
-        // should have two Ready jobs
-        core_1
-            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
-            })
-            .await;
+        // index should be empty
+        reconnect(&core_1, &core_2, 0).await;
+
+        // requested files shouldn't be queued, since they aren't in the index
+        let result = core_1
+            .core
+            .download_and_wait(&core_2.endpoint_id_str(), download_items, None)
+            .await
+            .expect("should download");
+        assert_eq!(result.completed_files, 0);
+        assert_eq!(result.failed_files, 0);
+        assert!(core_1.client_model(&core_2).transfer_jobs.is_empty());
+
+        // core 2: remove the filter
+        core_2
+            .core
+            .set_share_filter(&core_1.endpoint_id_str(), None)
+            .expect("should set share filter");
+        reconnect(&core_1, &core_2, 2).await;
+    }
+
+    /// Downloading all requested items completes the session, which sends an event.
+    #[tokio::test]
+    async fn session_completed_event() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
 
-        // request both items again
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
             .expect("should set downloads");
 
-        // should still have two Ready jobs
-        core_1
-            .wait_for_client_condition("still has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
-            })
-            .await;
+        core_1.wait_for_sessions_completed(1).await;
+        let event = core_1.event_handler.sessions_completed.lock().unwrap()[0].clone();
+        assert_eq!(event.endpoint_id, core_2.endpoint_id_str());
+        assert_eq!(event.completed_files, 2);
+        assert_eq!(event.failed_files, 0);
     }
 
-    /// Test set downloads with the same items while paused:
-    /// - Request both items
-    /// - Both jobs should be Ready
-    /// - Pause downloads
-    /// - Paused should become true
-    /// - Both jobs should be Ready
-    /// - Request both items again
-    /// - Paused should become false
-    /// - Both jobs should still be Ready (only 2 jobs)
+    /// In the background, compact progress is sent when the transfers start and finish.
     #[tokio::test]
-    async fn set_same_downloads_while_paused() {
+    async fn background_progress() {
         let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
-        core_1.test_hooks.enable_download_gate();
 
-        // request both items
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .enter_background()
+            .expect("should enter background");
+        core_1
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
             .expect("should set downloads");
 
-        // should have two Ready jobs
-        core_1
-            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
-            })
-            .await;
+        core_1.wait_for_sessions_completed(1).await;
+        let progress = core_1
+            .event_handler
+            .background_progress
+            .lock()
+            .unwrap()
+            .clone();
+        assert!(
+            !progress[0].active,
+            "should be sent right away, before any transfers"
+        );
+        let started = progress
+            .iter()
+            .find(|p| p.active)
+            .expect("should send progress while downloading");
+        assert_eq!(started.total_files, 2);
+        let last = progress.last().unwrap();
+        assert!(!last.active);
+        assert_eq!(last.title, "Transfers finished");
 
-        // pause downloads
         core_1
             .core
-            .pause_downloads(&core_2.endpoint_id_str())
-            .expect("should pause downloads");
+            .enter_foreground()
+            .expect("should enter foreground");
+    }
 
-        // paused should become true
+    /// With model diffs, entering the foreground resyncs the transfer jobs that changed in the
+    /// background with a snapshot.
+    #[tokio::test]
+    async fn background_resync_with_model_diffs() {
+        let fixture = LibraryFixture::Multiple;
+        let (core_1, core_2) = prepare_with_model_diffs(fixture, true).await;
         core_1
-            .wait_for_client_condition("paused is true", &core_2, |client| client.paused)
+            .wait_for_client_condition("index is complete", &core_2, |client| client.index_complete)
             .await;
 
-        // should have two Ready jobs
+        let VersionedNodeModel { version, mut model } = core_1
+            .core
+            .get_node_model_versioned()
+            .expect("should get node model");
+
+        // core 1: download all files in the background
         core_1
-            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            .core
+            .enter_background()
+            .expect("should enter background");
+        let download_items = model.clients[&core_2.endpoint_id_str()]
+            .index
+            .as_ref()
+            .expect("should have index")
+            .iter()
+            .map(|item| DownloadRequestModel {
+                endpoint_id: item.endpoint_id.clone(),
+                root: item.root.clone(),
+                path: item.path.clone(),
             })
-            .await;
-
-        // request both items again
+            .collect::<Vec<_>>();
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
             .expect("should set downloads");
+        core_1.wait_for_sessions_completed(1).await;
 
-        // paused should become false
         core_1
-            .wait_for_client_condition("paused is false", &core_2, |client| !client.paused)
-            .await;
-
-        // should still have two Ready jobs
+            .core
+            .enter_foreground()
+            .expect("should enter foreground");
         core_1
-            .wait_for_client_condition("still has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            .wait_for_node_model_diff("patch is Snapshot", |diff| {
+                matches!(diff.patch, NodeModelPatch::Snapshot { .. })
             })
             .await;
+
+        // applying the diffs after the snapshot should catch up on the jobs
+        for diff in core_1.event_handler.node_model_diffs.lock().unwrap().iter() {
+            if diff.version > version {
+                model.apply_patch(diff.patch.clone());
+            }
+        }
+        let jobs = &model.clients[&core_2.endpoint_id_str()].transfer_jobs;
+        assert_eq!(jobs.len(), fixture.num_items());
+        assert!(
+            jobs.iter()
+                .all(|job| matches!(job.progress, TransferJobProgressModel::Finished { .. })),
+            "jobs should be Finished: {jobs:?}"
+        );
     }
 
-    /// Test set downloads with the same items while finished:
-    /// - Request both items
-    /// - Both jobs should be Ready
-    /// - Allow both to download
-    /// - Both jobs should reach Finished
-    /// - Request both items again
-    /// - Both jobs should still be Finished (only 2 jobs)
+    /// The status summary counts connections and transfers without the full models.
     #[tokio::test]
-    async fn set_same_downloads_while_finished() {
+    async fn status_summary() {
         let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
-        core_1.test_hooks.enable_download_gate();
 
-        // request both items
+        let status = core_2.core.get_status().expect("should get status");
+        assert_eq!(status.endpoint_id, core_2.endpoint_id_str());
+        assert_eq!(status.library_files, 2);
+        assert!(!status.is_scanning);
+        assert_eq!(status.open_connections, 1);
+
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .set_downloads(&core_2.endpoint_id_str(), download_items)
             .expect("should set downloads");
+        core_1.wait_for_sessions_completed(1).await;
 
-        // should have two Ready jobs
-        core_1
-            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
-            })
-            .await;
+        let status = core_1.core.get_status().expect("should get status");
+        assert_eq!(status.open_connections, 1);
+        assert_eq!(status.active_downloads, 0);
+        assert_eq!(status.queued_downloads, 0);
+        assert!(status.recent_errors.is_empty());
+    }
 
-        // allow both to download
-        core_1.test_hooks.add_download_permits(2);
+    /// Records the progress of an awaited operation.
+    #[derive(Default)]
+    struct ProgressRecorder(Mutex<Vec<OperationProgress>>);
 
-        // both jobs should reach Finished
-        core_1
-            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
-            })
-            .await;
+    impl OperationProgressHandler for ProgressRecorder {
+        fn on_progress(&self, progress: OperationProgress) {
+            self.0.lock().unwrap().push(progress);
+        }
+    }
 
-        // request both items again
+    /// Scans, connections, and downloads can be awaited instead of watching the models.
+    #[tokio::test]
+    async fn await_operations() {
+        let fixture = LibraryFixture::Multiple;
+        let core_1 = TestCore::start("core 1").await;
+        let core_2 = TestCore::start("core 2").await;
+
+        std::fs::create_dir_all(&core_1.download_dir).expect("should create download dir");
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
-            .expect("should set downloads");
+            .set_download_directory(&core_1.download_dir.to_string_lossy())
+            .expect("should set download directory");
 
-        // should still have two Finished jobs
+        // core 2: the scan sees the new root
+        core_2
+            .core
+            .add_library_root("foo".into(), fixture.path().to_string_lossy().to_string())
+            .expect("should add library root");
+        let scan = core_2
+            .core
+            .rescan_library_and_wait(None)
+            .await
+            .expect("should rescan");
+        assert_eq!(scan.library_files, fixture.num_items() as u64);
+
+        // core 1: downloading without a connection fails right away
+        let err = core_1
+            .core
+            .download_and_wait(&core_2.endpoint_id_str(), Vec::new(), None)
+            .await
+            .expect_err("should fail without a connection");
+        assert!(matches!(
+            err.kind(),
+            CoreErrorKind::Connection {
+                error: ConnectionError::NotConnected { .. }
+            }
+        ));
+
+        // core 1: connect to core 2, which trusts it
+        core_2
+            .core
+            .trust_node(&core_1.endpoint_id_str())
+            .expect("should trust");
+        core_1.discover(&core_2).await;
+        let progress = Arc::new(ProgressRecorder::default());
         core_1
-            .wait_for_client_condition("still has 2 Finished jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            .core
+            .connect_and_wait(
+                Some(TranscodeFormat::Opus128),
+                &core_2.endpoint_id_str(),
+                Some(progress.clone()),
+            )
+            .await
+            .expect("should connect");
+        let index = core_1
+            .client_model(&core_2)
+            .index
+            .expect("should have index when connected");
+        assert_eq!(index.len(), fixture.num_items());
+
+        // core 1: download everything
+        let download_items = index
+            .into_iter()
+            .map(|item| DownloadRequestModel {
+                endpoint_id: item.endpoint_id,
+                root: item.root,
+                path: item.path,
             })
-            .await;
+            .collect::<Vec<_>>();
+        let result = core_1
+            .core
+            .download_and_wait(
+                &core_2.endpoint_id_str(),
+                download_items.clone(),
+                Some(progress.clone()),
+            )
+            .await
+            .expect("should download");
+        assert_eq!(result.completed_files, 2);
+        assert_eq!(result.failed_files, 0);
+
+        let progress = progress.0.lock().unwrap().clone();
+        assert!(matches!(progress[0], OperationProgress::ConnectionPending));
+        assert!(matches!(
+            progress[1],
+            OperationProgress::DownloadsQueued { queued_files: 2 }
+        ));
+
+        // core 1: files that are already downloaded finish right away
+        let result = core_1
+            .core
+            .download_and_wait(&core_2.endpoint_id_str(), download_items, None)
+            .await
+            .expect("should download");
+        assert_eq!(result.completed_files, 0);
     }
 
-    /// Test adding items while waiting using SetDownloads:
-    /// - Request first item
-    /// - Should be one Ready job
-    /// - Request second item
-    /// - Should be two Ready jobs
-    /// - Allow both to download
-    /// - Both jobs should reach Finished
+    /// Awaited downloads only wait for and count the files they queued, not other downloads from
+    /// the same server.
     #[tokio::test]
-    async fn add_items_while_waiting() {
+    async fn download_and_wait_own_files() {
         let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
-        core_1.test_hooks.enable_download_gate();
 
-        // request first item
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
+            .set_downloads(&core_2.endpoint_id_str(), download_items[..1].to_vec())
             .expect("should set downloads");
+        let result = core_1
+            .core
+            .download_and_wait(
+                &core_2.endpoint_id_str(),
+                download_items[1..].to_vec(),
+                None,
+            )
+            .await
+            .expect("should download");
+        assert_eq!(result.completed_files, 1);
+        assert_eq!(result.failed_files, 0);
+    }
 
-        // should have one Ready job
-        core_1
-            .wait_for_client_condition("has 1 Ready job", &core_2, |client| {
-                client.transfer_jobs.len() == 1
-                    && matches!(
-                        client.transfer_jobs.first().map(|j| &j.progress),
-                        Some(TransferJobProgressModel::Ready)
-                    )
-            })
-            .await;
+    /// Completed sessions are kept in the transfer history with only their own files, and pruned
+    /// by the retention.
+    #[tokio::test]
+    async fn transfer_history() {
+        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
 
-        // request second item
+        // two sessions, one file each
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
+            .set_downloads(&core_2.endpoint_id_str(), download_items[..1].to_vec())
             .expect("should set downloads");
-
-        // should have two Ready jobs
+        core_1.wait_for_sessions_completed(1).await;
         core_1
-            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
-            })
-            .await;
+            .core
+            .set_downloads(&core_2.endpoint_id_str(), download_items[1..].to_vec())
+            .expect("should set downloads");
+        core_1.wait_for_sessions_completed(2).await;
+
+        // the session is recorded after the event is sent
+        let mut sessions = Vec::new();
+        for _ in 0..20 {
+            sessions = core_1
+                .core
+                .get_transfer_history(Some(core_2.endpoint_id_str()), 10)
+                .expect("should get transfer history");
+            if sessions.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert_eq!(sessions.len(), 2);
+        for session in &sessions {
+            assert_eq!(session.endpoint_id, core_2.endpoint_id_str());
+            assert_eq!(session.completed_files, 1);
+            assert_eq!(session.failed_files, 0);
 
-        // allow both to download
-        core_1.test_hooks.add_download_permits(2);
+            let files = core_1
+                .core
+                .get_transfer_session_files(session.id)
+                .expect("should get transfer session files");
+            assert_eq!(files.len(), 1);
+            assert!(files.iter().all(|file| file.error.is_none()));
+        }
 
-        // both jobs should reach Finished
+        // the server has no download history
+        let sessions = core_2
+            .core
+            .get_transfer_history(None, 10)
+            .expect("should get transfer history");
+        assert!(sessions.is_empty());
+
+        // a retention of 0 days prunes everything that finished before now
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
         core_1
-            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
-            })
-            .await;
+            .core
+            .set_transfer_history_retention(Some(0))
+            .expect("should set retention");
+        assert_eq!(
+            core_1
+                .core
+                .get_settings_model()
+                .expect("should get settings")
+                .transfer_history_retention_days,
+            Some(0)
+        );
+        for _ in 0..20 {
+            let sessions = core_1
+                .core
+                .get_transfer_history(None, 10)
+                .expect("should get transfer history");
+            if sessions.is_empty() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("transfer history should be pruned");
     }
 
-    /// Test adding items while paused using SetDownloads:
-    /// - Request first item
-    /// - Should be one Ready job
-    /// - Pause downloads
-    /// - Paused should become true
-    /// - Should be one Ready job
-    /// - Request second item (should unpause)
-    /// - Paused should become false
-    /// - Should be two Ready jobs
-    /// - Allow both to download
-    /// - Both jobs should reach Finished
+    /// Test draining a client:
+    /// - TestHooks: allow only 1st item
+    /// - 1st job should reach Finished
+    /// - Drain the client
+    /// - Client should close without starting the 2nd job
     #[tokio::test]
-    async fn add_items_while_paused() {
+    async fn drain_client() {
         let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
         core_1.test_hooks.enable_download_gate();
 
-        // request first item
+        // request both items
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
+            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
             .expect("should set downloads");
 
-        // should have one Ready job
+        // allow one item
+        core_1.test_hooks.add_download_permits(1);
         core_1
-            .wait_for_client_condition("has 1 Ready job", &core_2, |client| {
-                client.transfer_jobs.len() == 1
-                    && matches!(
-                        client.transfer_jobs.first().map(|j| &j.progress),
-                        Some(TransferJobProgressModel::Ready)
-                    )
+            .wait_for_client_condition("one job is Finished", &core_2, |client| {
+                client
+                    .transfer_jobs
+                    .iter()
+                    .any(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
             })
             .await;
 
-        // pause downloads
+        // drain the client, which should close since nothing is downloading
         core_1
             .core
-            .pause_downloads(&core_2.endpoint_id_str())
-            .expect("should pause downloads");
+            .drain_client(&core_2.endpoint_id_str(), 30)
+            .expect("should drain client");
+        core_1.test_hooks.add_download_permits(1);
+        core_1.wait_for_client_closed(&core_2).await;
 
-        // paused should become true
-        core_1
-            .wait_for_client_condition("paused is true", &core_2, |client| client.paused)
-            .await;
+        // the other job should not have started
+        let client = core_1.client_model(&core_2);
+        assert!(client.draining);
+        assert_eq!(
+            client
+                .transfer_jobs
+                .iter()
+                .filter(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+                .count(),
+            1
+        );
+    }
 
-        // should have one Ready job
-        core_1
-            .wait_for_client_condition("has 1 Ready job", &core_2, |client| {
-                client.transfer_jobs.len() == 1
-                    && matches!(
-                        client.transfer_jobs.first().map(|j| &j.progress),
-                        Some(TransferJobProgressModel::Ready)
-                    )
-            })
+    /// Test draining a server without active transfers, which should close the connection.
+    #[tokio::test]
+    async fn drain_server() {
+        let (core_1, core_2) = prepare(LibraryFixture::Multiple).await;
+
+        // drain the server
+        core_2
+            .core
+            .drain_server(&core_1.endpoint_id_str(), 30)
+            .expect("should drain server");
+        core_2
+            .wait_for_server_condition("server is draining", &core_1, |server| server.draining)
             .await;
 
-        // request second item (should unpause)
+        // both connection ends should close
+        core_2.wait_for_server_closed(&core_1).await;
+        core_1.wait_for_client_closed(&core_2).await;
+    }
+}
+
+mod sync {
+    use crate::common::{LibraryFixture, TestCore, TestEndpointIdExt};
+    use musicopy::{
+        node::{
+            AutoDownloadModel, DownloadFilterModel, DownloadRequestModel, TransferJobProgressModel,
+        },
+        protocol::SyncConflictPolicy,
+    };
+
+    /// Sets up a download directory and a library root with the fixture files.
+    async fn prepare_core(label: &str, fixture: LibraryFixture) -> TestCore {
+        let core = TestCore::start(label).await;
+
+        std::fs::create_dir_all(&core.download_dir).expect("should create download dir");
+        core.core
+            .set_download_directory(&core.download_dir.to_string_lossy())
+            .expect("should set download directory");
+
+        core.core
+            .add_library_root("foo".into(), fixture.path().to_string_lossy().to_string())
+            .expect("should add library root");
+        core.wait_for_library_model_condition("root has files", |model| {
+            model
+                .local_roots
+                .first()
+                .is_some_and(|root| root.num_files == fixture.num_items() as u64)
+        })
+        .await;
+
+        core
+    }
+
+    /// Each side of a two-way sync downloads the files the other has that it lacks.
+    #[tokio::test]
+    async fn two_way_sync() {
+        let core_1 = prepare_core("core 1", LibraryFixture::Minimal).await;
+        let core_2 = prepare_core("core 2", LibraryFixture::Multiple).await;
+
+        // trust each other so both connections are accepted automatically
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
-            .expect("should set downloads");
+            .trust_node(&core_2.endpoint_id_str())
+            .expect("should trust node");
+        core_2
+            .core
+            .trust_node(&core_1.endpoint_id_str())
+            .expect("should trust node");
 
-        // paused should become false
+        // connect without transcoding
+        core_1.discover(&core_2).await;
         core_1
-            .wait_for_client_condition("paused is false", &core_2, |client| !client.paused)
-            .await;
+            .core
+            .connect(None, &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_1.wait_for_client_accepted(&core_2).await;
 
-        // should have two Ready jobs
         core_1
-            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
-            })
-            .await;
-
-        // allow both to download
-        core_1.test_hooks.add_download_permits(2);
+            .core
+            .two_way_sync(&core_2.endpoint_id_str(), SyncConflictPolicy::KeepLocal)
+            .expect("should start two-way sync");
 
-        // both jobs should reach Finished
+        // core 1 should download both files from core 2
         core_1
             .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
                 client.transfer_jobs.len() == 2
@@ -1450,90 +5726,146 @@ mod transfer {
                         .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
             })
             .await;
+
+        // core 2 should connect back and download the file from core 1
+        core_2
+            .wait_for_client_condition("job is Finished", &core_1, |client| {
+                client.transfer_jobs.len() == 1
+                    && matches!(
+                        client.transfer_jobs.first().map(|j| &j.progress),
+                        Some(TransferJobProgressModel::Finished { .. })
+                    )
+            })
+            .await;
     }
 
-    /// Test adding items while finished using SetDownloads:
-    /// - Request first item
-    /// - Should be one Ready job
-    /// - Allow it to download
-    /// - Should reach Finished
-    /// - Request second item
-    /// - Should be one Ready job
-    /// - Allow it to download
-    /// - Should reach Finished
+    /// A server can push files to a trusted client, which downloads them.
     #[tokio::test]
-    async fn add_items_while_finished() {
-        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
-        core_1.test_hooks.enable_download_gate();
+    async fn push_files() {
+        let core_1 = prepare_core("core 1", LibraryFixture::Minimal).await;
+        let core_2 = prepare_core("core 2", LibraryFixture::Multiple).await;
 
-        // request first item
+        // trust each other so the connection is accepted automatically and the push is honored
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
-            .expect("should set downloads");
+            .trust_node(&core_2.endpoint_id_str())
+            .expect("should trust node");
+        core_2
+            .core
+            .trust_node(&core_1.endpoint_id_str())
+            .expect("should trust node");
 
-        // should have one Ready job
+        // core 1: connect to core 2
+        core_1.discover(&core_2).await;
         core_1
-            .wait_for_client_condition("has 1 Ready job", &core_2, |client| {
-                client.transfer_jobs.len() == 1
-                    && matches!(
-                        client.transfer_jobs.first().map(|j| &j.progress),
-                        Some(TransferJobProgressModel::Ready)
-                    )
+            .core
+            .connect(None, &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_1.wait_for_client_accepted(&core_2).await;
+        core_2.wait_for_server_accepted(&core_1).await;
+        core_1
+            .wait_for_client_condition("index has items", &core_2, |client| {
+                client.index.as_ref().is_some_and(|idx| idx.len() == 2)
             })
             .await;
 
-        // allow first item to download
-        core_1.test_hooks.add_download_permits(1);
+        // core 2: push one file to core 1
+        core_2
+            .core
+            .push_files(
+                &core_1.endpoint_id_str(),
+                vec![DownloadRequestModel {
+                    endpoint_id: core_2.endpoint_id_str(),
+                    root: "foo".into(),
+                    path: "evolution.mp3".into(),
+                }],
+            )
+            .expect("should push files");
 
-        // should reach Finished
+        // core 1 should download the pushed file
         core_1
-            .wait_for_client_condition("job is Finished", &core_2, |client| {
+            .wait_for_client_condition("pushed job is Finished", &core_2, |client| {
                 client.transfer_jobs.len() == 1
-                    && matches!(
-                        client.transfer_jobs.first().map(|j| &j.progress),
-                        Some(TransferJobProgressModel::Finished { .. })
-                    )
+                    && client.transfer_jobs.iter().all(|j| {
+                        j.file_path == "evolution.mp3"
+                            && matches!(j.progress, TransferJobProgressModel::Finished { .. })
+                    })
             })
             .await;
+    }
 
-        // request second item
+    /// A server records which files it sent to a trusted client, and can push only the rest.
+    #[tokio::test]
+    async fn push_missing_files() {
+        let core_1 = prepare_core("core 1", LibraryFixture::Minimal).await;
+        let core_2 = prepare_core("core 2", LibraryFixture::Multiple).await;
+
+        // trust each other so the connection is accepted automatically and the push is honored
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[1].clone()])
-            .expect("should set downloads");
+            .trust_node(&core_2.endpoint_id_str())
+            .expect("should trust node");
+        core_2
+            .core
+            .trust_node(&core_1.endpoint_id_str())
+            .expect("should trust node");
 
-        // first job should still be Finished, second should be Ready
+        // core 1: connect to core 2
+        core_1.discover(&core_2).await;
         core_1
-            .wait_for_client_condition(
-                "first job is Finished, second job is Ready",
-                &core_2,
-                |client| {
-                    client.transfer_jobs.len() == 2
-                        && matches!(
-                            client
-                                .transfer_jobs
-                                .iter()
-                                .find(|j| j.job_id == 0)
-                                .map(|j| &j.progress),
-                            Some(TransferJobProgressModel::Finished { .. })
-                        )
-                        && matches!(
-                            client
-                                .transfer_jobs
-                                .iter()
-                                .find(|j| j.job_id == 1)
-                                .map(|j| &j.progress),
-                            Some(TransferJobProgressModel::Ready)
-                        )
-                },
+            .core
+            .connect(None, &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+        core_1.wait_for_client_accepted(&core_2).await;
+        core_2.wait_for_server_accepted(&core_1).await;
+        core_1
+            .wait_for_client_condition("index has items", &core_2, |client| {
+                client.index.as_ref().is_some_and(|idx| idx.len() == 2)
+            })
+            .await;
+
+        // core 2: push one file to core 1
+        core_2
+            .core
+            .push_files(
+                &core_1.endpoint_id_str(),
+                vec![DownloadRequestModel {
+                    endpoint_id: core_2.endpoint_id_str(),
+                    root: "foo".into(),
+                    path: "evolution.mp3".into(),
+                }],
             )
+            .expect("should push files");
+        core_1
+            .wait_for_client_condition("pushed job is Finished", &core_2, |client| {
+                client.transfer_jobs.len() == 1
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            })
             .await;
 
-        // allow second item to download
-        core_1.test_hooks.add_download_permits(1);
+        // core 2 should know that core 1 has one of its two files
+        let core_1_endpoint_id = core_1.endpoint_id_str();
+        core_2
+            .wait_for_node_model_condition("core 1 has 1 of 2 files", |model| {
+                model.trusted_nodes.iter().any(|node| {
+                    node.endpoint_id == core_1_endpoint_id
+                        && node.library_coverage.as_ref().is_some_and(|coverage| {
+                            coverage.delivered_files == 1 && coverage.library_files == 2
+                        })
+                })
+            })
+            .await;
 
-        // both jobs should reach Finished
+        // core 2: push the rest, which should only be the other file
+        core_2
+            .core
+            .push_missing_files(&core_1.endpoint_id_str())
+            .expect("should push missing files");
         core_1
             .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
                 client.transfer_jobs.len() == 2
@@ -1543,182 +5875,219 @@ mod transfer {
                         .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
             })
             .await;
+        core_2
+            .wait_for_node_model_condition("core 1 has 2 of 2 files", |model| {
+                model.trusted_nodes.iter().any(|node| {
+                    node.endpoint_id == core_1_endpoint_id
+                        && node.library_coverage.as_ref().is_some_and(|coverage| {
+                            coverage.delivered_files == 2 && coverage.library_files == 2
+                        })
+                })
+            })
+            .await;
     }
 
-    /// Test removing items while waiting:
-    /// - Request both items
-    /// - Should be two Ready jobs
-    /// - Request only first item
-    /// - Should still be two jobs, since we only remove jobs when paused
+    /// A client with auto-download enabled for a trusted server downloads its files when it
+    /// connects.
     #[tokio::test]
-    async fn remove_items_while_waiting() {
-        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
-        core_1.test_hooks.enable_download_gate();
+    async fn auto_download() {
+        let core_1 = prepare_core("core 1", LibraryFixture::Minimal).await;
+        let core_2 = prepare_core("core 2", LibraryFixture::Multiple).await;
 
-        // request both items
+        // core 1: trust core 2 and enable auto-download from it
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
-            .expect("should set downloads");
-
-        // should have two Ready jobs
+            .trust_node(&core_2.endpoint_id_str())
+            .expect("should trust node");
         core_1
-            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            .core
+            .set_auto_download(
+                &core_2.endpoint_id_str(),
+                Some(AutoDownloadModel {
+                    selections: Vec::new(),
+                    max_bytes: None,
+                }),
+            )
+            .expect("should set auto download");
+        core_1
+            .wait_for_node_model_condition("trusted node has auto download", |model| {
+                model
+                    .trusted_nodes
+                    .iter()
+                    .any(|node| node.auto_download.is_some())
             })
             .await;
 
-        // request only first item
+        // core 2: trust core 1 so the connection is accepted automatically
+        core_2
+            .core
+            .trust_node(&core_1.endpoint_id_str())
+            .expect("should trust node");
+
+        // core 1: connect to core 2
+        core_1.discover(&core_2).await;
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
-            .expect("should set downloads");
+            .connect(None, &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
 
-        // should still have two jobs, since we only remove jobs when paused
+        // core 1 should download both files without requesting them
         core_1
-            .wait_for_client_condition("still has 2 jobs", &core_2, |client| {
+            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
                 client.transfer_jobs.len() == 2
+                    && client
+                        .transfer_jobs
+                        .iter()
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
             })
             .await;
     }
 
-    /// Test removing items while paused:
-    /// - Request both items
-    /// - Should be two Ready jobs
-    /// - Pause downloads
-    /// - Paused should become true
-    /// - Should be two Ready jobs
-    /// - Request only first item (should unpause and remove second item)
-    /// - Paused should become false
-    /// - Should be only one Ready job for the first item
+    /// Auto-download skips files that don't fit in the storage quota for the server, and reports
+    /// them.
     #[tokio::test]
-    async fn remove_items_while_paused() {
-        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
-        core_1.test_hooks.enable_download_gate();
+    async fn storage_quota() {
+        let core_1 = prepare_core("core 1", LibraryFixture::Minimal).await;
+        let core_2 = prepare_core("core 2", LibraryFixture::Multiple).await;
 
-        // request both items
+        // core 1: trust core 2 and auto-download with a quota that nothing fits in
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
-            .expect("should set downloads");
-
-        // should have two Ready jobs
+            .trust_node(&core_2.endpoint_id_str())
+            .expect("should trust node");
         core_1
-            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            .core
+            .set_auto_download(
+                &core_2.endpoint_id_str(),
+                Some(AutoDownloadModel {
+                    selections: Vec::new(),
+                    max_bytes: None,
+                }),
+            )
+            .expect("should set auto download");
+        core_1
+            .core
+            .set_storage_quota(&core_2.endpoint_id_str(), Some(1))
+            .expect("should set storage quota");
+        core_1
+            .wait_for_node_model_condition("trusted node has storage quota", |model| {
+                model
+                    .trusted_nodes
+                    .iter()
+                    .any(|node| node.storage_quota == Some(1))
             })
             .await;
 
-        // pause downloads
+        core_2
+            .core
+            .trust_node(&core_1.endpoint_id_str())
+            .expect("should trust node");
+
+        core_1.discover(&core_2).await;
         core_1
             .core
-            .pause_downloads(&core_2.endpoint_id_str())
-            .expect("should pause downloads");
+            .connect(None, &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
 
-        // paused should become true
+        // both files should be skipped
         core_1
-            .wait_for_client_condition("paused is true", &core_2, |client| client.paused)
+            .wait_for_client_condition("both files skipped by quota", &core_2, |client| {
+                client
+                    .storage_quota
+                    .as_ref()
+                    .is_some_and(|quota| quota.skipped_items == 2 && quota.used_bytes == 0)
+            })
             .await;
+        assert!(core_1.client_model(&core_2).transfer_jobs.is_empty());
 
-        // should have two Ready jobs
+        // removing the quota should download them
         core_1
-            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
+            .core
+            .set_storage_quota(&core_2.endpoint_id_str(), None)
+            .expect("should set storage quota");
+        core_1
+            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
+                client.storage_quota.is_none()
+                    && client.transfer_jobs.len() == 2
                     && client
                         .transfer_jobs
                         .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
             })
             .await;
+    }
 
-        // request only first item
-        core_1
-            .core
-            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
-            .expect("should set downloads");
+    /// Auto-download skips files excluded by the download filter for the server, and they're
+    /// marked as filtered in its index.
+    #[tokio::test]
+    async fn download_filter() {
+        let core_1 = prepare_core("core 1", LibraryFixture::Minimal).await;
+        let core_2 = prepare_core("core 2", LibraryFixture::Multiple).await;
 
-        // paused should become false
+        // core 1: trust core 2 and auto-download with a filter that excludes everything
         core_1
-            .wait_for_client_condition("paused is false", &core_2, |client| !client.paused)
-            .await;
-
-        // should be only one Ready job for the first item
+            .core
+            .trust_node(&core_2.endpoint_id_str())
+            .expect("should trust node");
         core_1
-            .wait_for_client_condition(
-                "only has 1 Ready job for the first item",
-                &core_2,
-                |client| {
-                    client.transfer_jobs.len() == 1
-                        && matches!(
-                            client.transfer_jobs.first().map(|j| &j.progress),
-                            Some(TransferJobProgressModel::Ready)
-                        )
-                        && client.transfer_jobs.first().unwrap().file_path == download_items[0].path
-                },
+            .core
+            .set_auto_download(
+                &core_2.endpoint_id_str(),
+                Some(AutoDownloadModel {
+                    selections: Vec::new(),
+                    max_bytes: None,
+                }),
             )
-            .await;
-    }
-
-    /// Test removing items while finished:
-    /// - Request both items
-    /// - Should be two Ready jobs
-    /// - Allow both to download
-    /// - Should reach Finished
-    /// - Request only first item
-    /// - Should still be two Finished jobs, since we don't remove finished jobs
-    #[tokio::test]
-    async fn remove_items_while_finished() {
-        let (core_1, core_2, download_items) = prepare_with_index(LibraryFixture::Multiple).await;
-        core_1.test_hooks.enable_download_gate();
-
-        // request both items
+            .expect("should set auto download");
+        let download_filter = DownloadFilterModel {
+            max_file_size: Some(1),
+            max_duration: None,
+        };
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), download_items.clone())
-            .expect("should set downloads");
-
-        // should have two Ready jobs
+            .set_download_filter(&core_2.endpoint_id_str(), Some(download_filter.clone()))
+            .expect("should set download filter");
         core_1
-            .wait_for_client_condition("has 2 Ready jobs", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Ready))
+            .wait_for_node_model_condition("trusted node has download filter", |model| {
+                model
+                    .trusted_nodes
+                    .iter()
+                    .any(|node| node.download_filter.as_ref() == Some(&download_filter))
             })
             .await;
 
-        // allow both to download
-        core_1.test_hooks.add_download_permits(2);
+        core_2
+            .core
+            .trust_node(&core_1.endpoint_id_str())
+            .expect("should trust node");
 
-        // both jobs should reach Finished
+        core_1.discover(&core_2).await;
         core_1
-            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
-                client.transfer_jobs.len() == 2
-                    && client
-                        .transfer_jobs
-                        .iter()
-                        .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
+            .core
+            .connect(None, &core_2.endpoint_id_str())
+            .await
+            .expect("should connect");
+
+        // both files should be filtered
+        core_1
+            .wait_for_client_condition("both files filtered", &core_2, |client| {
+                client
+                    .index
+                    .as_ref()
+                    .is_some_and(|index| index.len() == 2 && index.iter().all(|item| item.filtered))
             })
             .await;
+        assert!(core_1.client_model(&core_2).transfer_jobs.is_empty());
 
-        // request only first item
+        // removing the filter should download them
         core_1
             .core
-            .set_downloads(&core_2.endpoint_id_str(), vec![download_items[0].clone()])
-            .expect("should set downloads");
-
-        // should still have two Finished jobs, since we don't remove finished jobs
+            .set_download_filter(&core_2.endpoint_id_str(), None)
+            .expect("should set download filter");
         core_1
-            .wait_for_client_condition("still has 2 Finished jobs", &core_2, |client| {
+            .wait_for_client_condition("both jobs are Finished", &core_2, |client| {
                 client.transfer_jobs.len() == 2
                     && client
                         .transfer_jobs
@@ -1726,6 +6095,11 @@ mod transfer {
                         .all(|j| matches!(j.progress, TransferJobProgressModel::Finished { .. }))
             })
             .await;
+        let index = core_1
+            .client_model(&core_2)
+            .index
+            .expect("should have index");
+        assert!(index.iter().all(|item| !item.filtered));
     }
 }
 
@@ -2019,4 +6393,195 @@ mod stats {
             "session should be counted again after reconnecting"
         );
     }
+
+    /// The diagnostic bundle is a zip file with the summaries.
+    #[tokio::test]
+    async fn export_diagnostics() {
+        let core = TestCore::start("core").await;
+        core.core
+            .add_library_root(
+                "foo".into(),
+                LibraryFixture::Multiple
+                    .path()
+                    .to_string_lossy()
+                    .to_string(),
+            )
+            .expect("should add library root");
+        core.wait_for_library_model_condition("root has files", |model| {
+            model
+                .local_roots
+                .first()
+                .is_some_and(|root| root.num_files == 2)
+        })
+        .await;
+
+        let path = testdir::testdir!().join("diagnostics.zip");
+        core.core
+            .export_diagnostics(path.to_string_lossy().to_string())
+            .expect("should export diagnostics");
+
+        let bundle = std::fs::read(&path).expect("should write bundle");
+        assert!(bundle.starts_with(b"PK\x03\x04"));
+        let contains = |needle: &[u8]| bundle.windows(needle.len()).any(|w| w == needle);
+        for name in ["platform.txt", "settings.txt", "status.txt", "database.txt"] {
+            assert!(contains(name.as_bytes()), "bundle should contain {name}");
+        }
+    }
+}
+
+mod share {
+    use crate::common::{LibraryFixture, TestCore};
+    use musicopy::node::NodeShareModel;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Sends a GET request for the path to the share server, and returns the status code and body.
+    async fn http_get(addr: &str, path: &str) -> (u16, Vec<u8>) {
+        let mut stream = tokio::net::TcpStream::connect(addr)
+            .await
+            .expect("should connect to share server");
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\n\r\n").as_bytes())
+            .await
+            .expect("should send request");
+
+        let mut response = Vec::new();
+        stream
+            .read_to_end(&mut response)
+            .await
+            .expect("should read response");
+
+        let head_end = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("response should have a head");
+        let head = String::from_utf8_lossy(&response[..head_end]).to_string();
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .expect("response should have a status");
+
+        (status, response[head_end + 4..].to_vec())
+    }
+
+    /// A one-time share link serves its files to a browser, then stops working once they've all
+    /// been downloaded.
+    #[tokio::test]
+    async fn one_time_link() {
+        let core = TestCore::start("core").await;
+        let fixture = LibraryFixture::Minimal;
+        core.core
+            .add_library_root("foo".into(), fixture.path().to_string_lossy().to_string())
+            .expect("should add library root");
+        core.wait_for_library_model_condition("root has files", |model| {
+            model
+                .local_roots
+                .first()
+                .is_some_and(|root| root.num_files == fixture.num_items() as u64)
+        })
+        .await;
+
+        let addr = core
+            .core
+            .start_share_server("127.0.0.1:0".into(), None)
+            .await
+            .expect("should start share server");
+
+        let url = core
+            .core
+            .create_share_link(
+                "Minimal".into(),
+                vec![NodeShareModel {
+                    root: "foo".into(),
+                    path_prefix: "".into(),
+                }],
+                None,
+                60,
+                true,
+            )
+            .expect("should create share link");
+        let path = url
+            .strip_prefix(&format!("http://{addr}"))
+            .expect("url should use the bound address")
+            .to_string();
+
+        // the index lists the file
+        let (status, body) = http_get(&addr, &path).await;
+        assert_eq!(status, 200);
+        assert!(String::from_utf8_lossy(&body).contains("test.mp3"));
+
+        // the file is served as-is
+        let (status, body) = http_get(&addr, &format!("{path}/0")).await;
+        assert_eq!(status, 200);
+        let original = std::fs::read(fixture.path().join("test.mp3")).expect("should read fixture");
+        assert_eq!(body, original);
+
+        // the link is used up
+        core.wait_for_node_model_condition("share link is removed", |model| {
+            model.share_links.is_empty()
+        })
+        .await;
+        let (status, _) = http_get(&addr, &path).await;
+        assert_eq!(status, 404);
+    }
+
+    /// A revoked share link stops working.
+    #[tokio::test]
+    async fn revoke_link() {
+        let core = TestCore::start("core").await;
+        let fixture = LibraryFixture::Multiple;
+        core.core
+            .add_library_root("foo".into(), fixture.path().to_string_lossy().to_string())
+            .expect("should add library root");
+        core.wait_for_library_model_condition("root has files", |model| {
+            model
+                .local_roots
+                .first()
+                .is_some_and(|root| root.num_files == fixture.num_items() as u64)
+        })
+        .await;
+
+        let addr = core
+            .core
+            .start_share_server("127.0.0.1:0".into(), Some("https://share.example/".into()))
+            .await
+            .expect("should start share server");
+
+        let url = core
+            .core
+            .create_share_link(
+                "Multiple".into(),
+                vec![NodeShareModel {
+                    root: "foo".into(),
+                    path_prefix: "fbp.mp3".into(),
+                }],
+                None,
+                60,
+                false,
+            )
+            .expect("should create share link");
+        assert!(url.starts_with("https://share.example/s/"));
+        let path = url
+            .strip_prefix("https://share.example")
+            .unwrap()
+            .to_string();
+
+        let model = core.core.get_node_model().expect("should get node model");
+        let link = model.share_links.first().expect("should have a share link");
+        assert_eq!(link.file_count, 1);
+
+        let (status, _) = http_get(&addr, &format!("{path}/0")).await;
+        assert_eq!(status, 200);
+
+        core.core
+            .revoke_share_link(link.token.clone())
+            .expect("should revoke share link");
+        core.wait_for_node_model_condition("share link is removed", |model| {
+            model.share_links.is_empty()
+        })
+        .await;
+
+        let (status, _) = http_get(&addr, &format!("{path}/0")).await;
+        assert_eq!(status, 404);
+    }
 }